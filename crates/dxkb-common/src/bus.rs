@@ -1,3 +1,7 @@
+use core::cell::RefCell;
+
+use crate::util::RingBuffer;
+
 #[derive(Debug)]
 pub enum BusPollError {
     WouldBlock,
@@ -9,19 +13,84 @@ pub enum BusTransferError {
     WouldBlock,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusBaudRateError {
+    /// The bus doesn't have a baud rate that can be changed.
+    Unsupported,
+    /// The bus is transferring a frame.
+    Busy,
+    /// The bus can't run at the given baud rate.
+    Unreachable,
+}
+
 pub trait BusWrite {
     fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError>;
     fn is_tx_busy(&self) -> bool;
+
+    /// Returns the current baud rate of the bus, if it has one that can be
+    /// changed with [`set_baud_rate`](Self::set_baud_rate).
+    #[inline]
+    fn baud_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Changes the baud rate of the bus. Any frame being received while it
+    /// changes is likely lost.
+    #[inline]
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> Result<(), BusBaudRateError> {
+        Err(BusBaudRateError::Unsupported)
+    }
 }
 
 pub trait BusRead {
     fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError>;
+
+    /// Like [`poll_next`](Self::poll_next), but hands the frame to `f` instead
+    /// of returning it in a buffer. Buses that keep the frames in memory, like
+    /// the DMA ones, hand them in place when they can, saving a copy of each
+    /// frame. `scratch` is used otherwise, and its length limits the length of
+    /// the frames in both cases.
+    #[inline]
+    fn poll_next_ref<R, F: FnOnce(&[u8]) -> R>(&self, scratch: &mut [u8], f: F) -> Result<R, BusPollError> {
+        let len = self.poll_next(scratch)?;
+        Ok(f(&scratch[..len as usize]))
+    }
 }
 
+/// A pair of lines, apart from the bus, through which each side tells the
+/// other that it has frames on their way, so that the receiver can sleep
+/// instead of polling the bus while the peer has nothing to send.
+pub trait AttentionLine {
+    /// Asserts our line while `pending`, and releases it otherwise.
+    fn set_pending(&mut self, pending: bool);
+
+    /// Returns whether the peer is asserting its line, or has asserted it
+    /// since the last call, even if it is released already.
+    fn take_peer_attention(&mut self) -> bool;
+}
+
+/// No attention line at all: the peer is assumed to always need attention, so
+/// the bus is polled every time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoAttentionLine;
+
+impl AttentionLine for NoAttentionLine {
+    #[inline]
+    fn set_pending(&mut self, _pending: bool) {}
+
+    #[inline]
+    fn take_peer_attention(&mut self) -> bool {
+        true
+    }
+}
+
+/// A bus that is never connected to anything: transfers are discarded and
+/// nothing is ever received. Useful as a placeholder for keyboards that don't
+/// have a peer, like single-piece keyboards.
 pub struct NullBus;
 
 impl BusWrite for NullBus {
-    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+    fn transfer(&mut self, _buf: &[u8]) -> Result<(), BusTransferError> {
         Ok(())
     }
 
@@ -35,3 +104,139 @@ impl BusRead for NullBus {
         Err(BusPollError::WouldBlock)
     }
 }
+
+struct LoopbackFrame<const MTU: usize> {
+    len: usize,
+    data: [u8; MTU],
+}
+
+/// A bus whose TX is wired to its own RX: every transfer becomes a frame that
+/// can be read back with [`BusRead::poll_next`], in the same order they were
+/// sent. Up to `FRAMES` frames of at most `MTU` bytes each can be pending to
+/// be read. Useful for tests and benchmarks.
+pub struct LoopbackBus<const FRAMES: usize, const MTU: usize> {
+    frames: RefCell<RingBuffer<LoopbackFrame<MTU>, FRAMES>>,
+}
+
+impl<const FRAMES: usize, const MTU: usize> LoopbackBus<FRAMES, MTU> {
+    pub const fn new() -> Self {
+        Self {
+            frames: RefCell::new(RingBuffer::new()),
+        }
+    }
+
+    /// Returns the number of frames that are pending to be read.
+    pub fn pending_frames(&self) -> usize {
+        self.frames.borrow().len()
+    }
+}
+
+impl<const FRAMES: usize, const MTU: usize> BusWrite for LoopbackBus<FRAMES, MTU> {
+    /// Queues the buffer as a new frame. Returns [`BusTransferError::WouldBlock`]
+    /// if there are already `FRAMES` frames pending to be read.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is larger than `MTU`.
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        assert!(buf.len() <= MTU, "Frame is larger than the loopback bus MTU");
+        let frames = self.frames.get_mut();
+        if frames.is_full() {
+            return Err(BusTransferError::WouldBlock);
+        }
+
+        let mut frame = LoopbackFrame { len: buf.len(), data: [0; MTU] };
+        frame.data[..buf.len()].copy_from_slice(buf);
+        frames.push(frame);
+        Ok(())
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.frames.borrow().is_full()
+    }
+}
+
+impl<const FRAMES: usize, const MTU: usize> BusRead for LoopbackBus<FRAMES, MTU> {
+    /// Reads the oldest pending frame. If it doesn't fit in the buffer, the
+    /// frame is discarded and [`BusPollError::BufferOverflow`] is returned.
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        let frame = self
+            .frames
+            .borrow_mut()
+            .poll_first()
+            .ok_or(BusPollError::WouldBlock)?;
+
+        if frame.len > buf.len() {
+            return Err(BusPollError::BufferOverflow);
+        }
+
+        buf[..frame.len].copy_from_slice(&frame.data[..frame.len]);
+        Ok(frame.len as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_bus_never_receives() {
+        let mut bus = NullBus;
+        let mut buf = [0u8; 4];
+        assert!(bus.transfer(&[1, 2, 3]).is_ok());
+        assert!(matches!(bus.poll_next(&mut buf), Err(BusPollError::WouldBlock)));
+    }
+
+    #[test]
+    fn test_loopback_bus_reads_frames_in_order() {
+        let mut bus = LoopbackBus::<4, 8>::new();
+        let mut buf = [0u8; 8];
+        bus.transfer(&[1, 2, 3]).unwrap();
+        bus.transfer(&[4]).unwrap();
+        assert_eq!(bus.pending_frames(), 2);
+
+        assert_eq!(bus.poll_next(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(bus.poll_next(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 4);
+        assert!(matches!(bus.poll_next(&mut buf), Err(BusPollError::WouldBlock)));
+    }
+
+    #[test]
+    fn test_poll_next_ref_hands_the_frame() {
+        let mut bus = LoopbackBus::<4, 8>::new();
+        bus.transfer(&[1, 2, 3]).unwrap();
+
+        let mut scratch = [0u8; 8];
+        let mut frame = [0u8; 3];
+        bus.poll_next_ref(&mut scratch, |f| frame.copy_from_slice(f)).unwrap();
+        assert_eq!(frame, [1, 2, 3]);
+        assert!(matches!(bus.poll_next_ref(&mut scratch, |_| ()), Err(BusPollError::WouldBlock)));
+    }
+
+    #[test]
+    fn test_loopback_bus_full() {
+        let mut bus = LoopbackBus::<2, 8>::new();
+        bus.transfer(&[1]).unwrap();
+        bus.transfer(&[2]).unwrap();
+        assert!(bus.is_tx_busy());
+        assert!(matches!(bus.transfer(&[3]), Err(BusTransferError::WouldBlock)));
+
+        let mut buf = [0u8; 8];
+        assert_eq!(bus.poll_next(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 1);
+        assert!(!bus.is_tx_busy());
+    }
+
+    #[test]
+    fn test_loopback_bus_discards_frames_not_fitting_the_buffer() {
+        let mut bus = LoopbackBus::<2, 8>::new();
+        bus.transfer(&[1, 2, 3, 4]).unwrap();
+        bus.transfer(&[5]).unwrap();
+
+        let mut buf = [0u8; 2];
+        assert!(matches!(bus.poll_next(&mut buf), Err(BusPollError::BufferOverflow)));
+        assert_eq!(bus.poll_next(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 5);
+    }
+}
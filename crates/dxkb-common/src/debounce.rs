@@ -0,0 +1,365 @@
+//! Debouncing of the signal read from the keys of a matrix, and tools for
+//! tuning debouncers against real bounce traces.
+//!
+//! A [`CapturingDebouncer`] can be put in front of the debouncer of a target to
+//! record the raw, pre-debounce transitions seen by each scan. The capture can
+//! then be dumped (one [`KeyTransition`] per line) and fed into [`replay`] on
+//! the host, so that any [`Debounce`] implementation can be validated offline
+//! against the exact same input. See `cargo xtask debounce-replay`.
+
+use core::fmt::Display;
+
+use crate::{KeyState, dev_trace, util::RingBuffer};
+
+/// Represents a type that is able to debounce the input signal
+/// generated by a button, attempting to remove the noise generated by
+/// the effect of pressing or unpressing it.
+pub trait Debounce<const ROWS: u8, const COLS: u8> {
+    fn debounce(
+        &mut self,
+        row: u8,
+        col: u8,
+        current_millis: u32,
+        prev_state: KeyState,
+        last_read_state: KeyState,
+    ) -> KeyState;
+}
+
+/// A debounce strategy where no debounce is done. Button status is
+/// passed to the matrix as it is coming from the wire.
+pub struct NoDebouncer {}
+impl<const ROWS: u8, const COLS: u8> Debounce<ROWS, COLS> for NoDebouncer {
+    fn debounce(
+        &mut self,
+        _row: u8,
+        _col: u8,
+        _current_millis: u32,
+        _prev_state: KeyState,
+        last_read_state: KeyState,
+    ) -> KeyState {
+        last_read_state
+    }
+}
+
+// Following a similar naming than QMK
+/// Debounce strategy in which a level change in the wire immediately
+/// triggers a change in the button state (pressed or unpressed,
+/// depending on the new level), and any other change in the wire for
+/// that specific button is ignored for the following
+/// `DEBOUNCE_MILLIS` milliseconds. For reducing the memory
+/// footprint, the maximum debounce time is limited to 254 ms.
+pub struct DebouncerEagerPerKey<const ROWS: u8, const COLS: u8, const DEBOUNCE_MILLIS: u8>
+where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+{
+    // Holds the last time, in millis, when each key was pressed,
+    // except if its value is 0xff. In such case, it is considered
+    // that the last event for the referred key happened a long time
+    // ago, and next event coming from this key can be applied. This
+    // is a trick that QMK uses for handling the fact that a u8 value
+    // that represents millis will constantly be wrapping, and
+    // therefore, zero is a perfectly valid value. Therefore, instead
+    // of zero, we reserve the value 0xff as a special value for
+    // represent this case.
+    last_change_millis: [u8; (ROWS as usize) * (COLS as usize)],
+}
+
+impl<const ROWS: u8, const COLS: u8, const DEBOUNCE_MILLIS: u8>
+    DebouncerEagerPerKey<ROWS, COLS, DEBOUNCE_MILLIS>
+where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+{
+    const fn assert_debounce_millis_in_range(millis: u8) {
+        assert!(millis < 255, "Debounce time cannot be greater than 254 ms!");
+    }
+
+    pub fn new() -> Self {
+        const { Self::assert_debounce_millis_in_range(DEBOUNCE_MILLIS) };
+
+        DebouncerEagerPerKey {
+            last_change_millis: [0xffu8; (ROWS as usize) * (COLS as usize)],
+        }
+    }
+
+    pub const fn diff_time(newer: u8, older: u8) -> u8 {
+        if newer >= older {
+            newer - older
+        } else {
+            255 - older + newer
+        }
+    }
+}
+
+impl<const ROWS: u8, const COLS: u8, const DEBOUNCE_MILLIS: u8> Debounce<ROWS, COLS>
+    for DebouncerEagerPerKey<ROWS, COLS, DEBOUNCE_MILLIS>
+where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+{
+    fn debounce(
+        &mut self,
+        row: u8,
+        col: u8,
+        current_millis: u32,
+        prev_state: KeyState,
+        last_read_state: KeyState,
+    ) -> KeyState {
+        let wrapped_millis = (current_millis % 254) as u8;
+        let last_change_ms =
+            &mut self.last_change_millis[row as usize * COLS as usize + col as usize];
+        if *last_change_ms != 0xff {
+            if Self::diff_time(wrapped_millis, *last_change_ms) < DEBOUNCE_MILLIS {
+                // The last update was recent. Just ignore everything.
+                return prev_state;
+            } else {
+                // The debounce time have already passed. Mark it as such, and continue.
+                dev_trace!(
+                    "Debounce time over for {}; {} ({} ms). Because {} - {} = {}",
+                    row,
+                    col,
+                    current_millis,
+                    wrapped_millis,
+                    *last_change_ms,
+                    Self::diff_time(wrapped_millis, *last_change_ms)
+                );
+                *last_change_ms = 0xff;
+            }
+        }
+
+        if prev_state != last_read_state {
+            // If there has been any change, report the change, and
+            // store the time when it happened.
+            dev_trace!(
+                "Debounce time set in {} ms after {:?}",
+                current_millis,
+                last_read_state
+            );
+            *last_change_ms = wrapped_millis;
+        }
+        return last_read_state;
+    }
+}
+
+/// A change of the state of a key at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyTransition {
+    pub millis: u32,
+    pub row: u8,
+    pub col: u8,
+    pub state: KeyState,
+}
+
+impl KeyTransition {
+    pub const fn new(millis: u32, row: u8, col: u8, state: KeyState) -> Self {
+        Self { millis, row, col, state }
+    }
+
+    /// Parses a transition in the format written by its [`Display`]
+    /// implementation (`<millis> <row> <col> <P|R>`). Only the last four words
+    /// of the line are taken into account, so lines copied from the debug log
+    /// can be parsed with their prefix.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace().rev();
+        let state = match words.next()? {
+            "P" => KeyState::Pressed,
+            "R" => KeyState::Released,
+            _ => return None,
+        };
+        let col = words.next()?.parse().ok()?;
+        let row = words.next()?.parse().ok()?;
+        let millis = words.next()?.parse().ok()?;
+        Some(Self::new(millis, row, col, state))
+    }
+}
+
+impl Display for KeyTransition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state = match self.state {
+            KeyState::Pressed => "P",
+            KeyState::Released => "R",
+        };
+        write!(f, "{} {} {} {}", self.millis, self.row, self.col, state)
+    }
+}
+
+/// Wraps a debouncer, recording the last `N` raw transitions read from the
+/// matrix before they are debounced, i.e including any bounce. The wrapped
+/// debouncer works as if it was used directly.
+pub struct CapturingDebouncer<D, const ROWS: u8, const COLS: u8, const N: usize>
+where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+{
+    inner: D,
+    /// The last raw state read from each key.
+    raw_state: [KeyState; (ROWS as usize) * (COLS as usize)],
+    capture: RingBuffer<KeyTransition, N>,
+}
+
+impl<D, const ROWS: u8, const COLS: u8, const N: usize> CapturingDebouncer<D, ROWS, COLS, N>
+where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+{
+    pub const fn new(inner: D) -> Self {
+        Self {
+            inner,
+            raw_state: [KeyState::Released; (ROWS as usize) * (COLS as usize)],
+            capture: RingBuffer::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Returns the number of transitions currently captured.
+    pub fn captured(&self) -> usize {
+        self.capture.len()
+    }
+
+    /// Removes and returns the oldest captured transition, if any.
+    pub fn poll_capture(&mut self) -> Option<KeyTransition> {
+        self.capture.poll_first()
+    }
+}
+
+impl<D, const ROWS: u8, const COLS: u8, const N: usize> Debounce<ROWS, COLS>
+    for CapturingDebouncer<D, ROWS, COLS, N>
+where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+    D: Debounce<ROWS, COLS>,
+{
+    fn debounce(
+        &mut self,
+        row: u8,
+        col: u8,
+        current_millis: u32,
+        prev_state: KeyState,
+        last_read_state: KeyState,
+    ) -> KeyState {
+        let raw_state = &mut self.raw_state[row as usize * COLS as usize + col as usize];
+        if *raw_state != last_read_state {
+            *raw_state = last_read_state;
+            self.capture
+                .push(KeyTransition::new(current_millis, row, col, last_read_state));
+        }
+
+        self.inner
+            .debounce(row, col, current_millis, prev_state, last_read_state)
+    }
+}
+
+/// Replays a capture of raw transitions through the given debouncer, calling
+/// `on_event` with every change of the debounced state of a key. The
+/// transitions must be sorted by time.
+///
+/// The matrix is simulated as if it was scanned every `scan_period_ms`
+/// milliseconds, starting at the time of the first transition, and for
+/// `tail_ms` milliseconds after the last one so that the debouncer can settle.
+/// Every key of the matrix is debounced on each scan, just like the matrix
+/// drivers do, and all the keys start released. A capture taken with the same
+/// scan period is replayed exactly as it was read.
+pub fn replay<const ROWS: u8, const COLS: u8, D, F>(
+    debouncer: &mut D,
+    capture: &[KeyTransition],
+    scan_period_ms: u32,
+    tail_ms: u32,
+    mut on_event: F,
+) where
+    [(); (ROWS as usize) * (COLS as usize)]:,
+    D: Debounce<ROWS, COLS> + ?Sized,
+    F: FnMut(KeyTransition),
+{
+    let (Some(first), Some(last)) = (capture.first(), capture.last()) else {
+        return;
+    };
+
+    let mut raw_state = [KeyState::Released; (ROWS as usize) * (COLS as usize)];
+    let mut state = [KeyState::Released; (ROWS as usize) * (COLS as usize)];
+    let mut pending = capture.iter().peekable();
+    let end = last.millis.saturating_add(tail_ms);
+    let mut millis = first.millis;
+
+    loop {
+        while let Some(t) = pending.next_if(|t| t.millis <= millis) {
+            if t.row < ROWS && t.col < COLS {
+                raw_state[t.row as usize * COLS as usize + t.col as usize] = t.state;
+            }
+        }
+
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let index = row as usize * COLS as usize + col as usize;
+                let new_state =
+                    debouncer.debounce(row, col, millis, state[index], raw_state[index]);
+                if new_state != state[index] {
+                    state[index] = new_state;
+                    on_event(KeyTransition::new(millis, row, col, new_state));
+                }
+            }
+        }
+
+        if millis >= end {
+            break;
+        }
+        millis = millis.saturating_add(scan_period_ms.max(1)).min(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    const BOUNCY_CAPTURE: [KeyTransition; 6] = [
+        KeyTransition::new(10, 0, 1, KeyState::Pressed),
+        KeyTransition::new(11, 0, 1, KeyState::Released),
+        KeyTransition::new(12, 0, 1, KeyState::Pressed),
+        KeyTransition::new(100, 0, 1, KeyState::Released),
+        KeyTransition::new(101, 0, 1, KeyState::Pressed),
+        KeyTransition::new(102, 0, 1, KeyState::Released),
+    ];
+
+    fn replay_events<D: Debounce<2, 2>>(mut debouncer: D) -> Vec<KeyTransition> {
+        let mut events = Vec::new();
+        replay::<2, 2, _, _>(&mut debouncer, &BOUNCY_CAPTURE, 1, 50, |e| events.push(e));
+        events
+    }
+
+    #[test]
+    fn test_replay_no_debouncer_passes_bounces_through() {
+        assert_eq!(replay_events(NoDebouncer {}), BOUNCY_CAPTURE);
+    }
+
+    #[test]
+    fn test_replay_eager_debouncer_filters_bounces() {
+        assert_eq!(
+            replay_events(DebouncerEagerPerKey::<2, 2, 5>::new()),
+            [
+                KeyTransition::new(10, 0, 1, KeyState::Pressed),
+                KeyTransition::new(100, 0, 1, KeyState::Released),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capture_replays_as_read() {
+        let mut capturing = CapturingDebouncer::<_, 2, 2, 8>::new(NoDebouncer {});
+        replay::<2, 2, _, _>(&mut capturing, &BOUNCY_CAPTURE, 1, 0, |_| {});
+
+        let mut captured = Vec::new();
+        while let Some(t) = capturing.poll_capture() {
+            captured.push(t);
+        }
+        assert_eq!(captured, BOUNCY_CAPTURE);
+    }
+
+    #[test]
+    fn test_transition_display_parse_roundtrip() {
+        let t = KeyTransition::new(123456, 4, 5, KeyState::Released);
+        let line = std::format!("[INFO] scan-capture: {}", t);
+        assert_eq!(KeyTransition::parse(&line), Some(t));
+        assert_eq!(KeyTransition::parse("1 2 3 X"), None);
+        assert_eq!(KeyTransition::parse("2 3 P"), None);
+    }
+}
@@ -77,3 +77,43 @@ macro_rules! dev_trace {
     () => {};
     ($($arg:tt)*) => {};
 }
+
+/// Logs a structured event (see [`log_event`](crate::log_event)), given its
+/// level, id, message and up to two numeric arguments, which are cast to u32.
+/// The message is only logged, through the dev log macro of that level, when
+/// the `dev-log-events` feature is disabled.
+#[macro_export]
+#[cfg(feature = "dev-log-events")]
+macro_rules! dev_event {
+    ($level:ident, $id:expr, $fmt:literal $(,)?) => {
+        $crate::dev_event!(@emit $level, $id, [0, 0])
+    };
+    ($level:ident, $id:expr, $fmt:literal, $a:expr $(,)?) => {
+        $crate::dev_event!(@emit $level, $id, [($a) as u32, 0])
+    };
+    ($level:ident, $id:expr, $fmt:literal, $a:expr, $b:expr $(,)?) => {
+        $crate::dev_event!(@emit $level, $id, [($a) as u32, ($b) as u32])
+    };
+    (@emit error, $id:expr, $args:expr) => { $crate::dev_event!(@emit_level Error, $id, $args) };
+    (@emit warn, $id:expr, $args:expr) => { $crate::dev_event!(@emit_level Warn, $id, $args) };
+    (@emit info, $id:expr, $args:expr) => { $crate::dev_event!(@emit_level Info, $id, $args) };
+    (@emit debug, $id:expr, $args:expr) => { $crate::dev_event!(@emit_level Debug, $id, $args) };
+    (@emit trace, $id:expr, $args:expr) => { $crate::dev_event!(@emit_level Trace, $id, $args) };
+    (@emit_level $level:ident, $id:expr, $args:expr) => {
+        $crate::log_event::emit(&$crate::log_event::LogEvent::new(
+            $crate::log_event::LogEventLevel::$level,
+            $id,
+            $args,
+        ))
+    };
+}
+
+#[macro_export]
+#[cfg(not(feature = "dev-log-events"))]
+macro_rules! dev_event {
+    (error, $id:expr, $($arg:tt)*) => { $crate::dev_error!($($arg)*) };
+    (warn, $id:expr, $($arg:tt)*) => { $crate::dev_warn!($($arg)*) };
+    (info, $id:expr, $($arg:tt)*) => { $crate::dev_info!($($arg)*) };
+    (debug, $id:expr, $($arg:tt)*) => { $crate::dev_debug!($($arg)*) };
+    (trace, $id:expr, $($arg:tt)*) => { $crate::dev_trace!($($arg)*) };
+}
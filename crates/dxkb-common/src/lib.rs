@@ -5,14 +5,24 @@
 #![feature(const_convert)]
 #![allow(incomplete_features)]
 
+#[cfg(any(test, feature = "testing"))]
+extern crate std;
+
 
 pub mod bus;
+pub mod debounce;
 mod devlog;
 mod key;
+pub mod log_event;
+mod reset;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod time;
 pub mod util;
+pub mod watchdog;
 
 pub use key::*;
+pub use reset::*;
 
 pub use log as __log;
 
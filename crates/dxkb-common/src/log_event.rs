@@ -0,0 +1,78 @@
+//! Structured log events, for logging from hot paths without pulling the
+//! formatting machinery of `core::fmt` in.
+//!
+//! An event is just an id and a couple of numeric arguments, which are handed
+//! to the hook installed with [`set_log_event_hook`] as they are, so that the
+//! formatting, if any, is deferred to whoever reads them, e.g the host. They
+//! are logged with [`dev_event!`](crate::dev_event), which only emits them when
+//! the `dev-log-events` feature is enabled, and otherwise logs the message
+//! given along with them through the regular dev log macros.
+//!
+//! The ids are defined by each crate, in its own range:
+//!
+//! - `0x01xx`: dxkb-split-link.
+//! - `0x02xx`: dxkb-core.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogEventLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogEvent {
+    pub level: LogEventLevel,
+    pub id: u16,
+    pub args: [u32; 2],
+}
+
+impl LogEvent {
+    pub const fn new(level: LogEventLevel, id: u16, args: [u32; 2]) -> Self {
+        Self { level, id, args }
+    }
+}
+
+static LOG_EVENT_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Sets the function every log event is handed to. Events are dropped until
+/// one is set. It may be called from interrupts, so it must be quick.
+pub fn set_log_event_hook(hook: fn(&LogEvent)) {
+    LOG_EVENT_HOOK.store(hook as *mut (), Ordering::Release);
+}
+
+/// Hands an event to the installed hook, if any.
+pub fn emit(event: &LogEvent) {
+    let hook = LOG_EVENT_HOOK.load(Ordering::Acquire);
+    if !hook.is_null() {
+        // Only fn(&LogEvent) pointers are ever stored.
+        let hook: fn(&LogEvent) = unsafe { core::mem::transmute(hook) };
+        hook(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicU32;
+
+    static RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+    fn hook(event: &LogEvent) {
+        RECEIVED.store(((event.id as u32) << 16) | event.args[0], Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_emit_calls_hook() {
+        emit(&LogEvent::new(LogEventLevel::Warn, 0x0101, [1, 0]));
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 0);
+
+        set_log_event_hook(hook);
+        emit(&LogEvent::new(LogEventLevel::Warn, 0x0101, [7, 0]));
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 0x0101_0007);
+    }
+}
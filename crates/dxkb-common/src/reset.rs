@@ -0,0 +1,70 @@
+/// Why the MCU was reset last, as told by the reset flags of the MCU. Reported
+/// to the peer during the link sync, so that the user learns when the other
+/// half is crashing.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    /// The supply was just powered on.
+    PowerOn = 1,
+    /// The supply dropped below the brown-out threshold.
+    BrownOut = 2,
+    /// The reset pin was pulled low, e.g by the reset button.
+    Pin = 3,
+    /// The firmware asked for a reset, e.g for rebooting into the bootloader,
+    /// or from a panic handler.
+    Software = 4,
+    /// The independent watchdog was not refreshed in time.
+    IndependentWatchdog = 5,
+    /// The window watchdog was not refreshed in time, or it was refreshed too
+    /// early.
+    WindowWatchdog = 6,
+    /// The MCU entered the Standby or Stop mode while they're configured to
+    /// reset it.
+    LowPower = 7,
+    /// None of the above, or a reason unknown to this firmware version.
+    Unknown = 0xff,
+}
+
+impl ResetReason {
+    /// Returns the reset reason encoded in the byte, where zero means that
+    /// there's no reset to report.
+    pub const fn from_u8(value: u8) -> Option<ResetReason> {
+        match value {
+            0 => None,
+            1 => Some(ResetReason::PowerOn),
+            2 => Some(ResetReason::BrownOut),
+            3 => Some(ResetReason::Pin),
+            4 => Some(ResetReason::Software),
+            5 => Some(ResetReason::IndependentWatchdog),
+            6 => Some(ResetReason::WindowWatchdog),
+            7 => Some(ResetReason::LowPower),
+            _ => Some(ResetReason::Unknown),
+        }
+    }
+
+    /// Whether the reset means that the firmware hung up or the MCU run out
+    /// of power.
+    pub const fn is_abnormal(self) -> bool {
+        matches!(self, ResetReason::BrownOut | ResetReason::IndependentWatchdog | ResetReason::WindowWatchdog)
+    }
+
+    /// Every reset reason, in the order of their values.
+    pub const ALL: [ResetReason; 8] = [
+        ResetReason::PowerOn,
+        ResetReason::BrownOut,
+        ResetReason::Pin,
+        ResetReason::Software,
+        ResetReason::IndependentWatchdog,
+        ResetReason::WindowWatchdog,
+        ResetReason::LowPower,
+        ResetReason::Unknown,
+    ];
+
+    /// Returns the position of the reason in [`Self::ALL`].
+    pub const fn index(self) -> usize {
+        match self {
+            ResetReason::Unknown => Self::ALL.len() - 1,
+            reason => reason as usize - 1,
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! Deterministic, host-side test doubles for the bus and clock traits, so that
+//! the protocols built on top of them can be tested with `cargo test`, without
+//! any hardware. Only available with the `testing` feature, since they need
+//! `std`.
+//!
+//! Nothing here depends on the real time or on any source of entropy: given
+//! the same seed and the same sequence of calls, the same frames are dropped,
+//! corrupted and delivered, so any failure can be reproduced.
+
+use std::{cell::{Cell, RefCell}, collections::VecDeque, rc::Rc, time::Duration, vec::Vec};
+
+use crate::{
+    bus::{BusPollError, BusRead, BusTransferError, BusWrite},
+    time::{Clock, TimeDiff},
+};
+
+/// A clock that only moves when told so. Clones share the same time, so the
+/// same clock can be handed to every component under test and advanced from
+/// the test itself. Instants are nanoseconds since the clock was created.
+#[derive(Clone, Default)]
+pub struct MockClock {
+    now: Rc<Cell<u64>>,
+    wrap_bits: Option<u32>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a clock whose instants only keep the low `bits` bits of the
+    /// nanoseconds, like the cycle counter of the MCU, so that the instants
+    /// more than half of its range apart tell the time backwards.
+    pub fn wrapping(bits: u32) -> Self {
+        Self {
+            now: Rc::default(),
+            wrap_bits: Some(bits),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration.as_nanos() as u64);
+    }
+
+    /// Returns the time elapsed since the clock was created.
+    pub fn now(&self) -> Duration {
+        Duration::from_nanos(self.now.get())
+    }
+}
+
+impl Clock for MockClock {
+    type TInstant = u64;
+
+    fn current_instant(&self) -> Self::TInstant {
+        match self.wrap_bits {
+            Some(bits) => self.now.get() & ((1 << bits) - 1),
+            None => self.now.get(),
+        }
+    }
+
+    fn diff(&self, newer: Self::TInstant, older: Self::TInstant) -> TimeDiff {
+        let Some(bits) = self.wrap_bits else {
+            return if newer >= older {
+                TimeDiff::Forward(Duration::from_nanos(newer - older))
+            } else {
+                TimeDiff::Backward(Duration::from_nanos(older - newer))
+            };
+        };
+
+        let mask = (1 << bits) - 1;
+        let diff = newer.wrapping_sub(older) & mask;
+        if diff <= mask >> 1 {
+            TimeDiff::Forward(Duration::from_nanos(diff))
+        } else {
+            TimeDiff::Backward(Duration::from_nanos(older.wrapping_sub(newer) & mask))
+        }
+    }
+
+    fn nanos(&self, instant: Self::TInstant) -> u64 {
+        instant
+    }
+}
+
+type FrameQueue<T> = Rc<RefCell<VecDeque<T>>>;
+
+fn pop_frame(frame: Option<Vec<u8>>, buf: &mut [u8]) -> Result<u16, BusPollError> {
+    let frame = frame.ok_or(BusPollError::WouldBlock)?;
+    if frame.len() > buf.len() {
+        return Err(BusPollError::BufferOverflow);
+    }
+
+    buf[..frame.len()].copy_from_slice(&frame);
+    Ok(frame.len() as u16)
+}
+
+/// One end of a perfect in-memory link: every frame transferred from one end
+/// is read from the other one, in order. Unlike
+/// [`bus::LoopbackBus`](crate::bus::LoopbackBus), frames are never read back
+/// from the end that sent them, and there's no limit on the number of pending
+/// frames or their length, so the TX is never busy.
+pub struct LoopbackPair {
+    tx: FrameQueue<Vec<u8>>,
+    rx: FrameQueue<Vec<u8>>,
+}
+
+impl LoopbackPair {
+    /// Creates both ends of a new link.
+    pub fn pair() -> (Self, Self) {
+        let a = FrameQueue::default();
+        let b = FrameQueue::default();
+        (
+            Self { tx: a.clone(), rx: b.clone() },
+            Self { tx: b, rx: a },
+        )
+    }
+
+    /// Creates an end whose frames are read back from itself, as if its TX
+    /// and RX lines were shorted.
+    pub fn shorted() -> Self {
+        let queue = FrameQueue::default();
+        Self { tx: queue.clone(), rx: queue }
+    }
+
+    /// Returns the number of frames that are pending to be read from this
+    /// end.
+    pub fn pending_frames(&self) -> usize {
+        self.rx.borrow().len()
+    }
+}
+
+impl BusWrite for LoopbackPair {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        self.tx.borrow_mut().push_back(buf.to_vec());
+        Ok(())
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        false
+    }
+}
+
+impl BusRead for LoopbackPair {
+    /// Reads the oldest pending frame. If it doesn't fit in the buffer, the
+    /// frame is discarded and [`BusPollError::BufferOverflow`] is returned.
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        pop_frame(self.rx.borrow_mut().pop_front(), buf)
+    }
+}
+
+/// What happens to the frames transferred from one end of a [`LossyBus`].
+/// Every frame is delivered intact and right away by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Impairments {
+    /// Probability of a frame being lost, from 0 to 1.
+    pub drop_rate: f64,
+    /// Probability of a delivered frame having a random bit flipped, from 0
+    /// to 1.
+    pub corruption_rate: f64,
+    /// Time that frames take to be readable from the other end. Frames are
+    /// still delivered in the same order they were sent.
+    pub delay: Duration,
+}
+
+impl Impairments {
+    pub const fn none() -> Self {
+        Self {
+            drop_rate: 0.0,
+            corruption_rate: 0.0,
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub const fn with_drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate;
+        self
+    }
+
+    pub const fn with_corruption_rate(mut self, corruption_rate: f64) -> Self {
+        self.corruption_rate = corruption_rate;
+        self
+    }
+
+    pub const fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Counters of what a [`LossyBus`] did with the frames transferred from it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LossyBusStats {
+    pub transferred: u32,
+    pub dropped: u32,
+    pub corrupted: u32,
+}
+
+/// A xorshift64* generator, good enough for deciding the fate of frames, and
+/// fully determined by its seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero.
+        Self(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns true with the given probability.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// One end of an in-memory link that drops, corrupts and delays the frames
+/// transferred from it, as configured by its [`Impairments`]. The decisions
+/// are taken by a pseudo-random generator seeded when the link is created,
+/// so each run is reproducible. Delays are measured with a [`MockClock`],
+/// which must be advanced for delayed frames to be delivered.
+pub struct LossyBus {
+    tx: FrameQueue<(u64, Vec<u8>)>,
+    rx: FrameQueue<(u64, Vec<u8>)>,
+    clock: MockClock,
+    impairments: Impairments,
+    connected: bool,
+    rng: Rng,
+    stats: LossyBusStats,
+}
+
+impl LossyBus {
+    /// Creates both ends of a new link, each one impairing the frames it
+    /// transfers. They start without any impairment.
+    pub fn pair(clock: &MockClock, seed: u64) -> (Self, Self) {
+        let a = FrameQueue::default();
+        let b = FrameQueue::default();
+        let end = |tx, rx, seed| Self {
+            tx,
+            rx,
+            clock: clock.clone(),
+            impairments: Impairments::none(),
+            connected: true,
+            rng: Rng::new(seed),
+            stats: LossyBusStats::default(),
+        };
+
+        (end(a.clone(), b.clone(), seed), end(b, a, seed ^ 0x5A5A_5A5A_5A5A_5A5A))
+    }
+
+    pub fn impairments(&self) -> &Impairments {
+        &self.impairments
+    }
+
+    /// Changes what happens to the frames transferred from now on. The frames
+    /// already on the way are not affected.
+    pub fn set_impairments(&mut self, impairments: Impairments) {
+        self.impairments = impairments;
+    }
+
+    /// Simulates unplugging the cable, if `connected` is false: every frame
+    /// transferred from this end is lost until it is connected again.
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+    }
+
+    pub fn stats(&self) -> &LossyBusStats {
+        &self.stats
+    }
+}
+
+impl BusWrite for LossyBus {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        self.stats.transferred += 1;
+        if !self.connected || self.rng.chance(self.impairments.drop_rate) {
+            self.stats.dropped += 1;
+            return Ok(());
+        }
+
+        let mut frame = buf.to_vec();
+        if !frame.is_empty() && self.rng.chance(self.impairments.corruption_rate) {
+            let bit = self.rng.next_u64() as usize % (frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+            self.stats.corrupted += 1;
+        }
+
+        let deliver_at = self.clock.current_instant() + self.impairments.delay.as_nanos() as u64;
+        self.tx.borrow_mut().push_back((deliver_at, frame));
+        Ok(())
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        false
+    }
+}
+
+impl BusRead for LossyBus {
+    /// Reads the oldest frame whose delay has elapsed. If it doesn't fit in
+    /// the buffer, the frame is discarded and [`BusPollError::BufferOverflow`]
+    /// is returned.
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        let now = self.clock.current_instant();
+        let mut rx = self.rx.borrow_mut();
+        let frame = match rx.front() {
+            // Delays are only ever changed between frames, so the front one
+            // is always the first to be due.
+            Some((deliver_at, _)) if *deliver_at <= now => rx.pop_front().map(|(_, frame)| frame),
+            _ => None,
+        };
+
+        pop_frame(frame, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::Stopwatch;
+
+    #[test]
+    fn test_wrapping_clock() {
+        let clock = MockClock::wrapping(8);
+        let start = clock.current_instant();
+        clock.advance(Duration::from_nanos(200));
+        assert_eq!(clock.current_instant(), 200);
+        assert!(matches!(clock.diff(clock.current_instant(), start), TimeDiff::Backward(d) if d.as_nanos() == 56));
+
+        clock.advance(Duration::from_nanos(100));
+        assert_eq!(clock.current_instant(), 44);
+        assert!(matches!(clock.diff(clock.current_instant(), 200), TimeDiff::Forward(d) if d.as_nanos() == 100));
+    }
+
+    #[test]
+    fn test_stopwatch_across_clock_wrap() {
+        let clock = MockClock::wrapping(8);
+        let mut stopwatch = Stopwatch::start(&clock);
+        for _ in 0..10 {
+            clock.advance(Duration::from_nanos(100));
+            stopwatch.elapsed(&clock);
+        }
+        assert_eq!(stopwatch.elapsed(&clock), Duration::from_nanos(1000));
+    }
+
+    #[test]
+    fn test_loopback_pair_delivers_to_the_other_end() {
+        let (mut a, b) = LoopbackPair::pair();
+        let mut buf = [0u8; 4];
+        a.transfer(&[1, 2]).unwrap();
+
+        assert!(matches!(a.poll_next(&mut buf), Err(BusPollError::WouldBlock)));
+        assert_eq!(b.pending_frames(), 1);
+        assert_eq!(b.poll_next(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[1, 2]);
+    }
+
+    #[test]
+    fn test_lossy_bus_delays_frames() {
+        let clock = MockClock::new();
+        let (mut a, b) = LossyBus::pair(&clock, 1);
+        a.set_impairments(Impairments::none().with_delay(Duration::from_millis(2)));
+        a.transfer(&[1]).unwrap();
+
+        let mut buf = [0u8; 4];
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(b.poll_next(&mut buf), Err(BusPollError::WouldBlock)));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(b.poll_next(&mut buf).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_lossy_bus_is_deterministic() {
+        let run = || {
+            let clock = MockClock::new();
+            let (mut a, b) = LossyBus::pair(&clock, 42);
+            a.set_impairments(Impairments::none().with_drop_rate(0.3).with_corruption_rate(0.3));
+
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4];
+            for i in 0..100u8 {
+                a.transfer(&[i; 4]).unwrap();
+                if let Ok(len) = b.poll_next(&mut buf) {
+                    received.push(buf[..len as usize].to_vec());
+                }
+            }
+            (received, *a.stats())
+        };
+
+        let (received, stats) = run();
+        assert_eq!(run(), (received.clone(), stats));
+        assert_eq!(received.len() as u32, stats.transferred - stats.dropped);
+        assert!(stats.dropped > 0 && stats.corrupted > 0);
+    }
+
+    #[test]
+    fn test_lossy_bus_disconnected() {
+        let clock = MockClock::new();
+        let (mut a, b) = LossyBus::pair(&clock, 1);
+        let mut buf = [0u8; 4];
+        a.set_connected(false);
+        a.transfer(&[1]).unwrap();
+        assert!(matches!(b.poll_next(&mut buf), Err(BusPollError::WouldBlock)));
+
+        a.set_connected(true);
+        a.transfer(&[2]).unwrap();
+        assert_eq!(b.poll_next(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 2);
+    }
+}
@@ -31,3 +31,32 @@ pub trait Clock {
     fn diff(&self, newer: Self::TInstant, older: Self::TInstant) -> TimeDiff;
     fn nanos(&self, instant: Self::TInstant) -> u64;
 }
+
+/// Measures the time elapsed since it was started, even beyond the range of
+/// the instants of the clock, by adding up the time between polls. It must be
+/// polled more often than the clock wraps around, which is the case when
+/// polled from the main loop.
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch<I> {
+    last_instant: I,
+    elapsed: Duration,
+}
+
+impl<I: Copy> Stopwatch<I> {
+    pub fn start<C: Clock<TInstant = I>>(clock: &C) -> Self {
+        Self {
+            last_instant: clock.current_instant(),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Returns the time elapsed since the stopwatch was started.
+    pub fn elapsed<C: Clock<TInstant = I>>(&mut self, clock: &C) -> Duration {
+        let now = clock.current_instant();
+        if let TimeDiff::Forward(elapsed) = clock.diff(now, self.last_instant) {
+            self.elapsed += elapsed;
+        }
+        self.last_instant = now;
+        self.elapsed
+    }
+}
@@ -3,12 +3,22 @@ use core::fmt::{Binary, Debug};
 pub trait BitMatrixLayout {
     type ColType: Copy + Default + Debug + Binary;
     const ZERO: Self::ColType;
+    /// A row with the bits of every column set.
+    const ONES: Self::ColType;
 
     /// Sets the state of the requested bit at the given column, and
     /// returns a value indicating whether the value has actually
     /// changed from the previous one.
     fn set_state(elem: &mut Self::ColType, col: u8, value: bool) -> bool;
     fn get_state(elem: Self::ColType, col: u8) -> bool;
+
+    /// Returns the bits of a row, widened so that rows of any width can be
+    /// combined.
+    fn to_bits(elem: Self::ColType) -> u128;
+
+    /// Returns a row from the given bits, ignoring the ones beyond the last
+    /// column.
+    fn from_bits(bits: u128) -> Self::ColType;
 }
 pub struct ColBitMatrixLayout<const COLS: u8> {}
 
@@ -23,9 +33,12 @@ fn gen_bit_matrix_layout_impls() {
             _ => "u128",
         };
 
+        let ones = if bits == 128 { u128::MAX } else { (1u128 << bits) - 1 };
+
         crabtime::output! {
             impl BitMatrixLayout for ColBitMatrixLayout<{{bits}}> {
                 const ZERO: {{typ}} = 0;
+                const ONES: {{typ}} = {{ones}};
                 type ColType = {{typ}};
 
                 #[inline(always)]
@@ -44,6 +57,16 @@ fn gen_bit_matrix_layout_impls() {
                 fn get_state(elem: Self::ColType, col: u8) -> bool {
                     (elem & (1 << col)) > 0
                 }
+
+                #[inline(always)]
+                fn to_bits(elem: Self::ColType) -> u128 {
+                    elem as u128
+                }
+
+                #[inline(always)]
+                fn from_bits(bits: u128) -> Self::ColType {
+                    (bits & Self::ONES as u128) as {{typ}}
+                }
             }
         }
     }
@@ -51,7 +74,7 @@ fn gen_bit_matrix_layout_impls() {
 
 gen_bit_matrix_layout_impls!();
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BitMatrix<const ROWS: usize, const COLS: u8>
 where
     ColBitMatrixLayout<COLS>: BitMatrixLayout,
@@ -69,6 +92,20 @@ where
         }
     }
 
+    /// Returns a matrix with every bit set.
+    pub const fn filled() -> Self {
+        Self {
+            buf: [ColBitMatrixLayout::<COLS>::ONES; ROWS],
+        }
+    }
+
+    /// Builds a matrix from the bits of each row, where the bit `n` of each
+    /// row is the value of the column `n`. Meant for declaring constant
+    /// matrices, e.g `BitMatrix::<2, 3>::from_rows([0b111, 0b101])`.
+    pub const fn from_rows(rows: [<ColBitMatrixLayout<COLS> as BitMatrixLayout>::ColType; ROWS]) -> Self {
+        Self { buf: rows }
+    }
+
     #[inline(always)]
     pub fn get_value(&self, row: usize, col: u8) -> bool {
         assert!(row < ROWS, "Row out of bounds");
@@ -84,4 +121,108 @@ where
 
         <ColBitMatrixLayout<COLS> as BitMatrixLayout>::set_state(&mut self.buf[row], col, value)
     }
+
+    /// Returns the bits of a row, where the bit `n` is the value of the
+    /// column `n`.
+    #[inline(always)]
+    pub fn row(&self, row: usize) -> <ColBitMatrixLayout<COLS> as BitMatrixLayout>::ColType {
+        assert!(row < ROWS, "Row out of bounds");
+        self.buf[row]
+    }
+
+    /// Like [`row`](Self::row), but widened to 128 bits.
+    #[inline(always)]
+    pub fn row_bits(&self, row: usize) -> u128 {
+        <ColBitMatrixLayout<COLS> as BitMatrixLayout>::to_bits(self.row(row))
+    }
+
+    /// Sets every column of a row at once, returning whether any of them has
+    /// changed.
+    #[inline(always)]
+    pub fn set_row(&mut self, row: usize, bits: <ColBitMatrixLayout<COLS> as BitMatrixLayout>::ColType) -> bool {
+        self.set_row_bits(row, <ColBitMatrixLayout<COLS> as BitMatrixLayout>::to_bits(bits), 0, COLS) != 0
+    }
+
+    /// Sets the `cols` columns of a row starting at `col_offset` from the
+    /// first `cols` bits of `bits`, leaving the rest of the columns as they
+    /// were, and returns the bits of the row that have changed.
+    pub fn set_row_bits(&mut self, row: usize, bits: u128, col_offset: u8, cols: u8) -> u128 {
+        assert!(row < ROWS, "Row out of bounds");
+        assert!(col_offset as u16 + cols as u16 <= COLS as u16, "Cols out of bounds");
+
+        let mask = if cols == 128 { u128::MAX } else { ((1u128 << cols) - 1) << col_offset };
+        let prev = self.row_bits(row);
+        let next = (prev & !mask) | ((bits << col_offset) & mask);
+        self.buf[row] = <ColBitMatrixLayout<COLS> as BitMatrixLayout>::from_bits(next);
+        prev ^ next
+    }
+
+    /// Copies every row of `src` into the columns of this matrix starting at
+    /// `col_offset`, e.g for placing the matrix of one side of a split
+    /// keyboard into the one of the whole layout. Returns whether any bit has
+    /// changed. The rows of `src` beyond the last one of this matrix are
+    /// ignored.
+    pub fn copy_from<const SRC_ROWS: usize, const SRC_COLS: u8>(
+        &mut self,
+        src: &BitMatrix<SRC_ROWS, SRC_COLS>,
+        col_offset: u8,
+    ) -> bool
+    where
+        ColBitMatrixLayout<SRC_COLS>: BitMatrixLayout,
+    {
+        let mut changed = false;
+        for row in 0..ROWS.min(SRC_ROWS) {
+            changed |= self.set_row_bits(row, src.row_bits(row), col_offset, SRC_COLS) != 0;
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filled_sets_only_existing_cols() {
+        let matrix = BitMatrix::<2, 6>::filled();
+        assert_eq!(matrix.buf, [0b111111, 0b111111]);
+
+        let matrix = BitMatrix::<1, 128>::filled();
+        assert_eq!(matrix.buf, [u128::MAX]);
+    }
+
+    #[test]
+    fn from_rows_sets_cols_by_bit_index() {
+        let matrix = BitMatrix::<2, 3>::from_rows([0b110, 0b001]);
+
+        assert!(!matrix.get_value(0, 0));
+        assert!(matrix.get_value(0, 1));
+        assert!(matrix.get_value(0, 2));
+        assert!(matrix.get_value(1, 0));
+        assert!(!matrix.get_value(1, 1));
+        assert!(!matrix.get_value(1, 2));
+    }
+
+    #[test]
+    fn set_row_ignores_extra_cols() {
+        let mut matrix = BitMatrix::<2, 3>::new();
+
+        assert!(matrix.set_row(1, 0b1111_1101));
+        assert_eq!(matrix.row(1), 0b101);
+        assert!(!matrix.set_row(1, 0b101));
+        assert_eq!(matrix.row(0), 0);
+    }
+
+    #[test]
+    fn copy_from_keeps_other_cols() {
+        let mut layout = BitMatrix::<2, 12>::from_rows([0b000000_000001, 0b000000_100000]);
+        let side = BitMatrix::<2, 6>::from_rows([0b100001, 0b000010]);
+
+        assert!(layout.copy_from(&side, 6));
+        assert_eq!(layout.buf, [0b100001_000001, 0b000010_100000]);
+        assert!(!layout.copy_from(&side, 6));
+
+        assert_eq!(layout.set_row_bits(0, 0b000001, 6, 6), 0b100000_000000);
+        assert_eq!(layout.row(0), 0b000001_000001);
+    }
 }
@@ -0,0 +1,11 @@
+//! Supervision of the firmware by a hardware watchdog, that resets the MCU
+//! unless it is fed often enough.
+
+/// A watchdog that has been started, and that must be fed before it expires.
+/// It is fed through a shared reference, so that a single watchdog can be
+/// handed to whatever tells whether the firmware is making progress, like the
+/// split link.
+pub trait Watchdog {
+    /// Restarts the countdown of the watchdog.
+    fn feed(&self);
+}
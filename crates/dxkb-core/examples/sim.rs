@@ -0,0 +1,66 @@
+//! A small single-piece keyboard running on the desktop, on top of the
+//! simulated hardware of `dxkb_core::sim`. Keys are pressed and released by
+//! writing `p <row> <col>` and `r <row> <col>` lines to the standard input,
+//! and the HID reports are printed as they change:
+//!
+//! ```text
+//! cargo run -p dxkb-core --example sim --no-default-features -F stm32f411,sim
+//! ```
+
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+#![feature(macro_metavar_expr_concat)]
+
+use std::thread;
+use std::time::Duration;
+
+use dxkb_core::keyboard::{AlwaysMaster, UnibodyKeyboard, UnibodyKeyboardLayout};
+use dxkb_core::keys::DefaultKey;
+use dxkb_core::sim::{SimClock, SimHid, SimMatrix, SimUsbBus};
+use dxkb_split_link::NullSplitBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+type Layout = UnibodyKeyboardLayout<DefaultKey, 2, 2, 3>;
+type Keyboard = UnibodyKeyboard<2, 2, 3, SimClock, SimHid, DefaultKey, SimMatrix<2, 3>, ()>;
+
+const LAYOUT: Layout = Layout::new(dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [  'A',  'B', f:LTPsh(1)],
+                [ LSft,  Spc,   Enter],
+            ]
+        },
+        {
+            name: "numbers",
+            parent: "base",
+            rows: [
+                [  '1',  '2',    *],
+                [    *,    *,  c:VUp],
+            ]
+        },
+    ]
+));
+
+fn main() {
+    let matrix = SimMatrix::new();
+    matrix.input().spawn_stdin();
+
+    let usb_alloc = UsbBusAllocator::new(SimUsbBus::new());
+    let mut device = UsbDeviceBuilder::new(&usb_alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let mut kb = Keyboard::builder()
+        .clock(SimClock::new())
+        .hid(SimHid::new())
+        .layout(LAYOUT)
+        .matrix(matrix)
+        .split_bus(NullSplitBus)
+        .master_sense(AlwaysMaster)
+        .build();
+
+    loop {
+        kb.poll(&mut (), &mut device);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
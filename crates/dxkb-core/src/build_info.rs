@@ -0,0 +1,87 @@
+//! Metadata of the firmware build, for identifying the exact revision and
+//! features of a device in the field.
+//!
+//! The metadata is taken from environment variables at compile time, which
+//! the build script of the target is expected to set:
+//!  - `DXKB_BUILD_GIT_HASH`: the commit the firmware was built from, with a
+//!    `-dirty` suffix if the tree had uncommitted changes.
+//!  - `DXKB_BUILD_DATE`: the UTC date of the build (YYYY-MM-DD).
+//!  - `DXKB_BUILD_FEATURES`: comma separated list of the cargo features
+//!    enabled in the target.
+//!
+//! Any of them that is not set is reported as "unknown".
+
+use core::fmt::Write;
+
+use dxkb_common::dev_info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_date: &'static str,
+    pub features: &'static str,
+}
+
+/// Builds the [`BuildInfo`] of the crate where it is invoked.
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info::BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: match option_env!("DXKB_BUILD_GIT_HASH") {
+                Some(v) => v,
+                None => "unknown",
+            },
+            build_date: match option_env!("DXKB_BUILD_DATE") {
+                Some(v) => v,
+                None => "unknown",
+            },
+            features: match option_env!("DXKB_BUILD_FEATURES") {
+                Some(v) => v,
+                None => "unknown",
+            },
+        }
+    };
+}
+
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl BuildInfo {
+    /// Writes the info into the buffer as `key=value` lines, returning the
+    /// written length, or None if the buffer is too small.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut writer = SliceWriter { buf: out, len: 0 };
+        write!(
+            writer,
+            "name={}\nversion={}\ngit={}\ndate={}\nfeatures={}\n",
+            self.name, self.version, self.git_hash, self.build_date, self.features
+        )
+        .ok()?;
+        Some(writer.len)
+    }
+
+    /// Logs the info, meant to be called on startup.
+    pub fn log_banner(&self) {
+        dev_info!("{} v{} ({}, built {})", self.name, self.version, self.git_hash, self.build_date);
+        dev_info!(" - Features: {}", self.features);
+    }
+}
@@ -0,0 +1,346 @@
+//! Typed builder of [`SplitKeyboard`]s, so that targets wire up their
+//! keyboard by naming each component instead of passing them all at once, in
+//! an order that is easy to get wrong:
+//!
+//! ```ignore
+//! let kb = TKeyboard::builder()
+//!     .clock(clock)
+//!     .hid(usb_feature_kb)
+//!     .layout(layout::LAYOUT)
+//!     .matrix(matrix)
+//!     .split_bus(split_bus)
+//!     .master_sense(master_tester)
+//!     .rollover_hook(on_rollover)
+//!     .build();
+//! ```
+//!
+//! The components that the keyboard can't work without (the clock, the HID,
+//! the layout, the matrix, the split bus and the master sense) are tracked in
+//! the type of the builder, so building the keyboard without any of them, or
+//! setting any of them twice, doesn't compile. The key filters can be left
+//! out if they have a default, and so can the hooks and the rest of settings,
+//! which are applied as if they were set right after building the keyboard.
+//!
+//! [`SplitKeyboard`]: crate::keyboard::SplitKeyboard
+
+use core::marker::PhantomData;
+use core::time::Duration;
+
+use crate::keyboard::{
+    HostLedsHook, LowVoltageHook, OverloadPolicy, PollOrder, RolloverHook, UnitKeyHook, UserMsgHook,
+};
+use crate::remap::OsRemaps;
+
+/// A component that hasn't been given to a [`KeyboardBuilder`] yet.
+pub struct Missing;
+
+/// A component already given to a [`KeyboardBuilder`].
+pub struct Set<T>(T);
+
+/// Implemented by the components of a [`KeyboardBuilder`] that are required
+/// for building the keyboard, once they are set.
+#[diagnostic::on_unimplemented(
+    message = "the keyboard is missing a component of type `{T}`",
+    label = "set it in the builder before calling `build`"
+)]
+pub trait Required<T> {
+    fn take(self) -> T;
+}
+
+impl<T> Required<T> for Set<T> {
+    fn take(self) -> T {
+        self.0
+    }
+}
+
+/// Implemented by the components of a [`KeyboardBuilder`] that can be left
+/// out, taking their default.
+#[diagnostic::on_unimplemented(
+    message = "the keyboard is missing a component of type `{T}`, which has no default",
+    label = "set it in the builder before calling `build`"
+)]
+pub trait Optional<T> {
+    fn take_or_default(self) -> T;
+}
+
+impl<T> Optional<T> for Set<T> {
+    fn take_or_default(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Default> Optional<T> for Missing {
+    fn take_or_default(self) -> T {
+        T::default()
+    }
+}
+
+/// The optional settings of a keyboard, each one left as the keyboard
+/// defaults to unless set in the [`KeyboardBuilder`].
+pub struct KeyboardSettings<User, U> {
+    pub(crate) poll_order: Option<PollOrder>,
+    pub(crate) overload_policy: Option<OverloadPolicy>,
+    pub(crate) max_pressed_keys: Option<usize>,
+    pub(crate) host_leds_settle_time: Option<Duration>,
+    pub(crate) os_remaps: Option<OsRemaps>,
+    pub(crate) low_voltage_warning: Option<(u16, LowVoltageHook<User>)>,
+    pub(crate) rollover_hook: Option<RolloverHook<User>>,
+    pub(crate) host_leds_hook: Option<HostLedsHook<User>>,
+    pub(crate) unit_key_hook: Option<UnitKeyHook<User>>,
+    pub(crate) user_msg_hook: Option<UserMsgHook<User, U>>,
+}
+
+impl<User, U> Default for KeyboardSettings<User, U> {
+    fn default() -> Self {
+        Self {
+            poll_order: None,
+            overload_policy: None,
+            max_pressed_keys: None,
+            host_leds_settle_time: None,
+            os_remaps: None,
+            low_voltage_warning: None,
+            rollover_hook: None,
+            host_leds_hook: None,
+            unit_key_hook: None,
+            user_msg_hook: None,
+        }
+    }
+}
+
+/// Everything a [`KeyboardBuilder`] collected for building the keyboard.
+pub struct KeyboardParts<Kb: BuildableKeyboard> {
+    pub(crate) clock: Kb::Clock,
+    pub(crate) hid: Kb::Hid,
+    pub(crate) layout: Kb::Layout,
+    pub(crate) matrix: Kb::Matrix,
+    pub(crate) split_bus: Kb::SplitBus,
+    pub(crate) master_sense: Kb::MasterSense,
+    pub(crate) filter: Kb::Filter,
+    pub(crate) settings: KeyboardSettings<Kb::User, Kb::UserMsg>,
+}
+
+/// A keyboard that can be built with a [`KeyboardBuilder`]. Implemented by
+/// every [`SplitKeyboard`](crate::keyboard::SplitKeyboard).
+pub trait BuildableKeyboard: Sized {
+    type Clock;
+    type Hid;
+    type Layout;
+    type Matrix;
+    type SplitBus;
+    type MasterSense;
+    type Filter;
+    type User;
+    /// The messages of the target exchanged through the split link.
+    type UserMsg;
+
+    fn from_parts(parts: KeyboardParts<Self>) -> Self;
+}
+
+/// Builder of a keyboard of type `Kb`. Each of the other type parameters is
+/// either [`Missing`] or [`Set`], depending on whether the component has been
+/// given already. See the [module docs](self).
+pub struct KeyboardBuilder<
+    Kb: BuildableKeyboard,
+    Clk = Missing,
+    Hid = Missing,
+    Layout = Missing,
+    Matrix = Missing,
+    Bus = Missing,
+    Master = Missing,
+    Filter = Missing,
+> {
+    clock: Clk,
+    hid: Hid,
+    layout: Layout,
+    matrix: Matrix,
+    split_bus: Bus,
+    master_sense: Master,
+    filter: Filter,
+    settings: KeyboardSettings<Kb::User, Kb::UserMsg>,
+    _kb: PhantomData<Kb>,
+}
+
+impl<Kb: BuildableKeyboard> KeyboardBuilder<Kb> {
+    /// Creates a builder without any of the components set.
+    pub fn new() -> Self {
+        Self {
+            clock: Missing,
+            hid: Missing,
+            layout: Missing,
+            matrix: Missing,
+            split_bus: Missing,
+            master_sense: Missing,
+            filter: Missing,
+            settings: KeyboardSettings::default(),
+            _kb: PhantomData,
+        }
+    }
+}
+
+impl<Kb: BuildableKeyboard> Default for KeyboardBuilder<Kb> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Defines the setter of a required component, only available while it is
+/// still missing, that moves the rest of components to the builder returned.
+/// The components before and after it are given as `Param: field` pairs, in
+/// the order of the type parameters of the builder.
+macro_rules! component_setter {
+    (
+        $(#[$meta:meta])*
+        $name:ident: $ty:ident,
+        [$($before:ident: $before_field:ident),*],
+        [$($after:ident: $after_field:ident),*]
+    ) => {
+        impl<Kb: BuildableKeyboard, $($before,)* $($after,)*>
+            KeyboardBuilder<Kb, $($before,)* Missing, $($after,)*>
+        {
+            $(#[$meta])*
+            pub fn $name(self, $name: Kb::$ty) -> KeyboardBuilder<Kb, $($before,)* Set<Kb::$ty>, $($after,)*> {
+                KeyboardBuilder {
+                    $($before_field: self.$before_field,)*
+                    $name: Set($name),
+                    $($after_field: self.$after_field,)*
+                    settings: self.settings,
+                    _kb: PhantomData,
+                }
+            }
+        }
+    };
+}
+
+component_setter!(
+    /// Sets the clock the keyboard measures the time with.
+    clock: Clock,
+    [],
+    [Hid: hid, Layout: layout, Matrix: matrix, Bus: split_bus, Master: master_sense, Filter: filter]
+);
+component_setter!(
+    /// Sets the HID the keys are reported through.
+    hid: Hid,
+    [Clk: clock],
+    [Layout: layout, Matrix: matrix, Bus: split_bus, Master: master_sense, Filter: filter]
+);
+component_setter!(
+    /// Sets the layout of both sides of the keyboard.
+    layout: Layout,
+    [Clk: clock, Hid: hid],
+    [Matrix: matrix, Bus: split_bus, Master: master_sense, Filter: filter]
+);
+component_setter!(
+    /// Sets the matrix of the current side.
+    matrix: Matrix,
+    [Clk: clock, Hid: hid, Layout: layout],
+    [Bus: split_bus, Master: master_sense, Filter: filter]
+);
+component_setter!(
+    /// Sets the bus of the link with the other side. Single-piece keyboards
+    /// take a [`NullSplitBus`](dxkb_split_link::NullSplitBus).
+    split_bus: SplitBus,
+    [Clk: clock, Hid: hid, Layout: layout, Matrix: matrix],
+    [Master: master_sense, Filter: filter]
+);
+component_setter!(
+    /// Sets what tells whether the current side is the master. Single-piece
+    /// keyboards take an [`AlwaysMaster`](crate::keyboard::AlwaysMaster).
+    master_sense: MasterSense,
+    [Clk: clock, Hid: hid, Layout: layout, Matrix: matrix, Bus: split_bus],
+    [Filter: filter]
+);
+component_setter!(
+    /// Sets the filters the key changes go through. Can be left out if they
+    /// have a default. See [`crate::filter`].
+    filter: Filter,
+    [Clk: clock, Hid: hid, Layout: layout, Matrix: matrix, Bus: split_bus, Master: master_sense],
+    []
+);
+
+impl<Kb: BuildableKeyboard, Clk, Hid, Layout, Matrix, Bus, Master, Filter>
+    KeyboardBuilder<Kb, Clk, Hid, Layout, Matrix, Bus, Master, Filter>
+{
+    /// See [`SplitKeyboard::set_poll_order`](crate::keyboard::SplitKeyboard::set_poll_order).
+    pub fn poll_order(mut self, order: PollOrder) -> Self {
+        self.settings.poll_order = Some(order);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_overload_policy`](crate::keyboard::SplitKeyboard::set_overload_policy).
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.settings.overload_policy = Some(policy);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_max_pressed_keys`](crate::keyboard::SplitKeyboard::set_max_pressed_keys).
+    pub fn max_pressed_keys(mut self, max: usize) -> Self {
+        self.settings.max_pressed_keys = Some(max);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_host_leds_settle_time`](crate::keyboard::SplitKeyboard::set_host_leds_settle_time).
+    pub fn host_leds_settle_time(mut self, settle_time: Duration) -> Self {
+        self.settings.host_leds_settle_time = Some(settle_time);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_os_remaps`](crate::keyboard::SplitKeyboard::set_os_remaps).
+    pub fn os_remaps(mut self, remaps: OsRemaps) -> Self {
+        self.settings.os_remaps = Some(remaps);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_low_voltage_warning`](crate::keyboard::SplitKeyboard::set_low_voltage_warning).
+    pub fn low_voltage_warning(mut self, threshold_mv: u16, hook: LowVoltageHook<Kb::User>) -> Self {
+        self.settings.low_voltage_warning = Some((threshold_mv, hook));
+        self
+    }
+
+    /// See [`SplitKeyboard::set_rollover_hook`](crate::keyboard::SplitKeyboard::set_rollover_hook).
+    pub fn rollover_hook(mut self, hook: RolloverHook<Kb::User>) -> Self {
+        self.settings.rollover_hook = Some(hook);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_host_leds_hook`](crate::keyboard::SplitKeyboard::set_host_leds_hook).
+    pub fn host_leds_hook(mut self, hook: HostLedsHook<Kb::User>) -> Self {
+        self.settings.host_leds_hook = Some(hook);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_unit_key_hook`](crate::keyboard::SplitKeyboard::set_unit_key_hook).
+    pub fn unit_key_hook(mut self, hook: UnitKeyHook<Kb::User>) -> Self {
+        self.settings.unit_key_hook = Some(hook);
+        self
+    }
+
+    /// See [`SplitKeyboard::set_user_msg_hook`](crate::keyboard::SplitKeyboard::set_user_msg_hook).
+    pub fn user_msg_hook(mut self, hook: UserMsgHook<Kb::User, Kb::UserMsg>) -> Self {
+        self.settings.user_msg_hook = Some(hook);
+        self
+    }
+
+    /// Builds the keyboard. Only compiles once every required component has
+    /// been set.
+    pub fn build(self) -> Kb
+    where
+        Clk: Required<Kb::Clock>,
+        Hid: Required<Kb::Hid>,
+        Layout: Required<Kb::Layout>,
+        Matrix: Required<Kb::Matrix>,
+        Bus: Required<Kb::SplitBus>,
+        Master: Required<Kb::MasterSense>,
+        Filter: Optional<Kb::Filter>,
+    {
+        Kb::from_parts(KeyboardParts {
+            clock: self.clock.take(),
+            hid: self.hid.take(),
+            layout: self.layout.take(),
+            matrix: self.matrix.take(),
+            split_bus: self.split_bus.take(),
+            master_sense: self.master_sense.take(),
+            filter: self.filter.take_or_default(),
+            settings: self.settings,
+        })
+    }
+}
@@ -0,0 +1,522 @@
+//! Import and export of the whole persistent config as a single binary blob,
+//! for backing it up from the host and restoring it later, possibly into a
+//! newer firmware version.
+//!
+//! The blob has the following layout:
+//!
+//! ```text
+//! +-------------+-------------+-----------+-------------+---------+----------------+
+//! | Magic(4*u8) | Version(u8) | Count(u8) | Len(u16 LE) | Entries | CRC-16(u16 LE) |
+//! +-------------+-------------+-----------+-------------+---------+----------------+
+//! ```
+//!
+//! Where `Len` is the length of the whole blob, CRC included, and each entry
+//! is encoded as `Key(u8) | Len(u16 LE) | Value(Len * u8)`. The CRC covers
+//! everything before it.
+//!
+//! Blobs exported from an older schema version are upgraded entry by entry on
+//! import through a [`ConfigUpgrade`] implementation, one version at a time.
+//! Blobs from a newer version are rejected.
+//!
+//! The blob is transferred through its own vendor-defined HID interface (see
+//! [`ConfigHidFeature`]) so that it doesn't get mixed with the debug log
+//! stream. The same interface also serves the [`BuildInfo`] of the firmware.
+
+use crc::Table;
+use dxkb_common::{dev_info, dev_warn, util};
+use dxkb_peripheral::flash_config::{
+    FLASH_CONFIG_MAX_LIVE_LEN, FLASH_CONFIG_MAX_VALUE_LEN, FlashConfig, FlashConfigError,
+};
+use usb_device::{bus::{UsbBus, UsbBusAllocator}, device::UsbDevice};
+use usbd_hid::hid_class::{HIDClass, HidClassSettings};
+
+use crate::{build_info::BuildInfo, usb::UsbFeature};
+
+/// Current version of the config schema. Must be bumped whenever the format of
+/// any persisted value changes, and a step for upgrading from the previous
+/// version must be added to the [`ConfigUpgrade`] implementation in use.
+pub const CONFIG_SCHEMA_VERSION: u8 = 1;
+
+const CONFIG_BLOB_MAGIC: [u8; 4] = *b"DXKC";
+const CONFIG_BLOB_HEADER_LEN: usize = 8;
+const CONFIG_BLOB_CRC_LEN: usize = 2;
+const CONFIG_BLOB_ENTRY_HEADER_LEN: usize = 3;
+
+/// Maximum length of a config blob. All the live values of the flash config
+/// are guaranteed to fit, since each blob entry is smaller than its flash
+/// record.
+pub const CONFIG_BLOB_MAX_LEN: usize =
+    CONFIG_BLOB_HEADER_LEN + FLASH_CONFIG_MAX_LIVE_LEN + CONFIG_BLOB_CRC_LEN;
+
+const CONFIG_BLOB_CRC: crc::Crc<u16, Table<1>> = crc::Crc::<u16, Table<1>>::new(&crc::CRC_16_USB);
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConfigBlobError {
+    BufferTooSmall,
+    InvalidMagic,
+    InvalidChecksum,
+    Malformed,
+    /// The blob was exported from a newer schema version than this firmware
+    /// supports.
+    UnsupportedVersion(u8),
+    /// The upgrade from the given version failed.
+    UpgradeFailed(u8),
+    Flash(FlashConfigError),
+}
+
+impl ConfigBlobError {
+    /// Status code of the error when reported through the config HID
+    /// interface.
+    pub const fn status_code(&self) -> u8 {
+        match self {
+            ConfigBlobError::BufferTooSmall => 1,
+            ConfigBlobError::InvalidMagic => 2,
+            ConfigBlobError::InvalidChecksum => 3,
+            ConfigBlobError::Malformed => 4,
+            ConfigBlobError::UnsupportedVersion(_) => 5,
+            ConfigBlobError::UpgradeFailed(_) => 6,
+            ConfigBlobError::Flash(_) => 7,
+        }
+    }
+}
+
+impl From<FlashConfigError> for ConfigBlobError {
+    fn from(value: FlashConfigError) -> Self {
+        ConfigBlobError::Flash(value)
+    }
+}
+
+/// Hooks for upgrading config values exported by older firmware versions.
+pub trait ConfigUpgrade {
+    /// Upgrades the value of the given key from the schema version
+    /// `from_version` to `from_version + 1`, writing the result into `out` and
+    /// returning its length. Returns None if the value must be dropped.
+    fn upgrade(&self, from_version: u8, key: u8, value: &[u8], out: &mut [u8]) -> Option<usize>;
+}
+
+/// Keeps every value as it is. Only valid while no schema change has been
+/// made yet.
+impl ConfigUpgrade for () {
+    fn upgrade(&self, _from_version: u8, _key: u8, value: &[u8], out: &mut [u8]) -> Option<usize> {
+        out[..value.len()].copy_from_slice(value);
+        Some(value.len())
+    }
+}
+
+struct BlobEntryIter<'a> {
+    buf: &'a [u8],
+    malformed: bool,
+}
+
+impl<'a> Iterator for BlobEntryIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        if self.buf.len() < CONFIG_BLOB_ENTRY_HEADER_LEN {
+            self.malformed = true;
+            return None;
+        }
+
+        let key = self.buf[0];
+        let len = u16::from_le_bytes([self.buf[1], self.buf[2]]) as usize;
+        let end = CONFIG_BLOB_ENTRY_HEADER_LEN + len;
+        if len > FLASH_CONFIG_MAX_VALUE_LEN || self.buf.len() < end {
+            self.malformed = true;
+            return None;
+        }
+
+        let value = &self.buf[CONFIG_BLOB_ENTRY_HEADER_LEN..end];
+        self.buf = &self.buf[end..];
+        Some((key, value))
+    }
+}
+
+/// Serializes all the values of the config into the buffer, returning the
+/// length of the blob.
+pub fn export_config(config: &FlashConfig, out: &mut [u8]) -> Result<usize, ConfigBlobError> {
+    let mut len = CONFIG_BLOB_HEADER_LEN;
+    let mut count: u8 = 0;
+    let mut overflow = false;
+
+    if out.len() < CONFIG_BLOB_HEADER_LEN + CONFIG_BLOB_CRC_LEN {
+        return Err(ConfigBlobError::BufferTooSmall);
+    }
+
+    config.for_each(|key, value| {
+        let entry_len = CONFIG_BLOB_ENTRY_HEADER_LEN + value.len();
+        if overflow || len + entry_len + CONFIG_BLOB_CRC_LEN > out.len() {
+            overflow = true;
+            return;
+        }
+
+        out[len] = key;
+        out[len + 1..len + 3].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        out[len + 3..len + entry_len].copy_from_slice(value);
+        len += entry_len;
+        count += 1;
+    });
+
+    if overflow {
+        return Err(ConfigBlobError::BufferTooSmall);
+    }
+
+    let total_len = len + CONFIG_BLOB_CRC_LEN;
+    out[0..4].copy_from_slice(&CONFIG_BLOB_MAGIC);
+    out[4] = CONFIG_SCHEMA_VERSION;
+    out[5] = count;
+    out[6..8].copy_from_slice(&(total_len as u16).to_le_bytes());
+
+    let crc = CONFIG_BLOB_CRC.checksum(&out[..len]);
+    out[len..total_len].copy_from_slice(&crc.to_le_bytes());
+    Ok(total_len)
+}
+
+/// Where the values of an imported blob are written to.
+pub trait ConfigStore {
+    fn write(&mut self, key: u8, value: &[u8]) -> Result<(), FlashConfigError>;
+}
+
+impl ConfigStore for FlashConfig {
+    fn write(&mut self, key: u8, value: &[u8]) -> Result<(), FlashConfigError> {
+        FlashConfig::write(self, key, value)
+    }
+}
+
+/// Upgrades the value of an entry from the schema version `version` to the
+/// current one, writing the result into `out`. Returns its length, or None if
+/// the value must be dropped.
+fn upgrade_entry<U: ConfigUpgrade>(
+    upgrade: &U,
+    version: u8,
+    key: u8,
+    value: &[u8],
+    out: &mut [u8; FLASH_CONFIG_MAX_VALUE_LEN],
+) -> Result<Option<usize>, ConfigBlobError> {
+    let mut next = [0u8; FLASH_CONFIG_MAX_VALUE_LEN];
+    let mut len = value.len();
+    out[..len].copy_from_slice(value);
+
+    for from_version in version..CONFIG_SCHEMA_VERSION {
+        match upgrade.upgrade(from_version, key, &out[..len], &mut next) {
+            Some(new_len) if new_len <= FLASH_CONFIG_MAX_VALUE_LEN => {
+                out[..new_len].copy_from_slice(&next[..new_len]);
+                len = new_len;
+            }
+            Some(_) => return Err(ConfigBlobError::UpgradeFailed(from_version)),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(len))
+}
+
+/// Validates the blob and writes all its values into the config, upgrading
+/// them first if they come from an older schema version. Keys that are not
+/// present in the blob are left untouched. Returns the number of imported
+/// values.
+///
+/// Nothing is written unless every value can be upgraded, so a blob is either
+/// imported whole or not at all, short of an error of the flash itself.
+pub fn import_config<S: ConfigStore, U: ConfigUpgrade>(
+    config: &mut S,
+    blob: &[u8],
+    upgrade: &U,
+) -> Result<usize, ConfigBlobError> {
+    if blob.len() < CONFIG_BLOB_HEADER_LEN + CONFIG_BLOB_CRC_LEN {
+        return Err(ConfigBlobError::Malformed);
+    }
+
+    if blob[0..4] != CONFIG_BLOB_MAGIC {
+        return Err(ConfigBlobError::InvalidMagic);
+    }
+
+    let version = blob[4];
+    let count = blob[5] as usize;
+    let total_len = u16::from_le_bytes([blob[6], blob[7]]) as usize;
+    if total_len != blob.len() {
+        return Err(ConfigBlobError::Malformed);
+    }
+
+    let crc_offset = total_len - CONFIG_BLOB_CRC_LEN;
+    let crc = u16::from_le_bytes([blob[crc_offset], blob[crc_offset + 1]]);
+    if CONFIG_BLOB_CRC.checksum(&blob[..crc_offset]) != crc {
+        return Err(ConfigBlobError::InvalidChecksum);
+    }
+
+    if version > CONFIG_SCHEMA_VERSION {
+        return Err(ConfigBlobError::UnsupportedVersion(version));
+    }
+
+    let entries = || BlobEntryIter { buf: &blob[CONFIG_BLOB_HEADER_LEN..crc_offset], malformed: false };
+
+    // Check the whole blob before writing anything, so that a malformed blob
+    // doesn't end up half imported.
+    let mut check = entries();
+    if check.by_ref().count() != count || check.malformed {
+        return Err(ConfigBlobError::Malformed);
+    }
+
+    if version < CONFIG_SCHEMA_VERSION {
+        dev_info!("Upgrading config blob from version {} to {}", version, CONFIG_SCHEMA_VERSION);
+    }
+
+    // Same for the upgrades. There's no room for keeping every upgraded
+    // value until they are written, so they are upgraded again below.
+    let mut value = [0u8; FLASH_CONFIG_MAX_VALUE_LEN];
+    for (key, blob_value) in entries() {
+        upgrade_entry(upgrade, version, key, blob_value, &mut value)?;
+    }
+
+    let mut imported = 0;
+    for (key, blob_value) in entries() {
+        let Some(len) = upgrade_entry(upgrade, version, key, blob_value, &mut value)? else {
+            dev_warn!("Config key {:#04x} dropped during upgrade", key);
+            continue;
+        };
+
+        config.write(key, &value[..len])?;
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+const CONFIG_EP_DESCRIPTOR: [u8; 23] = [
+    0x0b, 0x00, 0x00, 0x00, 0x00,  // USAGE (Generic Desktop:Undefined)
+    0x06, 0x01, 0xff,              // USAGE_PAGE (Vendor Defined Page 2)
+    0xa1, 0x01,                    // COLLECTION (Application)
+    0x75, 0x08,                    //   REPORT_SIZE (8)
+    0x95, 0x40,                    //   REPORT_COUNT (64)
+    0x81, 0x02,                    //   INPUT (Data,Var,Abs)
+    0x75, 0x08,                    //   REPORT_SIZE (8)
+    0x95, 0x40,                    //   REPORT_COUNT (64)
+    0x91, 0x02,                    //   OUTPUT (Data,Var,Abs)
+    0xc0                           // END_COLLECTION
+];
+
+/// Starts an export. The device answers with a sequence of
+/// [`CONFIG_CMD_EXPORT`] responses carrying the blob.
+pub const CONFIG_CMD_EXPORT: u8 = 0x01;
+/// Starts an import. Followed by the length of the blob (u16 LE).
+pub const CONFIG_CMD_IMPORT_BEGIN: u8 = 0x02;
+/// A chunk of the blob being imported. Followed by its offset (u16 LE), its
+/// length (u8) and the data.
+pub const CONFIG_CMD_IMPORT_DATA: u8 = 0x03;
+/// Applies the imported blob, once all of it has been sent.
+pub const CONFIG_CMD_IMPORT_COMMIT: u8 = 0x04;
+/// Requests the build info. The device answers with a sequence of
+/// [`CONFIG_CMD_BUILD_INFO`] responses carrying [`BuildInfo::encode`].
+pub const CONFIG_CMD_BUILD_INFO: u8 = 0x05;
+
+/// Flag set in the command byte of every report sent by the device, which
+/// carry the command they respond to, a status (0 on success, or
+/// [`ConfigBlobError::status_code`]), an offset (u16 LE), a data length (u8)
+/// and the data.
+pub const CONFIG_RESPONSE_FLAG: u8 = 0x80;
+
+/// Status of a successful response.
+pub const CONFIG_STATUS_OK: u8 = 0;
+/// Status of a response to an unknown or out of sequence command.
+pub const CONFIG_STATUS_BAD_REQUEST: u8 = 0xff;
+
+/// Length of the reports of the config interface, in both directions.
+pub const CONFIG_REPORT_LEN: usize = 64;
+/// Length of the header of the responses, before their data.
+pub const CONFIG_RESPONSE_HEADER_LEN: usize = 5;
+/// Maximum amount of data carried by a single response.
+pub const CONFIG_RESPONSE_MAX_DATA_LEN: usize = CONFIG_REPORT_LEN - CONFIG_RESPONSE_HEADER_LEN;
+/// Length of the header of [`CONFIG_CMD_IMPORT_DATA`] requests, before their
+/// data.
+pub const CONFIG_IMPORT_DATA_HEADER_LEN: usize = 4;
+
+/// Requests received through the config interface that need access to the
+/// flash config, and must be completed by the target through
+/// [`ConfigHidFeature::export`] or [`ConfigHidFeature::import`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigRequest {
+    Export,
+    Import,
+}
+
+#[derive(Clone, Copy)]
+enum TransferState {
+    Idle,
+    /// Sending the contents of the buffer, as responses to the given command.
+    Sending { cmd: u8, len: usize, sent: usize },
+    Importing { len: usize, received: usize },
+}
+
+pub struct ConfigHidFeature<'a, B: UsbBus> {
+    hid: HIDClass<'a, B>,
+    state: TransferState,
+    pending_response: Option<[u8; CONFIG_REPORT_LEN]>,
+    buf: [u8; CONFIG_BLOB_MAX_LEN],
+    build_info: &'a BuildInfo,
+}
+
+impl<'a, B: UsbBus> ConfigHidFeature<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>, build_info: &'a BuildInfo) -> Self {
+        let config_ep = HIDClass::new_ep_in_with_settings(
+            alloc,
+            &CONFIG_EP_DESCRIPTOR,
+            1,
+            HidClassSettings::default(),
+        );
+
+        Self {
+            hid: config_ep,
+            state: TransferState::Idle,
+            pending_response: None,
+            buf: [0u8; CONFIG_BLOB_MAX_LEN],
+            build_info,
+        }
+    }
+
+    fn respond(&mut self, cmd: u8, status: u8) {
+        let mut report = [0u8; CONFIG_REPORT_LEN];
+        report[0] = cmd | CONFIG_RESPONSE_FLAG;
+        report[1] = status;
+        self.pending_response = Some(report);
+    }
+
+    /// Exports the config into the internal buffer, which is then sent to the
+    /// host over the following polls.
+    pub fn export(&mut self, config: &FlashConfig) {
+        match export_config(config, &mut self.buf) {
+            Ok(len) => {
+                dev_info!("Exporting config ({} bytes)", len);
+                self.state = TransferState::Sending { cmd: CONFIG_CMD_EXPORT, len, sent: 0 };
+            }
+            Err(e) => {
+                dev_warn!("Config export failed: {:?}", e);
+                self.state = TransferState::Idle;
+                self.respond(CONFIG_CMD_EXPORT, e.status_code());
+            }
+        }
+    }
+
+    /// Imports the blob received from the host into the config.
+    pub fn import<U: ConfigUpgrade>(&mut self, config: &mut FlashConfig, upgrade: &U) -> Result<usize, ConfigBlobError> {
+        let TransferState::Importing { len, .. } = self.state else {
+            return Err(ConfigBlobError::Malformed);
+        };
+
+        self.state = TransferState::Idle;
+        let result = import_config(config, &self.buf[..len], upgrade);
+        match result {
+            Ok(count) => {
+                dev_info!("Imported {} config values", count);
+                self.respond(CONFIG_CMD_IMPORT_COMMIT, CONFIG_STATUS_OK);
+            }
+            Err(e) => {
+                dev_warn!("Config import failed: {:?}", e);
+                self.respond(CONFIG_CMD_IMPORT_COMMIT, e.status_code());
+            }
+        }
+        result
+    }
+
+    fn push_pending(&mut self) {
+        if let Some(report) = self.pending_response {
+            if self.hid.push_raw_input(&report).is_ok() {
+                self.pending_response = None;
+            }
+            return;
+        }
+
+        if let TransferState::Sending { cmd, len, sent } = self.state {
+            let n = (len - sent).min(CONFIG_RESPONSE_MAX_DATA_LEN);
+            let mut report = [0u8; CONFIG_REPORT_LEN];
+            report[0] = cmd | CONFIG_RESPONSE_FLAG;
+            report[1] = CONFIG_STATUS_OK;
+            report[2..4].copy_from_slice(&(sent as u16).to_le_bytes());
+            report[4] = n as u8;
+            report[CONFIG_RESPONSE_HEADER_LEN..CONFIG_RESPONSE_HEADER_LEN + n]
+                .copy_from_slice(&self.buf[sent..sent + n]);
+
+            if self.hid.push_raw_input(&report).is_ok() {
+                self.state = if sent + n == len {
+                    TransferState::Idle
+                } else {
+                    TransferState::Sending { cmd, len, sent: sent + n }
+                };
+            }
+        }
+    }
+
+    fn handle_report(&mut self, report: &[u8]) -> Option<ConfigRequest> {
+        match (report.first().copied()?, self.state) {
+            (CONFIG_CMD_EXPORT, _) => return Some(ConfigRequest::Export),
+            // The buffer is in use while importing.
+            (CONFIG_CMD_BUILD_INFO, TransferState::Idle | TransferState::Sending { .. }) => {
+                match self.build_info.encode(&mut self.buf) {
+                    Some(len) => {
+                        self.state = TransferState::Sending { cmd: CONFIG_CMD_BUILD_INFO, len, sent: 0 };
+                    }
+                    None => self.respond(CONFIG_CMD_BUILD_INFO, ConfigBlobError::BufferTooSmall.status_code()),
+                }
+            }
+            (CONFIG_CMD_IMPORT_BEGIN, _) if report.len() >= 3 => {
+                let len = u16::from_le_bytes([report[1], report[2]]) as usize;
+                if len <= CONFIG_BLOB_MAX_LEN {
+                    self.state = TransferState::Importing { len, received: 0 };
+                    self.respond(CONFIG_CMD_IMPORT_BEGIN, CONFIG_STATUS_OK);
+                } else {
+                    self.respond(CONFIG_CMD_IMPORT_BEGIN, ConfigBlobError::BufferTooSmall.status_code());
+                }
+            }
+            (CONFIG_CMD_IMPORT_DATA, TransferState::Importing { len, received })
+                if report.len() >= CONFIG_IMPORT_DATA_HEADER_LEN =>
+            {
+                let offset = u16::from_le_bytes([report[1], report[2]]) as usize;
+                let n = report[3] as usize;
+                if offset != received
+                    || received + n > len
+                    || report.len() < CONFIG_IMPORT_DATA_HEADER_LEN + n
+                {
+                    // Chunks must come in order. Abort the import so that the
+                    // host can start over.
+                    self.state = TransferState::Idle;
+                    self.respond(CONFIG_CMD_IMPORT_DATA, CONFIG_STATUS_BAD_REQUEST);
+                } else {
+                    self.buf[offset..offset + n].copy_from_slice(
+                        &report[CONFIG_IMPORT_DATA_HEADER_LEN..CONFIG_IMPORT_DATA_HEADER_LEN + n],
+                    );
+                    self.state = TransferState::Importing { len, received: received + n };
+                }
+            }
+            (CONFIG_CMD_IMPORT_COMMIT, TransferState::Importing { len, received }) if len == received => {
+                return Some(ConfigRequest::Import);
+            }
+            (cmd, _) => {
+                dev_warn!("Ignored unexpected config request {:02x}", cmd);
+                self.respond(cmd, CONFIG_STATUS_BAD_REQUEST);
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbFeature<B> for ConfigHidFeature<'a, B> {
+    const EP: usize = 1;
+    type TPoll = Option<ConfigRequest>;
+
+    fn endpoints_mut(&mut self) -> [&mut dyn usb_device::class::UsbClass<B>; Self::EP] {
+        util::slice::array_unify_length(
+          [&mut self.hid]
+        )
+    }
+
+    fn usb_poll(&mut self, _device: &mut UsbDevice<B>) -> Self::TPoll {
+        self.push_pending();
+
+        let mut report = [0u8; CONFIG_REPORT_LEN];
+        let info = self.hid.pull_raw_report(&mut report).ok()?;
+        self.handle_report(&report[..info.len])
+    }
+}
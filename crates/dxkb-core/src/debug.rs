@@ -1,5 +1,4 @@
 use dxkb_common::{dev_info, dev_warn, util};
-use dxkb_peripheral::BootloaderUtil;
 use usb_device::{bus::{UsbBus, UsbBusAllocator}, device::UsbDevice};
 use usbd_hid::hid_class::{HIDClass, HidClassSettings};
 
@@ -18,6 +17,22 @@ const DEBUG_EP_DESCRIPTOR: [u8; 23] = [
     0xc0                           // END_COLLECTION
 ];
 
+/// Length of the reports of the debug interface, in both directions. IN
+/// reports carry raw bytes of the debug log, OUT reports carry one of the
+/// `DEBUG_CMD_*` commands below, optionally followed by a newline.
+pub const DEBUG_REPORT_LEN: usize = 64;
+
+/// See [`DebugRequest::Bootloader`].
+pub const DEBUG_CMD_ENTER_DFU: &[u8] = b"enter-dfu";
+/// See [`DebugRequest::LinkStats`].
+pub const DEBUG_CMD_LINK_STATS: &[u8] = b"link-stats";
+/// See [`DebugRequest::Status`].
+pub const DEBUG_CMD_STATUS: &[u8] = b"status";
+/// See [`DebugRequest::ScanCapture`].
+pub const DEBUG_CMD_SCAN_CAPTURE: &[u8] = b"scan-capture";
+/// See [`DebugRequest::PeerBootloader`].
+pub const DEBUG_CMD_PEER_ENTER_DFU: &[u8] = b"peer-enter-dfu";
+
 pub trait DebugRead {
     fn peek(&mut self, buf: &mut [u8]) -> usize;
     fn consume(&mut self, n: usize);
@@ -44,6 +59,28 @@ impl DebugRead for NopDebugRead {
 }
 
 
+/// Debug requests received from the host that need to be handled by the
+/// target, since the debug feature itself has no access to the data involved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugRequest {
+    /// Print the cumulative split link stats into the debug log.
+    LinkStats,
+    /// Print the current status of the keyboard (link status, peer supply
+    /// voltage...) into the debug log.
+    Status,
+    /// Print the raw matrix transitions captured so far into the debug log,
+    /// if the target captures them. See [`dxkb_common::debounce`].
+    ScanCapture,
+    /// Reboot the other side into its bootloader, through the split link.
+    PeerBootloader,
+    /// Reboot this side into its bootloader. The target should close the
+    /// split link first with
+    /// [`ByeReason::Bootloader`](dxkb_split_link::ByeReason::Bootloader), so
+    /// that the peer doesn't wait for the idle timeout to notice, and then
+    /// call [`BootloaderUtil::enter_bootloader`](dxkb_peripheral::BootloaderUtil::enter_bootloader).
+    Bootloader,
+}
+
 pub struct DebugHidFeature<'a, B: UsbBus, O: DebugRead> {
     hid: HIDClass<'a, B>,
     output_src: O,
@@ -69,7 +106,7 @@ impl <'a, B: UsbBus, O: DebugRead> DebugHidFeature<'a, B, O> {
 
 impl<'a, B: UsbBus + 'a, O: DebugRead> UsbFeature<B> for DebugHidFeature<'a, B, O> {
     const EP: usize = 1;
-    type TPoll = ();
+    type TPoll = Option<DebugRequest>;
 
     fn endpoints_mut(&mut self) -> [&mut dyn usb_device::class::UsbClass<B>; Self::EP] {
         util::slice::array_unify_length(
@@ -79,10 +116,11 @@ impl<'a, B: UsbBus + 'a, O: DebugRead> UsbFeature<B> for DebugHidFeature<'a, B,
 
     fn usb_poll(&mut self, _device: &mut UsbDevice<B>) -> Self::TPoll {
         if self.enter_bootloader {
-            BootloaderUtil::enter_bootloader();
+            self.enter_bootloader = false;
+            return Some(DebugRequest::Bootloader);
         }
 
-        let mut debug_buf: [u8; 64] = [0u8; 64];
+        let mut debug_buf = [0u8; DEBUG_REPORT_LEN];
         let count = self.output_src.peek(&mut debug_buf);
         if count > 0 {
             let r = self.hid.push_raw_input(&debug_buf[0..count]);
@@ -97,13 +135,24 @@ impl<'a, B: UsbBus + 'a, O: DebugRead> UsbFeature<B> for DebugHidFeature<'a, B,
         // embedded-cli is more meant to read a byte at a time for autocomplete,
         // which probably we don't need.)
         if let Ok(info) = self.hid.pull_raw_report(&mut debug_buf) {
-            if &debug_buf[0..info.len] == b"enter-dfu" || &debug_buf[0..info.len] == b"enter-dfu\n" {
+            let cmd = debug_buf[0..info.len].strip_suffix(b"\n").unwrap_or(&debug_buf[0..info.len]);
+            if cmd == DEBUG_CMD_ENTER_DFU {
                 dev_info!("Requested entering into DFU mode...");
-                // Delay the bootloader entry until the end of the poll, so that the response to the debug request can be sent back to the host before the device reboots.
+                // Delay the bootloader entry until the next poll, so that the response to the debug request can be sent back to the host before the device reboots.
                 self.enter_bootloader = true;
+            } else if cmd == DEBUG_CMD_LINK_STATS {
+                return Some(DebugRequest::LinkStats);
+            } else if cmd == DEBUG_CMD_STATUS {
+                return Some(DebugRequest::Status);
+            } else if cmd == DEBUG_CMD_SCAN_CAPTURE {
+                return Some(DebugRequest::ScanCapture);
+            } else if cmd == DEBUG_CMD_PEER_ENTER_DFU {
+                return Some(DebugRequest::PeerBootloader);
             } else {
                 dev_warn!("Ignored unknown debug request: {:02x?}", &debug_buf[0..info.len]);
             }
         }
+
+        None
     }
 }
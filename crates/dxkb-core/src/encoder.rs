@@ -0,0 +1,157 @@
+//! Mapping of rotary encoder detents into key actions, with optional
+//! acceleration: when the knob is rotated fast, each detent emits more than one
+//! step, so that big volume or scroll changes don't take ages.
+//!
+//! There's no encoder driver yet, so the target is responsible for decoding the
+//! encoder signals and calling [`EncoderMapping::rotate`] on each detent.
+
+use core::time::Duration;
+
+use dxkb_common::{LogicalKeyState, time::Clock};
+
+use crate::keyboard::{HandleKey, KeyboardStateLike, SplitKeyboardLike};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderDirection {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderAcceleration {
+    /// Detents in the same direction that arrive closer than this to the
+    /// previous one are considered part of a fast rotation.
+    pub fast_interval: Duration,
+
+    /// Number of consecutive fast detents needed for emitting one more step
+    /// per detent.
+    pub detents_per_step: u8,
+
+    /// Max number of steps emitted for a single detent.
+    pub max_steps: u8,
+}
+
+impl EncoderAcceleration {
+    /// Every detent emits exactly one step.
+    pub const NONE: EncoderAcceleration = EncoderAcceleration {
+        fast_interval: Duration::ZERO,
+        detents_per_step: 1,
+        max_steps: 1,
+    };
+
+    /// Suitable for volume control.
+    pub const DEFAULT: EncoderAcceleration = EncoderAcceleration {
+        fast_interval: Duration::from_millis(40),
+        detents_per_step: 3,
+        max_steps: 4,
+    };
+
+    fn steps(&self, fast_streak: u8) -> u8 {
+        (1 + fast_streak / self.detents_per_step.max(1)).min(self.max_steps.max(1))
+    }
+}
+
+/// What the encoder does on a given layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderBinding<Key> {
+    pub clockwise: Key,
+    pub counter_clockwise: Key,
+    pub acceleration: EncoderAcceleration,
+}
+
+/// Turns the detents of an encoder into taps of the key bound to the current
+/// layer. Taps are queued and emitted one per HID report through
+/// [`EncoderMapping::tick`], since pressing and releasing a key within the same
+/// report would be invisible to the host.
+pub struct EncoderMapping<Key: HandleKey, Clk: Clock, const LAYERS: usize> {
+    bindings: [Option<EncoderBinding<Key>>; LAYERS],
+    last_detent: Option<(EncoderDirection, Clk::TInstant)>,
+    fast_streak: u8,
+    /// The key and the number of taps left to emit.
+    pending: Option<(Key, u8)>,
+    /// The key of the tap that has been pressed and needs to be released.
+    pressed: Option<Key>,
+}
+
+impl<Key: HandleKey, Clk: Clock, const LAYERS: usize> EncoderMapping<Key, Clk, LAYERS> {
+    /// Max number of taps that can be queued. Detents beyond this are dropped,
+    /// so that the knob doesn't keep acting long after it stopped.
+    pub const MAX_PENDING_TAPS: u8 = 16;
+
+    /// Creates a new mapping, with the binding of each layer. Layers with no
+    /// binding ignore the encoder.
+    pub const fn new(bindings: [Option<EncoderBinding<Key>>; LAYERS]) -> Self {
+        Self {
+            bindings,
+            last_detent: None,
+            fast_streak: 0,
+            pending: None,
+            pressed: None,
+        }
+    }
+
+    /// Registers a detent of the encoder in the given direction, queuing the
+    /// steps to emit for the binding of the given layer.
+    pub fn rotate(&mut self, clock: &Clk, layer: u8, direction: EncoderDirection) {
+        let Some(binding) = self.bindings.get(layer as usize).and_then(|b| b.as_ref()) else {
+            return;
+        };
+
+        let now = clock.current_instant();
+        let fast = match self.last_detent {
+            Some((last_direction, last_time)) => {
+                last_direction == direction
+                    && clock.elapsed_since(last_time) < binding.acceleration.fast_interval
+            }
+            None => false,
+        };
+
+        self.fast_streak = if fast { self.fast_streak.saturating_add(1) } else { 0 };
+        self.last_detent = Some((direction, now));
+
+        let key = match direction {
+            EncoderDirection::Clockwise => &binding.clockwise,
+            EncoderDirection::CounterClockwise => &binding.counter_clockwise,
+        };
+
+        let steps = binding.acceleration.steps(self.fast_streak);
+        match &mut self.pending {
+            Some((pending_key, taps)) if pending_key == key => {
+                *taps = taps.saturating_add(steps).min(Self::MAX_PENDING_TAPS);
+            }
+            // Drop whatever is left from the opposite direction or from
+            // another layer.
+            _ => self.pending = Some((key.clone(), steps.min(Self::MAX_PENDING_TAPS))),
+        }
+    }
+
+    /// Emits the next press or release of the pending taps. Must be called
+    /// from the main loop, only when the HID report of the previous call has
+    /// been already sent (i.e the HID is not dirty).
+    pub fn tick<S: KeyboardStateLike, Kb: SplitKeyboardLike<S>>(&mut self, kb: &mut Kb, user: &mut Key::User) {
+        if let Some(key) = self.pressed.take() {
+            key.handle_key_state_change(kb, user, LogicalKeyState::Pressed, LogicalKeyState::Released);
+            return;
+        }
+
+        if let Some((key, taps)) = self.pending.take() {
+            key.handle_key_state_change(kb, user, LogicalKeyState::Released, LogicalKeyState::Pressed);
+            if taps > 1 {
+                self.pending = Some((key.clone(), taps - 1));
+            }
+            self.pressed = Some(key);
+        }
+    }
+
+    /// Like [`rotate`](Self::rotate), but taking the current layer from the
+    /// keyboard state.
+    pub fn rotate_on<S: KeyboardStateLike, Kb: SplitKeyboardLike<S>>(
+        &mut self,
+        kb: &mut Kb,
+        clock: &Clk,
+        direction: EncoderDirection,
+    ) {
+        let layer = kb.state_mut().current_layer_raw();
+        self.rotate(clock, layer, direction);
+    }
+}
@@ -0,0 +1,451 @@
+//! Pipeline of filters that the key changes of both sides go through before
+//! reaching the layout.
+//!
+//! Each stage is a [`KeyFilter`] that receives the key changes of the previous
+//! one, in layout coordinates, and decides which changes go to the next stage:
+//! it can pass them through, drop them, hold them back for a while or turn
+//! them into changes of other keys. The stages are chained with
+//! [`KeyFilter::then`] into a single type, which is a type parameter of the
+//! [`SplitKeyboard`](crate::keyboard::SplitKeyboard), so each target only pays
+//! for the stages it enables:
+//!
+//! ```ignore
+//! let filter = GhostFilter::<ROWS, COLS>::new().then(ComboFilter::new(COMBOS, 50));
+//! ```
+//!
+//! The debouncing is not a stage, since it needs the raw signal read from each
+//! key. It is done by the matrix of each side, before the changes get here.
+//!
+//! The RAM taken by the stages is known at compile time through
+//! [`KeyFilter::RAM_USAGE`], and can be checked against a budget with
+//! [`assert_filter_ram_budget`]. Their worst execution time is only measured
+//! for the stages wrapped in [`Timed`], and every stage can be reported at
+//! runtime with [`KeyFilter::for_each_stage`], e.g from the status debug
+//! command. Note that each chained stage also takes a [`KeyEventBuf`] from the
+//! stack while it runs.
+
+use core::fmt::Display;
+
+use dxkb_common::{
+    KeyState, dev_warn,
+    time::Clock,
+    util::{BitMatrix, BitMatrixLayout, ColBitMatrixLayout},
+};
+use heapless::Vec;
+
+/// Max number of key changes that a stage can emit from a single change or
+/// tick. Any change emitted beyond this is dropped.
+pub const MAX_FILTER_OUTPUT: usize = 8;
+
+/// A change of the state of a key, in layout coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub state: KeyState,
+}
+
+impl KeyEvent {
+    pub const fn new(row: u8, col: u8, state: KeyState) -> Self {
+        Self { row, col, state }
+    }
+
+    const fn is_key(&self, key: (u8, u8)) -> bool {
+        self.row == key.0 && self.col == key.1
+    }
+}
+
+/// The key changes emitted by a stage, in order.
+pub type KeyEventBuf = Vec<KeyEvent, MAX_FILTER_OUTPUT>;
+
+fn emit(out: &mut KeyEventBuf, event: KeyEvent) {
+    if out.push(event).is_err() {
+        dev_warn!("Too many key changes emitted by a filter, dropping {:?}", event);
+    }
+}
+
+/// The resources used by a stage of the filter pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageBudget {
+    pub name: &'static str,
+    /// Bytes of RAM taken by the state of the stage.
+    pub ram: usize,
+    /// The stats of the runs of the stage, if it is [`Timed`].
+    pub timing: Option<StageTiming>,
+}
+
+impl Display for StageBudget {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {} bytes", self.name, self.ram)?;
+        if let Some(timing) = &self.timing {
+            write!(f, ", {} runs, worst {} ns", timing.runs, timing.worst_nanos)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many times a [`Timed`] stage has run, and the longest it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageTiming {
+    pub runs: u32,
+    pub worst_nanos: u32,
+}
+
+/// Fails the build if the stages of `F` take more than `max_bytes` of RAM, e.g
+/// `const { assert_filter_ram_budget::<TFilter>(256) }`.
+pub const fn assert_filter_ram_budget<F: KeyFilter>(max_bytes: usize) {
+    assert!(F::RAM_USAGE <= max_bytes, "The key filters take more RAM than their budget");
+}
+
+/// A stage of the filter pipeline.
+pub trait KeyFilter {
+    /// Name of the stage, for the reports.
+    const NAME: &'static str;
+
+    /// Bytes of RAM taken by the state of the stage, or the sum of the ones
+    /// of every stage of a [`Chain`].
+    const RAM_USAGE: usize = size_of::<Self>();
+
+    /// Takes a key change, and pushes into `out` the changes that must go to
+    /// the next stage in its place, if any. `now_ms` is the current time in
+    /// milliseconds, which wraps around.
+    fn filter(&mut self, now_ms: u32, event: KeyEvent, out: &mut KeyEventBuf);
+
+    /// Called on every poll, so that the stages that hold changes back can
+    /// emit them once their time is over.
+    fn tick(&mut self, _now_ms: u32, _out: &mut KeyEventBuf) {}
+
+    /// Asks the stage to stop holding back key changes, giving up the
+    /// features that need to (e.g combos), or to resume them. Used by the
+    /// gaming mode (see [`crate::gaming`]). Stages that never hold changes
+    /// back can ignore it.
+    fn set_low_latency(&mut self, _enabled: bool) {}
+
+    /// Calls `f` with the budget of each stage, in order.
+    fn for_each_stage(&self, f: &mut dyn FnMut(&StageBudget)) {
+        f(&StageBudget { name: Self::NAME, ram: Self::RAM_USAGE, timing: None });
+    }
+
+    /// Returns a filter that passes the changes emitted by this one through
+    /// `next`.
+    fn then<F: KeyFilter>(self, next: F) -> Chain<Self, F>
+    where
+        Self: Sized,
+    {
+        Chain { first: self, next }
+    }
+}
+
+/// Passes every key change as it is. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFilter;
+
+impl KeyFilter for NoFilter {
+    const NAME: &'static str = "none";
+
+    fn filter(&mut self, _now_ms: u32, event: KeyEvent, out: &mut KeyEventBuf) {
+        emit(out, event);
+    }
+}
+
+/// Two stages run one after the other. See [`KeyFilter::then`].
+#[derive(Debug, Clone, Default)]
+pub struct Chain<A: KeyFilter, B: KeyFilter> {
+    first: A,
+    next: B,
+}
+
+impl<A: KeyFilter, B: KeyFilter> Chain<A, B> {
+    pub fn first_mut(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    pub fn next_mut(&mut self) -> &mut B {
+        &mut self.next
+    }
+}
+
+impl<A: KeyFilter, B: KeyFilter> KeyFilter for Chain<A, B> {
+    const NAME: &'static str = "chain";
+    const RAM_USAGE: usize = A::RAM_USAGE + B::RAM_USAGE;
+
+    fn filter(&mut self, now_ms: u32, event: KeyEvent, out: &mut KeyEventBuf) {
+        let mut mid = KeyEventBuf::new();
+        self.first.filter(now_ms, event, &mut mid);
+        for event in mid {
+            self.next.filter(now_ms, event, out);
+        }
+    }
+
+    fn tick(&mut self, now_ms: u32, out: &mut KeyEventBuf) {
+        let mut mid = KeyEventBuf::new();
+        self.first.tick(now_ms, &mut mid);
+        for event in mid {
+            self.next.filter(now_ms, event, out);
+        }
+        self.next.tick(now_ms, out);
+    }
+
+    fn set_low_latency(&mut self, enabled: bool) {
+        self.first.set_low_latency(enabled);
+        self.next.set_low_latency(enabled);
+    }
+
+    fn for_each_stage(&self, f: &mut dyn FnMut(&StageBudget)) {
+        self.first.for_each_stage(f);
+        self.next.for_each_stage(f);
+    }
+}
+
+/// Measures how long a stage takes to run, for finding out whether it fits
+/// in the scan period. The time is taken from the clock on each run, so only
+/// the stages being profiled should be wrapped.
+pub struct Timed<F: KeyFilter, C: Clock> {
+    stage: F,
+    clock: C,
+    timing: StageTiming,
+}
+
+impl<F: KeyFilter, C: Clock> Timed<F, C> {
+    pub const fn new(stage: F, clock: C) -> Self {
+        Self { stage, clock, timing: StageTiming { runs: 0, worst_nanos: 0 } }
+    }
+
+    pub fn stage_mut(&mut self) -> &mut F {
+        &mut self.stage
+    }
+
+    /// Forgets the runs measured so far, e.g after changing the
+    /// configuration of the stage.
+    pub fn reset_timing(&mut self) {
+        self.timing = StageTiming::default();
+    }
+
+    fn record(&mut self, start: C::TInstant) {
+        let nanos = self.clock.elapsed_since(start).as_nanos().min(u32::MAX as u128) as u32;
+        self.timing.runs = self.timing.runs.saturating_add(1);
+        self.timing.worst_nanos = self.timing.worst_nanos.max(nanos);
+    }
+}
+
+impl<F: KeyFilter, C: Clock> KeyFilter for Timed<F, C> {
+    const NAME: &'static str = F::NAME;
+    const RAM_USAGE: usize = F::RAM_USAGE;
+
+    fn filter(&mut self, now_ms: u32, event: KeyEvent, out: &mut KeyEventBuf) {
+        let start = self.clock.current_instant();
+        self.stage.filter(now_ms, event, out);
+        self.record(start);
+    }
+
+    fn tick(&mut self, now_ms: u32, out: &mut KeyEventBuf) {
+        let start = self.clock.current_instant();
+        self.stage.tick(now_ms, out);
+        self.record(start);
+    }
+
+    fn set_low_latency(&mut self, enabled: bool) {
+        self.stage.set_low_latency(enabled);
+    }
+
+    fn for_each_stage(&self, f: &mut dyn FnMut(&StageBudget)) {
+        f(&StageBudget { name: Self::NAME, ram: Self::RAM_USAGE, timing: Some(self.timing) });
+    }
+}
+
+/// Drops the presses that may be ghosts, for matrices without a diode per
+/// key. When three keys at the corners of a rectangle of the matrix are held,
+/// the fourth corner is read as pressed as well, so a press that completes a
+/// rectangle is ignored until that key is released.
+///
+/// The rectangles are looked for in the whole layout, so on split keyboards
+/// the presses that complete a rectangle across both sides are dropped too,
+/// even though they can't be ghosts.
+pub struct GhostFilter<const ROWS: usize, const COLS: u8>
+where
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
+{
+    pressed: BitMatrix<ROWS, COLS>,
+    blocked: BitMatrix<ROWS, COLS>,
+}
+
+impl<const ROWS: usize, const COLS: u8> GhostFilter<ROWS, COLS>
+where
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
+{
+    pub const fn new() -> Self {
+        Self {
+            pressed: BitMatrix::new(),
+            blocked: BitMatrix::new(),
+        }
+    }
+
+    /// Returns whether pressing the key would complete a rectangle of held
+    /// keys.
+    fn is_ghost(&self, row: u8, col: u8) -> bool {
+        let row_bits = self.pressed.row_bits(row as usize);
+        (0..ROWS).any(|other| {
+            other != row as usize
+                && self.pressed.get_value(other, col)
+                && row_bits & self.pressed.row_bits(other) != 0
+        })
+    }
+}
+
+impl<const ROWS: usize, const COLS: u8> KeyFilter for GhostFilter<ROWS, COLS>
+where
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
+{
+    const NAME: &'static str = "ghost";
+
+    fn filter(&mut self, _now_ms: u32, event: KeyEvent, out: &mut KeyEventBuf) {
+        let (row, col) = (event.row as usize, event.col);
+        if self.blocked.get_value(row, col) {
+            if event.state == KeyState::Released {
+                self.blocked.set_value(row, col, false);
+            }
+            return;
+        }
+
+        if event.state == KeyState::Pressed && self.is_ghost(event.row, event.col) {
+            dev_warn!("Ignoring possible ghost press of ({}, {})", event.row, event.col);
+            self.blocked.set_value(row, col, true);
+            return;
+        }
+
+        self.pressed.set_value(row, col, event.state.to_bool());
+        emit(out, event);
+    }
+}
+
+/// Two keys that, when pressed together, act as the press of another key
+/// instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Combo {
+    /// The keys to press, as (row, col) in layout coordinates.
+    pub keys: [(u8, u8); 2],
+    /// The key pressed instead. It may be a position of the layout that has
+    /// no physical key, so that its definition is only reachable through the
+    /// combo.
+    pub output: (u8, u8),
+}
+
+impl Combo {
+    pub const fn new(keys: [(u8, u8); 2], output: (u8, u8)) -> Self {
+        Self { keys, output }
+    }
+}
+
+/// Turns the keys of each [`Combo`] into its output key when both are
+/// pressed within `window_ms` of each other. The press of the first one is
+/// held back until then, and emitted as it is if the second one isn't
+/// pressed in time, or if any other key changes meanwhile. The output key is
+/// released as soon as either key of the combo is released.
+///
+/// In low latency mode, no press is held back, so the combos don't trigger.
+/// The ones already triggered are still released normally.
+pub struct ComboFilter<const N: usize> {
+    combos: [Combo; N],
+    window_ms: u16,
+    low_latency: bool,
+    /// The press held back, and the time it happened.
+    pending: Option<(KeyEvent, u32)>,
+    /// The keys of each combo that are held after the combo was triggered, as
+    /// a bit per key.
+    held: [u8; N],
+}
+
+impl<const N: usize> ComboFilter<N> {
+    pub const fn new(combos: [Combo; N], window_ms: u16) -> Self {
+        Self {
+            combos,
+            window_ms,
+            low_latency: false,
+            pending: None,
+            held: [0; N],
+        }
+    }
+
+    fn is_combo_key(&self, event: &KeyEvent) -> bool {
+        self.combos.iter().any(|combo| combo.keys.iter().any(|key| event.is_key(*key)))
+    }
+
+    fn flush_pending(&mut self, out: &mut KeyEventBuf) {
+        if let Some((event, _)) = self.pending.take() {
+            emit(out, event);
+        }
+    }
+}
+
+impl<const N: usize> KeyFilter for ComboFilter<N> {
+    const NAME: &'static str = "combo";
+
+    fn filter(&mut self, now_ms: u32, event: KeyEvent, out: &mut KeyEventBuf) {
+        match event.state {
+            KeyState::Pressed if self.low_latency => {
+                self.flush_pending(out);
+                emit(out, event);
+            }
+            KeyState::Pressed => {
+                if let Some((pending, _)) = self.pending {
+                    let triggered = self.combos.iter().position(|combo| {
+                        (pending.is_key(combo.keys[0]) && event.is_key(combo.keys[1]))
+                            || (pending.is_key(combo.keys[1]) && event.is_key(combo.keys[0]))
+                    });
+
+                    if let Some(index) = triggered {
+                        let (row, col) = self.combos[index].output;
+                        self.pending = None;
+                        self.held[index] = 0b11;
+                        emit(out, KeyEvent::new(row, col, KeyState::Pressed));
+                        return;
+                    }
+
+                    self.flush_pending(out);
+                }
+
+                if self.is_combo_key(&event) {
+                    self.pending = Some((event, now_ms));
+                } else {
+                    emit(out, event);
+                }
+            }
+            KeyState::Released => {
+                // The releases of the keys of a triggered combo are swallowed,
+                // since their presses were never emitted.
+                let mut swallowed = false;
+                for (index, combo) in self.combos.iter().enumerate() {
+                    let Some(bit) = combo.keys.iter().position(|key| event.is_key(*key)) else {
+                        continue;
+                    };
+
+                    if self.held[index] & (1 << bit) != 0 {
+                        if self.held[index] == 0b11 {
+                            let (row, col) = combo.output;
+                            emit(out, KeyEvent::new(row, col, KeyState::Released));
+                        }
+                        self.held[index] &= !(1 << bit);
+                        swallowed = true;
+                    }
+                }
+
+                if !swallowed {
+                    self.flush_pending(out);
+                    emit(out, event);
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, now_ms: u32, out: &mut KeyEventBuf) {
+        if let Some((_, pressed_ms)) = self.pending {
+            if now_ms.wrapping_sub(pressed_ms) >= self.window_ms as u32 {
+                self.flush_pending(out);
+            }
+        }
+    }
+
+    fn set_low_latency(&mut self, enabled: bool) {
+        self.low_latency = enabled;
+    }
+}
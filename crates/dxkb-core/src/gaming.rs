@@ -0,0 +1,58 @@
+//! Gaming mode: while enabled, only a subset of the standard keys reach the
+//! host, so that the keys that could get in the way while playing (e.g the GUI
+//! keys) are ignored, and the key filters stop holding back key changes (see
+//! [`KeyFilter::set_low_latency`](crate::filter::KeyFilter::set_low_latency)),
+//! so that every press is reported as soon as it is scanned. Combos don't
+//! trigger while it is enabled.
+//!
+//! It can be toggled at runtime with a function key, and its state shown with
+//! an [`Indicator::GamingMode`](crate::indicators::Indicator::GamingMode)
+//! binding.
+
+use dxkb_common::dev_info;
+use usbd_hid::descriptor::KeyboardUsage;
+
+pub struct GamingMode {
+    enabled: bool,
+    allowed_keys: &'static [KeyboardUsage],
+}
+
+impl GamingMode {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            allowed_keys: &[],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the keys that are sent to the host while the gaming mode is
+    /// enabled. Empty if every key is.
+    pub fn allowed_keys(&self) -> &'static [KeyboardUsage] {
+        self.allowed_keys
+    }
+
+    /// Sets the keys that are sent to the host while the gaming mode is
+    /// enabled. An empty set lets every key through, so that the gaming mode
+    /// only disables the features that delay the key changes.
+    pub fn set_allowed_keys(&mut self, keys: &'static [KeyboardUsage]) {
+        self.allowed_keys = keys;
+    }
+
+    /// Returns whether the given key (after the OS remaps) can be pressed in
+    /// the host.
+    pub fn allows(&self, key: KeyboardUsage) -> bool {
+        !self.enabled || self.allowed_keys.is_empty() || self.allowed_keys.contains(&key)
+    }
+
+    /// Enables or disables the gaming mode. Returns whether it is enabled
+    /// after the change.
+    pub(crate) fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        dev_info!("Gaming mode enabled: {}", self.enabled);
+        self.enabled
+    }
+}
@@ -17,12 +17,14 @@ use zerocopy::{Immutable, IntoBytes};
 
 use crate::usb::UsbFeature;
 
+#[cfg(not(feature = "no-consumer-control"))]
 enum LookOrFindEmptyMutResult<'a, A> {
     Found(&'a mut A),
     Empty(&'a mut A),
     Full,
 }
 
+#[cfg(not(feature = "no-consumer-control"))]
 fn lookup_or_find_empty_mut<'a, A>(
     haystick: &'a mut [A],
     needle: &'a A,
@@ -100,6 +102,72 @@ pub trait HidKeyboard {
 
     fn unpress_all_keys(&mut self);
     fn total_pressed_keys(&self) -> usize;
+
+    /// Returns whether any key being held couldn't be reported to the host
+    /// because its report had no room left for it, or because of the limit
+    /// set with [`Self::set_max_pressed_keys`]. The keyboard leaves this
+    /// state once every rejected key is released.
+    fn is_rollover(&self) -> bool;
+
+    /// Sets the max number of keys of the keyboard report that can be held
+    /// at once, or removes the limit with `None`, which is the default. See
+    /// [`PressedKeysLimit`].
+    fn set_max_pressed_keys(&mut self, max: Option<usize>);
+}
+
+/// A limit on the number of standard keys that can be held at once, e.g for
+/// hosts or KVM switches that misbehave when too many keys are reported, or
+/// for capping the keys held by a faulty matrix. The presses beyond it are
+/// rejected with [`HidKeyboardPressError::Rollover`], and the keyboard stays
+/// in rollover until every rejected key is released.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PressedKeysLimit {
+    max: Option<usize>,
+    /// Number of keys held whose press was rejected.
+    rejected: usize,
+}
+
+impl PressedKeysLimit {
+    pub const fn new(max: Option<usize>) -> Self {
+        Self { max, rejected: 0 }
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    /// Changes the limit. The keys already held are kept, even if there are
+    /// more than the new limit.
+    pub fn set_max(&mut self, max: Option<usize>) {
+        self.max = max;
+    }
+
+    /// Checks whether one more key can be pressed while `pressed` keys are
+    /// held, counting it as rejected if it can't.
+    pub fn check_press(&mut self, pressed: usize) -> Result<(), HidKeyboardPressError> {
+        match self.max {
+            Some(max) if pressed >= max => {
+                self.rejected += 1;
+                Err(HidKeyboardPressError::Rollover)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Must be called when a key that wasn't held is released, since it is
+    /// most likely one whose press was rejected.
+    pub fn on_release_not_pressed(&mut self) {
+        self.rejected = self.rejected.saturating_sub(1);
+    }
+
+    pub fn is_rollover(&self) -> bool {
+        self.rejected > 0
+    }
+
+    /// Forgets the rejected keys, e.g after releasing every key.
+    pub fn reset(&mut self) {
+        self.rejected = 0;
+    }
 }
 
 // The linux kernel recognizes ~ 624 consumer control keys. (ref:
@@ -107,9 +175,25 @@ pub trait HidKeyboard {
 // Since we're already in the > 8 bit territory, and it is probably not worth it
 // at this point to start playing with data that is not byte-aligned, just
 // extending the min/max values to comprend the whole CC specification.
+#[cfg(not(feature = "no-consumer-control"))]
 const REPORT_HID_CC_USAGE_MIN: Consumer = Consumer::ConsumerControl;
+#[cfg(not(feature = "no-consumer-control"))]
 const REPORT_HID_CC_USAGE_MAX: Consumer = Consumer::ContactMisc;
 
+// Number of consumer control keys that can be reported as pressed at the same
+// time. The full mode uses the whole 64-byte USB packet (31 u16 slots + the
+// report ID + a padding byte), which is way more than anyone will ever press at
+// once, but every change of a media key sends the whole thing. The compact mode
+// reduces it to 4 slots (10 bytes per IN transfer), which is enough for any
+// normal usage and saves bandwidth and RAM on minimal builds.
+#[cfg(all(not(feature = "no-consumer-control"), not(feature = "cc-report-compact")))]
+type ReportHidConsumerControlSlots = [u16; 31];
+#[cfg(all(not(feature = "no-consumer-control"), feature = "cc-report-compact"))]
+type ReportHidConsumerControlSlots = [u16; 4];
+#[cfg(not(feature = "no-consumer-control"))]
+const REPORT_HID_CC_SLOTS: usize =
+    size_of::<ReportHidConsumerControlSlots>() / size_of::<u16>();
+
 const REPORT_HID_KB_USAGE_MIN: KeyboardUsage = KeyboardUsage::KeyboardErrorRollOver;
 const REPORT_HID_KB_USAGE_MAX: KeyboardUsage = KeyboardUsage::Reserved;
 const REPORT_HID_KB_USAGE_COUNT: usize =
@@ -126,13 +210,16 @@ const _: () = assert!(
 );
 
 type ReportHidKeyboardUsageBitArray = BitArray<OneBit, REPORT_HID_KB_USAGE_COUNT>;
+#[cfg(not(feature = "no-consumer-control"))]
 type ReportHidConsumerControlReportId = ConstU8<1>;
 type ReportHidKeyboardReportId = ConstU8<2>;
 
+#[cfg(not(feature = "no-consumer-control"))]
 const fn u16_lobits(n: u16) -> u8 {
     (n & 0xff) as u8
 }
 
+#[cfg(not(feature = "no-consumer-control"))]
 const fn u16_hibits(n: u16) -> u8 {
     (n >> 8) as u8
 }
@@ -145,8 +232,9 @@ const fn u16_hibits(n: u16) -> u8 {
 // make the device to SerDe anything. There has to be (or I should make),
 // something in between usbd-hid and just writing the bytes of the descriptor
 // manually.
+#[cfg(not(feature = "no-consumer-control"))]
 #[rustfmt::skip]
-const REPORT_HID_KEYBOARD_DESCRIPTOR: [u8; 76] =[
+const REPORT_HID_CC_DESCRIPTOR: [u8; 33] = [
     0x05, 0x0c,                                             // Usage Page (Consumer Devices)
     0x09, 0x01,                                             // Usage (Consumer Control)
     0xa1, 0x01,                                             // Collection (Application)
@@ -158,10 +246,14 @@ const REPORT_HID_KEYBOARD_DESCRIPTOR: [u8; 76] =[
     0x2a, u16_lobits(REPORT_HID_CC_USAGE_MAX as u16), u16_hibits(REPORT_HID_CC_USAGE_MAX as u16), //  Usage Maximum
     0x16, u16_lobits(REPORT_HID_CC_USAGE_MIN as u16), u16_hibits(REPORT_HID_CC_USAGE_MIN as u16), //  Logical Minimum
     0x26, u16_lobits(REPORT_HID_CC_USAGE_MAX as u16), u16_hibits(REPORT_HID_CC_USAGE_MAX as u16), //  Logical Maximum
-    0x95, 0x1f,                                             //  Report Count (31) 31 reports * 16 bits each = 62 bytes < 64 bytes, leaving space for 1 byte for the report ID.
+    0x95, REPORT_HID_CC_SLOTS as u8,                        //  Report Count (REPORT_HID_CC_SLOTS) 31 reports * 16 bits each = 62 bytes < 64 bytes, leaving space for 1 byte for the report ID. 4 in compact mode.
     0x75, 0x10,                                             //  Report Size (16)
     0x81, 0x00,                                             //  Input (Data,Arr,Abs)
     0xc0,                                                   // End Collection
+];
+
+#[rustfmt::skip]
+const REPORT_HID_KB_DESCRIPTOR: [u8; 43] = [
     0x05, 0x01,                                             // Usage Page (Generic Desktop)
     0x09, 0x06,                                             // Usage (Keyboard)
     0xa1, 0x01,                                             // Collection (Application)
@@ -186,6 +278,25 @@ const REPORT_HID_KEYBOARD_DESCRIPTOR: [u8; 76] =[
     0xc0,                                                   // End Collection
 ];
 
+// The consumer control collection is left out of the descriptor when it is
+// compiled out, so that the host doesn't expect any report from it.
+#[cfg(not(feature = "no-consumer-control"))]
+const REPORT_HID_KEYBOARD_DESCRIPTOR: [u8; REPORT_HID_CC_DESCRIPTOR.len() + REPORT_HID_KB_DESCRIPTOR.len()] = {
+    let mut descriptor = [0u8; REPORT_HID_CC_DESCRIPTOR.len() + REPORT_HID_KB_DESCRIPTOR.len()];
+    let mut i = 0;
+    while i < descriptor.len() {
+        descriptor[i] = if i < REPORT_HID_CC_DESCRIPTOR.len() {
+            REPORT_HID_CC_DESCRIPTOR[i]
+        } else {
+            REPORT_HID_KB_DESCRIPTOR[i - REPORT_HID_CC_DESCRIPTOR.len()]
+        };
+        i += 1;
+    }
+    descriptor
+};
+#[cfg(feature = "no-consumer-control")]
+const REPORT_HID_KEYBOARD_DESCRIPTOR: [u8; REPORT_HID_KB_DESCRIPTOR.len()] = REPORT_HID_KB_DESCRIPTOR;
+
 #[derive(IntoBytes, Immutable, Default)]
 #[repr(packed)]
 struct ReportHidKeyboardInReport {
@@ -206,20 +317,27 @@ impl ReportHidKeyboardInReport {
     }
 }
 
+#[cfg(not(feature = "no-consumer-control"))]
 #[derive(IntoBytes, Immutable, Default)]
 #[repr(C, packed(2))]
 struct ReportHidConsumerControlInReport {
     report_id: ReportHidConsumerControlReportId,
     _pad1: ConstU8<0>, // Explicit padding to keep the buttons aligned to 2 bytes. Included in the report descriptor.
-    pressed_buttons: [u16; 31],
+    pressed_buttons: ReportHidConsumerControlSlots,
 }
+#[cfg(not(feature = "no-consumer-control"))]
+const _: () = assert!(
+    size_of::<ReportHidConsumerControlInReport>() <= USB_HID_READ_LEN,
+    "Size for struct ReportHidConsumerControlInReport cannot be greater than 64 bytes."
+);
 
+#[cfg(not(feature = "no-consumer-control"))]
 impl ReportHidConsumerControlInReport {
     pub const fn new() -> Self {
         Self {
             report_id: ReportHidConsumerControlReportId::I,
             _pad1: ConstU8::I,
-            pressed_buttons: [0u16; 31],
+            pressed_buttons: [0u16; REPORT_HID_CC_SLOTS],
         }
     }
 }
@@ -278,8 +396,15 @@ pub struct ReportHidKeyboard<'a, B: UsbBus> {
     ep: HIDClass<'a, B>,
     kb: MutableReport<ReportHidKeyboardInReport>,
     kb_pressed_count: usize,
+    kb_limit: PressedKeysLimit,
+    #[cfg(not(feature = "no-consumer-control"))]
     cc: MutableReport<ReportHidConsumerControlInReport>,
+    #[cfg(not(feature = "no-consumer-control"))]
     cc_pressed_count: usize,
+    /// Number of consumer control keys held whose press was rejected
+    /// because every slot of the report was in use.
+    #[cfg(not(feature = "no-consumer-control"))]
+    cc_rolled_over_count: usize,
     leds: BootLeds,
     remote_wakeup_enabled: bool,
     usb_state: UsbDeviceState
@@ -305,8 +430,13 @@ impl<'a, B: UsbBus> ReportHidKeyboard<'a, B> {
             ep,
             kb: MutableReport::new(ReportHidKeyboardInReport::new()),
             kb_pressed_count: 0,
+            kb_limit: PressedKeysLimit::new(None),
+            #[cfg(not(feature = "no-consumer-control"))]
             cc: MutableReport::new(ReportHidConsumerControlInReport::new()),
+            #[cfg(not(feature = "no-consumer-control"))]
             cc_pressed_count: 0,
+            #[cfg(not(feature = "no-consumer-control"))]
+            cc_rolled_over_count: 0,
             leds: BootLeds::empty(),
             remote_wakeup_enabled: false,
             usb_state: UsbDeviceState::Suspend
@@ -323,6 +453,7 @@ impl<'a, B: UsbBus> ReportHidKeyboard<'a, B> {
         }
     }
 
+    #[cfg(not(feature = "no-consumer-control"))]
     fn ensure_cc_within_bounds(cc_btn: Consumer) -> Option<()> {
         if (cc_btn as u16) < (REPORT_HID_CC_USAGE_MIN as u16)
             || (cc_btn as u16) > (REPORT_HID_CC_USAGE_MAX as u16)
@@ -333,6 +464,18 @@ impl<'a, B: UsbBus> ReportHidKeyboard<'a, B> {
         }
     }
 
+    /// Reports the ErrorRollOver usage along with the keys held while the
+    /// limit of pressed keys is exceeded, which is how the HID standard
+    /// signals the rollover to the host.
+    fn sync_rollover_signal(&mut self) {
+        let index = KeyboardUsage::KeyboardErrorRollOver as usize - REPORT_HID_KB_USAGE_MIN as usize;
+        let rollover = self.kb_limit.is_rollover();
+        if self.kb.report.keys.get(index) != rollover {
+            self.kb.report.keys.put(index, rollover);
+            self.kb.set_dirty();
+        }
+    }
+
     fn do_rx(&mut self) -> Result<(), KeyboardTickError> {
         let mut buf: [u8; USB_HID_READ_LEN] = [0u8; USB_HID_READ_LEN];
         let report_info = match self.ep.pull_raw_report(&mut buf) {
@@ -386,6 +529,15 @@ impl<'a, B: UsbBus> HidKeyboard for ReportHidKeyboard<'a, B> {
     fn press_key(&mut self, key: KeyboardUsage) -> Result<(), HidKeyboardPressError> {
         Self::ensure_keyboard_usage_within_bounds(key).ok_or(HidKeyboardPressError::Unsupported)?;
 
+        let index = key as usize - REPORT_HID_KB_USAGE_MIN as usize;
+        if self.kb.report.keys.get(index) {
+            return Err(HidKeyboardPressError::AlreadyPressed);
+        }
+        if let Err(e) = self.kb_limit.check_press(self.kb_pressed_count) {
+            self.sync_rollover_signal();
+            return Err(e);
+        }
+
         if self
             .kb
             .report
@@ -416,10 +568,27 @@ impl<'a, B: UsbBus> HidKeyboard for ReportHidKeyboard<'a, B> {
             self.kb.set_dirty();
             Ok(())
         } else {
+            // Most likely rejected because of the limit of pressed keys.
+            self.kb_limit.on_release_not_pressed();
+            self.sync_rollover_signal();
             Err(HidKeyboardReleaseError::NotPressed)
         }
     }
 
+    #[cfg(feature = "no-consumer-control")]
+    fn press_consumer_control_key(&mut self, _key: Consumer) -> Result<(), HidKeyboardPressError> {
+        Err(HidKeyboardPressError::Unsupported)
+    }
+
+    #[cfg(feature = "no-consumer-control")]
+    fn release_consumer_control_key(
+        &mut self,
+        _key: Consumer,
+    ) -> Result<(), HidKeyboardReleaseError> {
+        Err(HidKeyboardReleaseError::Unsupported)
+    }
+
+    #[cfg(not(feature = "no-consumer-control"))]
     fn press_consumer_control_key(&mut self, key: Consumer) -> Result<(), HidKeyboardPressError> {
         Self::ensure_cc_within_bounds(key).ok_or(HidKeyboardPressError::Unsupported)?;
         match lookup_or_find_empty_mut(&mut self.cc.report.pressed_buttons, &(key as u16), &0) {
@@ -436,11 +605,13 @@ impl<'a, B: UsbBus> HidKeyboard for ReportHidKeyboard<'a, B> {
             }
             LookOrFindEmptyMutResult::Full => {
                 // Rollover
+                self.cc_rolled_over_count += 1;
                 Err(HidKeyboardPressError::Rollover)
             }
         }
     }
 
+    #[cfg(not(feature = "no-consumer-control"))]
     fn release_consumer_control_key(
         &mut self,
         key: Consumer,
@@ -456,7 +627,8 @@ impl<'a, B: UsbBus> HidKeyboard for ReportHidKeyboard<'a, B> {
                 Ok(())
             }
             LookOrFindEmptyMutResult::Empty(_) | LookOrFindEmptyMutResult::Full => {
-                // Wasn't pressed
+                // Wasn't pressed, most likely because it was rejected.
+                self.cc_rolled_over_count = self.cc_rolled_over_count.saturating_sub(1);
                 Err(HidKeyboardReleaseError::NotPressed)
             }
         }
@@ -466,12 +638,19 @@ impl<'a, B: UsbBus> HidKeyboard for ReportHidKeyboard<'a, B> {
         &self.leds
     }
 
+    #[cfg(not(feature = "no-consumer-control"))]
     fn dirty(&self) -> bool {
         self.kb.is_dirty() || self.cc.is_dirty()
     }
 
+    #[cfg(feature = "no-consumer-control")]
+    fn dirty(&self) -> bool {
+        self.kb.is_dirty()
+    }
+
     fn tick(&mut self) -> Result<(), KeyboardTickError> {
         Self::do_tx_report(&mut self.ep, &mut self.kb)?;
+        #[cfg(not(feature = "no-consumer-control"))]
         Self::do_tx_report(&mut self.ep, &mut self.cc)?;
         let ret = self.do_rx()?;
 
@@ -479,22 +658,51 @@ impl<'a, B: UsbBus> HidKeyboard for ReportHidKeyboard<'a, B> {
     }
 
     fn unpress_all_keys(&mut self) {
-        if self.kb_pressed_count > 0 {
+        if self.kb_pressed_count > 0 || self.kb_limit.is_rollover() {
             self.kb.reset();
             self.kb_pressed_count = 0;
             self.kb.set_dirty();
         }
+        self.kb_limit.reset();
 
+        #[cfg(not(feature = "no-consumer-control"))]
         if self.cc_pressed_count > 0 {
             self.cc.reset();
             self.cc_pressed_count = 0;
             self.cc.set_dirty();
         }
+
+        #[cfg(not(feature = "no-consumer-control"))]
+        {
+            self.cc_rolled_over_count = 0;
+        }
     }
 
+    #[cfg(not(feature = "no-consumer-control"))]
     fn total_pressed_keys(&self) -> usize {
         self.kb_pressed_count + self.cc_pressed_count
     }
+
+    #[cfg(feature = "no-consumer-control")]
+    fn total_pressed_keys(&self) -> usize {
+        self.kb_pressed_count
+    }
+
+    // The keyboard report has a bit for every key, so it only rolls over
+    // because of the limit of pressed keys.
+    #[cfg(not(feature = "no-consumer-control"))]
+    fn is_rollover(&self) -> bool {
+        self.kb_limit.is_rollover() || self.cc_rolled_over_count > 0
+    }
+
+    #[cfg(feature = "no-consumer-control")]
+    fn is_rollover(&self) -> bool {
+        self.kb_limit.is_rollover()
+    }
+
+    fn set_max_pressed_keys(&mut self, max: Option<usize>) {
+        self.kb_limit.set_max(max);
+    }
 }
 
 impl<'a, B: UsbBus + 'a> UsbFeature<B> for ReportHidKeyboard<'a, B> {
@@ -0,0 +1,152 @@
+//! Binding of logical indicators (the lock LEDs reported by the host, the
+//! active layer, the modes of the keyboard) to the LEDs of the keyboard. The bindings are declared by the
+//! target as a static table, usually next to the keymap:
+//!
+//! ```ignore
+//! static INDICATORS: [IndicatorBinding; 2] = [
+//!     IndicatorBinding::new(Indicator::HostLed(BootLeds::CAPS_LOCK), 0, Rgb::WHITE),
+//!     IndicatorBinding::new(Indicator::Layer(1), 1, Rgb::new(0, 0, 255)),
+//! ];
+//! ```
+
+use dxkb_common::dev_debug;
+
+use crate::hid::BootLeds;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(255, 255, 255);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indicator {
+    /// Active while the host reports any of the given LEDs as on.
+    HostLed(BootLeds),
+    /// Active while the given layer is the current one.
+    Layer(u8),
+    /// Active while the gaming mode is enabled. See [`crate::gaming`].
+    GamingMode,
+    /// Active while the presence mode is enabled. See [`crate::presence`].
+    PresenceMode,
+}
+
+impl Indicator {
+    fn is_active(&self, leds: BootLeds, layer: u8, gaming: bool, presence: bool) -> bool {
+        match *self {
+            Indicator::HostLed(flags) => leds.intersects(flags),
+            Indicator::Layer(l) => l == layer,
+            Indicator::GamingMode => gaming,
+            Indicator::PresenceMode => presence,
+        }
+    }
+}
+
+/// Lights the LED with the given index with the given color while the
+/// indicator is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndicatorBinding {
+    pub indicator: Indicator,
+    pub led: u8,
+    pub color: Rgb,
+}
+
+impl IndicatorBinding {
+    pub const fn new(indicator: Indicator, led: u8, color: Rgb) -> Self {
+        Self { indicator, led, color }
+    }
+}
+
+/// Something that can drive the LEDs used as indicators, like a lighting
+/// engine. Single color LEDs should be lit for any color other than
+/// [`Rgb::OFF`].
+pub trait IndicatorSink {
+    fn set_indicator_led(&mut self, led: u8, color: Rgb);
+}
+
+/// A sink for keyboards without indicator LEDs.
+impl IndicatorSink for () {
+    fn set_indicator_led(&mut self, _led: u8, _color: Rgb) {}
+}
+
+pub struct IndicatorBindings {
+    bindings: &'static [IndicatorBinding],
+    /// The host LEDs, the layer, the gaming mode and the presence mode of
+    /// the last update, if any.
+    last_state: Option<(BootLeds, u8, bool, bool)>,
+}
+
+impl IndicatorBindings {
+    pub const fn new(bindings: &'static [IndicatorBinding]) -> Self {
+        Self {
+            bindings,
+            last_state: None,
+        }
+    }
+
+    /// Updates the LEDs from the LED report of the host, the current layer
+    /// and whether the gaming and presence modes are enabled, if any of them
+    /// changed since the last call. When more than one active binding targets
+    /// the same LED, the one declared last wins. Should be called after each
+    /// poll of the keyboard.
+    pub fn poll<S: IndicatorSink>(&mut self, leds: BootLeds, layer: u8, gaming: bool, presence: bool, sink: &mut S) {
+        if self.last_state == Some((leds, layer, gaming, presence)) {
+            return;
+        }
+
+        dev_debug!(
+            "Updating indicators. LEDs: {:?}, layer: {}, gaming mode: {}, presence mode: {}",
+            leds, layer, gaming, presence
+        );
+        self.last_state = Some((leds, layer, gaming, presence));
+        for (i, binding) in self.bindings.iter().enumerate() {
+            // Only the last binding of each LED updates it, so that every LED
+            // is set just once.
+            if self.bindings[i + 1..].iter().any(|b| b.led == binding.led) {
+                continue;
+            }
+
+            let color = self.bindings[..=i]
+                .iter()
+                .rev()
+                .filter(|b| b.led == binding.led)
+                .find(|b| b.indicator.is_active(leds, layer, gaming, presence))
+                .map_or(Rgb::OFF, |b| b.color);
+
+            sink.set_indicator_led(binding.led, color);
+        }
+    }
+}
+
+#[cfg(feature = "stm32f411")]
+mod ws2812 {
+    use dxkb_peripheral::ws2812::{ws2812_buf_len, Ws2812};
+    use stm32f4xx_hal::{
+        dma::{traits::{Channel, DMASet, Stream, StreamISR}, ChannelX, MemoryToPeripheral},
+        spi::{Instance, Tx},
+    };
+
+    use super::{IndicatorSink, Rgb};
+
+    impl<SPI: Instance + 'static, S: Stream + StreamISR + 'static, const DMA_CH: u8, const LEDS: usize> IndicatorSink
+        for Ws2812<SPI, S, DMA_CH, LEDS>
+    where
+        [(); ws2812_buf_len(LEDS)]:,
+        ChannelX<DMA_CH>: Channel,
+        Tx<SPI>: DMASet<S, DMA_CH, MemoryToPeripheral>,
+    {
+        fn set_indicator_led(&mut self, led: u8, color: Rgb) {
+            self.set_led(led as usize, color.r, color.g, color.b);
+        }
+    }
+}
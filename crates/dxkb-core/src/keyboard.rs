@@ -1,10 +1,11 @@
-use core::marker::PhantomData;
+use core::{convert::Infallible, fmt::Debug, marker::PhantomData, time::Duration};
 
 use dxkb_common::{
-    KeyState, LogicalKeyState, dev_error, dev_info, dev_trace, dev_warn, time::Clock, util::{BitArray, BitMatrix, BitMatrixLayout, BoundedU8, ColBitMatrixLayout, ConstCond, IsTrue, TwoBits}
+    KeyState, LogicalKeyState, dev_debug, dev_error, dev_event, dev_info, dev_trace, dev_warn, time::Clock, util::{BitArray, BitMatrix, BitMatrixLayout, BoundedU8, ColBitMatrixLayout, ConstCond, IsTrue, TwoBits}
 };
-use dxkb_peripheral::{key_matrix::KeyMatrixLike, usb::UsbRemoteWakeup};
-use dxkb_split_link::SplitBusLike;
+use dxkb_peripheral::{key_matrix::KeyMatrixLike, usb::UsbRemoteWakeup, voltage::SupplyVoltageSensor};
+use dxkb_split_link::addressing::PeerAddress;
+use dxkb_split_link::{ByeReason, HostPresence, LinkEvent, NullSplitBus, PeerRole, SplitBusLike, TransferError};
 use heapless::Vec;
 use serde::{Deserialize, Serialize};
 use stm32f4xx_hal::{
@@ -16,7 +17,7 @@ use usb_device::{bus::UsbBus, device::{UsbDevice, UsbDeviceState}};
 // Re-export it to be used for macros without needing to reference the usbd-hid crate.
 pub use usbd_hid::descriptor::KeyboardUsage;
 
-use crate::hid::HidKeyboard;
+use crate::{builder::{BuildableKeyboard, KeyboardBuilder, KeyboardParts}, filter::{KeyEvent, KeyEventBuf, KeyFilter, NoFilter}, gaming::GamingMode, hid::{BootLeds, HidKeyboard}, midi::MidiOut, playback::TextPlayback, presence::PresenceMode, remap::OsRemaps, steno::StenoOut};
 
 pub trait MasterCheck {
     fn is_current_master(&mut self) -> bool;
@@ -56,14 +57,304 @@ impl<P: InputPin> MasterCheck for PinMasterSense<P> {
     }
 }
 
-// TODO Eventually I might want to implement something more (e.g
-// something like a keyboard whose SplitBusLink Msg is of type
-// Impl<KeyboardSplitLinkMessage>) so the caller can define its own
-// more custom protocol.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SplitKeyboardLinkMessage {
     MatrixKeyDown { row: u8, col: u8 },
     MatrixKeyUp { row: u8, col: u8 },
+    /// Periodic report of the supply voltage of the slave side.
+    SupplyVoltage { millivolts: u16 },
+    /// Every key change detected by the slave in a single scan of its
+    /// matrix, in the order they were detected. Used instead of
+    /// `MatrixKeyDown` and `MatrixKeyUp` when several keys change at once,
+    /// so that they only need a single frame and ACK round-trip.
+    MatrixKeyEvents { events: Vec<MatrixKeyEvent, MAX_BATCHED_KEY_EVENTS> },
+    /// The LEDs the host has turned on, sent by the master each time they
+    /// change and each time the link is established again, so that the
+    /// slave side can show them as well.
+    HostLeds { leds: u8 },
+}
+
+/// Max number of key changes sent in a single
+/// [`SplitKeyboardLinkMessage::MatrixKeyEvents`] message. Scans with more
+/// changes than this are split into several messages.
+pub const MAX_BATCHED_KEY_EVENTS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatrixKeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+}
+
+impl MatrixKeyEvent {
+    pub const fn state(&self) -> KeyState {
+        KeyState::from_bool(self.pressed)
+    }
+}
+
+impl SplitKeyboardLinkMessage {
+    /// Merges the key changes of `msg` into the ones of `queued`, if both are
+    /// key events and they all fit in a single
+    /// [`SplitKeyboardLinkMessage::MatrixKeyEvents`]. Returns whether they
+    /// were merged. Meant to be the
+    /// [`TxOverflowPolicy::Coalesce`](dxkb_split_link::TxOverflowPolicy::Coalesce)
+    /// policy of the priority channel, so that the key changes of a burst of
+    /// scans are not lost while the link is congested.
+    pub fn coalesce_key_events(queued: &mut Self, msg: &Self) -> bool {
+        let mut events = Vec::<MatrixKeyEvent, MAX_BATCHED_KEY_EVENTS>::new();
+        for m in [&*queued, msg] {
+            let added = match m {
+                SplitKeyboardLinkMessage::MatrixKeyDown { row, col } => {
+                    events.push(MatrixKeyEvent { row: *row, col: *col, pressed: true }).is_ok()
+                }
+                SplitKeyboardLinkMessage::MatrixKeyUp { row, col } => {
+                    events.push(MatrixKeyEvent { row: *row, col: *col, pressed: false }).is_ok()
+                }
+                SplitKeyboardLinkMessage::MatrixKeyEvents { events: more } => events.extend_from_slice(more).is_ok(),
+                _ => false,
+            };
+            if !added {
+                return false;
+            }
+        }
+
+        *queued = SplitKeyboardLinkMessage::MatrixKeyEvents { events };
+        true
+    }
+}
+
+/// The messages exchanged by both halves of a keyboard whose target defines
+/// messages of its own, e.g for showing the same thing on the displays of both
+/// sides. Both kinds of messages share the split link, told apart by their
+/// tag.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TaggedLinkMessage<U> {
+    /// A message of the keyboard itself.
+    CoreMsg(SplitKeyboardLinkMessage),
+    /// A message of the target, sent with
+    /// [`SplitKeyboard::transfer_user_msg`].
+    UserMsg(U),
+}
+
+impl<U> TaggedLinkMessage<U> {
+    /// Like [`SplitKeyboardLinkMessage::coalesce_key_events`], for the links
+    /// that carry messages of the target as well. These are never merged.
+    pub fn coalesce_key_events(queued: &mut Self, msg: &Self) -> bool {
+        match (queued, msg) {
+            (TaggedLinkMessage::CoreMsg(queued), TaggedLinkMessage::CoreMsg(msg)) => {
+                SplitKeyboardLinkMessage::coalesce_key_events(queued, msg)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The type of the messages sent through the split link of a
+/// [`SplitKeyboard`]: [`SplitKeyboardLinkMessage`] for the targets without
+/// messages of their own, or a [`TaggedLinkMessage`] otherwise.
+pub trait KeyboardLinkMessage: Clone + Debug {
+    /// The messages defined by the target.
+    type User;
+
+    fn from_core(msg: SplitKeyboardLinkMessage) -> Self;
+    fn from_user(msg: Self::User) -> Self;
+    fn as_core(&self) -> Option<&SplitKeyboardLinkMessage>;
+    fn as_user(&self) -> Option<&Self::User>;
+}
+
+impl KeyboardLinkMessage for SplitKeyboardLinkMessage {
+    type User = Infallible;
+
+    fn from_core(msg: SplitKeyboardLinkMessage) -> Self {
+        msg
+    }
+
+    fn from_user(msg: Infallible) -> Self {
+        match msg {}
+    }
+
+    fn as_core(&self) -> Option<&SplitKeyboardLinkMessage> {
+        Some(self)
+    }
+
+    fn as_user(&self) -> Option<&Infallible> {
+        None
+    }
+}
+
+impl<U: Clone + Debug> KeyboardLinkMessage for TaggedLinkMessage<U> {
+    type User = U;
+
+    fn from_core(msg: SplitKeyboardLinkMessage) -> Self {
+        TaggedLinkMessage::CoreMsg(msg)
+    }
+
+    fn from_user(msg: U) -> Self {
+        TaggedLinkMessage::UserMsg(msg)
+    }
+
+    fn as_core(&self) -> Option<&SplitKeyboardLinkMessage> {
+        match self {
+            TaggedLinkMessage::CoreMsg(msg) => Some(msg),
+            TaggedLinkMessage::UserMsg(_) => None,
+        }
+    }
+
+    fn as_user(&self) -> Option<&U> {
+        match self {
+            TaggedLinkMessage::CoreMsg(_) => None,
+            TaggedLinkMessage::UserMsg(msg) => Some(msg),
+        }
+    }
+}
+
+/// Time between each supply voltage report sent by the slave side.
+pub const SUPPLY_VOLTAGE_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Max number of messages from the peer processed on a single poll, whatever
+/// the [`OverloadPolicy`] says.
+pub const MAX_LINK_MSGS_PER_POLL: usize = 16;
+
+/// Function called on the master when the supply voltage reported by the slave
+/// drops below the configured threshold, with the reported voltage.
+pub type LowVoltageHook<User> = fn(&mut User, u16);
+
+/// Function called when the keyboard enters (`true`) or leaves (`false`) the
+/// rollover state. See [`HidKeyboard::is_rollover`].
+pub type RolloverHook<User> = fn(&mut User, bool);
+
+/// Function called on both sides when the LEDs turned on by the host change,
+/// once they have settled. See [`SplitKeyboard::set_host_leds_hook`].
+pub type HostLedsHook<User> = fn(&mut User, BootLeds);
+
+/// Time the LEDs turned on by the host need to stay the same before the
+/// keyboard takes them, by default. Some hosts toggle them several times in a
+/// row, e.g while booting, which would make the indicators flicker otherwise.
+pub const DEFAULT_HOST_LEDS_SETTLE_TIME: Duration = Duration::from_millis(50);
+
+/// Function called on the master for each key change reported by an extra
+/// unit of the board, with the address of the unit. See
+/// [`SplitKeyboard::poll_unit`].
+pub type UnitKeyHook<User> = fn(&mut User, PeerAddress, MatrixKeyEvent);
+
+/// Function called on both sides for each message of the target received from
+/// the peer. See [`SplitKeyboard::set_user_msg_hook`].
+pub type UserMsgHook<User, U> = fn(&mut User, &U);
+
+/// A summary of what happened during a single call to
+/// [`SplitKeyboard::poll`]. The keyboard doesn't decide any sleep or power
+/// policy by itself, but the main loop of the target can use this to lower the
+/// scan rate or sleep when nothing has happened for a while.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PollActivity {
+    /// Whether any key, local or remote, changed its state.
+    pub keys_changed: bool,
+
+    /// The number of messages received from the split link.
+    pub link_rx_count: usize,
+
+    /// Whether there was any pending update that required the USB to be
+    /// touched, like a HID report waiting to be sent or a remote wakeup
+    /// signal. Always false when running as slave.
+    pub usb_activity: bool,
+
+    /// How long the key changes received from the peer during this poll
+    /// waited for the local matrix to be scanned before being applied. Always
+    /// zero with [`PollOrder::LinkFirst`], or if no remote key changed. Useful
+    /// for measuring the skew between both sides during chords.
+    pub remote_keys_delay: Duration,
+
+    /// Whether a key was pressed while the peer, being the master, is in deep
+    /// sleep, as it announced when closing the link. The target should wake it
+    /// up through a side channel if it has any (e.g a wake line). Always false
+    /// when running as master.
+    pub peer_wake_requested: bool,
+
+    /// Whether the peer asked this side to reboot into its bootloader (see
+    /// [`SplitBus::request_peer_bootloader`](dxkb_split_link::SplitBus::request_peer_bootloader)).
+    /// The target should close the link with
+    /// [`ByeReason::Bootloader`] and reboot, e.g with
+    /// [`BootloaderUtil::enter_bootloader`](dxkb_peripheral::BootloaderUtil::enter_bootloader).
+    pub peer_bootloader_requested: bool,
+
+    /// Whether the host is suspended, either the one of this side, when
+    /// running as master, or the one of the peer, as it told through the
+    /// split link. The target can lower the scan rate while it lasts.
+    pub host_suspended: bool,
+}
+
+/// The order in which the master processes the local matrix and the messages
+/// received from the peer on each poll. Remote key changes already waited for
+/// a link round-trip, so processing them first keeps the skew between the keys
+/// of both sides smaller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PollOrder {
+    /// The link is processed before scanning the matrix.
+    #[default]
+    LinkFirst,
+    /// The matrix is scanned before processing the link.
+    MatrixFirst,
+    /// The order is swapped on each poll, so that none of the sources is
+    /// favored over the other.
+    Alternate,
+}
+
+/// Limits on the work done on each poll, so that a burst of messages from the
+/// peer, like the retransmissions after a noisy spell of the link, doesn't
+/// delay the scans of the local matrix. The messages left wait in the bus for
+/// the next polls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverloadPolicy {
+    /// Max number of messages from the peer processed on each poll. It is
+    /// never lower than 1, nor higher than [`MAX_LINK_MSGS_PER_POLL`].
+    pub max_link_msgs: usize,
+
+    /// Target max time between two scans of the local matrix. Once it has
+    /// passed since the last scan, no more messages from the peer are
+    /// processed on the poll, besides the first one, so that the link always
+    /// makes some progress.
+    pub max_scan_interval: Duration,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        Self {
+            max_link_msgs: MAX_LINK_MSGS_PER_POLL,
+            max_scan_interval: Duration::from_millis(2),
+        }
+    }
+}
+
+impl OverloadPolicy {
+    /// Returns whether no more messages from the peer must be processed after
+    /// `processed` ones, `since_scan` after the last scan of the matrix.
+    fn link_budget_exhausted(&self, processed: usize, since_scan: Option<Duration>) -> bool {
+        processed >= self.max_link_msgs.clamp(1, MAX_LINK_MSGS_PER_POLL)
+            || since_scan.is_some_and(|elapsed| elapsed >= self.max_scan_interval)
+    }
+}
+
+/// Counters of the starvation observed while applying the [`OverloadPolicy`],
+/// for checking whether it holds on real traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StarvationStats {
+    /// Polls in which the processing of the messages from the peer stopped
+    /// because of the policy.
+    pub link_polls_cut: u32,
+
+    /// Scans of the local matrix that started later than the max scan
+    /// interval after the previous one.
+    pub late_scans: u32,
+
+    /// The longest time observed between two scans of the local matrix.
+    pub max_scan_interval: Duration,
+}
+
+impl PollActivity {
+    /// Returns true if nothing at all happened during the poll.
+    pub const fn is_idle(&self) -> bool {
+        !self.keys_changed && self.link_rx_count == 0 && !self.usb_activity
+    }
 }
 
 /// Represents the possible sides of a split keyboard as enum variants
@@ -111,6 +402,33 @@ pub trait SplitKeyboardLike<State> {
 
     fn state_mut(&mut self) -> &mut State;
     fn hid_mut(&mut self) -> &mut Self::Hid;
+    fn text_playback_mut(&mut self) -> &mut TextPlayback;
+
+    /// Returns the queue of the MIDI notes played by the keys, that the
+    /// target sends to the host if it exposes a MIDI interface.
+    fn midi_mut(&mut self) -> &mut MidiOut;
+
+    /// Returns the chord accumulator of the steno keys, that the target sends
+    /// to the host if it exposes a steno interface.
+    fn steno_mut(&mut self) -> &mut StenoOut;
+
+    /// Enables or disables the presence mode. Returns whether it is enabled
+    /// after the change.
+    fn toggle_presence_mode(&mut self) -> bool;
+
+    /// Returns the gaming mode, for checking which keys can be pressed.
+    fn gaming_mode(&self) -> &GamingMode;
+
+    /// Enables or disables the gaming mode. Returns whether it is enabled
+    /// after the change.
+    fn toggle_gaming_mode(&mut self) -> bool;
+
+    /// Returns the OS remaps to be applied to the standard keys.
+    fn os_remaps(&self) -> OsRemaps;
+
+    /// Enables or disables the given OS remaps. Returns the enabled remaps
+    /// after the change.
+    fn toggle_os_remaps(&mut self, remaps: OsRemaps) -> OsRemaps;
 }
 
 
@@ -138,11 +456,14 @@ pub struct SplitKeyboard<
     Key: HandleKey,
     Matrix: KeyMatrixLike<MROWS, MCOLS>,
     MasterTester: MasterCheck,
-    SplitBus: SplitBusLike<SplitKeyboardLinkMessage>,
+    SplitBus: SplitBusLike<LinkMsg>,
     User,
+    Filter: KeyFilter = NoFilter,
+    LinkMsg: KeyboardLinkMessage = SplitKeyboardLinkMessage,
 > where
     [(); LLAYERS as usize]:,
     [(); LCOLS as usize]:,
+    ColBitMatrixLayout<LCOLS>: BitMatrixLayout,
     [(); LROWS as usize]:,
     [(); valid_matrix_size!(LROWS, LCOLS)]:,
     ConstCond<{ LLAYERS > 0 }>: IsTrue,
@@ -151,12 +472,68 @@ pub struct SplitKeyboard<
     matrix: Matrix,
     layout: SplitKeyboardLayout<LayoutConfig, Key, LLAYERS, LROWS, LCOLS>,
     state: KeyboardState<Key, LLAYERS, LROWS, LCOLS>,
+    /// The keys of the local matrix that were pressed on the last scan, in
+    /// layout coordinates.
+    physical_state: BitMatrix<{ LROWS as usize }, LCOLS>,
+    /// The filters that the key changes of both sides go through before
+    /// reaching the layout. Only used by the master.
+    filter: Filter,
     pub split_bus: SplitBus,
     master_tester: MasterTester,
     is_master: bool,
+    poll_order: PollOrder,
+    /// Whether the link was processed first on the last poll, for
+    /// [`PollOrder::Alternate`].
+    last_poll_link_first: bool,
+    overload_policy: OverloadPolicy,
+    starvation: StarvationStats,
+    /// When the local matrix was last scanned by a regular poll.
+    last_scan_time: Option<Clk::TInstant>,
 
     hid: Hid,
+    text_playback: TextPlayback,
+    midi: MidiOut,
+    steno: StenoOut,
+    presence: PresenceMode<Clk>,
+    gaming: GamingMode,
+    os_remaps: OsRemaps,
     remote_wakeup_signal_start_time: Option<Clk::TInstant>,
+    /// Whether any key of either side has been pressed since the last poll.
+    /// Used for waking up the host, since the keys pressed while suspended may
+    /// not produce any HID press, e.g if they were already released by the
+    /// time the batch of events of the slave side arrives, or if they are
+    /// layer keys.
+    key_pressed: bool,
+    /// The key changes of the slave that haven't been sent yet, because the
+    /// split link was congested. They are sent along with the ones of the
+    /// next scans, in a single message.
+    pending_key_events: Vec<MatrixKeyEvent, MAX_BATCHED_KEY_EVENTS>,
+
+    last_supply_voltage_report_time: Option<Clk::TInstant>,
+    peer_supply_voltage: Option<u16>,
+    low_voltage_warning: Option<(u16, LowVoltageHook<User>)>,
+
+    /// The LEDs turned on by the host. The master keeps the ones last sent to
+    /// the slave, and the slave the ones last received from the master.
+    host_leds: BootLeds,
+    /// Whether the slave has the current [`host_leds`](Self::host_leds).
+    /// Only used by the master.
+    host_leds_synced: bool,
+    /// The LEDs last taken by the keyboard, once they settled. These are the
+    /// ones sent to the slave and handed to the hook.
+    settled_host_leds: BootLeds,
+    /// LEDs different from the settled ones, and since when they've been
+    /// seen without changes.
+    pending_host_leds: Option<(BootLeds, Clk::TInstant)>,
+    host_leds_settle_time: Duration,
+    host_leds_hook: Option<HostLedsHook<User>>,
+
+    /// Whether the HID was in rollover at the end of the last poll.
+    rollover: bool,
+    rollover_hook: Option<RolloverHook<User>>,
+
+    unit_key_hook: Option<UnitKeyHook<User>>,
+    user_msg_hook: Option<UserMsgHook<User, LinkMsg::User>>,
 
     _side: PhantomData<Side>,
     _layout_config: PhantomData<LayoutConfig>,
@@ -178,6 +555,8 @@ impl<
     MasterTester,
     SplitBus,
     User,
+    Filter,
+    LinkMsg,
 >
     SplitKeyboard<
         LLAYERS,
@@ -194,6 +573,8 @@ impl<
         MasterTester,
         SplitBus,
         User,
+        Filter,
+        LinkMsg,
     >
 where
     Clk: Clock,
@@ -204,9 +585,12 @@ where
     Key: HandleKey<User = User>,
     Matrix: KeyMatrixLike<MROWS, MCOLS>,
     MasterTester: MasterCheck,
-    SplitBus: SplitBusLike<SplitKeyboardLinkMessage>,
+    SplitBus: SplitBusLike<LinkMsg>,
+    Filter: KeyFilter,
+    LinkMsg: KeyboardLinkMessage,
     [(); LLAYERS as usize]:,
     [(); LCOLS as usize]:,
+    ColBitMatrixLayout<LCOLS>: BitMatrixLayout,
     [(); LROWS as usize]:,
     [(); valid_matrix_size!(LROWS, LCOLS)]:,
     ConstCond<{ LLAYERS > 0 }>: IsTrue,
@@ -222,34 +606,445 @@ where
         );
     }
 
-    pub fn new(
+    /// Returns a builder of the keyboard, for setting up its components and
+    /// settings. See [`crate::builder`].
+    pub fn builder() -> KeyboardBuilder<Self> {
+        KeyboardBuilder::new()
+    }
+
+    fn assemble(
         clock: Clk,
         hid: Hid,
         layout: SplitKeyboardLayout<LayoutConfig, Key, LLAYERS, LROWS, LCOLS>,
         matrix: Matrix,
         split_bus: SplitBus,
         master_tester: MasterTester,
+        filter: Filter,
     ) -> Self {
         const { Self::assert_config_ok() }
+
+        let mut matrix = matrix;
+        for row in 0..MROWS {
+            for col in 0..MCOLS {
+                let (real_row, real_col) = layout.get_real_key_coordinate::<CurSide>(row, col);
+                matrix.set_key_present(row, col, layout.is_key_present(real_row, real_col));
+            }
+        }
+
         Self {
             clock,
             hid,
+            text_playback: TextPlayback::new(),
+            midi: MidiOut::new(),
+            steno: StenoOut::new(),
+            presence: PresenceMode::new(),
+            gaming: GamingMode::new(),
+            os_remaps: OsRemaps::empty(),
             remote_wakeup_signal_start_time: None,
+            key_pressed: false,
+            pending_key_events: Vec::new(),
+            last_supply_voltage_report_time: None,
+            peer_supply_voltage: None,
+            low_voltage_warning: None,
+            host_leds: BootLeds::empty(),
+            host_leds_synced: false,
+            settled_host_leds: BootLeds::empty(),
+            pending_host_leds: None,
+            host_leds_settle_time: DEFAULT_HOST_LEDS_SETTLE_TIME,
+            host_leds_hook: None,
+            rollover: false,
+            rollover_hook: None,
+            unit_key_hook: None,
+            user_msg_hook: None,
             matrix,
             layout,
             state: KeyboardState::new(),
+            physical_state: BitMatrix::new(),
+            filter,
             split_bus,
             master_tester,
             is_master: false,
+            poll_order: PollOrder::default(),
+            last_poll_link_first: false,
+            overload_policy: OverloadPolicy::default(),
+            starvation: StarvationStats::default(),
+            last_scan_time: None,
             _side: PhantomData,
             _layout_config: PhantomData,
             _user: PhantomData,
         }
     }
 
+    /// Sets the order in which the master processes the local matrix and the
+    /// link. See [`PollOrder`].
+    pub fn set_poll_order(&mut self, order: PollOrder) {
+        self.poll_order = order;
+    }
+
+    /// Sets the limits on the work done on each poll. See [`OverloadPolicy`].
+    pub fn set_overload_policy(&mut self, policy: OverloadPolicy) {
+        self.overload_policy = policy;
+    }
+
+    /// Returns the starvation observed since the keyboard started.
+    pub fn starvation_stats(&self) -> StarvationStats {
+        self.starvation
+    }
+
+    /// Sets a function to be called when the supply voltage reported by the
+    /// slave side goes below `threshold_mv`. It is only called once each time
+    /// the voltage crosses the threshold.
+    pub fn set_low_voltage_warning(&mut self, threshold_mv: u16, hook: LowVoltageHook<User>) {
+        self.low_voltage_warning = Some((threshold_mv, hook));
+    }
+
+    /// Sets a function to be called each time the keyboard enters or leaves
+    /// the rollover state, e.g for warning the user that some key presses
+    /// are not reaching the host.
+    pub fn set_rollover_hook(&mut self, hook: RolloverHook<User>) {
+        self.rollover_hook = Some(hook);
+    }
+
+    /// Sets the max number of standard keys that can be held at once, or
+    /// removes the limit with `None`, which is the default. The presses
+    /// beyond it don't reach the host, and put the keyboard in rollover (see
+    /// [`Self::set_rollover_hook`]).
+    pub fn set_max_pressed_keys(&mut self, max: Option<usize>) {
+        self.hid.set_max_pressed_keys(max);
+    }
+
+    /// Sets a function to be called each time the LEDs turned on by the host
+    /// change, once they have settled (see
+    /// [`Self::set_host_leds_settle_time`]). On the slave side, it is called
+    /// with the ones forwarded by the master.
+    pub fn set_host_leds_hook(&mut self, hook: HostLedsHook<User>) {
+        self.host_leds_hook = Some(hook);
+    }
+
+    /// Sets how long the LEDs turned on by the host need to stay the same
+    /// before the master takes them, updates its indicators and forwards them
+    /// to the slave. Defaults to [`DEFAULT_HOST_LEDS_SETTLE_TIME`].
+    pub fn set_host_leds_settle_time(&mut self, settle_time: Duration) {
+        self.host_leds_settle_time = settle_time;
+    }
+
+    /// Sets the function that receives the key changes of the extra units of
+    /// the board, polled through [`Self::poll_unit`].
+    pub fn set_unit_key_hook(&mut self, hook: UnitKeyHook<User>) {
+        self.unit_key_hook = Some(hook);
+    }
+
+    /// Sets the function that receives the messages of the target sent by
+    /// the peer with [`Self::transfer_user_msg`].
+    pub fn set_user_msg_hook(&mut self, hook: UserMsgHook<User, LinkMsg::User>) {
+        self.user_msg_hook = Some(hook);
+    }
+
+    /// Sends a message of the target to the peer, which hands it to its user
+    /// message hook (see [`Self::set_user_msg_hook`]). It goes through the
+    /// normal channel of the link, so it never delays the key changes.
+    pub fn transfer_user_msg(&mut self, msg: LinkMsg::User) -> Result<(), TransferError> {
+        self.split_bus.transfer(LinkMsg::from_user(msg))
+    }
+
+    /// Polls the link with an extra unit of the board, like a numpad sharing
+    /// the split bus with both halves (see [`dxkb_split_link::addressing`]).
+    /// The layout only covers both halves, so the key changes of the unit are
+    /// handed to the unit key hook instead, tagged with the address of the
+    /// unit. Only the master side routes them; they're dropped otherwise.
+    pub fn poll_unit<UnitBus: SplitBusLike<SplitKeyboardLinkMessage>>(
+        &mut self,
+        unit: PeerAddress,
+        bus: &mut UnitBus,
+        user: &mut User,
+    ) {
+        let route = if self.is_master { self.unit_key_hook } else { None };
+        let mut received = 0;
+        bus.poll(|msg| {
+            let mut route_event = |event: MatrixKeyEvent| match route {
+                Some(hook) => hook(user, unit, event),
+                None => dev_debug!("Dropping key change of unit {}: {:?}", unit, event),
+            };
+
+            match msg {
+                SplitKeyboardLinkMessage::MatrixKeyDown { row, col } => {
+                    route_event(MatrixKeyEvent { row: *row, col: *col, pressed: true });
+                }
+                SplitKeyboardLinkMessage::MatrixKeyUp { row, col } => {
+                    route_event(MatrixKeyEvent { row: *row, col: *col, pressed: false });
+                }
+                SplitKeyboardLinkMessage::MatrixKeyEvents { events } => {
+                    events.iter().copied().for_each(route_event);
+                }
+                SplitKeyboardLinkMessage::SupplyVoltage { millivolts } => {
+                    dev_debug!("Supply voltage of unit {}: {} mV", unit, millivolts);
+                }
+                SplitKeyboardLinkMessage::HostLeds { leds: _ } => {
+                    dev_warn!("Unexpected HostLeds message received from unit {}", unit);
+                }
+            }
+
+            received += 1;
+            received < MAX_LINK_MSGS_PER_POLL
+        });
+    }
+
+    /// Returns the number of keys held right now on both sides, including
+    /// the masked ones. Only the master side knows the keys of the peer.
+    pub fn pressed_count(&self) -> u8 {
+        self.state.pressed_key_count
+    }
+
+    /// Returns whether any key held couldn't be reported to the host because
+    /// its HID report was full.
+    pub fn is_rollover(&self) -> bool {
+        self.hid.is_rollover()
+    }
+
+    /// Returns the matrix of this side, e.g for accessing its debouncer.
+    pub fn matrix_mut(&mut self) -> &mut Matrix {
+        &mut self.matrix
+    }
+
+    /// Returns the filters of the key changes, e.g for reporting their
+    /// budget.
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Returns the filters of the key changes, e.g for configuring them at
+    /// runtime.
+    pub fn filter_mut(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    /// Returns the presence mode, so that the target can configure it or show
+    /// whether it is enabled (e.g with a LED).
+    pub fn presence_mode_mut(&mut self) -> &mut PresenceMode<Clk> {
+        &mut self.presence
+    }
+
+    /// Returns the gaming mode, so that the target can set the keys allowed
+    /// while it is enabled.
+    pub fn gaming_mode_mut(&mut self) -> &mut GamingMode {
+        &mut self.gaming
+    }
+
+    /// Returns whether the gaming mode is enabled, e.g for showing it with a
+    /// LED.
+    pub fn is_gaming_mode(&self) -> bool {
+        self.gaming.is_enabled()
+    }
+
+    /// Returns whether the presence mode is enabled, e.g for showing it with
+    /// a LED.
+    pub fn is_presence_mode(&self) -> bool {
+        self.presence.is_enabled()
+    }
+
+    /// Sets the OS remaps to be applied to the standard keys, e.g the ones
+    /// persisted in the flash. Every key is released in the host when they
+    /// change, so that no key is left pressed with its old usage.
+    pub fn set_os_remaps(&mut self, remaps: OsRemaps) {
+        if remaps != self.os_remaps {
+            dev_info!("OS remaps changed: {:?}", remaps);
+            self.os_remaps = remaps;
+            self.hid.unpress_all_keys();
+        }
+    }
+
+    /// Returns the index of the current layer.
+    pub fn current_layer(&self) -> u8 {
+        self.state.current_layer.value()
+    }
+
+    /// Returns whether this side was acting as the master (i.e it has VBUS
+    /// from the USB host) during the last poll.
+    pub fn is_master(&self) -> bool {
+        self.is_master
+    }
+
+    /// Returns the last supply voltage reported by the slave side, if any.
+    pub fn peer_supply_voltage(&self) -> Option<u16> {
+        self.peer_supply_voltage
+    }
+
+    /// Returns the LEDs the host has turned on, once they have settled. On the
+    /// slave side, these are the ones last forwarded by the master, or none if
+    /// the link is down.
+    pub fn host_leds(&self) -> BootLeds {
+        self.settled_host_leds
+    }
+
+    /// Measures the supply voltage and reports it to the master side every
+    /// [`SUPPLY_VOLTAGE_REPORT_INTERVAL`]. Must be called periodically by the
+    /// targets whose slave side is powered independently. Does nothing while
+    /// being the master side.
+    pub fn poll_supply_voltage<S: SupplyVoltageSensor>(&mut self, sensor: &mut S) {
+        if self.is_master {
+            return;
+        }
+
+        if let Some(last_report) = self.last_supply_voltage_report_time {
+            if self.clock.elapsed_since(last_report) < SUPPLY_VOLTAGE_REPORT_INTERVAL {
+                return;
+            }
+        }
+
+        self.last_supply_voltage_report_time = Some(self.clock.current_instant());
+        let millivolts = sensor.read_millivolts();
+        dev_trace!("Supply voltage: {} mV", millivolts);
+        Self::split_link_transfer_msg(&mut self.split_bus, SplitKeyboardLinkMessage::SupplyVoltage { millivolts });
+    }
+
+    fn handle_peer_supply_voltage(&mut self, millivolts: u16, user: &mut User) {
+        let previous = self.peer_supply_voltage.replace(millivolts);
+        if let Some((threshold, hook)) = self.low_voltage_warning {
+            let was_low = previous.is_some_and(|v| v < threshold);
+            if millivolts < threshold && !was_low {
+                dev_warn!("Low supply voltage on the slave side: {} mV", millivolts);
+                hook(user, millivolts);
+            }
+        }
+    }
+
+    fn update_rollover(&mut self, user: &mut User) {
+        let rollover = self.hid.is_rollover();
+        if rollover != self.rollover {
+            self.rollover = rollover;
+            if rollover {
+                dev_event!(
+                    warn,
+                    crate::log_ids::ROLLOVER_ENTERED,
+                    "Entered rollover, {} keys pressed",
+                    self.state.pressed_key_count
+                );
+            } else {
+                dev_event!(info, crate::log_ids::ROLLOVER_LEFT, "Left rollover");
+            }
+
+            if let Some(hook) = self.rollover_hook {
+                hook(user, rollover);
+            }
+        }
+    }
+
+    /// Handles the changes of the link that need the state shared with the
+    /// peer to be sent again. Returns whether the peer asked for a reboot into
+    /// the bootloader.
+    fn process_link_events(&mut self) -> bool {
+        let mut bootloader_requested = false;
+        while let Some(event) = self.split_bus.poll_event() {
+            match event {
+                LinkEvent::Up | LinkEvent::Resync | LinkEvent::PeerReset => {
+                    self.host_leds_synced = false;
+                }
+                LinkEvent::Down => {
+                    self.host_leds_synced = false;
+                    if !self.is_master {
+                        self.host_leds = BootLeds::empty();
+                    }
+                }
+                LinkEvent::BootloaderRequested => bootloader_requested = true,
+                // Only has effect if the host is suspended.
+                LinkEvent::WakeRequested if self.is_master => self.request_remote_wakeup(),
+                LinkEvent::Incompatible
+                | LinkEvent::Loopback
+                | LinkEvent::PeerRebooted(_)
+                | LinkEvent::PeerSuspended
+                | LinkEvent::PeerResumed
+                | LinkEvent::WakeRequested => {}
+            }
+        }
+        bootloader_requested
+    }
+
+    /// Takes the LEDs turned on by the host once they stay the same for the
+    /// settle time, and hands them to the hook. The ones received by the slave
+    /// were settled by the master already, so they're taken right away.
+    fn settle_host_leds(&mut self, user: &mut User) {
+        let leds = if self.is_master { *self.hid.leds() } else { self.host_leds };
+        if leds == self.settled_host_leds {
+            self.pending_host_leds = None;
+            return;
+        }
+
+        let since = match self.pending_host_leds {
+            Some((pending, since)) if pending == leds => since,
+            _ => {
+                let now = self.clock.current_instant();
+                self.pending_host_leds = Some((leds, now));
+                now
+            }
+        };
+        if self.is_master && self.clock.elapsed_since(since) < self.host_leds_settle_time {
+            return;
+        }
+
+        dev_debug!("Host LEDs: {:?}", leds);
+        self.pending_host_leds = None;
+        self.settled_host_leds = leds;
+        if let Some(hook) = self.host_leds_hook {
+            hook(user, leds);
+        }
+    }
+
+    /// Sends the host LEDs to the slave if it doesn't have them yet.
+    fn sync_host_leds(&mut self) {
+        let leds = self.settled_host_leds;
+        if self.host_leds_synced && leds == self.host_leds {
+            return;
+        }
+
+        match self.split_bus.transfer(LinkMsg::from_core(SplitKeyboardLinkMessage::HostLeds { leds: leds.bits() })) {
+            Ok(()) => {
+                self.host_leds = leds;
+                self.host_leds_synced = true;
+            }
+            // Sent again once the link is up.
+            Err(TransferError::LinkDown) => {}
+            Err(e) => {
+                dev_event!(
+                    warn,
+                    crate::log_ids::HOST_LEDS_TRANSFER_FAILED,
+                    "Couldn't transfer host LEDs through split link: {:?}",
+                    e
+                );
+            }
+        }
+    }
+
     fn split_link_transfer_msg(split_bus: &mut SplitBus, msg: SplitKeyboardLinkMessage) {
-        if let Err(e) = split_bus.transfer(msg) {
-            dev_warn!("Couldn't transfer message through split link: {:?}", e);
+        if let Err(e) = split_bus.transfer(LinkMsg::from_core(msg)) {
+            dev_event!(
+                warn,
+                crate::log_ids::LINK_MSG_TRANSFER_FAILED,
+                "Couldn't transfer message through split link: {:?}",
+                e
+            );
+        }
+    }
+
+    /// Sends the key changes of a scan to the master, using the single key
+    /// messages if there's just one change.
+    fn split_link_transfer_key_events(split_bus: &mut SplitBus, events: &mut Vec<MatrixKeyEvent, MAX_BATCHED_KEY_EVENTS>) {
+        let msg = match events.as_slice() {
+            [] => return,
+            [event] if event.pressed => SplitKeyboardLinkMessage::MatrixKeyDown { row: event.row, col: event.col },
+            [event] => SplitKeyboardLinkMessage::MatrixKeyUp { row: event.row, col: event.col },
+            _ => SplitKeyboardLinkMessage::MatrixKeyEvents { events: events.clone() },
+        };
+
+        events.clear();
+        // Key events go through the priority channel, so that they never
+        // wait behind other traffic.
+        if let Err(e) = split_bus.transfer_priority(LinkMsg::from_core(msg)) {
+            dev_event!(
+                warn,
+                crate::log_ids::KEY_EVENTS_TRANSFER_FAILED,
+                "Couldn't transfer key events through split link: {:?}",
+                e
+            );
         }
     }
 
@@ -261,19 +1056,47 @@ where
         user: &mut User,
     ) {
         let (real_row, real_col) = self.layout.get_real_key_coordinate::<Side>(row, col);
-        let (old, new) = self
-            .state
-            .notify_physical_key_change(real_row, real_col, current_state);
+        if !self.layout.is_key_present(real_row, real_col) {
+            dev_trace!("Ignoring state of non-existent key ({}, {})", real_row, real_col);
+            return;
+        }
 
-        if old != new {
-            let key: Key = self
-                .layout
-                .get_key_definition(self.state.current_layer, real_row, real_col)
-                .clone();
-            key.handle_key_state_change::<_, Self>(self, user, old, new);
+        let mut events = KeyEventBuf::new();
+        self.filter
+            .filter(self.current_millis(), KeyEvent::new(real_row, real_col, current_state), &mut events);
+        self.apply_key_events(events, user);
+    }
+
+    /// Emits the key changes held back by the filters whose time is over.
+    fn tick_filter(&mut self, user: &mut User) {
+        let mut events = KeyEventBuf::new();
+        self.filter.tick(self.current_millis(), &mut events);
+        self.apply_key_events(events, user);
+    }
+
+    /// Applies the key changes that went through the filters to the layout.
+    fn apply_key_events(&mut self, events: KeyEventBuf, user: &mut User) {
+        // The filters may emit changes of keys that aren't present, e.g the
+        // output of a combo, so they aren't checked again.
+        for event in events {
+            let (old, new) = self.state.notify_physical_key_change(event.row, event.col, event.state);
+            if old != new {
+                self.key_pressed |= event.state == KeyState::Pressed;
+                let key: Key = self
+                    .layout
+                    .get_key_definition(self.state.current_layer, event.row, event.col)
+                    .clone();
+                key.handle_key_state_change::<_, Self>(self, user, old, new);
+            }
         }
     }
 
+    /// Returns the current time in milliseconds for the filters, which wraps
+    /// around.
+    fn current_millis(&self) -> u32 {
+        (self.clock.nanos(self.clock.current_instant()) / 1_000_000) as u32
+    }
+
     fn sync_layers(&mut self, user: &mut User) {
         // If the requested layer differs from the current layer, means that the
         // user has requested a layer change in the last scan. To perform a
@@ -333,56 +1156,160 @@ where
         }
     }
 
-    fn poll_master<'a, B: UsbBus>(&mut self, user: &mut User, device: &mut UsbDevice<'a, B>) {
+    /// Accounts a scan of the local matrix that is about to start.
+    fn record_scan(&mut self) {
+        let now = self.clock.current_instant();
+        if let Some(last_scan) = self.last_scan_time {
+            let interval = self.clock.elapsed_since(last_scan);
+            if interval > self.overload_policy.max_scan_interval {
+                self.starvation.late_scans = self.starvation.late_scans.saturating_add(1);
+            }
+            self.starvation.max_scan_interval = self.starvation.max_scan_interval.max(interval);
+        }
+        self.last_scan_time = Some(now);
+    }
+
+    fn master_scan_matrix(&mut self, user: &mut User, activity: &mut PollActivity) {
         let matrix_changed = self.matrix.scan_matrix();
+        activity.keys_changed |= matrix_changed;
         if matrix_changed {
-            // TODO There has to be a better way to implement
-            // this. Maybe eventually I can just copy the bitmatrix
-            // to the layout bit matrix, making sure we only override
-            // the bits from the current side of the keyboard. For
-            // now following a naive implementation.
+            // Merging each scanned row into the physical state of the layout
+            // gives the keys of the row that have changed at once, so only
+            // those go through the layout.
+            let col_offset = <CurSide as SideLayoutOffset<LayoutConfig>>::SIDE_COL_OFFSET;
             for row in 0..MROWS {
-                for col in 0..MCOLS {
-                    self.layout_update_key_state::<CurSide>(
-                        row,
-                        col,
-                        self.matrix.get_key_state(row, col),
-                        user,
-                    );
+                let mut changed =
+                    self.physical_state
+                        .set_row_bits(row as usize, self.matrix.row_bits(row), col_offset, MCOLS);
+                while changed != 0 {
+                    let col = changed.trailing_zeros() as u8;
+                    changed &= changed - 1;
+                    let state = KeyState::from_bool(self.physical_state.get_value(row as usize, col));
+                    self.layout_update_key_state::<CurSide>(row, col - col_offset, state, user);
                 }
             }
         }
+    }
+
+    /// Processes the messages received from the peer. `poll_start` is the
+    /// instant the current poll started, for measuring how long the remote key
+    /// changes waited for the local matrix, if it was scanned first.
+    fn master_process_link(&mut self, user: &mut User, activity: &mut PollActivity, poll_start: Option<Clk::TInstant>) {
+        let mut incoming_split_msgs = Vec::<LinkMsg, MAX_LINK_MSGS_PER_POLL>::new();
+        let (clock, policy, last_scan) = (&self.clock, &self.overload_policy, self.last_scan_time);
+        let mut cut = false;
+        self.split_bus.poll(|msg| {
+            // Cannot fail, the budget is never larger than the vec.
+            let _ = incoming_split_msgs.push(msg.clone());
+            let since_scan = last_scan.map(|last_scan| clock.elapsed_since(last_scan));
+            cut = policy.link_budget_exhausted(incoming_split_msgs.len(), since_scan);
+            !cut
+        });
+        if cut {
+            self.starvation.link_polls_cut = self.starvation.link_polls_cut.saturating_add(1);
+        }
+        activity.link_rx_count = incoming_split_msgs.len();
+
+        if let Some(poll_start) = poll_start {
+            let has_key_changes = incoming_split_msgs.iter().any(|msg| matches!(msg.as_core(),
+                Some(SplitKeyboardLinkMessage::MatrixKeyDown { .. }
+                | SplitKeyboardLinkMessage::MatrixKeyUp { .. }
+                | SplitKeyboardLinkMessage::MatrixKeyEvents { .. })));
+            if has_key_changes {
+                activity.remote_keys_delay = self.clock.elapsed_since(poll_start);
+            }
+        }
+
+        for msg in &incoming_split_msgs {
+            let Some(msg) = msg.as_core() else {
+                Self::dispatch_user_msg(self.user_msg_hook, user, msg);
+                continue;
+            };
 
-        let mut incoming_split_msgs = Vec::<SplitKeyboardLinkMessage, 16>::new();
-        self.split_bus.poll_into_vec(&mut incoming_split_msgs);
-        for msg in incoming_split_msgs {
             match msg {
                 SplitKeyboardLinkMessage::MatrixKeyDown { row, col } => {
+                    activity.keys_changed = true;
                     self.layout_update_key_state::<CurSide::Opposite>(
-                        row,
-                        col,
+                        *row,
+                        *col,
                         KeyState::Pressed,
                         user,
                     );
                 }
                 SplitKeyboardLinkMessage::MatrixKeyUp { row, col } => {
+                    activity.keys_changed = true;
                     self.layout_update_key_state::<CurSide::Opposite>(
-                        row,
-                        col,
+                        *row,
+                        *col,
                         KeyState::Released,
                         user,
                     );
                 }
+                SplitKeyboardLinkMessage::SupplyVoltage { millivolts } => {
+                    self.handle_peer_supply_voltage(*millivolts, user);
+                }
+                SplitKeyboardLinkMessage::MatrixKeyEvents { events } => {
+                    activity.keys_changed = true;
+                    for event in events {
+                        self.layout_update_key_state::<CurSide::Opposite>(
+                            event.row,
+                            event.col,
+                            event.state(),
+                            user,
+                        );
+                    }
+                }
+                SplitKeyboardLinkMessage::HostLeds { leds: _ } => {
+                    dev_warn!("Unexpected HostLeds message received while in master mode");
+                }
             }
         }
+    }
+
+    /// Hands a message of the target received from the peer to its hook.
+    fn dispatch_user_msg(hook: Option<UserMsgHook<User, LinkMsg::User>>, user: &mut User, msg: &LinkMsg) {
+        match (hook, msg.as_user()) {
+            (Some(hook), Some(user_msg)) => hook(user, user_msg),
+            _ => dev_debug!("Dropping message without a handler: {:?}", msg),
+        }
+    }
+
+    fn poll_master<'a, B: UsbBus>(&mut self, user: &mut User, device: &mut UsbDevice<'a, B>) -> PollActivity {
+        let mut activity = PollActivity {
+            host_suspended: device.state() == UsbDeviceState::Suspend,
+            ..Default::default()
+        };
+        let link_first = match self.poll_order {
+            PollOrder::LinkFirst => true,
+            PollOrder::MatrixFirst => false,
+            PollOrder::Alternate => !self.last_poll_link_first,
+        };
+        self.last_poll_link_first = link_first;
+
+        if link_first {
+            self.master_process_link(user, &mut activity, None);
+            self.record_scan();
+            self.master_scan_matrix(user, &mut activity);
+        } else {
+            let poll_start = self.clock.current_instant();
+            self.record_scan();
+            self.master_scan_matrix(user, &mut activity);
+            self.master_process_link(user, &mut activity, Some(poll_start));
+        }
 
+        self.tick_filter(user);
         self.sync_layers(user);
+        self.update_rollover(user);
 
-        if device.remote_wakeup_enabled() && device.state() == UsbDeviceState::Suspend && self.hid.total_pressed_keys() > 0 && self.remote_wakeup_signal_start_time.is_none() {
+        // The split link is kept up while the host is suspended, so the keys
+        // pressed in the slave side wake up the host just like the local ones.
+        let key_pressed = core::mem::take(&mut self.key_pressed);
+        if device.remote_wakeup_enabled() && device.state() == UsbDeviceState::Suspend && (key_pressed || self.hid.total_pressed_keys() > 0) && self.remote_wakeup_signal_start_time.is_none() {
             dev_info!("Enabling wakeup signal");
             self.remote_wakeup_signal_start_time = Some(self.clock.current_instant());
             self.hid.unpress_all_keys();
             device.remote_wakeup_start();
+            activity.usb_activity = true;
         }
 
         if let Some(wake_up_start) = self.remote_wakeup_signal_start_time {
@@ -391,62 +1318,164 @@ where
                 device.remote_wakeup_end();
                 self.remote_wakeup_signal_start_time = None;
             }
+            activity.usb_activity = true;
         }
 
+        if !self.hid.dirty() {
+            if self.text_playback.is_playing() {
+                self.text_playback.tick(&mut self.hid);
+            } else {
+                self.presence.tick(&self.clock, &mut self.hid);
+            }
+        }
+
+        activity.usb_activity |= self.hid.dirty();
         if let Err(e) = self.hid.tick() {
             dev_error!("Usb stalled: {:?}", e);
         }
+
+        activity
     }
 
-    fn poll_slave(&mut self) {
-        self.matrix.scan_matrix_act(|row, col, state| match state {
-            KeyState::Released => {
-                Self::split_link_transfer_msg(
-                    &mut self.split_bus,
-                    SplitKeyboardLinkMessage::MatrixKeyUp { row, col },
-                );
-            }
-            KeyState::Pressed => {
-                Self::split_link_transfer_msg(
-                    &mut self.split_bus,
-                    SplitKeyboardLinkMessage::MatrixKeyDown { row, col },
-                );
+    fn poll_slave(&mut self, user: &mut User) -> PollActivity {
+        let mut activity = PollActivity::default();
+        let mut events = core::mem::take(&mut self.pending_key_events);
+        let mut key_pressed = false;
+        self.record_scan();
+        self.matrix.scan_matrix_act(|row, col, state| {
+            activity.keys_changed = true;
+            key_pressed |= state == KeyState::Pressed;
+            if events.is_full() {
+                Self::split_link_transfer_key_events(&mut self.split_bus, &mut events);
             }
+
+            // Cannot fail, the batch was just flushed if it was full.
+            let _ = events.push(MatrixKeyEvent { row, col, pressed: state == KeyState::Pressed });
         });
 
+        // Queueing a message per scan would only make the congestion worse,
+        // so the changes are held back until the link catches up, or until
+        // they fill a whole message.
+        if self.split_bus.is_tx_congested() {
+            self.pending_key_events = events;
+        } else {
+            Self::split_link_transfer_key_events(&mut self.split_bus, &mut events);
+        }
+
+        // The key events couldn't be sent anyway, since the link is down while
+        // the master sleeps.
+        activity.peer_wake_requested = self.split_bus.peer_bye_reason() == Some(ByeReason::Suspend) && key_pressed;
+
+        // If the link is still up, the master is asked to wake up its host.
+        // A lost request is made again on the next press.
+        activity.host_suspended = self.split_bus.is_peer_suspended();
+        if activity.host_suspended && key_pressed {
+            let _ = self.split_bus.request_peer_wakeup();
+        }
+
+        let (clock, policy, last_scan) = (&self.clock, &self.overload_policy, self.last_scan_time);
+        let host_leds = &mut self.host_leds;
+        let user_msg_hook = self.user_msg_hook;
+        let mut cut = false;
         self.split_bus.poll(|msg| {
-            match msg {
-                SplitKeyboardLinkMessage::MatrixKeyDown { row: _, col: _ } => {
+            activity.link_rx_count += 1;
+            match msg.as_core() {
+                Some(SplitKeyboardLinkMessage::MatrixKeyDown { row: _, col: _ }) => {
                     dev_warn!("Unexpected MatrixKeyDown message received while in slave mode");
                 }
-                SplitKeyboardLinkMessage::MatrixKeyUp { row: _, col: _ } => {
+                Some(SplitKeyboardLinkMessage::MatrixKeyUp { row: _, col: _ }) => {
                     dev_warn!("Unexpected MatrixKeyUp message received while in slave mode");
                 }
+                Some(SplitKeyboardLinkMessage::SupplyVoltage { millivolts: _ }) => {
+                    dev_warn!("Unexpected SupplyVoltage message received while in slave mode");
+                }
+                Some(SplitKeyboardLinkMessage::MatrixKeyEvents { events: _ }) => {
+                    dev_warn!("Unexpected MatrixKeyEvents message received while in slave mode");
+                }
+                Some(SplitKeyboardLinkMessage::HostLeds { leds }) => {
+                    *host_leds = BootLeds::from_bits_retain(*leds);
+                }
+                None => Self::dispatch_user_msg(user_msg_hook, user, msg),
             }
-            true
+            let since_scan = last_scan.map(|last_scan| clock.elapsed_since(last_scan));
+            cut = policy.link_budget_exhausted(activity.link_rx_count, since_scan);
+            !cut
         });
+        if cut {
+            self.starvation.link_polls_cut = self.starvation.link_polls_cut.saturating_add(1);
+        }
+
+        activity
     }
 
-    fn check_master(&mut self) {
-        let res = self.master_tester.is_current_master();
+    /// Tells the peer whether this side has a host, and takes the role elected
+    /// by both sides while the link is up, so that only one of them acts as
+    /// the master even if both are powered through USB. The master tester
+    /// decides alone while the link is down, and a side without a host is
+    /// never the master.
+    fn check_master(&mut self, usb_state: UsbDeviceState) {
+        let sensed = self.master_tester.is_current_master();
+        self.split_bus.set_host_presence(match usb_state {
+            _ if !sensed => HostPresence::None,
+            UsbDeviceState::Configured | UsbDeviceState::Suspend => HostPresence::Configured,
+            _ => HostPresence::Vbus,
+        });
+        let res = sensed && self.split_bus.negotiated_role().is_none_or(|role| role == PeerRole::Master);
         if res != self.is_master {
             self.is_master = res;
+            self.host_leds_synced = false;
             if res {
                 dev_info!("Controller has been promoted to master");
             } else {
                 dev_info!("Controller has been downgraded to slave");
             }
         }
+        // Lets the slave save power while the host sleeps.
+        self.split_bus.set_local_suspended(res && usb_state == UsbDeviceState::Suspend);
     }
 
-    pub fn poll<'a, B: UsbBus>(&mut self, user: &mut User, device: &mut UsbDevice<'a, B>) {
-        self.check_master();
+    /// Runs a single iteration of the keyboard: scans the matrix, exchanges
+    /// the pending messages with the peer and updates the HID reports if the
+    /// current side is the master. Returns a summary of the activity observed
+    /// so that the caller can decide how frequently this should be called.
+    pub fn poll<'a, B: UsbBus>(&mut self, user: &mut User, device: &mut UsbDevice<'a, B>) -> PollActivity {
+        self.check_master(device.state());
+        let bootloader_requested = self.process_link_events();
 
-        if self.is_master {
-            self.poll_master(user, device);
+        let mut activity = if self.is_master {
+            self.poll_master(user, device)
         } else {
-            self.poll_slave();
+            self.poll_slave(user)
+        };
+
+        self.settle_host_leds(user);
+        if self.is_master {
+            self.sync_host_leds();
         }
+        activity.peer_bootloader_requested = bootloader_requested;
+        activity
+    }
+
+    /// Scans the local matrix only, without touching the split link or the
+    /// USB, for when the master spends most of the time in deep sleep, waking
+    /// up just for scanning. Returns whether any key was pressed since the
+    /// last [`poll`](Self::poll), in which case the keyboard should be polled
+    /// normally again for waking up the host.
+    pub fn poll_suspended(&mut self, user: &mut User) -> bool {
+        let mut activity = PollActivity::default();
+        // The scans while suspended are far apart on purpose.
+        self.last_scan_time = None;
+        self.master_scan_matrix(user, &mut activity);
+        self.tick_filter(user);
+        self.key_pressed
+    }
+
+    /// Makes the next poll wake up the host as if a key had been pressed, e.g
+    /// because the peer asked for it through a wake line while the link was
+    /// down. Only has effect if the host is suspended. The wake requests sent
+    /// by the peer through the link are handled already.
+    pub fn request_remote_wakeup(&mut self) {
+        self.key_pressed = true;
     }
 }
 
@@ -465,6 +1494,8 @@ impl<
     MasterTester,
     SplitBus,
     User,
+    Filter,
+    LinkMsg,
 > SplitKeyboardLike<KeyboardState<Key, LLAYERS, LROWS, LCOLS>>
     for SplitKeyboard<
         LLAYERS,
@@ -481,6 +1512,8 @@ impl<
         MasterTester,
         SplitBus,
         User,
+        Filter,
+        LinkMsg,
     >
 where
     Clk: Clock,
@@ -491,9 +1524,12 @@ where
     Key: HandleKey<User = User>,
     Matrix: KeyMatrixLike<MROWS, MCOLS>,
     MasterTester: MasterCheck,
-    SplitBus: SplitBusLike<SplitKeyboardLinkMessage>,
+    SplitBus: SplitBusLike<LinkMsg>,
+    Filter: KeyFilter,
+    LinkMsg: KeyboardLinkMessage,
     [(); LLAYERS as usize]:,
     [(); LCOLS as usize]:,
+    ColBitMatrixLayout<LCOLS>: BitMatrixLayout,
     [(); LROWS as usize]:,
     [(); valid_matrix_size!(LROWS, LCOLS)]:,
     ConstCond<{ LLAYERS > 0 }>: IsTrue,
@@ -508,6 +1544,144 @@ where
     fn hid_mut(&mut self) -> &mut Hid {
         &mut self.hid
     }
+
+    fn text_playback_mut(&mut self) -> &mut TextPlayback {
+        &mut self.text_playback
+    }
+
+    fn midi_mut(&mut self) -> &mut MidiOut {
+        &mut self.midi
+    }
+
+    fn steno_mut(&mut self) -> &mut StenoOut {
+        &mut self.steno
+    }
+
+    fn toggle_presence_mode(&mut self) -> bool {
+        self.presence.toggle(&self.clock)
+    }
+
+    fn gaming_mode(&self) -> &GamingMode {
+        &self.gaming
+    }
+
+    fn toggle_gaming_mode(&mut self) -> bool {
+        let enabled = self.gaming.toggle();
+        self.filter.set_low_latency(enabled);
+        enabled
+    }
+
+    fn os_remaps(&self) -> OsRemaps {
+        self.os_remaps
+    }
+
+    fn toggle_os_remaps(&mut self, remaps: OsRemaps) -> OsRemaps {
+        self.set_os_remaps(self.os_remaps ^ remaps);
+        self.os_remaps
+    }
+}
+
+impl<
+    const LLAYERS: u8,
+    const LROWS: u8,
+    const LCOLS: u8,
+    const MROWS: u8,
+    const MCOLS: u8,
+    Clk,
+    CurSide,
+    Hid,
+    LayoutConfig,
+    Key,
+    Matrix,
+    MasterTester,
+    SplitBus,
+    User,
+    Filter,
+    LinkMsg,
+> BuildableKeyboard
+    for SplitKeyboard<
+        LLAYERS,
+        LROWS,
+        LCOLS,
+        MROWS,
+        MCOLS,
+        Clk,
+        CurSide,
+        Hid,
+        LayoutConfig,
+        Key,
+        Matrix,
+        MasterTester,
+        SplitBus,
+        User,
+        Filter,
+        LinkMsg,
+    >
+where
+    Clk: Clock,
+    CurSide: SideLayoutOffset<LayoutConfig>,
+    CurSide::Opposite: SideLayoutOffset<LayoutConfig>,
+    Hid: HidKeyboard,
+    LayoutConfig: SplitLayoutConfig,
+    Key: HandleKey<User = User>,
+    Matrix: KeyMatrixLike<MROWS, MCOLS>,
+    MasterTester: MasterCheck,
+    SplitBus: SplitBusLike<LinkMsg>,
+    Filter: KeyFilter,
+    LinkMsg: KeyboardLinkMessage,
+    [(); LLAYERS as usize]:,
+    [(); LCOLS as usize]:,
+    ColBitMatrixLayout<LCOLS>: BitMatrixLayout,
+    [(); LROWS as usize]:,
+    [(); valid_matrix_size!(LROWS, LCOLS)]:,
+    ConstCond<{ LLAYERS > 0 }>: IsTrue,
+{
+    type Clock = Clk;
+    type Hid = Hid;
+    type Layout = SplitKeyboardLayout<LayoutConfig, Key, LLAYERS, LROWS, LCOLS>;
+    type Matrix = Matrix;
+    type SplitBus = SplitBus;
+    type MasterSense = MasterTester;
+    type Filter = Filter;
+    type User = User;
+    type UserMsg = LinkMsg::User;
+
+    fn from_parts(parts: KeyboardParts<Self>) -> Self {
+        let mut kb = Self::assemble(
+            parts.clock,
+            parts.hid,
+            parts.layout,
+            parts.matrix,
+            parts.split_bus,
+            parts.master_sense,
+            parts.filter,
+        );
+
+        let settings = parts.settings;
+        if let Some(order) = settings.poll_order {
+            kb.set_poll_order(order);
+        }
+        if let Some(policy) = settings.overload_policy {
+            kb.set_overload_policy(policy);
+        }
+        if let Some(max) = settings.max_pressed_keys {
+            kb.set_max_pressed_keys(Some(max));
+        }
+        if let Some(settle_time) = settings.host_leds_settle_time {
+            kb.set_host_leds_settle_time(settle_time);
+        }
+        if let Some(remaps) = settings.os_remaps {
+            kb.set_os_remaps(remaps);
+        }
+        if let Some((threshold_mv, hook)) = settings.low_voltage_warning {
+            kb.set_low_voltage_warning(threshold_mv, hook);
+        }
+        kb.rollover_hook = settings.rollover_hook;
+        kb.host_leds_hook = settings.host_leds_hook;
+        kb.unit_key_hook = settings.unit_key_hook;
+        kb.user_msg_hook = settings.user_msg_hook;
+        kb
+    }
 }
 
 pub trait SplitLayoutConfig {
@@ -530,6 +1704,48 @@ impl<Config: SplitLayoutConfig> SideLayoutOffset<Config> for Right {
     const SIDE_COL_OFFSET: u8 = Config::SPLIT_RIGHT_COL_OFFSET;
 }
 
+/// Layout config for single-piece (non-split) keyboards, where the whole
+/// layout belongs to the only side of the keyboard, which is the left one.
+pub struct UnibodyLayoutConfig;
+impl SplitLayoutConfig for UnibodyLayoutConfig {
+    const SPLIT_RIGHT_COL_OFFSET: u8 = 0;
+}
+
+/// The layout of a [`UnibodyKeyboard`].
+pub type UnibodyKeyboardLayout<Key, const LAYERS: u8, const ROWS: u8, const COLS: u8> =
+    SplitKeyboardLayout<UnibodyLayoutConfig, Key, LAYERS, ROWS, COLS>;
+
+/// A single-piece (non-split) keyboard. This is a [`SplitKeyboard`] that is
+/// always the master, with a matrix that covers the whole layout, and whose
+/// split bus is a [`NullSplitBus`], so it doesn't carry any link state. Build
+/// it with [`SplitKeyboard::builder`] passing [`NullSplitBus`] and
+/// [`AlwaysMaster`].
+pub type UnibodyKeyboard<
+    const LAYERS: u8,
+    const ROWS: u8,
+    const COLS: u8,
+    Clk,
+    Hid,
+    Key,
+    Matrix,
+    User,
+> = SplitKeyboard<
+    LAYERS,
+    ROWS,
+    COLS,
+    ROWS,
+    COLS,
+    Clk,
+    Left,
+    Hid,
+    UnibodyLayoutConfig,
+    Key,
+    Matrix,
+    AlwaysMaster,
+    NullSplitBus,
+    User,
+>;
+
 #[repr(transparent)]
 pub struct LayerRow<Key, const COLS: u8>
 where
@@ -636,6 +1852,11 @@ pub trait KeyboardStateLike {
     fn requested_layer_raw(&self) -> u8;
 }
 
+#[cfg(not(feature = "no-layers-stack"))]
+const LAYERS_STACK_LEN: usize = 8;
+#[cfg(feature = "no-layers-stack")]
+const LAYERS_STACK_LEN: usize = 1;
+
 pub struct KeyboardState<K: HandleKey, const LAYERS: u8, const ROWS: u8, const COLS: u8>
 where
     [(); ROWS as usize]:,
@@ -646,7 +1867,7 @@ where
     ConstCond<{ LAYERS > 0 }>: IsTrue,
 {
     // Once the stack gets full, it will smash the last recent entry to make room for the new one.
-    layers_stack: Vec<BoundedU8<LAYERS>, 8>,
+    layers_stack: Vec<BoundedU8<LAYERS>, LAYERS_STACK_LEN>,
 
     // TODO We could have a list of keys pressed here, that indicates the exact
     // keys that are pressed, and prevent any duplicated press if a given key is
@@ -704,7 +1925,7 @@ where
     fn validate_requested_layer(layer: u8) -> Option<BoundedU8<LAYERS>> {
         let ret = BoundedU8::from_value(layer);
         if ret.is_none() {
-            dev_warn!("Requested layer out of bounds: {}", layer);
+            dev_event!(warn, crate::log_ids::LAYER_OUT_OF_BOUNDS, "Requested layer out of bounds: {}", layer);
         }
 
         ret
@@ -766,7 +1987,7 @@ where
     }
 
     fn request_active_layer(&mut self, layer: BoundedU8<LAYERS>) {
-        dev_info!("New layer requested: {}", layer.value());
+        dev_event!(info, crate::log_ids::LAYER_REQUESTED, "New layer requested: {}", layer.value());
         self.requested_layer = layer;
     }
 
@@ -776,20 +1997,29 @@ where
         // of them.
         let len = self.layers_stack.len();
         if let Err(_) = self.layers_stack.push(self.requested_layer) {
-            self.layers_stack[len - 1] = self.requested_layer;
+            // Without the stack, the only entry is kept, so that popping
+            // always goes back to the layer active before the first push.
+            if !cfg!(feature = "no-layers-stack") {
+                self.layers_stack[len - 1] = self.requested_layer;
+            }
         }
-        dev_info!("Pushed layer onto stack: {}", self.requested_layer.value());
+        dev_event!(
+            info,
+            crate::log_ids::LAYER_PUSHED,
+            "Pushed layer onto stack: {}",
+            self.requested_layer.value()
+        );
         self.request_active_layer(new_layer);
     }
 
     fn pop_layer(&mut self) -> Option<BoundedU8<LAYERS>> {
         if let Some(head) = self.layers_stack.pop() {
             let prev = self.requested_layer;
-            dev_trace!("Popped layer: {}", head);
+            dev_event!(trace, crate::log_ids::LAYER_POPPED, "Popped layer: {}", head.value());
             self.request_active_layer(head);
             Some(prev)
         } else {
-            dev_warn!("Failed to pop layer: Layer stack was empty");
+            dev_event!(warn, crate::log_ids::LAYER_STACK_EMPTY, "Failed to pop layer: Layer stack was empty");
             None
         }
     }
@@ -845,6 +2075,27 @@ where
     }
 }
 
+/// The expected key at a given position of a layout, as generated by the
+/// `layers!` macro with `emit: usage_table`.
+#[derive(Debug, Clone)]
+pub struct LayoutUsageEntry<Key> {
+    pub layer: u8,
+    pub row: u8,
+    pub col: u8,
+    /// The tokens used for defining the key in the macro, for diagnostics.
+    pub alias: &'static str,
+    pub key: Key,
+}
+
+#[derive(Debug)]
+pub enum LayoutUsageMismatch<'a, Key> {
+    /// The table doesn't have an entry for every position of the layout.
+    WrongSize { expected: usize, found: usize },
+    /// The key in the layout is not the expected one, or the position doesn't
+    /// exist in the layout (in which case `found` is None).
+    Key { entry: &'a LayoutUsageEntry<Key>, found: Option<&'a Key> },
+}
+
 pub struct SplitKeyboardLayout<
     C: SplitLayoutConfig,
     Key,
@@ -854,10 +2105,13 @@ pub struct SplitKeyboardLayout<
 > where
     [(); LAYERS as usize]:,
     [(); COLS as usize]:,
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
     [(); ROWS as usize]:,
 {
     _config: PhantomData<C>,
     layers: [LayoutLayer<Key, ROWS, COLS>; LAYERS as usize],
+    /// The positions of the layout that have a physical key.
+    presence: BitMatrix<{ ROWS as usize }, COLS>,
 }
 
 impl<C: SplitLayoutConfig, Key, const LAYERS: u8, const ROWS: u8, const COLS: u8>
@@ -865,6 +2119,7 @@ impl<C: SplitLayoutConfig, Key, const LAYERS: u8, const ROWS: u8, const COLS: u8
 where
     [(); LAYERS as usize]:,
     [(); COLS as usize]:,
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
     [(); ROWS as usize]:,
 {
     const fn assert_config_ok() {
@@ -881,9 +2136,27 @@ where
         Self {
             _config: PhantomData,
             layers,
+            presence: BitMatrix::filled(),
         }
     }
 
+    /// Sets the positions of the layout that have a physical key, as a
+    /// matrix of the same size of the layout. The rest of positions never
+    /// generate key events, even if the matrix reports them as pressed, e.g
+    /// because of spurious reads in unpopulated intersections. Every position
+    /// has a key by default.
+    pub const fn with_key_presence(mut self, presence: BitMatrix<{ ROWS as usize }, COLS>) -> Self {
+        self.presence = presence;
+        self
+    }
+
+    /// Returns whether there's a physical key in the given position of the
+    /// layout.
+    #[inline(always)]
+    pub fn is_key_present(&self, real_row: u8, real_col: u8) -> bool {
+        self.presence.get_value(real_row as usize, real_col)
+    }
+
     // TODO Better differentiation between real key coordinates (which
     // the (0,0) is at the top, left, of the left side) and side
     // dependent coords, which the (0, 0) is at the top, left of the
@@ -900,4 +2173,30 @@ where
     fn get_key_definition(&self, layer: BoundedU8<LAYERS>, real_row: u8, real_col: u8) -> &Key {
         self.layers[layer.value() as usize].get_key_definition(real_row, real_col)
     }
+
+    /// Checks that every key of the layout matches the given usage table,
+    /// returning the first mismatch found. Meant to be used from tests.
+    pub fn check_usage_table<'a>(
+        &'a self,
+        table: &'a [LayoutUsageEntry<Key>],
+    ) -> Result<(), LayoutUsageMismatch<'a, Key>>
+    where
+        Key: PartialEq,
+    {
+        let expected = matrix_size(ROWS, COLS) * LAYERS as usize;
+        if table.len() != expected {
+            return Err(LayoutUsageMismatch::WrongSize { expected, found: table.len() });
+        }
+
+        for entry in table {
+            let found = (entry.layer < LAYERS && entry.row < ROWS && entry.col < COLS)
+                .then(|| self.layers[entry.layer as usize].get_key_definition(entry.row, entry.col));
+
+            if found != Some(&entry.key) {
+                return Err(LayoutUsageMismatch::Key { entry, found });
+            }
+        }
+
+        Ok(())
+    }
 }
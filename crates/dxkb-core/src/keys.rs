@@ -5,6 +5,8 @@ use usbd_hid::descriptor::KeyboardUsage;
 use crate::{
     hid::HidKeyboard,
     keyboard::{HandleKey, KeyboardStateLike, SplitKeyboardLike},
+    remap::OsRemaps,
+    steno::StenoKey,
 };
 
 #[macro_export]
@@ -28,9 +30,16 @@ pub fn standard_key_handle<S, Kb: SplitKeyboardLike<S>>(
     old_key_state: LogicalKeyState,
     new_key_state: LogicalKeyState,
 ) {
+    let key = kb.os_remaps().apply(key);
     do_on_key_state_ignore_masked!(
         old_key_state, new_key_state,
-        { kb.hid_mut().press_key(key) }, {
+        {
+            // The releases always go through, in case the key was pressed
+            // before enabling the gaming mode.
+            if kb.gaming_mode().allows(key) {
+                let _ = kb.hid_mut().press_key(key);
+            }
+        }, {
         kb.hid_mut().release_key(key)
     });
 }
@@ -49,6 +58,49 @@ pub fn consumer_control_key_handle<S, Kb: SplitKeyboardLike<S>>(
     );
 }
 
+pub fn text_key_handle<S, Kb: SplitKeyboardLike<S>>(
+    kb: &mut Kb,
+    text: &'static str,
+    old_key_state: LogicalKeyState,
+    new_key_state: LogicalKeyState,
+) {
+    do_on_key_state_ignore_masked!(
+        old_key_state,
+        new_key_state,
+        { kb.text_playback_mut().start(text) },
+        {}
+    );
+}
+
+pub fn midi_note_key_handle<S, Kb: SplitKeyboardLike<S>>(
+    kb: &mut Kb,
+    channel: u8,
+    note: u8,
+    old_key_state: LogicalKeyState,
+    new_key_state: LogicalKeyState,
+) {
+    do_on_key_state_ignore_masked!(
+        old_key_state,
+        new_key_state,
+        { kb.midi_mut().note_on(channel, note) },
+        { kb.midi_mut().note_off(channel, note) }
+    );
+}
+
+pub fn steno_key_handle<S, Kb: SplitKeyboardLike<S>>(
+    kb: &mut Kb,
+    key: StenoKey,
+    old_key_state: LogicalKeyState,
+    new_key_state: LogicalKeyState,
+) {
+    do_on_key_state_ignore_masked!(
+        old_key_state,
+        new_key_state,
+        { kb.steno_mut().press(key) },
+        { kb.steno_mut().release(key) }
+    );
+}
+
 pub fn function_key_handle<S: KeyboardStateLike, Kb: SplitKeyboardLike<S>>(
     kb: &mut Kb,
     key: &BuiltinFunctionKey,
@@ -152,6 +204,36 @@ pub fn function_key_handle<S: KeyboardStateLike, Kb: SplitKeyboardLike<S>>(
                 }
             );
         }
+        BuiltinFunctionKey::TogglePresenceMode => {
+            do_on_key_state_ignore_masked!(
+                old_key_state,
+                new_key_state,
+                {
+                    let _ = kb.toggle_presence_mode();
+                },
+                {}
+            );
+        }
+        BuiltinFunctionKey::ToggleOsRemaps(remaps) => {
+            do_on_key_state_ignore_masked!(
+                old_key_state,
+                new_key_state,
+                {
+                    let _ = kb.toggle_os_remaps(*remaps);
+                },
+                {}
+            );
+        }
+        BuiltinFunctionKey::ToggleGamingMode => {
+            do_on_key_state_ignore_masked!(
+                old_key_state,
+                new_key_state,
+                {
+                    let _ = kb.toggle_gaming_mode();
+                },
+                {}
+            );
+        }
     }
 }
 
@@ -192,6 +274,18 @@ pub enum BuiltinFunctionKey {
     /// and requests the current layer minus the given offset to become active
     /// again.
     SetRelativeLayerTransient(i8),
+
+    /// Enables or disables the presence mode (see [`crate::presence`]). When
+    /// released, does nothing.
+    TogglePresenceMode,
+
+    /// Enables or disables the given OS remaps (see [`crate::remap`]). When
+    /// released, does nothing.
+    ToggleOsRemaps(OsRemaps),
+
+    /// Enables or disables the gaming mode (see [`crate::gaming`]). When
+    /// released, does nothing.
+    ToggleGamingMode,
 }
 
 // TODO after the inclusion of the consumer control keys, the size of this enum
@@ -200,13 +294,27 @@ pub enum BuiltinFunctionKey {
 // possible to make the rust enum optimization work for such amount of nested
 // enums, without changing the layout of the underlying enums, so alternatives
 // should be considered (e. g collapsing all the nested enums into the same one
-// and manually using the not used variants, etc).
+// and manually using the not used variants, etc). The text keys made it even
+// worse, since the &'static str takes 8 bytes by itself in the target.
 #[derive(Clone, PartialEq, Eq)]
 pub enum DefaultKey {
     NoOp,
     Standard(KeyboardUsage),
     Function(BuiltinFunctionKey),
     ConsumerControl(Consumer),
+    /// Types the given ASCII text when pressed. Chars that need shift in an
+    /// US layout (uppercase letters and symbols) are typed with it.
+    Text(&'static str),
+    /// Plays the given MIDI note (0 to 127) on the given channel (0 to 15)
+    /// while pressed, with the velocity set in the [`MidiOut`] of the
+    /// keyboard. See [`crate::midi`].
+    ///
+    /// [`MidiOut`]: crate::midi::MidiOut
+    MidiNote { channel: u8, note: u8 },
+    /// A key of a steno machine, that is sent as part of a chord once every
+    /// steno key is released, instead of through the HID keyboard. See
+    /// [`crate::steno`].
+    Steno(StenoKey),
 }
 
 impl From<KeyboardUsage> for DefaultKey {
@@ -236,6 +344,15 @@ impl HandleKey for DefaultKey {
             DefaultKey::ConsumerControl(key) => {
                 consumer_control_key_handle(kb, *key, old_state, new_state);
             }
+            DefaultKey::Text(text) => {
+                text_key_handle(kb, text, old_state, new_state);
+            }
+            DefaultKey::MidiNote { channel, note } => {
+                midi_note_key_handle(kb, *channel, *note, old_state, new_state);
+            }
+            DefaultKey::Steno(key) => {
+                steno_key_handle(kb, *key, old_state, new_state);
+            }
         }
     }
 }
@@ -422,6 +539,27 @@ macro_rules! function_key_from_alias {
             -$layer,
         )
     };
+    (Prsnc) => {
+        $crate::keys::BuiltinFunctionKey::TogglePresenceMode
+    };
+    (Game) => {
+        $crate::keys::BuiltinFunctionKey::ToggleGamingMode
+    };
+    (SwpCG) => {
+        $crate::keys::BuiltinFunctionKey::ToggleOsRemaps(
+            $crate::remap::OsRemaps::SWAP_LCTRL_LGUI,
+        )
+    };
+    (SwpAG) => {
+        $crate::keys::BuiltinFunctionKey::ToggleOsRemaps(
+            $crate::remap::OsRemaps::SWAP_ALT_GUI,
+        )
+    };
+    (CapsCtl) => {
+        $crate::keys::BuiltinFunctionKey::ToggleOsRemaps(
+            $crate::remap::OsRemaps::CAPS_LOCK_CTRL,
+        )
+    };
 }
 
 #[macro_export]
@@ -492,6 +630,20 @@ macro_rules! default_key_from_alias {
         $crate::keys::DefaultKey::Function($crate::function_key_from_alias!($($f)*))
     };
 
+    (t:$text:literal) => {
+        $crate::keys::DefaultKey::Text($text)
+    };
+
+    // MIDI channels are numbered from 1 to 16, as in most of the MIDI
+    // software.
+    (m:($channel:literal, $note:literal)) => {
+        $crate::keys::DefaultKey::MidiNote { channel: $channel - 1, note: $note }
+    };
+
+    (s:$key:ident) => {
+        $crate::keys::DefaultKey::Steno($crate::steno::StenoKey::$key)
+    };
+
     (c:$($cc:tt)*) => {
         $crate::keys::DefaultKey::ConsumerControl($crate::consumer_control_usage_from_alias!($($cc)*))
     };
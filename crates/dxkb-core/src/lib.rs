@@ -3,11 +3,31 @@
 #![feature(macro_metavar_expr_concat)]
 #![feature(maybe_uninit_uninit_array_transpose)]
 #![feature(macro_metavar_expr)]
-#![no_std]
+#![cfg_attr(not(feature = "sim"), no_std)]
 
+pub mod build_info;
+pub mod builder;
+pub mod encoder;
+pub mod filter;
+pub mod gaming;
 pub mod hid;
+pub mod indicators;
 pub mod keyboard;
 pub mod keys;
 pub mod log;
+pub mod log_ids;
+pub mod midi;
+pub mod playback;
+pub mod power;
+pub mod presence;
+pub mod remap;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod steno;
+pub mod usage_names;
 pub mod usb;
 pub mod debug;
+#[cfg(feature = "stm32f411")]
+pub mod link_stats;
+#[cfg(feature = "stm32f411")]
+pub mod config;
@@ -0,0 +1,113 @@
+use core::time::Duration;
+
+use dxkb_common::{dev_info, dev_warn, time::{Clock, Stopwatch}};
+use dxkb_peripheral::flash_config::FlashConfig;
+use dxkb_split_link::LinkStats;
+
+/// Key of the flash config area under which the cumulative link stats are
+/// stored.
+pub const LINK_STATS_CONFIG_KEY: u8 = 0x01;
+
+/// Minimum time between two writes of the link stats into the flash. The
+/// config sector is rated for 10k erase cycles, so this shouldn't be too short.
+pub const LINK_STATS_STORE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Version of the layout of the stored link stats, which goes in the first
+/// byte of the record. Fields appended to [`LinkStats`] don't need a new
+/// version, since they are read as zero from older records, but any other
+/// change to its layout does, so that older records are discarded instead of
+/// misread.
+const LINK_STATS_VERSION: u8 = 1;
+
+const LINK_STATS_ENCODED_LEN: usize = 1 + size_of::<LinkStats>();
+
+/// Keeps track of the link stats accumulated across power cycles. The stats
+/// from previous boots are loaded from the flash config area, and the totals
+/// are periodically stored back while the stats change.
+pub struct PersistentLinkStats<C: Clock> {
+    /// Totals accumulated in previous boots.
+    previous: LinkStats,
+    /// Stats of the current boot the last time they were stored.
+    last_stored: LinkStats,
+    /// Time since the stats were last stored, which is way longer than the
+    /// range of some clocks.
+    since_store: Stopwatch<C::TInstant>,
+}
+
+impl<C: Clock> PersistentLinkStats<C> {
+    pub fn load(config: &FlashConfig, clock: &C) -> Self {
+        let mut buf = [0u8; LINK_STATS_ENCODED_LEN];
+        let previous = match config.read(LINK_STATS_CONFIG_KEY, &mut buf) {
+            // Stats stored by older firmwares may have less fields. The whole
+            // zeroed buffer is deserialized, so that those are read as zero.
+            Some(len) if len > 0 && buf[0] == LINK_STATS_VERSION => {
+                match ssmarshal::deserialize::<LinkStats>(&buf[1..]) {
+                    Ok((stats, _)) => stats,
+                    Err(e) => {
+                        dev_warn!("Discarding invalid persisted link stats: {:?}", e);
+                        LinkStats::default()
+                    }
+                }
+            }
+            Some(_) => {
+                dev_warn!("Discarding persisted link stats of an unknown version");
+                LinkStats::default()
+            }
+            None => LinkStats::default(),
+        };
+
+        dev_info!("Persisted link stats: {:?}", previous);
+        Self {
+            previous,
+            last_stored: LinkStats::default(),
+            since_store: Stopwatch::start(clock),
+        }
+    }
+
+    /// Returns the stats accumulated across all the power cycles, given the
+    /// stats of the current one.
+    pub fn totals(&self, current: &LinkStats) -> LinkStats {
+        self.previous.accumulate(current)
+    }
+
+    fn errors_changed(a: &LinkStats, b: &LinkStats) -> bool {
+        a.retransmissions != b.retransmissions
+            || a.crc_errors != b.crc_errors
+            || a.link_down_count != b.link_down_count
+            || a.duplicate_acks != b.duplicate_acks
+            || a.duplicate_frames != b.duplicate_frames
+            || a.out_of_order_frames != b.out_of_order_frames
+            || a.unexpected_acks != b.unexpected_acks
+            || a.auth_failures != b.auth_failures
+            || a.dropped_msgs != b.dropped_msgs
+            || a.user_tx_queue_full != b.user_tx_queue_full
+            || a.control_tx_queue_full != b.control_tx_queue_full
+            || a.user_tx_queue_high_watermark != b.user_tx_queue_high_watermark
+            || a.control_tx_queue_high_watermark != b.control_tx_queue_high_watermark
+    }
+
+    /// Stores the totals in the flash if any of the error counters or the
+    /// high watermarks of the TX queues have changed and enough time has
+    /// passed since the last time they were stored. The traffic counters
+    /// change all the time while the link is up, so they alone don't trigger
+    /// a write, for sparing the flash.
+    pub fn poll(&mut self, config: &mut FlashConfig, clock: &C, current: &LinkStats) {
+        // Measured on every poll, so that the stopwatch keeps up with the
+        // clock.
+        if self.since_store.elapsed(clock) < LINK_STATS_STORE_INTERVAL
+            || !Self::errors_changed(current, &self.last_stored)
+        {
+            return;
+        }
+
+        self.since_store = Stopwatch::start(clock);
+
+        let mut buf = [0u8; LINK_STATS_ENCODED_LEN];
+        buf[0] = LINK_STATS_VERSION;
+        let len = 1 + ssmarshal::serialize(&mut buf[1..], &self.totals(current)).unwrap();
+        match config.write(LINK_STATS_CONFIG_KEY, &buf[..len]) {
+            Ok(()) => self.last_stored = *current,
+            Err(e) => dev_warn!("Couldn't persist link stats: {:?}", e),
+        }
+    }
+}
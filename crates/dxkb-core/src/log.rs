@@ -1,5 +1,7 @@
+#[cfg(not(feature = "sim"))]
 use core::cell::RefCell;
 
+#[cfg(not(feature = "sim"))]
 use cortex_m::interrupt::{free, Mutex};
 use dxkb_common::util::RingBuffer;
 use log::{Level, Log, SetLoggerError};
@@ -21,36 +23,61 @@ impl<const SIZE: usize> core::fmt::Write for WriterRingBuffer<SIZE> {
     }
 }
 
+// The critical sections of the MCU are not available in the simulation build,
+// where the logger may be shared by several threads instead.
+#[cfg(not(feature = "sim"))]
+type LockedBuffer<const SIZE: usize> = Mutex<RefCell<WriterRingBuffer<SIZE>>>;
+#[cfg(feature = "sim")]
+type LockedBuffer<const SIZE: usize> = std::sync::Mutex<WriterRingBuffer<SIZE>>;
+
 pub struct RingBufferLogger<const SIZE: usize> {
     log_level: Level,
-    buf: Mutex<RefCell<WriterRingBuffer<SIZE>>>
+    buf: LockedBuffer<SIZE>
 }
 
 impl<const SIZE: usize> RingBufferLogger<SIZE> {
     pub const fn new(level: Level, buf: RingBuffer<u8, SIZE>) -> Self {
         Self {
             log_level: level,
+            #[cfg(not(feature = "sim"))]
             buf: Mutex::new(RefCell::new(WriterRingBuffer {
                 buf
-            }))
+            })),
+            #[cfg(feature = "sim")]
+            buf: std::sync::Mutex::new(WriterRingBuffer {
+                buf
+            }),
         }
     }
 
+    #[cfg(not(feature = "sim"))]
+    fn with_buf<R>(&self, f: impl FnOnce(&mut WriterRingBuffer<SIZE>) -> R) -> R {
+        free(|cs| f(&mut self.buf.borrow(cs).borrow_mut()))
+    }
+
+    #[cfg(feature = "sim")]
+    fn with_buf<R>(&self, f: impl FnOnce(&mut WriterRingBuffer<SIZE>) -> R) -> R {
+        f(&mut self.buf.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
     pub fn install(logger: &'static Self) -> Result<(), SetLoggerError> {
+        #[cfg(not(feature = "sim"))]
         free(|_| log::set_logger(logger))?;
+        #[cfg(feature = "sim")]
+        log::set_logger(logger)?;
         log::set_max_level(logger.log_level.to_level_filter());
         Ok(())
     }
 
     pub fn drop_pending_bytes(&self, count: usize) {
-        free(|cs| {
-            self.buf.borrow(cs).borrow_mut().buf.drop_first(count);
+        self.with_buf(|w| {
+            w.buf.drop_first(count);
         })
     }
 
     pub fn read_pending_bytes(&self, buf: &mut [u8]) -> usize {
-        free(|cs| {
-            self.buf.borrow(cs).borrow().buf.read(buf)
+        self.with_buf(|w| {
+            w.buf.read(buf)
         })
     }
 }
@@ -60,10 +87,11 @@ impl<const SIZE: usize> Log for RingBufferLogger<SIZE> {
         metadata.level() >= self.log_level
     }
 
+    #[cfg(not(feature = "minimal-log"))]
     fn log(&self, record: &log::Record) {
-        free(|cs| {
+        self.with_buf(|w| {
             write!(
-                self.buf.borrow(cs).borrow_mut(),
+                w,
                 "{:<5} [{}] {}\n",
                 record.level(),
                 record.target(),
@@ -72,6 +100,18 @@ impl<const SIZE: usize> Log for RingBufferLogger<SIZE> {
         });
     }
 
+    #[cfg(feature = "minimal-log")]
+    fn log(&self, record: &log::Record) {
+        self.with_buf(|w| {
+            write!(
+                w,
+                "{:<5} {}\n",
+                record.level(),
+                record.args()
+            ).unwrap();
+        });
+    }
+
     fn flush(&self) {
 
     }
@@ -0,0 +1,22 @@
+//! Ids of the structured log events of the keyboard, logged with
+//! [`dxkb_common::dev_event!`] from the paths that run on every scan or key
+//! press.
+
+/// Args: the number of keys pressed.
+pub const ROLLOVER_ENTERED: u16 = 0x0201;
+pub const ROLLOVER_LEFT: u16 = 0x0202;
+/// Args: the [`TransferError`](dxkb_split_link::TransferError).
+pub const HOST_LEDS_TRANSFER_FAILED: u16 = 0x0203;
+/// Args: the [`TransferError`](dxkb_split_link::TransferError).
+pub const LINK_MSG_TRANSFER_FAILED: u16 = 0x0204;
+/// Args: the [`TransferError`](dxkb_split_link::TransferError).
+pub const KEY_EVENTS_TRANSFER_FAILED: u16 = 0x0205;
+/// Args: the layer.
+pub const LAYER_OUT_OF_BOUNDS: u16 = 0x0206;
+/// Args: the layer.
+pub const LAYER_REQUESTED: u16 = 0x0207;
+/// Args: the layer pushed onto the stack.
+pub const LAYER_PUSHED: u16 = 0x0208;
+/// Args: the layer popped from the stack.
+pub const LAYER_POPPED: u16 = 0x0209;
+pub const LAYER_STACK_EMPTY: u16 = 0x020A;
@@ -0,0 +1,244 @@
+//! USB-MIDI output, so that a layer can turn the keyboard into a MIDI
+//! controller through [`DefaultKey::MidiNote`](crate::keys::DefaultKey::MidiNote)
+//! keys.
+//!
+//! The keyboard queues the note events of those keys into its [`MidiOut`],
+//! whether or not the target exposes a MIDI interface. Targets that want one
+//! allocate a [`MidiFeature`], poll it along with the rest of USB features, and
+//! move the queued events into it with [`MidiFeature::send`] on every
+//! iteration of the main loop.
+
+use dxkb_common::{dev_debug, util};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::UsbClass,
+    descriptor::DescriptorWriter,
+    device::UsbDevice,
+    endpoint::EndpointIn,
+    UsbError,
+};
+
+use crate::usb::UsbFeature;
+
+/// Max number of note events that can be waiting for being sent to the host.
+pub const MIDI_QUEUE_LEN: usize = 16;
+
+/// Velocity of the notes played by keys that can't measure it.
+pub const MIDI_DEFAULT_VELOCITY: u8 = 100;
+
+const MIDI_PACKET_LEN: usize = 4;
+const MIDI_EP_PACKET_SIZE: u16 = 64;
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const USB_SUBCLASS_AUDIO_CONTROL: u8 = 0x01;
+const USB_SUBCLASS_MIDI_STREAMING: u8 = 0x03;
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const AC_HEADER: u8 = 0x01;
+const MS_HEADER: u8 = 0x01;
+const MS_MIDI_IN_JACK: u8 = 0x02;
+const MS_MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+const JACK_EMBEDDED: u8 = 0x01;
+const JACK_EXTERNAL: u8 = 0x02;
+
+// The notes come from an external jack (the keys) and reach the host through
+// the embedded jack bound to the IN endpoint.
+const EXTERNAL_IN_JACK_ID: u8 = 0x01;
+const EMBEDDED_OUT_JACK_ID: u8 = 0x02;
+
+// Length of every class-specific descriptor of the MIDI streaming interface,
+// including the endpoint ones: MS header (7), external IN jack (6), embedded
+// OUT jack (9), bulk endpoint (9) and MS endpoint (5).
+const MS_TOTAL_LENGTH: u16 = 7 + 6 + 9 + 9 + 5;
+
+/// A note event, as sent to the host. The channel ranges from 0 to 15, and the
+/// note and velocity from 0 to 127.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+}
+
+impl MidiEvent {
+    /// Returns the USB-MIDI event packet of the event, on the first virtual
+    /// cable.
+    pub fn to_packet(&self) -> [u8; MIDI_PACKET_LEN] {
+        match *self {
+            MidiEvent::NoteOn { channel, note, velocity } => {
+                [0x09, 0x90 | (channel & 0x0f), note & 0x7f, velocity & 0x7f]
+            }
+            MidiEvent::NoteOff { channel, note } => {
+                [0x08, 0x80 | (channel & 0x0f), note & 0x7f, 0]
+            }
+        }
+    }
+}
+
+/// Queue of the note events played by the keys, waiting for being sent to the
+/// host by a [`MidiFeature`].
+pub struct MidiOut {
+    pending: ConstGenericRingBuffer<MidiEvent, MIDI_QUEUE_LEN>,
+    velocity: u8,
+}
+
+impl MidiOut {
+    pub fn new() -> Self {
+        Self {
+            pending: ConstGenericRingBuffer::new(),
+            velocity: MIDI_DEFAULT_VELOCITY,
+        }
+    }
+
+    /// Returns the velocity of the notes played with [`Self::note_on`].
+    pub fn velocity(&self) -> u8 {
+        self.velocity
+    }
+
+    /// Sets the velocity of the notes played with [`Self::note_on`]. A
+    /// velocity of zero would be taken as a note off by the host, so it is
+    /// raised to 1.
+    pub fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = velocity.clamp(1, 127);
+    }
+
+    /// Starts playing the given note with the fixed velocity.
+    pub fn note_on(&mut self, channel: u8, note: u8) {
+        self.note_on_with_velocity(channel, note, self.velocity);
+    }
+
+    /// Starts playing the given note with the given velocity, for keys that
+    /// are able to measure it, like the ones of an analog matrix.
+    pub fn note_on_with_velocity(&mut self, channel: u8, note: u8, velocity: u8) {
+        self.push(MidiEvent::NoteOn { channel, note, velocity: velocity.clamp(1, 127) });
+    }
+
+    /// Stops playing the given note.
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        self.push(MidiEvent::NoteOff { channel, note });
+    }
+
+    /// Returns the number of events waiting for being sent.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops every event waiting for being sent, e.g because the host is gone.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    fn push(&mut self, event: MidiEvent) {
+        if self.pending.is_full() {
+            // Nothing is draining the queue if the target has no MIDI
+            // interface, so this is not worth a warning.
+            dev_debug!("MIDI queue is full. Dropping event {:?}", event);
+            return;
+        }
+        self.pending.enqueue(event);
+    }
+}
+
+struct MidiClass<'a, B: UsbBus> {
+    audio_if: InterfaceNumber,
+    midi_if: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        // USB-MIDI devices are audio devices, that need an (empty) audio
+        // control interface that refers to the MIDI streaming one.
+        writer.interface(self.audio_if, USB_CLASS_AUDIO, USB_SUBCLASS_AUDIO_CONTROL, 0x00)?;
+        writer.write(
+            CS_INTERFACE,
+            &[AC_HEADER, 0x00, 0x01, 0x09, 0x00, 0x01, u8::from(self.midi_if)],
+        )?;
+
+        writer.interface(self.midi_if, USB_CLASS_AUDIO, USB_SUBCLASS_MIDI_STREAMING, 0x00)?;
+        let [total_lo, total_hi] = MS_TOTAL_LENGTH.to_le_bytes();
+        writer.write(CS_INTERFACE, &[MS_HEADER, 0x00, 0x01, total_lo, total_hi])?;
+        writer.write(CS_INTERFACE, &[MS_MIDI_IN_JACK, JACK_EXTERNAL, EXTERNAL_IN_JACK_ID, 0x00])?;
+        writer.write(
+            CS_INTERFACE,
+            &[MS_MIDI_OUT_JACK, JACK_EMBEDDED, EMBEDDED_OUT_JACK_ID, 0x01, EXTERNAL_IN_JACK_ID, 0x01, 0x00],
+        )?;
+
+        // Audio class endpoints carry two extra bytes (bRefresh and
+        // bSynchAddress), unused by MIDI.
+        writer.endpoint_ex(&self.ep_in, |extra| {
+            extra[0] = 0x00;
+            extra[1] = 0x00;
+            Ok(2)
+        })?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 0x01, EMBEDDED_OUT_JACK_ID])?;
+
+        Ok(())
+    }
+}
+
+/// USB feature that exposes a USB-MIDI interface with a single MIDI IN port
+/// in the host, through which the events queued in a [`MidiOut`] are sent.
+pub struct MidiFeature<'a, B: UsbBus> {
+    class: MidiClass<'a, B>,
+}
+
+impl<'a, B: UsbBus> MidiFeature<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            class: MidiClass {
+                audio_if: alloc.interface(),
+                midi_if: alloc.interface(),
+                ep_in: alloc.bulk(MIDI_EP_PACKET_SIZE),
+            },
+        }
+    }
+
+    /// Sends as many of the events queued in the given [`MidiOut`] as fit in
+    /// a single transfer. Events are only removed from the queue once the
+    /// transfer has been accepted, so they're retried on the next call while
+    /// the endpoint is busy.
+    pub fn send(&mut self, out: &mut MidiOut) {
+        if out.pending.is_empty() {
+            return;
+        }
+
+        let mut buf = [0u8; MIDI_EP_PACKET_SIZE as usize];
+        let mut len = 0;
+        for event in out.pending.iter() {
+            if len + MIDI_PACKET_LEN > buf.len() {
+                break;
+            }
+            buf[len..len + MIDI_PACKET_LEN].copy_from_slice(&event.to_packet());
+            len += MIDI_PACKET_LEN;
+        }
+
+        match self.class.ep_in.write(&buf[..len]) {
+            Ok(_) => {
+                for _ in 0..len / MIDI_PACKET_LEN {
+                    out.pending.dequeue();
+                }
+            }
+            Err(UsbError::WouldBlock) => {}
+            Err(e) => {
+                dev_debug!("Couldn't send MIDI events: {:?}", e);
+            }
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbFeature<B> for MidiFeature<'a, B> {
+    const EP: usize = 1;
+    type TPoll = ();
+
+    fn endpoints_mut(&mut self) -> [&mut dyn UsbClass<B>; Self::EP] {
+        util::slice::array_unify_length(
+          [&mut self.class]
+        )
+    }
+
+    fn usb_poll(&mut self, _device: &mut UsbDevice<B>) -> Self::TPoll {
+        // Nothing is received from the host.
+    }
+}
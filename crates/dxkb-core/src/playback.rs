@@ -0,0 +1,121 @@
+//! Playback of predefined sequences of keystrokes, like the text typed by
+//! [`DefaultKey::Text`](crate::keys::DefaultKey::Text).
+//!
+//! Keystrokes are emitted one HID report at a time: a key that is pressed and
+//! released within the same report would be invisible to the host, and
+//! repeated chars need a report in between for the host to see them as two
+//! different keystrokes.
+
+use dxkb_common::dev_warn;
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::hid::HidKeyboard;
+
+/// Translates an ASCII char into the usage of the key that types it in an US
+/// layout, and whether shift needs to be pressed along with it.
+pub fn ascii_to_usage(c: u8) -> Option<(KeyboardUsage, bool)> {
+    let usage = match c {
+        b'a'..=b'z' => (KeyboardUsage::KeyboardAa as u8 + (c - b'a'), false),
+        b'A'..=b'Z' => (KeyboardUsage::KeyboardAa as u8 + (c - b'A'), true),
+        b'1'..=b'9' => (KeyboardUsage::Keyboard1Exclamation as u8 + (c - b'1'), false),
+        b'0' => (KeyboardUsage::Keyboard0CloseParens as u8, false),
+        b'!' => (KeyboardUsage::Keyboard1Exclamation as u8, true),
+        b'@' => (KeyboardUsage::Keyboard2At as u8, true),
+        b'#' => (KeyboardUsage::Keyboard3Hash as u8, true),
+        b'$' => (KeyboardUsage::Keyboard4Dollar as u8, true),
+        b'%' => (KeyboardUsage::Keyboard5Percent as u8, true),
+        b'^' => (KeyboardUsage::Keyboard6Caret as u8, true),
+        b'&' => (KeyboardUsage::Keyboard7Ampersand as u8, true),
+        b'*' => (KeyboardUsage::Keyboard8Asterisk as u8, true),
+        b'(' => (KeyboardUsage::Keyboard9OpenParens as u8, true),
+        b')' => (KeyboardUsage::Keyboard0CloseParens as u8, true),
+        b'\n' => (KeyboardUsage::KeyboardEnter as u8, false),
+        b'\t' => (KeyboardUsage::KeyboardTab as u8, false),
+        b' ' => (KeyboardUsage::KeyboardSpacebar as u8, false),
+        b'-' => (KeyboardUsage::KeyboardDashUnderscore as u8, false),
+        b'_' => (KeyboardUsage::KeyboardDashUnderscore as u8, true),
+        b'=' => (KeyboardUsage::KeyboardEqualPlus as u8, false),
+        b'+' => (KeyboardUsage::KeyboardEqualPlus as u8, true),
+        b'[' => (KeyboardUsage::KeyboardOpenBracketBrace as u8, false),
+        b'{' => (KeyboardUsage::KeyboardOpenBracketBrace as u8, true),
+        b']' => (KeyboardUsage::KeyboardCloseBracketBrace as u8, false),
+        b'}' => (KeyboardUsage::KeyboardCloseBracketBrace as u8, true),
+        b'\\' => (KeyboardUsage::KeyboardBackslashBar as u8, false),
+        b'|' => (KeyboardUsage::KeyboardBackslashBar as u8, true),
+        b';' => (KeyboardUsage::KeyboardSemiColon as u8, false),
+        b':' => (KeyboardUsage::KeyboardSemiColon as u8, true),
+        b'\'' => (KeyboardUsage::KeyboardSingleDoubleQuote as u8, false),
+        b'"' => (KeyboardUsage::KeyboardSingleDoubleQuote as u8, true),
+        b'`' => (KeyboardUsage::KeyboardBacktickTilde as u8, false),
+        b'~' => (KeyboardUsage::KeyboardBacktickTilde as u8, true),
+        b',' => (KeyboardUsage::KeyboardCommaLess as u8, false),
+        b'<' => (KeyboardUsage::KeyboardCommaLess as u8, true),
+        b'.' => (KeyboardUsage::KeyboardPeriodGreater as u8, false),
+        b'>' => (KeyboardUsage::KeyboardPeriodGreater as u8, true),
+        b'/' => (KeyboardUsage::KeyboardSlashQuestion as u8, false),
+        b'?' => (KeyboardUsage::KeyboardSlashQuestion as u8, true),
+        _ => return None,
+    };
+
+    Some((KeyboardUsage::from(usage.0), usage.1))
+}
+
+/// Types a string, one char every two HID reports (press and release).
+pub struct TextPlayback {
+    text: &'static [u8],
+    /// The key currently pressed, and whether shift was pressed by the
+    /// playback along with it.
+    pressed: Option<(KeyboardUsage, bool)>,
+}
+
+impl TextPlayback {
+    pub const fn new() -> Self {
+        Self {
+            text: &[],
+            pressed: None,
+        }
+    }
+
+    /// Starts typing the given text. Returns false if there's already some
+    /// text being typed, in which case the text is ignored.
+    pub fn start(&mut self, text: &'static str) -> bool {
+        if self.is_playing() {
+            dev_warn!("Ignoring text while another one is being typed");
+            return false;
+        }
+
+        self.text = text.as_bytes();
+        true
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.text.is_empty() || self.pressed.is_some()
+    }
+
+    /// Emits the next press or release of the text. Must only be called when
+    /// the HID report of the previous call has been already sent (i.e the HID
+    /// is not dirty).
+    pub fn tick<H: HidKeyboard>(&mut self, hid: &mut H) {
+        if let Some((usage, shift)) = self.pressed.take() {
+            let _ = hid.release_key(usage);
+            if shift {
+                let _ = hid.release_key(KeyboardUsage::KeyboardLeftShift);
+            }
+            return;
+        }
+
+        while let Some((&c, rest)) = self.text.split_first() {
+            self.text = rest;
+            let Some((usage, shift)) = ascii_to_usage(c) else {
+                dev_warn!("Skipping char that can't be typed: {:#x}", c);
+                continue;
+            };
+
+            // If shift is already held (e.g by the user), leave it as it is.
+            let shift = shift && hid.press_key(KeyboardUsage::KeyboardLeftShift).is_ok();
+            let _ = hid.press_key(usage);
+            self.pressed = Some((usage, shift));
+            return;
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Tracking of the current that the keyboard is allowed to draw from the
+//! upstream USB port, so that power hungry subsystems (e.g lighting) can scale
+//! down when connected to a charger or a host that didn't configure the device.
+//!
+//! The STM32F411 OTG peripheral has no USB Battery Charging (BCD) detection,
+//! so the kind of port is guessed from the enumeration: a data host resets and
+//! configures the device soon after VBUS is applied, while a dedicated charger
+//! never does.
+
+use core::time::Duration;
+
+use dxkb_common::{dev_info, time::Clock};
+use usb_device::device::UsbDeviceState;
+
+/// Time after VBUS is detected without the device being configured by a host
+/// for considering that the port is a charger.
+pub const CHARGER_DETECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Current that an unconfigured device can draw from a USB 2.0 port.
+pub const USB_UNCONFIGURED_CURRENT_MA: u16 = 100;
+
+/// Current assumed to be available from a charger. Chargers can usually give
+/// more, but without BCD detection the actual limit can't be known, so stick to
+/// the maximum of a regular USB 2.0 port.
+pub const USB_CHARGER_CURRENT_MA: u16 = 500;
+
+/// Current that a device can draw from a suspended USB 2.0 port.
+pub const USB_SUSPEND_CURRENT_MA: u16 = 2;
+
+/// Current reserved for the MCU and the rest of the keyboard, which the sinks
+/// must leave out of their budget.
+pub const MCU_CURRENT_MA: u16 = 50;
+
+/// Current drawn by a WS2812 LED lit in full white, 20 mA per color.
+pub const WS2812_MAX_CURRENT_MA: u16 = 60;
+
+/// Returns the brightness, out of 255, that keeps a strip of `leds` WS2812
+/// LEDs within the given current budget even if all of them are lit in full
+/// white.
+pub const fn ws2812_brightness_for_budget(leds: usize, budget_ma: u16) -> u8 {
+    let available_ma = budget_ma.saturating_sub(MCU_CURRENT_MA) as u32;
+    let max_ma = leds as u32 * WS2812_MAX_CURRENT_MA as u32;
+    if max_ma <= available_ma {
+        return u8::MAX;
+    }
+    (available_ma * u8::MAX as u32 / max_ma) as u8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbPortKind {
+    /// No VBUS, or not enough time has passed for telling what it is.
+    Unknown,
+    /// A host that has enumerated the device.
+    DataHost,
+    /// Something that provides VBUS but never enumerates the device, like a
+    /// wall charger or a power bank.
+    Charger,
+}
+
+/// Something whose current consumption can be limited, like a lighting
+/// engine.
+pub trait CurrentBudgetSink {
+    /// Called every time the current budget of the keyboard changes. The
+    /// implementation must not draw more than `budget_ma` in total, minus the
+    /// consumption of the MCU itself.
+    fn set_current_budget(&mut self, budget_ma: u16);
+}
+
+pub struct UsbPowerMonitor<C: Clock> {
+    /// Max power declared in the configuration descriptor of the device.
+    configured_max_power_ma: u16,
+    vbus_since: Option<C::TInstant>,
+    port_kind: UsbPortKind,
+    budget_ma: u16,
+}
+
+impl<C: Clock> UsbPowerMonitor<C> {
+    pub const fn new(configured_max_power_ma: u16) -> Self {
+        Self {
+            configured_max_power_ma,
+            vbus_since: None,
+            port_kind: UsbPortKind::Unknown,
+            budget_ma: 0,
+        }
+    }
+
+    pub fn port_kind(&self) -> UsbPortKind {
+        self.port_kind
+    }
+
+    pub fn current_budget(&self) -> u16 {
+        self.budget_ma
+    }
+
+    fn detect_port_kind(&mut self, clock: &C, vbus_present: bool, state: UsbDeviceState) -> UsbPortKind {
+        if !vbus_present {
+            self.vbus_since = None;
+            return UsbPortKind::Unknown;
+        }
+
+        let port_kind = match (self.port_kind, state) {
+            (_, UsbDeviceState::Configured) => UsbPortKind::DataHost,
+            // A host may suspend the device, but it's still a host.
+            (UsbPortKind::DataHost, _) => UsbPortKind::DataHost,
+            (UsbPortKind::Charger, _) => UsbPortKind::Charger,
+            (UsbPortKind::Unknown, _) => {
+                let vbus_since = *self.vbus_since.get_or_insert_with(|| clock.current_instant());
+                if clock.elapsed_since(vbus_since) >= CHARGER_DETECT_TIMEOUT {
+                    UsbPortKind::Charger
+                } else {
+                    UsbPortKind::Unknown
+                }
+            }
+        };
+
+        // Once decided, the time since VBUS showed up no longer matters, and
+        // it would eventually go out of the range of the clock.
+        if port_kind != UsbPortKind::Unknown {
+            self.vbus_since = None;
+        }
+        port_kind
+    }
+
+    /// Updates the port kind and the current budget from the VBUS presence and
+    /// the state of the USB device, notifying the sink if the budget changes.
+    /// Should be called after each poll of the USB device.
+    pub fn poll<S: CurrentBudgetSink>(
+        &mut self,
+        clock: &C,
+        vbus_present: bool,
+        state: UsbDeviceState,
+        sink: &mut S,
+    ) {
+        let port_kind = self.detect_port_kind(clock, vbus_present, state);
+        if port_kind != self.port_kind {
+            dev_info!("USB port kind changed: {:?} -> {:?}", self.port_kind, port_kind);
+            self.port_kind = port_kind;
+        }
+
+        let budget_ma = match (port_kind, state) {
+            // Without VBUS from USB, the keyboard is powered by other means
+            // (e.g the other half, or a battery) which are not known here, so
+            // be conservative.
+            (UsbPortKind::Unknown, _) => USB_UNCONFIGURED_CURRENT_MA,
+            (UsbPortKind::Charger, _) => USB_CHARGER_CURRENT_MA,
+            (UsbPortKind::DataHost, UsbDeviceState::Configured) => self.configured_max_power_ma,
+            (UsbPortKind::DataHost, UsbDeviceState::Suspend) => USB_SUSPEND_CURRENT_MA,
+            (UsbPortKind::DataHost, _) => USB_UNCONFIGURED_CURRENT_MA,
+        };
+
+        if budget_ma != self.budget_ma {
+            dev_info!("Current budget changed: {} mA -> {} mA", self.budget_ma, budget_ma);
+            self.budget_ma = budget_ma;
+            sink.set_current_budget(budget_ma);
+        }
+    }
+}
+
+/// A sink for when there's nothing that needs to be limited.
+impl CurrentBudgetSink for () {
+    fn set_current_budget(&mut self, _budget_ma: u16) {}
+}
+
+#[cfg(feature = "stm32f411")]
+mod ws2812 {
+    use dxkb_peripheral::ws2812::{ws2812_buf_len, Ws2812};
+    use stm32f4xx_hal::{
+        dma::{traits::{Channel, DMASet, Stream, StreamISR}, ChannelX, MemoryToPeripheral},
+        spi::{Instance, Tx},
+    };
+
+    use super::{ws2812_brightness_for_budget, CurrentBudgetSink};
+
+    /// Dims the whole strip as much as needed for the budget.
+    impl<SPI: Instance + 'static, S: Stream + StreamISR + 'static, const DMA_CH: u8, const LEDS: usize> CurrentBudgetSink
+        for Ws2812<SPI, S, DMA_CH, LEDS>
+    where
+        [(); ws2812_buf_len(LEDS)]:,
+        ChannelX<DMA_CH>: Channel,
+        Tx<SPI>: DMASet<S, DMA_CH, MemoryToPeripheral>,
+    {
+        fn set_current_budget(&mut self, budget_ma: u16) {
+            self.set_brightness(ws2812_brightness_for_budget(LEDS, budget_ma));
+        }
+    }
+}
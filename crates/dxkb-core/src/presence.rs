@@ -0,0 +1,95 @@
+//! Presence mode: while enabled, the keyboard periodically sends a double Scroll
+//! Lock pulse so that the host doesn't consider the user idle (which would lock
+//! the screen, set the user as away, etc). The pulse toggles the lock twice, so
+//! the Scroll Lock state of the host is left as it was.
+//!
+//! There's no mouse report yet, so the pulse is done with the keyboard, which
+//! also means that the Scroll Lock LED of the host blinks on each pulse. The
+//! mode itself can be shown with the [`Indicator::PresenceMode`] indicator.
+//!
+//! [`Indicator::PresenceMode`]: crate::indicators::Indicator::PresenceMode
+
+use core::time::Duration;
+
+use dxkb_common::{dev_info, time::{Clock, Stopwatch}};
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::hid::HidKeyboard;
+
+/// Default time between two pulses.
+pub const PRESENCE_DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Min time between two pulses that can be configured, for not flooding the
+/// host with keystrokes.
+pub const PRESENCE_MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of HID reports a pulse is made of: press, release, press, release.
+const PULSE_STEPS: u8 = 4;
+
+pub struct PresenceMode<C: Clock> {
+    enabled: bool,
+    interval: Duration,
+    /// Time since the last pulse, or since the mode was enabled, which may
+    /// be longer than the range of the clock. None while disabled.
+    since_pulse: Option<Stopwatch<C::TInstant>>,
+    /// Number of reports left for finishing the current pulse.
+    pulse_steps_left: u8,
+}
+
+impl<C: Clock> PresenceMode<C> {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            interval: PRESENCE_DEFAULT_INTERVAL,
+            since_pulse: None,
+            pulse_steps_left: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Sets the time between two pulses, which is clamped to
+    /// [`PRESENCE_MIN_INTERVAL`].
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval.max(PRESENCE_MIN_INTERVAL);
+    }
+
+    /// Enables or disables the presence mode. Returns whether it is enabled
+    /// after the change. A pulse in progress is always completed, so Scroll
+    /// Lock is never left toggled.
+    pub fn toggle(&mut self, clock: &C) -> bool {
+        self.enabled = !self.enabled;
+        self.since_pulse = self.enabled.then(|| Stopwatch::start(clock));
+        dev_info!("Presence mode enabled: {}", self.enabled);
+        self.enabled
+    }
+
+    /// Emits the next step of the pulse, or starts a new one if it's time to.
+    /// Must only be called when the HID report of the previous call has been
+    /// already sent (i.e the HID is not dirty).
+    pub fn tick<H: HidKeyboard>(&mut self, clock: &C, hid: &mut H) {
+        if self.pulse_steps_left == 0 {
+            let Some(since_pulse) = &mut self.since_pulse else {
+                return;
+            };
+
+            // Don't mess with whatever the user is typing. The user is
+            // obviously present anyway.
+            if since_pulse.elapsed(clock) < self.interval || hid.total_pressed_keys() > 0 {
+                return;
+            }
+
+            *since_pulse = Stopwatch::start(clock);
+            self.pulse_steps_left = PULSE_STEPS;
+        }
+
+        self.pulse_steps_left -= 1;
+        if self.pulse_steps_left % 2 == 1 {
+            let _ = hid.press_key(KeyboardUsage::KeyboardScrollLock);
+        } else {
+            let _ = hid.release_key(KeyboardUsage::KeyboardScrollLock);
+        }
+    }
+}
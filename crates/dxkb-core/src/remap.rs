@@ -0,0 +1,77 @@
+//! OS convenience remaps: small tweaks applied to every standard key right
+//! before it is sent to the host, so that the same layout can be used across
+//! operating systems without needing a separate layer for each one. They can be
+//! toggled at runtime with function keys, and persisted in the flash config.
+
+use bitflags::bitflags;
+use usbd_hid::descriptor::KeyboardUsage;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct OsRemaps: u8 {
+        /// Swaps the left Ctrl and the left GUI keys.
+        const SWAP_LCTRL_LGUI = 0b00000001;
+        /// Swaps the Alt and GUI keys of both sides, so that the keys next to
+        /// the space bar match the layout of a macOS keyboard.
+        const SWAP_ALT_GUI    = 0b00000010;
+        /// Makes CapsLock act as the left Ctrl.
+        const CAPS_LOCK_CTRL  = 0b00000100;
+    }
+}
+
+impl OsRemaps {
+    /// Returns the usage that must be sent to the host for the given key.
+    pub fn apply(&self, usage: KeyboardUsage) -> KeyboardUsage {
+        use KeyboardUsage::*;
+
+        // Each remap only applies to the keys as they are defined in the
+        // layout, so they are independent from each other. E.g CapsLock
+        // becomes Ctrl even if Ctrl is swapped with GUI.
+        match usage {
+            KeyboardCapsLock if self.contains(OsRemaps::CAPS_LOCK_CTRL) => KeyboardLeftControl,
+            KeyboardLeftControl if self.contains(OsRemaps::SWAP_LCTRL_LGUI) => KeyboardLeftGUI,
+            KeyboardLeftGUI if self.contains(OsRemaps::SWAP_LCTRL_LGUI) => KeyboardLeftControl,
+            KeyboardLeftAlt if self.contains(OsRemaps::SWAP_ALT_GUI) => KeyboardLeftGUI,
+            KeyboardLeftGUI if self.contains(OsRemaps::SWAP_ALT_GUI) => KeyboardLeftAlt,
+            KeyboardRightAlt if self.contains(OsRemaps::SWAP_ALT_GUI) => KeyboardRightGUI,
+            KeyboardRightGUI if self.contains(OsRemaps::SWAP_ALT_GUI) => KeyboardRightAlt,
+            other => other,
+        }
+    }
+}
+
+#[cfg(feature = "stm32f411")]
+mod persistence {
+    use dxkb_common::dev_warn;
+    use dxkb_peripheral::flash_config::FlashConfig;
+
+    use super::OsRemaps;
+
+    /// Key of the flash config area under which the enabled remaps are stored.
+    pub const OS_REMAPS_CONFIG_KEY: u8 = 0x02;
+
+    impl OsRemaps {
+        /// Loads the remaps stored in the flash config, or none if there
+        /// aren't any.
+        pub fn load(config: &FlashConfig) -> Self {
+            let mut buf = [0u8; 1];
+            match config.read(OS_REMAPS_CONFIG_KEY, &mut buf) {
+                Some(1) => OsRemaps::from_bits_truncate(buf[0]),
+                Some(_) => {
+                    dev_warn!("Discarding invalid persisted OS remaps");
+                    OsRemaps::empty()
+                }
+                None => OsRemaps::empty(),
+            }
+        }
+
+        pub fn store(&self, config: &mut FlashConfig) {
+            if let Err(e) = config.write(OS_REMAPS_CONFIG_KEY, &[self.bits()]) {
+                dev_warn!("Couldn't persist OS remaps: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "stm32f411")]
+pub use persistence::OS_REMAPS_CONFIG_KEY;
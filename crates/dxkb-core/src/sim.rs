@@ -0,0 +1,401 @@
+//! Host simulation of the hardware of a keyboard, for prototyping layouts and
+//! features on a desktop before flashing them. Only available with the `sim`
+//! feature, which builds this crate with std.
+//!
+//! The keyboard runs unmodified on top of:
+//!  - A [`SimMatrix`], whose keys are pressed and released through a
+//!    [`SimMatrixInput`]. The input can be shared with other threads, like the
+//!    one started by [`SimMatrixInput::spawn_stdin`], or a GUI test harness.
+//!  - A [`SimHid`], that prints every report that would have been sent to
+//!    the host.
+//!  - A [`SimUsbBus`], that is never connected to any host, for building the
+//!    [`UsbDevice`](usb_device::device::UsbDevice) the keyboard is polled with.
+//!  - A [`SimClock`], backed by the clock of the host.
+//!
+//! The lines read by [`SimMatrixInput::feed`] have the form `p <row> <col>`
+//! for pressing a key and `r <row> <col>` for releasing it. Empty lines and
+//! the ones starting with `#` are ignored.
+
+use std::io::BufRead;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use dxkb_common::time::{Clock, TimeDiff};
+use dxkb_common::{KeyState, dev_warn};
+use dxkb_peripheral::key_matrix::KeyMatrixLike;
+use hut::Consumer;
+use usb_device::bus::{PollResult, UsbBus};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::hid::{
+    BootLeds, HidKeyboard, HidKeyboardPressError, HidKeyboardReleaseError, KeyboardTickError, PressedKeysLimit,
+};
+
+/// The keys held in the simulated matrix, which can be pressed and released
+/// from any thread.
+#[derive(Clone)]
+pub struct SimMatrixInput {
+    rows: Arc<Mutex<Vec<u128>>>,
+}
+
+impl SimMatrixInput {
+    fn new(rows: u8) -> Self {
+        Self {
+            rows: Arc::new(Mutex::new(vec![0; rows as usize])),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<u128>> {
+        // A panic while holding the lock can't leave the rows half written.
+        self.rows.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sets the state of the key of the given position. Positions out of the
+    /// matrix are ignored.
+    pub fn set_key_state(&self, row: u8, col: u8, state: KeyState) {
+        let mut rows = self.lock();
+        let Some(bits) = rows.get_mut(row as usize) else {
+            dev_warn!("Key ({}, {}) is out of the simulated matrix", row, col);
+            return;
+        };
+        if col >= 128 {
+            dev_warn!("Key ({}, {}) is out of the simulated matrix", row, col);
+        } else if state == KeyState::Pressed {
+            *bits |= 1 << col;
+        } else {
+            *bits &= !(1 << col);
+        }
+    }
+
+    pub fn press(&self, row: u8, col: u8) {
+        self.set_key_state(row, col, KeyState::Pressed);
+    }
+
+    pub fn release(&self, row: u8, col: u8) {
+        self.set_key_state(row, col, KeyState::Released);
+    }
+
+    /// Applies every line read from `reader` until its end. Lines that can't
+    /// be parsed are logged and skipped.
+    pub fn feed<R: BufRead>(&self, reader: R) {
+        for line in reader.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_line(line) {
+                Some((state, row, col)) => self.set_key_state(row, col, state),
+                None => dev_warn!("Unrecognized matrix input: {}", line),
+            }
+        }
+    }
+
+    /// Starts a thread that feeds the matrix from the standard input.
+    pub fn spawn_stdin(&self) -> JoinHandle<()> {
+        let input = self.clone();
+        thread::spawn(move || input.feed(std::io::stdin().lock()))
+    }
+}
+
+fn parse_line(line: &str) -> Option<(KeyState, u8, u8)> {
+    let mut words = line.split_whitespace();
+    let state = match words.next()? {
+        "p" => KeyState::Pressed,
+        "r" => KeyState::Released,
+        _ => return None,
+    };
+    let row = words.next()?.parse().ok()?;
+    let col = words.next()?.parse().ok()?;
+    words.next().is_none().then_some((state, row, col))
+}
+
+/// A key matrix whose keys are held through a [`SimMatrixInput`]. Changes are
+/// picked up on the next scan, with no bouncing.
+pub struct SimMatrix<const ROWS: u8, const COLS: u8> {
+    input: SimMatrixInput,
+    scanned: Vec<u128>,
+    present: Vec<u128>,
+}
+
+impl<const ROWS: u8, const COLS: u8> SimMatrix<ROWS, COLS> {
+    pub fn new() -> Self {
+        Self {
+            input: SimMatrixInput::new(ROWS),
+            scanned: vec![0; ROWS as usize],
+            present: vec![u128::MAX; ROWS as usize],
+        }
+    }
+
+    /// Returns the input of the matrix, which can be moved to other threads.
+    pub fn input(&self) -> SimMatrixInput {
+        self.input.clone()
+    }
+}
+
+impl<const ROWS: u8, const COLS: u8> KeyMatrixLike<ROWS, COLS> for SimMatrix<ROWS, COLS> {
+    fn get_key_state(&self, row: u8, col: u8) -> KeyState {
+        KeyState::from_bool(self.scanned[row as usize] & (1 << col) != 0)
+    }
+
+    fn set_key_state(&mut self, row: u8, col: u8, state: KeyState) {
+        if state == KeyState::Pressed {
+            self.scanned[row as usize] |= 1 << col;
+        } else {
+            self.scanned[row as usize] &= !(1 << col);
+        }
+    }
+
+    fn row_bits(&self, row: u8) -> u128 {
+        self.scanned[row as usize]
+    }
+
+    fn set_key_present(&mut self, row: u8, col: u8, present: bool) {
+        if present {
+            self.present[row as usize] |= 1 << col;
+        } else {
+            self.present[row as usize] &= !(1 << col);
+            self.set_key_state(row, col, KeyState::Released);
+        }
+    }
+
+    fn scan_matrix_act<F: FnMut(u8, u8, KeyState) -> ()>(&mut self, mut changed_fn: F) -> bool {
+        let cols_mask = if COLS >= 128 { u128::MAX } else { (1 << COLS) - 1 };
+        let rows = self.input.lock();
+        let mut has_changed = false;
+
+        for row in 0..ROWS {
+            let bits = rows[row as usize] & self.present[row as usize] & cols_mask;
+            let mut changed = bits ^ self.scanned[row as usize];
+            self.scanned[row as usize] = bits;
+            has_changed |= changed != 0;
+
+            while changed != 0 {
+                let col = changed.trailing_zeros() as u8;
+                changed &= changed - 1;
+                changed_fn(row, col, KeyState::from_bool(bits & (1 << col) != 0));
+            }
+        }
+
+        has_changed
+    }
+}
+
+/// A HID keyboard that prints its reports instead of sending them, without
+/// any limit on the keys pressed at once other than the one set with
+/// [`HidKeyboard::set_max_pressed_keys`].
+pub struct SimHid {
+    keys: Vec<KeyboardUsage>,
+    limit: PressedKeysLimit,
+    consumer: Vec<Consumer>,
+    leds: BootLeds,
+    dirty: bool,
+}
+
+impl SimHid {
+    pub fn new() -> Self {
+        Self {
+            keys: Vec::new(),
+            limit: PressedKeysLimit::new(None),
+            consumer: Vec::new(),
+            leds: BootLeds::empty(),
+            dirty: false,
+        }
+    }
+
+    /// Returns the keys of the current report, in the order they were
+    /// pressed.
+    pub fn pressed_keys(&self) -> &[KeyboardUsage] {
+        &self.keys
+    }
+
+    /// Returns the consumer control keys of the current report, in the order
+    /// they were pressed.
+    pub fn pressed_consumer_keys(&self) -> &[Consumer] {
+        &self.consumer
+    }
+
+    /// Sets the LEDs turned on by the simulated host.
+    pub fn set_leds(&mut self, leds: BootLeds) {
+        self.leds = leds;
+    }
+}
+
+impl HidKeyboard for SimHid {
+    fn press_key(&mut self, key: KeyboardUsage) -> Result<(), HidKeyboardPressError> {
+        if self.keys.contains(&key) {
+            return Err(HidKeyboardPressError::AlreadyPressed);
+        }
+        self.limit.check_press(self.keys.len())?;
+        self.keys.push(key);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn release_key(&mut self, key: KeyboardUsage) -> Result<(), HidKeyboardReleaseError> {
+        let Some(index) = self.keys.iter().position(|k| *k == key) else {
+            self.limit.on_release_not_pressed();
+            return Err(HidKeyboardReleaseError::NotPressed);
+        };
+        self.keys.remove(index);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn press_consumer_control_key(&mut self, key: Consumer) -> Result<(), HidKeyboardPressError> {
+        if self.consumer.contains(&key) {
+            return Err(HidKeyboardPressError::AlreadyPressed);
+        }
+        self.consumer.push(key);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn release_consumer_control_key(&mut self, key: Consumer) -> Result<(), HidKeyboardReleaseError> {
+        let index = self
+            .consumer
+            .iter()
+            .position(|k| *k == key)
+            .ok_or(HidKeyboardReleaseError::NotPressed)?;
+        self.consumer.remove(index);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), KeyboardTickError> {
+        if self.dirty {
+            self.dirty = false;
+            print!("HID report: {:?}", self.keys);
+            if !self.consumer.is_empty() {
+                print!(", consumer control: {:?}", self.consumer);
+            }
+            if self.limit.is_rollover() {
+                print!(" (rollover)");
+            }
+            println!();
+        }
+        Ok(())
+    }
+
+    fn leds(&self) -> &BootLeds {
+        &self.leds
+    }
+
+    fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn unpress_all_keys(&mut self) {
+        self.keys.clear();
+        self.consumer.clear();
+        self.limit.reset();
+        self.dirty = true;
+    }
+
+    fn total_pressed_keys(&self) -> usize {
+        self.keys.len() + self.consumer.len()
+    }
+
+    fn is_rollover(&self) -> bool {
+        self.limit.is_rollover()
+    }
+
+    fn set_max_pressed_keys(&mut self, max: Option<usize>) {
+        self.limit.set_max(max);
+    }
+}
+
+/// A USB bus that is never connected to a host. Transfers are discarded and
+/// nothing is ever received.
+pub struct SimUsbBus {
+    next_ep: AtomicU8,
+}
+
+impl SimUsbBus {
+    pub fn new() -> Self {
+        Self { next_ep: AtomicU8::new(1) }
+    }
+}
+
+impl UsbBus for SimUsbBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        Ok(ep_addr.unwrap_or_else(|| {
+            EndpointAddress::from_parts(self.next_ep.fetch_add(1, Ordering::Relaxed) as usize, ep_dir)
+        }))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn read(&self, _ep_addr: EndpointAddress, _buf: &mut [u8]) -> usb_device::Result<usize> {
+        Err(UsbError::WouldBlock)
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        PollResult::None
+    }
+}
+
+/// A clock backed by the monotonic clock of the host.
+#[derive(Clone, Copy)]
+pub struct SimClock {
+    start: Instant,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+impl Clock for SimClock {
+    type TInstant = Instant;
+
+    fn current_instant(&self) -> Self::TInstant {
+        Instant::now()
+    }
+
+    fn diff(&self, newer: Self::TInstant, older: Self::TInstant) -> TimeDiff {
+        if newer >= older {
+            TimeDiff::Forward(newer - older)
+        } else {
+            TimeDiff::Backward(older - newer)
+        }
+    }
+
+    fn nanos(&self, instant: Self::TInstant) -> u64 {
+        instant.saturating_duration_since(self.start).as_nanos() as u64
+    }
+}
+
@@ -0,0 +1,351 @@
+//! Stenography support, for using the keyboard with Plover through one of the
+//! serial steno protocols (GeminiPR or TX Bolt).
+//!
+//! Steno keys ([`DefaultKey::Steno`](crate::keys::DefaultKey::Steno)) don't
+//! go through the HID keyboard. Instead, they're accumulated into a chord by
+//! the [`StenoOut`] of the keyboard while they're held, and the whole chord is
+//! encoded and queued once every key of it has been released, as steno
+//! machines do. Targets that want to use it allocate a [`StenoFeature`], a
+//! virtual serial port (CDC-ACM) that Plover can be pointed to, and move the
+//! queued chords into it with [`StenoFeature::send`] on every iteration of the
+//! main loop.
+
+use dxkb_common::{dev_debug, util};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::{ControlIn, ControlOut, UsbClass},
+    control::{Recipient, RequestType},
+    descriptor::DescriptorWriter,
+    device::UsbDevice,
+    endpoint::{EndpointIn, EndpointOut},
+    UsbError,
+};
+
+use crate::usb::UsbFeature;
+
+/// Max number of bytes of encoded chords that can be waiting for being sent to
+/// the host. Enough for 10 GeminiPR chords.
+pub const STENO_QUEUE_LEN: usize = 64;
+
+/// Max length of an encoded chord, among every protocol.
+pub const MAX_STENO_PACKET_LEN: usize = 6;
+
+/// The keys of a steno machine, in the order of the GeminiPR packet. Not every
+/// protocol supports every key: TX Bolt has no function, power nor reserved
+/// keys, and all of its number keys are the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StenoKey {
+    Fn, N1, N2, N3, N4, N5, N6,
+    S1, S2, TL, KL, PL, WL, HL,
+    RL, A, O, St1, St2, Res1, Res2,
+    Pwr, St3, St4, E, U, FR, RR,
+    PR, BR, LR, GR, TR, SR, DR,
+    N7, N8, N9, NA, NB, NC, ZR,
+}
+
+/// The TX Bolt byte of each [`StenoKey`], group included, or zero if the
+/// protocol doesn't support it.
+const TX_BOLT_BYTES: [u8; 42] = [
+    0x00, 0xd0, 0xd0, 0xd0, 0xd0, 0xd0, 0xd0,
+    0x01, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20,
+    0x41, 0x42, 0x44, 0x48, 0x48, 0x00, 0x00,
+    0x00, 0x48, 0x48, 0x50, 0x60, 0x81, 0x82,
+    0x84, 0x88, 0x90, 0xa0, 0xc1, 0xc2, 0xc4,
+    0xd0, 0xd0, 0xd0, 0xd0, 0xd0, 0xd0, 0xc8,
+];
+
+/// A set of steno keys pressed together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StenoChord(u64);
+
+impl StenoChord {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn with(self, key: StenoKey) -> Self {
+        Self(self.0 | 1 << key as u8)
+    }
+
+    pub const fn contains(&self, key: StenoKey) -> bool {
+        self.0 & (1 << key as u8) != 0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..TX_BOLT_BYTES.len() as u8).filter(|i| self.0 & (1 << i) != 0)
+    }
+}
+
+/// The serial protocol the chords are sent with. Plover must be configured to
+/// use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StenoProtocol {
+    /// 6 bytes per chord, with every key of the machine.
+    GeminiPr,
+    /// One byte per group of keys in the chord (up to 4), followed by a zero.
+    TxBolt,
+}
+
+impl StenoProtocol {
+    /// Encodes the chord into the buffer, returning the length of the packet.
+    pub fn encode(&self, chord: StenoChord, buf: &mut [u8; MAX_STENO_PACKET_LEN]) -> usize {
+        *buf = [0; MAX_STENO_PACKET_LEN];
+        match self {
+            StenoProtocol::GeminiPr => {
+                // The first bit of the packet marks its start.
+                buf[0] = 0x80;
+                for i in chord.keys() {
+                    buf[i as usize / 7] |= 0x40 >> (i % 7);
+                }
+                6
+            }
+            StenoProtocol::TxBolt => {
+                let mut groups = [0u8; 4];
+                for i in chord.keys() {
+                    let byte = TX_BOLT_BYTES[i as usize];
+                    if byte != 0 {
+                        groups[byte as usize >> 6] |= byte;
+                    }
+                }
+
+                let mut len = 0;
+                for byte in groups.into_iter().filter(|byte| *byte != 0) {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                // Tells Plover that the chord is complete.
+                buf[len] = 0;
+                len + 1
+            }
+        }
+    }
+}
+
+/// Accumulates the steno keys pressed into a chord, and queues the encoded
+/// chord once every key has been released, waiting for being sent to the host
+/// by a [`StenoFeature`].
+pub struct StenoOut {
+    protocol: StenoProtocol,
+    /// The keys currently held.
+    held: StenoChord,
+    /// Every key pressed since the last chord was sent.
+    chord: StenoChord,
+    pending: ConstGenericRingBuffer<u8, STENO_QUEUE_LEN>,
+}
+
+impl StenoOut {
+    pub fn new() -> Self {
+        Self {
+            protocol: StenoProtocol::GeminiPr,
+            held: StenoChord::empty(),
+            chord: StenoChord::empty(),
+            pending: ConstGenericRingBuffer::new(),
+        }
+    }
+
+    pub fn protocol(&self) -> StenoProtocol {
+        self.protocol
+    }
+
+    /// Sets the protocol of the chords. GeminiPR is used by default.
+    pub fn set_protocol(&mut self, protocol: StenoProtocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn press(&mut self, key: StenoKey) {
+        self.held = self.held.with(key);
+        self.chord = self.chord.with(key);
+    }
+
+    pub fn release(&mut self, key: StenoKey) {
+        self.held = StenoChord(self.held.0 & !(1 << key as u8));
+        if self.held.is_empty() && !self.chord.is_empty() {
+            let chord = core::mem::take(&mut self.chord);
+            self.push_chord(chord);
+        }
+    }
+
+    /// Returns the number of bytes waiting for being sent.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops the chord being accumulated and every byte waiting for being
+    /// sent.
+    pub fn clear(&mut self) {
+        self.held = StenoChord::empty();
+        self.chord = StenoChord::empty();
+        self.pending.clear();
+    }
+
+    fn push_chord(&mut self, chord: StenoChord) {
+        let mut packet = [0u8; MAX_STENO_PACKET_LEN];
+        let len = self.protocol.encode(chord, &mut packet);
+        if self.pending.capacity() - self.pending.len() < len {
+            // Nothing is draining the queue if the target has no steno
+            // interface, so this is not worth a warning.
+            dev_debug!("Steno queue is full. Dropping chord {:?}", chord);
+            return;
+        }
+        for byte in &packet[..len] {
+            self.pending.enqueue(*byte);
+        }
+    }
+}
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+const STENO_EP_PACKET_SIZE: u16 = 64;
+
+/// Minimal CDC-ACM class. The line coding is only stored for giving it back
+/// to the host, since there's no actual serial line behind.
+struct StenoSerialClass<'a, B: UsbBus> {
+    comm_if: InterfaceNumber,
+    data_if: InterfaceNumber,
+    ep_notify: EndpointIn<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    /// 9600 bauds, 1 stop bit, no parity, 8 data bits.
+    line_coding: [u8; 7],
+}
+
+impl<B: UsbBus> StenoSerialClass<'_, B> {
+    fn is_own_request(&self, request_type: RequestType, recipient: Recipient, index: u16) -> bool {
+        request_type == RequestType::Class
+            && recipient == Recipient::Interface
+            && index == u8::from(self.comm_if) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for StenoSerialClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.iad(self.comm_if, 2, USB_CLASS_CDC, CDC_SUBCLASS_ACM, 0x00, None)?;
+
+        writer.interface(self.comm_if, USB_CLASS_CDC, CDC_SUBCLASS_ACM, 0x00)?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, u8::from(self.data_if)])?;
+        // Supports the line coding and line state requests.
+        writer.write(CS_INTERFACE, &[CDC_TYPE_ACM, 0x02])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_UNION, u8::from(self.comm_if), u8::from(self.data_if)])?;
+        writer.endpoint(&self.ep_notify)?;
+
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)?;
+
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_own_request(req.request_type, req.recipient, req.index) {
+            return;
+        }
+
+        let _ = match req.request {
+            REQ_GET_LINE_CODING => xfer.accept_with(&self.line_coding),
+            _ => xfer.reject(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_own_request(req.request_type, req.recipient, req.index) {
+            return;
+        }
+
+        let _ = match req.request {
+            REQ_SET_LINE_CODING if xfer.data().len() >= self.line_coding.len() => {
+                self.line_coding.copy_from_slice(&xfer.data()[..7]);
+                xfer.accept()
+            }
+            REQ_SET_CONTROL_LINE_STATE => xfer.accept(),
+            _ => xfer.reject(),
+        };
+    }
+}
+
+/// USB feature that exposes a virtual serial port, through which the chords
+/// queued in a [`StenoOut`] are sent. Since it uses an interface association,
+/// the USB device must be built with `composite_with_iads`.
+pub struct StenoFeature<'a, B: UsbBus> {
+    class: StenoSerialClass<'a, B>,
+}
+
+impl<'a, B: UsbBus> StenoFeature<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            class: StenoSerialClass {
+                comm_if: alloc.interface(),
+                data_if: alloc.interface(),
+                ep_notify: alloc.interrupt(8, 255),
+                ep_in: alloc.bulk(STENO_EP_PACKET_SIZE),
+                ep_out: alloc.bulk(STENO_EP_PACKET_SIZE),
+                line_coding: [0x80, 0x25, 0x00, 0x00, 0x00, 0x00, 0x08],
+            },
+        }
+    }
+
+    /// Sends as many of the bytes queued in the given [`StenoOut`] as fit in
+    /// a single transfer. Bytes are only removed from the queue once the
+    /// transfer has been accepted, so they're retried on the next call while
+    /// the endpoint is busy.
+    pub fn send(&mut self, out: &mut StenoOut) {
+        if out.pending.is_empty() {
+            return;
+        }
+
+        let mut buf = [0u8; STENO_EP_PACKET_SIZE as usize];
+        let mut len = 0;
+        for (slot, byte) in buf.iter_mut().zip(out.pending.iter()) {
+            *slot = *byte;
+            len += 1;
+        }
+
+        match self.class.ep_in.write(&buf[..len]) {
+            Ok(written) => {
+                for _ in 0..written {
+                    out.pending.dequeue();
+                }
+            }
+            Err(UsbError::WouldBlock) => {}
+            Err(e) => {
+                dev_debug!("Couldn't send steno chords: {:?}", e);
+            }
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbFeature<B> for StenoFeature<'a, B> {
+    const EP: usize = 1;
+    type TPoll = ();
+
+    fn endpoints_mut(&mut self) -> [&mut dyn UsbClass<B>; Self::EP] {
+        util::slice::array_unify_length(
+          [&mut self.class]
+        )
+    }
+
+    fn usb_poll(&mut self, _device: &mut UsbDevice<B>) -> Self::TPoll {
+        // Whatever Plover sends is discarded.
+        let mut buf = [0u8; STENO_EP_PACKET_SIZE as usize];
+        let _ = self.class.ep_out.read(&mut buf);
+    }
+}
@@ -0,0 +1,82 @@
+//! Names of the keyboard usages, as accepted by the string keys of the keymaps
+//! (the `KeyboardUsage` variants without the `Keyboard` prefix, e.g `"Enter"`
+//! or `"3Hash"`), so that anything exchanging keys by name with the host, like
+//! a dynamic keymap tool, agrees with the names used in the layouts.
+//!
+//! The table is const data and the lookups are const fns, so they can also be
+//! used for building other tables at compile time. The names are included from
+//! `usage_names.in`, which dxkb-proc-macros includes as well for validating
+//! the keymaps.
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+macro_rules! keyboard_usage_names {
+    ($($name:literal),* $(,)?) => {
+        /// Every keyboard usage that can be referenced by name, along with its name.
+        pub const KEYBOARD_USAGES: &[(&str, KeyboardUsage)] =
+            &[$(($name, KeyboardUsage::${concat("Keyboard", $name)})),*];
+    };
+}
+
+include!("usage_names.in");
+
+/// The entries of [`KEYBOARD_USAGES`] indexed by the code of their usage.
+const USAGES_BY_CODE: [Option<(&str, KeyboardUsage)>; 256] = usages_by_code();
+
+const fn usages_by_code() -> [Option<(&'static str, KeyboardUsage)>; 256] {
+    let mut table = [None; 256];
+    let mut i = 0;
+    while i < KEYBOARD_USAGES.len() {
+        let usage = KEYBOARD_USAGES[i].1;
+        table[usage as usize] = Some(KEYBOARD_USAGES[i]);
+        i += 1;
+    }
+    table
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Returns the usage with the given name, which is case sensitive.
+pub const fn usage_from_name(name: &str) -> Option<KeyboardUsage> {
+    let mut i = 0;
+    while i < KEYBOARD_USAGES.len() {
+        let (candidate, usage) = KEYBOARD_USAGES[i];
+        if str_eq(candidate, name) {
+            return Some(usage);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Returns the name of the given usage, or None if it can't be referenced by
+/// name, like the keypad ones.
+pub const fn usage_name(usage: KeyboardUsage) -> Option<&'static str> {
+    match USAGES_BY_CODE[usage as usize] {
+        Some((name, _)) => Some(name),
+        None => None,
+    }
+}
+
+/// Returns the usage of the given code, e.g as received from the host, if it
+/// is one of the usages that can be referenced by name.
+pub const fn usage_from_code(code: u8) -> Option<KeyboardUsage> {
+    match USAGES_BY_CODE[code as usize] {
+        Some((_, usage)) => Some(usage),
+        None => None,
+    }
+}
@@ -1,6 +1,10 @@
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, time::Duration};
 
-use usb_device::{bus::UsbBus, class::UsbClass, device::UsbDevice};
+use cortex_m::{interrupt::InterruptNumber, peripheral::NVIC};
+use dxkb_common::{dev_info, dev_warn, time::{Clock, Stopwatch}};
+use dxkb_peripheral::usb::UsbSoftDisconnect;
+use heapless::spsc::Producer;
+use usb_device::{bus::UsbBus, class::UsbClass, device::{UsbDevice, UsbDeviceState}};
 
 /**
  * Represents a set of USB endpoints that can be polled together.
@@ -78,3 +82,178 @@ endpoint_set_impl!(1);
 endpoint_set_impl!(2);
 endpoint_set_impl!(3);
 endpoint_set_impl!(4);
+
+/**
+ * Polls a [`UsbFeatureSet`] from the USB interrupt handler, instead of from
+ * the main loop, so that the USB device is serviced as soon as the host needs
+ * it, regardless of what the main loop is busy with. The results of the polls
+ * are handed to the main loop through a SPSC queue, whose consumer end is kept
+ * by the main loop.
+ *
+ * The USB device and its features must not be touched from the main loop
+ * while the interrupt is enabled. Use an [`UsbInterruptMask`] for that.
+ */
+pub struct UsbInterruptPoll<'q, T, const N: usize> {
+    events: Producer<'q, T, N>,
+}
+
+impl<'q, T, const N: usize> UsbInterruptPoll<'q, T, N> {
+    pub fn new(events: Producer<'q, T, N>) -> Self {
+        Self { events }
+    }
+
+    /// Polls the features. Must be called from the USB interrupt handler.
+    pub fn on_interrupt<B: UsbBus, F: UsbFeatureSet<B, TPoll = T>>(&mut self, mut features: F, device: &mut UsbDevice<B>) {
+        if let Some(poll) = features.poll_all(device) {
+            if self.events.enqueue(poll).is_err() {
+                dev_warn!("USB event queue is full. Dropping USB event");
+            }
+        }
+    }
+}
+
+/// Keeps the given interrupt masked until dropped, so that the USB device and
+/// its features can be used from the main loop while they are polled by an
+/// [`UsbInterruptPoll`]. If the interrupt fires meanwhile, it is serviced right
+/// after being dropped.
+pub struct UsbInterruptMask<I: InterruptNumber + Copy> {
+    interrupt: I,
+    was_enabled: bool,
+}
+
+impl<I: InterruptNumber + Copy> UsbInterruptMask<I> {
+    pub fn new(interrupt: I) -> Self {
+        let was_enabled = NVIC::is_enabled(interrupt);
+        NVIC::mask(interrupt);
+        Self { interrupt, was_enabled }
+    }
+}
+
+impl<I: InterruptNumber + Copy> Drop for UsbInterruptMask<I> {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe {
+                // SAFETY: It was enabled before, so whatever it shares with
+                // the main loop is already expected to be used from there.
+                NVIC::unmask(self.interrupt);
+            }
+        }
+    }
+}
+
+/// Time after attaching to the bus without being enumerated by a host for
+/// considering that there's no host, e.g because the keyboard is the slave
+/// side, or it is connected to a charger.
+pub const USB_ENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between two polls of the USB device while there's no host. The
+/// peripheral keeps the setup packets until they're read, so the host barely
+/// notices the delay if it shows up.
+pub const USB_BACKOFF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time between two attempts of attaching to the bus again while there's no
+/// host, in case the host missed the device, e.g because it was booting.
+pub const USB_REATTACH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time the device is kept detached from the bus on each attempt, which must
+/// be long enough for the host to notice.
+const USB_REATTACH_DETACH_TIME: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy)]
+enum UsbEnumerationState<I> {
+    /// Attached to the bus, waiting for a host to enumerate the device.
+    Waiting { since: I },
+    /// A host has enumerated the device. It may be suspended, but it is
+    /// there anyway.
+    Enumerated,
+    /// No host enumerated the device in time, so it is polled less often.
+    /// The interval between attempts of attaching again is longer than the
+    /// range of some clocks, so it is measured with a stopwatch.
+    Backoff { since: Stopwatch<I> },
+    /// Detached from the bus for attaching again.
+    Detached { since: I },
+}
+
+/**
+ * Decides how often the USB device is polled, depending on whether there's a
+ * host. Without one, polling the device is useless, so it is only polled from
+ * time to time, leaving the main loop for the split link and the lighting,
+ * and the device is detached and attached again once in a while, in case the
+ * host missed it. The device is polled on every iteration again as soon as a
+ * host enumerates it.
+ */
+pub struct UsbEnumerationBackoff<C: Clock> {
+    state: UsbEnumerationState<C::TInstant>,
+    last_poll: Option<C::TInstant>,
+}
+
+impl<C: Clock> UsbEnumerationBackoff<C> {
+    pub fn new(clock: &C) -> Self {
+        Self {
+            state: UsbEnumerationState::Waiting { since: clock.current_instant() },
+            last_poll: None,
+        }
+    }
+
+    /// Returns whether a host has enumerated the device, or there hasn't been
+    /// time enough for telling there's none yet.
+    pub fn is_host_expected(&self) -> bool {
+        matches!(self.state, UsbEnumerationState::Waiting { .. } | UsbEnumerationState::Enumerated)
+    }
+
+    /// Must be called on every iteration of the main loop, with the current
+    /// state of the device. Returns whether the device must be polled on this
+    /// iteration.
+    pub fn poll<D: UsbSoftDisconnect>(&mut self, clock: &C, state: UsbDeviceState, device: &mut D) -> bool {
+        let now = clock.current_instant();
+        let enumerated = matches!(state, UsbDeviceState::Addressed | UsbDeviceState::Configured);
+
+        self.state = match self.state {
+            UsbEnumerationState::Enumerated if state == UsbDeviceState::Default => {
+                // Reset by the host, or unplugged.
+                UsbEnumerationState::Waiting { since: now }
+            }
+            UsbEnumerationState::Enumerated => UsbEnumerationState::Enumerated,
+            UsbEnumerationState::Detached { since } => {
+                if clock.elapsed_since(since) < USB_REATTACH_DETACH_TIME {
+                    return false;
+                }
+                device.set_soft_disconnect(false);
+                UsbEnumerationState::Waiting { since: now }
+            }
+            _ if enumerated => {
+                dev_info!("USB host found");
+                UsbEnumerationState::Enumerated
+            }
+            UsbEnumerationState::Waiting { since } if clock.elapsed_since(since) >= USB_ENUMERATION_TIMEOUT => {
+                dev_info!("No USB host found. Polling the USB device less often");
+                UsbEnumerationState::Backoff { since: Stopwatch::start(clock) }
+            }
+            UsbEnumerationState::Backoff { mut since } => {
+                if since.elapsed(clock) >= USB_REATTACH_INTERVAL {
+                    dev_info!("Attaching to the USB bus again");
+                    device.set_soft_disconnect(true);
+                    UsbEnumerationState::Detached { since: now }
+                } else {
+                    UsbEnumerationState::Backoff { since }
+                }
+            }
+            other => other,
+        };
+
+        match self.state {
+            UsbEnumerationState::Detached { .. } => return false,
+            UsbEnumerationState::Backoff { .. } => {
+                if let Some(last_poll) = self.last_poll {
+                    if clock.elapsed_since(last_poll) < USB_BACKOFF_POLL_INTERVAL {
+                        return false;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self.last_poll = Some(now);
+        true
+    }
+}
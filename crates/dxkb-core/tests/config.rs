@@ -0,0 +1,66 @@
+use dxkb_core::config::{
+    import_config, ConfigBlobError, ConfigStore, ConfigUpgrade, CONFIG_SCHEMA_VERSION,
+};
+use dxkb_peripheral::flash_config::{FlashConfigError, FLASH_CONFIG_MAX_VALUE_LEN};
+
+#[derive(Default)]
+struct RecordingStore {
+    writes: Vec<(u8, Vec<u8>)>,
+}
+
+impl ConfigStore for RecordingStore {
+    fn write(&mut self, key: u8, value: &[u8]) -> Result<(), FlashConfigError> {
+        self.writes.push((key, value.to_vec()));
+        Ok(())
+    }
+}
+
+/// Doubles every value, which doesn't fit for the key 0x02.
+struct DoublingUpgrade;
+
+impl ConfigUpgrade for DoublingUpgrade {
+    fn upgrade(&self, _from_version: u8, key: u8, value: &[u8], out: &mut [u8]) -> Option<usize> {
+        if key == 0x02 {
+            return Some(FLASH_CONFIG_MAX_VALUE_LEN + 1);
+        }
+
+        out[..value.len()].copy_from_slice(value);
+        out[value.len()..value.len() * 2].copy_from_slice(value);
+        Some(value.len() * 2)
+    }
+}
+
+fn blob(version: u8, entries: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut blob = b"DXKC".to_vec();
+    blob.extend([version, entries.len() as u8, 0, 0]);
+    for (key, value) in entries {
+        blob.push(*key);
+        blob.extend((value.len() as u16).to_le_bytes());
+        blob.extend(*value);
+    }
+
+    let len = (blob.len() + 2) as u16;
+    blob[6..8].copy_from_slice(&len.to_le_bytes());
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_USB).checksum(&blob);
+    blob.extend(crc.to_le_bytes());
+    blob
+}
+
+#[test]
+fn test_import_upgrades_values() {
+    let mut store = RecordingStore::default();
+    let blob = blob(CONFIG_SCHEMA_VERSION - 1, &[(0x01, &[1, 2])]);
+    assert!(matches!(import_config(&mut store, &blob, &DoublingUpgrade), Ok(1)));
+    assert_eq!(store.writes, [(0x01, vec![1, 2, 1, 2])]);
+}
+
+#[test]
+fn test_failed_upgrade_imports_nothing() {
+    let mut store = RecordingStore::default();
+    let blob = blob(CONFIG_SCHEMA_VERSION - 1, &[(0x01, &[1, 2]), (0x02, &[3])]);
+    assert!(matches!(
+        import_config(&mut store, &blob, &DoublingUpgrade),
+        Err(ConfigBlobError::UpgradeFailed(v)) if v == CONFIG_SCHEMA_VERSION - 1
+    ));
+    assert!(store.writes.is_empty());
+}
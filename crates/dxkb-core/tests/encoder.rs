@@ -0,0 +1,184 @@
+#![cfg(feature = "sim")]
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+#![feature(macro_metavar_expr_concat)]
+
+use core::time::Duration;
+
+use dxkb_common::testing::MockClock;
+use dxkb_core::encoder::{EncoderAcceleration, EncoderBinding, EncoderDirection, EncoderMapping};
+use dxkb_core::keyboard::{AlwaysMaster, SplitKeyboardLike, UnibodyKeyboard, UnibodyKeyboardLayout};
+use dxkb_core::keys::DefaultKey;
+use dxkb_core::sim::{SimClock, SimHid, SimMatrix};
+use dxkb_split_link::NullSplitBus;
+use usbd_hid::descriptor::KeyboardUsage;
+
+type Layout = UnibodyKeyboardLayout<DefaultKey, 1, 1, 1>;
+type Keyboard = UnibodyKeyboard<1, 1, 1, SimClock, SimHid, DefaultKey, SimMatrix<1, 1>, ()>;
+type Encoder = EncoderMapping<DefaultKey, MockClock, 1>;
+
+const LAYOUT: Layout = Layout::new(dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                ['A'],
+            ]
+        },
+    ]
+));
+
+const CW: KeyboardUsage = KeyboardUsage::KeyboardUpArrow;
+const CCW: KeyboardUsage = KeyboardUsage::KeyboardDownArrow;
+
+fn keyboard() -> Keyboard {
+    Keyboard::builder()
+        .clock(SimClock::new())
+        .hid(SimHid::new())
+        .layout(LAYOUT)
+        .matrix(SimMatrix::new())
+        .split_bus(NullSplitBus)
+        .master_sense(AlwaysMaster)
+        .build()
+}
+
+fn mapping(acceleration: EncoderAcceleration) -> Encoder {
+    Encoder::new([Some(EncoderBinding {
+        clockwise: DefaultKey::Standard(CW),
+        counter_clockwise: DefaultKey::Standard(CCW),
+        acceleration,
+    })])
+}
+
+/// Ticks the encoder until it has no taps left, returning the key of each tap.
+fn drain(encoder: &mut Encoder, kb: &mut Keyboard) -> Vec<KeyboardUsage> {
+    let mut taps = vec![];
+    loop {
+        encoder.tick(kb, &mut ());
+        match kb.hid_mut().pressed_keys() {
+            [] => return taps,
+            [key] => taps.push(*key),
+            keys => panic!("More than one key pressed: {:?}", keys),
+        }
+
+        encoder.tick(kb, &mut ());
+        assert!(kb.hid_mut().pressed_keys().is_empty());
+    }
+}
+
+/// Rotates the encoder clockwise once per interval, draining the taps of each
+/// detent, and returns the number of taps of each one.
+fn steps_per_detent(
+    encoder: &mut Encoder,
+    kb: &mut Keyboard,
+    clock: &MockClock,
+    detents: usize,
+    interval: Duration,
+) -> Vec<usize> {
+    (0..detents)
+        .map(|_| {
+            clock.advance(interval);
+            encoder.rotate(clock, 0, EncoderDirection::Clockwise);
+            drain(encoder, kb).len()
+        })
+        .collect()
+}
+
+#[test]
+fn test_slow_detents_emit_one_step() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = mapping(EncoderAcceleration::DEFAULT);
+    assert_eq!(steps_per_detent(&mut encoder, &mut kb, &clock, 5, Duration::from_millis(100)), [1; 5]);
+
+    // Exactly the fast interval isn't fast yet.
+    let fast_interval = EncoderAcceleration::DEFAULT.fast_interval;
+    assert_eq!(steps_per_detent(&mut encoder, &mut kb, &clock, 5, fast_interval), [1; 5]);
+}
+
+#[test]
+fn test_fast_detents_accelerate() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = mapping(EncoderAcceleration::DEFAULT);
+    assert_eq!(
+        steps_per_detent(&mut encoder, &mut kb, &clock, 12, Duration::from_millis(10)),
+        [1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4]
+    );
+
+    // A slow detent starts over.
+    assert_eq!(steps_per_detent(&mut encoder, &mut kb, &clock, 1, Duration::from_millis(100)), [1]);
+}
+
+#[test]
+fn test_no_acceleration() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = mapping(EncoderAcceleration::NONE);
+    assert_eq!(steps_per_detent(&mut encoder, &mut kb, &clock, 8, Duration::ZERO), [1; 8]);
+}
+
+#[test]
+fn test_pending_taps_accumulate_up_to_max() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = mapping(EncoderAcceleration::DEFAULT);
+
+    for _ in 0..6 {
+        clock.advance(Duration::from_millis(10));
+        encoder.rotate(&clock, 0, EncoderDirection::Clockwise);
+    }
+    assert_eq!(drain(&mut encoder, &mut kb), [CW; 9]);
+
+    for _ in 0..12 {
+        clock.advance(Duration::from_millis(10));
+        encoder.rotate(&clock, 0, EncoderDirection::Clockwise);
+    }
+    assert_eq!(drain(&mut encoder, &mut kb).len(), Encoder::MAX_PENDING_TAPS as usize);
+}
+
+#[test]
+fn test_direction_change_drops_pending_taps() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = mapping(EncoderAcceleration::DEFAULT);
+
+    for _ in 0..3 {
+        clock.advance(Duration::from_millis(10));
+        encoder.rotate(&clock, 0, EncoderDirection::Clockwise);
+    }
+    clock.advance(Duration::from_millis(10));
+    encoder.rotate(&clock, 0, EncoderDirection::CounterClockwise);
+    assert_eq!(drain(&mut encoder, &mut kb), [CCW]);
+}
+
+#[test]
+fn test_tap_in_progress_is_released() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = mapping(EncoderAcceleration::NONE);
+
+    encoder.rotate(&clock, 0, EncoderDirection::Clockwise);
+    encoder.tick(&mut kb, &mut ());
+    assert_eq!(kb.hid_mut().pressed_keys(), &[CW]);
+
+    // The pressed key is released before the taps of the new direction.
+    encoder.rotate(&clock, 0, EncoderDirection::CounterClockwise);
+    encoder.tick(&mut kb, &mut ());
+    assert!(kb.hid_mut().pressed_keys().is_empty());
+    assert_eq!(drain(&mut encoder, &mut kb), [CCW]);
+}
+
+#[test]
+fn test_unbound_layers_are_ignored() {
+    let clock = MockClock::new();
+    let mut kb = keyboard();
+    let mut encoder = Encoder::new([None]);
+    encoder.rotate(&clock, 0, EncoderDirection::Clockwise);
+    assert!(drain(&mut encoder, &mut kb).is_empty());
+
+    // Out of range.
+    let mut encoder = mapping(EncoderAcceleration::NONE);
+    encoder.rotate(&clock, 1, EncoderDirection::Clockwise);
+    assert!(drain(&mut encoder, &mut kb).is_empty());
+}
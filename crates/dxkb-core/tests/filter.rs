@@ -0,0 +1,115 @@
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+use dxkb_common::KeyState;
+use dxkb_common::testing::MockClock;
+use dxkb_core::filter::{
+    Chain, Combo, ComboFilter, GhostFilter, KeyEvent, KeyEventBuf, KeyFilter, NoFilter, Timed, assert_filter_ram_budget,
+};
+
+fn press(row: u8, col: u8) -> KeyEvent {
+    KeyEvent::new(row, col, KeyState::Pressed)
+}
+
+fn release(row: u8, col: u8) -> KeyEvent {
+    KeyEvent::new(row, col, KeyState::Released)
+}
+
+fn run<F: KeyFilter>(filter: &mut F, now_ms: u32, event: KeyEvent) -> Vec<KeyEvent> {
+    let mut out = KeyEventBuf::new();
+    filter.filter(now_ms, event, &mut out);
+    out.to_vec()
+}
+
+fn tick<F: KeyFilter>(filter: &mut F, now_ms: u32) -> Vec<KeyEvent> {
+    let mut out = KeyEventBuf::new();
+    filter.tick(now_ms, &mut out);
+    out.to_vec()
+}
+
+const COMBOS: [Combo; 1] = [Combo::new([(0, 0), (0, 1)], (1, 3))];
+
+#[test]
+fn test_combo_triggered() {
+    let mut filter = ComboFilter::new(COMBOS, 50);
+    assert_eq!(run(&mut filter, 0, press(0, 1)), vec![]);
+    assert_eq!(run(&mut filter, 20, press(0, 0)), vec![press(1, 3)]);
+    assert_eq!(run(&mut filter, 100, release(0, 0)), vec![release(1, 3)]);
+    assert_eq!(run(&mut filter, 110, release(0, 1)), vec![]);
+}
+
+#[test]
+fn test_combo_key_pressed_alone() {
+    let mut filter = ComboFilter::new(COMBOS, 50);
+    assert_eq!(run(&mut filter, 0, press(0, 0)), vec![]);
+    assert_eq!(tick(&mut filter, 49), vec![]);
+    assert_eq!(tick(&mut filter, 50), vec![press(0, 0)]);
+    assert_eq!(run(&mut filter, 60, press(0, 1)), vec![]);
+    assert_eq!(run(&mut filter, 70, release(0, 1)), vec![press(0, 1), release(0, 1)]);
+    assert_eq!(run(&mut filter, 80, release(0, 0)), vec![release(0, 0)]);
+}
+
+#[test]
+fn test_combo_interrupted_by_other_key() {
+    let mut filter = ComboFilter::new(COMBOS, 50);
+    assert_eq!(run(&mut filter, 0, press(0, 0)), vec![]);
+    assert_eq!(run(&mut filter, 10, press(1, 0)), vec![press(0, 0), press(1, 0)]);
+}
+
+#[test]
+fn test_combo_low_latency() {
+    let mut filter = NoFilter.then(ComboFilter::new(COMBOS, 50));
+    assert_eq!(run(&mut filter, 0, press(0, 1)), vec![]);
+    assert_eq!(run(&mut filter, 10, press(0, 0)), vec![press(1, 3)]);
+
+    // The combo already triggered is still released as usual, but no press
+    // is held back anymore.
+    filter.set_low_latency(true);
+    assert_eq!(run(&mut filter, 20, release(0, 0)), vec![release(1, 3)]);
+    assert_eq!(run(&mut filter, 30, press(0, 0)), vec![press(0, 0)]);
+    assert_eq!(run(&mut filter, 40, release(0, 1)), vec![]);
+    assert_eq!(run(&mut filter, 50, press(0, 1)), vec![press(0, 1)]);
+
+    filter.set_low_latency(false);
+    assert_eq!(run(&mut filter, 60, release(0, 1)), vec![release(0, 1)]);
+    assert_eq!(run(&mut filter, 70, press(0, 1)), vec![]);
+}
+
+#[test]
+fn test_ghost_press_dropped() {
+    let mut filter = GhostFilter::<2, 2>::new();
+    assert_eq!(run(&mut filter, 0, press(0, 0)), vec![press(0, 0)]);
+    assert_eq!(run(&mut filter, 0, press(0, 1)), vec![press(0, 1)]);
+    assert_eq!(run(&mut filter, 0, press(1, 0)), vec![press(1, 0)]);
+    assert_eq!(run(&mut filter, 0, press(1, 1)), vec![]);
+    assert_eq!(run(&mut filter, 0, release(1, 1)), vec![]);
+    assert_eq!(run(&mut filter, 0, release(0, 0)), vec![release(0, 0)]);
+    assert_eq!(run(&mut filter, 0, press(1, 1)), vec![press(1, 1)]);
+}
+
+#[test]
+fn test_chain_feeds_next_stage() {
+    let mut filter = NoFilter.then(ComboFilter::new(COMBOS, 50));
+    assert_eq!(run(&mut filter, 0, press(0, 0)), vec![]);
+    assert_eq!(tick(&mut filter, 60), vec![press(0, 0)]);
+}
+
+#[test]
+fn test_stage_budgets() {
+    type Filter = Chain<GhostFilter<2, 2>, Timed<ComboFilter<1>, MockClock>>;
+    const { assert_filter_ram_budget::<Filter>(1024) };
+    assert_eq!(Filter::RAM_USAGE, size_of::<GhostFilter<2, 2>>() + size_of::<ComboFilter<1>>());
+
+    let mut filter: Filter = GhostFilter::new().then(Timed::new(ComboFilter::new(COMBOS, 50), MockClock::new()));
+    run(&mut filter, 0, press(1, 1));
+    tick(&mut filter, 10);
+
+    let mut stages = vec![];
+    filter.for_each_stage(&mut |stage| stages.push(*stage));
+    assert_eq!(stages.len(), 2);
+    assert_eq!(stages[0].name, "ghost");
+    assert_eq!(stages[0].timing, None);
+    assert_eq!(stages[1].name, "combo");
+    assert_eq!(stages[1].ram, size_of::<ComboFilter<1>>());
+    assert_eq!(stages[1].timing.map(|timing| timing.runs), Some(2));
+}
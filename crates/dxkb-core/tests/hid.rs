@@ -0,0 +1,35 @@
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+use dxkb_core::hid::{HidKeyboardPressError, PressedKeysLimit};
+
+#[test]
+fn test_pressed_keys_limit_boundary() {
+    let mut limit = PressedKeysLimit::new(Some(2));
+    assert!(limit.check_press(0).is_ok());
+    assert!(limit.check_press(1).is_ok());
+    assert!(!limit.is_rollover());
+
+    assert!(matches!(limit.check_press(2), Err(HidKeyboardPressError::Rollover)));
+    assert!(matches!(limit.check_press(2), Err(HidKeyboardPressError::Rollover)));
+    assert!(limit.is_rollover());
+
+    // Both rejected keys have to be released for leaving the rollover.
+    limit.on_release_not_pressed();
+    assert!(limit.is_rollover());
+    limit.on_release_not_pressed();
+    assert!(!limit.is_rollover());
+    limit.on_release_not_pressed();
+    assert!(!limit.is_rollover());
+}
+
+#[test]
+fn test_pressed_keys_limit_disabled() {
+    let mut limit = PressedKeysLimit::new(None);
+    assert!(limit.check_press(usize::MAX).is_ok());
+
+    limit.set_max(Some(0));
+    assert!(limit.check_press(0).is_err());
+    limit.reset();
+    assert!(!limit.is_rollover());
+}
@@ -0,0 +1,145 @@
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+#![feature(macro_metavar_expr_concat)]
+
+use dxkb_common::util::BitMatrix;
+use dxkb_core::{
+    keyboard::{LayoutUsageEntry, LayoutUsageMismatch, SplitKeyboardLayout, SplitLayoutConfig},
+    keys::{BuiltinFunctionKey, DefaultKey},
+};
+use usbd_hid::descriptor::KeyboardUsage;
+
+// The same definition is used for building both the layout and the usage
+// table.
+macro_rules! test_layers {
+    ($($emit:tt)*) => {
+        dxkb_proc_macros::layers!(
+            $($emit)*
+            layers: [
+                {
+                    name: "base",
+                    rows: [
+                        [  Esc,    1,  /* | */    2,  '`'],
+                        [ LSft,    A,  /* | */  Spc, f:LTRelSet(+1)],
+                    ]
+                },
+                {
+                    name: "upper",
+                    parent: "base",
+                    rows: [
+                        [   F1,    *,  /* | */ t:"Hi!", c:VUp],
+                        [    *,    _,  /* | */    *,    *],
+                    ]
+                },
+                {
+                    name: "top",
+                    parent: "upper",
+                    rows: [
+                        [    *,    *,  /* | */   F2, m:(10, 36)],
+                        [    *,    *,  /* | */    *, fn:POP_LAYER],
+                    ]
+                },
+            ]
+        )
+    };
+}
+
+const POP_LAYER: DefaultKey = DefaultKey::Function(BuiltinFunctionKey::PopLayer);
+
+struct TestLayoutConfig;
+impl SplitLayoutConfig for TestLayoutConfig {
+    const SPLIT_RIGHT_COL_OFFSET: u8 = 2;
+}
+
+type TestLayout = SplitKeyboardLayout<TestLayoutConfig, DefaultKey, 3, 2, 4>;
+
+const LAYOUT: TestLayout = TestLayout::new(test_layers!());
+const USAGE_TABLE: &[LayoutUsageEntry<DefaultKey>] = test_layers!(emit: usage_table,);
+
+// The same layout, without the keys at the outer bottom corners.
+const MASKED_LAYOUT: TestLayout = TestLayout::new(test_layers!())
+    .with_key_presence(BitMatrix::from_rows([0b1111, 0b0110]));
+
+fn entry(layer: u8, row: u8, col: u8) -> &'static LayoutUsageEntry<DefaultKey> {
+    USAGE_TABLE
+        .iter()
+        .find(|e| e.layer == layer && e.row == row && e.col == col)
+        .unwrap()
+}
+
+#[test]
+fn layout_matches_usage_table() {
+    match LAYOUT.check_usage_table(USAGE_TABLE) {
+        Ok(()) => {}
+        Err(LayoutUsageMismatch::WrongSize { expected, found }) => {
+            panic!("Expected {} entries in the usage table, found {}", expected, found)
+        }
+        Err(LayoutUsageMismatch::Key { entry, .. }) => panic!(
+            "Layout key at ({}, {}, {}) doesn't match '{}'",
+            entry.layer, entry.row, entry.col, entry.alias
+        ),
+    }
+}
+
+#[test]
+fn passthrough_resolves_through_every_parent() {
+    assert!(entry(2, 0, 0).key == DefaultKey::Standard(KeyboardUsage::KeyboardF1));
+    assert!(entry(2, 0, 1).key == DefaultKey::Standard(KeyboardUsage::Keyboard1Exclamation));
+    assert!(entry(2, 1, 1).key == DefaultKey::NoOp);
+    assert_eq!(entry(2, 1, 1).alias, "_");
+}
+
+#[test]
+fn aliases_translate_to_the_expected_usage() {
+    assert!(entry(0, 0, 3).key == DefaultKey::Standard(KeyboardUsage::KeyboardBacktickTilde));
+    assert!(entry(0, 1, 2).key == DefaultKey::Standard(KeyboardUsage::KeyboardSpacebar));
+    assert!(entry(0, 1, 3).key == DefaultKey::Function(BuiltinFunctionKey::SetRelativeLayerTransient(1)));
+    assert!(entry(1, 0, 3).key == DefaultKey::ConsumerControl(hut::Consumer::VolumeIncrement));
+    assert!(entry(1, 0, 2).key == DefaultKey::Text("Hi!"));
+    assert!(entry(2, 0, 3).key == DefaultKey::MidiNote { channel: 9, note: 36 });
+}
+
+#[test]
+fn user_keys_are_referenced_as_is() {
+    assert!(entry(2, 1, 3).key == POP_LAYER);
+    assert_eq!(entry(2, 1, 3).alias, "fn : POP_LAYER");
+    assert!(entry(1, 1, 3).key == DefaultKey::Function(BuiltinFunctionKey::SetRelativeLayerTransient(1)));
+}
+
+#[test]
+fn mismatches_are_reported() {
+    let mut table = USAGE_TABLE.to_vec();
+    table[5].key = DefaultKey::NoOp;
+    assert!(matches!(
+        LAYOUT.check_usage_table(&table),
+        Err(LayoutUsageMismatch::Key { entry, .. }) if entry.layer == 0 && entry.row == 1 && entry.col == 1
+    ));
+
+    table.pop();
+    assert!(matches!(
+        LAYOUT.check_usage_table(&table),
+        Err(LayoutUsageMismatch::WrongSize { expected: 24, found: 23 })
+    ));
+}
+
+#[test]
+fn key_presence_defaults_to_every_key() {
+    for row in 0..2 {
+        for col in 0..4 {
+            assert!(LAYOUT.is_key_present(row, col));
+        }
+    }
+}
+
+#[test]
+fn key_presence_mask_hides_positions() {
+    assert!(MASKED_LAYOUT.is_key_present(0, 0));
+    assert!(MASKED_LAYOUT.is_key_present(0, 3));
+    assert!(!MASKED_LAYOUT.is_key_present(1, 0));
+    assert!(MASKED_LAYOUT.is_key_present(1, 1));
+    assert!(MASKED_LAYOUT.is_key_present(1, 2));
+    assert!(!MASKED_LAYOUT.is_key_present(1, 3));
+
+    // The keys themselves are kept.
+    assert!(MASKED_LAYOUT.check_usage_table(USAGE_TABLE).is_ok());
+}
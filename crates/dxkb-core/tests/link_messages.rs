@@ -0,0 +1,80 @@
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+use dxkb_core::keyboard::{
+    KeyboardLinkMessage, MAX_BATCHED_KEY_EVENTS, MatrixKeyEvent, SplitKeyboardLinkMessage, TaggedLinkMessage,
+};
+
+fn events(msg: &SplitKeyboardLinkMessage) -> Vec<MatrixKeyEvent> {
+    match msg {
+        SplitKeyboardLinkMessage::MatrixKeyEvents { events } => events.to_vec(),
+        other => panic!("Unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn test_coalesce_key_events() {
+    let mut queued = SplitKeyboardLinkMessage::MatrixKeyDown { row: 1, col: 2 };
+    assert!(SplitKeyboardLinkMessage::coalesce_key_events(
+        &mut queued,
+        &SplitKeyboardLinkMessage::MatrixKeyUp { row: 1, col: 2 }
+    ));
+    assert_eq!(
+        events(&queued),
+        vec![
+            MatrixKeyEvent { row: 1, col: 2, pressed: true },
+            MatrixKeyEvent { row: 1, col: 2, pressed: false },
+        ]
+    );
+
+    // Until the batch is full.
+    for col in 0..(MAX_BATCHED_KEY_EVENTS - 2) as u8 {
+        let msg = SplitKeyboardLinkMessage::MatrixKeyDown { row: 0, col };
+        assert!(SplitKeyboardLinkMessage::coalesce_key_events(&mut queued, &msg));
+    }
+    let msg = SplitKeyboardLinkMessage::MatrixKeyDown { row: 3, col: 3 };
+    assert!(!SplitKeyboardLinkMessage::coalesce_key_events(&mut queued, &msg));
+    assert_eq!(events(&queued).len(), MAX_BATCHED_KEY_EVENTS);
+}
+
+#[test]
+fn test_coalesce_key_events_only_merges_key_events() {
+    let mut queued = SplitKeyboardLinkMessage::HostLeds { leds: 1 };
+    let msg = SplitKeyboardLinkMessage::MatrixKeyDown { row: 0, col: 0 };
+    assert!(!SplitKeyboardLinkMessage::coalesce_key_events(&mut queued, &msg));
+    assert!(matches!(queued, SplitKeyboardLinkMessage::HostLeds { leds: 1 }));
+
+    let mut queued = msg.clone();
+    let leds = SplitKeyboardLinkMessage::HostLeds { leds: 1 };
+    assert!(!SplitKeyboardLinkMessage::coalesce_key_events(&mut queued, &leds));
+    assert!(matches!(queued, SplitKeyboardLinkMessage::MatrixKeyDown { row: 0, col: 0 }));
+}
+
+#[test]
+fn test_tagged_messages() {
+    type Msg = TaggedLinkMessage<u32>;
+
+    let core = Msg::from_core(SplitKeyboardLinkMessage::HostLeds { leds: 2 });
+    assert!(matches!(core.as_core(), Some(SplitKeyboardLinkMessage::HostLeds { leds: 2 })));
+    assert_eq!(core.as_user(), None);
+
+    let user = Msg::from_user(7);
+    assert!(user.as_core().is_none());
+    assert_eq!(user.as_user(), Some(&7));
+}
+
+#[test]
+fn test_tagged_messages_coalesce_key_events() {
+    type Msg = TaggedLinkMessage<u32>;
+
+    let mut queued = Msg::CoreMsg(SplitKeyboardLinkMessage::MatrixKeyDown { row: 1, col: 2 });
+    let msg = Msg::CoreMsg(SplitKeyboardLinkMessage::MatrixKeyUp { row: 1, col: 2 });
+    assert!(Msg::coalesce_key_events(&mut queued, &msg));
+    assert_eq!(events(queued.as_core().unwrap()).len(), 2);
+
+    // The messages of the target are never merged.
+    assert!(!Msg::coalesce_key_events(&mut queued, &Msg::UserMsg(1)));
+    let mut queued = Msg::UserMsg(1);
+    assert!(!Msg::coalesce_key_events(&mut queued, &msg));
+    assert_eq!(queued.as_user(), Some(&1));
+}
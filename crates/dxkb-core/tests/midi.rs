@@ -0,0 +1,29 @@
+use dxkb_core::midi::{MIDI_QUEUE_LEN, MidiEvent, MidiOut};
+
+#[test]
+fn test_note_packets() {
+    let on = MidiEvent::NoteOn { channel: 9, note: 36, velocity: 100 };
+    assert_eq!(on.to_packet(), [0x09, 0x99, 36, 100]);
+    let off = MidiEvent::NoteOff { channel: 0, note: 60 };
+    assert_eq!(off.to_packet(), [0x08, 0x80, 60, 0]);
+}
+
+#[test]
+fn test_velocity_is_never_zero() {
+    let mut midi = MidiOut::new();
+    midi.set_velocity(0);
+    assert_eq!(midi.velocity(), 1);
+    midi.set_velocity(200);
+    assert_eq!(midi.velocity(), 127);
+}
+
+#[test]
+fn test_full_queue_drops_events() {
+    let mut midi = MidiOut::new();
+    for note in 0..MIDI_QUEUE_LEN as u8 + 4 {
+        midi.note_on(0, note);
+    }
+    assert_eq!(midi.pending(), MIDI_QUEUE_LEN);
+    midi.clear();
+    assert_eq!(midi.pending(), 0);
+}
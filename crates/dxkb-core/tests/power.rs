@@ -0,0 +1,73 @@
+use core::time::Duration;
+
+use dxkb_common::testing::MockClock;
+use dxkb_core::power::{
+    ws2812_brightness_for_budget, CurrentBudgetSink, UsbPortKind, UsbPowerMonitor, CHARGER_DETECT_TIMEOUT,
+    USB_CHARGER_CURRENT_MA, USB_SUSPEND_CURRENT_MA, USB_UNCONFIGURED_CURRENT_MA,
+};
+use usb_device::device::UsbDeviceState;
+
+#[derive(Default)]
+struct RecordingSink {
+    budgets: Vec<u16>,
+}
+
+impl CurrentBudgetSink for RecordingSink {
+    fn set_current_budget(&mut self, budget_ma: u16) {
+        self.budgets.push(budget_ma);
+    }
+}
+
+#[test]
+fn test_ws2812_brightness_for_budget() {
+    assert_eq!(ws2812_brightness_for_budget(6, USB_CHARGER_CURRENT_MA), u8::MAX);
+    // 50 mA left for 360 mA of LEDs.
+    assert_eq!(ws2812_brightness_for_budget(6, USB_UNCONFIGURED_CURRENT_MA), 35);
+    assert_eq!(ws2812_brightness_for_budget(6, USB_SUSPEND_CURRENT_MA), 0);
+    assert_eq!(ws2812_brightness_for_budget(0, 0), u8::MAX);
+}
+
+#[test]
+fn test_budget_of_charger() {
+    let clock = MockClock::new();
+    let mut monitor = UsbPowerMonitor::<MockClock>::new(500);
+    let mut sink = RecordingSink::default();
+
+    monitor.poll(&clock, true, UsbDeviceState::Default, &mut sink);
+    assert_eq!(monitor.port_kind(), UsbPortKind::Unknown);
+
+    clock.advance(CHARGER_DETECT_TIMEOUT);
+    monitor.poll(&clock, true, UsbDeviceState::Default, &mut sink);
+    assert_eq!(monitor.port_kind(), UsbPortKind::Charger);
+    assert_eq!(sink.budgets, [USB_UNCONFIGURED_CURRENT_MA, USB_CHARGER_CURRENT_MA]);
+}
+
+#[test]
+fn test_budget_of_data_host() {
+    let clock = MockClock::new();
+    let mut monitor = UsbPowerMonitor::<MockClock>::new(250);
+    let mut sink = RecordingSink::default();
+
+    monitor.poll(&clock, true, UsbDeviceState::Configured, &mut sink);
+    clock.advance(Duration::from_secs(10));
+    monitor.poll(&clock, true, UsbDeviceState::Suspend, &mut sink);
+    assert_eq!(monitor.port_kind(), UsbPortKind::DataHost);
+    assert_eq!(sink.budgets, [250, USB_SUSPEND_CURRENT_MA]);
+}
+
+#[test]
+fn test_charger_across_clock_wrap() {
+    // Wraps around every ~17 s, like the cycle counter of the MCU does.
+    let clock = MockClock::wrapping(34);
+    let mut monitor = UsbPowerMonitor::<MockClock>::new(500);
+    let mut sink = RecordingSink::default();
+
+    monitor.poll(&clock, true, UsbDeviceState::Default, &mut sink);
+    clock.advance(CHARGER_DETECT_TIMEOUT);
+    for _ in 0..60 {
+        monitor.poll(&clock, true, UsbDeviceState::Default, &mut sink);
+        assert_eq!(monitor.port_kind(), UsbPortKind::Charger);
+        clock.advance(Duration::from_secs(1));
+    }
+    assert_eq!(sink.budgets, [USB_UNCONFIGURED_CURRENT_MA, USB_CHARGER_CURRENT_MA]);
+}
@@ -0,0 +1,133 @@
+#![cfg(feature = "sim")]
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+#![feature(macro_metavar_expr_concat)]
+
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use dxkb_common::KeyState;
+use dxkb_core::hid::{HidKeyboard, HidKeyboardPressError};
+use dxkb_core::keyboard::{AlwaysMaster, SplitKeyboardLike, UnibodyKeyboard, UnibodyKeyboardLayout};
+use dxkb_core::keys::DefaultKey;
+use dxkb_core::sim::{SimClock, SimHid, SimMatrix, SimUsbBus};
+use dxkb_peripheral::key_matrix::KeyMatrixLike;
+use dxkb_split_link::NullSplitBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_hid::descriptor::KeyboardUsage;
+
+type Layout = UnibodyKeyboardLayout<DefaultKey, 1, 1, 3>;
+type Keyboard = UnibodyKeyboard<1, 1, 3, SimClock, SimHid, DefaultKey, SimMatrix<1, 3>, ()>;
+
+const LAYOUT: Layout = Layout::new(dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [  'A',  'B',  'C'],
+            ]
+        },
+    ]
+));
+
+fn scan<const ROWS: u8, const COLS: u8>(matrix: &mut SimMatrix<ROWS, COLS>) -> Vec<(u8, u8, KeyState)> {
+    let mut changes = vec![];
+    matrix.scan_matrix_act(|row, col, state| changes.push((row, col, state)));
+    changes
+}
+
+#[test]
+fn test_matrix_fed_from_lines() {
+    let mut matrix = SimMatrix::<2, 3>::new();
+    matrix.input().feed(Cursor::new("# comment\np 0 1\n\np 1 2\nbogus\np 1 7\n"));
+    assert_eq!(scan(&mut matrix), vec![(0, 1, KeyState::Pressed), (1, 2, KeyState::Pressed)]);
+    assert_eq!(scan(&mut matrix), vec![]);
+
+    matrix.input().feed(Cursor::new("r 0 1\n"));
+    assert_eq!(scan(&mut matrix), vec![(0, 1, KeyState::Released)]);
+    assert_eq!(matrix.row_bits(1), 0b100);
+}
+
+#[test]
+fn test_matrix_fed_from_another_thread() {
+    let mut matrix = SimMatrix::<1, 1>::new();
+    let input = matrix.input();
+    std::thread::spawn(move || input.press(0, 0)).join().unwrap();
+    assert_eq!(scan(&mut matrix), vec![(0, 0, KeyState::Pressed)]);
+}
+
+#[test]
+fn test_missing_keys_are_never_pressed() {
+    let mut matrix = SimMatrix::<1, 2>::new();
+    matrix.set_key_present(0, 1, false);
+    matrix.input().press(0, 1);
+    assert_eq!(scan(&mut matrix), vec![]);
+}
+
+#[test]
+fn test_hid_reports() {
+    let mut hid = SimHid::new();
+    hid.press_key(KeyboardUsage::KeyboardAa).unwrap();
+    hid.press_key(KeyboardUsage::KeyboardBb).unwrap();
+    assert!(hid.press_key(KeyboardUsage::KeyboardAa).is_err());
+    assert!(hid.dirty());
+    hid.tick().unwrap();
+    assert!(!hid.dirty());
+
+    hid.release_key(KeyboardUsage::KeyboardAa).unwrap();
+    assert_eq!(hid.pressed_keys(), &[KeyboardUsage::KeyboardBb]);
+    assert!(hid.release_key(KeyboardUsage::KeyboardAa).is_err());
+}
+
+#[test]
+fn test_hid_max_pressed_keys() {
+    let mut hid = SimHid::new();
+    hid.set_max_pressed_keys(Some(2));
+    hid.press_key(KeyboardUsage::KeyboardAa).unwrap();
+    hid.press_key(KeyboardUsage::KeyboardBb).unwrap();
+    assert!(!hid.is_rollover());
+
+    assert!(matches!(hid.press_key(KeyboardUsage::KeyboardCc), Err(HidKeyboardPressError::Rollover)));
+    assert!(matches!(hid.press_key(KeyboardUsage::KeyboardAa), Err(HidKeyboardPressError::AlreadyPressed)));
+    assert!(hid.is_rollover());
+    assert_eq!(hid.pressed_keys(), &[KeyboardUsage::KeyboardAa, KeyboardUsage::KeyboardBb]);
+
+    // Releasing a reported key makes room, but the rejected one is still held.
+    hid.release_key(KeyboardUsage::KeyboardAa).unwrap();
+    assert!(hid.is_rollover());
+    assert!(hid.release_key(KeyboardUsage::KeyboardCc).is_err());
+    assert!(!hid.is_rollover());
+    hid.press_key(KeyboardUsage::KeyboardCc).unwrap();
+}
+
+static ROLLOVER: AtomicBool = AtomicBool::new(false);
+
+#[test]
+fn test_keyboard_from_builder() {
+    let matrix = SimMatrix::new();
+    let input = matrix.input();
+    let mut kb = Keyboard::builder()
+        .clock(SimClock::new())
+        .hid(SimHid::new())
+        .layout(LAYOUT)
+        .matrix(matrix)
+        .split_bus(NullSplitBus)
+        .master_sense(AlwaysMaster)
+        .max_pressed_keys(1)
+        .rollover_hook(|_, entered| ROLLOVER.store(entered, Ordering::Relaxed))
+        .build();
+
+    let usb_alloc = UsbBusAllocator::new(SimUsbBus::new());
+    let mut device = UsbDeviceBuilder::new(&usb_alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    input.press(0, 0);
+    kb.poll(&mut (), &mut device);
+    assert_eq!(kb.hid_mut().pressed_keys(), &[KeyboardUsage::KeyboardAa]);
+    assert!(!ROLLOVER.load(Ordering::Relaxed));
+
+    input.press(0, 1);
+    kb.poll(&mut (), &mut device);
+    assert_eq!(kb.hid_mut().pressed_keys(), &[KeyboardUsage::KeyboardAa]);
+    assert!(ROLLOVER.load(Ordering::Relaxed));
+}
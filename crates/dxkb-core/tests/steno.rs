@@ -0,0 +1,44 @@
+use dxkb_core::steno::{MAX_STENO_PACKET_LEN, StenoChord, StenoKey, StenoOut, StenoProtocol};
+
+fn encode(protocol: StenoProtocol, chord: StenoChord) -> Vec<u8> {
+    let mut buf = [0u8; MAX_STENO_PACKET_LEN];
+    let len = protocol.encode(chord, &mut buf);
+    buf[..len].to_vec()
+}
+
+#[test]
+fn test_gemini_pr_encoding() {
+    let chord = StenoChord::empty().with(StenoKey::TL).with(StenoKey::A).with(StenoKey::ZR);
+    assert_eq!(encode(StenoProtocol::GeminiPr, chord), vec![0x80, 0x10, 0x20, 0x00, 0x00, 0x01]);
+    assert_eq!(encode(StenoProtocol::GeminiPr, StenoChord::empty().with(StenoKey::Fn)), vec![0xc0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_tx_bolt_encoding() {
+    let chord = StenoChord::empty().with(StenoKey::TL).with(StenoKey::A).with(StenoKey::U);
+    assert_eq!(encode(StenoProtocol::TxBolt, chord), vec![0x02, 0x62, 0x00]);
+
+    let chord = StenoChord::empty().with(StenoKey::N3).with(StenoKey::DR).with(StenoKey::Pwr);
+    assert_eq!(encode(StenoProtocol::TxBolt, chord), vec![0xd4, 0x00]);
+}
+
+#[test]
+fn test_chord_sent_on_full_release() {
+    let mut steno = StenoOut::new();
+    steno.press(StenoKey::S1);
+    steno.press(StenoKey::TL);
+    steno.release(StenoKey::S1);
+    assert_eq!(steno.pending(), 0);
+
+    // Keys pressed while others are held join the same chord.
+    steno.press(StenoKey::E);
+    steno.release(StenoKey::TL);
+    assert_eq!(steno.pending(), 0);
+    steno.release(StenoKey::E);
+    assert_eq!(steno.pending(), MAX_STENO_PACKET_LEN);
+
+    steno.set_protocol(StenoProtocol::TxBolt);
+    steno.press(StenoKey::KL);
+    steno.release(StenoKey::KL);
+    assert_eq!(steno.pending(), MAX_STENO_PACKET_LEN + 2);
+}
@@ -0,0 +1,38 @@
+use dxkb_core::builder::{BuildableKeyboard, KeyboardBuilder, KeyboardParts};
+
+struct Clock;
+struct Hid;
+struct Layout;
+struct Matrix;
+struct Bus;
+struct Master;
+
+struct Keyboard;
+
+impl BuildableKeyboard for Keyboard {
+    type Clock = Clock;
+    type Hid = Hid;
+    type Layout = Layout;
+    type Matrix = Matrix;
+    type SplitBus = Bus;
+    type MasterSense = Master;
+    type Filter = ();
+    type User = ();
+    type UserMsg = ();
+
+    fn from_parts(_parts: KeyboardParts<Self>) -> Self {
+        Keyboard
+    }
+}
+
+fn main() {
+    let _ = KeyboardBuilder::<Keyboard>::new()
+        .clock(Clock)
+        .hid(Hid)
+        .clock(Clock)
+        .layout(Layout)
+        .matrix(Matrix)
+        .split_bus(Bus)
+        .master_sense(Master)
+        .build();
+}
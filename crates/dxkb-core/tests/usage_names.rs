@@ -0,0 +1,36 @@
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+#![feature(macro_metavar_expr_concat)]
+
+use dxkb_core::usage_names::{KEYBOARD_USAGES, usage_from_code, usage_from_name, usage_name};
+use usbd_hid::descriptor::KeyboardUsage;
+
+// Resolved at compile time.
+const ENTER: Option<KeyboardUsage> = usage_from_name("Enter");
+
+#[test]
+fn test_usage_names_match_the_keymap_aliases() {
+    assert_eq!(ENTER, Some(dxkb_core::hid_key_from_alias!(Enter)));
+    assert_eq!(usage_from_name("3Hash"), Some(dxkb_core::hid_key_from_alias!("3Hash")));
+    assert_eq!(usage_from_name("Aa"), Some(dxkb_core::hid_key_from_alias!(A)));
+    assert_eq!(usage_name(KeyboardUsage::KeyboardLeftGUI), Some("LeftGUI"));
+}
+
+#[test]
+fn test_usage_names_round_trip() {
+    for (name, usage) in KEYBOARD_USAGES {
+        assert_eq!(usage_from_name(name), Some(*usage));
+        assert_eq!(usage_name(*usage), Some(*name));
+        assert_eq!(usage_from_code(*usage as u8), Some(*usage));
+    }
+}
+
+#[test]
+fn test_unknown_usage_names() {
+    assert_eq!(usage_from_name("enter"), None);
+    assert_eq!(usage_from_name("KeyboardEnter"), None);
+    assert_eq!(usage_from_name(""), None);
+    assert_eq!(usage_name(KeyboardUsage::KeypadEnter), None);
+    assert_eq!(usage_from_code(0), None);
+    assert_eq!(usage_from_code(0xFF), None);
+}
@@ -0,0 +1,46 @@
+use core::time::Duration;
+
+use dxkb_common::testing::MockClock;
+use dxkb_core::usb::{UsbEnumerationBackoff, USB_ENUMERATION_TIMEOUT, USB_REATTACH_INTERVAL};
+use dxkb_peripheral::usb::UsbSoftDisconnect;
+use usb_device::device::UsbDeviceState;
+
+#[derive(Default)]
+struct RecordingDevice {
+    disconnects: Vec<bool>,
+}
+
+impl UsbSoftDisconnect for RecordingDevice {
+    fn set_soft_disconnect(&mut self, disconnect: bool) {
+        self.disconnects.push(disconnect);
+    }
+}
+
+#[test]
+fn test_reattach_across_clock_wrap() {
+    // Wraps around every ~17 s, so that the reattach interval is longer than
+    // the range of the clock, like the cycle counter of the MCU.
+    let clock = MockClock::wrapping(34);
+    let mut backoff = UsbEnumerationBackoff::new(&clock);
+    let mut device = RecordingDevice::default();
+
+    clock.advance(USB_ENUMERATION_TIMEOUT);
+    assert!(backoff.poll(&clock, UsbDeviceState::Default, &mut device));
+    assert!(!backoff.is_host_expected());
+
+    let step = Duration::from_millis(100);
+    for _ in 0..(USB_REATTACH_INTERVAL.as_millis() / step.as_millis() - 1) {
+        clock.advance(step);
+        backoff.poll(&clock, UsbDeviceState::Default, &mut device);
+    }
+    assert!(device.disconnects.is_empty());
+
+    clock.advance(step);
+    assert!(!backoff.poll(&clock, UsbDeviceState::Default, &mut device));
+    assert_eq!(device.disconnects, [true]);
+
+    clock.advance(step);
+    assert!(backoff.poll(&clock, UsbDeviceState::Default, &mut device));
+    assert_eq!(device.disconnects, [true, false]);
+    assert!(backoff.is_host_expected());
+}
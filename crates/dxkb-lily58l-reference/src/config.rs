@@ -0,0 +1,130 @@
+use dxkb_core::{hid::ReportHidKeyboard, keyboard::{PinMasterSense, SplitKeyboard, SplitKeyboardLayout, SplitKeyboardLinkMessage, SplitLayoutConfig}, keys::DefaultKey};
+use dxkb_peripheral::{clock::DWTClock, dma::{assert_distinct_streams, DmaStreamIdentity}, key_matrix::{DebouncerEagerPerKey, KeyMatrix, RowScan}, uart_dma_rb::{HalfDuplex, UartDmaRb, UartDmaRbIsr}};
+use dxkb_split_link::{DefaultSplitLinkTimings, SplitBus};
+use stm32f4xx_hal::{dma::{Stream5, Stream6}, gpio::{DynamicPin, Pin}, otg_fs::USB, pac::{DMA1, USART2}, signature::Uid};
+use synopsys_usb_otg::UsbBus;
+
+// The total layers of the layout.
+const LAYERS: u8 = 3;
+
+// The dimensions of each side of the keyboard.
+const SIDE_ROWS: u8 = 5;
+const SIDE_COLS: u8 = 6;
+
+// The total dimensions of the keyboard, including both sides.
+const LAYOUT_ROWS: u8 = SIDE_ROWS;
+const LAYOUT_COLS: u8 = 2 * SIDE_COLS;
+
+const DEBOUNCE_MILLIS: u8 = 20;
+
+pub type KeyMatrixRowPins = (
+    DynamicPin<'B', 3>,
+    DynamicPin<'B', 4>,
+    DynamicPin<'B', 5>,
+    DynamicPin<'B', 8>,
+    DynamicPin<'B', 9>,
+);
+
+#[cfg(feature = "side-left")]
+pub type KeyMatrixColPins = (
+    DynamicPin<'B', 1>,
+    DynamicPin<'B', 0>,
+    DynamicPin<'A', 5>,
+    DynamicPin<'A', 6>,
+    DynamicPin<'A', 7>,
+    DynamicPin<'A', 4>,
+);
+
+#[cfg(feature = "side-right")]
+pub type KeyMatrixColPins = (
+    DynamicPin<'A', 4>,
+    DynamicPin<'A', 7>,
+    DynamicPin<'A', 6>,
+    DynamicPin<'A', 5>,
+    DynamicPin<'B', 0>,
+    DynamicPin<'B', 1>,
+);
+
+// High while the USB host powers this side, which makes it the master.
+pub type UsbBusSensePin = Pin<'A', 9>;
+
+// The split link is half duplex, over a single wire.
+pub type SplitBusTxRxPin = Pin<'A', 2>;
+pub type SplitBusUsartPort = USART2;
+pub type SplitBusDmaPeripheral = DMA1;
+
+pub type SplitBusTxDmaStream = Stream6<SplitBusDmaPeripheral>;
+pub type SplitBusRxDmaStream = Stream5<SplitBusDmaPeripheral>;
+
+// Both sides must use the same baud rate.
+pub const SPLIT_BUS_BAUD_RATE: u32 = 2_000_000;
+
+// The DMA streams of every user must be different from each other.
+const _: () = assert_distinct_streams(&[
+    <SplitBusTxDmaStream as DmaStreamIdentity>::ID,
+    <SplitBusRxDmaStream as DmaStreamIdentity>::ID,
+]);
+
+pub type SplitBusLineMode = HalfDuplex<SplitBusUsartPort, SplitBusTxDmaStream, SplitBusRxDmaStream, 4, 4>;
+pub type SplitBusUsart = UartDmaRb<SplitBusLineMode, 256, 256, 128>;
+pub type SplitBusUsartIsr = UartDmaRbIsr<SplitBusLineMode, 256, 128>;
+pub type TSplitBus = SplitBus<SplitKeyboardLinkMessage, DefaultSplitLinkTimings, SplitBusUsart, DWTClock, 32>;
+
+pub type TKeyMatrixDebounce = DebouncerEagerPerKey<SIDE_ROWS, SIDE_COLS, DEBOUNCE_MILLIS>;
+pub type TKeyMatrix = KeyMatrix<
+    SIDE_ROWS,
+    SIDE_COLS,
+    KeyMatrixRowPins,
+    KeyMatrixColPins,
+    RowScan,
+    TKeyMatrixDebounce,
+    ()
+>;
+
+pub type TLayout = SplitKeyboardLayout<KeyboardLayoutConfig, DefaultKey, LAYERS, LAYOUT_ROWS, LAYOUT_COLS>;
+
+pub type TKeyboard<'b> = SplitKeyboard<
+    LAYERS,
+    LAYOUT_ROWS,
+    LAYOUT_COLS,
+    SIDE_ROWS,
+    SIDE_COLS,
+    DWTClock,
+    CurrentSide,
+    ReportHidKeyboard<'b, UsbBus<USB>>,
+    KeyboardLayoutConfig,
+    DefaultKey,
+    TKeyMatrix,
+    PinMasterSense<UsbBusSensePin>,
+    TSplitBus,
+    (),
+>;
+
+pub struct KeyboardLayoutConfig;
+impl SplitLayoutConfig for KeyboardLayoutConfig {
+    const SPLIT_RIGHT_COL_OFFSET: u8 = SIDE_COLS;
+}
+
+#[cfg(feature = "side-left")]
+pub type CurrentSide = dxkb_core::keyboard::Left;
+
+#[cfg(feature = "side-right")]
+pub type CurrentSide = dxkb_core::keyboard::Right;
+
+#[cfg(not(any(feature = "side-right", feature = "side-left")))]
+pub type CurrentSide = dxkb_core::keyboard::Left;
+
+#[cfg(all(feature = "side-right", feature = "side-left"))]
+compile_error!("Only side-left or side-right features must be enabled at a time!");
+
+/// Returns the unique ID of the MCU, used for telling apart both sides in the
+/// split link.
+pub fn get_device_id() -> u128 {
+    let mut uid = [0u8; 16];
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(core::mem::transmute(Uid::get()), uid.as_mut_ptr(), size_of::<Uid>());
+    };
+
+    u128::from_le_bytes(uid)
+}
@@ -0,0 +1,262 @@
+// Reference firmware for a Lily58L with a STeMCell controller. Unlike the
+// dxkb-lily58l-stemcell target, which is where new features are tried first,
+// this one only includes what every split keyboard needs (the layers! macro,
+// ReportHidKeyboard, SplitKeyboard, PinMasterSense and a half duplex
+// UartDmaRb), and only goes through the public APIs of the dxkb crates. It
+// is meant to be the starting point of new targets, and it is always kept
+// building, so it also works as an integration test of those APIs.
+
+#![no_std]
+#![no_main]
+#![allow(incomplete_features)]
+#![allow(static_mut_refs)]
+#![feature(generic_const_exprs)]
+#![feature(macro_metavar_expr_concat)]
+
+mod config;
+mod layout;
+
+use config::*;
+
+use core::any::type_name;
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+use cortex_m::interrupt::free;
+use dxkb_common::{dev_info, util::RingBuffer};
+use dxkb_core::debug::{DebugHidFeature, DebugRequest};
+use dxkb_core::hid::ReportHidKeyboard;
+use dxkb_core::keyboard::{PinMasterSense, SplitKeyboardLike};
+use dxkb_core::log::RingBufferLogger;
+use dxkb_core::usb::UsbFeatureSet;
+use dxkb_peripheral::{clock::DWTClock, uart_dma_rb::HalfDuplexInitializer, BootloaderUtil, InterruptReceiver};
+
+#[allow(unused_imports)]
+use panic_itm as _;
+
+use cortex_m_rt::entry;
+use dxkb_peripheral::uart_dma_rb::{DmaRingBuffer, UartDmaRb};
+use dxkb_split_link::{ByeReason, SplitBus};
+use stm32f4xx_hal::{pac::EXTI, syscfg::SysCfg};
+use stm32f4xx_hal::rcc::Clocks;
+use stm32f4xx_hal::{
+    dma::StreamsTuple,
+    interrupt,
+    otg_fs::USB,
+    pac::{self, NVIC},
+    prelude::*,
+    rcc::RccExt,
+};
+use synopsys_usb_otg::UsbBus;
+use usb_device::{device::{UsbDeviceBuilder, UsbRev}, LangID};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{StringDescriptors, UsbVidPid};
+
+// How long to wait for the Bye frame to be sent before rebooting into the
+// bootloader, when the host asks for it.
+const BOOTLOADER_BYE_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10);
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+static mut SPLIT_BUS_DMA_RX_BUF: DmaRingBuffer<256, 128> = DmaRingBuffer::new();
+static mut SPLIT_BUS_DMA_TX_BUF: [u8; 256] = [0u8; 256];
+
+static mut KEYBOARD: MaybeUninit<TKeyboard> = MaybeUninit::uninit();
+// The interrupt handlers of the split bus, which don't need the keyboard.
+static mut SPLIT_BUS_ISR: MaybeUninit<SplitBusUsartIsr> = MaybeUninit::uninit();
+static mut USB_ALLOC: MaybeUninit<UsbBusAllocator<UsbBus<USB>>> = MaybeUninit::uninit();
+
+static mut HID_LOGGER: RingBufferLogger<1024> = RingBufferLogger::new(log::Level::Trace, RingBuffer::new());
+
+fn init_split_bus(
+    usart: SplitBusUsartPort,
+    tx_stream: SplitBusTxDmaStream,
+    rx_stream: SplitBusRxDmaStream,
+    txrx_pin: SplitBusTxRxPin,
+    clock: DWTClock,
+    clocks: &Clocks,
+    syscfg: &mut SysCfg,
+    exti: &mut EXTI
+) -> TSplitBus {
+    // The UID is 96 bits long.
+    let device_id = get_device_id();
+    let (uart_dma, uart_dma_isr) = UartDmaRb::init_split(
+        HalfDuplexInitializer::new(usart, txrx_pin, tx_stream, rx_stream, syscfg, exti)
+            .with_baud_rate(SPLIT_BUS_BAUD_RATE)
+            .with_backoff_seed((device_id ^ (device_id >> 32) ^ (device_id >> 64)) as u32),
+        unsafe { &mut SPLIT_BUS_DMA_TX_BUF },
+        unsafe { &mut SPLIT_BUS_DMA_RX_BUF },
+        &clocks,
+    );
+
+    unsafe {
+        SPLIT_BUS_ISR.write(uart_dma_isr);
+    }
+
+    SplitBus::new(uart_dma, clock, device_id)
+}
+
+fn init_key_matrix(rows: KeyMatrixRowPins, cols: KeyMatrixColPins, clocks: &Clocks) -> TKeyMatrix {
+    TKeyMatrix::new(
+        clocks.sysclk(),
+        rows,
+        cols,
+        TKeyMatrixDebounce::new(),
+    )
+}
+
+#[entry]
+fn main() -> ! {
+    unsafe {
+        BootloaderUtil::handle_bootloader_enter_request();
+    }
+
+    let mut dp = pac::Peripherals::take().unwrap();
+    let mut cortex = cortex_m::Peripherals::take().unwrap();
+
+    let rcc = dp.RCC.constrain();
+
+    // The USB peripheral needs the 48 MHz clock.
+    let clocks = rcc
+        .cfgr
+        .use_hse(8.MHz())
+        .sysclk(96.MHz())
+        .pclk1(48.MHz())
+        .pclk2(48.MHz())
+        .require_pll48clk()
+        .freeze();
+
+    let gpioa = dp.GPIOA.split();
+    let gpiob = dp.GPIOB.split();
+
+    RingBufferLogger::install(unsafe { &HID_LOGGER }).unwrap();
+    dev_info!("Device startup. Device configuration:");
+    dev_info!(" - Current Side: {:?}", type_name::<CurrentSide>());
+
+    let clock = DWTClock::new(&clocks, &mut cortex.DCB, &mut cortex.DWT);
+
+    let usb = USB {
+        usb_global: dp.OTG_FS_GLOBAL,
+        usb_device: dp.OTG_FS_DEVICE,
+        usb_pwrclk: dp.OTG_FS_PWRCLK,
+        pin_dm: gpioa.pa11.into(),
+        pin_dp: gpioa.pa12.into(),
+        hclk: clocks.hclk(),
+    };
+
+    let usb_alloc = unsafe {
+        USB_ALLOC.write(UsbBus::new(usb, addr_of_mut!(EP_MEMORY).as_mut().unwrap()))
+    };
+
+    let mut usb_feature_debug = DebugHidFeature::new(usb_alloc, unsafe { &HID_LOGGER });
+
+    let usb_feature_kb = ReportHidKeyboard::alloc(
+        usb_alloc,
+        1
+    );
+
+    #[cfg(feature = "side-left")]
+    let product = "Lily58L Reference (Left Side)";
+
+    #[cfg(feature = "side-right")]
+    let product = "Lily58L Reference (Right Side)";
+
+    let mut usb_dev =
+        UsbDeviceBuilder::new(usb_alloc, UsbVidPid(0x16c0, 0x27db))
+            .usb_rev(UsbRev::Usb200)
+            .supports_remote_wakeup(true)
+            .strings(&[StringDescriptors::new(LangID::EN)
+                .serial_number("0")
+                .manufacturer("dxkb")
+                .product(product)])
+            .unwrap()
+            .build();
+
+    let matrix = init_key_matrix(
+        (
+            gpiob.pb3.into_dynamic(),
+            gpiob.pb4.into_dynamic(),
+            gpiob.pb5.into_dynamic(),
+            gpiob.pb8.into_dynamic(),
+            gpiob.pb9.into_dynamic(),
+        ),
+        #[cfg(feature = "side-left")]
+        (
+            gpiob.pb1.into_dynamic(),
+            gpiob.pb0.into_dynamic(),
+            gpioa.pa5.into_dynamic(),
+            gpioa.pa6.into_dynamic(),
+            gpioa.pa7.into_dynamic(),
+            gpioa.pa4.into_dynamic(),
+        ),
+        #[cfg(feature = "side-right")]
+        (
+            gpioa.pa4.into_dynamic(),
+            gpioa.pa7.into_dynamic(),
+            gpioa.pa6.into_dynamic(),
+            gpioa.pa5.into_dynamic(),
+            gpiob.pb0.into_dynamic(),
+            gpiob.pb1.into_dynamic(),
+        ),
+        &clocks,
+    );
+
+    let mut syscfg = dp.SYSCFG.constrain();
+    let dma = StreamsTuple::new(dp.DMA1);
+    let split_bus = init_split_bus(dp.USART2, dma.6, dma.5, gpioa.pa2, clock.clone(), &clocks, &mut syscfg, &mut dp.EXTI);
+
+    let master_tester = PinMasterSense::new(gpioa.pa9.into_pull_down_input());
+    unsafe {
+        KEYBOARD.write(
+            TKeyboard::builder()
+                .clock(clock)
+                .hid(usb_feature_kb)
+                .layout(layout::LAYOUT)
+                .matrix(matrix)
+                .split_bus(split_bus)
+                .master_sense(master_tester)
+                .build(),
+        );
+    }
+
+    unsafe {
+        // Go!
+        free(|_cs| {
+            NVIC::unmask(SplitBusUsartPort::INTERRUPT);
+            NVIC::unmask(SplitBusTxDmaStream::INTERRUPT);
+            NVIC::unmask(SplitBusTxRxPin::INTERRUPT);
+        });
+    }
+
+    loop {
+        let kb =
+            unsafe {
+                KEYBOARD.assume_init_mut()
+            };
+
+        if let Some((_, Some(DebugRequest::Bootloader))) = (kb.hid_mut(), &mut usb_feature_debug).poll_all(&mut usb_dev) {
+            kb.split_bus.shutdown(ByeReason::Bootloader, BOOTLOADER_BYE_TIMEOUT);
+            BootloaderUtil::enter_bootloader();
+        }
+        kb.poll(&mut (), &mut usb_dev);
+    }
+}
+
+#[interrupt]
+fn USART2() {
+    unsafe {
+        SPLIT_BUS_ISR.assume_init_mut().handle_usart_intr();
+    }
+}
+
+#[interrupt]
+fn DMA1_STREAM6() {
+    unsafe {
+        SPLIT_BUS_ISR.assume_init_mut().handle_dma_intr();
+    }
+}
+
+#[interrupt]
+fn EXTI2() {
+    unsafe {
+        SPLIT_BUS_ISR.assume_init_mut().handle_exti_intr();
+    }
+}
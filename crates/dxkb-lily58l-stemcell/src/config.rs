@@ -1,6 +1,15 @@
-use dxkb_core::{hid::ReportHidKeyboard, keyboard::{Left, Right, PinMasterSense, SplitKeyboard, SplitKeyboardLayout, SplitKeyboardLinkMessage, SplitLayoutConfig}, keys::DefaultKey};
-use dxkb_peripheral::{clock::DWTClock, key_matrix::{DebouncerEagerPerKey, KeyMatrix, RowScan}, uart_dma_rb::{HalfDuplex, UartDmaRb}};
+#[cfg(feature = "scan-capture")]
+use dxkb_common::debounce::CapturingDebouncer;
+use dxkb_core::{filter::NoFilter, hid::ReportHidKeyboard, keyboard::{Left, Right, PinMasterSense, SplitKeyboard, SplitKeyboardLayout, SplitLayoutConfig}, keys::DefaultKey};
+#[cfg(not(feature = "soak-test"))]
+use dxkb_core::keyboard::SplitKeyboardLinkMessage;
+#[cfg(feature = "soak-test")]
+use dxkb_core::keyboard::TaggedLinkMessage;
+#[cfg(feature = "soak-test")]
+use dxkb_split_link::soak::{SoakMsg, SoakTest};
+use dxkb_peripheral::{clock::DWTClock, key_matrix::{DebouncerEagerPerKey, KeyMatrix, RowScan}, uart_dma_rb::{HalfDuplex, UartDmaRb, UartDmaRbIsr}};
 use dxkb_split_link::{DefaultSplitLinkTimings, SplitBus};
+use dxkb_peripheral::dma::{assert_distinct_streams, DmaStreamIdentity};
 use stm32f4xx_hal::{dma::{Stream5, Stream6, Stream7}, gpio::{DynamicPin, Input, Output, Pin, PushPull}, otg_fs::USB, pac::{DMA1, DMA2, USART1, USART2}, signature::Uid};
 use synopsys_usb_otg::UsbBus;
 
@@ -51,16 +60,85 @@ pub type KeyMatrixColPins = (
 pub type UsbBusSensePin = Pin<'A', 9>;
 
 pub type SplitBusTxRxPin = Pin<'A', 2>;
+
+// The wake line, wired between the PB10 of both halves.
+#[cfg(feature = "wake-line")]
+pub type TWakeLine = dxkb_peripheral::low_power::WakeLine<'B', 10>;
 pub type SplitBusUsartPort = USART2;
 pub type SplitBusDmaPeripheral = DMA1;
 
 pub type SplitBusTxDmaStream = Stream6<SplitBusDmaPeripheral>;
 pub type SplitBusRxDmaStream = Stream5<SplitBusDmaPeripheral>;
 
-pub type SplitBusUsart = UartDmaRb<HalfDuplex<SplitBusUsartPort, SplitBusTxDmaStream, SplitBusRxDmaStream, 4, 4>, 256, 256, 128>;
-pub type TSplitBus = SplitBus<SplitKeyboardLinkMessage, DefaultSplitLinkTimings, SplitBusUsart, DWTClock, 32>;
-
+// Both sides must use the same baud rate.
+pub const SPLIT_BUS_BAUD_RATE: u32 = 2_000_000;
+
+// The baud rates the split bus falls back to when the link is too noisy,
+// starting from SPLIT_BUS_BAUD_RATE. Both sides must use the same ones.
+pub const SPLIT_BUS_FALLBACK_BAUD_RATES: &[u32] = &[SPLIT_BUS_BAUD_RATE, 1_000_000, 115_200];
+
+// The DMA streams of every user must be different from each other.
+const _: () = assert_distinct_streams(&[
+    <SplitBusTxDmaStream as DmaStreamIdentity>::ID,
+    <SplitBusRxDmaStream as DmaStreamIdentity>::ID,
+]);
+
+pub type SplitBusLineMode = HalfDuplex<SplitBusUsartPort, SplitBusTxDmaStream, SplitBusRxDmaStream, 4, 4>;
+pub type SplitBusUsart = UartDmaRb<SplitBusLineMode, 256, 256, 128>;
+pub type SplitBusUsartIsr = UartDmaRbIsr<SplitBusLineMode, 256, 128>;
+
+// The messages exchanged by both sides, which also carry the counters of the
+// soak test when it is enabled.
+#[cfg(not(feature = "soak-test"))]
+pub type TLinkMessage = SplitKeyboardLinkMessage;
+#[cfg(feature = "soak-test")]
+pub type TLinkMessage = TaggedLinkMessage<SoakMsg>;
+
+// How long the soak test runs since the link is first up. Both sides must use
+// the same duration.
+#[cfg(feature = "soak-test")]
+pub const SOAK_TEST_DURATION: core::time::Duration = core::time::Duration::from_secs(10 * 60);
+
+// How long the main loop can go without polling a working split bus before
+// the watchdog resets the MCU. It must be longer than the erase of the config
+// sector of the flash, which can take a couple of seconds.
+#[cfg(feature = "watchdog")]
+pub const WATCHDOG_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(8);
+
+pub type TSplitBus = SplitBus<TLinkMessage, DefaultSplitLinkTimings, SplitBusUsart, DWTClock, 32>;
+
+// The underglow strip, with its data line connected to PB15, driven by the SPI2
+// MOSI. Its DMA stream lives in the same controller as the split bus ones.
+#[cfg(feature = "underglow")]
+pub const UNDERGLOW_LEDS: usize = 6;
+#[cfg(feature = "underglow")]
+pub type UnderglowSpi = stm32f4xx_hal::pac::SPI2;
+#[cfg(feature = "underglow")]
+pub type UnderglowDmaStream = stm32f4xx_hal::dma::Stream4<SplitBusDmaPeripheral>;
+#[cfg(feature = "underglow")]
+pub type TUnderglow = dxkb_peripheral::ws2812::Ws2812<UnderglowSpi, UnderglowDmaStream, 0, UNDERGLOW_LEDS>;
+
+#[cfg(feature = "underglow")]
+const _: () = assert_distinct_streams(&[
+    <SplitBusTxDmaStream as DmaStreamIdentity>::ID,
+    <SplitBusRxDmaStream as DmaStreamIdentity>::ID,
+    <UnderglowDmaStream as DmaStreamIdentity>::ID,
+]);
+
+#[cfg(not(feature = "scan-capture"))]
 pub type TKeyMatrixDebounce = DebouncerEagerPerKey<SIDE_ROWS, SIDE_COLS, DEBOUNCE_MILLIS>;
+
+// The number of raw transitions kept by the scan capture.
+#[cfg(feature = "scan-capture")]
+const SCAN_CAPTURE_LEN: usize = 512;
+
+#[cfg(feature = "scan-capture")]
+pub type TKeyMatrixDebounce = CapturingDebouncer<
+    DebouncerEagerPerKey<SIDE_ROWS, SIDE_COLS, DEBOUNCE_MILLIS>,
+    SIDE_ROWS,
+    SIDE_COLS,
+    SCAN_CAPTURE_LEN,
+>;
 pub type TKeyMatrix = KeyMatrix<
     SIDE_ROWS,
     SIDE_COLS,
@@ -88,6 +166,8 @@ pub type TKeyboard<'b> = SplitKeyboard<
     PinMasterSense<UsbBusSensePin>,
     TSplitBus,
     KeyboardContext,
+    NoFilter,
+    TLinkMessage,
 >;
 
 pub struct KeyboardLayoutConfig;
@@ -136,11 +216,17 @@ macro_rules! custom_key_from_alias {
 
 pub struct KeyboardContext {
     pub plus_pending_press: bool,
+    #[cfg(feature = "soak-test")]
+    pub soak: SoakTest<DWTClock>,
 }
 
 impl KeyboardContext {
     pub const fn new() -> Self {
-        Self { plus_pending_press: false }
+        Self {
+            plus_pending_press: false,
+            #[cfg(feature = "soak-test")]
+            soak: SoakTest::new(SOAK_TEST_DURATION),
+        }
     }
 }
 
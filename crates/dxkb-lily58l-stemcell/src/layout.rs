@@ -1,4 +1,5 @@
  use crate::{config::TLayout, custom_key_from_alias};
+use dxkb_core::{hid::BootLeds, indicators::{Indicator, IndicatorBinding, Rgb}};
 
 #[rustfmt::skip]
 pub const LAYOUT: TLayout = TLayout::new(
@@ -51,3 +52,11 @@ pub const LAYOUT: TLayout = TLayout::new(
         ]
     )
 );
+
+/// The underglow LEDs used as indicators of the lock keys and the layers.
+pub static INDICATORS: [IndicatorBinding; 4] = [
+    IndicatorBinding::new(Indicator::HostLed(BootLeds::CAPS_LOCK), 0, Rgb::WHITE),
+    IndicatorBinding::new(Indicator::Layer(1), 1, Rgb::new(0, 0, 255)),
+    IndicatorBinding::new(Indicator::Layer(2), 1, Rgb::new(255, 0, 0)),
+    IndicatorBinding::new(Indicator::PresenceMode, 2, Rgb::new(0, 255, 0)),
+];
@@ -22,23 +22,50 @@ mod layout;
 use config::*;
 
 use cortex_m::interrupt::free;
-use dxkb_common::{LogicalKeyState, dev_info, util::RingBuffer};
-use dxkb_core::{debug::DebugHidFeature, do_on_key_state_ignore_masked, hid::HidKeyboard, keyboard::{HandleKey, KeyboardUsage, PinMasterSense}, log::RingBufferLogger};
+use dxkb_common::{LogicalKeyState, dev_info, dev_warn, util::RingBuffer};
+use dxkb_core::{build_info::BuildInfo, config::{ConfigHidFeature, ConfigRequest}, debug::{DebugHidFeature, DebugRequest}, link_stats::PersistentLinkStats, power::UsbPowerMonitor, remap::OsRemaps, indicators::IndicatorBindings, do_on_key_state_ignore_masked, hid::HidKeyboard, keyboard::{HandleKey, KeyboardUsage, PinMasterSense}, log::RingBufferLogger};
 use core::any::type_name;
 use core::mem::MaybeUninit;
 use core::ptr::addr_of_mut;
 use dxkb_core::hid::ReportHidKeyboard;
+#[cfg(not(feature = "usb-irq"))]
 use dxkb_core::usb::UsbFeatureSet;
 use dxkb_core::keyboard::SplitKeyboardLike;
+use dxkb_core::filter::KeyFilter;
 
-use dxkb_peripheral::{clock::DWTClock, uart_dma_rb::HalfDuplexInitializer, BootloaderUtil, InterruptReceiver};
+use dxkb_peripheral::{clock::DWTClock, flash_config::FlashConfig, reset::{self, ResetCounts}, uart_dma_rb::HalfDuplexInitializer, BootloaderUtil, InterruptReceiver};
+
+#[cfg(feature = "supply-voltage-sense")]
+use dxkb_peripheral::voltage::AdcSupplyVoltage;
+
+#[cfg(feature = "wake-line")]
+use dxkb_peripheral::low_power;
+
+#[cfg(feature = "underglow")]
+use dxkb_common::bus::BusWrite;
+#[cfg(feature = "underglow")]
+use dxkb_peripheral::{dma::DmaTimeSlicer, ws2812::{ws2812_buf_len, WS2812_MIN_REFRESH_PERIOD}};
+
+#[cfg(feature = "watchdog")]
+use dxkb_common::watchdog::Watchdog;
+#[cfg(feature = "watchdog")]
+use dxkb_peripheral::watchdog::IndependentWatchdog;
+
+#[cfg(feature = "usb-irq")]
+use dxkb_core::usb::{UsbInterruptMask, UsbInterruptPoll};
+#[cfg(not(feature = "usb-irq"))]
+use dxkb_core::usb::UsbEnumerationBackoff;
+#[cfg(feature = "usb-irq")]
+use heapless::spsc::Queue;
+#[cfg(feature = "usb-irq")]
+use usb_device::device::UsbDevice;
 
 #[allow(unused_imports)]
 use panic_itm as _;
 
 use cortex_m_rt::entry;
 use dxkb_peripheral::uart_dma_rb::{DmaRingBuffer, UartDmaRb};
-use dxkb_split_link::SplitBus;
+use dxkb_split_link::{Channel, SplitBus, TxOverflowPolicy};
 use stm32f4xx_hal::{pac::EXTI, syscfg::SysCfg};
 use stm32f4xx_hal::rcc::Clocks;
 use stm32f4xx_hal::{
@@ -57,9 +84,37 @@ use usb_device::device::{StringDescriptors, UsbVidPid};
 static mut EP_MEMORY: [u32; 1024] = [0; 1024];
 static mut SPLIT_BUS_DMA_RX_BUF: DmaRingBuffer<256, 128> = DmaRingBuffer::new();
 static mut SPLIT_BUS_DMA_TX_BUF: [u8; 256] = [0u8; 256];
+#[cfg(feature = "underglow")]
+static mut UNDERGLOW_DMA_BUF: [u8; ws2812_buf_len(UNDERGLOW_LEDS)] = [0u8; ws2812_buf_len(UNDERGLOW_LEDS)];
 
 static mut KEYBOARD: MaybeUninit<TKeyboard> = MaybeUninit::uninit();
+// The interrupt handlers of the split bus, which don't need the keyboard.
+static mut SPLIT_BUS_ISR: MaybeUninit<SplitBusUsartIsr> = MaybeUninit::uninit();
 static mut USB_ALLOC: MaybeUninit<UsbBusAllocator<UsbBus<USB>>> = MaybeUninit::uninit();
+// Fed by the split bus while it works, and while sleeping.
+#[cfg(feature = "watchdog")]
+static mut WATCHDOG: MaybeUninit<IndependentWatchdog> = MaybeUninit::uninit();
+
+// With usb-irq, everything polled from the USB interrupt is shared with it.
+#[cfg(feature = "usb-irq")]
+type TUsbPoll = ((), Option<DebugRequest>, Option<ConfigRequest>);
+#[cfg(feature = "usb-irq")]
+const USB_EVENT_QUEUE_LEN: usize = 4;
+#[cfg(feature = "usb-irq")]
+static mut USB_DEVICE: MaybeUninit<UsbDevice<'static, UsbBus<USB>>> = MaybeUninit::uninit();
+#[cfg(feature = "usb-irq")]
+static mut USB_FEATURE_DEBUG: MaybeUninit<DebugHidFeature<'static, UsbBus<USB>, &'static RingBufferLogger<1024>>> = MaybeUninit::uninit();
+#[cfg(feature = "usb-irq")]
+static mut USB_FEATURE_CONFIG: MaybeUninit<ConfigHidFeature<'static, UsbBus<USB>>> = MaybeUninit::uninit();
+#[cfg(feature = "usb-irq")]
+static mut USB_EVENTS: Queue<TUsbPoll, USB_EVENT_QUEUE_LEN> = Queue::new();
+#[cfg(feature = "usb-irq")]
+static mut USB_INTERRUPT_POLL: MaybeUninit<UsbInterruptPoll<'static, TUsbPoll, USB_EVENT_QUEUE_LEN>> = MaybeUninit::uninit();
+
+static BUILD_INFO: BuildInfo = dxkb_core::build_info!();
+
+/// Max power declared to the USB host.
+const USB_MAX_POWER_MA: u16 = 500;
 
 static mut HID_LOGGER: RingBufferLogger<1024> = RingBufferLogger::new(log::Level::Trace, RingBuffer::new());
 
@@ -95,26 +150,129 @@ impl HandleKey for CustomKey {
 
 fn init_split_bus(
     usart: SplitBusUsartPort,
-    dma: SplitBusDmaPeripheral,
+    tx_stream: SplitBusTxDmaStream,
+    rx_stream: SplitBusRxDmaStream,
     txrx_pin: SplitBusTxRxPin,
     clock: DWTClock,
     clocks: &Clocks,
     syscfg: &mut SysCfg,
     exti: &mut EXTI
 ) -> TSplitBus {
-    let dma = StreamsTuple::new(dma);
-    let uart_dma = UartDmaRb::init(
-        HalfDuplexInitializer::new(usart, txrx_pin, dma.6, dma.5, syscfg, exti),
+    // The UID is 96 bits long.
+    let device_id = get_device_id();
+    let (uart_dma, uart_dma_isr) = UartDmaRb::init_split(
+        HalfDuplexInitializer::new(usart, txrx_pin, tx_stream, rx_stream, syscfg, exti)
+            .with_baud_rate(SPLIT_BUS_BAUD_RATE)
+            .with_backoff_seed((device_id ^ (device_id >> 32) ^ (device_id >> 64)) as u32),
         unsafe { &mut SPLIT_BUS_DMA_TX_BUF },
         unsafe { &mut SPLIT_BUS_DMA_RX_BUF },
         &clocks,
     );
 
-    SplitBus::new(uart_dma, clock, get_device_id())
+    unsafe {
+        SPLIT_BUS_ISR.write(uart_dma_isr);
+    }
+
+    let mut split_bus = SplitBus::new(uart_dma, clock, device_id);
+    // Pack the messages queued while the bus was busy in a single frame.
+    split_bus.set_coalescing(true);
+    // Pack the key changes of the scans that don't fit in the queue into the
+    // last queued message, instead of losing them.
+    split_bus.set_tx_overflow_policy(
+        Channel::Priority,
+        TxOverflowPolicy::Coalesce(TLinkMessage::coalesce_key_events),
+    );
+    // Track the clock of the other side, e.g for running the underglow
+    // animations in sync.
+    split_bus.set_time_sync_interval(Some(core::time::Duration::from_secs(1)));
+    split_bus.set_baud_fallback(SPLIT_BUS_FALLBACK_BAUD_RATES).unwrap();
+    split_bus
 }
 
+// The minimum time between two refreshes of the underglow. A refresh only
+// takes a few hundreds of microseconds, so the DMA is free for the split bus
+// almost all the time.
+#[cfg(feature = "underglow")]
+const UNDERGLOW_REFRESH_PERIOD: core::time::Duration = core::time::Duration::from_millis(20);
+
+#[cfg(feature = "underglow")]
+const _: () = assert!(
+    UNDERGLOW_REFRESH_PERIOD.as_micros() >= WS2812_MIN_REFRESH_PERIOD.as_micros(),
+    "The underglow refresh period is too short for the LEDs to latch"
+);
+
+// How long the slave side asserts the wake line for waking up the master side.
+// It must cover the time the master takes for waking up from Stop mode and
+// restarting its clocks.
+#[cfg(feature = "wake-line")]
+const WAKE_LINE_PULSE: core::time::Duration = core::time::Duration::from_millis(5);
+
+// How often the master side wakes up from deep sleep for scanning its own
+// matrix, since its keys cannot wake it up by themselves.
+#[cfg(feature = "wake-line")]
+const DEEP_SLEEP_SCAN_PERIOD: core::time::Duration = core::time::Duration::from_millis(25);
+
+// Time given to the split bus for telling the peer that the link is going down.
+#[cfg(feature = "wake-line")]
+const DEEP_SLEEP_BYE_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10);
+
+// How long to wait for the Bye frame to be sent before rebooting into the
+// bootloader, when the peer or the host asks for it.
+const BOOTLOADER_BYE_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10);
+
+/// Keeps the master side in Stop mode until the host resumes the bus, the
+/// slave side asserts the wake line, or a local key is pressed.
+#[cfg(feature = "wake-line")]
+fn deep_sleep(
+    user: &mut KeyboardContext,
+    wake_line: &mut TWakeLine,
+    scb: &mut cortex_m::peripheral::SCB,
+    exti: &mut EXTI,
+) {
+    let kb = unsafe { KEYBOARD.assume_init_mut() };
+
+    dev_info!("Entering deep sleep");
+    // Tell the slave side that it must use the wake line from now on.
+    kb.split_bus.shutdown(dxkb_split_link::ByeReason::Suspend, DEEP_SLEEP_BYE_TIMEOUT);
+
+    // Discard anything that happened before, including the own wake pulses
+    // observed while being the slave side.
+    low_power::take_usb_wakeup(exti);
+    wake_line.take_wake_request();
+    low_power::enable_rtc_wakeup(DEEP_SLEEP_SCAN_PERIOD);
+    loop {
+        low_power::enter_stop_mode(scb);
+        low_power::take_rtc_wakeup();
+        // The watchdog keeps running in Stop mode.
+        #[cfg(feature = "watchdog")]
+        unsafe { WATCHDOG.assume_init_ref() }.feed();
+
+        if low_power::take_usb_wakeup(exti) {
+            break;
+        }
+
+        if wake_line.take_wake_request() {
+            kb.request_remote_wakeup();
+            break;
+        }
+
+        if kb.poll_suspended(user) {
+            break;
+        }
+    }
+    low_power::disable_rtc_wakeup();
+    dev_info!("Leaving deep sleep");
+}
+
+// The maximum number of captured transitions dumped on each debug request.
+#[cfg(feature = "scan-capture")]
+const SCAN_CAPTURE_CHUNK_LEN: usize = 24;
+
 fn init_key_matrix(rows: KeyMatrixRowPins, cols: KeyMatrixColPins, clocks: &Clocks) -> TKeyMatrix {
+    #[cfg(not(feature = "scan-capture"))]
     let debouncer: TKeyMatrixDebounce = TKeyMatrixDebounce::new();
+    #[cfg(feature = "scan-capture")]
+    let debouncer: TKeyMatrixDebounce = TKeyMatrixDebounce::new(dxkb_peripheral::key_matrix::DebouncerEagerPerKey::new());
     TKeyMatrix::new(
         clocks.sysclk(),
         rows,
@@ -132,6 +290,7 @@ fn main0() -> ! {
     unsafe {
         BootloaderUtil::handle_bootloader_enter_request();
     }
+    let reset_reason = reset::take_reset_reason();
 
     let mut dp = pac::Peripherals::take().unwrap();
     let mut cortex = cortex_m::Peripherals::take().unwrap();
@@ -152,10 +311,18 @@ fn main0() -> ! {
 
     //    itm_logger::init_with_level(log::Level::Trace).unwrap();
     RingBufferLogger::install(unsafe { &HID_LOGGER }).unwrap();
+    BUILD_INFO.log_banner();
     dev_info!("Device startup. Device configuration:");
     dev_info!(" - Current Side: {:?}", type_name::<CurrentSide>());
 
     let clock = DWTClock::new(&clocks, &mut cortex.DCB, &mut cortex.DWT);
+    let mut flash_config = FlashConfig::new(dp.FLASH);
+    let mut reset_counts = ResetCounts::load(&flash_config);
+    if let Err(e) = reset_counts.record(&mut flash_config, reset_reason) {
+        dev_warn!("Couldn't store the reset counts: {:?}", e);
+    }
+    let mut link_stats = PersistentLinkStats::load(&flash_config, &clock);
+    let loop_clock = clock.clone();
 
     let usb = USB {
         usb_global: dp.OTG_FS_GLOBAL,
@@ -170,7 +337,10 @@ fn main0() -> ! {
         USB_ALLOC.write(UsbBus::new(usb, addr_of_mut!(EP_MEMORY).as_mut().unwrap()))
     };
 
+    #[cfg_attr(feature = "usb-irq", allow(unused_mut))]
     let mut usb_feature_debug = DebugHidFeature::new(usb_alloc, unsafe { &HID_LOGGER });
+    #[cfg_attr(feature = "usb-irq", allow(unused_mut))]
+    let mut usb_feature_config = ConfigHidFeature::new(usb_alloc, &BUILD_INFO);
 
     let mut usb_feature_kb = ReportHidKeyboard::alloc(
         usb_alloc,
@@ -183,10 +353,13 @@ fn main0() -> ! {
     #[cfg(feature = "side-right")]
     let product = "STeMCell Lily58L (Right Side)";
 
+    #[cfg_attr(feature = "usb-irq", allow(unused_mut))]
     let mut usb_dev =
         UsbDeviceBuilder::new(usb_alloc, UsbVidPid(0x16c0, 0x27db))
             .usb_rev(UsbRev::Usb200)
             .supports_remote_wakeup(true)
+            .max_power(USB_MAX_POWER_MA as usize)
+            .unwrap()
             .strings(&[StringDescriptors::new(LangID::ES)
                 .serial_number("0")
                 .manufacturer("devcexx")
@@ -223,18 +396,66 @@ fn main0() -> ! {
         &clocks,
     );
 
-    let split_bus = init_split_bus(dp.USART2, dp.DMA1, gpioa.pa2, clock.clone(), &clocks, &mut dp.SYSCFG.constrain(), &mut dp.EXTI);
+    #[cfg(feature = "supply-voltage-sense")]
+    let mut supply_voltage = AdcSupplyVoltage::new(dp.ADC1, gpioa.pa0.into_analog(), 100_000, 100_000);
+
+    let mut syscfg = dp.SYSCFG.constrain();
+    let dma = StreamsTuple::new(dp.DMA1);
+    let mut split_bus = init_split_bus(dp.USART2, dma.6, dma.5, gpioa.pa2, clock.clone(), &clocks, &mut syscfg, &mut dp.EXTI);
+    // Tell the other half, so it finds out when this one is crashing.
+    split_bus.set_reset_reason(reset_reason);
+
+    #[cfg(feature = "underglow")]
+    let mut underglow = TUnderglow::new(
+        dp.SPI2
+            .spi(
+                (stm32f4xx_hal::gpio::NoPin::new(), stm32f4xx_hal::gpio::NoPin::new(), gpiob.pb15),
+                stm32f4xx_hal::hal::spi::MODE_0,
+                3.MHz(),
+                &clocks,
+            )
+            .use_dma()
+            .tx(),
+        dma.4,
+        unsafe { &mut UNDERGLOW_DMA_BUF },
+    );
+    #[cfg(feature = "underglow")]
+    let mut underglow_slicer = DmaTimeSlicer::new(UNDERGLOW_REFRESH_PERIOD);
+
+    #[cfg(feature = "wake-line")]
+    let mut wake_line = TWakeLine::new(gpiob.pb10.into_open_drain_output(), &mut syscfg, &mut dp.EXTI);
+    #[cfg(feature = "wake-line")]
+    low_power::enable_usb_wakeup(&mut dp.EXTI);
+
     let master_tester = PinMasterSense::new(gpioa.pa9.into_pull_down_input());
+    let mut os_remaps = OsRemaps::load(&flash_config);
     unsafe {
-        KEYBOARD.write(TKeyboard::new(
-            clock,
-            usb_feature_kb,
-            layout::LAYOUT,
-            matrix,
-            split_bus,
-            master_tester,
-        ));
+        KEYBOARD.write(
+            TKeyboard::builder()
+                .clock(clock)
+                .hid(usb_feature_kb)
+                .layout(layout::LAYOUT)
+                .matrix(matrix)
+                .split_bus(split_bus)
+                .master_sense(master_tester)
+                .os_remaps(os_remaps)
+                .build(),
+        );
     }
+    // Both sides stream the counters of the soak test to each other, see
+    // dxkb_split_link::soak.
+    #[cfg(feature = "soak-test")]
+    unsafe { KEYBOARD.assume_init_mut() }.set_user_msg_hook(|context, msg| context.soak.on_msg(msg));
+
+    #[cfg(not(feature = "usb-irq"))]
+    let usb_dev = &mut usb_dev;
+    #[cfg(feature = "usb-irq")]
+    let (usb_dev, usb_feature_config, mut usb_events) = unsafe {
+        let (producer, consumer) = USB_EVENTS.split();
+        USB_INTERRUPT_POLL.write(UsbInterruptPoll::new(producer));
+        USB_FEATURE_DEBUG.write(usb_feature_debug);
+        (USB_DEVICE.write(usb_dev), USB_FEATURE_CONFIG.write(usb_feature_config), consumer)
+    };
 
     unsafe {
         // Go!
@@ -242,16 +463,34 @@ fn main0() -> ! {
             NVIC::unmask(SplitBusUsartPort::INTERRUPT);
             NVIC::unmask(SplitBusTxDmaStream::INTERRUPT);
             NVIC::unmask(SplitBusTxRxPin::INTERRUPT);
+            #[cfg(feature = "usb-irq")]
+            NVIC::unmask(pac::Interrupt::OTG_FS);
         });
     }
 
+    let mut usb_power = UsbPowerMonitor::new(USB_MAX_POWER_MA);
+    #[cfg(not(feature = "usb-irq"))]
+    let mut usb_backoff = UsbEnumerationBackoff::new(&loop_clock);
+    let mut indicators = IndicatorBindings::new(&layout::INDICATORS);
     let mut kb_context = KeyboardContext::new();
+    // Started right before the main loop, so that the setup doesn't have to
+    // feed it.
+    #[cfg(feature = "watchdog")]
+    {
+        let watchdog: &'static dyn Watchdog = unsafe { WATCHDOG.write(IndependentWatchdog::start(dp.IWDG, WATCHDOG_TIMEOUT)) };
+        unsafe { KEYBOARD.assume_init_mut() }.split_bus.set_watchdog(Some(watchdog));
+    }
     loop {
         let kb =
             unsafe {
                 KEYBOARD.assume_init_mut()
             };
 
+        // The keyboard owns the HID feature, so the USB interrupt must wait
+        // until the keyboard has been polled.
+        #[cfg(feature = "usb-irq")]
+        let usb_masked = UsbInterruptMask::new(pac::Interrupt::OTG_FS);
+
         if !kb.hid_mut().dirty() {
             // Apparently, for pressing a combination of a modifier key plus a
             // key, we need to do it in phases. First, we need to send an IN
@@ -264,8 +503,120 @@ fn main0() -> ! {
                 kb_context.plus_pending_press = false;
             }
         }
-        (kb.hid_mut(), &mut usb_feature_debug).poll_all(&mut usb_dev);
-        kb.poll(&mut kb_context, &mut usb_dev);
+        // Without a host, e.g on the slave side, polling the device all the
+        // time would only slow the main loop down.
+        #[cfg(not(feature = "usb-irq"))]
+        let usb_poll = if usb_backoff.poll(&loop_clock, usb_dev.state(), usb_dev) {
+            (kb.hid_mut(), &mut usb_feature_debug, &mut usb_feature_config).poll_all(usb_dev)
+        } else {
+            None
+        };
+        #[cfg(feature = "usb-irq")]
+        let usb_poll = usb_events.dequeue();
+        if let Some((_, debug_request, config_request)) = usb_poll {
+            match debug_request {
+                Some(DebugRequest::LinkStats) => {
+                    dev_info!("Link stats (all time): {:?}", link_stats.totals(kb.split_bus.stats()));
+                    dev_info!("Link stats (since boot): {:?}", kb.split_bus.stats());
+                }
+                Some(DebugRequest::Status) => {
+                    dev_info!("Link status: {:?} (peer protocol version: {:?})", kb.split_bus.link_status_detail(), kb.split_bus.peer_protocol_version());
+                    dev_info!("Reset reason: {:?} (all time: {:?})", reset_reason, reset_counts);
+                    dev_info!("Peer reset reason: {:?}", kb.split_bus.peer_reset_reason());
+                    dev_info!("Peer supply voltage: {:?} mV", kb.peer_supply_voltage());
+                    dev_info!("Split bus collisions: {}", kb.split_bus.bus().collisions());
+                    dev_info!("Split bus baud rate: {:?}", kb.split_bus.current_baud());
+                    kb.filter().for_each_stage(&mut |stage| dev_info!("Key filter stage {}", stage));
+                    dev_info!("Poll starvation: {:?}", kb.starvation_stats());
+                    #[cfg(feature = "soak-test")]
+                    dev_info!("Soak test: {:?} (peer: {:?})", kb_context.soak.stats(), kb_context.soak.peer_stats());
+                }
+                #[cfg(feature = "scan-capture")]
+                Some(DebugRequest::ScanCapture) => {
+                    // The capture is dumped in chunks, so that they fit in
+                    // the debug log buffer.
+                    let debouncer = kb.matrix_mut().debouncer_mut();
+                    for _ in 0..SCAN_CAPTURE_CHUNK_LEN {
+                        let Some(transition) = debouncer.poll_capture() else {
+                            break;
+                        };
+                        dev_info!("scan-capture: {}", transition);
+                    }
+                    dev_info!("Scan capture: {} transitions left", debouncer.captured());
+                }
+                #[cfg(not(feature = "scan-capture"))]
+                Some(DebugRequest::ScanCapture) => {
+                    dxkb_common::dev_warn!("Scan capture is not enabled in this build");
+                }
+                Some(DebugRequest::Bootloader) => {
+                    kb.split_bus.shutdown(dxkb_split_link::ByeReason::Bootloader, BOOTLOADER_BYE_TIMEOUT);
+                    BootloaderUtil::enter_bootloader();
+                }
+                Some(DebugRequest::PeerBootloader) => {
+                    if let Err(e) = kb.split_bus.request_peer_bootloader() {
+                        dxkb_common::dev_warn!("Couldn't request the peer to enter the bootloader: {:?}", e);
+                    }
+                }
+                None => {}
+            }
+
+            match config_request {
+                Some(ConfigRequest::Export) => usb_feature_config.export(&flash_config),
+                Some(ConfigRequest::Import) => {
+                    if usb_feature_config.import(&mut flash_config, &()).is_ok() {
+                        // The persisted link stats may have been replaced.
+                        link_stats = PersistentLinkStats::load(&flash_config, &loop_clock);
+                        os_remaps = OsRemaps::load(&flash_config);
+                        kb.set_os_remaps(os_remaps);
+                    }
+                }
+                None => {}
+            }
+        }
+        let activity = kb.poll(&mut kb_context, usb_dev);
+        #[cfg(feature = "soak-test")]
+        kb_context.soak.poll(&loop_clock, &mut kb.split_bus, TLinkMessage::UserMsg);
+        if activity.peer_bootloader_requested {
+            kb.split_bus.shutdown(dxkb_split_link::ByeReason::Bootloader, BOOTLOADER_BYE_TIMEOUT);
+            BootloaderUtil::enter_bootloader();
+        }
+        #[cfg(feature = "wake-line")]
+        {
+            if activity.peer_wake_requested {
+                wake_line.pulse(clocks.sysclk(), WAKE_LINE_PULSE);
+            }
+
+            if kb.is_master()
+                && usb_dev.state() == usb_device::device::UsbDeviceState::Suspend
+                && activity.is_idle()
+                && kb.hid_mut().total_pressed_keys() == 0
+            {
+                deep_sleep(&mut kb_context, &mut wake_line, &mut cortex.SCB, &mut dp.EXTI);
+            }
+        }
+        #[cfg(not(feature = "underglow"))]
+        usb_power.poll(&loop_clock, kb.is_master(), usb_dev.state(), &mut ());
+        #[cfg(feature = "underglow")]
+        usb_power.poll(&loop_clock, kb.is_master(), usb_dev.state(), &mut underglow);
+        let leds = kb.host_leds();
+        #[cfg(feature = "usb-irq")]
+        drop(usb_masked);
+
+        #[cfg(not(feature = "underglow"))]
+        indicators.poll(leds, kb.current_layer(), kb.is_gaming_mode(), kb.is_presence_mode(), &mut ());
+        #[cfg(feature = "underglow")]
+        {
+            indicators.poll(leds, kb.current_layer(), kb.is_gaming_mode(), kb.is_presence_mode(), &mut underglow);
+            let link_idle = !kb.split_bus.bus().is_tx_busy() && kb.split_bus.user_tx_queue_len() == 0;
+            underglow.poll(&loop_clock, &mut underglow_slicer, link_idle);
+        }
+        #[cfg(feature = "supply-voltage-sense")]
+        kb.poll_supply_voltage(&mut supply_voltage);
+        link_stats.poll(&mut flash_config, &loop_clock, kb.split_bus.stats());
+        if kb.os_remaps() != os_remaps {
+            os_remaps = kb.os_remaps();
+            os_remaps.store(&mut flash_config);
+        }
     }
 }
 
@@ -274,32 +625,35 @@ fn main0() -> ! {
 #[interrupt]
 fn USART2() {
     unsafe {
-        KEYBOARD
-            .assume_init_mut()
-            .split_bus
-            .bus_mut()
-            .handle_usart_intr();
+        SPLIT_BUS_ISR.assume_init_mut().handle_usart_intr();
     }
 }
 
 #[interrupt]
 fn DMA1_STREAM6() {
  unsafe {
-     KEYBOARD
-         .assume_init_mut()
-         .split_bus
-         .bus_mut()
-         .handle_dma_intr();
+     SPLIT_BUS_ISR.assume_init_mut().handle_dma_intr();
  }
 }
 
 #[interrupt]
 fn EXTI2() {
  unsafe {
-     KEYBOARD
-         .assume_init_mut()
-         .split_bus
-         .bus_mut()
-         .handle_exti_intr();
+     SPLIT_BUS_ISR.assume_init_mut().handle_exti_intr();
  }
 }
+
+#[cfg(feature = "usb-irq")]
+#[interrupt]
+fn OTG_FS() {
+    unsafe {
+        USB_INTERRUPT_POLL.assume_init_mut().on_interrupt(
+            (
+                KEYBOARD.assume_init_mut().hid_mut(),
+                USB_FEATURE_DEBUG.assume_init_mut(),
+                USB_FEATURE_CONFIG.assume_init_mut(),
+            ),
+            USB_DEVICE.assume_init_mut(),
+        );
+    }
+}
@@ -23,7 +23,7 @@ use core::any::type_name;
 use core::mem::MaybeUninit;
 use core::ptr::addr_of_mut;
 use dxkb_common::util::RingBuffer;
-use dxkb_core::debug::{DebugHidFeature, NopDebugRead};
+use dxkb_core::debug::{DebugHidFeature, DebugRequest, NopDebugRead};
 use dxkb_core::hid::HidKeyboard;
 
 use dxkb_common::bus::{BusPollError, BusTransferError, NullBus};
@@ -49,7 +49,7 @@ use panic_itm as _;
 
 use cortex_m_rt::entry;
 use dxkb_peripheral::uart_dma_rb::{DmaRingBuffer, FullDuplex, FullDuplexInitializer, HalfDuplex, HalfDuplexInitializer, UartDmaRb};
-use dxkb_split_link::{SplitBus, TestingTimings};
+use dxkb_split_link::{ByeReason, SplitBus, TestingTimings};
 use dxkb_core::usb::UsbFeatureSet;
 use ringbuffer::ConstGenericRingBuffer;
 use stm32f4xx_hal::dma::{Stream5, Stream7};
@@ -149,6 +149,10 @@ type KeyboardT<Hid> = SplitKeyboard<
     CustomKeyContext,
 >;
 
+// How long to wait for the Bye frame to be sent before rebooting into the
+// bootloader, when the host asks for it.
+const BOOTLOADER_BYE_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10);
+
 static mut EP_MEMORY: [u32; 1024] = [0; 1024];
 static mut SPLIT_BUS_DMA_RX_BUF: DmaRingBuffer<256, 128> = DmaRingBuffer::new();
 static mut SPLIT_BUS_DMA_TX_BUF: [u8; 256] = [0u8; 256];
@@ -323,14 +327,16 @@ fn main0() -> ! {
     let mut split_bus = init_split_bus(dp.USART1, dp.DMA2, gpiob.pb6, gpiob.pb7, clock.clone(), &clocks);
     let master_tester = make_usb_master_checker(gpioa.pa9.into_input());
     unsafe {
-        KEYBOARD.write(KeyboardT::new(
-            clock.clone(),
-            usb_feature_kb,
-            build_keyboard_layout(),
-            matrix,
-            split_bus,
-            master_tester,
-        ));
+        KEYBOARD.write(
+            KeyboardT::builder()
+                .clock(clock.clone())
+                .hid(usb_feature_kb)
+                .layout(build_keyboard_layout())
+                .matrix(matrix)
+                .split_bus(split_bus)
+                .master_sense(master_tester)
+                .build(),
+        );
     }
 
     unsafe {
@@ -360,7 +366,10 @@ fn main0() -> ! {
             }
         }
 
-        (kb.hid_mut(), &mut usb_feature_debug).poll_all(&mut usb_dev);
+        if let Some((_, Some(DebugRequest::Bootloader))) = (kb.hid_mut(), &mut usb_feature_debug).poll_all(&mut usb_dev) {
+            kb.split_bus.shutdown(ByeReason::Bootloader, BOOTLOADER_BYE_TIMEOUT);
+            BootloaderUtil::enter_bootloader();
+        }
         kb.poll(&mut key_context, &mut usb_dev);
     }
 }
@@ -0,0 +1,71 @@
+//! Attention lines for the split bus: a pair of GPIOs wired crosswise between
+//! both halves, through which each side tells the other that it has frames on
+//! their way (see `SplitBus::with_attention_line`).
+//!
+//! Like the wakeup sources of [`low_power`](crate::low_power), the line of the
+//! peer is configured as an EXTI interrupt that is left masked in the NVIC. It
+//! is never handled, its pending bit just latches the assertions of the peer
+//! until the split bus is polled, and it wakes up the core through the
+//! SEVONPEND event, e.g from [`low_power::sleep_until_event`](crate::low_power::sleep_until_event).
+
+use dxkb_common::bus::AttentionLine;
+use stm32f4xx_hal::{
+    gpio::{Edge, ExtiPin, Input, Output, Pin, PushPull, Pull},
+    pac::{EXTI, Interrupt},
+    syscfg::SysCfg,
+};
+
+use crate::InterruptReceiver;
+
+/// An attention line made of an output pin, driven by us, and an input pin,
+/// driven by the peer. Both lines are active high.
+pub struct GpioAttentionLine<const OP: char, const ON: u8, const IP: char, const IN: u8> {
+    ours: Pin<OP, ON, Output<PushPull>>,
+    peer: Pin<IP, IN, Input>,
+}
+
+impl<const OP: char, const ON: u8, const IP: char, const IN: u8> GpioAttentionLine<OP, ON, IP, IN> {
+    /// Sets up our line, released, and configures a rising edge EXTI
+    /// interrupt on the line of the peer, pulled down so that it reads as
+    /// released while the peer is not powered.
+    pub fn new(
+        mut ours: Pin<OP, ON, Output<PushPull>>,
+        mut peer: Pin<IP, IN, Input>,
+        syscfg: &mut SysCfg,
+        exti: &mut EXTI,
+    ) -> Self {
+        ours.set_low();
+        peer.set_internal_resistor(Pull::Down);
+        peer.make_interrupt_source(syscfg);
+        peer.trigger_on_edge(exti, Edge::Rising);
+        peer.enable_interrupt(exti);
+        peer.clear_interrupt_pending_bit();
+
+        Self { ours, peer }
+    }
+}
+
+impl<const OP: char, const ON: u8, const IP: char, const IN: u8> AttentionLine for GpioAttentionLine<OP, ON, IP, IN> {
+    fn set_pending(&mut self, pending: bool) {
+        if pending {
+            self.ours.set_high();
+        } else {
+            self.ours.set_low();
+        }
+    }
+
+    fn take_peer_attention(&mut self) -> bool {
+        let latched = self.peer.check_interrupt();
+        self.peer.clear_interrupt_pending_bit();
+        latched || self.peer.is_high()
+    }
+}
+
+/// The EXTI interrupt of the line of the peer, which may be shared with other
+/// pins.
+impl<const OP: char, const ON: u8, const IP: char, const IN: u8> InterruptReceiver for GpioAttentionLine<OP, ON, IP, IN>
+where
+    Pin<IP, IN, Input>: InterruptReceiver,
+{
+    const INTERRUPT: Interrupt = <Pin<IP, IN, Input> as InterruptReceiver>::INTERRUPT;
+}
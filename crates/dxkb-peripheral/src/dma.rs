@@ -1,28 +1,128 @@
+//! Bookkeeping of the DMA streams used by a board.
+//!
+//! The streams are assigned to each of their users at compile time, in the
+//! board config, which is expected to check that no stream is given to more
+//! than one user with [`assert_distinct_streams`]:
+//!
+//! ```ignore
+//! const _: () = assert_distinct_streams(&[
+//!     <SplitBusTxDmaStream as DmaStreamIdentity>::ID,
+//!     <SplitBusRxDmaStream as DmaStreamIdentity>::ID,
+//! ]);
+//! ```
+//!
+//! Streams of the same controller still compete for it, which is arbitrated
+//! by their priority. Users that move a lot of data but are not time
+//! sensitive, like LED strips, can also be time sliced with a
+//! [`DmaTimeSlicer`], so that they stay away from the controller while the
+//! users that are time sensitive, like the split link, are busy.
+
+use core::time::Duration;
+
+use dxkb_common::time::{Clock, TimeDiff};
 use stm32f4xx_hal::{dma::StreamX, pac::{Interrupt, DMA1, DMA2}};
 use crate::InterruptReceiver;
 
-macro_rules! dma_interrupt_impl {
-    ($dma:ident, $stream:literal, $intr:ident) => {
+/// Identifies a DMA stream of the MCU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaStreamId {
+    /// The number of the DMA controller, starting from 1.
+    pub dma: u8,
+    pub stream: u8,
+}
+
+pub trait DmaStreamIdentity {
+    const ID: DmaStreamId;
+}
+
+/// Fails if any stream appears more than once in `streams`. Meant to be
+/// evaluated at compile time.
+pub const fn assert_distinct_streams(streams: &[DmaStreamId]) {
+    let mut i = 0;
+    while i < streams.len() {
+        let mut j = i + 1;
+        while j < streams.len() {
+            assert!(
+                streams[i].dma != streams[j].dma || streams[i].stream != streams[j].stream,
+                "A DMA stream cannot be assigned to more than one user"
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+macro_rules! dma_stream_impl {
+    ($dma:ident = $dma_num:literal, $stream:literal, $intr:ident) => {
         impl InterruptReceiver for StreamX<$dma, $stream> where {
             const INTERRUPT: Interrupt = Interrupt::$intr;
         }
+
+        impl DmaStreamIdentity for StreamX<$dma, $stream> {
+            const ID: DmaStreamId = DmaStreamId {
+                dma: $dma_num,
+                stream: $stream,
+            };
+        }
     };
 }
 
-dma_interrupt_impl!(DMA1, 0, DMA1_STREAM0);
-dma_interrupt_impl!(DMA1, 1, DMA1_STREAM1);
-dma_interrupt_impl!(DMA1, 2, DMA1_STREAM2);
-dma_interrupt_impl!(DMA1, 3, DMA1_STREAM3);
-dma_interrupt_impl!(DMA1, 4, DMA1_STREAM4);
-dma_interrupt_impl!(DMA1, 5, DMA1_STREAM5);
-dma_interrupt_impl!(DMA1, 6, DMA1_STREAM6);
-dma_interrupt_impl!(DMA1, 7, DMA1_STREAM7);
-
-dma_interrupt_impl!(DMA2, 0, DMA2_STREAM0);
-dma_interrupt_impl!(DMA2, 1, DMA2_STREAM1);
-dma_interrupt_impl!(DMA2, 2, DMA2_STREAM2);
-dma_interrupt_impl!(DMA2, 3, DMA2_STREAM3);
-dma_interrupt_impl!(DMA2, 4, DMA2_STREAM4);
-dma_interrupt_impl!(DMA2, 5, DMA2_STREAM5);
-dma_interrupt_impl!(DMA2, 6, DMA2_STREAM6);
-dma_interrupt_impl!(DMA2, 7, DMA2_STREAM7);
+dma_stream_impl!(DMA1 = 1, 0, DMA1_STREAM0);
+dma_stream_impl!(DMA1 = 1, 1, DMA1_STREAM1);
+dma_stream_impl!(DMA1 = 1, 2, DMA1_STREAM2);
+dma_stream_impl!(DMA1 = 1, 3, DMA1_STREAM3);
+dma_stream_impl!(DMA1 = 1, 4, DMA1_STREAM4);
+dma_stream_impl!(DMA1 = 1, 5, DMA1_STREAM5);
+dma_stream_impl!(DMA1 = 1, 6, DMA1_STREAM6);
+dma_stream_impl!(DMA1 = 1, 7, DMA1_STREAM7);
+
+dma_stream_impl!(DMA2 = 2, 0, DMA2_STREAM0);
+dma_stream_impl!(DMA2 = 2, 1, DMA2_STREAM1);
+dma_stream_impl!(DMA2 = 2, 2, DMA2_STREAM2);
+dma_stream_impl!(DMA2 = 2, 3, DMA2_STREAM3);
+dma_stream_impl!(DMA2 = 2, 4, DMA2_STREAM4);
+dma_stream_impl!(DMA2 = 2, 5, DMA2_STREAM5);
+dma_stream_impl!(DMA2 = 2, 6, DMA2_STREAM6);
+dma_stream_impl!(DMA2 = 2, 7, DMA2_STREAM7);
+
+/// Grants slices of DMA time to a user that must never get in the way of the
+/// others. A slice is only granted while the other users are idle, and no
+/// sooner than a minimum period after the previous one, so the user cannot
+/// take more than a bounded share of the controller time. The user is
+/// expected to do a single, short transfer on each slice.
+pub struct DmaTimeSlicer<C: Clock> {
+    min_period: Duration,
+    last_slice: Option<C::TInstant>,
+}
+
+impl<C: Clock> DmaTimeSlicer<C> {
+    pub const fn new(min_period: Duration) -> Self {
+        Self {
+            min_period,
+            last_slice: None,
+        }
+    }
+
+    /// Returns whether a new slice can start now, given whether the users
+    /// that have priority over this one are idle. If so, the slice is
+    /// considered started.
+    pub fn try_begin_slice(&mut self, clock: &C, others_idle: bool) -> bool {
+        if !others_idle {
+            return false;
+        }
+
+        let now = clock.current_instant();
+        if let Some(last_slice) = self.last_slice {
+            // Slices may be far apart, so a last slice that looks like it is
+            // in the future is just one that is beyond the range of the clock.
+            if let TimeDiff::Forward(elapsed) = clock.diff(now, last_slice) {
+                if elapsed < self.min_period {
+                    return false;
+                }
+            }
+        }
+
+        self.last_slice = Some(now);
+        true
+    }
+}
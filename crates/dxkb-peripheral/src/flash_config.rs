@@ -0,0 +1,252 @@
+//! A small persistent key-value store living in the last sector of the flash
+//! (sector 7, 128kB), which is excluded from the firmware image in memory.x.
+//!
+//! Values are appended to the sector as records, so updating a value doesn't
+//! require erasing the sector each time. The latest valid record of a key is
+//! its current value. Once the sector is full, the live values are compacted:
+//! they are copied to RAM, the sector is erased and they are written back.
+//!
+//! Each record has the following layout:
+//!
+//! ```text
+//! +-----------+---------+-------------+----------------+-------------+
+//! | Magic(u8) | Key(u8) | Len(u16 LE) | Value(Len * u8)| CRC-8(u8)   |
+//! +-----------+---------+-------------+----------------+-------------+
+//! ```
+//!
+//! The CRC covers the key, the length and the value. Erased flash reads as
+//! 0xFF, so the first byte that is not a record magic marks the end of the log.
+
+use crc::Table;
+use dxkb_common::{dev_info, dev_warn};
+use stm32f4xx_hal::{flash::{self, FlashExt}, pac::FLASH};
+
+/// Number of the flash sector reserved for the config area.
+pub const FLASH_CONFIG_SECTOR: u8 = 7;
+
+/// Offset of the config area from the start of the flash.
+pub const FLASH_CONFIG_OFFSET: usize = 0x6_0000;
+
+/// Size of the config area.
+pub const FLASH_CONFIG_LEN: usize = 128 * 1024;
+
+/// Maximum length of a single value.
+pub const FLASH_CONFIG_MAX_VALUE_LEN: usize = 256;
+
+/// Maximum amount of bytes, including record headers, that all the live values
+/// can take in total. This is the size of the RAM buffer used for compacting
+/// the config area.
+pub const FLASH_CONFIG_MAX_LIVE_LEN: usize = 1024;
+
+const RECORD_MAGIC: u8 = 0xA5;
+const RECORD_HEADER_LEN: usize = 4;
+const RECORD_TRAILER_LEN: usize = 1;
+
+const FLASH_CONFIG_CRC: crc::Crc<u8, Table<1>> = crc::Crc::<u8, Table<1>>::new(&crc::CRC_8_SMBUS);
+
+#[derive(Debug, Clone, Copy)]
+pub enum FlashConfigError {
+    /// The value is longer than [`FLASH_CONFIG_MAX_VALUE_LEN`].
+    ValueTooLong,
+    /// The live values don't fit in the compaction buffer anymore.
+    Full,
+    /// The flash controller reported an error while erasing or programming.
+    Flash(flash::Error),
+}
+
+impl From<flash::Error> for FlashConfigError {
+    fn from(value: flash::Error) -> Self {
+        FlashConfigError::Flash(value)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Record<'a> {
+    key: u8,
+    value: &'a [u8],
+    valid: bool,
+}
+
+impl<'a> Record<'a> {
+    const fn encoded_len(value_len: usize) -> usize {
+        RECORD_HEADER_LEN + value_len + RECORD_TRAILER_LEN
+    }
+
+    fn crc8(key: u8, value: &[u8]) -> u8 {
+        let mut digest = FLASH_CONFIG_CRC.digest();
+        digest.update(&[key]);
+        digest.update(&(value.len() as u16).to_le_bytes());
+        digest.update(value);
+        digest.finalize()
+    }
+
+    /// Parses the record at the start of the buffer. Returns None if there's no
+    /// record there, which is the end of the log.
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < RECORD_HEADER_LEN || buf[0] != RECORD_MAGIC {
+            return None;
+        }
+
+        let key = buf[1];
+        let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if len > FLASH_CONFIG_MAX_VALUE_LEN || buf.len() < Self::encoded_len(len) {
+            return None;
+        }
+
+        let value = &buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+        Some(Record {
+            key,
+            value,
+            valid: buf[RECORD_HEADER_LEN + len] == Self::crc8(key, value),
+        })
+    }
+
+    fn encode(key: u8, value: &[u8], out: &mut [u8]) -> usize {
+        let len = value.len() as u16;
+        out[0] = RECORD_MAGIC;
+        out[1] = key;
+        out[2..4].copy_from_slice(&len.to_le_bytes());
+        out[RECORD_HEADER_LEN..RECORD_HEADER_LEN + value.len()].copy_from_slice(value);
+        out[RECORD_HEADER_LEN + value.len()] = Self::crc8(key, value);
+        Self::encoded_len(value.len())
+    }
+}
+
+struct RecordIter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for RecordIter<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = Record::parse(&self.buf[self.pos..])?;
+        self.pos += Record::encoded_len(record.value.len());
+        Some(record)
+    }
+}
+
+pub struct FlashConfig {
+    flash: FLASH,
+    write_pos: usize,
+}
+
+impl FlashConfig {
+    pub fn new(flash: FLASH) -> Self {
+        let mut config = FlashConfig { flash, write_pos: 0 };
+        let mut records = config.records();
+        for _ in records.by_ref() {}
+        let end = records.pos;
+
+        config.write_pos = if config.area()[end..].iter().all(|b| *b == 0xFF) {
+            end
+        } else {
+            // Something that is not a record nor erased flash, probably an
+            // interrupted write. Don't write anything after it; the next write
+            // will compact the area and get rid of it.
+            dev_warn!("Flash config area is corrupted at offset {}", end);
+            FLASH_CONFIG_LEN
+        };
+        config
+    }
+
+    fn area(&self) -> &[u8] {
+        &self.flash.read()[FLASH_CONFIG_OFFSET..FLASH_CONFIG_OFFSET + FLASH_CONFIG_LEN]
+    }
+
+    fn records(&self) -> RecordIter<'_> {
+        RecordIter { buf: self.area(), pos: 0 }
+    }
+
+    fn latest(&self, key: u8) -> Option<&[u8]> {
+        self.records()
+            .filter(|r| r.valid && r.key == key)
+            .last()
+            .map(|r| r.value)
+    }
+
+    /// Copies the current value of the given key into the buffer, returning
+    /// its length, or None if the key has never been written. The value is
+    /// truncated if the buffer is too small.
+    pub fn read(&self, key: u8, buf: &mut [u8]) -> Option<usize> {
+        self.latest(key).map(|value| {
+            let len = value.len().min(buf.len());
+            buf[..len].copy_from_slice(&value[..len]);
+            len
+        })
+    }
+
+    /// Calls the given function with the current value of every key that has
+    /// been written, in ascending key order.
+    pub fn for_each(&self, mut f: impl FnMut(u8, &[u8])) {
+        for key in 0..=u8::MAX {
+            if let Some(value) = self.latest(key) {
+                f(key, value);
+            }
+        }
+    }
+
+    /// Stores a new value for the given key. Nothing is written if the current
+    /// value is already the same, for saving flash erase cycles.
+    pub fn write(&mut self, key: u8, value: &[u8]) -> Result<(), FlashConfigError> {
+        if value.len() > FLASH_CONFIG_MAX_VALUE_LEN {
+            return Err(FlashConfigError::ValueTooLong);
+        }
+
+        if self.latest(key) == Some(value) {
+            return Ok(());
+        }
+
+        if self.write_pos + Record::encoded_len(value.len()) > FLASH_CONFIG_LEN {
+            self.compact(key, value)
+        } else {
+            let mut buf = [0u8; Record::encoded_len(FLASH_CONFIG_MAX_VALUE_LEN)];
+            let len = Record::encode(key, value, &mut buf);
+            self.flash
+                .unlocked()
+                .program(FLASH_CONFIG_OFFSET + self.write_pos, buf[..len].iter())?;
+            self.write_pos += len;
+            Ok(())
+        }
+    }
+
+    /// Erases the whole config area.
+    pub fn clear(&mut self) -> Result<(), FlashConfigError> {
+        self.flash.unlocked().erase(FLASH_CONFIG_SECTOR)?;
+        self.write_pos = 0;
+        Ok(())
+    }
+
+    /// Rewrites the area from scratch with just the latest value of each key,
+    /// replacing the value of `new_key` with `new_value`.
+    fn compact(&mut self, new_key: u8, new_value: &[u8]) -> Result<(), FlashConfigError> {
+        let mut live = [0u8; FLASH_CONFIG_MAX_LIVE_LEN];
+        let mut live_len = 0;
+        let mut full = false;
+
+        self.for_each(|key, value| {
+            if key == new_key || full {
+                return;
+            }
+            if live_len + Record::encoded_len(value.len()) > live.len() {
+                full = true;
+            } else {
+                live_len += Record::encode(key, value, &mut live[live_len..]);
+            }
+        });
+
+        if full || live_len + Record::encoded_len(new_value.len()) > live.len() {
+            return Err(FlashConfigError::Full);
+        }
+        live_len += Record::encode(new_key, new_value, &mut live[live_len..]);
+
+        dev_info!("Compacting flash config area ({} live bytes)", live_len);
+        let mut flash = self.flash.unlocked();
+        flash.erase(FLASH_CONFIG_SECTOR)?;
+        self.write_pos = 0;
+        flash.program(FLASH_CONFIG_OFFSET, live[..live_len].iter())?;
+        self.write_pos = live_len;
+        Ok(())
+    }
+}
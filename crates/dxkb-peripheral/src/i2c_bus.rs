@@ -0,0 +1,341 @@
+//! A [`BusRead`] + [`BusWrite`] implementation over the I2C peripheral, for
+//! the split keyboards whose halves are wired through I2C instead of a serial
+//! line.
+//!
+//! Unlike a regular I2C bus, there isn't a fixed controller: both sides are
+//! addressed as targets with their own address while they're idle, receiving
+//! every frame the peer writes to them, and become the controller just for
+//! writing a frame to the peer. The end of each frame is marked by the stop
+//! condition. If both sides start writing at the same time, the hardware
+//! arbitration lets one of them go on, and the other one starts again once
+//! the bus is free.
+//!
+//! The bytes are moved by the interrupt handlers, which live in a separate
+//! handle ([`I2cBusIsr`]) so that they don't need to access the keyboard. Both
+//! handles share the state in a [`I2cBusShared`], that must live in a static.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{free, Mutex};
+use dxkb_common::{
+    bus::{BusPollError, BusRead, BusTransferError, BusWrite},
+    dev_warn,
+    util::RingBuffer,
+};
+use stm32f4xx_hal::{
+    i2c::{I2c, Instance, Mode},
+    pac::{self, Interrupt},
+    rcc::Clocks,
+};
+
+/// The interrupts of an I2C peripheral.
+pub trait I2cInterrupts {
+    /// The interrupt of the events of the transfer, which must call
+    /// [`I2cBusIsr::handle_event_intr`].
+    const EVENT_INTERRUPT: Interrupt;
+    /// The interrupt of the errors of the transfer, which must call
+    /// [`I2cBusIsr::handle_error_intr`].
+    const ERROR_INTERRUPT: Interrupt;
+}
+
+macro_rules! i2c_interrupts_impl {
+    ($i2c:ident, $ev:ident, $er:ident) => {
+        impl I2cInterrupts for pac::$i2c {
+            const EVENT_INTERRUPT: Interrupt = Interrupt::$ev;
+            const ERROR_INTERRUPT: Interrupt = Interrupt::$er;
+        }
+    };
+}
+
+i2c_interrupts_impl!(I2C1, I2C1_EV, I2C1_ER);
+i2c_interrupts_impl!(I2C2, I2C2_EV, I2C2_ER);
+#[cfg(feature = "stm32f411")]
+i2c_interrupts_impl!(I2C3, I2C3_EV, I2C3_ER);
+
+struct I2cFrame<const MTU: usize> {
+    len: usize,
+    data: [u8; MTU],
+}
+
+impl<const MTU: usize> I2cFrame<MTU> {
+    const fn new() -> Self {
+        Self { len: 0, data: [0; MTU] }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    /// The start condition has been requested, and the address of the peer
+    /// will be sent once it is generated.
+    Starting,
+    /// The peer has acknowledged its address, and the frame is being sent.
+    Sending,
+}
+
+struct I2cBusState<const MTU: usize, const FRAMES: usize> {
+    rx_frames: RingBuffer<I2cFrame<MTU>, FRAMES>,
+    rx_current: I2cFrame<MTU>,
+    /// Whether the frame being received didn't fit in `MTU` bytes, in which
+    /// case it is dropped.
+    rx_overflow: bool,
+    tx: I2cFrame<MTU>,
+    tx_pos: usize,
+    tx_state: TxState,
+}
+
+/// The state shared by the handles of an [`I2cBus`]: the frames received that
+/// haven't been read yet, up to `FRAMES` of at most `MTU` bytes each, and the
+/// frame being sent.
+pub struct I2cBusShared<const MTU: usize, const FRAMES: usize> {
+    state: Mutex<RefCell<I2cBusState<MTU, FRAMES>>>,
+}
+
+impl<const MTU: usize, const FRAMES: usize> I2cBusShared<MTU, FRAMES> {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(I2cBusState {
+                rx_frames: RingBuffer::new(),
+                rx_current: I2cFrame::new(),
+                rx_overflow: false,
+                tx: I2cFrame::new(),
+                tx_pos: 0,
+                tx_state: TxState::Idle,
+            })),
+        }
+    }
+}
+
+/// The main loop handle of the bus.
+pub struct I2cBus<I2C: Instance, const MTU: usize, const FRAMES: usize> {
+    i2c: I2C,
+    _pins: (I2C::Scl, I2C::Sda),
+    shared: &'static I2cBusShared<MTU, FRAMES>,
+}
+
+/// The interrupt handlers of an [`I2cBus`].
+pub struct I2cBusIsr<I2C: Instance, const MTU: usize, const FRAMES: usize> {
+    i2c: *const I2C::RB,
+    peer_address: u8,
+    shared: &'static I2cBusShared<MTU, FRAMES>,
+}
+
+// The registers are only accessed from the interrupt handlers.
+unsafe impl<I2C: Instance, const MTU: usize, const FRAMES: usize> Send for I2cBusIsr<I2C, MTU, FRAMES> {}
+
+impl<I2C: Instance, const MTU: usize, const FRAMES: usize> I2cBus<I2C, MTU, FRAMES> {
+    /// Sets up the I2C peripheral with the given speed, listening on
+    /// `own_address` and sending the frames to `peer_address`, both of 7
+    /// bits. Each side must use the address of the other one as the peer
+    /// address. The interrupts of the peripheral (see [`I2cInterrupts`])
+    /// must be unmasked afterwards.
+    pub fn init(
+        i2c: I2C,
+        pins: (impl Into<I2C::Scl>, impl Into<I2C::Sda>),
+        mode: impl Into<Mode>,
+        own_address: u8,
+        peer_address: u8,
+        shared: &'static I2cBusShared<MTU, FRAMES>,
+        clocks: &Clocks,
+    ) -> (Self, I2cBusIsr<I2C, MTU, FRAMES>) {
+        assert!(own_address < 0x80 && peer_address < 0x80, "I2C addresses must be of 7 bits");
+        assert!(own_address != peer_address, "Both sides must have different I2C addresses");
+
+        // The HAL sets the pins, the clocks and the speed up.
+        let (i2c, pins) = I2c::new(i2c, pins, mode, clocks).release();
+
+        // The bit 14 of OAR1 must be always kept at 1.
+        i2c.oar1().write(|w| unsafe { w.bits((1 << 14) | ((own_address as u32) << 1)) });
+        i2c.cr1().modify(|_, w| w.ack().set_bit());
+        i2c.cr2().modify(|_, w| w.itevten().set_bit().itbufen().set_bit().iterren().set_bit());
+
+        let isr = I2cBusIsr {
+            i2c: I2C::ptr(),
+            peer_address,
+            shared,
+        };
+
+        (
+            Self {
+                i2c,
+                _pins: pins,
+                shared,
+            },
+            isr,
+        )
+    }
+}
+
+impl<I2C: Instance, const MTU: usize, const FRAMES: usize> BusWrite for I2cBus<I2C, MTU, FRAMES> {
+    /// Starts writing the buffer to the peer. Returns
+    /// [`BusTransferError::WouldBlock`] if the previous frame is still being
+    /// sent.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is larger than `MTU`.
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        assert!(buf.len() <= MTU, "Frame is larger than the I2C bus MTU");
+        free(|cs| {
+            let mut state = self.shared.state.borrow(cs).borrow_mut();
+            if state.tx_state != TxState::Idle {
+                return Err(BusTransferError::WouldBlock);
+            }
+
+            state.tx.data[..buf.len()].copy_from_slice(buf);
+            state.tx.len = buf.len();
+            state.tx_pos = 0;
+            state.tx_state = TxState::Starting;
+            // If the bus is busy, the start condition is generated once it is
+            // free.
+            self.i2c.cr1().modify(|_, w| w.start().set_bit());
+            Ok(())
+        })
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        free(|cs| self.shared.state.borrow(cs).borrow().tx_state != TxState::Idle)
+    }
+}
+
+impl<I2C: Instance, const MTU: usize, const FRAMES: usize> BusRead for I2cBus<I2C, MTU, FRAMES> {
+    /// Reads the oldest frame received. If it doesn't fit in the buffer, the
+    /// frame is discarded and [`BusPollError::BufferOverflow`] is returned.
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        free(|cs| {
+            let mut state = self.shared.state.borrow(cs).borrow_mut();
+            let frame = state.rx_frames.peek_first().ok_or(BusPollError::WouldBlock)?;
+            let len = frame.len;
+            let res = if len > buf.len() {
+                Err(BusPollError::BufferOverflow)
+            } else {
+                buf[..len].copy_from_slice(&frame.data[..len]);
+                Ok(len as u16)
+            };
+            state.rx_frames.drop_first(1);
+            res
+        })
+    }
+}
+
+impl<I2C: Instance, const MTU: usize, const FRAMES: usize> I2cBusIsr<I2C, MTU, FRAMES> {
+    #[inline(always)]
+    fn regs(&self) -> &I2C::RB {
+        unsafe { &*self.i2c }
+    }
+
+    pub fn handle_event_intr(&mut self) {
+        let i2c = self.regs();
+        free(|cs| {
+            let mut state = self.shared.state.borrow(cs).borrow_mut();
+            let sr1 = i2c.sr1().read();
+
+            if sr1.sb().bit_is_set() {
+                // Start generated: address the peer for writing.
+                i2c.dr().write(|w| w.dr().set(self.peer_address << 1));
+                return;
+            }
+
+            if sr1.addr().bit_is_set() {
+                // Reading SR2 after SR1 clears the flag.
+                let sr2 = i2c.sr2().read();
+                if sr2.msl().bit_is_set() {
+                    state.tx_state = TxState::Sending;
+                } else {
+                    state.rx_current.len = 0;
+                    state.rx_overflow = false;
+                }
+            }
+
+            if sr1.rx_ne().bit_is_set() {
+                let byte = i2c.dr().read().dr().bits();
+                let len = state.rx_current.len;
+                if len < MTU {
+                    state.rx_current.data[len] = byte;
+                    state.rx_current.len += 1;
+                } else {
+                    state.rx_overflow = true;
+                }
+            }
+
+            if sr1.tx_e().bit_is_set() {
+                if state.tx_state == TxState::Sending {
+                    if state.tx_pos < state.tx.len {
+                        let byte = state.tx.data[state.tx_pos];
+                        state.tx_pos += 1;
+                        i2c.dr().write(|w| w.dr().set(byte));
+                    } else if sr1.btf().bit_is_set() {
+                        i2c.cr1().modify(|_, w| w.stop().set_bit());
+                        i2c.cr2().modify(|_, w| w.itbufen().set_bit());
+                        state.tx_state = TxState::Idle;
+                    } else {
+                        // Nothing else to write until the last byte is out,
+                        // which is signaled by BTF, without the buffer
+                        // interrupt.
+                        i2c.cr2().modify(|_, w| w.itbufen().clear_bit());
+                    }
+                } else if !i2c.sr2().read().msl().bit_is_set() {
+                    // The peer never reads from us.
+                    i2c.dr().write(|w| w.dr().set(0xFF));
+                }
+            }
+
+            if sr1.stopf().bit_is_set() {
+                // Writing CR1 after reading SR1 clears the flag.
+                i2c.cr1().modify(|_, w| w);
+                let len = state.rx_current.len;
+                if state.rx_overflow {
+                    dev_warn!("Dropping I2C frame larger than the MTU");
+                } else if len > 0 {
+                    let mut frame = I2cFrame::new();
+                    frame.data[..len].copy_from_slice(&state.rx_current.data[..len]);
+                    frame.len = len;
+                    state.rx_frames.push(frame);
+                }
+                state.rx_current.len = 0;
+                state.rx_overflow = false;
+            }
+        });
+    }
+
+    pub fn handle_error_intr(&mut self) {
+        let i2c = self.regs();
+        free(|cs| {
+            let mut state = self.shared.state.borrow(cs).borrow_mut();
+            let sr1 = i2c.sr1().read();
+
+            if sr1.arlo().bit_is_set() {
+                // The peer has won the bus, and the hardware is back to be a
+                // target, so the frame is sent again once the bus is free.
+                i2c.sr1().modify(|_, w| w.arlo().clear_bit());
+                if state.tx_state != TxState::Idle {
+                    state.tx_pos = 0;
+                    state.tx_state = TxState::Starting;
+                    i2c.cr2().modify(|_, w| w.itbufen().set_bit());
+                    i2c.cr1().modify(|_, w| w.start().set_bit());
+                }
+            }
+
+            if sr1.af().bit_is_set() {
+                i2c.sr1().modify(|_, w| w.af().clear_bit());
+                if i2c.sr2().read().msl().bit_is_set() {
+                    // The peer is not there, or it cannot take the frame.
+                    // Losing it is fine, as the link re-sends it if needed.
+                    i2c.cr1().modify(|_, w| w.stop().set_bit());
+                    i2c.cr2().modify(|_, w| w.itbufen().set_bit());
+                    state.tx_state = TxState::Idle;
+                }
+            }
+
+            if sr1.berr().bit_is_set() {
+                i2c.sr1().modify(|_, w| w.berr().clear_bit());
+                state.rx_overflow = true;
+            }
+
+            if sr1.ovr().bit_is_set() {
+                i2c.sr1().modify(|_, w| w.ovr().clear_bit());
+                state.rx_overflow = true;
+            }
+        });
+    }
+}
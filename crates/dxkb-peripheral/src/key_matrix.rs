@@ -64,133 +64,7 @@ use crate::pin_set::{PinSet, PinSetSized};
 //     }
 // }
 
-/// Represents a type that is able to debounce the input signal
-/// generated by a button, attempting to remove the noise generated by
-/// the effect of pressing or unpressing it.
-pub trait Debounce<const ROWS: u8, const COLS: u8> {
-    fn debounce(
-        &mut self,
-        row: u8,
-        col: u8,
-        current_millis: u32,
-        prev_state: KeyState,
-        last_read_state: KeyState,
-    ) -> KeyState;
-}
-
-/// A debounce strategy where no debounce is done. Button status is
-/// passed to the matrix as it is coming from the wire.
-pub struct NoDebouncer {}
-impl<const ROWS: u8, const COLS: u8> Debounce<ROWS, COLS> for NoDebouncer {
-    fn debounce(
-        &mut self,
-        _row: u8,
-        _col: u8,
-        _current_millis: u32,
-        _prev_state: KeyState,
-        last_read_state: KeyState,
-    ) -> KeyState {
-        last_read_state
-    }
-}
-
-// Following a similar naming than QMK
-/// Debounce strategy in which a level change in the wire immediately
-/// triggers a change in the button state (pressed or unpressed,
-/// depending on the new level), and any other change in the wire for
-/// that specific button is ignored for the following
-/// `DEBOUNCE_MILLIS` milliseconds. For reducing the memory
-/// footprint, the maximum debounce time is limited to 254 ms.
-pub struct DebouncerEagerPerKey<const ROWS: u8, const COLS: u8, const DEBOUNCE_MILLIS: u8>
-where
-    [(); (ROWS as usize) * (COLS as usize)]:,
-{
-    // Holds the last time, in millis, when each key was pressed,
-    // except if its value is 0xff. In such case, it is considered
-    // that the last event for the referred key happened a long time
-    // ago, and next event coming from this key can be applied. This
-    // is a trick that QMK uses for handling the fact that a u8 value
-    // that represents millis will constantly be wrapping, and
-    // therefore, zero is a perfectly valid value. Therefore, instead
-    // of zero, we reserve the value 0xff as a special value for
-    // represent this case.
-    last_change_millis: [u8; (ROWS as usize) * (COLS as usize)],
-}
-
-impl<const ROWS: u8, const COLS: u8, const DEBOUNCE_MILLIS: u8>
-    DebouncerEagerPerKey<ROWS, COLS, DEBOUNCE_MILLIS>
-where
-    [(); (ROWS as usize) * (COLS as usize)]:,
-{
-    const fn assert_debounce_millis_in_range(millis: u8) {
-        assert!(millis < 255, "Debounce time cannot be greater than 254 ms!");
-    }
-
-    pub fn new() -> Self {
-        const { Self::assert_debounce_millis_in_range(DEBOUNCE_MILLIS) };
-
-        DebouncerEagerPerKey {
-            last_change_millis: [0xffu8; (ROWS as usize) * (COLS as usize)],
-        }
-    }
-
-    pub const fn diff_time(newer: u8, older: u8) -> u8 {
-        if newer >= older {
-            newer - older
-        } else {
-            255 - older + newer
-        }
-    }
-}
-
-impl<const ROWS: u8, const COLS: u8, const DEBOUNCE_MILLIS: u8> Debounce<ROWS, COLS>
-    for DebouncerEagerPerKey<ROWS, COLS, DEBOUNCE_MILLIS>
-where
-    [(); (ROWS as usize) * (COLS as usize)]:,
-{
-    fn debounce(
-        &mut self,
-        row: u8,
-        col: u8,
-        current_millis: u32,
-        prev_state: KeyState,
-        last_read_state: KeyState,
-    ) -> KeyState {
-        let wrapped_millis = (current_millis % 254) as u8;
-        let last_change_ms =
-            &mut self.last_change_millis[row as usize * COLS as usize + col as usize];
-        if *last_change_ms != 0xff {
-            if Self::diff_time(wrapped_millis, *last_change_ms) < DEBOUNCE_MILLIS {
-                // The last update was recent. Just ignore everything.
-                return prev_state;
-            } else {
-                // The debounce time have already passed. Mark it as such, and continue.
-                dev_trace!(
-                    "Debounce time over for {}; {} ({} ms). Because {} - {} = {}",
-                    row,
-                    col,
-                    current_millis,
-                    wrapped_millis,
-                    *last_change_ms,
-                    Self::diff_time(wrapped_millis, *last_change_ms)
-                );
-                *last_change_ms = 0xff;
-            }
-        }
-
-        if prev_state != last_read_state {
-            // If there has been any change, report the change, and
-            // store the time when it happened.
-            dev_trace!(
-                "Debounce time set in {} ms after {:?}",
-                current_millis,
-                last_read_state
-            );
-            *last_change_ms = wrapped_millis;
-        }
-        return last_read_state;
-    }
-}
+pub use dxkb_common::debounce::{Debounce, DebouncerEagerPerKey, NoDebouncer};
 
 /// Represents a type that determines the way in which a key matrix is
 /// scanned. It allows to dynamically determine which will be the type
@@ -258,6 +132,18 @@ pub trait KeyMatrixLike<const ROWS: u8, const COLS: u8> {
     fn get_key_state(&self, row: u8, col: u8) -> KeyState;
     fn set_key_state(&mut self, row: u8, col: u8, state: KeyState);
 
+    /// Returns the state of every key of a row, where the bit `n` is set if
+    /// the key of the column `n` is pressed.
+    fn row_bits(&self, row: u8) -> u128 {
+        (0..COLS).fold(0, |bits, col| bits | ((self.get_key_state(row, col) == KeyState::Pressed) as u128) << col)
+    }
+
+    /// Sets whether there's a key in the given position of the matrix. The
+    /// positions without a key are never reported as pressed by the scans,
+    /// so that spurious reads in unpopulated intersections are ignored. Every
+    /// position has a key by default.
+    fn set_key_present(&mut self, row: u8, col: u8, present: bool);
+
     /// Scans the current status of the key matrix, returning true if
     /// something has changed from the past scan.
     fn scan_matrix(&mut self) -> bool {
@@ -305,6 +191,8 @@ where
     ColBitMatrixLayout<COLS>: BitMatrixLayout,
 {
     matrix: BitMatrix<{ ROWS as usize }, COLS>,
+    /// The positions of the matrix that have a key.
+    present: BitMatrix<{ ROWS as usize }, COLS>,
     input_pins: S::InPins,
     output_pins: S::OutPins,
     debouncer: D,
@@ -350,12 +238,17 @@ where
 
         Self {
             matrix: BitMatrix::new(),
+            present: BitMatrix::filled(),
             input_pins: in_pins,
             output_pins: out_pins,
             debouncer,
             sysclk_freq,
         }
     }
+
+    pub fn debouncer_mut(&mut self) -> &mut D {
+        &mut self.debouncer
+    }
 }
 
 impl<const ROWS: u8, const COLS: u8, RowPins, ColPins, S, D, R> KeyMatrixLike<ROWS, COLS>
@@ -380,6 +273,18 @@ where
             .set_value(row as usize, col, state == KeyState::Pressed);
     }
 
+    #[inline(always)]
+    fn row_bits(&self, row: u8) -> u128 {
+        self.matrix.row_bits(row as usize)
+    }
+
+    fn set_key_present(&mut self, row: u8, col: u8, present: bool) {
+        self.present.set_value(row as usize, col, present);
+        if !present {
+            self.set_key_state(row, col, KeyState::Released);
+        }
+    }
+
     #[inline(never)]
     fn scan_matrix_act<F: FnMut(u8, u8, KeyState) -> ()>(&mut self, mut changed_fn: F) -> bool {
         let current_millis =
@@ -426,6 +331,10 @@ where
                 let new_state = KeyState::from_bool(!inputs[input_pin_index]);
 
                 let (row, col) = S::translate_indexes(input_pin_index as u8, output_pin_index as u8);
+                if !self.present.get_value(row as usize, col) {
+                    continue;
+                }
+
                 let prev_state = self.get_key_state(row, col);
 
                 let effective_state =
@@ -12,14 +12,36 @@ use stm32f4xx_hal::{pac::{Interrupt, PWR, RCC, RTC, SCB}, rcc::Enable};
 pub mod clock;
 pub mod gpio;
 pub mod key_matrix;
+pub mod mcp23017;
+pub mod i2c_bus;
+pub mod spi_dma;
 pub mod uart_dma_rb;
 pub mod usart;
 pub mod dma;
 pub mod usb;
+pub mod voltage;
 
 #[cfg(feature = "stm32f411")]
 pub mod pin_set;
 
+#[cfg(feature = "stm32f411")]
+pub mod flash_config;
+
+#[cfg(feature = "stm32f411")]
+pub mod low_power;
+
+#[cfg(feature = "stm32f411")]
+pub mod attention;
+
+#[cfg(feature = "stm32f411")]
+pub mod reset;
+
+#[cfg(feature = "stm32f411")]
+pub mod ws2812;
+
+#[cfg(feature = "stm32f411")]
+pub mod watchdog;
+
 pub trait InterruptReceiver {
     const INTERRUPT: Interrupt;
 }
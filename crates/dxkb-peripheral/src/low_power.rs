@@ -0,0 +1,225 @@
+//! Support for the Stop mode of the MCU, where every clock except the low
+//! speed ones is stopped, and for the sources that can wake it up from it:
+//!  - The wake line: a dedicated GPIO wired between both halves, that the
+//!    slave side pulls low for waking up the master side.
+//!  - The USB wakeup line (EXTI 18), raised when the host resumes the bus.
+//!  - The RTC wakeup timer (EXTI 22), for waking up periodically, e.g for
+//!    scanning the local matrix.
+//!
+//! The wakeup sources are configured as EXTI interrupts, but they are left
+//! masked in the NVIC: they are never handled, and they only wake up the core
+//! through the SEVONPEND event. Each source has to be checked and cleared
+//! after waking up.
+
+use core::time::Duration;
+
+use cortex_m::{asm, peripheral::SCB};
+use stm32f4xx_hal::{
+    gpio::{Edge, ExtiPin, OpenDrain, Output, Pin, Pull},
+    pac::{EXTI, PWR, RCC, RTC},
+    rcc::Enable,
+    syscfg::SysCfg,
+    time::Hertz,
+};
+
+/// SEVONPEND bit of the SCB System Control Register.
+const SCB_SCR_SEVONPEND: u32 = 1 << 4;
+
+/// Frequency that the RTC wakeup timer runs at: the nominal LSI frequency,
+/// divided by 16.
+const RTC_WAKEUP_TIMER_FREQ_HZ: u32 = 32_000 / 16;
+
+/// A line shared between both halves of the keyboard, active low. Both sides
+/// drive it as open drain with the internal pull-up enabled, so any of them
+/// can assert it, and it is safe to use regardless of which side is the
+/// master.
+pub struct WakeLine<const P: char, const N: u8> {
+    pin: Pin<P, N, Output<OpenDrain>>,
+}
+
+impl<const P: char, const N: u8> WakeLine<P, N> {
+    /// Sets up the line, released, and configures a falling edge EXTI
+    /// interrupt on it.
+    pub fn new(mut pin: Pin<P, N, Output<OpenDrain>>, syscfg: &mut SysCfg, exti: &mut EXTI) -> Self {
+        pin.set_high();
+        pin.set_internal_resistor(Pull::Up);
+        pin.make_interrupt_source(syscfg);
+        pin.trigger_on_edge(exti, Edge::Falling);
+        pin.enable_interrupt(exti);
+        pin.clear_interrupt_pending_bit();
+
+        Self { pin }
+    }
+
+    /// Asserts the line during `duration`, blocking. The line must stay
+    /// asserted long enough for the peer to wake up and notice it, so a few
+    /// milliseconds are recommended.
+    pub fn pulse(&mut self, sysclk: Hertz, duration: Duration) {
+        self.pin.set_low();
+        asm::delay((sysclk.raw() as u64 * duration.as_micros() as u64 / 1_000_000) as u32);
+        self.pin.set_high();
+    }
+
+    /// Returns whether the peer is asserting the line right now.
+    pub fn is_asserted(&self) -> bool {
+        self.pin.is_low()
+    }
+
+    /// Returns whether the line was asserted since the last call.
+    pub fn take_wake_request(&mut self) -> bool {
+        let requested = self.pin.check_interrupt();
+        self.pin.clear_interrupt_pending_bit();
+        requested
+    }
+}
+
+/// Makes the host resuming the USB bus wake up the MCU from Stop mode.
+pub fn enable_usb_wakeup(exti: &mut EXTI) {
+    exti.rtsr().modify(|_, w| w.tr18().set_bit());
+    exti.imr().modify(|_, w| w.mr18().set_bit());
+    exti.pr().write(|w| w.pr18().clear());
+}
+
+/// Returns whether the host resumed the USB bus since the last call.
+pub fn take_usb_wakeup(exti: &mut EXTI) -> bool {
+    let woken = exti.pr().read().pr18().is_pending();
+    exti.pr().write(|w| w.pr18().clear());
+    woken
+}
+
+/// Starts the RTC wakeup timer, for waking up the MCU from Stop mode every
+/// `period`, which must be between 1 ms and 32 s. The RTC is clocked from the
+/// LSI if it didn't have a clock already. Since the LSI is quite inaccurate,
+/// the actual period may be off by up to a 50%.
+pub fn enable_rtc_wakeup(period: Duration) {
+    let ticks = (period.as_millis() as u32 * RTC_WAKEUP_TIMER_FREQ_HZ / 1000).clamp(1, u16::MAX as u32 + 1);
+
+    let rcc = unsafe { RCC::steal() };
+    let pwr = unsafe { PWR::steal() };
+    let rtc = unsafe { RTC::steal() };
+    let exti = unsafe { EXTI::steal() };
+
+    // The RTC lives in the backup domain, which is write protected.
+    unsafe {
+        PWR::enable_unchecked();
+    }
+    pwr.cr().modify(|_, w| w.dbp().set_bit());
+
+    rcc.csr().modify(|_, w| w.lsion().set_bit());
+    while rcc.csr().read().lsirdy().bit_is_clear() {}
+
+    if rcc.bdcr().read().rtcsel().is_no_clock() {
+        rcc.bdcr().modify(|_, w| w.rtcsel().lsi());
+    }
+    rcc.bdcr().modify(|_, w| w.rtcen().set_bit());
+
+    rtc.wpr().write(|w| unsafe { w.key().bits(0xCA) });
+    rtc.wpr().write(|w| unsafe { w.key().bits(0x53) });
+
+    rtc.cr().modify(|_, w| w.wute().clear_bit());
+    while rtc.isr().read().wutwf().bit_is_clear() {}
+
+    rtc.wutr().write(|w| unsafe { w.wut().bits((ticks - 1) as u16) });
+    rtc.isr().modify(|_, w| w.wutf().clear());
+    rtc.cr().modify(|_, w| w.wucksel().div16().wutie().set_bit().wute().set_bit());
+
+    rtc.wpr().write(|w| unsafe { w.key().bits(0xFF) });
+
+    exti.rtsr().modify(|_, w| w.tr22().set_bit());
+    exti.imr().modify(|_, w| w.mr22().set_bit());
+    exti.pr().write(|w| w.pr22().clear());
+}
+
+/// Stops the RTC wakeup timer, if it was running.
+pub fn disable_rtc_wakeup() {
+    let rtc = unsafe { RTC::steal() };
+    let exti = unsafe { EXTI::steal() };
+
+    rtc.wpr().write(|w| unsafe { w.key().bits(0xCA) });
+    rtc.wpr().write(|w| unsafe { w.key().bits(0x53) });
+    rtc.cr().modify(|_, w| w.wute().clear_bit().wutie().clear_bit());
+    rtc.isr().modify(|_, w| w.wutf().clear());
+    rtc.wpr().write(|w| unsafe { w.key().bits(0xFF) });
+
+    exti.imr().modify(|_, w| w.mr22().clear_bit());
+    exti.pr().write(|w| w.pr22().clear());
+}
+
+/// Returns whether the RTC wakeup timer expired since the last call.
+pub fn take_rtc_wakeup() -> bool {
+    let rtc = unsafe { RTC::steal() };
+    let exti = unsafe { EXTI::steal() };
+
+    let woken = exti.pr().read().pr22().is_pending();
+    if woken {
+        // The flag is in the RTC registers too, and the timer doesn't
+        // trigger again until it is cleared.
+        rtc.isr().modify(|_, w| w.wutf().clear());
+        exti.pr().write(|w| w.pr22().clear());
+    }
+    woken
+}
+
+/// Puts the core to sleep, with every clock running, until any enabled
+/// interrupt is raised or any of the EXTI lines left masked in the NVIC, like
+/// the ones of the wakeup sources, triggers. It may return right away if
+/// an event was latched before, so it must be called in a loop that checks
+/// for the actual reason of waking up.
+pub fn sleep_until_event(scb: &mut SCB) {
+    unsafe {
+        scb.scr.modify(|r| r | SCB_SCR_SEVONPEND);
+    }
+    asm::dsb();
+    asm::wfe();
+}
+
+/// Puts the MCU in Stop mode, with the voltage regulator in low power mode,
+/// until any of the enabled wakeup sources triggers, or any enabled interrupt
+/// is raised. The clock configuration is restored before returning.
+///
+/// The cycle counter doesn't run during Stop mode, so the time spent there is
+/// not accounted by [`DWTClock`](crate::clock::DWTClock); the keyboard behaves
+/// as if time had stopped.
+pub fn enter_stop_mode(scb: &mut SCB) {
+    let rcc = unsafe { RCC::steal() };
+    let pwr = unsafe { PWR::steal() };
+
+    let cfgr = rcc.cfgr().read().bits();
+    let cr = rcc.cr().read();
+    let hse_on = cr.hseon().bit_is_set();
+    let pll_on = cr.pllon().bit_is_set();
+
+    unsafe {
+        PWR::enable_unchecked();
+    }
+    pwr.cr().modify(|_, w| w.pdds().clear_bit().lpds().set_bit().cwuf().set_bit());
+
+    scb.set_sleepdeep();
+    unsafe {
+        scb.scr.modify(|r| r | SCB_SCR_SEVONPEND);
+    }
+
+    // The first WFE clears any event that is already latched, so only the
+    // second one actually waits.
+    asm::dsb();
+    asm::sev();
+    asm::wfe();
+    asm::wfe();
+
+    scb.clear_sleepdeep();
+
+    // The MCU wakes up running from the HSI, with the HSE and the PLL off.
+    if hse_on {
+        rcc.cr().modify(|_, w| w.hseon().set_bit());
+        while rcc.cr().read().hserdy().bit_is_clear() {}
+    }
+
+    if pll_on {
+        rcc.cr().modify(|_, w| w.pllon().set_bit());
+        while rcc.cr().read().pllrdy().bit_is_clear() {}
+    }
+
+    rcc.cfgr().write(|w| unsafe { w.bits(cfgr) });
+    let sw = (cfgr & 0b11) as u8;
+    while rcc.cfgr().read().sws().bits() != sw {}
+}
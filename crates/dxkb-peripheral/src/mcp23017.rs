@@ -0,0 +1,238 @@
+//! Support for key matrices wired through a MCP23017 I2C GPIO expander.
+//!
+//! The expander is used with its default configuration (`IOCON.BANK = 0`, with
+//! sequential addressing), so that the registers of both ports are laid out
+//! next to each other and they can be read and written as a single 16 bit
+//! value in one transaction, where the port A is the low byte. Pin `n` of the
+//! expander refers to GPA`n` for `n < 8` and GPB`n-8` otherwise.
+
+use stm32f4xx_hal::{hal::i2c::I2c, time::Hertz};
+use cortex_m::peripheral::DWT;
+
+use dxkb_common::{
+    dev_trace, dev_warn, util::{BitMatrix, BitMatrixLayout, ColBitMatrixLayout}, KeyState
+};
+
+use crate::key_matrix::{Debounce, KeyMatrixLike};
+
+/// I2C address of the expander when A2, A1 and A0 are tied to ground.
+pub const MCP23017_BASE_ADDRESS: u8 = 0x20;
+
+const REG_IODIRA: u8 = 0x00;
+const REG_GPPUA: u8 = 0x0C;
+const REG_GPIOA: u8 = 0x12;
+const REG_OLATA: u8 = 0x14;
+
+/// Driver for the MCP23017. It keeps a copy of the output latches, so that
+/// writing outputs that haven't changed doesn't need any I2C transaction.
+pub struct Mcp23017<I2C: I2c> {
+    i2c: I2C,
+    address: u8,
+    /// The last value written to the OLAT registers, if known.
+    olat: Option<u16>,
+}
+
+impl<I2C: I2c> Mcp23017<I2C> {
+    /// Creates the driver of the expander with the given address. See
+    /// [`MCP23017_BASE_ADDRESS`].
+    pub const fn new(i2c: I2C, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            olat: None,
+        }
+    }
+
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    fn write_u16(&mut self, reg: u8, value: u16) -> Result<(), I2C::Error> {
+        let [lo, hi] = value.to_le_bytes();
+        self.i2c.write(self.address, &[reg, lo, hi])
+    }
+
+    /// Sets the pins in `inputs` as inputs, and the rest as outputs. The
+    /// pull-up resistor is enabled in the pins of `pull_ups`.
+    pub fn configure(&mut self, inputs: u16, pull_ups: u16) -> Result<(), I2C::Error> {
+        self.write_u16(REG_IODIRA, inputs)?;
+        self.write_u16(REG_GPPUA, pull_ups)
+    }
+
+    /// Writes the output latches of both ports. Does nothing if they already
+    /// had this value.
+    pub fn write_outputs(&mut self, value: u16) -> Result<(), I2C::Error> {
+        if self.olat == Some(value) {
+            return Ok(());
+        }
+
+        // If the write fails, the value of the latches is unknown.
+        self.olat = None;
+        self.write_u16(REG_OLATA, value)?;
+        self.olat = Some(value);
+        Ok(())
+    }
+
+    /// Reads the level of the pins of both ports.
+    pub fn read_inputs(&mut self) -> Result<u16, I2C::Error> {
+        let mut buf = [0u8; 2];
+        self.i2c.write_read(self.address, &[REG_GPIOA], &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+}
+
+/// A key matrix whose rows and columns are connected to the pins of a
+/// MCP23017. The rows are selected one by one, and the columns are read all at
+/// once, so the diodes must go from the columns to the rows, as in
+/// [`RowScan`](crate::key_matrix::RowScan). A key is considered pressed when it
+/// is active low.
+///
+/// A full scan takes exactly two I2C transactions per row: one for selecting
+/// the row, and another one for reading the columns. The last row is left
+/// selected after the scan, since selecting the first row on the next scan
+/// deselects it anyway.
+pub struct Mcp23017KeyMatrix<const ROWS: u8, const COLS: u8, I2C: I2c, D>
+where
+    [(); ROWS as usize]:,
+    [(); COLS as usize]:,
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
+{
+    expander: Mcp23017<I2C>,
+    matrix: BitMatrix<{ ROWS as usize }, COLS>,
+    /// The positions of the matrix that have a key.
+    present: BitMatrix<{ ROWS as usize }, COLS>,
+    row_pins: [u8; ROWS as usize],
+    col_pins: [u8; COLS as usize],
+    debouncer: D,
+    sysclk_freq: Hertz, // TODO Change by usage of Clock trait
+}
+
+impl<const ROWS: u8, const COLS: u8, I2C: I2c, D> Mcp23017KeyMatrix<ROWS, COLS, I2C, D>
+where
+    [(); ROWS as usize]:,
+    [(); COLS as usize]:,
+    D: Debounce<ROWS, COLS>,
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
+{
+    const fn pins_mask(pins: &[u8]) -> u16 {
+        let mut mask = 0u16;
+        let mut i = 0;
+        while i < pins.len() {
+            assert!(pins[i] < 16, "The MCP23017 only has 16 pins");
+            mask |= 1 << pins[i];
+            i += 1;
+        }
+        mask
+    }
+
+    /// Creates the matrix, configuring the column pins as inputs with pull-up
+    /// and the row pins as outputs, set high.
+    pub fn new(
+        sysclk_freq: Hertz,
+        mut expander: Mcp23017<I2C>,
+        row_pins: [u8; ROWS as usize],
+        col_pins: [u8; COLS as usize],
+        debouncer: D,
+    ) -> Result<Self, I2C::Error> {
+        let row_mask = Self::pins_mask(&row_pins);
+        let col_mask = Self::pins_mask(&col_pins);
+        assert!(row_mask & col_mask == 0, "A pin cannot be used as both row and column");
+
+        expander.configure(col_mask, col_mask)?;
+        expander.write_outputs(u16::MAX)?;
+
+        Ok(Self {
+            expander,
+            matrix: BitMatrix::new(),
+            present: BitMatrix::filled(),
+            row_pins,
+            col_pins,
+            debouncer,
+            sysclk_freq,
+        })
+    }
+}
+
+impl<const ROWS: u8, const COLS: u8, I2C: I2c, D> KeyMatrixLike<ROWS, COLS>
+    for Mcp23017KeyMatrix<ROWS, COLS, I2C, D>
+where
+    [(); ROWS as usize]:,
+    [(); COLS as usize]:,
+    D: Debounce<ROWS, COLS>,
+    ColBitMatrixLayout<COLS>: BitMatrixLayout,
+{
+    #[inline(always)]
+    fn get_key_state(&self, row: u8, col: u8) -> KeyState {
+        KeyState::from_bool(self.matrix.get_value(row as usize, col))
+    }
+
+    #[inline(always)]
+    fn set_key_state(&mut self, row: u8, col: u8, state: KeyState) {
+        self.matrix
+            .set_value(row as usize, col, state == KeyState::Pressed);
+    }
+
+    #[inline(always)]
+    fn row_bits(&self, row: u8) -> u128 {
+        self.matrix.row_bits(row as usize)
+    }
+
+    fn set_key_present(&mut self, row: u8, col: u8, present: bool) {
+        self.present.set_value(row as usize, col, present);
+        if !present {
+            self.set_key_state(row, col, KeyState::Released);
+        }
+    }
+
+    fn scan_matrix_act<F: FnMut(u8, u8, KeyState) -> ()>(&mut self, mut changed_fn: F) -> bool {
+        let current_millis =
+            ((DWT::cycle_count() as u64) * 1000 / self.sysclk_freq.raw() as u64) as u32;
+        let mut has_changed = false;
+
+        for row in 0..ROWS {
+            let selected = !(1u16 << self.row_pins[row as usize]);
+            // The I2C transaction itself takes much longer than the time the
+            // line needs to settle, so no delay is needed before reading.
+            let inputs = match self
+                .expander
+                .write_outputs(selected)
+                .and_then(|_| self.expander.read_inputs())
+            {
+                Ok(inputs) => inputs,
+                Err(e) => {
+                    // Keep the previous state of the row, it will be read
+                    // again on the next scan.
+                    dev_warn!("Couldn't scan matrix row {} through MCP23017: {:?}", row, e);
+                    continue;
+                }
+            };
+
+            for col in 0..COLS {
+                if !self.present.get_value(row as usize, col) {
+                    continue;
+                }
+
+                let new_state = KeyState::from_bool(inputs & (1 << self.col_pins[col as usize]) == 0);
+                let prev_state = self.get_key_state(row, col);
+
+                let effective_state =
+                    self.debouncer
+                        .debounce(row, col, current_millis, prev_state, new_state);
+                if effective_state != prev_state {
+                    has_changed = true;
+                    self.set_key_state(row, col, effective_state);
+                    changed_fn(row, col, effective_state);
+                    dev_trace!(
+                        "{:?} ({}; {}) ({} ms)",
+                        effective_state,
+                        row,
+                        col,
+                        current_millis
+                    );
+                }
+            }
+        }
+
+        has_changed
+    }
+}
@@ -0,0 +1,101 @@
+//! Reading of the reset flags of the MCU, for finding out why it was reset,
+//! and persistent counting of the resets of each kind, so that a keyboard that
+//! resets now and then in the field can be diagnosed later.
+
+use core::fmt::{self, Debug};
+
+use dxkb_common::{dev_info, dev_warn, ResetReason};
+use stm32f4xx_hal::pac::RCC;
+
+use crate::flash_config::{FlashConfig, FlashConfigError};
+
+/// Key of the reset counters in the flash config area.
+pub const RESET_COUNTS_CONFIG_KEY: u8 = 0x03;
+
+const RESET_COUNT_LEN: usize = size_of::<u32>();
+
+/// Returns why the MCU was reset last, and clears the reset flags, so that a
+/// later reset isn't mistaken for this one. It must be called once on boot,
+/// before anything resets the MCU on purpose.
+pub fn take_reset_reason() -> ResetReason {
+    let rcc = unsafe { RCC::steal() };
+    let csr = rcc.csr().read();
+
+    // A power-on reset raises the brown-out and pin flags too, and the
+    // watchdogs raise the pin flag too, so the flags are checked from the
+    // most specific to the least.
+    let reason = if csr.lpwrrstf().bit_is_set() {
+        ResetReason::LowPower
+    } else if csr.wwdgrstf().bit_is_set() {
+        ResetReason::WindowWatchdog
+    } else if csr.wdgrstf().bit_is_set() {
+        ResetReason::IndependentWatchdog
+    } else if csr.sftrstf().bit_is_set() {
+        ResetReason::Software
+    } else if csr.porrstf().bit_is_set() {
+        ResetReason::PowerOn
+    } else if csr.borrstf().bit_is_set() {
+        ResetReason::BrownOut
+    } else if csr.padrstf().bit_is_set() {
+        ResetReason::Pin
+    } else {
+        ResetReason::Unknown
+    };
+
+    rcc.csr().modify(|_, w| w.rmvf().set_bit());
+    reason
+}
+
+/// Number of resets of each kind since the config area was last cleared.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ResetCounts {
+    counts: [u32; ResetReason::ALL.len()],
+}
+
+impl ResetCounts {
+    /// Reads the counters from the config area. Counters missing from it, e.g
+    /// because they were stored by a firmware that didn't know some reason,
+    /// start from zero.
+    pub fn load(config: &FlashConfig) -> Self {
+        let mut buf = [0u8; ResetReason::ALL.len() * RESET_COUNT_LEN];
+        let len = config.read(RESET_COUNTS_CONFIG_KEY, &mut buf).unwrap_or(0);
+
+        let mut counts = [0u32; ResetReason::ALL.len()];
+        for (count, bytes) in counts.iter_mut().zip(buf[..len].chunks_exact(RESET_COUNT_LEN)) {
+            *count = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        ResetCounts { counts }
+    }
+
+    /// Returns how many times the MCU was reset for the given reason.
+    pub fn count(&self, reason: ResetReason) -> u32 {
+        self.counts[reason.index()]
+    }
+
+    /// Logs the reason of the last reset, counts it and stores the counters
+    /// back into the config area. It must be called once on boot.
+    pub fn record(&mut self, config: &mut FlashConfig, reason: ResetReason) -> Result<(), FlashConfigError> {
+        let count = &mut self.counts[reason.index()];
+        *count = count.saturating_add(1);
+
+        if reason.is_abnormal() {
+            dev_warn!("Reset by {:?} ({} times so far)", reason, *count);
+        } else {
+            dev_info!("Reset by {:?} ({} times so far)", reason, *count);
+        }
+
+        let mut buf = [0u8; ResetReason::ALL.len() * RESET_COUNT_LEN];
+        for (count, bytes) in self.counts.iter().zip(buf.chunks_exact_mut(RESET_COUNT_LEN)) {
+            bytes.copy_from_slice(&count.to_le_bytes());
+        }
+        config.write(RESET_COUNTS_CONFIG_KEY, &buf)
+    }
+}
+
+impl Debug for ResetCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(ResetReason::ALL.iter().map(|reason| (reason, self.count(*reason))).filter(|(_, count)| *count > 0))
+            .finish()
+    }
+}
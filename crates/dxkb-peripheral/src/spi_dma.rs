@@ -0,0 +1,546 @@
+//! A [`BusRead`] + [`BusWrite`] implementation over a full-duplex SPI line
+//! moved by DMA, for the split keyboards whose halves are wired with 4 wires
+//! (SCK, MOSI, MISO and CS), which can run the link much faster than a
+//! serial line.
+//!
+//! One of the sides is the master, which drives the clock and the chip select
+//! line, and the other one is the slave, and they exchange frames through
+//! transactions, delimited by the chip select line: it is pulled low when the
+//! transaction starts, and released when it ends. Since the slave can only
+//! send while the master clocks the line, every transaction has the same
+//! length, `FRAME_LEN`, and carries up to one frame on each direction, so
+//! that the slave can answer with a frame of any length. The first byte of
+//! each direction is the length of the frame that follows it, zero if there
+//! is no frame, and the rest of the transaction is padding, so frames are at
+//! most `FRAME_LEN - 1` bytes long.
+//!
+//! The master starts a transaction whenever it has a frame to send. Since the
+//! slave has no way of asking for one, the master also starts an empty one
+//! when the bus is polled and the line is idle, so the peer must be polled
+//! often, as the split link does anyway. The slave gets ready for the next
+//! transaction when the chip select line is released, so the master must not
+//! start the next one right after the previous one, which is not the case
+//! when they're only started from the main loop. A transaction that is cut
+//! short, e.g because the master was reset in the middle of it, is discarded,
+//! and the frame sent by the slave in it is sent again on the next one.
+//!
+//! The DMA streams only raise an interrupt once per transaction on the master
+//! (the end of the RX transfer, see [`SpiDmaBusIsr::handle_dma_intr`]), and
+//! the slave only gets the interrupt of the chip select line (see
+//! [`SpiDmaBusIsr::handle_exti_intr`]).
+
+use core::cell::UnsafeCell;
+use core::mem;
+
+use cortex_m::interrupt::Mutex;
+use dxkb_common::{
+    bus::{BusPollError, BusRead, BusTransferError, BusWrite},
+    dev_info, dev_warn,
+    util::RingBuffer,
+};
+use stm32f4xx_hal::{
+    dma::{
+        self, ChannelX, DmaEvent, DmaFlag, MemoryToPeripheral, PeripheralToMemory,
+        traits::{Channel, DMASet, Stream, StreamISR},
+    },
+    gpio::{Edge, ErasedPin, ExtiPin, Output},
+    pac::EXTI,
+    rcc::Clocks,
+    spi::{Instance, Mode, Spi, SpiSlave},
+    syscfg::SysCfg,
+    time::Hertz,
+};
+
+use crate::free_with_muts;
+use crate::uart_dma_rb::{setup_dma_for_rx, setup_dma_for_tx};
+
+/// Length of the header of each direction of the transactions.
+const SPI_FRAME_HEADER_LEN: usize = 1;
+
+struct SpiFrame<const FRAME_LEN: usize> {
+    len: usize,
+    data: [u8; FRAME_LEN],
+}
+
+impl<const FRAME_LEN: usize> SpiFrame<FRAME_LEN> {
+    const fn new() -> Self {
+        Self { len: 0, data: [0; FRAME_LEN] }
+    }
+}
+
+struct SpiBusState<const FRAME_LEN: usize, const FRAMES: usize> {
+    rx_frames: RingBuffer<SpiFrame<FRAME_LEN>, FRAMES>,
+
+    /// The frame waiting for the next transaction, if `tx_pending` is set.
+    tx_next: SpiFrame<FRAME_LEN>,
+    tx_pending: bool,
+
+    /// Master only: whether a transaction is in progress.
+    in_transaction: bool,
+
+    /// Slave only: whether the transaction that is ready to be clocked by
+    /// the master carries a frame, which is sent again if it is cut short.
+    tx_in_flight: bool,
+}
+
+/// The buffers the DMA streams of a [`SpiDmaBus`] read from and write to,
+/// along with the frames received that haven't been read yet, up to `FRAMES`
+/// of at most `FRAME_LEN - 1` bytes each. It must live in a static.
+pub struct SpiDmaBuffers<const FRAME_LEN: usize, const FRAMES: usize> {
+    tx_buf: [u8; FRAME_LEN],
+    rx_buf: [u8; FRAME_LEN],
+    state: Mutex<UnsafeCell<SpiBusState<FRAME_LEN, FRAMES>>>,
+}
+
+impl<const FRAME_LEN: usize, const FRAMES: usize> SpiDmaBuffers<FRAME_LEN, FRAMES> {
+    pub const fn new() -> Self {
+        assert!(
+            FRAME_LEN > SPI_FRAME_HEADER_LEN && FRAME_LEN <= 256,
+            "SPI transactions must be between 2 and 256 bytes long"
+        );
+        Self {
+            tx_buf: [0; FRAME_LEN],
+            rx_buf: [0; FRAME_LEN],
+            state: Mutex::new(UnsafeCell::new(SpiBusState {
+                rx_frames: RingBuffer::new(),
+                tx_next: SpiFrame::new(),
+                tx_pending: false,
+                in_transaction: false,
+                tx_in_flight: false,
+            })),
+        }
+    }
+}
+
+/// Which side of the line a [`SpiDmaBus`] is, along with its chip select pin.
+pub enum SpiRole<SPI: Instance> {
+    /// Drives the clock and the chip select line.
+    Master(ErasedPin<Output>),
+    /// Follows the master, and detects the end of the transactions with the
+    /// interrupt of the chip select line, which is the NSS pin of the
+    /// peripheral.
+    Slave(SPI::Nss),
+}
+
+pub struct SpiDmaBus<
+    SPI: Instance,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8,
+    const FRAME_LEN: usize,
+    const FRAMES: usize,
+> {
+    spi: SPI,
+    role: SpiRole<SPI>,
+    tx_stream: TxStream,
+    rx_stream: RxStream,
+    bufs: &'static SpiDmaBuffers<FRAME_LEN, FRAMES>,
+}
+
+/// The interrupt handlers of a [`SpiDmaBus`], returned by
+/// [`SpiDmaBus::init_master`] and [`SpiDmaBus::init_slave`].
+pub struct SpiDmaBusIsr<
+    SPI: Instance,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8,
+    const FRAME_LEN: usize,
+    const FRAMES: usize,
+> {
+    bus: SpiDmaBus<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>,
+}
+
+impl<
+    SPI: Instance,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8,
+    const FRAME_LEN: usize,
+    const FRAMES: usize,
+> SpiDmaBus<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>
+where
+    ChannelX<DMA_TX_CH>: Channel,
+    ChannelX<DMA_RX_CH>: Channel,
+    SPI: DMASet<TxStream, DMA_TX_CH, MemoryToPeripheral>,
+    SPI: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>,
+    SPI::Nss: ExtiPin,
+{
+    /// Sets the SPI peripheral up as the master of the line, clocking it at
+    /// `freq`. The interrupt of the RX stream must be unmasked afterwards.
+    pub fn init_master(
+        spi: SPI,
+        pins: (impl Into<SPI::Sck>, impl Into<SPI::Miso>, impl Into<SPI::Mosi>),
+        cs_pin: impl Into<ErasedPin<Output>>,
+        streams: (TxStream, RxStream),
+        mode: impl Into<Mode>,
+        freq: Hertz,
+        bufs: &'static mut SpiDmaBuffers<FRAME_LEN, FRAMES>,
+        clocks: &Clocks,
+    ) -> (Self, SpiDmaBusIsr<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>) {
+        let mut cs_pin = cs_pin.into();
+        cs_pin.set_high();
+
+        let (spi, _pins) = Spi::new(spi, pins, mode, freq, clocks).release();
+        let mut bus = Self::init(spi, SpiRole::Master(cs_pin), streams, bufs);
+        bus.rx_stream.listen_only(DmaEvent::TransferComplete);
+        dev_info!("SPI line enabled as master");
+        bus.split()
+    }
+
+    /// Sets the SPI peripheral up as the slave of the line. The NSS pin,
+    /// which is the chip select, is also set up as an interrupt source,
+    /// triggered when it is released, whose interrupt must be unmasked
+    /// afterwards.
+    pub fn init_slave(
+        spi: SPI,
+        pins: (impl Into<SPI::Sck>, impl Into<SPI::Miso>, impl Into<SPI::Mosi>, impl Into<SPI::Nss>),
+        streams: (TxStream, RxStream),
+        mode: impl Into<Mode>,
+        bufs: &'static mut SpiDmaBuffers<FRAME_LEN, FRAMES>,
+        syscfg: &mut SysCfg,
+        exti: &mut EXTI,
+    ) -> (Self, SpiDmaBusIsr<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>) {
+        let (spi, (_, _, _, cs_pin)) = SpiSlave::new(spi, (pins.0, pins.1, pins.2, Some(pins.3.into())), mode).release();
+        let mut cs_pin = cs_pin.unwrap();
+        cs_pin.make_interrupt_source(syscfg);
+        cs_pin.trigger_on_edge(exti, Edge::Rising);
+        cs_pin.enable_interrupt(exti);
+
+        let mut bus = Self::init(spi, SpiRole::Slave(cs_pin), streams, bufs);
+        free_with_muts!(
+            state <- bus.bufs.state,
+            || {
+                bus.arm_slave_transaction(state);
+            }
+        );
+        dev_info!("SPI line enabled as slave");
+        bus.split()
+    }
+
+    fn init(
+        spi: SPI,
+        role: SpiRole<SPI>,
+        streams: (TxStream, RxStream),
+        bufs: &'static mut SpiDmaBuffers<FRAME_LEN, FRAMES>,
+    ) -> Self {
+        let (mut tx_stream, mut rx_stream) = streams;
+        let peri_addr = spi.dr().as_ptr() as u32;
+        setup_dma_for_tx(&mut tx_stream, <ChannelX<DMA_TX_CH> as Channel>::VALUE, peri_addr, dma::config::Priority::High);
+        setup_dma_for_rx(&mut rx_stream, FRAME_LEN as u16, <ChannelX<DMA_RX_CH> as Channel>::VALUE, bufs.rx_buf.as_ptr(), peri_addr);
+        // A transaction is a single transfer of each stream.
+        rx_stream.set_circular_mode(false);
+        spi.cr2().modify(|_, w| w.txdmaen().enabled().rxdmaen().enabled());
+
+        Self {
+            spi,
+            role,
+            tx_stream,
+            rx_stream,
+            bufs,
+        }
+    }
+
+    fn split(self) -> (Self, SpiDmaBusIsr<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>) {
+        let isr = SpiDmaBusIsr {
+            // SAFETY: The handle is only used by the interrupt handlers.
+            bus: unsafe { self.isr_handle() },
+        };
+        (self, isr)
+    }
+
+    /// Returns another handle to the same line. The handles to the
+    /// peripherals and the pins are just tokens that don't hold any state,
+    /// nor they implement Drop, and the state of the line is only accessed
+    /// through a critical section.
+    ///
+    /// # Safety
+    /// The handle shares the DMA streams with this one, so it must only be
+    /// used for starting a transaction while none is in progress, or for
+    /// handling the interrupts of the line.
+    unsafe fn isr_handle(&self) -> Self {
+        unsafe { mem::transmute_copy(self) }
+    }
+
+    #[inline(always)]
+    fn is_master(&self) -> bool {
+        matches!(self.role, SpiRole::Master(_))
+    }
+
+    /// Arms both streams for a transaction of `FRAME_LEN` bytes, whose
+    /// outgoing side must be in the TX buffer already.
+    fn arm_streams(&mut self) {
+        self.rx_stream.clear_all_flags();
+        self.rx_stream.set_memory_address(self.bufs.rx_buf.as_ptr() as u32);
+        self.rx_stream.set_number_of_transfers(FRAME_LEN as u16);
+        self.tx_stream.clear_all_flags();
+        self.tx_stream.set_memory_address(self.bufs.tx_buf.as_ptr() as u32);
+        self.tx_stream.set_number_of_transfers(FRAME_LEN as u16);
+        unsafe {
+            // RX goes first, so that no byte is missed.
+            self.rx_stream.enable();
+            self.tx_stream.enable();
+        }
+    }
+
+    /// Writes the header and the frame, if any, of the next transaction
+    /// into the TX buffer.
+    fn fill_tx_buf(&self, frame: Option<&SpiFrame<FRAME_LEN>>) {
+        // SAFETY: The TX buffer is only written while no transaction is in
+        // progress, so the DMA is not reading it.
+        let tx_buf = unsafe { &mut *(self.bufs.tx_buf.as_ptr() as *mut [u8; FRAME_LEN]) };
+        match frame {
+            Some(frame) => {
+                tx_buf[0] = frame.len as u8;
+                tx_buf[SPI_FRAME_HEADER_LEN..SPI_FRAME_HEADER_LEN + frame.len].copy_from_slice(&frame.data[..frame.len]);
+            }
+            None => tx_buf[0] = 0,
+        }
+    }
+
+    /// Master only: starts a transaction, carrying the pending frame if
+    /// there's any, unless one is in progress already.
+    fn start_master_transaction(&mut self, state: &mut SpiBusState<FRAME_LEN, FRAMES>) {
+        if state.in_transaction {
+            return;
+        }
+
+        if state.tx_pending {
+            self.fill_tx_buf(Some(&state.tx_next));
+            state.tx_pending = false;
+        } else {
+            self.fill_tx_buf(None);
+        }
+
+        state.in_transaction = true;
+        if let SpiRole::Master(cs_pin) = &mut self.role {
+            cs_pin.set_low();
+        }
+        self.arm_streams();
+    }
+
+    /// Slave only: gets ready for the next transaction, carrying the frame
+    /// of the last one again if it was cut short, or the pending one.
+    fn arm_slave_transaction(&mut self, state: &mut SpiBusState<FRAME_LEN, FRAMES>) {
+        if !state.tx_in_flight {
+            if state.tx_pending {
+                self.fill_tx_buf(Some(&state.tx_next));
+                state.tx_pending = false;
+                state.tx_in_flight = true;
+            } else {
+                self.fill_tx_buf(None);
+            }
+        }
+
+        // The peripheral keeps the byte that was meant to go next in the
+        // previous transaction, which is dropped by disabling it.
+        self.spi.cr1().modify(|_, w| w.spe().clear_bit());
+        let _ = self.spi.dr().read();
+        self.spi.cr1().modify(|_, w| w.spe().set_bit());
+        self.arm_streams();
+    }
+
+    /// Takes the frame received in the last transaction.
+    fn take_rx_frame(&self, state: &mut SpiBusState<FRAME_LEN, FRAMES>) {
+        let rx_buf = &self.bufs.rx_buf;
+        let len = rx_buf[0] as usize;
+        if len == 0 {
+            return;
+        }
+
+        if len > FRAME_LEN - SPI_FRAME_HEADER_LEN {
+            dev_warn!("Discarded SPI frame with invalid length {}", len);
+            return;
+        }
+
+        if state.rx_frames.is_full() {
+            dev_warn!("SPI RX queue is full. Dropping the oldest frame");
+        }
+
+        let mut frame = SpiFrame::new();
+        frame.data[..len].copy_from_slice(&rx_buf[SPI_FRAME_HEADER_LEN..SPI_FRAME_HEADER_LEN + len]);
+        frame.len = len;
+        state.rx_frames.push(frame);
+    }
+
+    fn handle_dma_intr(&mut self) {
+        if !self.rx_stream.flags().contains(DmaFlag::TransferComplete) {
+            self.rx_stream.clear_all_flags();
+            return;
+        }
+        self.rx_stream.clear_all_flags();
+
+        // The last byte has been received, so the transaction is over.
+        if let SpiRole::Master(cs_pin) = &mut self.role {
+            cs_pin.set_high();
+        }
+
+        free_with_muts!(
+            state <- self.bufs.state,
+            || {
+                state.in_transaction = false;
+                self.take_rx_frame(state);
+                if state.tx_pending {
+                    self.start_master_transaction(state);
+                }
+            }
+        );
+    }
+
+    fn handle_exti_intr(&mut self) {
+        let received = FRAME_LEN - self.rx_stream.number_of_transfers() as usize;
+        unsafe {
+            self.rx_stream.disable();
+            self.tx_stream.disable();
+        }
+
+        if let SpiRole::Slave(cs_pin) = &mut self.role {
+            cs_pin.clear_interrupt_pending_bit();
+        }
+
+        free_with_muts!(
+            state <- self.bufs.state,
+            || {
+                if received == FRAME_LEN {
+                    self.take_rx_frame(state);
+                    state.tx_in_flight = false;
+                } else if received > 0 {
+                    dev_warn!("Discarded SPI transaction cut short ({} of {} bytes)", received, FRAME_LEN);
+                }
+                self.arm_slave_transaction(state);
+            }
+        );
+    }
+}
+
+impl<
+    SPI: Instance,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8,
+    const FRAME_LEN: usize,
+    const FRAMES: usize,
+> SpiDmaBusIsr<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>
+where
+    ChannelX<DMA_TX_CH>: Channel,
+    ChannelX<DMA_RX_CH>: Channel,
+    SPI: DMASet<TxStream, DMA_TX_CH, MemoryToPeripheral>,
+    SPI: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>,
+    SPI::Nss: ExtiPin,
+{
+    /// The handler of the interrupt of the RX stream, on the master.
+    #[inline(always)]
+    pub fn handle_dma_intr(&mut self) {
+        self.bus.handle_dma_intr();
+    }
+
+    /// The handler of the interrupt of the chip select pin, on the slave.
+    #[inline(always)]
+    pub fn handle_exti_intr(&mut self) {
+        self.bus.handle_exti_intr();
+    }
+}
+
+impl<
+    SPI: Instance,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8,
+    const FRAME_LEN: usize,
+    const FRAMES: usize,
+> BusWrite for SpiDmaBus<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>
+where
+    ChannelX<DMA_TX_CH>: Channel,
+    ChannelX<DMA_RX_CH>: Channel,
+    SPI: DMASet<TxStream, DMA_TX_CH, MemoryToPeripheral>,
+    SPI: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>,
+    SPI::Nss: ExtiPin,
+{
+    /// Queues the buffer for the next transaction. Returns
+    /// [`BusTransferError::WouldBlock`] if the previous frame is still
+    /// waiting for it.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is longer than `FRAME_LEN - 1` bytes.
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        assert!(buf.len() <= FRAME_LEN - SPI_FRAME_HEADER_LEN, "Frame doesn't fit in a SPI transaction");
+        let is_master = self.is_master();
+        free_with_muts!(
+            state <- self.bufs.state,
+            || {
+                if state.tx_pending {
+                    return Err(BusTransferError::WouldBlock);
+                }
+
+                state.tx_next.data[..buf.len()].copy_from_slice(buf);
+                state.tx_next.len = buf.len();
+                state.tx_pending = true;
+                if is_master {
+                    self.start_master_transaction(state);
+                }
+                Ok(())
+            }
+        )
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        free_with_muts!(
+            state <- self.bufs.state,
+            || {
+                state.tx_pending
+            }
+        )
+    }
+}
+
+impl<
+    SPI: Instance,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8,
+    const FRAME_LEN: usize,
+    const FRAMES: usize,
+> BusRead for SpiDmaBus<SPI, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH, FRAME_LEN, FRAMES>
+where
+    ChannelX<DMA_TX_CH>: Channel,
+    ChannelX<DMA_RX_CH>: Channel,
+    SPI: DMASet<TxStream, DMA_TX_CH, MemoryToPeripheral>,
+    SPI: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>,
+    SPI::Nss: ExtiPin,
+{
+    /// Reads the oldest frame received. If it doesn't fit in the buffer, the
+    /// frame is discarded and [`BusPollError::BufferOverflow`] is returned.
+    /// On the master, it also starts an empty transaction if the line is
+    /// idle, so that the slave can send its frames.
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        let is_master = self.is_master();
+        free_with_muts!(
+            state <- self.bufs.state,
+            || {
+                if is_master && !state.in_transaction {
+                    // SAFETY: No transaction is in progress, so nothing else
+                    // is using the streams.
+                    let mut bus = unsafe { self.isr_handle() };
+                    bus.start_master_transaction(state);
+                }
+
+                let frame = state.rx_frames.peek_first().ok_or(BusPollError::WouldBlock)?;
+                let len = frame.len;
+                let res = if len > buf.len() {
+                    Err(BusPollError::BufferOverflow)
+                } else {
+                    buf[..len].copy_from_slice(&frame.data[..len]);
+                    Ok(len as u16)
+                };
+                state.rx_frames.drop_first(1);
+                res
+            }
+        )
+    }
+}
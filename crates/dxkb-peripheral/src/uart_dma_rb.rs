@@ -8,7 +8,7 @@ use core::mem;
 use cortex_m::interrupt::Mutex;
 use dxkb_common::dev_trace;
 use dxkb_common::{
-    bus::{BusPollError, BusRead, BusTransferError, BusWrite}, dev_info, dev_warn
+    bus::{BusBaudRateError, BusPollError, BusRead, BusTransferError, BusWrite}, dev_info, dev_warn
 };
 use enumflags2::{BitFlag, BitFlags};
 use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
@@ -16,7 +16,7 @@ use stm32f4xx_hal::gpio::{Edge, ExtiPin};
 use stm32f4xx_hal::pac::EXTI;
 use stm32f4xx_hal::syscfg::SysCfg;
 use stm32f4xx_hal::Ptr;
-use stm32f4xx_hal::dma::{DmaChannel, DmaEvent, DmaFlag, MemoryToPeripheral};
+use stm32f4xx_hal::dma::{DmaChannel, DmaEvent, MemoryToPeripheral};
 use stm32f4xx_hal::dma::traits::DMASet;
 use stm32f4xx_hal::pac::usart1::RegisterBlock;
 use stm32f4xx_hal::serial::{CFlag, Flag, Instance, RxISR};
@@ -139,6 +139,52 @@ impl<const BUF_LEN: usize, const MAX_FRAME_COUNT: usize>
     }
 }
 
+/// Max number of transfers refused after a collision on a [`HalfDuplex`]
+/// line. The actual number is picked at random, so that both sides don't
+/// collide again.
+const MAX_COLLISION_BACKOFF: u32 = 16;
+
+/// The state of a [`HalfDuplex`] line, shared with its interrupt handlers.
+pub struct HalfDuplexLineState {
+    /// The "Clear To Send" flag.
+    cts: bool,
+
+    /// The address and the length of the frame being sent, which is
+    /// received back as it goes out, for detecting collisions. Zero length
+    /// if none.
+    echo_addr: usize,
+    echo_len: usize,
+
+    /// Number of frames whose echo didn't match, and the number of transfers
+    /// left to be refused after the last one.
+    collisions: u32,
+    backoff: u32,
+    rng: u32,
+}
+
+impl HalfDuplexLineState {
+    const fn new() -> Self {
+        Self {
+            cts: true,
+            echo_addr: 0,
+            echo_len: 0,
+            collisions: 0,
+            backoff: 0,
+            rng: 1,
+        }
+    }
+
+    fn on_collision(&mut self) {
+        // Xorshift32, which is random enough for this.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.collisions = self.collisions.wrapping_add(1);
+        self.backoff = 1 + self.rng % MAX_COLLISION_BACKOFF;
+        dev_trace!("Collision on half-duplex line. Backing off {} transfers", self.backoff);
+    }
+}
+
 pub struct DmaRingBuffer<const BUF_LEN: usize, const MAX_RB_FRAG_COUNT: usize> {
     /// The circular buffer to which the DMA will write the received bytes.
     buf: [u8; BUF_LEN],
@@ -150,6 +196,10 @@ pub struct DmaRingBuffer<const BUF_LEN: usize, const MAX_RB_FRAG_COUNT: usize> {
     // Using an unsafe cell here for avoiding the overload of a
     // RefCell on an interrupt context. Use it with care.
     write_side: Mutex<UnsafeCell<DmaRingBufferWriteSide<BUF_LEN, MAX_RB_FRAG_COUNT>>>,
+
+    /// The state of a [`HalfDuplex`] line. It lives here, since it is shared
+    /// with the interrupt handlers of the line as well.
+    line: Mutex<UnsafeCell<HalfDuplexLineState>>,
 }
 
 impl<const BUF_LEN: usize, const MAX_FRAME_COUNT: usize> DmaRingBuffer<BUF_LEN, MAX_FRAME_COUNT> {
@@ -161,9 +211,17 @@ impl<const BUF_LEN: usize, const MAX_FRAME_COUNT: usize> DmaRingBuffer<BUF_LEN,
                 frames: ConstGenericRingBuffer::new(),
                 current_frame_begin_off: 0,
             })),
+            line: Mutex::new(UnsafeCell::new(HalfDuplexLineState::new())),
         }
     }
 
+    /// Returns whether the frame being received is the same as `echo`.
+    fn current_frame_eq(&self, side: &DmaRingBufferWriteSide<BUF_LEN, MAX_FRAME_COUNT>, ndt: u16, echo: &[u8]) -> bool {
+        let begin_off = side.current_frame_begin_off;
+        let len = DmaRingBufferWriteSide::<BUF_LEN, MAX_FRAME_COUNT>::current_frame_length(ndt, begin_off);
+        len == echo.len() && echo.iter().enumerate().all(|(i, byte)| self.buf[(begin_off + i) % BUF_LEN] == *byte)
+    }
+
     #[inline]
     fn copy_next_read_buffer_bytes(&self, read_off: usize, buf: &mut [u8]) {
         // buf cannot be greater than the internal circular buffer size!
@@ -179,7 +237,9 @@ impl<const BUF_LEN: usize, const MAX_FRAME_COUNT: usize> DmaRingBuffer<BUF_LEN,
         }
     }
 
-    pub fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+    /// Dequeues the next frame that fits in `max_len` bytes, skipping the
+    /// discarded sections, and returns its offset and length in `buf`.
+    fn dequeue_next_frame(&self, max_len: usize) -> Result<(usize, u16), BusPollError> {
         loop {
             let (read_off, section) = free_with_muts!(
             side <- self.write_side,
@@ -198,11 +258,11 @@ impl<const BUF_LEN: usize, const MAX_FRAME_COUNT: usize> DmaRingBuffer<BUF_LEN,
             })
             .ok_or(BusPollError::WouldBlock)?;
 
-            if section.len as usize > buf.len() {
+            if section.len as usize > max_len {
                 dev_warn!(
                     "Discarded frame that is greater than the rx buffer ({} > {})",
                     section.len,
-                    buf.len()
+                    max_len
                 );
                 // In this case, we drop the frame since it is
                 // unlikely that the caller will be able to handle it
@@ -216,12 +276,50 @@ impl<const BUF_LEN: usize, const MAX_FRAME_COUNT: usize> DmaRingBuffer<BUF_LEN,
                 }
                 RbSectionKind::Frame => {
                     dev_trace!("Polled frame: {}", section.len);
-                    self.copy_next_read_buffer_bytes(read_off, &mut buf[0..section.len as usize]);
-                    return Ok(section.len);
+                    return Ok((read_off, section.len));
                 }
             }
         }
     }
+
+    pub fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        let (read_off, len) = self.dequeue_next_frame(buf.len())?;
+        self.copy_next_read_buffer_bytes(read_off, &mut buf[0..len as usize]);
+        Ok(len)
+    }
+
+    /// Hands the next frame to `f` straight from the ring buffer, unless it
+    /// wraps around its end, in which case it is copied into `scratch` first.
+    /// The frame is read the same way as [`Self::poll_next`] does, so it is
+    /// as safe from being overwritten by the DMA while `f` runs as the copy
+    /// would be.
+    pub fn poll_next_ref<R, F: FnOnce(&[u8]) -> R>(&self, scratch: &mut [u8], f: F) -> Result<R, BusPollError> {
+        let (read_off, len) = self.dequeue_next_frame(scratch.len())?;
+        let len = len as usize;
+        if read_off + len <= BUF_LEN {
+            Ok(f(&self.buf[read_off..read_off + len]))
+        } else {
+            self.copy_next_read_buffer_bytes(read_off, &mut scratch[0..len]);
+            Ok(f(&scratch[0..len]))
+        }
+    }
+}
+
+/// The baud rate of the lines whose initializer doesn't set any other.
+pub const DEFAULT_BAUD_RATE: u32 = 2_000_000;
+
+/// The max deviation of the actual baud rate from the requested one, in
+/// permille. The USART needs both sides to be within a few percent of each
+/// other for sampling the bits right.
+const MAX_BAUD_RATE_ERROR_PERMILLE: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaudRateError {
+    /// The baud rate can't be derived from the clock of the USART, or not
+    /// accurately enough.
+    Unreachable,
+    /// The line is transferring a frame.
+    Busy,
 }
 
 pub struct UsartConfig {
@@ -239,7 +337,7 @@ pub trait UsartExt {
     fn tx_set_enabled(&self, enabled: bool);
     fn rx_set_enabled(&self, enabled: bool);
     fn set_error_interrupt_enable(&self, enable: bool);
-
+    fn set_baud_rate(&self, baud_rate: u32, clocks: &Clocks) -> Result<(), BaudRateError>;
 }
 
 pub trait HandleExtiIntr {
@@ -253,23 +351,47 @@ pub trait HandleDmaIntr {
 pub trait UartLineModeInit {
     type Mode;
 
-    fn init(self, rx_buf: &[u8], clocks: &Clocks) -> Self::Mode;
+    fn init(self, rx_buf: &[u8], line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>, clocks: &Clocks) -> Self::Mode;
+
+    /// The baud rate the line is initialized with.
+    fn baud_rate(&self) -> u32;
 }
 
-pub trait UartLineMode where Serial<Self::Usart, u8>: Listen<Event = Event> + ReadFlags<Flag = Flag> + ClearFlags<Flag = CFlag> {
+/// What the interrupt handlers of a line need from it, implemented both by
+/// the line itself and by the handle returned by [`UartLineMode::isr_handle`].
+pub trait UartLineIsr {
     type Usart: Instance<RB = RegisterBlock>;
-    type DmaTxStream: Stream + StreamISR;
     type DmaRxStream: Stream + StreamISR;
 
     fn usart(&self) -> &Self::Usart;
-
-    fn dma_tx_stream(&self) -> &Self::DmaTxStream;
     fn dma_rx_stream(&self) -> &Self::DmaRxStream;
     fn handle_usart_intr(&mut self, flags: BitFlags<Flag>);
+}
+
+pub trait UartLineMode: UartLineIsr where Serial<Self::Usart, u8>: Listen<Event = Event> + ReadFlags<Flag = Flag> + ClearFlags<Flag = CFlag> {
+    type DmaTxStream: Stream + StreamISR;
+    type Isr: UartLineIsr;
+
+    fn dma_tx_stream(&self) -> &Self::DmaTxStream;
 
     fn transfer(&mut self, data_buf: &[u8], tx_buf: &mut [u8]) -> Result<(), BusTransferError>;
     fn is_tx_busy(&self) -> bool;
 
+    /// Returns another handle to the same line, for its interrupt handlers.
+    ///
+    /// Implementations must build the handle only from the register tokens
+    /// of the peripherals of the line, copied with [`copy_register_token`],
+    /// and from the state shared with the line through a `&'static`
+    /// critical section, so that both handles see the same line. Anything
+    /// else owned by the line, like its pins or the state kept outside a
+    /// critical section, must never end up in the handle.
+    ///
+    /// # Safety
+    /// The returned handle accesses the same registers as the line, so it
+    /// must only be used for handling the interrupts of the line, and only
+    /// one handle can be made for each line.
+    unsafe fn isr_handle(&self) -> Self::Isr;
+
 //    fn intr_handle_tx_completed(&sef);
 
 
@@ -287,7 +409,8 @@ impl<T> UsartExt for T where T: Instance + Ptr<RB = RegisterBlock> {
         }
 
         let pclk_freq = T::clock(clocks).raw();
-        let (over8, div) = calculate_brr(pclk_freq, config.baud_rate);
+        let (over8, div) = calculate_brr(pclk_freq, config.baud_rate)
+            .expect("The baud rate can't be derived from the USART clock");
 
         self.brr().write(|w| unsafe { w.bits(div) });
 
@@ -370,10 +493,27 @@ impl<T> UsartExt for T where T: Instance + Ptr<RB = RegisterBlock> {
             w.eie().bit(enable)
         });
     }
+
+    fn set_baud_rate(&self, baud_rate: u32, clocks: &Clocks) -> Result<(), BaudRateError> {
+        let (over8, div) = calculate_brr(T::clock(clocks).raw(), baud_rate)?;
+
+        // The oversampling can only be changed while the USART is disabled.
+        self.cr1().modify(|_, w| w.ue().clear_bit());
+        self.brr().write(|w| unsafe { w.bits(div) });
+        self.cr1().modify(|_, w| w.over8().bit(over8).ue().set_bit());
+        Ok(())
+    }
 }
 
 
-fn calculate_brr(pclk_freq: u32, baud: u32) -> (bool, u32) {
+/// Returns the OVER8 bit and the value of the BRR register for the given
+/// baud rate, failing if it can't be derived accurately enough from the clock
+/// of the USART.
+fn calculate_brr(pclk_freq: u32, baud: u32) -> Result<(bool, u32), BaudRateError> {
+    if baud == 0 {
+        return Err(BaudRateError::Unreachable);
+    }
+
     // The frequency to calculate USARTDIV is this:
     //
     // (Taken from STM32F411xC/E Reference Manual,
@@ -398,7 +538,7 @@ fn calculate_brr(pclk_freq: u32, baud: u32) -> (bool, u32) {
     // right one bit
 
     // Calculate correct baudrate divisor on the fly
-    if (pclk_freq / 16) >= baud {
+    let (over8, div, actual) = if (pclk_freq / 16) >= baud {
         // We have the ability to oversample to 16 bits, take
         // advantage of it.
         //
@@ -406,26 +546,34 @@ fn calculate_brr(pclk_freq: u32, baud: u32) -> (bool, u32) {
         // rounding of values to the closest scale, rather than the
         // floored behavior of normal integer division.
         let div = (pclk_freq + (baud / 2)) / baud;
-        (false, div)
+        (false, div, pclk_freq as u64 / div as u64)
     } else if (pclk_freq / 8) >= baud {
         // We are close enough to pclk where we can only
         // oversample 8.
         //
         // See note above regarding `baud` and rounding.
         let div = ((pclk_freq * 2) + (baud / 2)) / baud;
+        let actual = pclk_freq as u64 * 2 / div as u64;
 
         // Ensure the the fractional bits (only 3) are
         // right-aligned.
         let frac = div & 0xF;
         let div = (div & !0xF) | (frac >> 1);
-        (true, div)
+        (true, div, actual)
     } else {
-        // TODO Better errors?
-        panic!("Invalid config");
+        return Err(BaudRateError::Unreachable);
+    };
+
+    // The mantissa of the divisor only has 12 bits, which limits the lowest
+    // baud rate.
+    if div > 0xFFFF || actual.abs_diff(baud as u64) * 1000 > baud as u64 * MAX_BAUD_RATE_ERROR_PERMILLE {
+        return Err(BaudRateError::Unreachable);
     }
+
+    Ok((over8, div))
 }
 
-fn setup_dma_for_rx<S: StreamISR + Stream>(s: &mut S, numtx: u16, ch: DmaChannel, buf: *const u8, peri_addr: u32) {
+pub(crate) fn setup_dma_for_rx<S: StreamISR + Stream>(s: &mut S, numtx: u16, ch: DmaChannel, buf: *const u8, peri_addr: u32) {
     unsafe { s.disable() };
     s.set_number_of_transfers(numtx);
     s.set_channel(ch);
@@ -450,7 +598,7 @@ fn setup_dma_for_rx<S: StreamISR + Stream>(s: &mut S, numtx: u16, ch: DmaChannel
     s.set_peripheral_burst(BurstMode::NoBurst);
 }
 
-fn setup_dma_for_tx<S: StreamISR + Stream>(s: &mut S, ch: DmaChannel, peri_addr: u32) {
+pub(crate) fn setup_dma_for_tx<S: StreamISR + Stream>(s: &mut S, ch: DmaChannel, peri_addr: u32, priority: dma::config::Priority) {
     unsafe {
         s.disable();
     }
@@ -459,7 +607,7 @@ fn setup_dma_for_tx<S: StreamISR + Stream>(s: &mut S, ch: DmaChannel, peri_addr:
     s.set_direction(MemoryToPeripheral::direction());
     s.set_peripheral_address(peri_addr);
     s.clear_all_flags();
-    s.set_priority(dma::config::Priority::High);
+    s.set_priority(priority);
     unsafe {
         s.set_memory_size(dma::DmaDataSize::Byte);
         s.set_peripheral_size(dma::DmaDataSize::Byte);
@@ -480,6 +628,23 @@ fn usart_clear_idle_interrupt<U: Instance + Ptr<RB = RegisterBlock>>(usart: &U)
     let _ = usart.dr().read();
 }
 
+/// Returns another handle to the registers of a peripheral, for the
+/// interrupt handlers of a line. Only zero-sized tokens without `Drop` can be
+/// copied, since anything else may hold state of its own.
+///
+/// # Safety
+/// Both handles access the same registers, so they must never be used for
+/// conflicting operations.
+unsafe fn copy_register_token<T>(token: &T) -> T {
+    const {
+        assert!(
+            mem::size_of::<T>() == 0 && !mem::needs_drop::<T>(),
+            "Only zero-sized register tokens can be copied"
+        );
+    }
+    unsafe { mem::transmute_copy(token) }
+}
+
 fn usart_get_flags<U: Instance + Ptr<RB = RegisterBlock>>(usart: &U) -> BitFlags<Flag> {
     unsafe {
         Flag::from_bits_unchecked(usart.sr().read().bits())
@@ -503,6 +668,7 @@ const DMA_RX_CH: u8> {
     rx_pin: Usart::Rx<PushPull>,
     tx_stream: TxStream,
     rx_stream: RxStream,
+    baud_rate: u32,
 }
 
 impl<
@@ -534,8 +700,15 @@ impl<
             rx_pin: pins.1.into(),
             tx_stream,
             rx_stream,
+            baud_rate: DEFAULT_BAUD_RATE,
         }
     }
+
+    /// Sets the baud rate of the line, instead of [`DEFAULT_BAUD_RATE`].
+    pub fn with_baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
 }
 
 impl<
@@ -553,12 +726,14 @@ impl<
         Usart: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>
 {
     type Mode = FullDuplex<Usart, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH>;
-    fn init(self, rx_buf: &[u8], clocks: &Clocks) -> Self::Mode {
+    fn init(self, rx_buf: &[u8], _line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>, clocks: &Clocks) -> Self::Mode {
+        calculate_brr(Usart::clock(clocks).raw(), self.baud_rate)
+            .expect("The baud rate can't be derived from the USART clock");
         let mut serial: Serial<Usart, u8> = Serial::new(
-            unsafe { mem::transmute_copy(&self.usart) },
+            unsafe { copy_register_token(&self.usart) },
             (self.tx_pin, self.rx_pin),
             Config::default()
-                .baudrate(2000000.bps())
+                .baudrate(self.baud_rate.bps())
                 .parity_none()
                 .stopbits(StopBits::STOP1)
                 .wordlength_8()
@@ -584,7 +759,7 @@ impl<
             usart: self.usart,
         };
 
-        setup_dma_for_tx(&mut ret.tx_stream, <ChannelX<DMA_TX_CH> as Channel>::VALUE, tx_peri_addr);
+        setup_dma_for_tx(&mut ret.tx_stream, <ChannelX<DMA_TX_CH> as Channel>::VALUE, tx_peri_addr, dma::config::Priority::High);
         setup_dma_for_rx(&mut ret.rx_stream, rx_buf.len() as u16, <ChannelX<DMA_RX_CH> as Channel>::VALUE, rx_buf.as_ptr(), rx_peri_addr);
         unsafe {
             ret.rx_stream.enable();
@@ -592,6 +767,10 @@ impl<
         dev_info!("DMA RX enabled on USART line, full-duplex.");
         ret
     }
+
+    fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
 }
 
 /// A serial line in full-duplex mode. This mode requires two wires, tx and rx,
@@ -609,6 +788,31 @@ pub struct FullDuplex<
     rx_stream: RxStream,
 }
 
+impl<
+    Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8
+> UartLineIsr for FullDuplex<Usart, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH>
+{
+    type Usart = Usart;
+    type DmaRxStream = RxStream;
+
+    fn usart(&self) -> &Usart {
+        &self.usart
+    }
+
+    fn dma_rx_stream(&self) -> &Self::DmaRxStream {
+        &self.rx_stream
+    }
+
+    #[inline(always)]
+    fn handle_usart_intr(&mut self, _flags: BitFlags<Flag>) {
+        // Nothing to do
+    }
+}
+
 impl<
     Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
     TxStream: Stream + StreamISR + 'static,
@@ -623,22 +827,13 @@ where
     Usart: DMASet<TxStream, DMA_TX_CH, MemoryToPeripheral>,
     Usart: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>
 {
-    type Usart = Usart;
     type DmaTxStream = TxStream;
-    type DmaRxStream = RxStream;
-
-    fn usart(&self) -> &Usart {
-        &self.usart
-    }
+    type Isr = FullDuplexIsr<Usart, RxStream>;
 
     fn dma_tx_stream(&self) -> &Self::DmaTxStream {
         &self.tx_stream
     }
 
-    fn dma_rx_stream(&self) -> &Self::DmaRxStream {
-        &self.rx_stream
-    }
-
     fn transfer(&mut self, data_buf: &[u8], tx_buf: &mut [u8]) -> Result<(), BusTransferError> {
         if self.is_tx_busy() {
             return Err(BusTransferError::WouldBlock);
@@ -664,6 +859,36 @@ where
         self.tx_stream.is_enabled()
     }
 
+    unsafe fn isr_handle(&self) -> Self::Isr {
+        unsafe {
+            FullDuplexIsr {
+                usart: copy_register_token(&self.usart),
+                rx_stream: copy_register_token(&self.rx_stream),
+            }
+        }
+    }
+}
+
+/// The handle of a [`FullDuplex`] line for its interrupt handlers.
+pub struct FullDuplexIsr<Usart: Instance + Ptr<RB = RegisterBlock> + 'static, RxStream: Stream + StreamISR + 'static> {
+    usart: Usart,
+    rx_stream: RxStream,
+}
+
+impl<Usart: Instance + Ptr<RB = RegisterBlock> + 'static, RxStream: Stream + StreamISR + 'static> UartLineIsr
+    for FullDuplexIsr<Usart, RxStream>
+{
+    type Usart = Usart;
+    type DmaRxStream = RxStream;
+
+    fn usart(&self) -> &Usart {
+        &self.usart
+    }
+
+    fn dma_rx_stream(&self) -> &Self::DmaRxStream {
+        &self.rx_stream
+    }
+
     #[inline(always)]
     fn handle_usart_intr(&mut self, _flags: BitFlags<Flag>) {
         // Nothing to do
@@ -680,6 +905,8 @@ const DMA_RX_CH: u8
     txrx_pin: Usart::Tx<PushPull>,
     tx_stream: TxStream,
     rx_stream: RxStream,
+    baud_rate: u32,
+    backoff_seed: u32,
 }
 
 impl<
@@ -721,8 +948,24 @@ impl<
             txrx_pin: txrx_pin.into(),
             tx_stream,
             rx_stream,
+            baud_rate: DEFAULT_BAUD_RATE,
+            backoff_seed: 1,
         }
     }
+
+    /// Sets the baud rate of the line, instead of [`DEFAULT_BAUD_RATE`].
+    pub fn with_baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// Sets the seed of the random backoff after a collision. Both sides
+    /// must use a different one, e.g derived from their unique device ID,
+    /// otherwise they would back off for the same time, and collide again.
+    pub fn with_backoff_seed(mut self, seed: u32) -> Self {
+        self.backoff_seed = seed;
+        self
+    }
 }
 
 impl<
@@ -740,10 +983,18 @@ impl<
         Usart: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>
 {
     type Mode = HalfDuplex<Usart, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH>;
-    fn init(mut self, rx_buf: &[u8], clocks: &Clocks) -> Self::Mode {
+    fn init(mut self, rx_buf: &[u8], line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>, clocks: &Clocks) -> Self::Mode {
+        free_with_muts!(
+            state <- line,
+            || {
+                // Zero would be stuck at zero forever.
+                state.rng = self.backoff_seed | 1;
+            }
+        );
+
         self.usart.setup(&UsartConfig {
             half_duplex: true,
-            baud_rate: 2000000,
+            baud_rate: self.baud_rate,
             dma_tx: true,
             dma_rx: true
         }, clocks);
@@ -751,7 +1002,7 @@ impl<
         self.usart.set_interrupt_mask(Event::Idle);
         self.usart.set_error_interrupt_enable(true);
 
-        setup_dma_for_tx(&mut self.tx_stream, <ChannelX<DMA_TX_CH> as Channel>::VALUE, Usart::peri_address());
+        setup_dma_for_tx(&mut self.tx_stream, <ChannelX<DMA_TX_CH> as Channel>::VALUE, Usart::peri_address(), dma::config::Priority::High);
         setup_dma_for_rx(&mut self.rx_stream, rx_buf.len() as u16, <ChannelX<DMA_RX_CH> as Channel>::VALUE, rx_buf.as_ptr(), Usart::peri_address());
 
         self.tx_stream.listen_only(DmaEvent::TransferComplete);
@@ -768,27 +1019,33 @@ impl<
             txrx_pin: self.txrx_pin,
             tx_stream: self.tx_stream,
             rx_stream: self.rx_stream,
-            cts: Mutex::new(UnsafeCell::new(true)),
+            line,
         };
 
         dev_info!("DMA RX enabled on USART line, half-duplex.");
         ret
     }
+
+    fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
 }
 
 /// A serial line in half-duplex mode. This mode enables bi-directional
 /// communication between the current device and the peer, over a single wire.
 /// When this mode is set, the RX pin becomes internally connected to the TX
-/// pin, and the latter is the only one used for communication. The driver will
-/// take care of toggling the reception on the line while we're transmitting,
-/// for preventing receiving an echo message. For transmitting, the driver
-/// maintains a software-based "Clear To Send" flag, that is cleared when
-/// activity on the line is detected, through a GPIO EXTI pin interrupt, and set
-/// again when an idle character is detected. Note however that this line
-/// arbitration method is fallible, and may lead to race conditions if both
-/// devices decide to start a transmission exactly at the same moment. This
-/// driver does not implement anything for preventing that situation, which
-/// needs to be manually resolved at transport level.
+/// pin, and the latter is the only one used for communication. For
+/// transmitting, the driver maintains a software-based "Clear To Send" flag,
+/// that is cleared when activity on the line is detected, through a GPIO EXTI
+/// pin interrupt, and set again when an idle character is detected. This line
+/// arbitration method is still fallible if both devices decide to start a
+/// transmission exactly at the same moment, so the reception is kept enabled
+/// while transmitting, and the frame received back is compared with the one
+/// sent. If they don't match, both frames collided and are dropped, and the
+/// line refuses a random number of transfers before sending again, so that
+/// both sides don't collide again (see
+/// [`HalfDuplexInitializer::with_backoff_seed`]). The frames lost this way are
+/// left to the transport level to be sent again, as with any other loss.
 pub struct HalfDuplex<Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
 TxStream: Stream + StreamISR + 'static,
 RxStream: Stream + StreamISR + 'static,
@@ -799,7 +1056,32 @@ const DMA_RX_CH: u8
     txrx_pin: Usart::Tx<PushPull>,
     tx_stream: TxStream,
     rx_stream: RxStream,
-    cts: Mutex<UnsafeCell<bool>>
+    line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>
+}
+
+impl<
+    Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static,
+    const DMA_TX_CH: u8,
+    const DMA_RX_CH: u8
+> UartLineIsr for HalfDuplex<Usart, TxStream, RxStream, DMA_TX_CH, DMA_RX_CH>
+{
+    type Usart = Usart;
+    type DmaRxStream = RxStream;
+
+    fn usart(&self) -> &Usart {
+        &self.usart
+    }
+
+    fn dma_rx_stream(&self) -> &Self::DmaRxStream {
+        &self.rx_stream
+    }
+
+    #[inline(always)]
+    fn handle_usart_intr(&mut self, flags: BitFlags<Flag>) {
+        half_duplex_usart_intr(self.line, flags);
+    }
 }
 
 impl<
@@ -816,33 +1098,39 @@ where
     Usart: DMASet<TxStream, DMA_TX_CH, MemoryToPeripheral>,
     Usart: DMASet<RxStream, DMA_RX_CH, PeripheralToMemory>
 {
-    type Usart = Usart;
     type DmaTxStream = TxStream;
-    type DmaRxStream = RxStream;
-
-    fn usart(&self) -> &Usart {
-        &self.usart
-    }
+    type Isr = HalfDuplexIsr<Usart, TxStream, RxStream>;
 
     fn dma_tx_stream(&self) -> &Self::DmaTxStream {
         &self.tx_stream
     }
 
-    fn dma_rx_stream(&self) -> &Self::DmaRxStream {
-        &self.rx_stream
-    }
-
     fn transfer(&mut self, data_buf: &[u8], tx_buf: &mut [u8]) -> Result<(), BusTransferError> {
+        if self.tx_stream.is_enabled() {
+            return Err(BusTransferError::WouldBlock);
+        }
+
         let cts = free_with_muts!(
-            cts <- self.cts,
+            line <- self.line,
             || {
-                let prev = *cts;
-                *cts = false;
-                return prev;
+                if line.backoff > 0 {
+                    line.backoff -= 1;
+                    return false;
+                }
+
+                if !line.cts {
+                    return false;
+                }
+
+                line.cts = false;
+                // The frame is received back as it goes out.
+                line.echo_addr = tx_buf.as_ptr() as usize;
+                line.echo_len = data_buf.len();
+                true
             }
         );
 
-        if !cts || self.tx_stream.is_enabled() {
+        if !cts {
             return Err(BusTransferError::WouldBlock);
         }
 
@@ -854,7 +1142,6 @@ where
         self.tx_stream.set_number_of_transfers(data_buf.len() as u16);
         tx_buf[0..data_buf.len()].copy_from_slice(data_buf);
 
-        self.usart.rx_set_enabled(false);
         unsafe {
             self.tx_stream.enable();
         }
@@ -866,36 +1153,114 @@ where
         // time for the next IDLE signal to be sent, so the peer can detect the
         // previous frame termination.
         let cts = free_with_muts!(
-            cts <- self.cts,
+            line <- self.line,
             || {
-               *cts
+               line.cts
             }
         );
 
         !cts || self.tx_stream.is_enabled()
     }
 
+    unsafe fn isr_handle(&self) -> Self::Isr {
+        unsafe {
+            HalfDuplexIsr {
+                usart: copy_register_token(&self.usart),
+                txrx_pin: copy_register_token(&self.txrx_pin),
+                tx_stream: copy_register_token(&self.tx_stream),
+                rx_stream: copy_register_token(&self.rx_stream),
+                line: self.line,
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn half_duplex_usart_intr(line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>, flags: BitFlags<Flag>) {
+    if flags.contains(Flag::Idle) {
+        // Set CTS to true when we detect that the line is idle. Our own
+        // frames are received back as well, so this happens after
+        // transmitting too.
+        free_with_muts!(
+            line <- line,
+            || {
+                line.cts = true;
+            }
+        );
+    }
+}
+
+#[inline(always)]
+fn half_duplex_exti_intr<P: ExtiPin>(txrx_pin: &mut P, line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>) {
+    txrx_pin.clear_interrupt_pending_bit();
+    free_with_muts!(
+        line <- line,
+        || {
+            line.cts = false;
+        }
+    );
+}
+
+/// The handle of a [`HalfDuplex`] line for its interrupt handlers. The pin
+/// of the line is only used for clearing its EXTI pending bit.
+pub struct HalfDuplexIsr<Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
+TxStream: Stream + StreamISR + 'static,
+RxStream: Stream + StreamISR + 'static
+> {
+    usart: Usart,
+    txrx_pin: Usart::Tx<PushPull>,
+    tx_stream: TxStream,
+    rx_stream: RxStream,
+    line: &'static Mutex<UnsafeCell<HalfDuplexLineState>>
+}
+
+impl<
+    Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static
+> UartLineIsr for HalfDuplexIsr<Usart, TxStream, RxStream>
+{
+    type Usart = Usart;
+    type DmaRxStream = RxStream;
+
+    fn usart(&self) -> &Usart {
+        &self.usart
+    }
+
+    fn dma_rx_stream(&self) -> &Self::DmaRxStream {
+        &self.rx_stream
+    }
+
     #[inline(always)]
     fn handle_usart_intr(&mut self, flags: BitFlags<Flag>) {
-        if flags.contains(Flag::Idle) {
-            // Set CTS to true when we detect that the line is idle.
-            free_with_muts!(
-                cts <- self.cts,
-                || {
-                    *cts = true;
-                }
-            );
-        } else if flags.contains(Flag::TransmissionComplete) {
-            // After the transfer is completed, set CTS and re-enable RX
-            free_with_muts!(
-                cts <- self.cts,
-                || {
-                    *cts = true;
-                }
-            );
-            self.usart.rx_set_enabled(true);
-            self.usart.set_interrupt_mask(Event::Idle);
-        }
+        half_duplex_usart_intr(self.line, flags);
+    }
+}
+
+impl<
+    Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static
+> HandleExtiIntr for HalfDuplexIsr<Usart, TxStream, RxStream>
+where
+    Usart::Tx<PushPull>: ExtiPin
+{
+    #[inline(always)]
+    fn handle_exti_intr(&mut self) {
+        half_duplex_exti_intr(&mut self.txrx_pin, self.line);
+    }
+}
+
+impl<
+    Usart: Instance + Ptr<RB = RegisterBlock> + 'static,
+    TxStream: Stream + StreamISR + 'static,
+    RxStream: Stream + StreamISR + 'static
+> HandleDmaIntr for HalfDuplexIsr<Usart, TxStream, RxStream>
+{
+    #[inline(always)]
+    fn handle_dma_intr(&mut self) {
+        // See the one of the line itself.
+        self.tx_stream.clear_all_flags();
     }
 }
 
@@ -916,13 +1281,7 @@ where
 {
     #[inline(always)]
     fn handle_exti_intr(&mut self) {
-        self.txrx_pin.clear_interrupt_pending_bit();
-        free_with_muts!(
-            cts <- self.cts,
-            || {
-                *cts = false;
-            }
-        );
+        half_duplex_exti_intr(&mut self.txrx_pin, self.line);
     }
 }
 
@@ -942,17 +1301,8 @@ where
 {
     #[inline(always)]
     fn handle_dma_intr(&mut self) {
-        if self.tx_stream.flags().contains(DmaFlag::TransferComplete) {
-            // So at this point the DMA has finished writing all the data into
-            // the DR register from the USART. However, it is likely that it
-            // hasn't been sent out by the hardware. Therefore, to re-enable
-            // USART RX, we need to wait until the last byte has been fully sent
-            // out. For that, we set here the proper interrupt mask to receive
-            // the Transmission Complete interrupt, and there will be where we
-            // re-enable RX.
-            self.usart.set_interrupt_mask(Event::Idle | Event::TransmissionComplete);
-        }
-
+        // Nothing to do until the line is idle again, which is when the frame
+        // has been fully received back.
         self.tx_stream.clear_all_flags();
     }
 }
@@ -964,8 +1314,91 @@ pub struct UartDmaRb<
     const DMA_RX_FRAME_CNT: usize
 > {
     mode: Mode,
-    rx_buf: &'static mut DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>,
+    rx_buf: &'static DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>,
     tx_buf: &'static mut [u8; DMA_TX_BUF_SZ],
+    baud_rate: u32,
+    clocks: Clocks,
+}
+
+/// The interrupt handlers of an [`UartDmaRb`], returned by
+/// [`UartDmaRb::init_split`]. Unlike the bus itself, which is usually owned by
+/// the split bus of the keyboard, this can be kept on its own by the interrupt
+/// handlers of the target, so that they don't need to reach the bus through
+/// the keyboard.
+pub struct UartDmaRbIsr<
+    Mode: UartLineMode,
+    const DMA_RX_BUF_SZ: usize,
+    const DMA_RX_FRAME_CNT: usize
+> {
+    line: Mode::Isr,
+    rx_buf: &'static DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>,
+}
+
+fn handle_usart_intr<Line: UartLineIsr, const DMA_RX_BUF_SZ: usize, const DMA_RX_FRAME_CNT: usize>(
+    mode: &mut Line,
+    rx_buf: &DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>,
+) {
+    let ndt = mode.dma_rx_stream().number_of_transfers();
+
+    let flags = usart_get_flags(mode.usart());
+
+    let error = flags.intersects(Flag::FramingError | Flag::Noise | Flag::Overrun);
+
+    // As for now, any error produced by these functions indicates
+    // a desync between the DMA and the state of the ring buffer,
+    // so for now any error that happened here we consider it fatal.
+
+    // TODO instead of panicking, recover from these errors by
+    // disabling, resetting and re-enabling DMA transfer.
+    if error {
+        // Something weird error have happened while reading the
+        // current frame. We're completely discarding it. If it was our
+        // own frame, received back on a half-duplex line, the peer was
+        // likely transmitting at the same time.
+        free_with_muts!(
+            write_side <- rx_buf.write_side,
+            line <- rx_buf.line,
+            || {
+                write_side.discard_current_frame(ndt).unwrap();
+                if line.echo_len > 0 {
+                    line.echo_len = 0;
+                    line.on_collision();
+                }
+            }
+        );
+    } else if flags.contains(Flag::Idle) {
+        // We consider the current frame has terminated. We push
+        // the final length of the just read frame to the ring
+        // buffer and we reset everything for reading the next
+
+        free_with_muts!(
+            write_side <- rx_buf.write_side,
+            line <- rx_buf.line,
+            || {
+                if line.echo_len > 0 {
+                    // Our own frame, received back on a half-duplex line,
+                    // which is not for us anyway.
+                    let echo = unsafe {
+                        // SAFETY: The TX buffer is not written until the line
+                        // is clear to send again.
+                        core::slice::from_raw_parts(line.echo_addr as *const u8, line.echo_len)
+                    };
+                    if !rx_buf.current_frame_eq(write_side, ndt, echo) {
+                        line.on_collision();
+                    }
+                    line.echo_len = 0;
+                    write_side.discard_current_frame(ndt).unwrap();
+                } else {
+                    write_side.close_current_frame(ndt).unwrap();
+                }
+            }
+        );
+    }
+
+    mode.handle_usart_intr(flags);
+
+    // This also clears all the error flags
+    usart_clear_idle_interrupt(mode.usart());
 }
 
 impl<
@@ -982,59 +1415,79 @@ where Mode: UartLineMode
         rx_buf: &'static mut DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>,
         clocks: &Clocks
     ) -> Self {
+        let rx_buf: &'static DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT> = rx_buf;
+        let baud_rate = mode_initializer.baud_rate();
         let mode = mode_initializer.init(
             &rx_buf.buf,
+            &rx_buf.line,
             &clocks
         );
 
         Self {
             mode,
             tx_buf,
-            rx_buf
+            rx_buf,
+            baud_rate,
+            clocks: *clocks,
         }
     }
 
+    /// Returns the number of frames that collided with the ones of the peer,
+    /// on a half-duplex line.
+    pub fn collisions(&self) -> u32 {
+        free_with_muts!(
+            line <- self.rx_buf.line,
+            || {
+                line.collisions
+            }
+        )
+    }
 
-    #[inline(always)]
-    pub fn handle_usart_intr(&mut self) {
-        let ndt = self.mode.dma_rx_stream().number_of_transfers();
-
-        let flags = usart_get_flags(self.mode.usart());
+    /// Returns the current baud rate of the line.
+    pub fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
 
-        let error = flags.intersects(Flag::FramingError | Flag::Noise | Flag::Overrun);
+    /// Changes the baud rate of the line, e.g after agreeing on a different
+    /// one with the peer. Fails if the line is transferring a frame, since it
+    /// would be garbled, or if the baud rate can't be derived from the
+    /// clocks the line was initialized with.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), BaudRateError> {
+        if self.mode.is_tx_busy() {
+            return Err(BaudRateError::Busy);
+        }
 
-        // As for now, any error produced by these functions indicates
-        // a desync between the DMA and the state of the ring buffer,
-        // so for now any error that happened here we consider it fatal.
+        self.mode.usart().set_baud_rate(baud_rate, &self.clocks)?;
+        dev_info!("USART baud rate set to {}", baud_rate);
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
 
-        // TODO instead of panicking, recover from these errors by
-        // disabling, resetting and re-enabling DMA transfer.
-        if error {
-            // Something weird error have happened while reading the
-            // current frame. We're completely discarding it.
-            free_with_muts!(
-                write_side <- self.rx_buf.write_side,
-                || {
-                    write_side.discard_current_frame(ndt).unwrap();
-                }
-            );
-        } else if flags.contains(Flag::Idle) {
-            // We consider the current frame has terminated. We push
-            // the final length of the just read frame to the ring
-            // buffer and we reset everything for reading the next
-
-            free_with_muts!(
-                write_side <- self.rx_buf.write_side,
-                || {
-                    write_side.close_current_frame(ndt).unwrap();
-                }
-            );
-        }
 
-        self.mode.handle_usart_intr(flags);
+    /// Like [`init`](Self::init), but also returns the handlers of the
+    /// interrupts of the line, which are meant to be moved to wherever
+    /// the interrupt handlers of the target can reach them.
+    pub fn init_split<I: UartLineModeInit<Mode = Mode>>(
+        mode_initializer: I,
+        tx_buf: &'static mut [u8; DMA_TX_BUF_SZ],
+        rx_buf: &'static mut DmaRingBuffer<DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>,
+        clocks: &Clocks
+    ) -> (Self, UartDmaRbIsr<Mode, DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>) {
+        let bus = Self::init(mode_initializer, tx_buf, rx_buf, clocks);
+        let isr = UartDmaRbIsr {
+            line: unsafe {
+                // SAFETY: The handle is only used by the interrupt handlers,
+                // and this is the only one made for the line.
+                bus.mode.isr_handle()
+            },
+            rx_buf: bus.rx_buf,
+        };
+        (bus, isr)
+    }
 
-        // This also clears all the error flags
-        usart_clear_idle_interrupt(self.mode.usart());
+    #[inline(always)]
+    pub fn handle_usart_intr(&mut self) {
+        handle_usart_intr(&mut self.mode, self.rx_buf);
     }
 }
 
@@ -1067,6 +1520,45 @@ where Mode: UartLineMode + HandleDmaIntr
 }
 
 
+impl<
+    Mode,
+    const DMA_RX_BUF_SZ: usize,
+    const DMA_RX_FRAME_CNT: usize
+> UartDmaRbIsr<Mode, DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>
+where Mode: UartLineMode
+{
+    #[inline(always)]
+    pub fn handle_usart_intr(&mut self) {
+        handle_usart_intr(&mut self.line, self.rx_buf);
+    }
+}
+
+impl<
+    Mode,
+    const DMA_RX_BUF_SZ: usize,
+    const DMA_RX_FRAME_CNT: usize
+> UartDmaRbIsr<Mode, DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>
+where Mode: UartLineMode, Mode::Isr: HandleExtiIntr
+{
+    #[inline(always)]
+    pub fn handle_exti_intr(&mut self) {
+        self.line.handle_exti_intr();
+    }
+}
+
+impl<
+    Mode,
+    const DMA_RX_BUF_SZ: usize,
+    const DMA_RX_FRAME_CNT: usize
+> UartDmaRbIsr<Mode, DMA_RX_BUF_SZ, DMA_RX_FRAME_CNT>
+where Mode: UartLineMode, Mode::Isr: HandleDmaIntr
+{
+    #[inline(always)]
+    pub fn handle_dma_intr(&mut self) {
+        self.line.handle_dma_intr();
+    }
+}
+
 impl<
     Mode,
     const DMA_TX_BUF_SZ: usize,
@@ -1084,6 +1576,17 @@ where
     fn is_tx_busy(&self) -> bool {
         self.mode.is_tx_busy()
     }
+
+    fn baud_rate(&self) -> Option<u32> {
+        Some(self.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), BusBaudRateError> {
+        UartDmaRb::set_baud_rate(self, baud_rate).map_err(|e| match e {
+            BaudRateError::Unreachable => BusBaudRateError::Unreachable,
+            BaudRateError::Busy => BusBaudRateError::Busy,
+        })
+    }
 }
 
 impl<
@@ -1098,4 +1601,8 @@ where
     fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
         self.rx_buf.poll_next(buf)
     }
+
+    fn poll_next_ref<R, F: FnOnce(&[u8]) -> R>(&self, scratch: &mut [u8], f: F) -> Result<R, BusPollError> {
+        self.rx_buf.poll_next_ref(scratch, f)
+    }
 }
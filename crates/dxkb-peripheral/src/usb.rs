@@ -36,3 +36,24 @@ impl<'a, B: UsbBus> UsbRemoteWakeup for UsbDevice<'a, B> {
         }
     }
 }
+
+pub trait UsbSoftDisconnect {
+    /**
+     * Detaches the device from the bus, as if it was unplugged, or attaches it
+     * back. The host only notices the device again after it has been detached
+     * for a few milliseconds.
+     */
+    fn set_soft_disconnect(&mut self, disconnect: bool);
+}
+
+#[cfg(feature = "stm32f411")]
+impl<'a, B: UsbBus> UsbSoftDisconnect for UsbDevice<'a, B> {
+    fn set_soft_disconnect(&mut self, disconnect: bool) {
+        use stm32f4xx_hal::pac::OTG_FS_DEVICE;
+        unsafe {
+            OTG_FS_DEVICE::steal().dctl().modify(|_, w| {
+                w.sdis().bit(disconnect)
+            });
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Supply voltage measurement, for halves of the keyboard that are powered
+//! independently (e.g from a battery).
+
+/// Something able to measure the supply voltage of the keyboard.
+pub trait SupplyVoltageSensor {
+    fn read_millivolts(&mut self) -> u16;
+}
+
+#[cfg(feature = "stm32f411")]
+mod adc {
+    use stm32f4xx_hal::{adc::{Adc, config::{AdcConfig, SampleTime}}, hal_02::adc::Channel, pac::ADC1};
+
+    use super::SupplyVoltageSensor;
+
+    /// Measures the supply voltage through an ADC1 pin, connected to the
+    /// supply through a resistor divider so the voltage in the pin never goes
+    /// above VDDA.
+    pub struct AdcSupplyVoltage<PIN: Channel<ADC1, ID = u8>> {
+        adc: Adc<ADC1>,
+        pin: PIN,
+        divider_top_ohms: u32,
+        divider_bottom_ohms: u32,
+    }
+
+    impl<PIN: Channel<ADC1, ID = u8>> AdcSupplyVoltage<PIN> {
+        /// Creates a new sensor for a pin that is between the resistors
+        /// `divider_top_ohms` (to the supply) and `divider_bottom_ohms` (to
+        /// ground) of a divider. The pin must be already in analog mode.
+        pub fn new(adc: ADC1, pin: PIN, divider_top_ohms: u32, divider_bottom_ohms: u32) -> Self {
+            Self {
+                adc: Adc::adc1(adc, true, AdcConfig::default()),
+                pin,
+                divider_top_ohms,
+                divider_bottom_ohms,
+            }
+        }
+    }
+
+    impl<PIN: Channel<ADC1, ID = u8>> SupplyVoltageSensor for AdcSupplyVoltage<PIN> {
+        fn read_millivolts(&mut self) -> u16 {
+            // The supply is expected to change slowly, so use the longest
+            // sample time for reducing the noise as much as possible.
+            let sample = self.adc.convert(&self.pin, SampleTime::Cycles_480);
+            let pin_mv = self.adc.sample_to_millivolts(sample) as u32;
+            let mv = pin_mv * (self.divider_top_ohms + self.divider_bottom_ohms) / self.divider_bottom_ohms;
+            mv.min(u16::MAX as u32) as u16
+        }
+    }
+}
+
+#[cfg(feature = "stm32f411")]
+pub use adc::AdcSupplyVoltage;
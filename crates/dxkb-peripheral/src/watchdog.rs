@@ -0,0 +1,71 @@
+//! The independent watchdog (IWDG) of the MCU, which runs from the LSI clock,
+//! so it keeps counting even if the main clocks or the core are hung, and in
+//! Stop mode. Once started, it can only be stopped by a reset.
+
+use core::time::Duration;
+
+use dxkb_common::{dev_info, watchdog::Watchdog};
+use stm32f4xx_hal::pac::IWDG;
+
+/// Nominal frequency of the LSI clock the watchdog runs from. The actual one
+/// varies between 17 and 47 kHz, so the timeouts are only approximate.
+const LSI_FREQ_HZ: u64 = 32_000;
+
+/// Values written to the key register for unlocking the prescaler and reload
+/// registers, for reloading the counter and for starting the watchdog.
+const KR_UNLOCK: u16 = 0x5555;
+const KR_RELOAD: u16 = 0xaaaa;
+const KR_START: u16 = 0xcccc;
+
+/// Max value of the reload register, which is 12 bits long.
+const MAX_RELOAD: u64 = 0xfff;
+
+/// Max value of the prescaler register, for a divider of 256.
+const MAX_PRESCALER: u8 = 6;
+
+pub struct IndependentWatchdog {
+    iwdg: IWDG,
+}
+
+impl IndependentWatchdog {
+    /// Starts the watchdog, so that it resets the MCU unless it is fed at
+    /// least once every `timeout`, up to about 32 seconds.
+    pub fn start(iwdg: IWDG, timeout: Duration) -> Self {
+        let (prescaler, reload) = Self::timing(timeout);
+
+        iwdg.kr().write(|w| unsafe { w.key().bits(KR_START) });
+        iwdg.kr().write(|w| unsafe { w.key().bits(KR_UNLOCK) });
+        iwdg.pr().write(|w| w.pr().bits(prescaler));
+        iwdg.rlr().write(|w| w.rl().bits(reload));
+        // The new values take effect once they reach the LSI domain.
+        while iwdg.sr().read().pvu().bit_is_set() || iwdg.sr().read().rvu().bit_is_set() {}
+        iwdg.kr().write(|w| unsafe { w.key().bits(KR_RELOAD) });
+
+        dev_info!("Watchdog started with a timeout of {:?}", timeout);
+        Self { iwdg }
+    }
+
+    /// Returns the prescaler and reload values for the given timeout, with the
+    /// smallest divider that fits it, for the best resolution.
+    fn timing(timeout: Duration) -> (u8, u16) {
+        let ticks = timeout.as_micros() as u64 * LSI_FREQ_HZ / 1_000_000;
+        for prescaler in 0..=MAX_PRESCALER {
+            let reload = ticks / (4 << prescaler);
+            if reload <= MAX_RELOAD {
+                return (prescaler, reload.max(1) as u16);
+            }
+        }
+        (MAX_PRESCALER, MAX_RELOAD as u16)
+    }
+
+    /// Reloads the counter of the watchdog.
+    pub fn feed(&self) {
+        self.iwdg.kr().write(|w| unsafe { w.key().bits(KR_RELOAD) });
+    }
+}
+
+impl Watchdog for IndependentWatchdog {
+    fn feed(&self) {
+        IndependentWatchdog::feed(self)
+    }
+}
@@ -0,0 +1,164 @@
+//! Driver for strips of WS2812 LEDs, driven through the MOSI line of a SPI
+//! peripheral, with its TX DMA stream.
+//!
+//! The SPI must run at 3 MHz, so that each bit sent to the LEDs is encoded as
+//! four SPI bits of 333 ns: `1000` for a zero, and `1100` for a one. The data
+//! of the whole strip is sent in a single DMA transfer, since the LEDs latch
+//! their color as soon as the line stays low for a while, so a refresh cannot
+//! be split in several transfers. Instead, refreshes are time sliced with a
+//! [`DmaTimeSlicer`], so that they never overlap with the transfers of the
+//! split link.
+//!
+//! The line stays low between refreshes, which is what makes the LEDs latch
+//! the new colors. The slicer must not allow a refresh sooner than
+//! [`WS2812_MIN_REFRESH_PERIOD`] after the previous one.
+
+use core::time::Duration;
+
+use dxkb_common::time::Clock;
+use stm32f4xx_hal::{
+    dma::{
+        self, ChannelX, MemoryToPeripheral,
+        traits::{Channel, DMASet, PeriAddress, Stream, StreamISR},
+    },
+    spi::{Instance, Tx},
+};
+
+use crate::{dma::DmaTimeSlicer, uart_dma_rb::setup_dma_for_tx};
+
+/// The bytes sent through the SPI for each LED: 24 bits of color, 4 SPI bits
+/// each.
+pub const WS2812_BYTES_PER_LED: usize = 12;
+
+/// The minimum time between two refreshes, for the line to be low long
+/// enough for the LEDs to latch the previous one. Older LEDs need 50 us,
+/// but newer ones need up to 280 us.
+pub const WS2812_MIN_REFRESH_PERIOD: Duration = Duration::from_micros(300);
+
+const SPI_BITS_ZERO: u8 = 0b1000;
+const SPI_BITS_ONE: u8 = 0b1100;
+
+/// Returns the length of the DMA buffer needed for a strip of `leds` LEDs. A
+/// trailing zero byte makes sure the line is left low after each refresh.
+pub const fn ws2812_buf_len(leds: usize) -> usize {
+    leds * WS2812_BYTES_PER_LED + 1
+}
+
+pub struct Ws2812<SPI: Instance + 'static, S: Stream + StreamISR + 'static, const DMA_CH: u8, const LEDS: usize>
+where
+    [(); ws2812_buf_len(LEDS)]:,
+{
+    _tx: Tx<SPI>,
+    stream: S,
+    buf: &'static mut [u8; ws2812_buf_len(LEDS)],
+    /// The colors of each LED, in the order the LEDs expect them: green, red
+    /// and blue.
+    grb: [[u8; 3]; LEDS],
+    /// The brightness every color is scaled by when sent, out of 255.
+    brightness: u8,
+    /// Whether any color changed since the last refresh.
+    dirty: bool,
+}
+
+impl<SPI: Instance + 'static, S: Stream + StreamISR + 'static, const DMA_CH: u8, const LEDS: usize> Ws2812<SPI, S, DMA_CH, LEDS>
+where
+    [(); ws2812_buf_len(LEDS)]:,
+    ChannelX<DMA_CH>: Channel,
+    Tx<SPI>: DMASet<S, DMA_CH, MemoryToPeripheral>,
+{
+    const _ASSERT_BUF_LEN: () = assert!(
+        ws2812_buf_len(LEDS) <= 65535,
+        "The strip is too long for a single DMA transfer"
+    );
+
+    /// Creates the driver, with every LED off. The stream is set up with a
+    /// low priority, so that the other streams of the same controller take
+    /// precedence when they compete for it.
+    pub fn new(tx: Tx<SPI>, mut stream: S, buf: &'static mut [u8; ws2812_buf_len(LEDS)]) -> Self {
+        let () = Self::_ASSERT_BUF_LEN;
+
+        setup_dma_for_tx(
+            &mut stream,
+            <ChannelX<DMA_CH> as Channel>::VALUE,
+            tx.address(),
+            dma::config::Priority::Low,
+        );
+        buf.fill(0);
+
+        Self {
+            _tx: tx,
+            stream,
+            buf,
+            grb: [[0; 3]; LEDS],
+            brightness: u8::MAX,
+            // The LEDs may be showing anything after a reset.
+            dirty: true,
+        }
+    }
+
+    /// Sets the color of the LED with the given index. Out of range indices
+    /// are ignored. The change is not visible until the next refresh.
+    pub fn set_led(&mut self, led: usize, r: u8, g: u8, b: u8) {
+        if let Some(grb) = self.grb.get_mut(led) {
+            if *grb != [g, r, b] {
+                *grb = [g, r, b];
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Limits the brightness of the whole strip, scaling every color by
+    /// `brightness / 255` when sent, e.g for keeping the current drawn by the
+    /// LEDs within a budget. The change is not visible until the next refresh.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        if self.brightness != brightness {
+            self.brightness = brightness;
+            self.dirty = true;
+        }
+    }
+
+    /// Returns whether a refresh is being sent to the strip.
+    pub fn is_busy(&self) -> bool {
+        self.stream.is_enabled()
+    }
+
+    /// Refreshes the strip if any color changed, and the slicer grants a
+    /// slice for it. `link_idle` tells whether the split link is idle, so
+    /// that the refresh never competes with it. Should be called after each
+    /// poll of the keyboard.
+    pub fn poll<C: Clock>(&mut self, clock: &C, slicer: &mut DmaTimeSlicer<C>, link_idle: bool) {
+        if !self.dirty || self.is_busy() || !slicer.try_begin_slice(clock, link_idle) {
+            return;
+        }
+
+        self.encode();
+        self.dirty = false;
+
+        unsafe {
+            self.stream.disable();
+        }
+        self.stream.clear_all_flags();
+        self.stream.set_memory_address(self.buf.as_ptr() as u32);
+        self.stream.set_number_of_transfers(self.buf.len() as u16);
+        unsafe {
+            self.stream.enable();
+        }
+    }
+
+    fn encode(&mut self) {
+        let bits = |bit: u8| if bit != 0 { SPI_BITS_ONE } else { SPI_BITS_ZERO };
+        let brightness = self.brightness as u16;
+
+        for (led, grb) in self.grb.iter().enumerate() {
+            for (i, byte) in grb.iter().enumerate() {
+                let byte = (*byte as u16 * brightness / u8::MAX as u16) as u8;
+                // Each SPI byte carries two bits of color, MSB first.
+                for j in 0..4 {
+                    let hi = (byte >> (7 - 2 * j)) & 1;
+                    let lo = (byte >> (6 - 2 * j)) & 1;
+                    self.buf[led * WS2812_BYTES_PER_LED + i * 4 + j] = (bits(hi) << 4) | bits(lo);
+                }
+            }
+        }
+    }
+}
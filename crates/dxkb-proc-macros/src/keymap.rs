@@ -0,0 +1,350 @@
+//! Knowledge of the aliases accepted by `dxkb_core::default_key_from_alias!`,
+//! so that unknown keys can be reported by the layers! macro right at the
+//! offending token, with suggestions of similar keys, instead of as an obscure
+//! macro_rules error deep inside dxkb-core.
+//!
+//! The translation itself is still done by the macros in dxkb-core, so the
+//! tables here must be kept in sync with them.
+
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+use syn::Lit;
+
+/// Identifiers matched by the explicit branches of `hid_key_from_alias!`.
+const HID_IDENT_ALIASES: &[&str] = &[
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S",
+    "T", "U", "V", "W", "X", "Y", "Z", "Esc", "LCtl", "RCtl", "LSft", "RSft", "LAlt", "RAlt",
+    "LWin", "RWin", "LGui", "RGui", "Bksp", "Home", "End", "PrScr", "Up", "Down", "Left", "Right",
+    "Insrt", "Del", "Caps", "PgUp", "PgDn", "Spc",
+];
+
+/// Char literals matched by the explicit branches of `hid_key_from_alias!`.
+const HID_CHAR_ALIASES: &[char] = &[
+    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S',
+    'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '`', '\\',
+    ',', '.', '/', '-', '=', '[', ']', '\'', ' ', ';',
+];
+
+/// String literals matched by the explicit branches of `hid_key_from_alias!`.
+const HID_STR_ALIASES: &[&str] = &["'"];
+
+macro_rules! keyboard_usage_names {
+    ($($name:literal),* $(,)?) => {
+        /// Names of the `KeyboardUsage` variants, without the `Keyboard`
+        /// prefix, which are accepted by the fallback branches of
+        /// `hid_key_from_alias!`. The same names are looked up at runtime
+        /// through `dxkb_core::usage_names`, from the same file.
+        const KEYBOARD_USAGE_NAMES: &[&str] = &[$($name),*];
+    };
+}
+
+include!("../../dxkb-core/src/usage_names.in");
+
+/// Shape of the argument taken by a function key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKeyArg {
+    None,
+    /// An absolute layer index, e.g `LPsh(1)`.
+    Layer,
+    /// A signed layer offset, e.g `LRelSet(+1)`.
+    RelativeLayer,
+}
+
+/// Function keys accepted by `function_key_from_alias!`.
+const FUNCTION_KEYS: &[(&str, FunctionKeyArg)] = &[
+    ("LPshNxt", FunctionKeyArg::None),
+    ("LPsh", FunctionKeyArg::Layer),
+    ("LPop", FunctionKeyArg::None),
+    ("LTPshNxt", FunctionKeyArg::None),
+    ("LTPsh", FunctionKeyArg::Layer),
+    ("LSet", FunctionKeyArg::Layer),
+    ("LRelSet", FunctionKeyArg::RelativeLayer),
+    ("LTRelSet", FunctionKeyArg::RelativeLayer),
+    ("Prsnc", FunctionKeyArg::None),
+    ("Game", FunctionKeyArg::None),
+    ("SwpCG", FunctionKeyArg::None),
+    ("SwpAG", FunctionKeyArg::None),
+    ("CapsCtl", FunctionKeyArg::None),
+];
+
+/// Variants of `dxkb_core::steno::StenoKey`, accepted after `s:`.
+const STENO_KEYS: &[&str] = &[
+    "Fn", "N1", "N2", "N3", "N4", "N5", "N6", "S1", "S2", "TL", "KL", "PL", "WL", "HL", "RL", "A",
+    "O", "St1", "St2", "Res1", "Res2", "Pwr", "St3", "St4", "E", "U", "FR", "RR", "PR", "BR", "LR",
+    "GR", "TR", "SR", "DR", "N7", "N8", "N9", "NA", "NB", "NC", "ZR",
+];
+
+/// Returns the candidates that are close enough to `name` for being suggested,
+/// closest first.
+fn near_matches<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    const MAX_SUGGESTIONS: usize = 3;
+
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() + 2) / 3;
+    let mut matches = candidates
+        .map(|candidate| (strsim::levenshtein(&name, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+
+    matches.sort();
+    matches.dedup_by(|a, b| a.1 == b.1);
+    matches
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+fn unknown_key_error(span: Span, what: &str, key: &str, suggestions: &[&str]) -> syn::Error {
+    let mut message = format!("Unknown {} `{}`", what, key);
+    if let Some((last, rest)) = suggestions.split_last() {
+        message.push_str(". Did you mean ");
+        for s in rest {
+            message.push_str(&format!("`{}`, ", s));
+        }
+        if !rest.is_empty() {
+            message.truncate(message.len() - 2);
+            message.push_str(" or ");
+        }
+        message.push_str(&format!("`{}`?", last));
+    }
+
+    syn::Error::new(span, message)
+}
+
+fn ident_key_names() -> impl Iterator<Item = &'static str> {
+    HID_IDENT_ALIASES.iter().copied().chain(
+        KEYBOARD_USAGE_NAMES
+            .iter()
+            .copied()
+            .filter(|name| !name.starts_with(|c: char| c.is_ascii_digit())),
+    )
+}
+
+fn validate_standard_key(span: Span, tokens: &[TokenTree]) -> syn::Result<()> {
+    let [tt] = tokens else {
+        return Err(syn::Error::new(
+            span,
+            "Expected a single identifier or literal as the key",
+        ));
+    };
+
+    match tt {
+        TokenTree::Ident(ident) => {
+            let name = ident.to_string();
+            if ident_key_names().any(|k| k == name) {
+                Ok(())
+            } else {
+                Err(unknown_key_error(
+                    ident.span(),
+                    "key",
+                    &name,
+                    &near_matches(&name, ident_key_names()),
+                ))
+            }
+        }
+        TokenTree::Literal(literal) => match Lit::new(literal.clone()) {
+            Lit::Char(c) if HID_CHAR_ALIASES.contains(&c.value()) => Ok(()),
+            Lit::Char(c) => Err(unknown_key_error(
+                c.span(),
+                "key",
+                &format!("{:?}", c.value()),
+                &[],
+            )),
+            Lit::Int(i) if i.suffix().is_empty() && i.base10_digits().len() == 1 => Ok(()),
+            Lit::Str(s) => {
+                let name = s.value();
+                if HID_STR_ALIASES.contains(&name.as_str())
+                    || KEYBOARD_USAGE_NAMES.contains(&name.as_str())
+                {
+                    Ok(())
+                } else {
+                    let suggestions = near_matches(&name, KEYBOARD_USAGE_NAMES.iter().copied())
+                        .into_iter()
+                        .map(|s| format!("\"{}\"", s))
+                        .collect::<Vec<_>>();
+                    Err(unknown_key_error(
+                        s.span(),
+                        "key",
+                        &format!("\"{}\"", name),
+                        &suggestions.iter().map(String::as_str).collect::<Vec<_>>(),
+                    ))
+                }
+            }
+            lit => Err(syn::Error::new(
+                lit.span(),
+                "Expected a single digit, a char or a string literal as the key",
+            )),
+        },
+        tt => Err(syn::Error::new(
+            tt.span(),
+            "Expected a single identifier or literal as the key",
+        )),
+    }
+}
+
+fn validate_function_key(span: Span, tokens: &[TokenTree]) -> syn::Result<()> {
+    let Some((TokenTree::Ident(ident), args)) = tokens.split_first() else {
+        return Err(syn::Error::new(span, "Expected the name of a function key after f:"));
+    };
+
+    let name = ident.to_string();
+    let Some((_, expected_arg)) = FUNCTION_KEYS.iter().find(|(k, _)| *k == name) else {
+        return Err(unknown_key_error(
+            ident.span(),
+            "function key",
+            &name,
+            &near_matches(&name, FUNCTION_KEYS.iter().map(|(k, _)| *k)),
+        ));
+    };
+
+    let arg = match args {
+        [] => None,
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::Parenthesis => {
+            Some(group.stream().into_iter().collect::<Vec<_>>())
+        }
+        [tt, ..] => {
+            return Err(syn::Error::new(
+                tt.span(),
+                format!("Unexpected tokens after function key {}", name),
+            ));
+        }
+    };
+
+    let valid = match (expected_arg, arg.as_deref()) {
+        (FunctionKeyArg::None, None) => true,
+        (FunctionKeyArg::Layer, Some([TokenTree::Literal(_)])) => true,
+        (FunctionKeyArg::RelativeLayer, Some([TokenTree::Punct(sign), TokenTree::Literal(_)])) => {
+            sign.as_char() == '+' || sign.as_char() == '-'
+        }
+        _ => false,
+    };
+
+    if valid {
+        return Ok(());
+    }
+
+    let usage = match expected_arg {
+        FunctionKeyArg::None => format!("{} takes no arguments", name),
+        FunctionKeyArg::Layer => format!("Expected a layer index, e.g f:{}(1)", name),
+        FunctionKeyArg::RelativeLayer => {
+            format!("Expected a signed layer offset, e.g f:{}(+1)", name)
+        }
+    };
+    let arg_span = args.first().map(|tt| tt.span()).unwrap_or(ident.span());
+    Err(syn::Error::new(arg_span, usage))
+}
+
+/// Checks that a text key is a string literal that can be typed by
+/// `dxkb_core::playback::TextPlayback`, that is, printable ASCII chars, tabs
+/// and new lines.
+fn validate_text_key(span: Span, tokens: &[TokenTree]) -> syn::Result<()> {
+    let [TokenTree::Literal(literal)] = tokens else {
+        return Err(syn::Error::new(span, "Expected a string literal after t:"));
+    };
+
+    let Lit::Str(text) = Lit::new(literal.clone()) else {
+        return Err(syn::Error::new(literal.span(), "Expected a string literal after t:"));
+    };
+
+    match text
+        .value()
+        .chars()
+        .find(|c| !(c.is_ascii_graphic() || *c == ' ' || *c == '\n' || *c == '\t'))
+    {
+        Some(c) => Err(syn::Error::new(
+            text.span(),
+            format!("The char {:?} can't be typed by a text key", c),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks that a MIDI key is a parenthesized channel (1 to 16) and note (0 to
+/// 127), e.g `m:(1, 60)`.
+fn validate_midi_key(span: Span, tokens: &[TokenTree]) -> syn::Result<()> {
+    let usage = "Expected a MIDI channel and note, e.g m:(1, 60)";
+    let [TokenTree::Group(group)] = tokens else {
+        return Err(syn::Error::new(span, usage));
+    };
+    if group.delimiter() != Delimiter::Parenthesis {
+        return Err(syn::Error::new(group.span(), usage));
+    }
+
+    let args = group.stream().into_iter().collect::<Vec<_>>();
+    let [TokenTree::Literal(channel), TokenTree::Punct(comma), TokenTree::Literal(note)] = args.as_slice() else {
+        return Err(syn::Error::new(group.span(), usage));
+    };
+    if comma.as_char() != ',' {
+        return Err(syn::Error::new(comma.span(), usage));
+    }
+
+    for (literal, range, what) in [(channel, 1..=16, "MIDI channel"), (note, 0..=127, "MIDI note")] {
+        let valid = match Lit::new(literal.clone()) {
+            Lit::Int(i) => i.base10_parse::<u8>().is_ok_and(|v| range.contains(&v)),
+            _ => false,
+        };
+        if !valid {
+            return Err(syn::Error::new(
+                literal.span(),
+                format!("Expected a {} from {} to {}", what, range.start(), range.end()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_steno_key(span: Span, tokens: &[TokenTree]) -> syn::Result<()> {
+    let [TokenTree::Ident(ident)] = tokens else {
+        return Err(syn::Error::new(span, "Expected the name of a steno key after s:"));
+    };
+
+    let name = ident.to_string();
+    if STENO_KEYS.contains(&name.as_str()) {
+        return Ok(());
+    }
+
+    Err(unknown_key_error(
+        ident.span(),
+        "steno key",
+        &name,
+        &near_matches(&name, STENO_KEYS.iter().copied()),
+    ))
+}
+
+/// Checks that the tokens of a key can be translated by
+/// `default_key_from_alias!`, returning an error pointing at the offending
+/// token otherwise. The `span` is used when there are no tokens at all.
+///
+/// Consumer control keys (`c:`) are not checked, since they fall back to the
+/// variants of `hut::Consumer`, on which rustc already reports unknown names
+/// at the right token.
+pub fn validate_default_key(span: Span, tokens: &TokenStream) -> syn::Result<()> {
+    let tokens = tokens.clone().into_iter().collect::<Vec<_>>();
+    match tokens.as_slice() {
+        [] => Err(syn::Error::new(span, "Expected a key")),
+        [TokenTree::Ident(ident)] if ident == "_" => Ok(()),
+        [TokenTree::Ident(prefix), TokenTree::Punct(colon), rest @ ..] if colon.as_char() == ':' => {
+            if prefix == "f" {
+                validate_function_key(colon.span(), rest)
+            } else if prefix == "t" {
+                validate_text_key(colon.span(), rest)
+            } else if prefix == "s" {
+                validate_steno_key(colon.span(), rest)
+            } else if prefix == "m" {
+                validate_midi_key(colon.span(), rest)
+            } else if prefix == "c" {
+                Ok(())
+            } else {
+                Err(unknown_key_error(
+                    prefix.span(),
+                    "key prefix",
+                    &format!("{}:", prefix),
+                    &["f:", "c:", "t:", "m:", "s:", "fn:"],
+                ))
+            }
+        }
+        tokens => validate_standard_key(span, tokens),
+    }
+}
+
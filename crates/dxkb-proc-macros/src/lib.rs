@@ -8,6 +8,8 @@ use syn::{
     spanned::Spanned,
 };
 
+mod keymap;
+
 struct ResultAcc<T, E> {
     oks: Vec<T>,
     errors: Vec<E>,
@@ -49,6 +51,42 @@ fn dxkb_keyboard_symbol(name: &str) -> TokenStream {
     }
 }
 
+/// Returns the tokens after the `fn:` prefix, if the given key tokens refer to
+/// a user-defined key.
+fn user_key_tokens(tt: &TokenStream) -> Option<(Span, TokenStream)> {
+    let mut iter = tt.clone().into_iter();
+    match (iter.next(), iter.next()) {
+        (Some(TokenTree::Ident(prefix)), Some(TokenTree::Punct(colon)))
+            if prefix == "fn" && colon.as_char() == ':' =>
+        {
+            Some((colon.span(), iter.collect()))
+        }
+        _ => None,
+    }
+}
+
+/// Parses the path of a user-defined key (`fn:path::to::KEY`).
+fn parse_user_key(span: Span, tt: TokenStream) -> syn::Result<Path> {
+    if tt.is_empty() {
+        return Err(syn::Error::new(span, "Expected the path to a key after fn:"));
+    }
+
+    syn::parse2::<Path>(tt.clone()).map_err(|_| {
+        syn::Error::new_spanned(tt, "Expected the path to a key after fn:")
+    })
+}
+
+/// Generates the expression of a key. User-defined keys are referenced as-is,
+/// while anything else is translated by the alias resolver.
+fn key_expr(resolver: &Path, tt: &TokenStream) -> TokenStream {
+    match user_key_tokens(tt).map(|(span, tt)| parse_user_key(span, tt)) {
+        Some(Ok(path)) => quote! { #path },
+        // Already reported by LayersDef::validate_keys.
+        Some(Err(e)) => e.to_compile_error(),
+        None => quote! { #resolver!(#tt) },
+    }
+}
+
 #[derive(Debug, Clone)]
 enum KeyAction {
     Passthrough(Span),
@@ -343,15 +381,33 @@ impl Parse for LayerDef<KeyAction> {
     }
 }
 
+/// Returns whether the given alias resolver is `dxkb_core::default_key_from_alias`.
+fn is_default_resolver(path: &Path) -> bool {
+    path.segments.iter().map(|s| s.ident.to_string()).eq(["dxkb_core", "default_key_from_alias"])
+}
+
+/// What the layers! macro has to generate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmitKind {
+    /// The array of layers, to be used for building the layout.
+    Layers,
+    /// A table of (layer, row, col) -> key for each position of the layout, for
+    /// checking in tests that the layout built from the layers matches what the
+    /// macro input says.
+    UsageTable,
+}
+
 #[derive(Debug)]
 struct LayersDef<K> {
     resolver: Option<Path>,
+    emit: EmitKind,
     layers: Vec<LayerDef<K>>,
 }
 
 #[derive(Debug)]
 struct ResolvedLayersDef<K> {
     resolver: Option<Path>,
+    emit: EmitKind,
     num_cols: usize,
     layers: Vec<Rc<ResolvedLayerDef<K>>>,
 }
@@ -370,6 +426,7 @@ impl LayersDef<KeyAction> {
         }
 
         const ATTR_RESOLVER: &str = "alias_resolver";
+        const ATTR_EMIT: &str = "emit";
         const ATTR_LAYERS: &str = "layers";
 
         let attrs = AttributeSet::new(outer_span, Parser::parse2(do_parse_attrs, input)?);
@@ -380,12 +437,29 @@ impl LayersDef<KeyAction> {
             None
         };
 
+        let emit = if let Some(attr) = attrs.find_attr(ATTR_EMIT) {
+            let path = attr.require_value_path()?;
+            if path.is_ident("layers") {
+                EmitKind::Layers
+            } else if path.is_ident("usage_table") {
+                EmitKind::UsageTable
+            } else {
+                return Err(syn::Error::new(
+                    path.span(),
+                    "Expected either layers or usage_table as the value of emit",
+                ));
+            }
+        } else {
+            EmitKind::Layers
+        };
+
         let layers_attr = attrs
             .require_attr(ATTR_LAYERS)
             .and_then(|a| a.require_bracket_group())?;
 
         Ok(LayersDef {
             resolver: alias_resolver_attr.cloned(),
+            emit,
             layers: Parser::parse2(do_parse_layers, layers_attr.stream())?,
         })
     }
@@ -503,6 +577,7 @@ impl LayersDef<KeyAction> {
         let Some(first_layer) = self.layers.first() else {
             return Ok(ResolvedLayersDef {
                 resolver: self.resolver.clone(),
+                emit: self.emit,
                 num_cols: 0,
                 layers: vec![],
             });
@@ -528,12 +603,45 @@ impl LayersDef<KeyAction> {
 
         Ok(ResolvedLayersDef {
             resolver: self.resolver.clone(),
+            emit: self.emit,
             num_cols: expected_col_count,
             layers: r.oks,
         })
     }
 }
 
+impl LayersDef<KeyAction> {
+    /// Checks that every key of every layer can be translated, reporting all
+    /// the unknown keys at once. Aliases are only checked for the default
+    /// alias resolver, whether it is given explicitly or not, since the
+    /// aliases of a custom one are unknown here.
+    pub fn validate_keys(&self) -> syn::Result<()> {
+        let default_resolver = self.resolver.as_ref().is_none_or(is_default_resolver);
+        let validate_key = |span: Span, tt: &TokenStream| match user_key_tokens(tt) {
+            Some((span, tt)) => parse_user_key(span, tt).map(|_| ()),
+            None if default_resolver => keymap::validate_default_key(span, tt),
+            None => Ok(()),
+        };
+
+        let errors = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.rows.iter())
+            .flat_map(|row| {
+                row.actions.iter().filter_map(|action| match action {
+                    KeyAction::Passthrough(_) => None,
+                    KeyAction::Key(tt) => validate_key(row.span, tt).err(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        match combine_syn_errors(&errors) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
 impl ResolvedLayersDef<KeyAction> {
     fn flatten_with_parent(
         layer: &ResolvedLayerDef<KeyAction>,
@@ -662,19 +770,76 @@ impl ResolvedLayersDef<KeyAction> {
 
         Ok(ResolvedLayersDef {
             resolver: self.resolver.clone(),
+            emit: self.emit,
             num_cols: self.num_cols,
             layers: r.oks,
         })
     }
 }
 
+impl ResolvedLayersDef<KeyAction> {
+    /// Finds the key at the given position of the layer, walking up through
+    /// its parents while the position is a passthrough. This is done on purpose
+    /// without relying on [`Self::flatten`], so that the generated usage table
+    /// can catch any regression in it.
+    fn lookup_key(layer: &ResolvedLayerDef<KeyAction>, row: usize, col: usize) -> Option<&TokenStream> {
+        match &layer.rows[row].actions[col] {
+            KeyAction::Key(tt) => Some(tt),
+            KeyAction::Passthrough(_) => Self::lookup_key(layer.parent.as_deref()?, row, col),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn gen_usage_table_code(&self) -> proc_macro2::TokenStream {
+        let LayoutUsageEntry = dxkb_keyboard_symbol("LayoutUsageEntry");
+        let resolver = self.resolver.clone().unwrap_or_else(|| {
+            syn::parse2::<Path>(quote! { dxkb_core::default_key_from_alias }).unwrap()
+        });
+
+        let mut entries = Vec::new();
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            for (row_idx, row) in layer.rows.iter().enumerate() {
+                for col_idx in 0..row.actions.len() {
+                    // Passthrough keys in layers with no parent are reported
+                    // by the flatten step, that always runs before this.
+                    let Some(tt) = Self::lookup_key(layer, row_idx, col_idx) else {
+                        continue;
+                    };
+
+                    let layer_idx = layer_idx as u8;
+                    let row_idx = row_idx as u8;
+                    let col_idx = col_idx as u8;
+                    let alias = tt.to_string();
+                    let key = key_expr(&resolver, tt);
+                    entries.push(quote! {
+                        #LayoutUsageEntry {
+                            layer: #layer_idx,
+                            row: #row_idx,
+                            col: #col_idx,
+                            alias: #alias,
+                            key: #key,
+                        }
+                    });
+                }
+            }
+        }
+
+        quote! {
+            &[
+                #(#entries),*
+            ]
+        }
+    }
+}
+
 impl ToTokens for ConcreteKeyAction {
     #[allow(non_snake_case)]
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let layout_key_ref = match self {
-            ConcreteKeyAction::Key(tt) => quote! {
-                dxkb_core::default_key_from_alias!(#tt)
-            },
+            ConcreteKeyAction::Key(tt) => key_expr(
+                &syn::parse2::<Path>(quote! { dxkb_core::default_key_from_alias }).unwrap(),
+                tt,
+            ),
         };
 
         tokens.append_all(layout_key_ref);
@@ -696,9 +861,7 @@ impl ToTokens for LayerRow<ConcreteKeyAction> {
 
 fn key_to_tokens(resolver: &Path, key: &ConcreteKeyAction) -> TokenStream {
     match key {
-        ConcreteKeyAction::Key(tt) => quote! {
-            #resolver!(#tt)
-        },
+        ConcreteKeyAction::Key(tt) => key_expr(resolver, tt),
     }
 }
 
@@ -748,6 +911,20 @@ impl ResolvedLayersDef<ConcreteKeyAction> {
     }
 }
 
+/// Generates the array of layers of a keyboard layout.
+///
+/// Each key is translated through the alias resolver (`alias_resolver`, or
+/// `dxkb_core::default_key_from_alias` by default), except user-defined keys,
+/// written as `fn:path::to::KEY`. These are emitted as a plain reference to
+/// the given path, without going through the resolver, so the path must name a
+/// `const` whose type is the key type of the layout (any type implementing
+/// `HandleKey`). A `const` is needed instead of a `static` because the layout
+/// is built in a const context.
+///
+/// When `emit: usage_table` is given, a `&[LayoutUsageEntry]` slice with the
+/// expected key of every (layer, row, col) position is generated instead, which
+/// can be checked in tests against the layout built from the same definition
+/// with `SplitKeyboardLayout::check_usage_table`.
 #[proc_macro]
 pub fn layers(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let stream: proc_macro2::TokenStream = item.into();
@@ -764,8 +941,19 @@ pub fn layers(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
         Err(e) => return e.to_compile_error().into(),
     }
 
-    match layers.flatten() {
-        Ok(r) => r.gen_layers_code().into(),
+    if let Err(e) = input.validate_keys() {
+        return e.to_compile_error().into();
+    }
+
+    // Flattening is needed even when emitting the usage table, for validating
+    // the layers.
+    let flattened = match layers.flatten() {
+        Ok(r) => r,
         Err(e) => return e.to_compile_error().into(),
+    };
+
+    match layers.emit {
+        EmitKind::Layers => flattened.gen_layers_code().into(),
+        EmitKind::UsageTable => layers.gen_usage_table_code().into(),
     }
 }
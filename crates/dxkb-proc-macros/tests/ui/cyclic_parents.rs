@@ -0,0 +1,20 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "first",
+            parent: "second",
+            rows: [
+                [A, B],
+            ]
+        },
+        {
+            name: "second",
+            parent: "first",
+            rows: [
+                [A, B],
+            ]
+        },
+    ]
+);
+
+fn main() {}
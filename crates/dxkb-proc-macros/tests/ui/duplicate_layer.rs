@@ -0,0 +1,18 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [A, B],
+            ]
+        },
+        {
+            name: "base",
+            rows: [
+                [C, D],
+            ]
+        },
+    ]
+);
+
+fn main() {}
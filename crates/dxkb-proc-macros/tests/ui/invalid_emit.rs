@@ -0,0 +1,13 @@
+dxkb_proc_macros::layers!(
+    emit: keymap,
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [A, B],
+            ]
+        },
+    ]
+);
+
+fn main() {}
@@ -0,0 +1,12 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [f:LPush(1), f:LRelSet(1), f:LPop(1), f:LSet],
+            ]
+        },
+    ]
+);
+
+fn main() {}
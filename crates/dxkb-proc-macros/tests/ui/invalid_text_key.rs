@@ -0,0 +1,12 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [t:"¡hola!", t:Hello, t:"ok"],
+            ]
+        },
+    ]
+);
+
+fn main() {}
@@ -0,0 +1,12 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [fn:, fn:1, fn:MY_KEY],
+            ]
+        },
+    ]
+);
+
+fn main() {}
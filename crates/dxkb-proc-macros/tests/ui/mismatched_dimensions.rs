@@ -0,0 +1,21 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [A, B],
+                [C],
+            ]
+        },
+        {
+            name: "upper",
+            parent: "base",
+            rows: [
+                [*, *, *],
+                [*, *, *],
+            ]
+        },
+    ]
+);
+
+fn main() {}
@@ -0,0 +1,12 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [Escp, LSift, "3Hsh", Qwertyuiop],
+            ]
+        },
+    ]
+);
+
+fn main() {}
@@ -0,0 +1,13 @@
+dxkb_proc_macros::layers!(
+    alias_resolver: dxkb_core::default_key_from_alias,
+    layers: [
+        {
+            name: "base",
+            rows: [
+                [Esc, Escp],
+            ]
+        },
+    ]
+);
+
+fn main() {}
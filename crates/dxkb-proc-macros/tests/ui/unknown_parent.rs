@@ -0,0 +1,13 @@
+dxkb_proc_macros::layers!(
+    layers: [
+        {
+            name: "base",
+            parent: "missing",
+            rows: [
+                [A, B],
+            ]
+        },
+    ]
+);
+
+fn main() {}
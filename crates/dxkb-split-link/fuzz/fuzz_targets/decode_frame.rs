@@ -0,0 +1,63 @@
+//! Feeds random frames into a split bus whose link is up, for making sure
+//! that malformed frames are dropped without panicking. Run it from the
+//! `dxkb-split-link` directory with:
+//!
+//! ```
+//! cargo fuzz run decode_frame
+//! ```
+//!
+//! The first byte of each frame of the input is its length. Unless the input
+//! starts with an odd byte, the preamble and the CRC-8 of each frame are
+//! fixed up before sending it, so that most of them go past the checksum and
+//! reach the parsing of the frame contents.
+
+#![no_main]
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+use core::time::Duration;
+
+use dxkb_common::bus::BusWrite;
+use dxkb_common::testing::{LoopbackPair, MockClock};
+use dxkb_split_link::{DefaultSplitLinkTimings, LinkStatus, SplitBus, SplitBusLike};
+use libfuzzer_sys::fuzz_target;
+
+type Link = SplitBus<[u32; 4], DefaultSplitLinkTimings, LoopbackPair, MockClock, 4, 2>;
+
+const FRAME_PRELUDE_BYTE: u8 = 0x99;
+const CRC8: crc::Crc<u8> = crc::Crc::<u8>::new(&crc::CRC_8_SMBUS);
+const POLL_PERIOD: Duration = Duration::from_millis(1);
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&mode, mut rest)) = data.split_first() else {
+        return;
+    };
+    let fix_checksum = mode & 1 == 0;
+
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut peer = Link::new(a, clock.clone(), 1);
+    let mut link = Link::new(b, clock.clone(), 2);
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        peer.poll(|_| true);
+        link.poll(|_| true);
+    }
+    assert_eq!(link.link_status(), LinkStatus::Up);
+
+    while let Some((&len, tail)) = rest.split_first() {
+        let (frame, tail) = tail.split_at((len as usize).min(tail.len()));
+        rest = tail;
+
+        let mut frame = frame.to_vec();
+        if fix_checksum && frame.len() >= 2 {
+            frame[0] = FRAME_PRELUDE_BYTE;
+            frame[1] = CRC8.checksum(&frame[2..]);
+        }
+
+        let _ = peer.bus_mut().transfer(&frame);
+        clock.advance(POLL_PERIOD);
+        link.poll(|_| true);
+        peer.poll(|_| true);
+    }
+});
@@ -0,0 +1,212 @@
+//! Addressing of the frames of several links sharing a single bus, for boards
+//! made of more than two units, like a keyboard, a numpad and a macro pad
+//! daisy chained on the same half-duplex line.
+//!
+//! Every frame sent through the shared bus is prefixed with the address of
+//! its destination and the address of its source:
+//!
+//! ```text
+//! +------------------+------------------+----------------------+
+//! | Destination (u8) |    Source (u8)   |  Split link frame... |
+//! +------------------+------------------+----------------------+
+//! ```
+//!
+//! An [`AddressedBus`] wraps the shared bus and hands a [`PeerPort`] for each
+//! of the peers reachable through it. A port is a bus by itself, that only
+//! carries the frames exchanged with its peer, and it is meant to be driven
+//! by its own [`SplitBus`](crate::SplitBus). That way, each pair of units
+//! keeps its own sequence numbers, retransmissions and link state, and a unit
+//! being unplugged doesn't bring down the links with the rest.
+//!
+//! The frames received from the shared bus are sorted into a queue for each
+//! peer, so polling the port of a peer doesn't lose the frames of the others.
+//! Frames addressed to other units, or coming from unknown ones, are dropped.
+
+use core::cell::RefCell;
+
+use dxkb_common::bus::{BusPollError, BusRead, BusTransferError, BusWrite};
+use dxkb_common::dev_debug;
+use heapless::Vec;
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+/// Address of a unit on a shared bus.
+pub type PeerAddress = u8;
+
+/// Length of the addressing header prefixed to every frame.
+pub const ADDRESS_HEADER_LEN: usize = 2;
+
+/// Counters of the frames dropped by an [`AddressedBus`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddressingStats {
+    /// Frames addressed to another unit.
+    pub foreign: u32,
+    /// Frames addressed to us by a unit that is not one of our peers.
+    pub unknown_source: u32,
+    /// Frames too short for holding the addressing header.
+    pub malformed: u32,
+    /// Frames dropped because the queue of their peer was full, or because
+    /// they didn't fit in the buffers of the bus.
+    pub overflows: u32,
+}
+
+struct AddressedRx<const PEERS: usize, const MTU: usize, const FRAMES: usize> {
+    queues: [ConstGenericRingBuffer<Vec<u8, MTU>, FRAMES>; PEERS],
+    scratch: [u8; MTU],
+    stats: AddressingStats,
+}
+
+/// A bus shared by this unit and `PEERS` other units, each one identified by
+/// its address. Frames up to `MTU` bytes long, addressing header included,
+/// can be exchanged through it, and up to `FRAMES` frames of each peer can be
+/// waiting for being polled through its [`PeerPort`].
+pub struct AddressedBus<B, const PEERS: usize, const MTU: usize, const FRAMES: usize> {
+    bus: RefCell<B>,
+    address: PeerAddress,
+    peers: [PeerAddress; PEERS],
+    rx: RefCell<AddressedRx<PEERS, MTU, FRAMES>>,
+}
+
+impl<B: BusRead + BusWrite, const PEERS: usize, const MTU: usize, const FRAMES: usize>
+    AddressedBus<B, PEERS, MTU, FRAMES>
+{
+    /// Creates a shared bus on which this unit has the given `address`, and
+    /// that reaches the units of the given addresses.
+    ///
+    /// # Panics
+    ///
+    /// If any of the addresses is repeated.
+    pub fn new(bus: B, address: PeerAddress, peers: [PeerAddress; PEERS]) -> Self {
+        for (i, peer) in peers.iter().enumerate() {
+            assert!(*peer != address, "A peer can't have the address of this unit");
+            assert!(!peers[..i].contains(peer), "Peer addresses must be unique");
+        }
+
+        Self {
+            bus: RefCell::new(bus),
+            address,
+            peers,
+            rx: RefCell::new(AddressedRx {
+                queues: core::array::from_fn(|_| ConstGenericRingBuffer::new()),
+                scratch: [0; MTU],
+                stats: AddressingStats::default(),
+            }),
+        }
+    }
+
+    /// Returns the address of this unit.
+    pub fn address(&self) -> PeerAddress {
+        self.address
+    }
+
+    /// Returns the port through which the frames of the given peer are
+    /// exchanged, or None if it isn't one of the peers of this bus.
+    pub fn port(&self, peer: PeerAddress) -> Option<PeerPort<'_, B, PEERS, MTU, FRAMES>> {
+        let index = self.peers.iter().position(|p| *p == peer)?;
+        Some(PeerPort { shared: self, index })
+    }
+
+    /// Returns the counters of the frames dropped so far.
+    pub fn stats(&self) -> AddressingStats {
+        self.rx.borrow().stats
+    }
+
+    /// Reads every frame pending in the shared bus into the queue of the peer
+    /// that sent it.
+    fn sort_incoming(&self) {
+        let bus = self.bus.borrow();
+        let rx = &mut *self.rx.borrow_mut();
+
+        loop {
+            let len = match bus.poll_next(&mut rx.scratch) {
+                Ok(len) => len as usize,
+                Err(BusPollError::WouldBlock) => break,
+                Err(BusPollError::BufferOverflow) => {
+                    rx.stats.overflows = rx.stats.overflows.wrapping_add(1);
+                    continue;
+                }
+            };
+
+            let [dst, src, frame @ ..] = &rx.scratch[..len] else {
+                rx.stats.malformed = rx.stats.malformed.wrapping_add(1);
+                continue;
+            };
+
+            if *dst != self.address {
+                rx.stats.foreign = rx.stats.foreign.wrapping_add(1);
+                continue;
+            }
+
+            let Some(index) = self.peers.iter().position(|p| p == src) else {
+                dev_debug!("Dropping frame from unknown unit {}", src);
+                rx.stats.unknown_source = rx.stats.unknown_source.wrapping_add(1);
+                continue;
+            };
+
+            let queue = &mut rx.queues[index];
+            if queue.is_full() {
+                rx.stats.overflows = rx.stats.overflows.wrapping_add(1);
+                continue;
+            }
+            // Cannot fail, the frame comes from a buffer of the same length.
+            let _ = queue.enqueue(Vec::from_slice(frame).unwrap());
+        }
+    }
+}
+
+/// The side of an [`AddressedBus`] that only carries the frames exchanged
+/// with one of its peers.
+pub struct PeerPort<'a, B, const PEERS: usize, const MTU: usize, const FRAMES: usize> {
+    shared: &'a AddressedBus<B, PEERS, MTU, FRAMES>,
+    index: usize,
+}
+
+impl<B, const PEERS: usize, const MTU: usize, const FRAMES: usize> PeerPort<'_, B, PEERS, MTU, FRAMES> {
+    /// Returns the address of the peer of this port.
+    pub fn peer(&self) -> PeerAddress {
+        self.shared.peers[self.index]
+    }
+}
+
+impl<B: BusRead + BusWrite, const PEERS: usize, const MTU: usize, const FRAMES: usize> BusWrite
+    for PeerPort<'_, B, PEERS, MTU, FRAMES>
+{
+    /// Sends the buffer to the peer of this port, prefixed with the
+    /// addressing header.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer and the addressing header don't fit in `MTU`.
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        assert!(buf.len() + ADDRESS_HEADER_LEN <= MTU, "Frame is larger than the shared bus MTU");
+        let mut frame = [0u8; MTU];
+        frame[0] = self.peer();
+        frame[1] = self.shared.address;
+        frame[ADDRESS_HEADER_LEN..ADDRESS_HEADER_LEN + buf.len()].copy_from_slice(buf);
+        self.shared.bus.borrow_mut().transfer(&frame[..ADDRESS_HEADER_LEN + buf.len()])
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.shared.bus.borrow().is_tx_busy()
+    }
+}
+
+impl<B: BusRead + BusWrite, const PEERS: usize, const MTU: usize, const FRAMES: usize> BusRead
+    for PeerPort<'_, B, PEERS, MTU, FRAMES>
+{
+    /// Reads the oldest frame received from the peer of this port, without
+    /// its addressing header. If it doesn't fit in the buffer, the frame is
+    /// discarded and [`BusPollError::BufferOverflow`] is returned.
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.shared.sort_incoming();
+
+        let frame = self.shared.rx.borrow_mut().queues[self.index]
+            .dequeue()
+            .ok_or(BusPollError::WouldBlock)?;
+        if frame.len() > buf.len() {
+            return Err(BusPollError::BufferOverflow);
+        }
+
+        buf[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len() as u16)
+    }
+}
@@ -0,0 +1,125 @@
+//! Authentication of the frames of the link, so that a tampered cable, or
+//! anything else wired to it, can't inject frames into it, like fake key events.
+//!
+//! The authentication is chosen through a type parameter of
+//! [`SplitBus`](crate::SplitBus). When enabled, a tag computed from the whole
+//! frame and a key shared by both peers is appended to every frame, and every
+//! frame received without the right tag is dropped, as if its CRC was wrong.
+//! Both peers must use the same authentication and the same key, otherwise no
+//! frame gets through and the link never comes up.
+//!
+//! The frames are not encrypted, so they can still be read from the cable. A
+//! recorded frame could also be sent again as it is, but the transport frames
+//! are dropped by the peer unless their seq number is the expected one, so
+//! only the link control frames and the datagrams may be replayed.
+
+/// The max length of the tag of a frame, among all the [`FrameAuth`]
+/// implementations.
+pub const MAX_AUTH_TAG_LEN: usize = 8;
+
+/// A message authentication code appended to every frame.
+pub trait FrameAuth {
+    /// Length of the tag, at most [`MAX_AUTH_TAG_LEN`]. Zero disables the
+    /// authentication.
+    const TAG_LEN: usize;
+
+    /// Writes the tag of `frame` into `tag`, whose length is `TAG_LEN`.
+    fn tag(&self, frame: &[u8], tag: &mut [u8]);
+
+    /// Returns whether `tag` is the right one for `frame`. The comparison
+    /// takes the same time wherever the first mismatch is, so that the right
+    /// tag can't be guessed byte by byte.
+    fn verify(&self, frame: &[u8], tag: &[u8]) -> bool {
+        let mut expected = [0u8; MAX_AUTH_TAG_LEN];
+        self.tag(frame, &mut expected[..Self::TAG_LEN]);
+        tag.len() == Self::TAG_LEN
+            && expected[..Self::TAG_LEN].iter().zip(tag).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+/// No authentication at all. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuth;
+
+impl FrameAuth for NoAuth {
+    const TAG_LEN: usize = 0;
+
+    fn tag(&self, _frame: &[u8], _tag: &mut [u8]) {}
+
+    fn verify(&self, _frame: &[u8], _tag: &[u8]) -> bool {
+        true
+    }
+}
+
+/// A SipHash-2-4 of the frame, keyed with a 128 bits key shared by both peers
+/// (e.g stored in the flash when pairing them), sent in little endian.
+#[derive(Clone, Copy)]
+pub struct SipHashAuth {
+    key: [u8; 16],
+}
+
+impl SipHashAuth {
+    pub const fn new(key: [u8; 16]) -> Self {
+        Self { key }
+    }
+}
+
+impl FrameAuth for SipHashAuth {
+    const TAG_LEN: usize = 8;
+
+    fn tag(&self, frame: &[u8], tag: &mut [u8]) {
+        tag.copy_from_slice(&siphash24(&self.key, frame).to_le_bytes());
+    }
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+fn sip_compress(v: &mut [u64; 4], m: u64) {
+    v[3] ^= m;
+    sip_round(v);
+    sip_round(v);
+    v[0] ^= m;
+}
+
+/// Returns the SipHash-2-4 of `data` with the given key, as defined in
+/// <https://www.aumasson.jp/siphash/siphash.pdf>.
+pub fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[0..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..16].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut blocks = data.chunks_exact(8);
+    for block in &mut blocks {
+        sip_compress(&mut v, u64::from_le_bytes(block.try_into().unwrap()));
+    }
+
+    // The last block has the remaining bytes and the length of the data in
+    // its most significant byte.
+    let mut last = (data.len() as u64) << 56;
+    for (i, b) in blocks.remainder().iter().enumerate() {
+        last |= (*b as u64) << (8 * i);
+    }
+    sip_compress(&mut v, last);
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
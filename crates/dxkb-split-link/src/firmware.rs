@@ -0,0 +1,426 @@
+//! A subprotocol for sending a firmware image through the link, so that the
+//! side that isn't connected to the host can be updated through the USB port
+//! of the other one.
+//!
+//! The image is sent in chunks of up to [`FIRMWARE_CHUNK_LEN`] bytes, as
+//! [`FirmwareMsg`]s carried by the user messages of the link, so they are
+//! delivered in order and re-sent if lost. The transfer goes as follows:
+//!
+//! - The sender offers the image, with its length and CRC-32.
+//! - The receiver prepares its storage and accepts it, answering with the
+//!   offset the sender must start from. If it is the same image it was already
+//!   receiving, the offset is the number of bytes it has already stored, so an
+//!   interrupted transfer is resumed instead of started over.
+//! - The sender sends the chunks from there on.
+//! - Once the receiver has the whole image, it checks its CRC-32 against the
+//!   one offered, reading it back from the storage, and tells the sender the
+//!   result.
+//!
+//! The link drops every queued message when it goes down, so the sender offers
+//! the image again when that happens, resuming it once the link is up again.
+//!
+//! The [`FirmwareSender`] and [`FirmwareReceiver`] don't own the link, since
+//! the image usually shares it with other messages: the received
+//! [`FirmwareMsg`]s are handed to them with `on_msg`, and `poll` sends theirs,
+//! wrapped in the message type of the link.
+
+use crc::Table;
+use dxkb_common::{dev_debug, dev_info, dev_warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{SplitBusLike, TransferError};
+
+/// The max number of bytes of the image sent in each chunk. It is the largest
+/// array serde can (de)serialize.
+pub const FIRMWARE_CHUNK_LEN: usize = 32;
+
+static FIRMWARE_CRC32: crc::Crc<u32, Table<1>> = crc::Crc::<u32, Table<1>>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Returns the CRC-32 of a whole firmware image, as sent in
+/// [`FirmwareMsg::Offer`].
+pub fn firmware_crc32(image: &[u8]) -> u32 {
+    FIRMWARE_CRC32.checksum(image)
+}
+
+/// The reason why a firmware transfer has failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareError {
+    /// The receiver doesn't have room for the image.
+    Rejected,
+    /// The storage of the receiver has failed preparing, writing or reading
+    /// the image.
+    Storage,
+    /// The image stored by the receiver doesn't match the CRC-32 offered.
+    CrcMismatch,
+    /// The link cannot send the messages of the transfer.
+    Transfer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareMsg {
+    /// Sent by the sender for starting or resuming the transfer of an image.
+    Offer { image_len: u32, image_crc: u32 },
+
+    /// Sent by the receiver when it is ready to receive the image offered,
+    /// with the offset of the first byte it is missing.
+    Accept { offset: u32 },
+
+    /// A piece of the image, of `len` bytes starting at `offset`.
+    Chunk {
+        offset: u32,
+        len: u8,
+        data: [u8; FIRMWARE_CHUNK_LEN],
+    },
+
+    /// Sent by the receiver once the transfer is over, either because it has
+    /// the whole image or because it has failed.
+    Finished { error: Option<FirmwareError> },
+}
+
+/// Where the receiver stores the image, typically a flash bank.
+pub trait FirmwareStorage {
+    type Error;
+
+    /// The max length of the image that can be stored.
+    fn capacity(&self) -> u32;
+
+    /// Prepares the storage for receiving an image of the given length from
+    /// the beginning, e.g erasing it.
+    fn begin(&mut self, image_len: u32) -> Result<(), Self::Error>;
+
+    /// Writes a piece of the image. The pieces are always written in order.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Reads a piece of the image already written.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareSendState {
+    /// The image has to be offered, once the link can send it.
+    Offering,
+    /// The image has been offered, and the sender waits for the receiver to
+    /// accept it.
+    WaitingAccept,
+    /// The chunks of the image are being sent.
+    Sending,
+    /// Every chunk has been sent, and the sender waits for the receiver to
+    /// check the image.
+    WaitingResult,
+    /// The receiver has the whole image, and it is valid.
+    Completed,
+    Failed(FirmwareError),
+}
+
+/// Sends a firmware image to a [`FirmwareReceiver`] in the peer.
+pub struct FirmwareSender<'a> {
+    image: &'a [u8],
+    image_crc: u32,
+    offset: u32,
+    state: FirmwareSendState,
+}
+
+impl<'a> FirmwareSender<'a> {
+    pub fn new(image: &'a [u8]) -> Self {
+        Self {
+            image,
+            image_crc: firmware_crc32(image),
+            offset: 0,
+            state: FirmwareSendState::Offering,
+        }
+    }
+
+    pub fn state(&self) -> FirmwareSendState {
+        self.state
+    }
+
+    /// Returns the number of bytes of the image that have been sent, which
+    /// can go back if the receiver has lost some of them.
+    pub fn sent_len(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn image_len(&self) -> u32 {
+        self.image.len() as u32
+    }
+
+    /// Returns whether the transfer is over, either because it has completed
+    /// or because it has failed.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, FirmwareSendState::Completed | FirmwareSendState::Failed(_))
+    }
+
+    /// Makes the sender offer the image again, for resuming the transfer. It
+    /// must be called whenever the link goes down or the peer is reset, since
+    /// the messages in flight are lost then.
+    pub fn on_link_down(&mut self) {
+        if !self.is_finished() {
+            self.state = FirmwareSendState::Offering;
+        }
+    }
+
+    /// Handles a message received from the receiver.
+    pub fn on_msg(&mut self, msg: &FirmwareMsg) {
+        match (self.state, msg) {
+            (FirmwareSendState::WaitingAccept, FirmwareMsg::Accept { offset }) => {
+                if *offset > self.image_len() {
+                    dev_warn!("Firmware receiver accepted the image from an invalid offset {}", offset);
+                    self.state = FirmwareSendState::Failed(FirmwareError::Rejected);
+                    return;
+                }
+
+                if *offset > 0 {
+                    dev_info!("Resuming firmware transfer from offset {}", offset);
+                }
+                self.offset = *offset;
+                self.state = if self.offset == self.image_len() {
+                    FirmwareSendState::WaitingResult
+                } else {
+                    FirmwareSendState::Sending
+                };
+            }
+            (
+                FirmwareSendState::WaitingAccept | FirmwareSendState::Sending | FirmwareSendState::WaitingResult,
+                FirmwareMsg::Finished { error },
+            ) => {
+                dev_info!("Firmware transfer finished: {:?}", error);
+                self.state = match error {
+                    None => FirmwareSendState::Completed,
+                    Some(error) => FirmwareSendState::Failed(*error),
+                };
+            }
+            _ => {
+                dev_debug!("Ignoring unexpected firmware message in state {:?}", self.state);
+            }
+        }
+    }
+
+    /// Sends the offer or as many chunks as the link can queue, wrapping them
+    /// with `wrap`.
+    pub fn poll<M: Clone + core::fmt::Debug, L: SplitBusLike<M>, W: Fn(FirmwareMsg) -> M>(
+        &mut self,
+        link: &mut L,
+        wrap: W,
+    ) {
+        match self.state {
+            FirmwareSendState::Offering => {
+                let offer = FirmwareMsg::Offer {
+                    image_len: self.image_len(),
+                    image_crc: self.image_crc,
+                };
+                match link.transfer(wrap(offer)) {
+                    Ok(()) => self.state = FirmwareSendState::WaitingAccept,
+                    Err(e) => self.on_transfer_error(e),
+                }
+            }
+            FirmwareSendState::Sending => {
+                while self.state == FirmwareSendState::Sending {
+                    let start = self.offset as usize;
+                    let len = (self.image.len() - start).min(FIRMWARE_CHUNK_LEN);
+                    let mut data = [0u8; FIRMWARE_CHUNK_LEN];
+                    data[..len].copy_from_slice(&self.image[start..start + len]);
+
+                    let chunk = FirmwareMsg::Chunk {
+                        offset: self.offset,
+                        len: len as u8,
+                        data,
+                    };
+                    match link.transfer(wrap(chunk)) {
+                        Ok(()) => {
+                            self.offset += len as u32;
+                            if self.offset == self.image_len() {
+                                self.state = FirmwareSendState::WaitingResult;
+                            }
+                        }
+                        Err(e) => {
+                            self.on_transfer_error(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            FirmwareSendState::WaitingAccept
+            | FirmwareSendState::WaitingResult
+            | FirmwareSendState::Completed
+            | FirmwareSendState::Failed(_) => {}
+        }
+    }
+
+    fn on_transfer_error(&mut self, error: TransferError) {
+        match error {
+            // Sent again on the next poll.
+            TransferError::BufferOverflow => {}
+            TransferError::LinkDown => self.state = FirmwareSendState::Offering,
+            _ => {
+                dev_warn!("Cannot send firmware message: {:?}", error);
+                self.state = FirmwareSendState::Failed(FirmwareError::Transfer);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareRecvState {
+    /// No image has been offered yet.
+    Idle,
+    Receiving,
+    /// The whole image has been stored, and it is valid.
+    Completed,
+    Failed(FirmwareError),
+}
+
+/// Receives a firmware image sent by a [`FirmwareSender`] in the peer, and
+/// writes it into a [`FirmwareStorage`].
+pub struct FirmwareReceiver<S: FirmwareStorage> {
+    storage: S,
+    image_len: u32,
+    image_crc: u32,
+    received: u32,
+    state: FirmwareRecvState,
+    // The answer that has yet to be sent. Only the last one matters.
+    reply: Option<FirmwareMsg>,
+}
+
+impl<S: FirmwareStorage> FirmwareReceiver<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            image_len: 0,
+            image_crc: 0,
+            received: 0,
+            state: FirmwareRecvState::Idle,
+            reply: None,
+        }
+    }
+
+    pub fn state(&self) -> FirmwareRecvState {
+        self.state
+    }
+
+    /// Returns the number of bytes of the image stored so far.
+    pub fn received_len(&self) -> u32 {
+        self.received
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Handles a message received from the sender.
+    pub fn on_msg(&mut self, msg: &FirmwareMsg) {
+        match msg {
+            FirmwareMsg::Offer { image_len, image_crc } => self.on_offer(*image_len, *image_crc),
+            FirmwareMsg::Chunk { offset, len, data } => self.on_chunk(*offset, &data[..(*len as usize).min(FIRMWARE_CHUNK_LEN)]),
+            FirmwareMsg::Accept { .. } | FirmwareMsg::Finished { .. } => {
+                dev_debug!("Ignoring unexpected firmware message {:?}", msg);
+            }
+        }
+    }
+
+    /// Sends the pending answer to the sender, if any, wrapping it with
+    /// `wrap`.
+    pub fn poll<M: Clone + core::fmt::Debug, L: SplitBusLike<M>, W: Fn(FirmwareMsg) -> M>(
+        &mut self,
+        link: &mut L,
+        wrap: W,
+    ) {
+        if let Some(reply) = self.reply.take() {
+            if link.transfer(wrap(reply.clone())).is_err() {
+                self.reply = Some(reply);
+            }
+        }
+    }
+
+    fn on_offer(&mut self, image_len: u32, image_crc: u32) {
+        let same_image = self.image_len == image_len && self.image_crc == image_crc;
+        match self.state {
+            FirmwareRecvState::Receiving if same_image => {
+                self.reply = Some(FirmwareMsg::Accept { offset: self.received });
+                return;
+            }
+            FirmwareRecvState::Completed if same_image => {
+                self.reply = Some(FirmwareMsg::Finished { error: None });
+                return;
+            }
+            _ => {}
+        }
+
+        if image_len == 0 || image_len > self.storage.capacity() {
+            dev_warn!("Rejecting firmware image of {} bytes", image_len);
+            self.fail(FirmwareError::Rejected);
+            return;
+        }
+
+        if self.storage.begin(image_len).is_err() {
+            dev_warn!("Cannot prepare the firmware storage");
+            self.fail(FirmwareError::Storage);
+            return;
+        }
+
+        dev_info!("Receiving firmware image of {} bytes", image_len);
+        self.image_len = image_len;
+        self.image_crc = image_crc;
+        self.received = 0;
+        self.state = FirmwareRecvState::Receiving;
+        self.reply = Some(FirmwareMsg::Accept { offset: 0 });
+    }
+
+    fn on_chunk(&mut self, offset: u32, data: &[u8]) {
+        if self.state != FirmwareRecvState::Receiving
+            || offset != self.received
+            || data.len() as u32 > self.image_len - self.received
+        {
+            // Chunks queued before the transfer was resumed.
+            dev_debug!("Ignoring unexpected firmware chunk at offset {}", offset);
+            return;
+        }
+
+        if self.storage.write(offset, data).is_err() {
+            dev_warn!("Cannot write firmware chunk at offset {}", offset);
+            self.fail(FirmwareError::Storage);
+            return;
+        }
+
+        self.received += data.len() as u32;
+        if self.received == self.image_len {
+            match self.stored_crc() {
+                Ok(crc) if crc == self.image_crc => {
+                    dev_info!("Firmware image received successfully");
+                    self.state = FirmwareRecvState::Completed;
+                    self.reply = Some(FirmwareMsg::Finished { error: None });
+                }
+                Ok(_) => {
+                    dev_warn!("Firmware image CRC mismatch");
+                    self.fail(FirmwareError::CrcMismatch);
+                }
+                Err(_) => {
+                    dev_warn!("Cannot read back the firmware image");
+                    self.fail(FirmwareError::Storage);
+                }
+            }
+        }
+    }
+
+    /// Computes the CRC-32 of the image, as it has been stored.
+    fn stored_crc(&mut self) -> Result<u32, S::Error> {
+        let mut digest = FIRMWARE_CRC32.digest();
+        let mut buf = [0u8; FIRMWARE_CHUNK_LEN];
+        let mut offset = 0;
+        while offset < self.image_len {
+            let len = ((self.image_len - offset) as usize).min(FIRMWARE_CHUNK_LEN);
+            self.storage.read(offset, &mut buf[..len])?;
+            digest.update(&buf[..len]);
+            offset += len as u32;
+        }
+        Ok(digest.finalize())
+    }
+
+    fn fail(&mut self, error: FirmwareError) {
+        self.state = FirmwareRecvState::Failed(error);
+        self.reply = Some(FirmwareMsg::Finished { error: Some(error) });
+    }
+}
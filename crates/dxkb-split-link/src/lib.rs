@@ -6,6 +6,44 @@
  and they are received in the same order as it was send. The transport
  messages exchanged are generic and can be easily extended.
 
+ User messages are sent through one of two channels (see [`Channel`]), each
+ one with its own sequence numbers and send window. The messages queued in
+ the priority channel are sent before any queued message of the normal one,
+ and neither of them is held back by the losses or the fragments of the
+ other, while the order of the messages of each channel is kept.
+
+ Messages that don't need to be delivered reliably, like periodic state
+ updates, can also be sent as datagrams (see
+ [`SplitBusLike::transfer_unreliable`]), which are sent only once, without
+ sequence number nor ACK, so a stale one is never replayed after a loss.
+
+ The link goes through the states of [`LinkStatus`], and each frame and timer
+ is handled depending on the current one. The changes that matter to the user,
+ like the link going up or down, are queued as [`LinkEvent`]s, which can be
+ read with [`SplitBusLike::poll_event`].
+
+ Several small messages queued in the same channel can be packed in a single
+ frame (see [`SplitBus::set_coalescing`]), saving the overhead of a frame for
+ each of them. They still take a sequence number each, and they're ACK'ed as
+ if they had been sent one by one.
+
+ Each peer can also estimate the clock of the other one, by exchanging
+ timestamps periodically (see [`SplitBus::set_time_sync_interval`]), and
+ convert times between both clocks (see [`SplitBus::peer_time_to_local`]),
+ e.g for running animations in sync in both sides, or timestamping the events
+ of the peer.
+
+ A firmware image can be sent through the link as well, for updating the side
+ that isn't connected to the host (see [`firmware`]), and the frames can be
+ authenticated with a key shared by both peers (see [`auth`]).
+
+ On buses whose baud rate can be changed, both peers can agree on falling back
+ to a slower one when the frames keep being corrupted, and on climbing back up
+ once the link has been stable for a while (see [`SplitBus::set_baud_fallback`]).
+
+ Boards made of more than two units can share a single bus among all of them,
+ with a link for each pair of units (see [`addressing`]).
+
  ## Frame format
 
 Each frame has the following format:
@@ -26,9 +64,13 @@ Where:
   - `Preamble`: 8 bits that marks the beginning of the frame. It is set
     to the constant `0x99`.
 
-  - `Crc`: a CRC-8 computation of the Seq + Frame Type and Frame
-    Payload, using the same parameters as the SMBus CRC 8 calculation
-    ([ref](https://reveng.sourceforge.io/crc-catalogue/all.htm#crc.cat.crc-8-smbus)).
+  - `Crc`: a checksum of the Seq + Frame Type and Frame Payload. By
+    default, it is a CRC-8 computation using the same parameters as the
+    SMBus CRC 8 calculation
+    ([ref](https://reveng.sourceforge.io/crc-catalogue/all.htm#crc.cat.crc-8-smbus)),
+    but it can be replaced by a 16 bits CRC (see [`FrameIntegrity`]). The
+    frames that set up the link (`LinkProbe`, `Sync` and `SyncAck`) always
+    use the CRC-8, so that peers using a different checksum can detect it.
   - `Frame Type`: Specifies the current frame type. It can be set to:
      - `LinkProbe`: Frame that is sent at a fixed rate and allows the
        peer to determine if the link is down. If the peer stops
@@ -44,13 +86,22 @@ Where:
 #![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
 
+pub mod addressing;
+pub mod auth;
+pub mod firmware;
+pub mod log_ids;
+#[cfg(feature = "soak-test")]
+pub mod soak;
+
 use core::fmt::Debug;
 use core::marker::PhantomData;
 use core::time::Duration;
+use auth::{FrameAuth, MAX_AUTH_TAG_LEN, NoAuth};
 use crc::Table;
-use dxkb_common::bus::{BusPollError, BusRead, BusTransferError, BusWrite};
-use dxkb_common::time::Clock;
-use dxkb_common::{dev_debug, dev_error, dev_info, dev_trace, dev_warn};
+use dxkb_common::bus::{AttentionLine, BusBaudRateError, BusPollError, BusRead, BusTransferError, BusWrite, NoAttentionLine};
+use dxkb_common::time::{Clock, TimeDiff};
+use dxkb_common::watchdog::Watchdog;
+use dxkb_common::{ResetReason, dev_debug, dev_error, dev_event, dev_info, dev_trace, dev_warn};
 use heapless::Vec;
 use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
 use serde::de::DeserializeOwned;
@@ -74,6 +125,33 @@ pub trait SplitLinkTimings {
     /// just sent user message, before re-sending it in case that the
     /// peer hasn't properly received it.
     const MSG_REPLAY_DELAY_TIME: Duration;
+
+    /// Time without any CRC error after which the link tries to climb back
+    /// to the next faster baud rate, if it fell back to a slower one (see
+    /// [`SplitBus::set_baud_fallback`]).
+    const BAUD_RESTORE_STABLE_TIME: Duration = Duration::from_secs(60);
+
+    /// Number of times a user message is re-sent without being ACK'ed before
+    /// dropping it, so that a message the peer can never accept doesn't block
+    /// its channel forever (see [`SplitBus::set_on_message_dropped`]). Zero
+    /// re-sends the messages for as long as the link is up.
+    const MAX_RETRANSMISSIONS: u8 = 0;
+
+    /// The probe interval used instead of
+    /// [`Self::LINK_IDLE_PROBE_INTERVAL_TIME`] while the host of either side
+    /// is suspended (see [`SplitBus::set_local_suspended`]), so that the link
+    /// wakes up the MCUs less often.
+    const SUSPENDED_PROBE_INTERVAL_TIME: Duration = Duration::from_secs(1);
+
+    /// The max idle time used instead of [`Self::MAX_LINK_IDLE_TIME`] while
+    /// the host of either side is suspended. It must be longer than
+    /// [`Self::SUSPENDED_PROBE_INTERVAL_TIME`].
+    const SUSPENDED_MAX_LINK_IDLE_TIME: Duration = Duration::from_secs(5);
+
+    /// Max time the bus can stay busy without taking any new frame, before
+    /// the link stops feeding its watchdog (see [`SplitBus::set_watchdog`]),
+    /// since the DMA or the USART behind it are likely hung.
+    const TX_STALL_TIME: Duration = Duration::from_millis(500);
 }
 
 pub struct DefaultSplitLinkTimings {}
@@ -94,9 +172,58 @@ impl SplitLinkTimings for TestingTimings {
     const MSG_REPLAY_DELAY_TIME: Duration = Duration::from_millis(200);
 }
 
-const SPLIT_BUS_CRC: crc::Crc<u8, Table<1>> = crc::Crc::<u8, Table<1>>::new(&crc::CRC_8_SMBUS);
+const SPLIT_BUS_CRC8: crc::Crc<u8, Table<1>> = crc::Crc::<u8, Table<1>>::new(&crc::CRC_8_SMBUS);
+const SPLIT_BUS_CRC16: crc::Crc<u16, Table<1>> = crc::Crc::<u16, Table<1>>::new(&crc::CRC_16_IBM_3740);
 const FRAME_PRELUDE_BYTE: u8 = 0x99;
 
+/// The version of the split link protocol, exchanged during the link sync. It
+/// must be increased on every incompatible change of the frame format.
+pub const LINK_PROTOCOL_VERSION: u8 = 12;
+
+/// The max length of the checksum of a frame, among all the
+/// [`FrameIntegrity`] implementations.
+pub const MAX_CHECKSUM_LEN: usize = 2;
+
+/// The checksum used for detecting corrupted frames, chosen through a type
+/// parameter of [`SplitBus`]. Both peers must use the same one, which is
+/// checked during the link sync.
+pub trait FrameIntegrity {
+    /// Identifies the checksum during the link sync.
+    const ID: u8;
+
+    /// Length of the checksum, at most [`MAX_CHECKSUM_LEN`].
+    const LEN: usize;
+
+    /// Writes the checksum of `data` into `out`, whose length is `LEN`.
+    fn checksum(data: &[u8], out: &mut [u8]);
+}
+
+/// CRC-8 with the SMBus parameters. The default, enough for short frames.
+pub struct Crc8Smbus;
+
+impl FrameIntegrity for Crc8Smbus {
+    const ID: u8 = 0;
+    const LEN: usize = 1;
+
+    fn checksum(data: &[u8], out: &mut [u8]) {
+        out[0] = SPLIT_BUS_CRC8.checksum(data);
+    }
+}
+
+/// CRC-16/CCITT-FALSE (also known as CRC-16/IBM-3740), sent in big endian.
+/// Better suited for frames carrying large messages, for which the CRC-8 would
+/// miss too many errors.
+pub struct Crc16Ccitt;
+
+impl FrameIntegrity for Crc16Ccitt {
+    const ID: u8 = 1;
+    const LEN: usize = 2;
+
+    fn checksum(data: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&SPLIT_BUS_CRC16.checksum(data).to_be_bytes());
+    }
+}
+
 fn seq_diff(new: u8, cur: u8) -> i8 {
     // Since the sequence number space is limited to 8 bytes, we
     // divide the space in half and we consider that new is:
@@ -110,7 +237,7 @@ pub enum NoMsg {}
 
 #[repr(C)]
 pub struct Frame<M> {
-    crc: u8,
+    checksum: [u8; MAX_CHECKSUM_LEN],
     envelope: FrameContentEnvelope<M>,
 }
 
@@ -139,12 +266,181 @@ pub enum FrameContent<M> {
     LinkProbe {
         device_id: [u8; 16]
     },
-    Ack,
-    SyncAck,
+    Ack {
+        channel: Channel,
+    },
+    /// Confirms a Sync, announcing the protocol version and the checksum of
+    /// the peer as well (see [`SplitBus::set_protocol_version`] and
+    /// [`FrameIntegrity::ID`]), and the rest of the fields of the Sync.
+    SyncAck {
+        version: u8,
+        integrity: u8,
+        reset_reason: u8,
+        host: u8,
+        device_id: [u8; 16],
+    },
+    /// Starts the link sync. `reset_reason` is why the peer was reset (see
+    /// [`ResetReason::from_u8`]), if the link hasn't been up since then, or
+    /// zero otherwise. `host` is the [`HostPresence`] of the peer, for
+    /// electing the master side.
     Sync {
-        device_id: [u8; 16]
+        version: u8,
+        integrity: u8,
+        device_id: [u8; 16],
+        reset_reason: u8,
+        host: u8,
+    },
+    TransportMessage {
+        channel: Channel,
+        msg: M,
+    },
+    /// Tells the peer that the link is being torn down on purpose, so that it
+    /// can consider it down right away instead of waiting for the idle
+    /// timeout.
+    Bye {
+        reason: ByeReason
+    },
+    /// A fragment of a transport message that doesn't fit in a single frame
+    /// (see [`SplitBus::set_max_frame_len`]). The bytes of the fragment are
+    /// appended raw after the serialized envelope, and they're covered by the
+    /// CRC too. Each fragment is delivered reliably, as any other transport
+    /// message, and the message is only received once every fragment has
+    /// arrived.
+    TransportFragment {
+        channel: Channel,
+        index: u8,
+        count: u8,
+    },
+    /// A transport message that is not delivered reliably: it doesn't use
+    /// any sequence number, so Seq is always zero, it is never ACK'ed nor
+    /// re-sent, and it is only received if it fits in a single frame.
+    Datagram {
+        msg: M,
+    },
+    /// Several consecutive transport messages of the same channel, sent in a
+    /// single frame (see [`SplitBus::set_coalescing`]). Seq is the one of the
+    /// first message, and the following ones take the following seq
+    /// numbers. Each message is appended raw after the serialized envelope,
+    /// preceded by its length in an u8, and they're covered by the CRC too.
+    /// The peer ACKs the last message it accepted, as ACKs are cumulative.
+    TransportBatch {
+        channel: Channel,
+        count: u8,
+    },
+    /// Asks the peer for its current time, for estimating its clock (see
+    /// [`SplitBus::set_time_sync_interval`]). `t0` is the local time when it
+    /// was sent, in microseconds. Seq is always zero.
+    //
+    // The times are little endian byte arrays, like the device IDs, so that
+    // they don't make the frames larger due to their alignment.
+    TimeRequest {
+        t0: [u8; 8],
+    },
+    /// The answer to a `TimeRequest`, with its `t0` and the time of the peer
+    /// when it was answered, `t1`, in microseconds. Seq is always zero.
+    TimeResponse {
+        t0: [u8; 8],
+        t1: [u8; 8],
+    },
+    /// Asks the peer to reboot into its bootloader (see
+    /// [`SplitBus::request_peer_bootloader`]). It is sent again until the
+    /// peer answers with a `BootloaderAck`. Seq is always zero.
+    BootloaderRequest,
+    /// Confirms a `BootloaderRequest`. The peer closes the link with
+    /// [`ByeReason::Bootloader`] right after. Seq is always zero.
+    BootloaderAck,
+    /// Asks the peer to switch the bus to another baud rate, in bauds (u32
+    /// LE), one of the ones set with [`SplitBus::set_baud_fallback`]. It is
+    /// sent again until the peer answers with a `BaudChangeAck`. Seq is
+    /// always zero.
+    BaudChange {
+        baud: [u8; 4],
+    },
+    /// Confirms a `BaudChange`. Both peers switch to the new baud rate once
+    /// this is sent. Seq is always zero.
+    BaudChangeAck {
+        baud: [u8; 4],
+    },
+    /// Asks the peer for a `Pong` with the same `id`, for measuring the round
+    /// trip time of the link (see [`SplitBus::measure_rtt`]). Seq is always
+    /// zero.
+    Ping {
+        id: u8,
+    },
+    /// The answer to a `Ping`. Seq is always zero.
+    Pong {
+        id: u8,
+    },
+    /// Tells the peer whether the USB bus of this side is suspended (1) or
+    /// not (0), see [`SplitBus::set_local_suspended`]. It is sent again until
+    /// the peer answers with a `PowerStateAck`. Seq is always zero.
+    PowerState {
+        suspended: u8,
+    },
+    /// Confirms a `PowerState`, with the same `suspended` value. Seq is always
+    /// zero.
+    PowerStateAck {
+        suspended: u8,
     },
-    TransportMessage(M),
+    /// Asks the peer to wake up its suspended host (see
+    /// [`SplitBus::request_peer_wakeup`]). It is not answered, nor sent
+    /// again. Seq is always zero.
+    WakeRequest,
+}
+
+/// The channel a user message is sent through.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// The channel used by [`SplitBusLike::transfer`], for bulk traffic or
+    /// anything that is not time sensitive.
+    Normal,
+    /// The channel used by [`SplitBusLike::transfer_priority`], for messages
+    /// that must not wait behind the normal ones, like key events.
+    Priority,
+}
+
+impl Channel {
+    const ALL: [Channel; 2] = [Channel::Priority, Channel::Normal];
+}
+
+impl<M> FrameContent<M> {
+    /// Whether raw data follows the envelope of the frame until its end, as
+    /// in transport fragments and batches.
+    const fn has_raw_data(&self) -> bool {
+        matches!(self, FrameContent::TransportFragment { .. } | FrameContent::TransportBatch { .. })
+    }
+
+    /// Whether this is one of the frames used for setting up the link, which
+    /// are always protected with [`Crc8Smbus`] regardless of the checksum
+    /// chosen.
+    fn is_link_setup(&self) -> bool {
+        matches!(self, FrameContent::LinkProbe { .. } | FrameContent::Sync { .. } | FrameContent::SyncAck { .. })
+    }
+
+    /// The channel of the transport frames and the ACKs.
+    const fn channel(&self) -> Option<Channel> {
+        match self {
+            FrameContent::Ack { channel }
+            | FrameContent::TransportMessage { channel, .. }
+            | FrameContent::TransportFragment { channel, .. }
+            | FrameContent::TransportBatch { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+}
+
+/// The reason why a peer has torn the link down on purpose.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByeReason {
+    /// The peer is being powered off, or it is going to stop using the link.
+    Shutdown,
+    /// The peer is entering a low power state. It will be back eventually.
+    Suspend,
+    /// The peer is rebooting into the bootloader, e.g for a firmware update.
+    /// It won't be back until the update is done.
+    Bootloader,
+    /// The peer is rebooting, and it will try to sync again right after.
+    Reset,
 }
 
 #[derive(Debug)]
@@ -154,10 +450,46 @@ pub enum FrameDecodeError {
     SerdeError(ssmarshal::Error),
 }
 
+/// Why a frame polled from the bus was dropped before reaching the link.
+enum RxError {
+    Unauthentic,
+    Decode(FrameDecodeError),
+}
+
+impl RxError {
+    fn drop_reason(&self) -> FrameDropReason {
+        match self {
+            RxError::Unauthentic => FrameDropReason::Unauthentic,
+            RxError::Decode(FrameDecodeError::PreludeError) => FrameDropReason::Prelude,
+            RxError::Decode(FrameDecodeError::CrcError) => FrameDropReason::Checksum,
+            RxError::Decode(FrameDecodeError::SerdeError(_)) => FrameDropReason::Malformed,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TransferError {
     BufferOverflow,
     LinkDown,
+    /// The message is so large that it would need more than 255 fragments.
+    MessageTooLarge,
+    /// The max frame length is too small for fitting the control frames.
+    InvalidMaxFrameLength,
+    /// The message doesn't fit in a single frame, which is required for
+    /// datagrams.
+    DatagramTooLarge,
+    /// The message couldn't be serialized, e.g because its serialized form
+    /// is larger than the frame buffers, which are sized after the size of
+    /// the message in memory.
+    EncodeError(ssmarshal::Error),
+}
+
+/// Why a frame couldn't be sent through the bus.
+#[derive(Debug)]
+enum FrameTxError {
+    /// The frame couldn't be encoded, so retrying it is pointless.
+    Encode(ssmarshal::Error),
+    Bus(BusTransferError),
 }
 
 impl<M> FrameContentEnvelope<M> {
@@ -165,28 +497,6 @@ impl<M> FrameContentEnvelope<M> {
     pub const fn new(seq: u8, content: FrameContent<M>) -> Self {
         Self { seq, content }
     }
-
-    #[inline(always)]
-    pub fn crc8(&self) -> u8 {
-        let self_bytes: &[u8] = unsafe {
-            // SAFETY: slice length matches size of the input
-            // envelop. Returned reference has the same lifetime
-            // as the envelope lifetime.
-            core::slice::from_raw_parts(
-                self as *const FrameContentEnvelope<M> as *const u8,
-                size_of::<FrameContentEnvelope<M>>(),
-            )
-        };
-        SPLIT_BUS_CRC.checksum(self_bytes)
-    }
-
-    #[inline(always)]
-    pub fn into_frame(self) -> Frame<M> {
-        Frame {
-            crc: self.crc8(),
-            envelope: self,
-        }
-    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -200,17 +510,459 @@ pub enum LinkStatus {
     // change.
     /// The link has been sync'ed and is ready to transmit or receive frames.
     Up,
+
+    /// The peer answered the sync with a different protocol version or frame
+    /// checksum, so no message is exchanged with it. The sync is only retried
+    /// from time to time, in case the firmware of the peer is updated.
+    Incompatible,
+}
+
+/// Why the link went down the last time. See [`SplitBus::link_status_detail`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkDownReason {
+    /// The link hasn't gone down yet since the split bus was created or reset.
+    Initial,
+    /// Nothing was received from the peer for
+    /// [`SplitLinkTimings::MAX_LINK_IDLE_TIME`].
+    IdleTimeout,
+    /// The peer didn't answer our sync in
+    /// [`SplitLinkTimings::MAX_SYNC_ACK_WAIT_TIME`].
+    SyncTimeout,
+    /// Too many ACKs and transport frames were received in a row with an
+    /// unexpected seq number. See [`SplitBus::set_max_seq_errors`].
+    TooManySeqErrors,
+    /// The peer tried to sync with our own device ID, which usually means
+    /// that our frames are being received back.
+    Loopback,
+    /// The peer closed the link on purpose, for the given reason.
+    PeerClosed(ByeReason),
+    /// We closed the link on purpose, through [`SplitBus::shutdown`] or
+    /// [`SplitBus::reset`].
+    Closed,
+}
+
+impl LinkStatus {
+    /// Returns whether the link can go from this status to `next`. Every
+    /// status can go to any other, except that an Up link cannot go back to
+    /// Sync: a sync started by the peer while the link is up keeps it up.
+    pub const fn can_transition_to(self, next: LinkStatus) -> bool {
+        !matches!((self, next), (LinkStatus::Up, LinkStatus::Sync))
+    }
+}
+
+/// Default number of ACKs and transport frames that can be received in a row
+/// with an unexpected seq number before the link is set down. See
+/// [`SplitBus::set_max_seq_errors`].
+pub const DEFAULT_MAX_SEQ_ERRORS: u8 = 32;
+
+/// Number of CRC errors within [`BAUD_FALLBACK_WINDOW`] after which the link
+/// falls back to the next slower baud rate. See
+/// [`SplitBus::set_baud_fallback`].
+pub const BAUD_FALLBACK_CRC_ERRORS: u8 = 8;
+
+/// The period the CRC errors are counted in, for falling back to a slower
+/// baud rate.
+pub const BAUD_FALLBACK_WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of times a baud rate change is proposed to the peer without an
+/// answer before giving up.
+const MAX_BAUD_CHANGE_ATTEMPTS: u8 = 5;
+
+/// The state of the fallback of the baud rate of the bus. See
+/// [`SplitBus::set_baud_fallback`].
+struct BaudFallback<T> {
+    /// The baud rates to fall back to, from the fastest one to the slowest
+    /// one, and the index of the current one.
+    rates: &'static [u32],
+    index: usize,
+    /// The CRC errors counted since `window_start`, which is the uptime of
+    /// the bus in nanoseconds.
+    crc_errors: u8,
+    window_start: u64,
+    /// The uptime of the bus in nanoseconds at the last CRC error or baud
+    /// rate change, for telling when the link is stable enough for trying a
+    /// faster rate. That takes longer than the range of some clocks, so it
+    /// isn't an instant.
+    stable_since: u64,
+    /// The index of the rate proposed to the peer, the last time it was sent
+    /// and the number of times it has been sent, until the peer ACKs it.
+    proposal: Option<(usize, T, u8)>,
+    /// The index of the rate to switch to, once the frames queued before
+    /// agreeing on it are sent.
+    switch_to: Option<usize>,
+}
+
+/// Min time between the two samples the skew of the clock of the peer is
+/// measured from, since the offset of a single sample is only accurate to a
+/// few microseconds.
+const MIN_SKEW_BASELINE_US: u64 = 1_000_000;
+
+/// An estimate of the clock of the peer, relative to the local one, from the
+/// answers to the time requests.
+#[derive(Debug, Clone, Copy)]
+struct PeerClockEstimate {
+    /// The local time of the last sample, in microseconds.
+    sample_time_us: u64,
+    /// The time of the peer minus the local time, on the last sample.
+    offset_us: i64,
+    /// The local time and the offset of the sample the skew is measured from.
+    skew_ref_time_us: u64,
+    skew_ref_offset_us: i64,
+    /// How much faster the clock of the peer runs, in parts per million, once
+    /// the samples span enough time for measuring it.
+    skew_ppm: Option<i32>,
+}
+
+impl PeerClockEstimate {
+    /// Returns the time of the peer minus the local time, at the given local
+    /// time, in microseconds.
+    fn offset_at(&self, local_us: u64) -> i64 {
+        let Some(skew_ppm) = self.skew_ppm else {
+            return self.offset_us;
+        };
+
+        let elapsed = local_us as i64 - self.sample_time_us as i64;
+        self.offset_us + (elapsed * skew_ppm as i64) / 1_000_000
+    }
+}
+
+/// How close a side of the keyboard is to being enumerated by the host,
+/// announced to the peer for electing the master side. See
+/// [`SplitBus::set_host_presence`].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HostPresence {
+    /// There's no host on the USB port.
+    None = 0,
+    /// The USB port is powered by a host, but it hasn't configured the
+    /// device yet.
+    Vbus = 1,
+    /// The host has configured the device.
+    Configured = 2,
+}
+
+impl HostPresence {
+    /// Returns the host presence encoded in the byte. Unknown values are taken
+    /// as no host at all.
+    pub const fn from_u8(value: u8) -> HostPresence {
+        match value {
+            1 => HostPresence::Vbus,
+            2 => HostPresence::Configured,
+            _ => HostPresence::None,
+        }
+    }
+}
+
+/// The role of a side of the keyboard, elected by both peers during the link
+/// sync. See [`SplitBus::negotiated_role`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerRole {
+    Master,
+    Slave,
+}
+
+/// Function called with the new role of this side each time it changes. See
+/// [`SplitBus::set_on_role_changed`].
+pub type RoleChangedHook = fn(PeerRole);
+
+/// Function called with each user message dropped after being re-sent
+/// [`SplitLinkTimings::MAX_RETRANSMISSIONS`] times, and the channel it was
+/// queued in.
+pub type MessageDroppedHook<Msg> = fn(Channel, &Msg);
+
+/// What is known about a frame seen by a [`FrameObserver`], apart from its raw
+/// bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub seq: u8,
+    /// The frame type, as encoded on the wire: the index of the variant of
+    /// the frame content.
+    pub frame_type: u8,
+    /// The channel of transport frames and ACKs.
+    pub channel: Option<Channel>,
+}
+
+/// Why a frame received from the bus was dropped before reaching the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDropReason {
+    /// It doesn't start with the preamble byte.
+    Prelude,
+    /// Its checksum doesn't match.
+    Checksum,
+    /// It couldn't be parsed, e.g because it is truncated.
+    Malformed,
+    /// Its authentication tag doesn't match.
+    Unauthentic,
+    /// The bytes that followed a valid frame in the same chunk aren't a frame.
+    Leftover,
+    /// It didn't fit in the buffer it was read into, so its bytes are not
+    /// known.
+    Overflow,
+}
+
+/// Sees every frame that goes through the bus of a [`SplitBus`], e.g for
+/// streaming a trace of the link through the logger, without a logic
+/// analyzer. Frames are seen as they are sent or received, so the ones that
+/// are later discarded by the link, like duplicates, are seen anyway. See
+/// [`SplitBus::set_frame_observer`].
+///
+/// The observer is called in the middle of the polls of the bus, so it must
+/// be quick.
+pub trait FrameObserver {
+    /// Called with each frame sent, including its authentication tag.
+    fn on_tx(&self, _raw: &[u8], _info: &FrameInfo) {}
+
+    /// Called with each frame received that was decoded and authenticated.
+    fn on_rx(&self, _raw: &[u8], _info: &FrameInfo) {}
+
+    /// Called with each frame received that was dropped before decoding it.
+    fn on_drop(&self, _raw: &[u8], _reason: FrameDropReason) {}
+}
+
+/// Default number of control frames that can be queued at once. See
+/// [`SplitBus`].
+pub const DEFAULT_CONTROL_QUEUE_LEN: usize = 4;
+
+/// Function that merges a user message into the last one queued on its
+/// channel, returning whether it could. See [`TxOverflowPolicy::Coalesce`].
+pub type CoalesceHook<Msg> = fn(&mut Msg, &Msg) -> bool;
+
+/// What is done with a user message queued on a channel whose queue is full.
+/// See [`SplitBus::set_tx_overflow_policy`].
+#[derive(Debug)]
+pub enum TxOverflowPolicy<Msg> {
+    /// The message is refused with [`TransferError::BufferOverflow`].
+    Reject,
+    /// The oldest message that hasn't been sent yet is dropped to make room
+    /// for the new one, for the traffic in which only the latest messages
+    /// matter. The message is refused if every queued one is in flight.
+    DropOldest,
+    /// The message is merged with the given function into the last queued
+    /// one, if it hasn't been sent yet, e.g for packing several key events
+    /// into a single message. It is refused if they can't be merged.
+    Coalesce(CoalesceHook<Msg>),
+}
+
+/// Max number of [`LinkEvent`]s kept until they are read with
+/// [`SplitBusLike::poll_event`]. The oldest ones are dropped after that.
+pub const LINK_EVENT_QUEUE_LEN: usize = 8;
+
+/// A change in the link observed by a [`SplitBus`], e.g for telling the user
+/// that the other half of the keyboard was disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// The link has been established, and messages can be sent through it.
+    Up,
+    /// The link went down, either because it failed or because one of the
+    /// peers closed it. Every queued message has been dropped.
+    Down,
+    /// The peer started a new sync while the link was up, e.g because it was
+    /// reset without closing the link first. The link stays up, but the
+    /// sequence numbers start over.
+    Resync,
+    /// The peer closed the link because it is rebooting. It follows a
+    /// [`LinkEvent::Down`] if the link was up, and the peer will try to sync
+    /// again soon.
+    PeerReset,
+    /// The peer uses a different protocol version or frame checksum, so the
+    /// link cannot be established.
+    Incompatible,
+    /// The peer asked this side to reboot into its bootloader, and the request
+    /// has been ACK'ed already. The user should close the link with
+    /// [`ByeReason::Bootloader`] (see [`SplitBus::shutdown`]) and reboot.
+    /// Only emitted once until the link goes down.
+    BootloaderRequested,
+    /// The frames sent are being received back, e.g because the TX and RX
+    /// lines are shorted, or the cable is miswired. The link is kept down
+    /// instead of trying to sync with itself. Only emitted once until a frame
+    /// from an actual peer is received. See [`SplitBus::is_loopback_detected`].
+    Loopback,
+    /// The peer has booted since the link was last up, for the given reason
+    /// (see [`SplitBus::set_reset_reason`]). It follows a
+    /// [`LinkEvent::Down`] if the link was up when the peer reset, unless
+    /// the peer synced again before the link timed out.
+    PeerRebooted(ResetReason),
+    /// The USB bus of the peer has been suspended, so this side should save
+    /// power too, e.g by scanning its matrix less often. See
+    /// [`SplitBus::is_peer_suspended`].
+    PeerSuspended,
+    /// The USB bus of the peer has been resumed, or the link went down while
+    /// it was suspended.
+    PeerResumed,
+    /// The peer asked this side to wake up its suspended host, e.g because a
+    /// key was pressed in it. See [`SplitBus::request_peer_wakeup`].
+    WakeRequested,
+}
+
+/// Cumulative statistics about the link health since the split bus was
+/// created. These can be persisted and accumulated across power cycles, for
+/// detecting things like the degradation of the cable over time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Number of user messages re-sent because no ACK was received in time.
+    pub retransmissions: u32,
+
+    /// Number of frames dropped because of a CRC mismatch.
+    pub crc_errors: u32,
+
+    /// Number of times the link has gone down after being up.
+    pub link_down_count: u32,
+
+    /// Number of frames sent through the bus, of any kind.
+    pub frames_sent: u32,
+
+    /// Number of valid frames received from the bus, of any kind.
+    pub frames_received: u32,
+
+    /// Number of ACKs received for messages that were already ACK'ed, which
+    /// usually means that an ACK took longer than expected or the message
+    /// was re-sent too early.
+    pub duplicate_acks: u32,
+
+    /// Smoothed estimate of the time between sending a user message and
+    /// receiving its ACK, in microseconds, or zero if there's no estimate
+    /// yet. Only messages that weren't re-sent are measured.
+    pub rtt_us: u32,
+
+    /// Number of times a transport message has been serialized. Each message
+    /// is serialized once while it is in the head of its queue, however many
+    /// fragments or retransmissions it takes.
+    pub msg_serializations: u32,
+
+    /// Number of transport messages sent in the same frame as a previous
+    /// one, instead of in a frame of their own.
+    pub coalesced_msgs: u32,
+
+    /// Number of transport frames dropped because their seq number was lower
+    /// than expected, which means that the peer re-sent a message that was
+    /// already received, usually because its ACK was lost.
+    pub duplicate_frames: u32,
+
+    /// Number of transport frames dropped because their seq number was
+    /// greater than expected, which means that a previous message was lost.
+    pub out_of_order_frames: u32,
+
+    /// Number of ACKs received for messages that were never sent, or that
+    /// were not in flight anymore.
+    pub unexpected_acks: u32,
+
+    /// Number of frames dropped because their authentication tag was wrong
+    /// (see [`auth`]).
+    pub auth_failures: u32,
+
+    /// Number of transport frames received ahead of the expected one that
+    /// were held until the missing ones arrived, instead of being dropped.
+    /// See [`SplitBus::set_rx_reorder_depth`].
+    pub reordered_frames: u32,
+
+    /// Number of frames recovered from chunks received from the bus that
+    /// didn't start with the preamble, usually because of noise on the line
+    /// right before the frame.
+    pub resynced_frames: u32,
+
+    /// Number of user messages dropped because they were re-sent
+    /// [`SplitLinkTimings::MAX_RETRANSMISSIONS`] times without being ACK'ed.
+    pub dropped_msgs: u32,
+
+    /// Max number of messages that have been waiting at once in the queue of
+    /// a user channel, including the ones in flight, or in the datagram
+    /// queue. Once it gets close to `TX_QUEUE_LEN`, the queues may be too
+    /// short for the traffic of the target.
+    pub user_tx_queue_high_watermark: u32,
+
+    /// Max number of frames that have been waiting at once in the control
+    /// queue. Once it gets close to `CONTROL_QUEUE_LEN`, the control queue may
+    /// be too short.
+    pub control_tx_queue_high_watermark: u32,
+
+    /// Number of user messages and datagrams queued while their queue was
+    /// full, regardless of whether they were refused or room was made for
+    /// them (see [`TxOverflowPolicy`]).
+    pub user_tx_queue_full: u32,
+
+    /// Number of control frames that couldn't be queued because the control
+    /// queue was full, e.g answers to the peer that were dropped.
+    pub control_tx_queue_full: u32,
+}
+
+impl LinkStats {
+    /// Returns the sum of both stats, saturating on overflow. The round-trip
+    /// estimate is not a counter, so the one of `other` is kept, if any, and
+    /// the high watermarks of the queues are the max of both.
+    pub const fn accumulate(&self, other: &LinkStats) -> LinkStats {
+        LinkStats {
+            retransmissions: self.retransmissions.saturating_add(other.retransmissions),
+            crc_errors: self.crc_errors.saturating_add(other.crc_errors),
+            link_down_count: self.link_down_count.saturating_add(other.link_down_count),
+            frames_sent: self.frames_sent.saturating_add(other.frames_sent),
+            frames_received: self.frames_received.saturating_add(other.frames_received),
+            duplicate_acks: self.duplicate_acks.saturating_add(other.duplicate_acks),
+            rtt_us: if other.rtt_us != 0 { other.rtt_us } else { self.rtt_us },
+            msg_serializations: self.msg_serializations.saturating_add(other.msg_serializations),
+            coalesced_msgs: self.coalesced_msgs.saturating_add(other.coalesced_msgs),
+            duplicate_frames: self.duplicate_frames.saturating_add(other.duplicate_frames),
+            out_of_order_frames: self.out_of_order_frames.saturating_add(other.out_of_order_frames),
+            unexpected_acks: self.unexpected_acks.saturating_add(other.unexpected_acks),
+            auth_failures: self.auth_failures.saturating_add(other.auth_failures),
+            reordered_frames: self.reordered_frames.saturating_add(other.reordered_frames),
+            resynced_frames: self.resynced_frames.saturating_add(other.resynced_frames),
+            dropped_msgs: self.dropped_msgs.saturating_add(other.dropped_msgs),
+            user_tx_queue_high_watermark: if other.user_tx_queue_high_watermark > self.user_tx_queue_high_watermark {
+                other.user_tx_queue_high_watermark
+            } else {
+                self.user_tx_queue_high_watermark
+            },
+            control_tx_queue_high_watermark: if other.control_tx_queue_high_watermark > self.control_tx_queue_high_watermark {
+                other.control_tx_queue_high_watermark
+            } else {
+                self.control_tx_queue_high_watermark
+            },
+            user_tx_queue_full: self.user_tx_queue_full.saturating_add(other.user_tx_queue_full),
+            control_tx_queue_full: self.control_tx_queue_full.saturating_add(other.control_tx_queue_full),
+        }
+    }
+
+    /// Adds a round-trip sample to the estimate, as an exponentially weighted
+    /// moving average that gives 1/8 of the weight to the new sample, like TCP
+    /// does.
+    fn add_rtt_sample(&mut self, sample: Duration) {
+        let sample = sample.as_micros().min(u32::MAX as u128) as u32;
+        self.rtt_us = if self.rtt_us == 0 {
+            sample
+        } else {
+            ((self.rtt_us as u64 * 7 + sample as u64) / 8) as u32
+        };
+    }
 }
 
+/// The bus used for talking to the other half of the keyboard. It can be used
+/// as a trait object, e.g `&mut dyn SplitBusLike<Msg>`, for writing code that
+/// doesn't depend on the actual bus, at the cost of a dynamic call for each
+/// message received. The generic methods are not available through it, so
+/// messages are received with [`poll_dyn`](Self::poll_dyn) instead.
 pub trait SplitBusLike<Msg: Clone + Debug> {
-    fn poll<F: FnMut(&Msg) -> bool>(&mut self, recvf: F);
+    /// Hands the received messages to `recvf` by reference, until it returns
+    /// false or no more messages are available. The messages are decoded
+    /// straight from the receive buffer of the bus where it allows it, so
+    /// this is cheaper than [`poll_into_vec`](Self::poll_into_vec), which
+    /// clones each of them, on tight polling loops.
+    fn poll<F: FnMut(&Msg) -> bool>(&mut self, recvf: F)
+    where
+        Self: Sized;
+
+    /// Like [`poll`](Self::poll), but through a trait object, so that it can
+    /// be called on a `dyn SplitBusLike`.
+    fn poll_dyn(&mut self, recvf: &mut dyn FnMut(&Msg) -> bool);
 
     #[inline]
     fn poll_max<F: FnMut(&Msg, usize) -> ()>(
         &mut self,
         max_msg_count: usize,
         mut recvf: F,
-    ) -> usize {
+    ) -> usize
+    where
+        Self: Sized,
+    {
         let mut received = max_msg_count;
         self.poll(|msg| {
             recvf(msg, received);
@@ -222,24 +974,147 @@ pub trait SplitBusLike<Msg: Clone + Debug> {
     }
 
     #[inline]
-    fn poll_into_vec<const MAX: usize>(&mut self, buf: &mut Vec<Msg, MAX>) -> usize {
+    fn poll_into_vec<const MAX: usize>(&mut self, buf: &mut Vec<Msg, MAX>) -> usize
+    where
+        Self: Sized,
+    {
         self.poll_max(MAX, |msg, _| {
             buf.push(msg.clone()).unwrap();
         })
     }
     fn transfer(&mut self, message: Msg) -> Result<(), TransferError>;
+
+    /// Like [`transfer`](Self::transfer), but through the priority channel,
+    /// so that the message is sent before any message queued with
+    /// [`transfer`](Self::transfer). The order is only kept between the
+    /// messages of the same channel. Buses without channels just transfer
+    /// it as any other message.
+    #[inline]
+    fn transfer_priority(&mut self, message: Msg) -> Result<(), TransferError> {
+        self.transfer(message)
+    }
+
+    /// Sends the message as a datagram: it is sent only once, and it is never
+    /// re-sent if it is lost, so it may never reach the peer, but it doesn't
+    /// delay nor get delayed by the losses of the other messages. Meant for
+    /// periodic messages whose last value is the only one that matters.
+    /// Buses without datagrams just transfer it as any other message.
+    #[inline]
+    fn transfer_unreliable(&mut self, message: Msg) -> Result<(), TransferError> {
+        self.transfer(message)
+    }
+
+    /// Returns why the peer closed the link, if it did it with a Bye frame and
+    /// the link hasn't been up again since then.
+    #[inline]
+    fn peer_bye_reason(&self) -> Option<ByeReason> {
+        None
+    }
+
+    /// Returns the number of messages that can still be queued in the given
+    /// channel before [`transfer`](Self::transfer) starts failing with
+    /// [`TransferError::BufferOverflow`]. Buses without a queue return
+    /// `usize::MAX`.
+    #[inline]
+    fn tx_queue_free(&self, _channel: Channel) -> usize {
+        usize::MAX
+    }
+
+    /// Returns whether the queue of any channel is close to be full, because
+    /// messages are queued faster than the link can deliver them. Senders of
+    /// frequent messages should gather them into fewer ones while it lasts,
+    /// instead of filling the queue up. It is checked again on each call, so
+    /// it can be polled right after each transfer.
+    #[inline]
+    fn is_tx_congested(&self) -> bool {
+        false
+    }
+
+    /// Returns the oldest link event that hasn't been read yet, if any. Buses
+    /// without a link never have any.
+    #[inline]
+    fn poll_event(&mut self) -> Option<LinkEvent> {
+        None
+    }
+
+    /// Returns the estimated time of the peer minus the local one, in
+    /// microseconds, if known. Buses without a link never know it.
+    #[inline]
+    fn peer_time_offset(&self) -> Option<i64> {
+        None
+    }
+
+    /// Converts a time of the peer into the local one, in microseconds, if
+    /// its clock is known. See [`SplitBus::peer_time_to_local`].
+    #[inline]
+    fn peer_time_to_local(&self, _peer_us: u64) -> Option<u64> {
+        None
+    }
+
+    /// Sets how close this side is to being enumerated by the host, for
+    /// electing the master side with the peer. Ignored by buses without a
+    /// link.
+    #[inline]
+    fn set_host_presence(&mut self, _presence: HostPresence) {}
+
+    /// Returns the role of this side elected with the peer, if the link is up.
+    /// Buses without a link never have one.
+    #[inline]
+    fn negotiated_role(&self) -> Option<PeerRole> {
+        None
+    }
+
+    /// Tells the peer whether the USB bus of this side is suspended. See
+    /// [`SplitBus::set_local_suspended`]. Ignored by buses without a link.
+    #[inline]
+    fn set_local_suspended(&mut self, _suspended: bool) {}
+
+    /// Returns whether the peer has told that its USB bus is suspended. Buses
+    /// without a link never are.
+    #[inline]
+    fn is_peer_suspended(&self) -> bool {
+        false
+    }
+
+    /// Asks the peer to wake up its suspended host. See
+    /// [`SplitBus::request_peer_wakeup`]. Buses without a link fail as if the
+    /// link was down.
+    #[inline]
+    fn request_peer_wakeup(&mut self) -> Result<(), TransferError> {
+        Err(TransferError::LinkDown)
+    }
 }
 
+/// The split bus link. Up to `TX_QUEUE_LEN` user messages can be queued for
+/// transmission on each channel, and up to `TX_WINDOW` of them can be in
+/// flight at the same time on each channel, waiting for their ACK. A larger
+/// window improves the throughput of fast buses, at the cost of re-sending
+/// more messages when one is lost, since the peer drops every message
+/// received out of order. What happens when a queue is full is chosen for
+/// each channel with [`SplitBus::set_tx_overflow_policy`].
+///
+/// Up to `CONTROL_QUEUE_LEN` control frames, like the answers to the requests
+/// of the peer, can be queued apart from the user messages. They are only a
+/// few at once, so it doesn't need to grow along with `TX_QUEUE_LEN`.
 pub struct SplitBus<
     Msg,
     Ts: SplitLinkTimings,
     B: BusWrite + BusRead,
     CS: Clock,
     const TX_QUEUE_LEN: usize,
-> {
+    const TX_WINDOW: usize = 1,
+    I: FrameIntegrity = Crc8Smbus,
+    A: FrameAuth = NoAuth,
+    L: AttentionLine = NoAttentionLine,
+    const CONTROL_QUEUE_LEN: usize = DEFAULT_CONTROL_QUEUE_LEN,
+> where
+    [(); MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]:,
+{
     bus: B,
     clock: CS,
     link_status: LinkStatus,
+    /// The link events that haven't been read yet.
+    events: ConstGenericRingBuffer<LinkEvent, LINK_EVENT_QUEUE_LEN>,
     last_link_status_change_time: CS::TInstant,
     last_recv_frame_time: CS::TInstant,
     last_sent_frame_time: CS::TInstant,
@@ -249,15 +1124,173 @@ pub struct SplitBus<
     /// that they don't clash by chance).
     device_id: u128,
 
-    /// If not empty, indicates that we haven't received yet an ACK
-    /// from the peer indicating that it has received the user message
-    /// stored in the head of the `user_tx_queue`. The time value
-    /// inside the optional contains the last time the message was
-    /// re-sent.
-    user_msg_pending_ack_sent_time: Option<CS::TInstant>,
+    /// The queue that contains the frames that are queued to be sent
+    /// that are required to control the link. These differs from the
+    /// queues of the user channels in which the latter won't be read until
+    /// this one is empty, since control frames always takes
+    /// precedence over user transmission requests.
+    ///
+    /// The ACKs and the frames that set up the link are not queued here, but
+    /// kept as pending state (see [`UserChannel::rx_ack`] and
+    /// `pending_link_frame`) and sent before any queued frame, so that a
+    /// flood of them can't fill this queue. Every other control frame is only
+    /// queued after checking that there's room for it.
+    control_tx_queue: ConstGenericRingBuffer<FrameContentEnvelope<NoMsg>, CONTROL_QUEUE_LEN>,
+
+    /// The frame that sets up the link waiting to be sent, if any. It is
+    /// built when sent, so a newer one replaces the pending one instead of
+    /// being queued behind it.
+    pending_link_frame: Option<LinkFrame>,
+
+    /// The state of each user channel, indexed by [`Channel`].
+    channels: [UserChannel<Msg, CS::TInstant, TX_QUEUE_LEN, TX_WINDOW>; 2],
+
+    /// The datagrams waiting to be sent. They are sent after the messages of
+    /// the priority channel, but before the ones of the normal channel.
+    datagram_tx_queue: ConstGenericRingBuffer<Msg, TX_QUEUE_LEN>,
+
+    /// Max length of the frames sent through the bus. Transport messages that
+    /// don't fit in a frame of this length are fragmented.
+    max_frame_len: usize,
+
+    /// Whether the messages queued in the same channel are packed in a single
+    /// frame when possible. See [`Self::set_coalescing`].
+    coalesce: bool,
+
+    /// The time between the time requests sent to the peer, if enabled, and
+    /// the last time one was sent.
+    time_sync_interval: Option<Duration>,
+    last_time_request_time: Option<CS::TInstant>,
+    peer_clock: Option<PeerClockEstimate>,
+
+    /// The last time a bootloader request was sent to the peer, while it
+    /// hasn't been ACK'ed yet, and whether the peer has requested it to us.
+    peer_bootloader_request_time: Option<CS::TInstant>,
+    bootloader_requested: bool,
+
+    /// The id of the last ping sent to the peer and when it was sent, while
+    /// its pong hasn't been received yet, and the last round trip time
+    /// measured. See [`Self::measure_rtt`].
+    ping_id: u8,
+    ping_sent_time: Option<CS::TInstant>,
+    measured_rtt: Option<Duration>,
+
+    /// Whether the USB bus of this side is suspended, whether the peer has to
+    /// be told about it, along with the last time it was, and whether the
+    /// USB bus of the peer is suspended. See [`Self::set_local_suspended`].
+    local_suspended: bool,
+    power_state_pending: bool,
+    power_state_sent_time: Option<CS::TInstant>,
+    peer_suspended: bool,
+
+    /// Whether our own frames have been received back, and no frame from the
+    /// peer has been received since then.
+    loopback_detected: bool,
+
+    /// The baud rate fallback, if enabled.
+    baud_fallback: Option<BaudFallback<CS::TInstant>>,
+
+    /// The local time in nanoseconds at `uptime_ref`, for keeping a time that
+    /// doesn't wrap around, unlike some clocks.
+    uptime_ns: u64,
+    uptime_ref: CS::TInstant,
+
+    /// Number of ACKs and transport frames received in a row with an
+    /// unexpected seq number, and the number of them that sets the link down.
+    seq_errors: u8,
+    max_seq_errors: u8,
+
+    /// ACKs and transport frames whose seq number is off by at least this
+    /// from the expected one are logged whole. Zero disables it.
+    seq_jump_log_threshold: u8,
+
+    /// How far ahead of the expected seq number the transport messages
+    /// received are held for delivering them in order. Zero disables it.
+    rx_reorder_depth: u8,
 
-    /// The sequence number that will use the outgoing frames to the
-    /// peer.
+    /// A channel is congested when no more than this number of messages can
+    /// be queued in it. See [`SplitBusLike::is_tx_congested`].
+    tx_low_watermark: usize,
+
+    stats: LinkStats,
+
+    /// The authentication of the frames sent and received.
+    auth: A,
+
+    /// The attention line shared with the peer, and whether the peer was
+    /// asserting it on the last poll. See [`Self::with_attention_line`].
+    attention: L,
+    peer_attention: bool,
+
+    /// The reason of the last Bye frame received from the peer, if the link
+    /// hasn't been up again since then.
+    peer_bye_reason: Option<ByeReason>,
+
+    /// Why the link went down the last time.
+    link_down_reason: LinkDownReason,
+
+    /// Why this side was reset, until the link is up. See
+    /// [`Self::set_reset_reason`].
+    reset_reason: Option<ResetReason>,
+
+    /// Why the peer was reset the last time it reported it.
+    peer_reset_reason: Option<ResetReason>,
+
+    /// How close this side is to being enumerated by the host, and the
+    /// value announced to the peer during the last sync, for electing the
+    /// master side. See [`Self::set_host_presence`].
+    host_presence: HostPresence,
+    advertised_host_presence: HostPresence,
+    negotiated_role: Option<PeerRole>,
+    on_role_changed: Option<RoleChangedHook>,
+
+    /// The frames received after the one that was handled last, in the same
+    /// chunk.
+    rx_pending: Vec<u8, { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }>,
+
+    /// What is done when a user message is dropped. See
+    /// [`Self::set_on_message_dropped`].
+    on_message_dropped: Option<MessageDroppedHook<Msg>>,
+    resync_on_drop: bool,
+
+    /// See [`Self::set_frame_observer`].
+    frame_observer: Option<&'static dyn FrameObserver>,
+
+    /// See [`Self::set_watchdog`].
+    watchdog: Option<&'static dyn Watchdog>,
+    /// The frames sent as of the last time the watchdog was checked, and
+    /// since when the bus has been busy without taking any new one.
+    watchdog_frames_sent: u32,
+    tx_busy_since: Option<CS::TInstant>,
+    /// Whether the watchdog has been left unfed because of a stalled bus.
+    watchdog_starved: bool,
+
+    /// The protocol version announced to the peer, and the one announced by
+    /// the peer during the last sync, if any.
+    protocol_version: u8,
+    peer_protocol_version: Option<u8>,
+    _msg: PhantomData<Msg>,
+    _timings: PhantomData<Ts>,
+    _integrity: PhantomData<I>,
+}
+
+/// The state of one of the user channels.
+struct UserChannel<Msg, T, const TX_QUEUE_LEN: usize, const TX_WINDOW: usize>
+where
+    [(); MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]:,
+{
+    tx_queue: ConstGenericRingBuffer<Msg, TX_QUEUE_LEN>,
+
+    /// The state of each of the user messages that are in flight (sent
+    /// but not ACK'ed yet by the peer) was sent. Its length is the number
+    /// of messages in flight, which are the ones in the head of the
+    /// `tx_queue`, and it is never larger than the send window
+    /// (`TX_WINDOW`).
+    tx_in_flight: ConstGenericRingBuffer<InFlightMsg<T>, TX_WINDOW>,
+
+    /// The sequence number of the user message in the head of the
+    /// `tx_queue`. The following messages use the following
+    /// sequence numbers.
     tx_seq: u8,
 
     /// The sequence number that will use the current instance to
@@ -266,71 +1299,372 @@ pub struct SplitBus<
     /// dropped.
     rx_seq: u8,
 
-    /// The queue that contains the frames that are queued to be sent
-    /// that are required to control the link. These differs from the
-    /// `user_tx_queue` queue in which the latter won't be read until
-    /// this one is empty, since control frames always takes
-    /// precedence over user transmission requests (e.g an ACK for a
-    /// previous received message must be sent before any other
-    /// transport frame is sent)
-    control_tx_queue: ConstGenericRingBuffer<FrameContentEnvelope<NoMsg>, TX_QUEUE_LEN>,
-    user_tx_queue: ConstGenericRingBuffer<Msg, TX_QUEUE_LEN>,
-    _msg: PhantomData<Msg>,
-    _timings: PhantomData<Ts>,
-}
+    /// The seq number of the last transport message received that hasn't
+    /// been ACK'ed yet. ACKs are cumulative, so only the latest one needs to
+    /// be sent, and there's never more than one waiting per channel.
+    rx_ack: Option<u8>,
 
-pub struct MaxFrameLength<Msg> {
-    _msg: PhantomData<Msg>,
-}
+    /// The transport messages received ahead of `rx_seq`, indexed by how far
+    /// ahead their seq number is, so the first one is always empty. See
+    /// [`SplitBus::set_rx_reorder_depth`].
+    rx_reorder: [Option<Msg>; TX_WINDOW],
 
-impl<Msg> MaxFrameLength<Msg>
+    /// The serialized message in the head of the `tx_queue`, so that it isn't
+    /// serialized again for each of its fragments or retransmissions. Empty
+    /// while it hasn't been serialized yet.
+    tx_head_msg: Vec<u8, { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }>,
+
+    /// The fragment of the message in the head of the `tx_queue` that is
+    /// being sent, and the number of fragments of that message. The count is
+    /// zero if the message is not fragmented.
+    tx_fragment_index: u8,
+    tx_fragment_count: u8,
+
+    /// The data of the fragments received so far of the current incoming
+    /// message, the index of the next fragment expected, and the number of
+    /// fragments of that message.
+    rx_fragments: Vec<u8, { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }>,
+    rx_fragment_next: u8,
+    rx_fragment_count: u8,
+
+    /// What is done with the messages queued while `tx_queue` is full.
+    overflow_policy: TxOverflowPolicy<Msg>,
+}
+
+impl<Msg, T, const TX_QUEUE_LEN: usize, const TX_WINDOW: usize> UserChannel<Msg, T, TX_QUEUE_LEN, TX_WINDOW>
+where
+    [(); MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]:,
+{
+    fn new() -> Self {
+        Self {
+            tx_queue: ConstGenericRingBuffer::new(),
+            tx_in_flight: ConstGenericRingBuffer::new(),
+            tx_seq: 0,
+            rx_seq: 0,
+            rx_ack: None,
+            rx_reorder: [const { None }; TX_WINDOW],
+            tx_head_msg: Vec::new(),
+            tx_fragment_index: 0,
+            tx_fragment_count: 0,
+            rx_fragments: Vec::new(),
+            rx_fragment_next: 0,
+            rx_fragment_count: 0,
+            overflow_policy: TxOverflowPolicy::Reject,
+        }
+    }
+
+    fn reset_rx_fragments(&mut self) {
+        self.rx_fragments.clear();
+        self.rx_fragment_next = 0;
+        self.rx_fragment_count = 0;
+    }
+
+    fn reset_rx_reorder(&mut self) {
+        for slot in &mut self.rx_reorder {
+            *slot = None;
+        }
+    }
+
+    /// Moves on to the next seq number expected, along with the messages
+    /// held ahead of it.
+    fn next_rx_seq(&mut self) {
+        self.rx_seq = self.rx_seq.wrapping_add(1);
+        self.rx_reorder[0] = None;
+        self.rx_reorder.rotate_left(1);
+    }
+
+    /// Returns the number of messages in the head of `tx_queue` that have been
+    /// sent, in whole or in part.
+    fn sent_len(&self) -> usize {
+        if self.tx_fragment_count > 0 { 1 } else { self.tx_in_flight.len() }
+    }
+
+    /// Drops the oldest queued message that hasn't been sent yet. Returns
+    /// whether there was any.
+    fn drop_oldest_unsent(&mut self) -> bool {
+        let index = self.sent_len();
+        let len = self.tx_queue.len();
+        if index >= len {
+            return false;
+        }
+
+        // Every message is dequeued and queued back in the same order, but
+        // the dropped one.
+        for i in 0..len {
+            if let Some(msg) = self.tx_queue.dequeue() {
+                if i != index {
+                    self.tx_queue.push(msg);
+                }
+            }
+        }
+        if index == 0 {
+            self.tx_head_msg.clear();
+        }
+        true
+    }
+
+    /// Merges the message into the last queued one, if it hasn't been sent
+    /// yet. Returns whether it was merged.
+    fn coalesce_last(&mut self, merge: CoalesceHook<Msg>, message: &Msg) -> bool {
+        let len = self.tx_queue.len();
+        if self.sent_len() >= len {
+            return false;
+        }
+
+        let Some(last) = self.tx_queue.get_mut(len - 1) else {
+            return false;
+        };
+        if !merge(last, message) {
+            return false;
+        }
+        if len == 1 {
+            self.tx_head_msg.clear();
+        }
+        true
+    }
+
+    /// Drops every queued and in flight message.
+    fn clear(&mut self) {
+        self.tx_in_flight.clear();
+        self.tx_queue.clear();
+        self.tx_head_msg.clear();
+        self.tx_fragment_index = 0;
+        self.tx_fragment_count = 0;
+        self.rx_ack = None;
+        self.reset_rx_fragments();
+        self.reset_rx_reorder();
+    }
+}
+
+/// The frames that set up the link, which are sent from
+/// [`SplitBus::pending_link_frame`] instead of being queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkFrame {
+    Probe,
+    Sync,
+    SyncAck,
+}
+
+/// Where the control frame being sent comes from.
+enum ControlSource {
+    Link,
+    Ack(Channel),
+    Queue,
+}
+
+/// A user message that has been sent and is waiting for its ACK.
+struct InFlightMsg<I> {
+    /// The last time the message was sent.
+    sent_time: I,
+    /// The number of times the message has been re-sent. If any, it's not
+    /// known which of the transfers the ACK belongs to.
+    retransmissions: u8,
+}
+
+pub struct MaxFrameLength<Msg> {
+    _msg: PhantomData<Msg>,
+}
+
+impl<Msg> MaxFrameLength<Msg>
 where
     Msg: Sized,
 {
-    const MAX_FRAME_LENGTH: usize = size_of::<Frame<Msg>>() + 1; // Max frame length plus the preamble byte.
+    const MAX_FRAME_LENGTH: usize = size_of::<Frame<Msg>>() + 1 + MAX_AUTH_TAG_LEN; // Max frame length plus the preamble byte and the authentication tag.
 }
 
-// TODO Refactor code so that the code is based on the status of the link (like a state machine with actions on each transition and all that). (E.g move the code to an impl LinkStatus).
 impl<
     Msg: Clone + Debug + DeserializeOwned + Serialize,
     Ts: SplitLinkTimings,
     B: BusWrite + BusRead,
     CS: Clock,
     const TX_QUEUE_LEN: usize,
-> SplitBus<Msg, Ts, B, CS, TX_QUEUE_LEN>
+    const TX_WINDOW: usize,
+    I: FrameIntegrity,
+    A: FrameAuth,
+    L: AttentionLine,
+    const CONTROL_QUEUE_LEN: usize,
+> SplitBus<Msg, Ts, B, CS, TX_QUEUE_LEN, TX_WINDOW, I, A, L, CONTROL_QUEUE_LEN>
 where
     [(); MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]:,
     [(); MaxFrameLength::<NoMsg>::MAX_FRAME_LENGTH]:,
 {
-    pub fn new(bus: B, clock: CS, device_id: u128) -> Self {
+    /// Length of the frame fields that precede a serialized transport message:
+    /// Preamble, CRC, Seq, Frame Type and Channel. The authentication tag that
+    /// follows it is accounted here as well, as any other overhead.
+    const TRANSPORT_MESSAGE_HEADER_LEN: usize = 4 + I::LEN + A::TAG_LEN;
+
+    /// Length of the frame fields that precede the data of a transport
+    /// fragment: Preamble, CRC, Seq, Frame Type, Channel, the fragment index
+    /// and the fragment count, plus the authentication tag.
+    const TRANSPORT_FRAGMENT_HEADER_LEN: usize = 6 + I::LEN + A::TAG_LEN;
+
+    /// Length of the frame fields that precede the messages of a transport
+    /// batch: Preamble, CRC, Seq, Frame Type, Channel and the message count,
+    /// plus the authentication tag.
+    const TRANSPORT_BATCH_HEADER_LEN: usize = 5 + I::LEN + A::TAG_LEN;
+
+    /// Length of the frame fields that precede a serialized datagram:
+    /// Preamble, CRC, Seq and Frame Type, plus the authentication tag.
+    const DATAGRAM_HEADER_LEN: usize = 3 + I::LEN + A::TAG_LEN;
+
+    const fn assert_config_ok() {
+        assert!(I::LEN > 0 && I::LEN <= MAX_CHECKSUM_LEN, "Invalid checksum length");
+        assert!(A::TAG_LEN <= MAX_AUTH_TAG_LEN, "Invalid authentication tag length");
+        assert!(TX_WINDOW > 0, "The send window must be at least 1");
+        assert!(TX_WINDOW <= TX_QUEUE_LEN, "The send window cannot be larger than the TX queue");
+        assert!(CONTROL_QUEUE_LEN > 0, "The control queue must hold at least one frame");
+        // Otherwise, the seq numbers in flight cannot be told apart from
+        // the old ones (see seq_diff).
+        assert!(TX_WINDOW < 128, "The send window must be smaller than 128");
+    }
+
+    pub fn new(bus: B, clock: CS, device_id: u128) -> Self
+    where
+        A: Default,
+        L: Default,
+    {
+        Self::with_auth(bus, clock, device_id, A::default())
+    }
+
+    /// Like [`Self::new`], but authenticating the frames with the given
+    /// authentication, e.g one holding the key shared by both peers.
+    pub fn with_auth(bus: B, clock: CS, device_id: u128, auth: A) -> Self
+    where
+        L: Default,
+    {
+        Self::with_attention_line(bus, clock, device_id, auth, L::default())
+    }
+
+    /// Like [`Self::with_auth`], but signaling the peer through the given
+    /// attention line while there are frames waiting to be sent or being
+    /// sent. The bus is only drained while the peer signals us the same way,
+    /// so that the MCU can sleep between polls instead of polling the bus
+    /// while the peer has nothing to say.
+    pub fn with_attention_line(bus: B, clock: CS, device_id: u128, auth: A, attention: L) -> Self {
+        const { Self::assert_config_ok() }
         let cur = clock.current_instant();
 
         Self {
             bus,
             clock,
             link_status: LinkStatus::Down,
+            events: ConstGenericRingBuffer::new(),
             last_link_status_change_time: cur,
             last_recv_frame_time: cur,
             last_sent_frame_time: cur,
-            user_msg_pending_ack_sent_time: None,
-            tx_seq: 0,
-            rx_seq: 0,
             control_tx_queue: ConstGenericRingBuffer::new(),
-            user_tx_queue: ConstGenericRingBuffer::new(),
+            pending_link_frame: None,
+            channels: [UserChannel::new(), UserChannel::new()],
+            datagram_tx_queue: ConstGenericRingBuffer::new(),
+            max_frame_len: usize::MAX,
+            coalesce: false,
+            time_sync_interval: None,
+            last_time_request_time: None,
+            peer_clock: None,
+            peer_bootloader_request_time: None,
+            bootloader_requested: false,
+            ping_id: 0,
+            ping_sent_time: None,
+            measured_rtt: None,
+            local_suspended: false,
+            power_state_pending: false,
+            power_state_sent_time: None,
+            peer_suspended: false,
+            loopback_detected: false,
+            baud_fallback: None,
+            uptime_ns: 0,
+            uptime_ref: cur,
+            seq_errors: 0,
+            max_seq_errors: DEFAULT_MAX_SEQ_ERRORS,
+            seq_jump_log_threshold: 0,
+            rx_reorder_depth: 0,
+            tx_low_watermark: TX_QUEUE_LEN / 4,
+            stats: LinkStats::default(),
+            auth,
+            attention,
+            peer_attention: false,
+            peer_bye_reason: None,
+            link_down_reason: LinkDownReason::Initial,
+            reset_reason: None,
+            peer_reset_reason: None,
+            host_presence: HostPresence::None,
+            advertised_host_presence: HostPresence::None,
+            negotiated_role: None,
+            on_role_changed: None,
+            rx_pending: Vec::new(),
+            on_message_dropped: None,
+            resync_on_drop: true,
+            frame_observer: None,
+            watchdog: None,
+            watchdog_frames_sent: 0,
+            tx_busy_since: None,
+            watchdog_starved: false,
+            protocol_version: LINK_PROTOCOL_VERSION,
+            peer_protocol_version: None,
             device_id,
             _msg: PhantomData,
             _timings: PhantomData,
+            _integrity: PhantomData,
+        }
+    }
+
+    fn checksum<J: FrameIntegrity>(buf: &[u8]) -> [u8; MAX_CHECKSUM_LEN] {
+        let mut checksum = [0u8; MAX_CHECKSUM_LEN];
+        J::checksum(buf, &mut checksum[0..J::LEN]);
+        dev_trace!("CRC for {:x?} = {:x?}", &buf, &checksum[0..J::LEN]);
+        checksum
+    }
+
+    /// Decodes the frame at the start of the buffer, returning it along with
+    /// the bytes that follow the envelope. These are the raw data of transport
+    /// fragments and batches, or the frames received right after any other
+    /// frame.
+    fn decode_frame(buf: &[u8]) -> Result<(Frame<Msg>, &[u8]), FrameDecodeError> {
+        // The frames that set up the link use the CRC-8 anyway. They're told
+        // apart by their checksum before parsing them, since parsing a frame
+        // with the wrong layout would yield garbage.
+        if I::ID != Crc8Smbus::ID {
+            if let Some(len) = Self::link_setup_layout_len(buf) {
+                let (setup, leftover) = buf.split_at(len);
+                if Self::checksum::<Crc8Smbus>(&setup[2..])[0] == setup[1] {
+                    if let Ok((frame, _)) = Self::decode_frame_with::<Crc8Smbus>(setup) {
+                        if frame.envelope.content.is_link_setup() {
+                            return Ok((frame, leftover));
+                        }
+                    }
+                }
+            }
+        }
+
+        let res = Self::decode_frame_with::<I>(buf);
+        if matches!(res, Err(FrameDecodeError::CrcError)) {
+            dev_event!(warn, log_ids::FRAME_CRC_MISMATCH, "Frame CRC mismatch. Dropping frame");
         }
+        res
     }
 
-    fn crc8(buf: &[u8]) -> u8 {
-        let crc = SPLIT_BUS_CRC.checksum(&buf);
-        dev_trace!("CRC for {:x?} = {:x}", &buf, crc);
-        return crc;
+    /// Returns the length of the frame at the start of the buffer if it has
+    /// the frame type of any of the frames that set up the link, with the
+    /// layout of the CRC-8, and the buffer is long enough for it. This way,
+    /// other frames whose checksum happens to match aren't parsed as such,
+    /// which could read past the end of the frame.
+    fn link_setup_layout_len(buf: &[u8]) -> Option<usize> {
+        (0..3).find_map(|i| {
+            let content = match i {
+                0 => FrameContent::LinkProbe { device_id: [0; 16] },
+                1 => FrameContent::Sync { version: 0, integrity: 0, device_id: [0; 16], reset_reason: 0, host: 0 },
+                _ => FrameContent::SyncAck { version: 0, integrity: 0, reset_reason: 0, host: 0, device_id: [0; 16] },
+            };
+            let mut setup_frame = [0u8; { MaxFrameLength::<NoMsg>::MAX_FRAME_LENGTH }];
+            let len =
+                Self::encode_frame_with::<Crc8Smbus, NoMsg>(&mut setup_frame, &FrameContentEnvelope::new(0, content))
+                    .ok()?;
+            (len <= buf.len() && buf[0] == FRAME_PRELUDE_BYTE && buf[3] == setup_frame[3]).then_some(len)
+        })
     }
 
-    fn decode_frame(buf: &[u8]) -> Result<Frame<Msg>, FrameDecodeError> {
-        if buf.len() < 4 {
+    fn decode_frame_with<J: FrameIntegrity>(buf: &[u8]) -> Result<(Frame<Msg>, &[u8]), FrameDecodeError> {
+        if buf.len() < 3 + J::LEN {
             // Min bytes are Preamble, CRC, Seq and Frame Type
             // Reusing the EOF error already defined in ssmarshal.
             return Err(FrameDecodeError::SerdeError(ssmarshal::Error::EndOfStream));
@@ -340,30 +1674,33 @@ where
             return Err(FrameDecodeError::PreludeError);
         }
 
-        let crc = buf[1];
-        let envelope_bytes = &buf[2..];
+        let crc = &buf[1..1 + J::LEN];
+        let envelope_bytes = &buf[1 + J::LEN..];
         let (envelope, read_bytes) =
             ssmarshal::deserialize::<FrameContentEnvelope<Msg>>(envelope_bytes)
                 .map_err(|e| FrameDecodeError::SerdeError(e))?;
-        let expected_crc = Self::crc8(&envelope_bytes[0..read_bytes]);
+        let crc_len = if envelope.content.has_raw_data() { envelope_bytes.len() } else { read_bytes };
+        let checksum = Self::checksum::<J>(&envelope_bytes[0..crc_len]);
 
-        if crc != expected_crc {
-            dev_warn!("Frame CRC mismatch. Dropping frame");
+        if crc != &checksum[0..J::LEN] {
             return Err(FrameDecodeError::CrcError);
         }
 
-        let leftover = envelope_bytes.len() - read_bytes;
-        if leftover > 0 {
-            dev_warn!("Frame decode left {} bytes unused. Ignoring", leftover);
-        }
-
-        Ok(Frame { crc, envelope })
+        // Any other frame may be followed by more frames received in the same
+        // chunk, which are left to the caller.
+        let leftover = &envelope_bytes[read_bytes..];
+        Ok((Frame { checksum, envelope }, leftover))
     }
 
     #[inline(always)]
     fn reset_sequence_numbers(&mut self) {
-        self.tx_seq = 0;
-        self.rx_seq = 0;
+        for channel in &mut self.channels {
+            channel.tx_seq = 0;
+            channel.rx_seq = 0;
+            channel.rx_ack = None;
+            channel.reset_rx_reorder();
+        }
+        self.seq_errors = 0;
     }
 
     pub fn bus(&self) -> &B {
@@ -379,380 +1716,2971 @@ where
         self.link_status
     }
 
-    fn change_link_state(&mut self, new_state: LinkStatus) {
-        // For now I'm not validation the state transitions, but the possible status changes should be:
-        // - Down -> Sync: When received a link probe and initiated a link synchronization process.
-        // - Down -> Up: When we've received a sync message from the peer.
-        // - Sync -> Up: When we receive a sync ack from the peer.
-        // - Sync -> Down: When the sync process times out.
-        // - Up -> Down: When something wrong happens in the link and it goes down.
-
-        if self.link_status != new_state {
-            dev_info!(
-                "Link state changed {:?} => {:?}",
-                self.link_status,
-                new_state
-            );
-            self.last_link_status_change_time = self.clock.current_instant();
-            self.link_status = new_state;
+    /// Returns the status of the link, along with the reason why it went down
+    /// if it is down. Any sync attempted while down that didn't succeed is
+    /// not a reason by itself, unless it timed out.
+    pub fn link_status_detail(&self) -> (LinkStatus, Option<LinkDownReason>) {
+        (self.link_status, (self.link_status == LinkStatus::Down).then_some(self.link_down_reason))
+    }
 
-            if new_state == LinkStatus::Down {
-                // Reset the link status, clearing all the outgoing control and user messages.
-                self.last_recv_frame_time = self.clock.current_instant();
-                self.last_sent_frame_time = self.clock.current_instant();
-                self.user_msg_pending_ack_sent_time = None;
-                self.control_tx_queue.clear();
-                self.user_tx_queue.clear();
-                dev_info!("Link was reset");
-            }
-        }
+    /// Returns the link statistics collected since this instance was created, or
+    /// since the last call to [`Self::reset_stats`].
+    pub fn stats(&self) -> &LinkStats {
+        &self.stats
     }
 
-    fn push_control_frame(&mut self, frame: FrameContentEnvelope<NoMsg>) {
-        if self.control_tx_queue.is_full() {
-            panic!("No more space in the TX control queue. This MUST NOT happen!");
-        }
+    /// Resets the stats to zero, e.g for measuring the link health during a
+    /// specific period of time.
+    pub fn reset_stats(&mut self) {
+        self.stats = LinkStats::default();
+    }
 
-        self.control_tx_queue.push(frame);
+    /// Returns the max number of user messages that have been waiting at
+    /// once in a queue of `TX_QUEUE_LEN` (the queue of a channel, or the
+    /// datagram one), since the stats were reset. Useful for sizing
+    /// `TX_QUEUE_LEN` for the traffic of a target, along with
+    /// [`LinkStats::user_tx_queue_full`].
+    pub fn user_tx_queue_high_watermark(&self) -> usize {
+        self.stats.user_tx_queue_high_watermark as usize
     }
 
-    fn read_device_id(buf: [u8; 16]) -> u128 {
-        u128::from_be_bytes(buf)
+    /// Returns the max number of frames that have been waiting at once in
+    /// the control queue, since the stats were reset. See
+    /// [`LinkStats::control_tx_queue_full`].
+    pub fn control_tx_queue_high_watermark(&self) -> usize {
+        self.stats.control_tx_queue_high_watermark as usize
     }
 
-    fn write_device_id(id: u128) -> [u8; 16] {
-        u128::to_be_bytes(id)
+    /// Sets the max length of the frames sent through the bus, which is usually
+    /// limited by the size of the buffers of the bus. Transport messages that
+    /// don't fit in a single frame are split into fragments, and reassembled by
+    /// the peer. There's no limit by default. It must be at least the length of
+    /// the largest control frame, and it should be set before the link is
+    /// established, since the fragments in flight are not re-sent.
+    pub fn set_max_frame_len(&mut self, len: usize) -> Result<(), TransferError> {
+        if len < MaxFrameLength::<NoMsg>::MAX_FRAME_LENGTH || len <= Self::TRANSPORT_FRAGMENT_HEADER_LEN {
+            return Err(TransferError::InvalidMaxFrameLength);
+        }
+
+        self.max_frame_len = len;
+        Ok(())
     }
 
-    /// Mutates the current state of the link based on a received
-    /// frame. Returns a value that indicates whether it should
-    /// continue polling, depending on the function provided by the
-    /// user and the last received frame. The continuing decision will
-    /// be always true unless the next frame type is a message, in
-    /// which case, the function provided is executed, and its result
-    /// is used as result for this function.
-    fn handle_rx_frame<F: FnMut(&Msg) -> bool>(
-        &mut self,
-        frame: &Frame<Msg>,
-        recvf: &mut F,
-    ) -> bool {
-        match frame.envelope.content {
-            FrameContent::LinkProbe { device_id: device_id_bytes } => {
-                // There's nothing to do with this frame, unless the
-                // link is down. If that case, receiving this frame
-                // triggers a link sync.
-                let peer_device_id = Self::read_device_id(device_id_bytes);
-                if self.device_id == peer_device_id {
-                    dev_warn!("Ignoring link probe coming from same Device ID: 0x{:x}", peer_device_id);
-                } else if self.link_status == LinkStatus::Down {
-                    dev_debug!("Received bus probe. Starting link synchronization");
-                    self.change_link_state(LinkStatus::Sync);
-                    self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::Sync { device_id: Self::write_device_id(self.device_id) }));
-                }
-            }
+    /// Sets the number of ACKs and transport frames that can be received in a
+    /// row with an unexpected seq number before setting the link down and
+    /// syncing it again. It is [`DEFAULT_MAX_SEQ_ERRORS`] by default, and zero
+    /// disables it.
+    pub fn set_max_seq_errors(&mut self, count: u8) {
+        self.max_seq_errors = count;
+    }
 
-            FrameContent::Ack => {
-                // TODO Maybe I should put here a counter of
-                // unexpected ACKs received, and when the number is
-                // quite big (20?), give up and set the link down, to force
-                // a new resync.
-                let diff = seq_diff(frame.envelope.seq, self.tx_seq);
-                if diff < 0 {
-                    dev_warn!(
-                        "Received duplicated ACK for seq number {}",
-                        frame.envelope.seq
-                    );
-                } else {
-                    if diff != 0 {
-                        dev_warn!(
-                            "TX seq number increased unexpectedly by remoted peer by {}.",
-                            diff
-                        );
-                    }
+    /// Sets a function to be called with each user message dropped after
+    /// being re-sent [`SplitLinkTimings::MAX_RETRANSMISSIONS`] times.
+    pub fn set_on_message_dropped(&mut self, hook: MessageDroppedHook<Msg>) {
+        self.on_message_dropped = Some(hook);
+    }
 
-                    if self.user_msg_pending_ack_sent_time.is_some() {
-                        dev_trace!(
-                            "Successfully ACK'ed message with seq: {}",
-                            frame.envelope.seq
-                        );
-                        self.user_msg_pending_ack_sent_time = None;
-                        let _ = self.user_tx_queue.dequeue();
-                    } else {
-                        dev_debug!("Received an ACK message when there was no in-flight message?");
-                    }
+    /// Sets the observer that sees every frame sent and received through the
+    /// bus, or None for not tracing them, which is the default.
+    pub fn set_frame_observer(&mut self, observer: Option<&'static dyn FrameObserver>) {
+        self.frame_observer = observer;
+    }
 
-                    self.tx_seq = frame.envelope.seq.wrapping_add(1);
-                }
-            }
-            FrameContent::SyncAck => {
-                // This only should be received when our link is in
-                // sync state, and confirms that the peer has resetted
-                // the seq numbers and it has set its link to Up,
-                // becoming ready to receive traffic.
-                if self.link_status == LinkStatus::Sync {
-                    dev_debug!("Received SyncACK");
-                    self.change_link_state(LinkStatus::Up);
-                    self.reset_sequence_numbers();
-                } else {
-                    dev_debug!("Received unsolicitated SyncACK. Ignoring.");
-                }
-            }
-            FrameContent::Sync { device_id: device_id_bytes } => {
-                // A sync can happen on any of the different link states:
-                //
-                // - Down: We were anyway wainting for a sync, and the
-                // peer has started it.
-                // - Sync: We've recently sent a sync frame. The peer
-                // did this too, so we are both trying to sync.
-                // - Up: We were supposed both peers to be Up at this
-                // point, but it seems that the other maybe have
-                // de-sync the status, and it is trying to sync again.
-
-                // Regardless of when we do receive this kind of
-                // frame, the outcome must be a transition to the Up
-                // state, a reset in the sequence numbers, and a
-                // transmission of a SyncAck frame.
-
-                // TODO Should clean the control tx queue when
-                // receiving this? I mean, seq numbers are resetted,
-                // any Ack or that was pending before to be sent
-                // is anyway useless.
-                let peer_device_id = Self::read_device_id(device_id_bytes);
-                if peer_device_id == self.device_id {
-                    dev_error!("Peer sent our same device ID while trying to sync the channel. Crosstalk between the bus lines? Link establishment aborted");
-                    self.change_link_state(LinkStatus::Down);
-                } else {
-                    dev_info!("Established connection with peer: 0x{:x}", peer_device_id);
-                    self.change_link_state(LinkStatus::Up);
-                    self.reset_sequence_numbers();
-                    self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::SyncAck));
-                }
+    /// Sets the watchdog fed on each poll, or None for not feeding any, which
+    /// is the default. It is only fed while the bus keeps taking frames, so
+    /// that a hung DMA or USART resets the MCU instead of leaving this side
+    /// unreachable. The firmware must poll the link more often than the
+    /// watchdog expires, and feed it itself while it doesn't, e.g while
+    /// sleeping.
+    pub fn set_watchdog(&mut self, watchdog: Option<&'static dyn Watchdog>) {
+        self.watchdog = watchdog;
+    }
 
-            }
-            FrameContent::TransportMessage(ref msg) => {
-                if self.link_status == LinkStatus::Up {
-                    let diff = seq_diff(frame.envelope.seq, self.rx_seq);
-
-                    // For every message received, we need to answer with an ACK:
-                    // - If the received seq number is the expected
-                    // one, or greater, then we need to send the ACK
-                    // to notify that we've received the message.
-
-                    // - If we've received a seq number lower than
-                    // expected, is because the peer has re-send a
-                    // message that it considered dropped. This could
-                    // be because the previous ACK frame that we've
-                    // sent hasn't been received properly by the peer,
-                    // so it is important to send it again.
-                    self.push_control_frame(FrameContentEnvelope {
-                        seq: frame.envelope.seq,
-                        content: FrameContent::Ack,
-                    });
-
-                    if diff < 0 {
-                        dev_debug!(
-                            "Dropping possibly duplicated frame. Expecting seq {} but {} found",
-                            self.rx_seq,
-                            frame.envelope.seq
-                        );
-                    } else {
-                        if diff != 0 {
-                            dev_debug!(
-                                "RX seq number increased unexpectedly by remoted peer by {}.",
-                                diff
-                            );
-                        }
+    /// Sets what is done with the user messages queued on the given channel
+    /// while its queue is full. It is [`TxOverflowPolicy::Reject`] by
+    /// default.
+    pub fn set_tx_overflow_policy(&mut self, channel: Channel, policy: TxOverflowPolicy<Msg>) {
+        self.channels[channel as usize].overflow_policy = policy;
+    }
 
-                        self.rx_seq = frame.envelope.seq.wrapping_add(1);
+    /// Sets whether the link is synced again after dropping a user message,
+    /// which is the default. The peer only accepts the messages in order, so
+    /// otherwise it will drop every message that follows the dropped one,
+    /// until the link is set down because of the seq errors (see
+    /// [`Self::set_max_seq_errors`]).
+    pub fn set_resync_on_drop(&mut self, resync: bool) {
+        self.resync_on_drop = resync;
+    }
 
-                        return recvf(msg);
-                    }
-                } else {
-                    dev_debug!(
-                        "Received transport frame when link status was not Up. Silently discarding frame"
-                    );
-                }
+    /// Makes the link log the whole envelope of every ACK and transport frame
+    /// received with a seq number off by at least `threshold` from the
+    /// expected one, for diagnosing where unexpected seq numbers come from.
+    /// It is zero, which disables it, by default.
+    pub fn set_seq_jump_log_threshold(&mut self, threshold: u8) {
+        self.seq_jump_log_threshold = threshold;
+    }
+
+    /// Makes the link hold the transport messages received up to `depth` seq
+    /// numbers ahead of the expected one, instead of dropping them, and
+    /// deliver them in order as soon as the missing ones arrive. This way,
+    /// when a frame is lost, the peer only needs to re-send that one before
+    /// the rest of its send window is ACK'ed. The depth is limited to one
+    /// less than the send window, which is how far ahead the frames of a
+    /// peer with the same window can be. It is zero, which disables it, by
+    /// default. Fragments and batches are never held.
+    pub fn set_rx_reorder_depth(&mut self, depth: u8) {
+        self.rx_reorder_depth = depth.min((TX_WINDOW - 1) as u8);
+        if self.rx_reorder_depth == 0 {
+            for channel in &mut self.channels {
+                channel.reset_rx_reorder();
             }
         }
+    }
 
-        true
+    /// Sets the number of free slots of a channel at or below which it is
+    /// considered congested. It is a quarter of the queue by default.
+    pub fn set_tx_low_watermark(&mut self, free: usize) {
+        self.tx_low_watermark = free;
     }
 
-    fn do_rx<F: FnMut(&Msg) -> bool>(&mut self, mut recvf: F) {
-        let mut rxbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
-        while {
-            let should_continue = match self.bus.poll_next(&mut rxbuf) {
-                Ok(frame_len) => {
-                    dev_trace!("<-- RX: {:x?}", &rxbuf[0..frame_len as usize]);
-                    match Self::decode_frame(&rxbuf[0..frame_len as usize]) {
-                        Ok(frame) => {
-                            self.last_recv_frame_time = self.clock.current_instant();
-                            self.handle_rx_frame(&frame, &mut recvf)
-                        }
-                        Err(FrameDecodeError::PreludeError) => {
-                            dev_debug!("Invalid prelude in frame. Dropping frame");
-                            true
-                        }
-                        Err(FrameDecodeError::CrcError) => {
-                            dev_debug!("Invalid frame CRC. Dropping frame");
-                            true
-                        }
-                        Err(e @ FrameDecodeError::SerdeError(_)) => {
-                            dev_debug!("Failed to parse frame: {:?}", e);
-                            true
-                        }
-                    }
-                }
-                Err(BusPollError::BufferOverflow) => true,
-                Err(BusPollError::WouldBlock) => false,
-            };
+    /// Enables or disables packing several queued messages of the same
+    /// channel in a single frame, up to the max frame length (see
+    /// [`Self::set_max_frame_len`]). Only messages within the send window
+    /// are packed, and never the ones that need fragmentation. Both peers
+    /// must run the same protocol version, but only the sending one needs it
+    /// enabled. Disabled by default.
+    pub fn set_coalescing(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
 
-            should_continue
-        } {}
+    /// Enables or disables the time requests sent to the peer, every
+    /// `interval`, for estimating its clock (see [`Self::peer_time_offset`]).
+    /// Only the peer that needs the estimate has to enable them. Disabled by
+    /// default.
+    pub fn set_time_sync_interval(&mut self, interval: Option<Duration>) {
+        self.time_sync_interval = interval;
     }
 
-    fn encode_frame<M: Serialize>(buf: &mut [u8], frame: &FrameContentEnvelope<M>) -> usize {
-        buf[0] = FRAME_PRELUDE_BYTE;
-        let encoded_len = ssmarshal::serialize(&mut buf[2..], frame).unwrap();
-        buf[1] = Self::crc8(&buf[2..2 + encoded_len]);
-        encoded_len + 2
+    /// Returns the estimated time of the peer minus the local one, in
+    /// microseconds, as it would be measured right now, or `None` if there's
+    /// no estimate since the link came up. The local time is the one of
+    /// [`Self::uptime_us`].
+    pub fn peer_time_offset(&self) -> Option<i64> {
+        let est = self.peer_clock?;
+        Some(est.offset_at(self.uptime_us()))
     }
 
-    fn transfer_frame<M: Serialize + Debug>(
-        bus: &mut B,
-        clock: &CS,
-        last_sent_frame_time: &mut CS::TInstant,
-        frame: &FrameContentEnvelope<M>,
-    ) -> Result<(), BusTransferError>
-    where
-        [(); MaxFrameLength::<M>::MAX_FRAME_LENGTH]:,
-    {
-        let mut txbuf = [0u8; { MaxFrameLength::<M>::MAX_FRAME_LENGTH }];
-        let len = Self::encode_frame(&mut txbuf, frame);
-        let res = bus.transfer(&mut txbuf[0..len]);
-        if matches!(res, Ok(_)) {
-            dev_trace!("--> TX: {:x?}; {:?}", &txbuf[0..len], &frame);
-            // TODO Should I add the estimated time that a frame will
-            // take to be transferred through the bus to this, so that
-            // I can store the time when I think message should have
-            // been received by the peer? Note: this will make the
-            // current ticks to go beyond the current time, so any
-            // calculation like cycle_count - last_sent_frame_ticks
-            // will give invalid results.
-            *last_sent_frame_time = clock.current_instant();
-        }
+    /// Converts a time of the peer, as returned by its [`Self::uptime_us`],
+    /// into the local one, accounting for the skew of its clock, or returns
+    /// `None` if there's no estimate since the link came up. E.g for placing
+    /// the key events of the peer in the local timeline, or for measuring
+    /// how long a message took to arrive from its timestamp. Times from
+    /// before the local bus was created are returned as zero.
+    pub fn peer_time_to_local(&self, peer_us: u64) -> Option<u64> {
+        let est = self.peer_clock?;
+        // The offset only changes by a few ppm of the elapsed time, so taking
+        // it at the first guess of the local time is accurate enough.
+        let guess = (peer_us as i64 - est.offset_us).max(0) as u64;
+        Some((peer_us as i64 - est.offset_at(guess)).max(0) as u64)
+    }
 
-        res
+    /// Converts a local time, as returned by [`Self::uptime_us`], into the
+    /// one of the peer, or returns `None` if there's no estimate since the
+    /// link came up. E.g for scheduling something to happen at the same time
+    /// in both sides, like the phase of a lighting animation. Times from
+    /// before the peer bus was created are returned as zero.
+    pub fn local_time_to_peer(&self, local_us: u64) -> Option<u64> {
+        let est = self.peer_clock?;
+        Some((local_us as i64 + est.offset_at(local_us)).max(0) as u64)
     }
 
-    fn transfer_next_user_msg(&mut self) {
-        if let Some(next_frame) = self.user_tx_queue.peek() {
-            if let Ok(_) = Self::transfer_frame(
-                &mut self.bus,
-                &self.clock,
-                &mut self.last_sent_frame_time,
-                &FrameContentEnvelope {
-                    seq: self.tx_seq,
-                    content: FrameContent::TransportMessage(next_frame.clone()),
-                },
-            ) {
-                self.user_msg_pending_ack_sent_time = Some(self.clock.current_instant());
-            }
+    /// Returns how much faster the clock of the peer runs than the local one,
+    /// in parts per million, once there are enough time samples for measuring
+    /// it.
+    pub fn peer_clock_skew_ppm(&self) -> Option<i32> {
+        self.peer_clock.and_then(|est| est.skew_ppm)
+    }
+
+    /// Returns the time since the bus was created, in microseconds. Unlike
+    /// the instants of some clocks, it never wraps around, as long as the bus
+    /// is polled more often than the clock does.
+    pub fn uptime_us(&self) -> u64 {
+        self.uptime_ns_at(self.clock.current_instant()) / 1000
+    }
+
+    fn uptime_ns_at(&self, instant: CS::TInstant) -> u64 {
+        match self.clock.diff(instant, self.uptime_ref) {
+            TimeDiff::Forward(elapsed) => self.uptime_ns + elapsed.as_nanos() as u64,
+            TimeDiff::Backward(_) => self.uptime_ns,
         }
     }
 
-    fn do_tx(&mut self) {
-        if !self.bus.is_tx_busy() {
-            if let Some(control_frame) = self.control_tx_queue.peek() {
-                if let Ok(_) = Self::transfer_frame::<NoMsg>(
-                    &mut self.bus,
-                    &self.clock,
-                    &mut self.last_sent_frame_time,
-                    control_frame,
-                ) {
-                    self.control_tx_queue.dequeue();
-                }
-            }
+    /// Moves the reference of the uptime forward, so that the elapsed time
+    /// since then never gets close to the wrap around of the clock.
+    fn update_uptime(&mut self) {
+        let now = self.clock.current_instant();
+        if matches!(self.clock.diff(now, self.uptime_ref), TimeDiff::Forward(elapsed) if elapsed >= Duration::from_secs(1)) {
+            self.uptime_ns = self.uptime_ns_at(now);
+            self.uptime_ref = now;
         }
+    }
 
-        // A new user message can only be transferred from here when:
-        //  - The link is up.
-        //  - The bus is not busy
-        //  - No other priority control message is scheduled for transfer.
-        //  - There's no message pending to be ACK'ed. Replying that message is part of the job of do_timed_actions.
-        if self.link_status == LinkStatus::Up
-            && !self.bus.is_tx_busy()
-            && self.control_tx_queue.is_empty()
-            && self.user_msg_pending_ack_sent_time.is_none()
-        {
-            self.transfer_next_user_msg();
+    /// Adds a sample of the clock of the peer, from an answer to one of our
+    /// time requests.
+    fn on_time_response(&mut self, t0: u64, t1: u64) {
+        let t3 = self.uptime_us();
+        if t3 < t0 {
+            return;
+        }
+
+        // The peer is assumed to answer halfway through the round trip.
+        let offset_us = t1 as i64 - (t0 + (t3 - t0) / 2) as i64;
+        dev_trace!("Peer clock sample: offset {} us, round trip {} us", offset_us, t3 - t0);
+        let Some(est) = &mut self.peer_clock else {
+            self.peer_clock = Some(PeerClockEstimate {
+                sample_time_us: t3,
+                offset_us,
+                skew_ref_time_us: t3,
+                skew_ref_offset_us: offset_us,
+                skew_ppm: None,
+            });
+            return;
+        };
+
+        let baseline = t3 - est.skew_ref_time_us;
+        if baseline >= MIN_SKEW_BASELINE_US {
+            let sample_ppm = ((offset_us - est.skew_ref_offset_us) * 1_000_000 / baseline as i64) as i32;
+            est.skew_ppm = Some(match est.skew_ppm {
+                Some(ppm) => (ppm * 3 + sample_ppm) / 4,
+                None => sample_ppm,
+            });
+            est.skew_ref_time_us = t3;
+            est.skew_ref_offset_us = offset_us;
         }
+        est.sample_time_us = t3;
+        est.offset_us = offset_us;
     }
 
-    fn do_timed_actions(&mut self) {
-        if self.clock.elapsed_since(self.last_sent_frame_time) >= Ts::LINK_IDLE_PROBE_INTERVAL_TIME
-        {
-            // TODO We need to do something about probes:
-            // - If we stop sending probes when we receive normal frames, we need to trigger link sync everytime we receive a valid frame.
-            // - Either that, or we keep sending link probes indefinitely. I prefer the first option just to save some bandwidth
-            self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::LinkProbe { device_id: Self::write_device_id(self.device_id) }));
+    /// Asks the peer to reboot into its bootloader, e.g for updating the
+    /// firmware of both sides from the master one. The request is sent again
+    /// until the peer ACKs it, or until the link goes down. Then, the peer
+    /// closes the link with [`ByeReason::Bootloader`], which can be checked
+    /// with [`Self::peer_bye_reason`].
+    pub fn request_peer_bootloader(&mut self) -> Result<(), TransferError> {
+        if self.link_status != LinkStatus::Up {
+            return Err(TransferError::LinkDown);
+        }
+        if !self.control_tx_queue_has_room() {
+            return Err(TransferError::BufferOverflow);
         }
 
-        if self.link_status == LinkStatus::Sync
-            && self.clock.elapsed_since(self.last_link_status_change_time)
-                >= Ts::MAX_SYNC_ACK_WAIT_TIME
-        {
-            dev_warn!("Couldn't receive a SyncACK frame in time. Giving up link synchronization");
-            self.change_link_state(LinkStatus::Down);
+        dev_info!("Requesting the peer to enter the bootloader");
+        self.peer_bootloader_request_time = Some(self.clock.current_instant());
+        self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::BootloaderRequest));
+        Ok(())
+    }
+
+    /// Tells the bus whether the USB bus of this side is suspended, which the
+    /// peer is told about (see [`LinkEvent::PeerSuspended`]). It is sent again
+    /// until the peer ACKs it, and every time the link is set up. While the
+    /// host of either side is suspended, the link is probed less often, using
+    /// [`SplitLinkTimings::SUSPENDED_PROBE_INTERVAL_TIME`] and
+    /// [`SplitLinkTimings::SUSPENDED_MAX_LINK_IDLE_TIME`].
+    pub fn set_local_suspended(&mut self, suspended: bool) {
+        if self.local_suspended == suspended {
+            return;
         }
 
+        dev_info!("Local host suspended: {}", suspended);
+        self.local_suspended = suspended;
         if self.link_status == LinkStatus::Up {
-            if self.clock.elapsed_since(self.last_recv_frame_time) >= Ts::MAX_LINK_IDLE_TIME {
-                dev_warn!("Link has been idle for so long. Considering it down");
-                self.change_link_state(LinkStatus::Down);
-            } else if let Some(last_replay_time) = self.user_msg_pending_ack_sent_time {
-                if !self.bus.is_tx_busy()
-                    && self.clock.elapsed_since(last_replay_time) > Ts::MSG_REPLAY_DELAY_TIME
-                {
-                    dev_debug!("Re-sent user message for which no ACK has been received");
-                    self.transfer_next_user_msg();
-                }
-            }
+            self.power_state_pending = true;
+            self.power_state_sent_time = None;
         }
     }
 
-    pub fn user_tx_queue_len(&self) -> usize {
-        self.user_tx_queue.len()
+    /// Returns whether the USB bus of this side is suspended, as set with
+    /// [`Self::set_local_suspended`].
+    pub fn is_local_suspended(&self) -> bool {
+        self.local_suspended
     }
-}
 
-impl<
-    Msg: Clone + Debug + DeserializeOwned + Serialize,
-    Ts: SplitLinkTimings,
-    B: BusWrite + BusRead,
-    CS: Clock,
-    const TX_QUEUE_LEN: usize,
-> SplitBusLike<Msg> for SplitBus<Msg, Ts, B, CS, TX_QUEUE_LEN>
-where
-    [(); MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]:,
-    [(); MaxFrameLength::<NoMsg>::MAX_FRAME_LENGTH]:,
-{
-    fn poll<F: FnMut(&Msg) -> bool>(&mut self, recvf: F) {
-        self.do_rx(recvf);
-        self.do_timed_actions();
-        self.do_tx();
+    /// Returns whether the peer has told that its USB bus is suspended. It is
+    /// false while the link is not up.
+    pub fn is_peer_suspended(&self) -> bool {
+        self.peer_suspended
     }
 
-    fn transfer(&mut self, message: Msg) -> Result<(), TransferError> {
+    /// Asks the peer to wake up its suspended host, which is reported to it
+    /// with [`LinkEvent::WakeRequested`]. The request is not sent again if
+    /// it is lost, so it is meant to be sent on every key press for as long
+    /// as the peer stays suspended.
+    pub fn request_peer_wakeup(&mut self) -> Result<(), TransferError> {
         if self.link_status != LinkStatus::Up {
             return Err(TransferError::LinkDown);
         }
+        if !self.control_tx_queue_has_room() {
+            return Err(TransferError::BufferOverflow);
+        }
+
+        dev_info!("Requesting the peer to wake up its host");
+        self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::WakeRequest));
+        Ok(())
+    }
+
+    /// Returns whether a bootloader request has been sent to the peer, and it
+    /// hasn't been ACK'ed yet.
+    pub fn is_peer_bootloader_pending(&self) -> bool {
+        self.peer_bootloader_request_time.is_some()
+    }
 
-        if self.user_tx_queue.is_full() {
+    /// Sends a ping to the peer, for measuring the round trip time of the
+    /// link, e.g for checking that the wiring and the baud rate meet some
+    /// latency target. The time is measured with the clock of the bus once
+    /// the answer arrives, and it can be read with [`Self::measured_rtt`].
+    /// Unlike the estimate of [`LinkStats::rtt_us`], it doesn't depend on the
+    /// user messages being sent. A ping that is still waiting for its answer
+    /// is forgotten.
+    pub fn measure_rtt(&mut self) -> Result<(), TransferError> {
+        if self.link_status != LinkStatus::Up {
+            return Err(TransferError::LinkDown);
+        }
+        if !self.control_tx_queue_has_room() {
             return Err(TransferError::BufferOverflow);
-        } else {
-            self.user_tx_queue.push(message);
-            return Ok(());
+        }
+
+        self.ping_id = self.ping_id.wrapping_add(1);
+        self.ping_sent_time = Some(self.clock.current_instant());
+        self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::Ping { id: self.ping_id }));
+        Ok(())
+    }
+
+    /// Returns the round trip time measured by the last ping answered by the
+    /// peer, if any.
+    pub fn measured_rtt(&self) -> Option<Duration> {
+        self.measured_rtt
+    }
+
+    /// Returns whether a ping has been sent to the peer, and it hasn't been
+    /// answered yet.
+    pub fn is_rtt_measurement_pending(&self) -> bool {
+        self.ping_sent_time.is_some()
+    }
+
+    fn on_pong(&mut self, id: u8) {
+        if id != self.ping_id {
+            dev_debug!("Ignoring pong of a stale ping: {}", id);
+            return;
+        }
+        let Some(sent_time) = self.ping_sent_time.take() else {
+            return;
+        };
+
+        let rtt = self.clock.elapsed_since(sent_time);
+        dev_debug!("Measured round trip time: {} us", rtt.as_micros());
+        self.measured_rtt = Some(rtt);
+    }
+
+    /// Enables the automatic fallback of the baud rate of the bus, when
+    /// `rates` is not empty, or disables it otherwise. When more than
+    /// [`BAUD_FALLBACK_CRC_ERRORS`] frames are received with a wrong CRC
+    /// within [`BAUD_FALLBACK_WINDOW`], both peers agree on switching to the
+    /// next rate, and after [`SplitLinkTimings::BAUD_RESTORE_STABLE_TIME`]
+    /// without any CRC error they try the previous one again.
+    ///
+    /// The rates go from the fastest to the slowest one, and both peers must
+    /// be set with the same ones. The bus is switched to the first one right
+    /// away, and it goes back to it whenever the link goes down, so that the
+    /// peers can find each other again. Fails if the baud rate of the bus
+    /// can't be changed.
+    pub fn set_baud_fallback(&mut self, rates: &'static [u32]) -> Result<(), BusBaudRateError> {
+        let Some(&fastest) = rates.first() else {
+            self.baud_fallback = None;
+            return Ok(());
+        };
+
+        if self.bus.baud_rate() != Some(fastest) {
+            self.bus.set_baud_rate(fastest)?;
+        }
+
+        let now = self.uptime_ns_at(self.clock.current_instant());
+        self.baud_fallback = Some(BaudFallback {
+            rates,
+            index: 0,
+            crc_errors: 0,
+            window_start: now,
+            stable_since: now,
+            proposal: None,
+            switch_to: None,
+        });
+        Ok(())
+    }
+
+    /// Returns the current baud rate of the bus, if it has one that can be
+    /// changed.
+    pub fn current_baud(&self) -> Option<u32> {
+        self.bus.baud_rate()
+    }
+
+    /// Counts a frame received with a wrong CRC, falling back to a slower baud
+    /// rate if there are too many of them.
+    fn on_crc_error(&mut self) {
+        self.stats.crc_errors = self.stats.crc_errors.saturating_add(1);
+        if self.link_status != LinkStatus::Up {
+            return;
+        }
+
+        let now = self.uptime_ns_at(self.clock.current_instant());
+        let Some(fallback) = &mut self.baud_fallback else {
+            return;
+        };
+
+        fallback.stable_since = now;
+        if now.saturating_sub(fallback.window_start) >= BAUD_FALLBACK_WINDOW.as_nanos() as u64 {
+            fallback.window_start = now;
+            fallback.crc_errors = 0;
+        }
+        fallback.crc_errors = fallback.crc_errors.saturating_add(1);
+
+        if fallback.crc_errors > BAUD_FALLBACK_CRC_ERRORS
+            && fallback.proposal.is_none()
+            && fallback.switch_to.is_none()
+            && fallback.index + 1 < fallback.rates.len()
+        {
+            dev_warn!("Too many CRC errors at {} bauds. Falling back to a slower rate", fallback.rates[fallback.index]);
+            let index = fallback.index + 1;
+            self.propose_baud_rate(index, 1);
+        }
+    }
+
+    /// Asks the peer to switch to the baud rate with the given index.
+    fn propose_baud_rate(&mut self, index: usize, attempt: u8) {
+        if !self.control_tx_queue_has_room() {
+            return;
+        }
+
+        let Some(fallback) = &mut self.baud_fallback else {
+            return;
+        };
+
+        let baud = fallback.rates[index];
+        fallback.proposal = Some((index, self.clock.current_instant(), attempt));
+        self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::BaudChange { baud: baud.to_le_bytes() }));
+    }
+
+    /// Returns whether a baud rate change has to be proposed to the peer,
+    /// either because the last proposal hasn't been answered in time, or
+    /// because the link has been stable for long enough at a slower rate.
+    fn is_baud_proposal_due(&self) -> bool {
+        let Some(fallback) = &self.baud_fallback else {
+            return false;
+        };
+
+        if self.control_tx_queue.is_full() || fallback.switch_to.is_some() {
+            return false;
+        }
+
+        match fallback.proposal {
+            Some((_, sent, _)) => self.clock.elapsed_since(sent) > Ts::MSG_REPLAY_DELAY_TIME,
+            None => {
+                fallback.index > 0
+                    && self.uptime_ns_at(self.clock.current_instant()).saturating_sub(fallback.stable_since)
+                        >= Ts::BAUD_RESTORE_STABLE_TIME.as_nanos() as u64
+            }
+        }
+    }
+
+    fn on_baud_proposal_due(&mut self) {
+        let now = self.uptime_ns_at(self.clock.current_instant());
+        let Some(fallback) = &mut self.baud_fallback else {
+            return;
+        };
+
+        match fallback.proposal {
+            Some((_, _, attempts)) if attempts >= MAX_BAUD_CHANGE_ATTEMPTS => {
+                dev_warn!("Peer didn't answer the baud rate change. Giving up");
+                fallback.proposal = None;
+                fallback.stable_since = now;
+            }
+            Some((index, _, attempts)) => self.propose_baud_rate(index, attempts + 1),
+            None => {
+                dev_info!("Link stable at {} bauds. Trying a faster rate", fallback.rates[fallback.index]);
+                let index = fallback.index - 1;
+                self.propose_baud_rate(index, 1);
+            }
+        }
+    }
+
+    /// Handles a baud rate change proposed by the peer, which is accepted if
+    /// it is one of our rates.
+    fn on_baud_change(&mut self, baud: [u8; 4]) {
+        let rate = u32::from_le_bytes(baud);
+        let index = self
+            .baud_fallback
+            .as_ref()
+            .and_then(|fallback| fallback.rates.iter().position(|r| *r == rate));
+        let Some(index) = index else {
+            dev_warn!("Peer proposed an unsupported baud rate {}. Ignoring", rate);
+            return;
+        };
+
+        // The peer proposes it again if the ACK is lost, which will be
+        // received at the new rate anyway.
+        if self.control_tx_queue_has_room() {
+            if let Some(fallback) = &mut self.baud_fallback {
+                fallback.proposal = None;
+                fallback.switch_to = Some(index);
+            }
+            self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::BaudChangeAck { baud }));
+        }
+    }
+
+    fn on_baud_change_ack(&mut self, baud: [u8; 4]) {
+        let Some(fallback) = &mut self.baud_fallback else {
+            return;
+        };
+
+        if let Some((index, _, _)) = fallback.proposal {
+            if fallback.rates[index] == u32::from_le_bytes(baud) {
+                fallback.proposal = None;
+                fallback.switch_to = Some(index);
+            }
+        }
+    }
+
+    /// Switches the bus to the baud rate agreed with the peer, once every
+    /// control frame queued until then has been sent. Returns whether the
+    /// switch is still pending, in which case no user message must be sent.
+    fn switch_baud_rate(&mut self) -> bool {
+        let pending_control = self.has_pending_control();
+        let now = self.uptime_ns_at(self.clock.current_instant());
+        let Some(fallback) = &mut self.baud_fallback else {
+            return false;
+        };
+        let Some(index) = fallback.switch_to else {
+            return false;
+        };
+
+        if pending_control {
+            return true;
+        }
+
+        let rate = fallback.rates[index];
+        match self.bus.set_baud_rate(rate) {
+            Ok(()) => {
+                dev_info!("Link baud rate changed to {}", rate);
+                fallback.index = index;
+                fallback.crc_errors = 0;
+                fallback.window_start = now;
+                fallback.stable_since = now;
+                fallback.switch_to = None;
+                false
+            }
+            Err(BusBaudRateError::Busy) => true,
+            Err(BusBaudRateError::Unsupported | BusBaudRateError::Unreachable) => {
+                dev_error!("Couldn't change the link baud rate to {}, the bus doesn't support it", rate);
+                fallback.switch_to = None;
+                false
+            }
+        }
+    }
+
+    /// Returns the number of bytes of a message that can be sent in each
+    /// fragment.
+    fn fragment_data_len(max_frame_len: usize) -> usize {
+        max_frame_len.min(MaxFrameLength::<Msg>::MAX_FRAME_LENGTH) - Self::TRANSPORT_FRAGMENT_HEADER_LEN
+    }
+
+    /// Returns the number of fragments needed for sending a message whose
+    /// serialized length is `msg_len` through frames of up to `max_frame_len`
+    /// bytes, or zero if it fits in a single frame.
+    fn fragment_count(max_frame_len: usize, msg_len: usize) -> usize {
+        // The frame buffers are sized after the size of the message in memory,
+        // which its serialized form may exceed, even without a limit set.
+        if msg_len + Self::TRANSPORT_MESSAGE_HEADER_LEN <= max_frame_len.min(MaxFrameLength::<Msg>::MAX_FRAME_LENGTH) {
+            0
+        } else {
+            msg_len.div_ceil(Self::fragment_data_len(max_frame_len))
+        }
+    }
+
+    fn serialize_msg(msg: &Msg, buf: &mut [u8; MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]) -> Result<usize, ssmarshal::Error> {
+        ssmarshal::serialize(buf, msg)
+    }
+
+    /// Serializes a transport message, counting it in the stats.
+    fn serialize_transport_msg(
+        stats: &mut LinkStats,
+        msg: &Msg,
+        buf: &mut [u8; MaxFrameLength::<Msg>::MAX_FRAME_LENGTH],
+    ) -> Result<usize, ssmarshal::Error> {
+        stats.msg_serializations = stats.msg_serializations.saturating_add(1);
+        Self::serialize_msg(msg, buf)
+    }
+
+    /// Returns whether the frames sent through the bus are being received
+    /// back, e.g because the TX and RX lines are shorted. Meant for production
+    /// tests with a loopback plug, and for telling the user that the cable is
+    /// miswired. It stays set until a frame from an actual peer is received.
+    pub fn is_loopback_detected(&self) -> bool {
+        self.loopback_detected
+    }
+
+    /// Returns why the peer closed the link, if it did it with a Bye frame and
+    /// the link hasn't been up again since then.
+    pub fn peer_bye_reason(&self) -> Option<ByeReason> {
+        self.peer_bye_reason
+    }
+
+    /// Returns why the peer was reset, the last time it reported it during
+    /// a link sync. See [`LinkEvent::PeerRebooted`].
+    pub fn peer_reset_reason(&self) -> Option<ResetReason> {
+        self.peer_reset_reason
+    }
+
+    /// Sets why this side was reset, usually read from the reset flags of the
+    /// MCU on boot, for reporting it to the peer on the next link sync. It is
+    /// only reported once the link is up, so the peer can tell when this
+    /// side is crashing instead of just seeing the link go silent.
+    pub fn set_reset_reason(&mut self, reason: ResetReason) {
+        self.reset_reason = Some(reason);
+    }
+
+    /// Sets how close this side is to being enumerated by the host, e.g from
+    /// the VBUS sense pin and the state of the USB device. It is announced to
+    /// the peer during the link sync for electing the master side (see
+    /// [`Self::negotiated_role`]), and the link is synced again when it
+    /// changes while the link is up, e.g because the USB cable has been
+    /// moved to the other half.
+    pub fn set_host_presence(&mut self, presence: HostPresence) {
+        self.host_presence = presence;
+    }
+
+    /// Returns the role of this side elected during the last link sync, as
+    /// long as the link is up. See [`Self::set_host_presence`].
+    pub fn negotiated_role(&self) -> Option<PeerRole> {
+        if self.link_status == LinkStatus::Up {
+            self.negotiated_role
+        } else {
+            None
+        }
+    }
+
+    /// Sets a function to be called each time the role of this side elected
+    /// during a link sync is different from the previous one, e.g because
+    /// the USB cable has been moved to the other half.
+    pub fn set_on_role_changed(&mut self, hook: RoleChangedHook) {
+        self.on_role_changed = Some(hook);
+    }
+
+    /// Returns the protocol version announced by the peer during the last link
+    /// sync, if any, even if it wasn't compatible.
+    pub fn peer_protocol_version(&self) -> Option<u8> {
+        self.peer_protocol_version
+    }
+
+    /// Sets the protocol version announced to the peer during the link sync,
+    /// which is [`LINK_PROTOCOL_VERSION`] by default. The link is only set up
+    /// if both peers announce the same version. Users can set their own
+    /// version for also detecting incompatible changes of the messages they
+    /// exchange, which would be mis-deserialized otherwise. Takes effect on the
+    /// next sync.
+    pub fn set_protocol_version(&mut self, version: u8) {
+        self.protocol_version = version;
+    }
+
+    /// Records the protocol version announced by the peer, moving the link to
+    /// Incompatible if it or the checksum of the peer don't match ours.
+    fn accept_peer_sync(&mut self, version: u8, integrity: u8) -> bool {
+        self.peer_protocol_version = Some(version);
+        if version != self.protocol_version {
+            dev_error!(
+                "Peer uses the protocol version {}, but ours is {}. Link establishment aborted",
+                version,
+                self.protocol_version
+            );
+            self.change_link_state(LinkStatus::Incompatible);
+            return false;
+        }
+
+        if integrity != I::ID {
+            dev_error!(
+                "Peer uses the frame checksum {}, but ours is {}. Link establishment aborted",
+                integrity,
+                I::ID
+            );
+            self.change_link_state(LinkStatus::Incompatible);
+            return false;
+        }
+        true
+    }
+
+    /// Sets the status of the link, running the actions of entering the new
+    /// status, and emitting its event. The possible transitions are:
+    /// - Down -> Sync: When received a link probe and initiated a link synchronization process.
+    /// - Down -> Up: When we've received a sync message from the peer.
+    /// - Sync -> Up: When we receive a sync ack from the peer.
+    /// - Sync -> Down: When the sync process times out.
+    /// - Up -> Down: When something wrong happens in the link and it goes down.
+    /// - Sync/Up -> Down: When the link is closed on purpose (see close_link).
+    /// - Down/Sync/Up -> Incompatible: When the peer announces a different protocol version.
+    /// - Incompatible -> Sync/Up: When retrying the sync, in case the peer was updated.
+    ///
+    /// Any other transition is a bug, and it is ignored.
+    fn change_link_state(&mut self, new_state: LinkStatus) {
+        if self.link_status == new_state {
+            return;
+        }
+
+        if !self.link_status.can_transition_to(new_state) {
+            dev_error!("Invalid link state transition {:?} => {:?}. Ignoring", self.link_status, new_state);
+            debug_assert!(false, "Invalid link state transition");
+            return;
+        }
+
+        if new_state == LinkStatus::Down {
+            dev_warn!(
+                "Link state changed {:?} => {:?} ({:?})",
+                self.link_status,
+                new_state,
+                self.link_down_reason
+            );
+        } else {
+            dev_info!(
+                "Link state changed {:?} => {:?}",
+                self.link_status,
+                new_state
+            );
+        }
+        let was_up = self.link_status == LinkStatus::Up;
+        if was_up && new_state == LinkStatus::Down {
+            self.stats.link_down_count = self.stats.link_down_count.saturating_add(1);
+        }
+
+        self.last_link_status_change_time = self.clock.current_instant();
+        self.link_status = new_state;
+
+        match new_state {
+            LinkStatus::Down => {
+                self.clear_link();
+                // A failed sync is not reported, the link was never up.
+                if was_up {
+                    self.emit_event(LinkEvent::Down);
+                }
+            }
+            LinkStatus::Incompatible => {
+                self.clear_link();
+                self.emit_event(LinkEvent::Incompatible);
+            }
+            LinkStatus::Up => {
+                self.peer_bye_reason = None;
+                // The peer has learnt about our last reset.
+                self.reset_reason = None;
+                self.loopback_detected = false;
+                // The peer starts over assuming that our host is awake.
+                self.power_state_pending = self.local_suspended;
+                self.emit_event(LinkEvent::Up);
+            }
+            LinkStatus::Sync => {}
+        }
+    }
+
+    /// Moves the link to Down because of a failure, for the given reason.
+    fn set_link_down(&mut self, reason: LinkDownReason) {
+        if self.link_status != LinkStatus::Down {
+            self.link_down_reason = reason;
+        }
+        self.change_link_state(LinkStatus::Down);
+    }
+
+    fn emit_event(&mut self, event: LinkEvent) {
+        // Pushing onto a full queue drops the oldest event.
+        self.events.push(event);
+    }
+
+    /// Resets the link status, clearing all the outgoing control and user
+    /// messages.
+    fn clear_link(&mut self) {
+        self.last_recv_frame_time = self.clock.current_instant();
+        self.last_sent_frame_time = self.clock.current_instant();
+        self.seq_errors = 0;
+        self.control_tx_queue.clear();
+        self.pending_link_frame = None;
+        for channel in &mut self.channels {
+            channel.clear();
+        }
+        self.datagram_tx_queue.clear();
+        // The peer may have been reset, along with its clock.
+        self.peer_clock = None;
+        self.peer_bootloader_request_time = None;
+        self.bootloader_requested = false;
+        self.ping_sent_time = None;
+        self.power_state_pending = false;
+        self.power_state_sent_time = None;
+        if self.peer_suspended {
+            self.peer_suspended = false;
+            self.emit_event(LinkEvent::PeerResumed);
+        }
+        if let Some(fallback) = &mut self.baud_fallback {
+            fallback.proposal = None;
+            fallback.crc_errors = 0;
+            fallback.switch_to = (fallback.index != 0).then_some(0);
+        }
+        dev_info!("Link was reset");
+    }
+
+    /// Moves the link to Down because of a deliberate teardown, either ours or
+    /// the peer's, which is not accounted as a link failure in the stats.
+    fn close_link(&mut self, reason: LinkDownReason) {
+        if self.link_status != LinkStatus::Down {
+            if !self.link_status.can_transition_to(LinkStatus::Down) {
+                dev_error!("Invalid link state transition {:?} => {:?}. Ignoring", self.link_status, LinkStatus::Down);
+                debug_assert!(false, "Invalid link state transition");
+                return;
+            }
+
+            self.link_down_reason = reason;
+            dev_info!("Link state changed {:?} => {:?} ({:?})", self.link_status, LinkStatus::Down, reason);
+            self.last_link_status_change_time = self.clock.current_instant();
+            if self.link_status == LinkStatus::Up {
+                self.emit_event(LinkEvent::Down);
+            }
+            self.link_status = LinkStatus::Down;
+        }
+        self.clear_link();
+    }
+
+    /// Waits for the bus to finish the current transfer, for at most the time
+    /// left until `deadline` after `start`. Returns false on timeout.
+    fn wait_tx_idle(&self, start: CS::TInstant, deadline: Duration) -> bool {
+        while self.bus.is_tx_busy() {
+            if self.clock.elapsed_since(start) >= deadline {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Tears the link down on purpose, e.g before a role handover, entering
+    /// suspend or rebooting into the bootloader. If the link is up, the pending
+    /// control frames are flushed and a Bye frame with the given reason is
+    /// sent, so that the peer considers the link down right away. This blocks
+    /// for at most `timeout` waiting for the bus. Then, every queued message is
+    /// dropped and the link is marked as Down. Returns whether the Bye frame
+    /// was sent completely.
+    ///
+    /// The link will be established again on the next polls if the peer keeps
+    /// probing, so polling should stop after this if that's not desired.
+    pub fn shutdown(&mut self, reason: ByeReason, timeout: Duration) -> bool {
+        let start = self.clock.current_instant();
+        let mut sent = false;
+
+        if self.link_status == LinkStatus::Up {
+            while self.has_pending_control() {
+                if !self.wait_tx_idle(start, timeout) || !self.transfer_control_frame() {
+                    break;
+                }
+            }
+
+            sent = !self.has_pending_control()
+                && self.wait_tx_idle(start, timeout)
+                && Self::transfer_frame::<NoMsg>(
+                    &mut self.bus,
+                    &self.auth,
+                    &self.clock,
+                    &mut self.last_sent_frame_time,
+                    &mut self.stats,
+                    self.frame_observer,
+                    &FrameContentEnvelope::new(0, FrameContent::Bye { reason }),
+                )
+                .is_ok()
+                && self.wait_tx_idle(start, timeout);
+
+            if !sent {
+                dev_warn!("Couldn't send the Bye frame to the peer in time");
+            }
+        }
+
+        self.close_link(LinkDownReason::Closed);
+        sent
+    }
+
+    /// Resets the whole state of the link as if it was just created, without
+    /// telling anything to the peer. Every queued message is dropped. The stats
+    /// are kept, since they are cumulative.
+    pub fn reset(&mut self) {
+        self.close_link(LinkDownReason::Closed);
+        self.reset_sequence_numbers();
+        self.peer_bye_reason = None;
+        self.link_down_reason = LinkDownReason::Initial;
+        self.loopback_detected = false;
+        self.rx_pending.clear();
+        dev_info!("Split bus reset");
+    }
+
+    fn push_control_frame(&mut self, frame: FrameContentEnvelope<NoMsg>) {
+        // Every caller checks for room first, so this shouldn't happen. Still,
+        // dropping a control frame is recoverable, the peer will ask again or
+        // the link will be reset.
+        if !self.control_tx_queue_has_room() {
+            dev_error!("No more space in the TX control queue. Dropping {:?}", frame.content);
+            return;
+        }
+
+        self.control_tx_queue.push(frame);
+        let len = self.control_tx_queue.len() as u32;
+        self.stats.control_tx_queue_high_watermark = self.stats.control_tx_queue_high_watermark.max(len);
+    }
+
+    /// Schedules the ACK of the transport message with the given seq number.
+    /// It replaces the ACK of any older message that hasn't been sent yet.
+    fn push_ack(&mut self, channel: Channel, seq: u8) {
+        let ack = &mut self.channels[channel as usize].rx_ack;
+        if ack.is_none_or(|pending| seq_diff(seq, pending) > 0) {
+            *ack = Some(seq);
+        }
+    }
+
+    /// Returns whether there's any control frame waiting to be sent, either
+    /// queued or pending.
+    fn has_pending_control(&self) -> bool {
+        self.pending_link_frame.is_some()
+            || self.channels.iter().any(|ch| ch.rx_ack.is_some())
+            || !self.control_tx_queue.is_empty()
+    }
+
+    /// Transfers the next control frame: the pending frame that sets up the
+    /// link first, then the pending ACKs, and then the queued frames. Returns
+    /// whether a frame was sent.
+    fn transfer_control_frame(&mut self) -> bool {
+        let link_frame;
+        let ack;
+        let (frame, source) = if let Some(pending) = self.pending_link_frame {
+            link_frame = self.build_link_frame(pending);
+            (&link_frame, ControlSource::Link)
+        } else if let Some(channel) = Channel::ALL.into_iter().find(|c| self.channels[*c as usize].rx_ack.is_some()) {
+            ack = FrameContentEnvelope {
+                seq: self.channels[channel as usize].rx_ack.unwrap_or_default(),
+                content: FrameContent::Ack { channel },
+            };
+            (&ack, ControlSource::Ack(channel))
+        } else if let Some(queued) = self.control_tx_queue.peek() {
+            (queued, ControlSource::Queue)
+        } else {
+            return false;
+        };
+
+        let res = Self::transfer_frame::<NoMsg>(
+            &mut self.bus,
+            &self.auth,
+            &self.clock,
+            &mut self.last_sent_frame_time,
+            &mut self.stats,
+            self.frame_observer,
+            frame,
+        );
+        if res.is_err() {
+            return false;
+        }
+
+        match source {
+            ControlSource::Link => self.pending_link_frame = None,
+            ControlSource::Ack(channel) => self.channels[channel as usize].rx_ack = None,
+            ControlSource::Queue => {
+                self.control_tx_queue.dequeue();
+            }
+        }
+        true
+    }
+
+    /// Returns whether there's room for one more control frame, counting the
+    /// times there isn't. Only for the frames that are dropped or refused
+    /// when the queue is full, not for the ones that are just deferred to a
+    /// later poll.
+    fn control_tx_queue_has_room(&mut self) -> bool {
+        if self.control_tx_queue.is_full() {
+            self.stats.control_tx_queue_full = self.stats.control_tx_queue_full.saturating_add(1);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn read_device_id(buf: [u8; 16]) -> u128 {
+        u128::from_be_bytes(buf)
+    }
+
+    fn write_device_id(id: u128) -> [u8; 16] {
+        u128::to_be_bytes(id)
+    }
+
+    /// Mutates the current state of the link based on a received
+    /// frame and the current status of the link. Returns a value that
+    /// indicates whether it should continue polling, depending on the
+    /// function provided by the user and the last received frame. The
+    /// continuing decision will be always true unless the next frame
+    /// type is a message, in which case, the function provided is
+    /// executed, and its result is used as result for this function.
+    fn on_frame<F: FnMut(&Msg) -> bool>(
+        &mut self,
+        frame: &Frame<Msg>,
+        fragment_data: &[u8],
+        recvf: &mut F,
+    ) -> bool {
+        if self.seq_jump_log_threshold > 0
+            && self.link_status == LinkStatus::Up
+            && self.seq_jump(&frame.envelope).unsigned_abs() >= self.seq_jump_log_threshold as u16
+        {
+            dev_warn!(
+                "Received frame with a seq number off by at least {} from the expected one: {:?}",
+                self.seq_jump_log_threshold,
+                frame.envelope
+            );
+        }
+
+        match (self.link_status, &frame.envelope.content) {
+            // There's nothing to do with a probe, unless the link is
+            // down. In that case, receiving it triggers a link sync. The peer
+            // stops probing while it has other frames to send, so any of the
+            // ones below triggers it as well.
+            (_, FrameContent::LinkProbe { device_id }) if Self::read_device_id(*device_id) == self.device_id => {
+                dev_warn!("Ignoring link probe coming from same Device ID: 0x{:x}", self.device_id);
+                self.on_loopback();
+            }
+            (LinkStatus::Down, FrameContent::LinkProbe { .. }) => self.begin_sync(),
+            (LinkStatus::Incompatible, FrameContent::LinkProbe { .. })
+                if self.clock.elapsed_since(self.last_link_status_change_time) >= Ts::MAX_LINK_IDLE_TIME =>
+            {
+                self.begin_sync()
+            }
+            (_, FrameContent::LinkProbe { .. }) => {}
+
+            (_, FrameContent::Sync { version, integrity, device_id, reset_reason, host }) => {
+                self.on_sync(
+                    *version,
+                    *integrity,
+                    Self::read_device_id(*device_id),
+                    ResetReason::from_u8(*reset_reason),
+                    HostPresence::from_u8(*host),
+                );
+            }
+
+            // This only should be received when our link is in
+            // sync state, and confirms that the peer has resetted
+            // the seq numbers and it has set its link to Up,
+            // becoming ready to receive traffic, unless its
+            // version doesn't match ours.
+            (LinkStatus::Sync, FrameContent::SyncAck { version, integrity, reset_reason, host, device_id }) => {
+                dev_debug!("Received SyncACK");
+                if self.accept_peer_sync(*version, *integrity) {
+                    if let Some(reason) = ResetReason::from_u8(*reset_reason) {
+                        self.on_peer_rebooted(reason);
+                    }
+                    self.elect_role(HostPresence::from_u8(*host), Self::read_device_id(*device_id));
+                    self.change_link_state(LinkStatus::Up);
+                    self.reset_sequence_numbers();
+                }
+            }
+            (
+                LinkStatus::Down,
+                FrameContent::SyncAck { .. }
+                | FrameContent::Ack { .. }
+                | FrameContent::TransportMessage { .. }
+                | FrameContent::Datagram { .. }
+                | FrameContent::TransportFragment { .. }
+                | FrameContent::TransportBatch { .. }
+                | FrameContent::TimeRequest { .. }
+                | FrameContent::TimeResponse { .. }
+                | FrameContent::BootloaderRequest
+                | FrameContent::BootloaderAck
+                | FrameContent::BaudChange { .. }
+                | FrameContent::BaudChangeAck { .. }
+                | FrameContent::Ping { .. }
+                | FrameContent::Pong { .. }
+                | FrameContent::PowerState { .. }
+                | FrameContent::PowerStateAck { .. }
+                | FrameContent::WakeRequest,
+            ) => self.begin_sync(),
+            // The answer to a Sync sent while the link was up, e.g for
+            // electing the master side again.
+            (LinkStatus::Up, FrameContent::SyncAck { host, device_id, .. }) => {
+                self.elect_role(HostPresence::from_u8(*host), Self::read_device_id(*device_id));
+            }
+            (_, FrameContent::SyncAck { .. }) => {
+                dev_debug!("Received unsolicitated SyncACK. Ignoring.");
+            }
+
+            // Whatever the state of the link is, the peer won't
+            // process anything we send from now on, so there's no
+            // point on keep waiting for ACKs or a sync to
+            // complete. If the peer comes back, it will start
+            // probing the link again and the usual sync will
+            // happen.
+            (LinkStatus::Down, FrameContent::Bye { .. }) => {}
+            (_, FrameContent::Bye { reason }) => {
+                dev_info!("Peer closed the link: {:?}", reason);
+                self.close_link(LinkDownReason::PeerClosed(*reason));
+                self.peer_bye_reason = Some(*reason);
+                if *reason == ByeReason::Reset {
+                    self.emit_event(LinkEvent::PeerReset);
+                }
+            }
+
+            (LinkStatus::Up, FrameContent::Ack { channel }) => self.on_ack(*channel, frame.envelope.seq),
+            (LinkStatus::Up, FrameContent::TransportMessage { channel, msg }) => {
+                return self.handle_rx_msg(*channel, frame.envelope.seq, msg, recvf);
+            }
+            (LinkStatus::Up, FrameContent::Datagram { msg }) => return recvf(msg),
+            (LinkStatus::Up, FrameContent::TransportFragment { channel, index, count }) => {
+                if self.accept_transport_frame(*channel, frame.envelope.seq) {
+                    return self.handle_rx_fragment(*channel, *index, *count, fragment_data, recvf);
+                }
+            }
+            (LinkStatus::Up, FrameContent::TransportBatch { channel, count }) => {
+                return self.handle_rx_batch(*channel, frame.envelope.seq, *count, fragment_data, recvf);
+            }
+            (LinkStatus::Up, FrameContent::TimeRequest { t0 }) => {
+                // Losing an answer only costs a sample, so it is not worth
+                // waiting for space in the queue.
+                if self.control_tx_queue_has_room() {
+                    let t1 = self.uptime_us().to_le_bytes();
+                    self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::TimeResponse { t0: *t0, t1 }));
+                }
+            }
+            (LinkStatus::Up, FrameContent::TimeResponse { t0, t1 }) => {
+                self.on_time_response(u64::from_le_bytes(*t0), u64::from_le_bytes(*t1));
+            }
+            (LinkStatus::Up, FrameContent::BootloaderRequest) => {
+                // The request is sent again if the ACK is lost, so there's no
+                // need to wait for space in the queue.
+                if self.control_tx_queue_has_room() {
+                    self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::BootloaderAck));
+                }
+                if !self.bootloader_requested {
+                    dev_info!("Peer requested entering the bootloader");
+                    self.bootloader_requested = true;
+                    self.emit_event(LinkEvent::BootloaderRequested);
+                }
+            }
+            (LinkStatus::Up, FrameContent::BootloaderAck) => {
+                if self.peer_bootloader_request_time.take().is_some() {
+                    dev_info!("Peer is entering the bootloader");
+                }
+            }
+            (LinkStatus::Up, FrameContent::BaudChange { baud }) => self.on_baud_change(*baud),
+            (LinkStatus::Up, FrameContent::BaudChangeAck { baud }) => self.on_baud_change_ack(*baud),
+            (LinkStatus::Up, FrameContent::Ping { id }) => {
+                // A lost answer only fails the measurement, which can be
+                // started again.
+                if self.control_tx_queue_has_room() {
+                    self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::Pong { id: *id }));
+                }
+            }
+            (LinkStatus::Up, FrameContent::Pong { id }) => self.on_pong(*id),
+            (LinkStatus::Up, FrameContent::PowerState { suspended }) => {
+                // The state is sent again if the ACK is lost.
+                if self.control_tx_queue_has_room() {
+                    self.push_control_frame(FrameContentEnvelope::new(
+                        0,
+                        FrameContent::PowerStateAck { suspended: *suspended },
+                    ));
+                }
+                self.on_peer_power_state(*suspended != 0);
+            }
+            (LinkStatus::Up, FrameContent::PowerStateAck { suspended }) => {
+                // An ACK of a state that changed again in the meantime doesn't
+                // count.
+                if self.power_state_pending && (*suspended != 0) == self.local_suspended {
+                    self.power_state_pending = false;
+                    self.power_state_sent_time = None;
+                }
+            }
+            (LinkStatus::Up, FrameContent::WakeRequest) => {
+                dev_info!("Peer requested waking up the host");
+                self.emit_event(LinkEvent::WakeRequested);
+            }
+            (
+                _,
+                FrameContent::Ack { .. }
+                | FrameContent::TransportMessage { .. }
+                | FrameContent::Datagram { .. }
+                | FrameContent::TransportFragment { .. }
+                | FrameContent::TransportBatch { .. }
+                | FrameContent::TimeRequest { .. }
+                | FrameContent::TimeResponse { .. }
+                | FrameContent::BootloaderRequest
+                | FrameContent::BootloaderAck
+                | FrameContent::BaudChange { .. }
+                | FrameContent::BaudChangeAck { .. }
+                | FrameContent::Ping { .. }
+                | FrameContent::Pong { .. }
+                | FrameContent::PowerState { .. }
+                | FrameContent::PowerStateAck { .. }
+                | FrameContent::WakeRequest,
+            ) => {
+                dev_debug!(
+                    "Received transport frame when link status was not Up. Silently discarding frame"
+                );
+            }
+        }
+
+        true
+    }
+
+    /// Starts a link sync, after receiving a frame from the peer while the link
+    /// was not up.
+    fn begin_sync(&mut self) {
+        dev_debug!("Received frame from peer. Starting link synchronization");
+        self.loopback_detected = false;
+        self.change_link_state(LinkStatus::Sync);
+        self.push_sync();
+    }
+
+    fn push_sync(&mut self) {
+        self.advertised_host_presence = self.host_presence;
+        self.pending_link_frame = Some(LinkFrame::Sync);
+    }
+
+    fn push_sync_ack(&mut self) {
+        self.advertised_host_presence = self.host_presence;
+        self.pending_link_frame = Some(LinkFrame::SyncAck);
+    }
+
+    /// Builds a frame that sets up the link from the current state. The host
+    /// presence sent is the one advertised when it was requested.
+    fn build_link_frame(&self, frame: LinkFrame) -> FrameContentEnvelope<NoMsg> {
+        let device_id = Self::write_device_id(self.device_id);
+        let content = match frame {
+            LinkFrame::Probe => FrameContent::LinkProbe { device_id },
+            LinkFrame::Sync => FrameContent::Sync {
+                version: self.protocol_version,
+                integrity: I::ID,
+                device_id,
+                reset_reason: self.reset_reason_byte(),
+                host: self.advertised_host_presence as u8,
+            },
+            LinkFrame::SyncAck => FrameContent::SyncAck {
+                version: self.protocol_version,
+                integrity: I::ID,
+                reset_reason: self.reset_reason_byte(),
+                host: self.advertised_host_presence as u8,
+                device_id,
+            },
+        };
+        FrameContentEnvelope::new(0, content)
+    }
+
+    /// Elects which side is the master from the host presence announced by
+    /// both peers during the last sync: the one that is closer to being
+    /// enumerated by the host, or the one with the greatest device ID if
+    /// both are as close. Both peers reach the same result, since they use
+    /// the same announcements.
+    fn elect_role(&mut self, peer_host: HostPresence, peer_device_id: u128) {
+        let role = match self.advertised_host_presence.cmp(&peer_host) {
+            core::cmp::Ordering::Greater => PeerRole::Master,
+            core::cmp::Ordering::Less => PeerRole::Slave,
+            core::cmp::Ordering::Equal if self.device_id > peer_device_id => PeerRole::Master,
+            core::cmp::Ordering::Equal => PeerRole::Slave,
+        };
+
+        if let Some(previous) = self.negotiated_role {
+            if previous != role {
+                dev_info!("Negotiated role changed {:?} => {:?}", previous, role);
+                if let Some(hook) = self.on_role_changed {
+                    hook(role);
+                }
+            }
+        }
+        self.negotiated_role = Some(role);
+    }
+
+    fn reset_reason_byte(&self) -> u8 {
+        self.reset_reason.map_or(0, |reason| reason as u8)
+    }
+
+    fn on_sync(
+        &mut self,
+        version: u8,
+        integrity: u8,
+        peer_device_id: u128,
+        reset_reason: Option<ResetReason>,
+        peer_host: HostPresence,
+    ) {
+        // A sync can happen on any of the different link states:
+        //
+        // - Down: We were anyway wainting for a sync, and the
+        // peer has started it.
+        // - Sync: We've recently sent a sync frame. The peer
+        // did this too, so we are both trying to sync.
+        // - Up: We were supposed both peers to be Up at this
+        // point, but it seems that the other maybe have
+        // de-sync the status, and it is trying to sync again.
+
+        // Regardless of when we do receive this kind of
+        // frame, the outcome must be a transition to the Up
+        // state, a reset in the sequence numbers, and a
+        // transmission of a SyncAck frame.
+
+        // The ACKs pending to be sent are dropped along with the
+        // seq numbers, since they would be useless after the
+        // reset.
+        if peer_device_id == self.device_id {
+            dev_error!("Peer sent our same device ID while trying to sync the channel. Crosstalk between the bus lines? Link establishment aborted");
+            self.set_link_down(LinkDownReason::Loopback);
+            self.on_loopback();
+        } else if !self.accept_peer_sync(version, integrity) {
+            // Answer anyway, so that the peer finds out about the
+            // incompatibility too instead of waiting for the SyncAck.
+            self.push_sync_ack();
+        } else {
+            dev_info!("Established connection with peer: 0x{:x}", peer_device_id);
+            if let Some(reason) = reset_reason {
+                self.on_peer_rebooted(reason);
+            }
+            // Elected with the presence announced in the SyncAck below.
+            self.advertised_host_presence = self.host_presence;
+            self.elect_role(peer_host, peer_device_id);
+            if self.link_status == LinkStatus::Up {
+                self.power_state_pending = self.local_suspended;
+                self.emit_event(LinkEvent::Resync);
+            } else {
+                self.change_link_state(LinkStatus::Up);
+            }
+            self.reset_sequence_numbers();
+            self.push_sync_ack();
+        }
+    }
+
+    /// Records the power state told by the peer, reporting it if it changed.
+    fn on_peer_power_state(&mut self, suspended: bool) {
+        if self.peer_suspended == suspended {
+            return;
+        }
+
+        dev_info!("Peer host suspended: {}", suspended);
+        self.peer_suspended = suspended;
+        self.emit_event(if suspended { LinkEvent::PeerSuspended } else { LinkEvent::PeerResumed });
+    }
+
+    /// Returns whether the link is in low power, because the host of either
+    /// side is suspended. It is kept while a change of our state hasn't been
+    /// ACK'ed, since the peer may still be probing with the longer interval.
+    fn is_low_power(&self) -> bool {
+        self.local_suspended || self.peer_suspended || self.power_state_pending
+    }
+
+    /// Returns the time between the probes of the link.
+    fn probe_interval(&self) -> Duration {
+        if self.is_low_power() {
+            Ts::SUSPENDED_PROBE_INTERVAL_TIME
+        } else {
+            Ts::LINK_IDLE_PROBE_INTERVAL_TIME
+        }
+    }
+
+    /// Returns the max time without receiving anything before the link is
+    /// considered down.
+    fn max_idle_time(&self) -> Duration {
+        if self.is_low_power() {
+            Ts::SUSPENDED_MAX_LINK_IDLE_TIME
+        } else {
+            Ts::MAX_LINK_IDLE_TIME
+        }
+    }
+
+    /// Returns whether our power state has to be sent to the peer, either for
+    /// the first time or because the last one wasn't ACK'ed in time.
+    fn is_power_state_due(&self) -> bool {
+        self.power_state_pending
+            && !self.control_tx_queue.is_full()
+            && self
+                .power_state_sent_time
+                .is_none_or(|sent| self.clock.elapsed_since(sent) > Ts::MSG_REPLAY_DELAY_TIME)
+    }
+
+    /// Reports that the peer has been reset since the link was last up. The
+    /// peer keeps telling it until the link is up, so it may be reported
+    /// twice if a SyncAck is lost.
+    fn on_peer_rebooted(&mut self, reason: ResetReason) {
+        if reason.is_abnormal() {
+            dev_warn!("Peer rebooted after an abnormal reset: {:?}", reason);
+        } else {
+            dev_info!("Peer rebooted: {:?}", reason);
+        }
+        self.peer_reset_reason = Some(reason);
+        self.emit_event(LinkEvent::PeerRebooted(reason));
+    }
+
+    /// Reports that our own frames are being received back, the first time
+    /// they are since the last frame from the peer.
+    fn on_loopback(&mut self) {
+        if !self.loopback_detected {
+            dev_error!("Loopback detected: our own frames are received back. Are the TX and RX lines shorted?");
+            self.loopback_detected = true;
+            self.emit_event(LinkEvent::Loopback);
+        }
+    }
+
+    fn on_ack(&mut self, channel: Channel, seq: u8) {
+        // ACKs are cumulative: the ACK of a seq number
+        // confirms that message and every previous one, since
+        // the peer only accepts messages in order.
+        let ch = &mut self.channels[channel as usize];
+        let diff = seq_diff(seq, ch.tx_seq);
+        let in_flight = ch.tx_in_flight.len();
+        if diff < 0 {
+            dev_event!(warn, log_ids::DUPLICATE_ACK, "Received duplicated ACK for seq number {}", seq);
+            self.stats.duplicate_acks = self.stats.duplicate_acks.saturating_add(1);
+            self.on_seq_error();
+            return;
+        }
+
+        let mut acked = diff as usize + 1;
+        let unexpected = in_flight == 0 || acked > in_flight;
+        if in_flight == 0 {
+            dev_debug!("Received an ACK message when there was no in-flight message?");
+            acked = 0;
+        } else if acked > in_flight {
+            dev_event!(
+                warn,
+                log_ids::UNEXPECTED_ACK,
+                "TX seq number increased unexpectedly by remoted peer by {}.",
+                acked - in_flight
+            );
+            acked = in_flight;
+        }
+
+        for _ in 0..acked {
+            if let Some(msg) = ch.tx_in_flight.dequeue() {
+                if msg.retransmissions == 0 {
+                    self.stats.add_rtt_sample(self.clock.elapsed_since(msg.sent_time));
+                }
+            }
+            if ch.tx_fragment_index + 1 < ch.tx_fragment_count {
+                // Keep the message until every fragment is sent.
+                ch.tx_fragment_index += 1;
+            } else {
+                ch.tx_fragment_index = 0;
+                ch.tx_fragment_count = 0;
+                let _ = ch.tx_queue.dequeue();
+                ch.tx_head_msg.clear();
+            }
+        }
+
+        if acked > 0 {
+            dev_trace!(
+                "Successfully ACK'ed {} messages up to seq: {} ({:?})",
+                acked,
+                seq,
+                channel
+            );
+        }
+
+        ch.tx_seq = seq.wrapping_add(1);
+
+        if unexpected {
+            self.stats.unexpected_acks = self.stats.unexpected_acks.saturating_add(1);
+            self.on_seq_error();
+        } else {
+            self.seq_errors = 0;
+        }
+    }
+
+    /// Counts an ACK or a transport frame whose seq number was not the expected
+    /// one. These are usual after a loss, but if no seq number is the expected
+    /// one for so long, the peer is likely using a different sequence, so the
+    /// link is set down, forcing a new sync.
+    fn on_seq_error(&mut self) {
+        self.seq_errors = self.seq_errors.saturating_add(1);
+        if self.max_seq_errors > 0 && self.seq_errors >= self.max_seq_errors {
+            dev_event!(
+                warn,
+                log_ids::TOO_MANY_SEQ_ERRORS,
+                "Received {} unexpected seq numbers in a row. Resetting link",
+                self.seq_errors
+            );
+            self.set_link_down(LinkDownReason::TooManySeqErrors);
+        }
+    }
+
+    /// ACKs a received transport frame with the given seq number, returning
+    /// whether it is a new frame that must be processed.
+    fn accept_transport_frame(&mut self, channel: Channel, seq: u8) -> bool {
+        let accepted = self.check_transport_seq(channel, seq);
+        if accepted.is_some() {
+            self.push_ack(channel, seq);
+        }
+        accepted == Some(true)
+    }
+
+    /// Returns how far the seq number of an ACK or transport frame is from the
+    /// expected one, or zero for any other frame. For ACKs, any seq number of
+    /// a message in flight is expected.
+    fn seq_jump(&self, envelope: &FrameContentEnvelope<Msg>) -> i16 {
+        match &envelope.content {
+            FrameContent::Ack { channel } => {
+                let ch = &self.channels[*channel as usize];
+                let diff = seq_diff(envelope.seq, ch.tx_seq) as i16;
+                let in_flight = ch.tx_in_flight.len() as i16;
+                if diff < 0 {
+                    diff
+                } else {
+                    (diff + 1 - in_flight).max(0)
+                }
+            }
+            FrameContent::TransportMessage { channel, .. }
+            | FrameContent::TransportFragment { channel, .. }
+            | FrameContent::TransportBatch { channel, .. } => {
+                seq_diff(envelope.seq, self.channels[*channel as usize].rx_seq) as i16
+            }
+            _ => 0,
+        }
+    }
+
+    /// Checks the seq number of a received transport message, moving on to
+    /// the next one if it was the expected one. Returns whether it is a new
+    /// message, or `None` if it must be dropped without ACK.
+    fn check_transport_seq(&mut self, channel: Channel, seq: u8) -> Option<bool> {
+        let rx_seq = self.channels[channel as usize].rx_seq;
+        let diff = seq_diff(seq, rx_seq);
+
+        if diff > 0 {
+            // The peer may have several messages in flight, so a
+            // seq number greater than expected means that a
+            // previous message was lost. This one is dropped
+            // without ACK, and the peer will re-send it after the
+            // lost one, once it doesn't receive their ACKs in
+            // time.
+            dev_debug!(
+                "Dropping out of order frame. Expecting seq {} but {} found ({:?})",
+                rx_seq,
+                seq,
+                channel
+            );
+            self.stats.out_of_order_frames = self.stats.out_of_order_frames.saturating_add(1);
+            self.on_seq_error();
+            return None;
+        }
+
+        // For every other message received, we need to answer with an ACK:
+        // - If the received seq number is the expected
+        // one, then we need to send the ACK
+        // to notify that we've received the message.
+
+        // - If we've received a seq number lower than
+        // expected, is because the peer has re-send a
+        // message that it considered dropped. This could
+        // be because the previous ACK frame that we've
+        // sent hasn't been received properly by the peer,
+        // so it is important to send it again.
+        if diff < 0 {
+            dev_debug!(
+                "Dropping possibly duplicated frame. Expecting seq {} but {} found ({:?})",
+                rx_seq,
+                seq,
+                channel
+            );
+            self.stats.duplicate_frames = self.stats.duplicate_frames.saturating_add(1);
+            self.on_seq_error();
+            Some(false)
+        } else {
+            self.channels[channel as usize].next_rx_seq();
+            self.seq_errors = 0;
+            Some(true)
+        }
+    }
+
+    /// Holds a transport message received ahead of the expected one, if it
+    /// is within the reorder depth. Returns whether it was held.
+    fn hold_rx_msg(&mut self, channel: Channel, seq: u8, msg: &Msg) -> bool {
+        let ch = &mut self.channels[channel as usize];
+        let ahead = seq_diff(seq, ch.rx_seq);
+        if ahead <= 0 || ahead as u8 > self.rx_reorder_depth {
+            return false;
+        }
+
+        dev_debug!(
+            "Holding out of order frame. Expecting seq {} but {} found ({:?})",
+            ch.rx_seq,
+            seq,
+            channel
+        );
+        // It isn't ACK'ed, since the ACKs are cumulative and the previous
+        // messages are still missing.
+        ch.rx_reorder[ahead as usize] = Some(msg.clone());
+        self.stats.reordered_frames = self.stats.reordered_frames.saturating_add(1);
+        true
+    }
+
+    /// Delivers a received transport message, followed by the ones held
+    /// right after it, and ACKs the last one delivered. Returns whether the
+    /// polling should continue, as [`Self::on_frame`] does.
+    fn handle_rx_msg<F: FnMut(&Msg) -> bool>(&mut self, channel: Channel, seq: u8, msg: &Msg, recvf: &mut F) -> bool {
+        if self.hold_rx_msg(channel, seq, msg) {
+            return true;
+        }
+
+        let Some(is_new) = self.check_transport_seq(channel, seq) else {
+            return true;
+        };
+
+        let mut ack = seq;
+        let mut should_continue = !is_new || recvf(msg);
+        if is_new {
+            while should_continue {
+                let ch = &mut self.channels[channel as usize];
+                let Some(held) = ch.rx_reorder[0].take() else {
+                    break;
+                };
+                ack = ch.rx_seq;
+                ch.next_rx_seq();
+                should_continue = recvf(&held);
+            }
+        }
+
+        self.push_ack(channel, ack);
+        should_continue
+    }
+
+    /// Delivers the new messages of a received transport batch, in order, and
+    /// ACKs the last one accepted. If the polling is stopped in the middle of
+    /// the batch, the rest of it is left unACK'ed, so the peer will re-send
+    /// it. Returns whether the polling should continue, as
+    /// [`Self::on_frame`] does.
+    fn handle_rx_batch<F: FnMut(&Msg) -> bool>(
+        &mut self,
+        channel: Channel,
+        seq: u8,
+        count: u8,
+        mut data: &[u8],
+        recvf: &mut F,
+    ) -> bool {
+        let mut ack = None;
+        let mut should_continue = true;
+        for i in 0..count {
+            let Some((&len, rest)) = data.split_first() else {
+                dev_event!(
+                    warn,
+                    log_ids::SHORT_TRANSPORT_BATCH,
+                    "Transport batch is shorter than expected. Dropping the rest of it"
+                );
+                break;
+            };
+            if rest.len() < len as usize {
+                dev_event!(
+                    warn,
+                    log_ids::SHORT_TRANSPORT_BATCH,
+                    "Transport batch is shorter than expected. Dropping the rest of it"
+                );
+                break;
+            }
+            let (msg_data, rest) = rest.split_at(len as usize);
+            data = rest;
+
+            let Ok((msg, _)) = ssmarshal::deserialize::<Msg>(msg_data) else {
+                dev_event!(
+                    warn,
+                    log_ids::INVALID_BATCHED_MSG,
+                    "Failed to parse message in transport batch. Dropping the rest of it"
+                );
+                break;
+            };
+
+            let msg_seq = seq.wrapping_add(i);
+            match self.check_transport_seq(channel, msg_seq) {
+                None => break,
+                Some(false) => ack = Some(msg_seq),
+                Some(true) => {
+                    ack = Some(msg_seq);
+                    if !recvf(&msg) {
+                        should_continue = false;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(seq) = ack {
+            self.push_ack(channel, seq);
+        }
+        should_continue
+    }
+
+    /// Appends a received fragment to the incoming message, delivering it if
+    /// it was the last one. Returns whether the polling should continue, as
+    /// [`Self::on_frame`] does.
+    fn handle_rx_fragment<F: FnMut(&Msg) -> bool>(
+        &mut self,
+        channel: Channel,
+        index: u8,
+        count: u8,
+        data: &[u8],
+        recvf: &mut F,
+    ) -> bool {
+        let ch = &mut self.channels[channel as usize];
+        if index == 0 {
+            ch.reset_rx_fragments();
+            ch.rx_fragment_count = count;
+        }
+
+        if index >= count || index != ch.rx_fragment_next || count != ch.rx_fragment_count {
+            dev_event!(
+                warn,
+                log_ids::UNEXPECTED_FRAGMENT,
+                "Received unexpected fragment {}/{}. Dropping message",
+                index,
+                count
+            );
+            ch.reset_rx_fragments();
+            return true;
+        }
+
+        if ch.rx_fragments.extend_from_slice(data).is_err() {
+            dev_event!(
+                warn,
+                log_ids::FRAGMENTED_MSG_TOO_LARGE,
+                "Fragmented message is larger than expected. Dropping message"
+            );
+            ch.reset_rx_fragments();
+            return true;
+        }
+
+        ch.rx_fragment_next = index + 1;
+        if ch.rx_fragment_next < count {
+            return true;
+        }
+
+        let decoded = ssmarshal::deserialize::<Msg>(&ch.rx_fragments);
+        ch.reset_rx_fragments();
+        let Ok((msg, _)) = decoded else {
+            dev_event!(warn, log_ids::INVALID_FRAGMENTED_MSG, "Failed to parse fragmented message. Dropping it");
+            return true;
+        };
+
+        recvf(&msg)
+    }
+
+    /// Decodes the first frame of a chunk polled from the bus, and checks its
+    /// authentication. See [`Self::decode_authentic_frame`]. Noise on the line
+    /// may prepend garbage to a frame within the same chunk, so if the chunk
+    /// doesn't start with the preamble, the frame is looked for from each
+    /// preamble byte found in it, until one of them yields a valid frame. The
+    /// length returned includes the bytes skipped, which are returned apart
+    /// as well.
+    fn decode_polled_frame<'f>(auth: &A, chunk: &'f [u8]) -> Result<(Frame<Msg>, &'f [u8], usize, usize), RxError> {
+        if chunk.first() != Some(&FRAME_PRELUDE_BYTE) {
+            let resynced = chunk
+                .iter()
+                .enumerate()
+                .skip(1)
+                .filter(|(_, byte)| **byte == FRAME_PRELUDE_BYTE)
+                .find_map(|(offset, _)| {
+                    let (frame, raw, len) = Self::decode_authentic_frame(auth, &chunk[offset..]).ok()?;
+                    Some((frame, raw, offset, offset + len))
+                });
+            if let Some(resynced) = resynced {
+                return Ok(resynced);
+            }
+        }
+
+        // Nothing found, so the frame is dropped with the error of the
+        // chunk as a whole.
+        Self::decode_authentic_frame(auth, chunk).map(|(frame, raw, len)| (frame, raw, 0, len))
+    }
+
+    /// Decodes the frame at the start of `chunk` and checks its
+    /// authentication, returning it along with its raw data and the length it
+    /// takes in the chunk, including the authentication tag. Frames sent back
+    /// to back may arrive in the same chunk if there's no idle gap between
+    /// them, so any frame may be followed by more frames, except for transport
+    /// fragments and batches, which take the rest of the chunk since the
+    /// length of their raw data is not encoded.
+    fn decode_authentic_frame<'f>(auth: &A, chunk: &'f [u8]) -> Result<(Frame<Msg>, &'f [u8], usize), RxError> {
+        if chunk.len() < A::TAG_LEN {
+            return Err(RxError::Unauthentic);
+        }
+
+        let body = &chunk[..chunk.len() - A::TAG_LEN];
+        let (frame, leftover) = Self::decode_frame(body).map_err(RxError::Decode)?;
+        let (len, raw) = if frame.envelope.content.has_raw_data() {
+            (chunk.len(), leftover)
+        } else {
+            // The tag of the frame is in the leftover, right after the
+            // envelope.
+            (body.len() - leftover.len() + A::TAG_LEN, &[][..])
+        };
+
+        if !Self::is_authentic(auth, &chunk[..len]) {
+            return Err(RxError::Unauthentic);
+        }
+        Ok((frame, raw, len))
+    }
+
+    /// Returns whether the frame ends with the right authentication tag.
+    fn is_authentic(auth: &A, frame: &[u8]) -> bool {
+        frame.len() >= A::TAG_LEN && {
+            let (frame, tag) = frame.split_at(frame.len() - A::TAG_LEN);
+            auth.verify(frame, tag)
+        }
+    }
+
+    fn do_rx<F: FnMut(&Msg) -> bool>(&mut self, mut recvf: F) {
+        // The frames are decoded in place when the bus can hand them that way,
+        // so `rxbuf` is only written by the buses that can't. The raw data
+        // that follows the envelope of fragments and batches, and the frames
+        // that follow the first one of a chunk, are the only parts of a chunk
+        // that need to outlive the borrow of the bus.
+        let mut rxbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+        let mut rawbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+        while {
+            let should_continue = if !self.rx_pending.is_empty() {
+                self.on_pending_frame(&mut recvf)
+            } else {
+                let auth = &self.auth;
+                let observer = self.frame_observer;
+                let pending = &mut self.rx_pending;
+                let polled = self.bus.poll_next_ref(&mut rxbuf, |chunk| {
+                    dev_trace!("<-- RX: {:x?}", chunk);
+                    let (decoded, raw, skipped, len) = Self::decode_polled_frame(auth, chunk).inspect_err(|e| {
+                        if let Some(observer) = observer {
+                            observer.on_drop(chunk, e.drop_reason());
+                        }
+                    })?;
+                    if let Some(observer) = observer {
+                        let frame = &chunk[skipped..len];
+                        observer.on_rx(frame, &Self::frame_info(&decoded.envelope.content, decoded.envelope.seq, frame));
+                    }
+                    rawbuf[..raw.len()].copy_from_slice(raw);
+                    // Cannot fail, the chunk is not larger than the buffer.
+                    let _ = pending.extend_from_slice(&chunk[len..]);
+                    Ok((decoded, raw.len(), skipped))
+                });
+
+                match polled {
+                    Ok(Ok((frame, raw_len, skipped))) => {
+                        if skipped > 0 {
+                            dev_debug!("Skipped {} bytes before the preamble of the frame", skipped);
+                            self.stats.resynced_frames = self.stats.resynced_frames.saturating_add(1);
+                        }
+                        self.on_decoded_frame(&frame, &rawbuf[..raw_len], &mut recvf)
+                    }
+                    Ok(Err(RxError::Unauthentic)) => {
+                        dev_event!(warn, log_ids::FRAME_AUTH_FAILED, "Frame authentication failed. Dropping frame");
+                        self.stats.auth_failures = self.stats.auth_failures.saturating_add(1);
+                        true
+                    }
+                    Ok(Err(RxError::Decode(FrameDecodeError::PreludeError))) => {
+                        dev_debug!("Invalid prelude in frame. Dropping frame");
+                        true
+                    }
+                    Ok(Err(RxError::Decode(FrameDecodeError::CrcError))) => {
+                        dev_debug!("Invalid frame CRC. Dropping frame");
+                        self.on_crc_error();
+                        true
+                    }
+                    Ok(Err(RxError::Decode(e @ FrameDecodeError::SerdeError(_)))) => {
+                        dev_debug!("Failed to parse frame: {:?}", e);
+                        true
+                    }
+                    Err(BusPollError::BufferOverflow) => {
+                        if let Some(observer) = self.frame_observer {
+                            observer.on_drop(&[], FrameDropReason::Overflow);
+                        }
+                        true
+                    }
+                    Err(BusPollError::WouldBlock) => false,
+                }
+            };
+
+            should_continue
+        } {}
+    }
+
+    /// Handles the next frame of the ones received in the same chunk as a
+    /// previous one. They are kept until the next poll if the user stops
+    /// polling messages before.
+    fn on_pending_frame<F: FnMut(&Msg) -> bool>(&mut self, recvf: &mut F) -> bool {
+        let pending = core::mem::take(&mut self.rx_pending);
+        match Self::decode_authentic_frame(&self.auth, &pending) {
+            Ok((frame, raw, len)) => {
+                if let Some(observer) = self.frame_observer {
+                    let info = Self::frame_info(&frame.envelope.content, frame.envelope.seq, &pending);
+                    observer.on_rx(&pending[..len], &info);
+                }
+                // Cannot fail, it was taken from there.
+                let _ = self.rx_pending.extend_from_slice(&pending[len..]);
+                self.on_decoded_frame(&frame, raw, recvf)
+            }
+            Err(_) => {
+                if let Some(observer) = self.frame_observer {
+                    observer.on_drop(&pending, FrameDropReason::Leftover);
+                }
+                // Whatever follows a valid frame and is not a frame is most
+                // likely noise, rather than a corrupted frame.
+                dev_event!(
+                    warn,
+                    log_ids::FRAME_LEFTOVER_BYTES,
+                    "Frame decode left {} bytes unused. Ignoring",
+                    pending.len()
+                );
+                true
+            }
+        }
+    }
+
+    fn on_decoded_frame<F: FnMut(&Msg) -> bool>(&mut self, frame: &Frame<Msg>, raw: &[u8], recvf: &mut F) -> bool {
+        self.last_recv_frame_time = self.clock.current_instant();
+        self.stats.frames_received = self.stats.frames_received.saturating_add(1);
+        self.on_frame(frame, raw, recvf)
+    }
+
+    /// Encodes the frame at the start of the buffer, returning its length.
+    /// Fails if it doesn't fit in the buffer.
+    fn encode_frame<M: Serialize>(buf: &mut [u8], frame: &FrameContentEnvelope<M>) -> Result<usize, ssmarshal::Error> {
+        if frame.content.is_link_setup() {
+            Self::encode_frame_with::<Crc8Smbus, M>(buf, frame)
+        } else {
+            Self::encode_frame_with::<I, M>(buf, frame)
+        }
+    }
+
+    fn encode_frame_with<J: FrameIntegrity, M: Serialize>(
+        buf: &mut [u8],
+        frame: &FrameContentEnvelope<M>,
+    ) -> Result<usize, ssmarshal::Error> {
+        let start = 1 + J::LEN;
+        // Reusing the EOF error of ssmarshal, as the decoding does.
+        let encoded_len = ssmarshal::serialize(buf.get_mut(start..).ok_or(ssmarshal::Error::EndOfStream)?, frame)?;
+        buf[0] = FRAME_PRELUDE_BYTE;
+        let checksum = Self::checksum::<J>(&buf[start..start + encoded_len]);
+        buf[1..start].copy_from_slice(&checksum[0..J::LEN]);
+        Ok(start + encoded_len)
+    }
+
+    /// Encodes a transport frame whose payload is already serialized in
+    /// `data`, appending it raw after the envelope. The message of the
+    /// envelope, if any, is a unit, which takes no bytes, so the data takes its
+    /// place. Fails if the frame doesn't fit in the buffer.
+    fn encode_raw_frame(
+        buf: &mut [u8],
+        envelope: &FrameContentEnvelope<()>,
+        data: &[u8],
+    ) -> Result<usize, ssmarshal::Error> {
+        let start = 1 + I::LEN;
+        let header_len = ssmarshal::serialize(buf.get_mut(start..).ok_or(ssmarshal::Error::EndOfStream)?, envelope)?;
+        let end = start + header_len + data.len();
+        buf.get_mut(start + header_len..end)
+            .ok_or(ssmarshal::Error::EndOfStream)?
+            .copy_from_slice(data);
+        buf[0] = FRAME_PRELUDE_BYTE;
+        let checksum = Self::checksum::<I>(&buf[start..end]);
+        buf[1..start].copy_from_slice(&checksum[0..I::LEN]);
+        Ok(end)
+    }
+
+    fn transfer_frame<M: Serialize + Debug>(
+        bus: &mut B,
+        auth: &A,
+        clock: &CS,
+        last_sent_frame_time: &mut CS::TInstant,
+        stats: &mut LinkStats,
+        observer: Option<&'static dyn FrameObserver>,
+        frame: &FrameContentEnvelope<M>,
+    ) -> Result<(), FrameTxError>
+    where
+        [(); MaxFrameLength::<M>::MAX_FRAME_LENGTH]:,
+    {
+        let mut txbuf = [0u8; { MaxFrameLength::<M>::MAX_FRAME_LENGTH }];
+        let len = Self::encode_frame(&mut txbuf, frame).map_err(FrameTxError::Encode)?;
+        if len + A::TAG_LEN > txbuf.len() {
+            return Err(FrameTxError::Encode(ssmarshal::Error::EndOfStream));
+        }
+        let res = Self::transfer_encoded_frame(bus, auth, clock, last_sent_frame_time, stats, &mut txbuf, len);
+        if matches!(res, Ok(_)) {
+            dev_trace!("--> TX: {:?}", &frame);
+            if let Some(observer) = observer {
+                observer.on_tx(&txbuf[..len + A::TAG_LEN], &Self::frame_info(&frame.content, frame.seq, &txbuf));
+            }
+        }
+
+        res.map_err(FrameTxError::Bus)
+    }
+
+    /// Returns what is known about a frame, from its content and its encoded
+    /// bytes.
+    fn frame_info<M>(content: &FrameContent<M>, seq: u8, encoded: &[u8]) -> FrameInfo {
+        // Preamble, CRC and Seq go before the frame type.
+        let crc_len = if content.is_link_setup() { Crc8Smbus::LEN } else { I::LEN };
+        FrameInfo {
+            seq,
+            frame_type: encoded.get(2 + crc_len).copied().unwrap_or_default(),
+            channel: content.channel(),
+        }
+    }
+
+    /// Sends the frame encoded in the first `len` bytes of the buffer,
+    /// appending its authentication tag right after it.
+    fn transfer_encoded_frame(
+        bus: &mut B,
+        auth: &A,
+        clock: &CS,
+        last_sent_frame_time: &mut CS::TInstant,
+        stats: &mut LinkStats,
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<(), BusTransferError> {
+        let (frame, tag) = buf.split_at_mut(len);
+        auth.tag(frame, &mut tag[..A::TAG_LEN]);
+        let frame = &buf[..len + A::TAG_LEN];
+        let res = bus.transfer(frame);
+        if matches!(res, Ok(_)) {
+            dev_trace!("--> TX: {:x?}", frame);
+            // TODO Should I add the estimated time that a frame will
+            // take to be transferred through the bus to this, so that
+            // I can store the time when I think message should have
+            // been received by the peer? Note: this will make the
+            // current ticks to go beyond the current time, so any
+            // calculation like cycle_count - last_sent_frame_ticks
+            // will give invalid results.
+            *last_sent_frame_time = clock.current_instant();
+            stats.frames_sent = stats.frames_sent.saturating_add(1);
+        }
+
+        res
+    }
+
+    /// Sends the message in the given position of the queue of the channel,
+    /// which must be either in flight or the next one to be sent, and
+    /// restarts its retransmission timer. With coalescing enabled, the
+    /// following messages may be sent in the same frame. Returns whether it
+    /// was sent.
+    fn transfer_user_msg(&mut self, channel: Channel, index: usize) -> bool {
+        let ch = &mut self.channels[channel as usize];
+        // RingBuffer::get wraps the index around, so it needs to be checked.
+        if index >= ch.tx_queue.len() {
+            return false;
+        }
+        let Some(next_msg) = ch.tx_queue.get(index) else {
+            return false;
+        };
+        let seq = ch.tx_seq.wrapping_add(index as u8);
+
+        // The head of the queue is the only message that may be fragmented or
+        // re-sent several times, so it is the only one whose serialization is
+        // kept.
+        let mut msgbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+        let msg: &[u8] = if index == 0 {
+            if ch.tx_head_msg.is_empty() {
+                // Cannot fail, every message is serialized once when queued.
+                let Ok(len) = Self::serialize_transport_msg(&mut self.stats, next_msg, &mut msgbuf) else {
+                    return false;
+                };
+                // Cannot fail, both have the same capacity.
+                let _ = ch.tx_head_msg.extend_from_slice(&msgbuf[..len]);
+            }
+            &ch.tx_head_msg
+        } else {
+            let Ok(len) = Self::serialize_transport_msg(&mut self.stats, next_msg, &mut msgbuf) else {
+                return false;
+            };
+            &msgbuf[..len]
+        };
+
+        let mut txbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+        let mut batch_len = 1;
+        let count = Self::fragment_count(self.max_frame_len, msg.len());
+        let len = if count > 0 {
+            // Fragmented messages are sent alone, so that the
+            // fragment being sent always refers to the head of the
+            // queue.
+            if index != 0 {
+                return false;
+            }
+
+            let data_len = Self::fragment_data_len(self.max_frame_len);
+            let start = ch.tx_fragment_index as usize * data_len;
+            let data = &msg[start..msg.len().min(start + data_len)];
+
+            // Already checked when the message was queued that the count
+            // fits in an u8.
+            ch.tx_fragment_count = count as u8;
+            let content = FrameContent::TransportFragment { channel, index: ch.tx_fragment_index, count: ch.tx_fragment_count };
+            Self::encode_raw_frame(&mut txbuf, &FrameContentEnvelope::new(seq, content), data)
+        } else if self.coalesce && msg.len() <= u8::MAX as usize {
+            // The messages that follow are packed in the same frame while
+            // they fit, and while they're within the send window.
+            let max_len = self.max_frame_len.min(MaxFrameLength::<Msg>::MAX_FRAME_LENGTH);
+            let mut batch = Vec::<u8, { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }>::new();
+            let _ = batch.push(msg.len() as u8);
+            let _ = batch.extend_from_slice(msg);
+            while index + batch_len < TX_WINDOW.min(ch.tx_queue.len()) {
+                let Some(next_msg) = ch.tx_queue.get(index + batch_len) else {
+                    break;
+                };
+                let mut nextbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+                let Ok(next_len) = Self::serialize_transport_msg(&mut self.stats, next_msg, &mut nextbuf) else {
+                    break;
+                };
+                if next_len > u8::MAX as usize
+                    || Self::TRANSPORT_BATCH_HEADER_LEN + batch.len() + 1 + next_len > max_len
+                {
+                    break;
+                }
+                let _ = batch.push(next_len as u8);
+                let _ = batch.extend_from_slice(&nextbuf[..next_len]);
+                batch_len += 1;
+            }
+
+            if batch_len > 1 {
+                let content = FrameContent::TransportBatch { channel, count: batch_len as u8 };
+                Self::encode_raw_frame(&mut txbuf, &FrameContentEnvelope::new(seq, content), &batch)
+            } else {
+                let content = FrameContent::TransportMessage { channel, msg: () };
+                Self::encode_raw_frame(&mut txbuf, &FrameContentEnvelope::new(seq, content), msg)
+            }
+        } else {
+            let content = FrameContent::TransportMessage { channel, msg: () };
+            Self::encode_raw_frame(&mut txbuf, &FrameContentEnvelope::new(seq, content), msg)
+        };
+        // Cannot fail, the messages that don't fit in a frame are fragmented.
+        let Ok(len) = len else {
+            dev_error!("Couldn't encode user message of channel {:?}", channel);
+            return false;
+        };
+        let res = Self::transfer_encoded_frame(&mut self.bus, &self.auth, &self.clock, &mut self.last_sent_frame_time, &mut self.stats, &mut txbuf, len);
+
+        if res.is_err() {
+            return false;
+        }
+        if let Some(observer) = self.frame_observer {
+            let info = FrameInfo { seq, frame_type: txbuf[2 + I::LEN], channel: Some(channel) };
+            observer.on_tx(&txbuf[..len + A::TAG_LEN], &info);
+        }
+
+        self.stats.coalesced_msgs = self.stats.coalesced_msgs.saturating_add(batch_len as u32 - 1);
+        let now = self.clock.current_instant();
+        for i in index..index + batch_len {
+            if i < ch.tx_in_flight.len() {
+                if let Some(msg) = ch.tx_in_flight.get_mut(i) {
+                    msg.sent_time = now;
+                    msg.retransmissions = msg.retransmissions.saturating_add(1);
+                }
+            } else {
+                ch.tx_in_flight.push(InFlightMsg {
+                    sent_time: now,
+                    retransmissions: 0,
+                });
+            }
+        }
+        true
+    }
+
+    fn do_tx(&mut self) {
+        if !self.bus.is_tx_busy() {
+            self.transfer_control_frame();
+        }
+
+        if self.switch_baud_rate() {
+            return;
+        }
+
+        // A new user message can only be transferred from here when:
+        //  - The link is up.
+        //  - The bus is not busy
+        //  - No other priority control message is scheduled for transfer.
+        //  - The send window of its channel is not full. Replaying the
+        //    messages in flight is part of the job of on_tick. Fragmented
+        //    messages are sent alone, so the window is just one message
+        //    while sending them.
+        // The priority channel is checked first, so its messages are sent
+        // before any queued normal message.
+        // Datagrams go right after the priority channel.
+        if self.link_status == LinkStatus::Up
+            && !self.bus.is_tx_busy()
+            && !self.has_pending_control()
+        {
+            for channel in Channel::ALL {
+                if channel == Channel::Normal && self.transfer_datagram() {
+                    break;
+                }
+
+                let ch = &self.channels[channel as usize];
+                let window = if ch.tx_fragment_count > 0 { 1 } else { TX_WINDOW };
+                let in_flight = ch.tx_in_flight.len();
+                if in_flight < window && self.transfer_user_msg(channel, in_flight) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Sends the datagram in the head of the queue, if any. Returns whether it
+    /// was sent.
+    fn transfer_datagram(&mut self) -> bool {
+        let Some(msg) = self.datagram_tx_queue.peek() else {
+            return false;
+        };
+
+        let res = Self::transfer_frame(
+            &mut self.bus,
+            &self.auth,
+            &self.clock,
+            &mut self.last_sent_frame_time,
+            &mut self.stats,
+            self.frame_observer,
+            &FrameContentEnvelope::new(0, FrameContent::Datagram { msg: msg.clone() }),
+        );
+
+        match res {
+            Ok(()) => {
+                self.datagram_tx_queue.dequeue();
+                true
+            }
+            // Already checked when it was queued, but it would block the
+            // queue forever otherwise.
+            Err(FrameTxError::Encode(e)) => {
+                dev_error!("Dropping datagram that cannot be encoded: {:?}", e);
+                self.datagram_tx_queue.dequeue();
+                false
+            }
+            Err(FrameTxError::Bus(_)) => false,
+        }
+    }
+
+    /// Feeds the watchdog, if any, unless the bus has been busy for longer
+    /// than [`SplitLinkTimings::TX_STALL_TIME`] without taking any new frame.
+    fn feed_watchdog(&mut self) {
+        let Some(watchdog) = self.watchdog else {
+            return;
+        };
+
+        let frames_sent = self.stats.frames_sent;
+        let progressed = !self.bus.is_tx_busy() || frames_sent != self.watchdog_frames_sent;
+        self.watchdog_frames_sent = frames_sent;
+        if progressed {
+            self.tx_busy_since = None;
+            self.watchdog_starved = false;
+            watchdog.feed();
+            return;
+        }
+
+        let busy_since = *self.tx_busy_since.get_or_insert_with(|| self.clock.current_instant());
+        if self.clock.elapsed_since(busy_since) < Ts::TX_STALL_TIME {
+            watchdog.feed();
+        } else if !self.watchdog_starved {
+            self.watchdog_starved = true;
+            dev_error!("The split bus has stalled, leaving the watchdog unfed");
+        }
+    }
+
+    /// Runs the timers of the current link state.
+    fn on_tick(&mut self) {
+        self.update_uptime();
+
+        // Any frame tells the peer that we are alive, so probes are only sent
+        // when there's nothing else to send. Otherwise, a probe would be
+        // queued on each poll while the bus is busy.
+        if self.clock.elapsed_since(self.last_sent_frame_time) >= self.probe_interval()
+            && !self.has_pending_tx()
+        {
+            self.pending_link_frame = Some(LinkFrame::Probe);
+        }
+
+        match self.link_status {
+            LinkStatus::Sync
+                if self.clock.elapsed_since(self.last_link_status_change_time) >= Ts::MAX_SYNC_ACK_WAIT_TIME =>
+            {
+                dev_warn!("Couldn't receive a SyncACK frame in time. Giving up link synchronization");
+                self.set_link_down(LinkDownReason::SyncTimeout);
+            }
+            LinkStatus::Up if self.clock.elapsed_since(self.last_recv_frame_time) >= self.max_idle_time() => {
+                dev_warn!("Link has been idle for so long. Considering it down");
+                self.set_link_down(LinkDownReason::IdleTimeout);
+            }
+            LinkStatus::Up if self.host_presence != self.advertised_host_presence =>
+            {
+                dev_info!("Host presence changed to {:?}. Electing the master side again", self.host_presence);
+                self.resync();
+            }
+            LinkStatus::Up
+                if !self.control_tx_queue.is_full()
+                    && self.peer_bootloader_request_time.is_some_and(|sent| self.clock.elapsed_since(sent) > Ts::MSG_REPLAY_DELAY_TIME) =>
+            {
+                dev_debug!("Re-sent bootloader request for which no ACK has been received");
+                self.peer_bootloader_request_time = Some(self.clock.current_instant());
+                self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::BootloaderRequest));
+            }
+            LinkStatus::Up if self.is_power_state_due() => {
+                self.power_state_sent_time = Some(self.clock.current_instant());
+                let suspended = self.local_suspended as u8;
+                self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::PowerState { suspended }));
+            }
+            LinkStatus::Up if self.is_baud_proposal_due() => self.on_baud_proposal_due(),
+            LinkStatus::Up if self.is_time_request_due() => {
+                let t0 = self.uptime_us().to_le_bytes();
+                self.last_time_request_time = Some(self.clock.current_instant());
+                self.push_control_frame(FrameContentEnvelope::new(0, FrameContent::TimeRequest { t0 }));
+            }
+            LinkStatus::Up if !self.bus.is_tx_busy() => {
+                // Each message in flight has its own timer. Only
+                // the oldest expired one is re-sent on each poll,
+                // since the bus will be busy after that, looking at
+                // the priority channel first.
+                let expired = Channel::ALL.into_iter().find_map(|channel| {
+                    self.channels[channel as usize]
+                        .tx_in_flight
+                        .iter()
+                        .position(|msg| self.clock.elapsed_since(msg.sent_time) > Ts::MSG_REPLAY_DELAY_TIME)
+                        .map(|index| (channel, index))
+                });
+                if let Some((channel, index)) = expired {
+                    let retransmissions = self.channels[channel as usize]
+                        .tx_in_flight
+                        .get(index)
+                        .map_or(0, |msg| msg.retransmissions);
+                    if Ts::MAX_RETRANSMISSIONS > 0 && retransmissions >= Ts::MAX_RETRANSMISSIONS {
+                        self.drop_head_msg(channel);
+                    } else {
+                        dev_debug!("Re-sent user message for which no ACK has been received");
+                        self.stats.retransmissions = self.stats.retransmissions.saturating_add(1);
+                        self.transfer_user_msg(channel, index);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops the message in the head of the channel, which is the oldest one
+    /// in flight, after re-sending it too many times. The messages in flight
+    /// behind it are sent again, with the seq numbers that follow.
+    fn drop_head_msg(&mut self, channel: Channel) {
+        let ch = &mut self.channels[channel as usize];
+        let Some(msg) = ch.tx_queue.dequeue() else {
+            return;
+        };
+        ch.tx_seq = ch.tx_seq.wrapping_add(1);
+        ch.tx_in_flight.clear();
+        ch.tx_head_msg.clear();
+        ch.tx_fragment_index = 0;
+        ch.tx_fragment_count = 0;
+
+        dev_warn!(
+            "Dropped user message of channel {:?} after {} retransmissions: {:?}",
+            channel,
+            Ts::MAX_RETRANSMISSIONS,
+            msg
+        );
+        self.stats.dropped_msgs = self.stats.dropped_msgs.saturating_add(1);
+        if let Some(hook) = self.on_message_dropped {
+            hook(channel, &msg);
+        }
+
+        if self.resync_on_drop {
+            self.resync();
+        }
+    }
+
+    /// Syncs the link again while it is up, so that both peers start over
+    /// the seq numbers, without dropping any queued message.
+    fn resync(&mut self) {
+        dev_info!("Syncing the link again");
+        self.reset_sequence_numbers();
+        for channel in &mut self.channels {
+            channel.tx_in_flight.clear();
+        }
+        self.push_sync();
+        self.emit_event(LinkEvent::Resync);
+    }
+
+    /// Returns whether a time request has to be sent to the peer. Like the
+    /// probes, they wait for the control queue to be empty.
+    fn is_time_request_due(&self) -> bool {
+        let Some(interval) = self.time_sync_interval else {
+            return false;
+        };
+
+        !self.has_pending_control()
+            && self.last_time_request_time.is_none_or(|last| self.clock.elapsed_since(last) >= interval)
+    }
+
+    /// Returns whether there's any frame waiting to be sent.
+    fn has_pending_tx(&self) -> bool {
+        self.has_pending_control()
+            || !self.datagram_tx_queue.is_empty()
+            || self.channels.iter().any(|ch| !ch.tx_queue.is_empty())
+    }
+
+    /// Returns the number of user messages queued in both channels, including
+    /// the ones in flight, and the datagrams waiting to be sent.
+    pub fn user_tx_queue_len(&self) -> usize {
+        self.channels.iter().map(|ch| ch.tx_queue.len()).sum::<usize>() + self.datagram_tx_queue.len()
+    }
+
+    fn enqueue_datagram(&mut self, message: Msg) -> Result<(), TransferError> {
+        if self.link_status != LinkStatus::Up {
+            return Err(TransferError::LinkDown);
+        }
+
+        // Datagrams are never fragmented, since fragments need sequence
+        // numbers for being reassembled. The frame buffers may be shorter than
+        // the serialized message even without a limit set.
+        let mut msgbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+        let msg_len = Self::serialize_msg(&message, &mut msgbuf).map_err(TransferError::EncodeError)?;
+        if msg_len + Self::DATAGRAM_HEADER_LEN > self.max_frame_len.min(MaxFrameLength::<Msg>::MAX_FRAME_LENGTH) {
+            return Err(TransferError::DatagramTooLarge);
+        }
+
+        if self.datagram_tx_queue.is_full() {
+            self.stats.user_tx_queue_full = self.stats.user_tx_queue_full.saturating_add(1);
+            Err(TransferError::BufferOverflow)
+        } else {
+            self.datagram_tx_queue.push(message);
+            self.update_user_tx_queue_high_watermark(self.datagram_tx_queue.len());
+            Ok(())
+        }
+    }
+
+    fn enqueue_user_msg(&mut self, channel: Channel, message: Msg) -> Result<(), TransferError> {
+        if self.link_status != LinkStatus::Up {
+            return Err(TransferError::LinkDown);
+        }
+
+        let ch = &mut self.channels[channel as usize];
+        if ch.tx_queue.is_full() {
+            self.stats.user_tx_queue_full = self.stats.user_tx_queue_full.saturating_add(1);
+            let made_room = match ch.overflow_policy {
+                TxOverflowPolicy::Reject => false,
+                TxOverflowPolicy::DropOldest => ch.drop_oldest_unsent(),
+                TxOverflowPolicy::Coalesce(merge) => {
+                    return if ch.coalesce_last(merge, &message) {
+                        Ok(())
+                    } else {
+                        Err(TransferError::BufferOverflow)
+                    };
+                }
+            };
+            if !made_room {
+                return Err(TransferError::BufferOverflow);
+            }
+        }
+
+        // Every message is serialized here, so that the ones that cannot be
+        // are refused instead of blocking the channel. A message queued on an
+        // empty channel is the next one to be sent, so its serialization is
+        // kept, and the next poll only needs to frame it.
+        let pre_serialize = ch.tx_queue.is_empty();
+        let mut msgbuf = [0u8; { MaxFrameLength::<Msg>::MAX_FRAME_LENGTH }];
+        let msg_len = if pre_serialize {
+            Self::serialize_transport_msg(&mut self.stats, &message, &mut msgbuf)
+        } else {
+            Self::serialize_msg(&message, &mut msgbuf)
+        }
+        .map_err(TransferError::EncodeError)?;
+        if Self::fragment_count(self.max_frame_len, msg_len) > u8::MAX as usize {
+            return Err(TransferError::MessageTooLarge);
+        }
+        if pre_serialize {
+            ch.tx_head_msg.clear();
+            let _ = ch.tx_head_msg.extend_from_slice(&msgbuf[..msg_len]);
+        }
+
+        ch.tx_queue.push(message);
+        let len = ch.tx_queue.len();
+        self.update_user_tx_queue_high_watermark(len);
+        Ok(())
+    }
+
+    fn update_user_tx_queue_high_watermark(&mut self, len: usize) {
+        self.stats.user_tx_queue_high_watermark = self.stats.user_tx_queue_high_watermark.max(len as u32);
+    }
+}
+
+impl<
+    Msg: Clone + Debug + DeserializeOwned + Serialize,
+    Ts: SplitLinkTimings,
+    B: BusWrite + BusRead,
+    CS: Clock,
+    const TX_QUEUE_LEN: usize,
+    const TX_WINDOW: usize,
+    I: FrameIntegrity,
+    A: FrameAuth,
+    L: AttentionLine,
+    const CONTROL_QUEUE_LEN: usize,
+> SplitBusLike<Msg> for SplitBus<Msg, Ts, B, CS, TX_QUEUE_LEN, TX_WINDOW, I, A, L, CONTROL_QUEUE_LEN>
+where
+    [(); MaxFrameLength::<Msg>::MAX_FRAME_LENGTH]:,
+    [(); MaxFrameLength::<NoMsg>::MAX_FRAME_LENGTH]:,
+{
+    fn poll<F: FnMut(&Msg) -> bool>(&mut self, recvf: F) {
+        // The bus is drained once more after the peer releases the line, for
+        // the frames that finished arriving after the last poll.
+        let peer_attention = self.attention.take_peer_attention();
+        if peer_attention || self.peer_attention {
+            self.do_rx(recvf);
+        }
+        self.peer_attention = peer_attention;
+
+        self.on_tick();
+        self.do_tx();
+        self.attention.set_pending(self.has_pending_tx() || self.bus.is_tx_busy());
+        self.feed_watchdog();
+    }
+
+    fn poll_dyn(&mut self, recvf: &mut dyn FnMut(&Msg) -> bool) {
+        self.poll(recvf)
+    }
+
+    fn transfer(&mut self, message: Msg) -> Result<(), TransferError> {
+        self.enqueue_user_msg(Channel::Normal, message)
+    }
+
+    fn transfer_priority(&mut self, message: Msg) -> Result<(), TransferError> {
+        self.enqueue_user_msg(Channel::Priority, message)
+    }
+
+    fn transfer_unreliable(&mut self, message: Msg) -> Result<(), TransferError> {
+        self.enqueue_datagram(message)
+    }
+
+    fn peer_bye_reason(&self) -> Option<ByeReason> {
+        self.peer_bye_reason
+    }
+
+    fn tx_queue_free(&self, channel: Channel) -> usize {
+        let queue = &self.channels[channel as usize].tx_queue;
+        queue.capacity() - queue.len()
+    }
+
+    fn is_tx_congested(&self) -> bool {
+        Channel::ALL.into_iter().any(|channel| self.tx_queue_free(channel) <= self.tx_low_watermark)
+    }
+
+    fn poll_event(&mut self) -> Option<LinkEvent> {
+        self.events.dequeue()
+    }
+
+    fn peer_time_offset(&self) -> Option<i64> {
+        SplitBus::peer_time_offset(self)
+    }
+
+    fn peer_time_to_local(&self, peer_us: u64) -> Option<u64> {
+        SplitBus::peer_time_to_local(self, peer_us)
+    }
+
+    fn set_host_presence(&mut self, presence: HostPresence) {
+        SplitBus::set_host_presence(self, presence)
+    }
+
+    fn negotiated_role(&self) -> Option<PeerRole> {
+        SplitBus::negotiated_role(self)
+    }
+
+    fn set_local_suspended(&mut self, suspended: bool) {
+        SplitBus::set_local_suspended(self, suspended)
+    }
+
+    fn is_peer_suspended(&self) -> bool {
+        SplitBus::is_peer_suspended(self)
+    }
+
+    fn request_peer_wakeup(&mut self) -> Result<(), TransferError> {
+        SplitBus::request_peer_wakeup(self)
+    }
+}
+
+/// A split bus that is never connected to a peer, for keyboards that are not
+/// split. It doesn't hold any link state: nothing is ever received, and every
+/// transfer fails as if the link was down.
+pub struct NullSplitBus;
+
+impl<Msg: Clone + Debug> SplitBusLike<Msg> for NullSplitBus {
+    fn poll<F: FnMut(&Msg) -> bool>(&mut self, _recvf: F) {}
+
+    fn poll_dyn(&mut self, _recvf: &mut dyn FnMut(&Msg) -> bool) {}
+
+    fn transfer(&mut self, _message: Msg) -> Result<(), TransferError> {
+        Err(TransferError::LinkDown)
+    }
+}
+
+/// Lets a `&mut dyn SplitBusLike` be used where a [`SplitBusLike`] is
+/// expected, e.g as the bus of a keyboard.
+impl<Msg: Clone + Debug> SplitBusLike<Msg> for &mut (dyn SplitBusLike<Msg> + '_) {
+    fn poll<F: FnMut(&Msg) -> bool>(&mut self, mut recvf: F) {
+        (**self).poll_dyn(&mut recvf)
+    }
+
+    fn poll_dyn(&mut self, recvf: &mut dyn FnMut(&Msg) -> bool) {
+        (**self).poll_dyn(recvf)
+    }
+
+    fn transfer(&mut self, message: Msg) -> Result<(), TransferError> {
+        (**self).transfer(message)
+    }
+
+    fn transfer_priority(&mut self, message: Msg) -> Result<(), TransferError> {
+        (**self).transfer_priority(message)
+    }
+
+    fn transfer_unreliable(&mut self, message: Msg) -> Result<(), TransferError> {
+        (**self).transfer_unreliable(message)
+    }
+
+    fn peer_bye_reason(&self) -> Option<ByeReason> {
+        (**self).peer_bye_reason()
+    }
+
+    fn tx_queue_free(&self, channel: Channel) -> usize {
+        (**self).tx_queue_free(channel)
+    }
+
+    fn is_tx_congested(&self) -> bool {
+        (**self).is_tx_congested()
+    }
+
+    fn poll_event(&mut self) -> Option<LinkEvent> {
+        (**self).poll_event()
+    }
+
+    fn peer_time_offset(&self) -> Option<i64> {
+        (**self).peer_time_offset()
+    }
+
+    fn peer_time_to_local(&self, peer_us: u64) -> Option<u64> {
+        (**self).peer_time_to_local(peer_us)
+    }
+
+    fn set_host_presence(&mut self, presence: HostPresence) {
+        (**self).set_host_presence(presence)
+    }
+
+    fn negotiated_role(&self) -> Option<PeerRole> {
+        (**self).negotiated_role()
+    }
+
+    fn set_local_suspended(&mut self, suspended: bool) {
+        (**self).set_local_suspended(suspended)
+    }
+
+    fn is_peer_suspended(&self) -> bool {
+        (**self).is_peer_suspended()
+    }
+
+    fn request_peer_wakeup(&mut self) -> Result<(), TransferError> {
+        (**self).request_peer_wakeup()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dxkb_common::bus::NullBus;
+    use dxkb_common::testing::MockClock;
+
+    use super::*;
+
+    type TestBus<I = Crc8Smbus> = SplitBus<u32, DefaultSplitLinkTimings, NullBus, MockClock, 1, 1, I>;
+
+    const T0: [u8; 8] = 1_000_000u64.to_le_bytes();
+    const T1: [u8; 8] = 2_500_000u64.to_le_bytes();
+    const BAUD: [u8; 4] = 115_200u32.to_le_bytes();
+
+    /// Raw data appended after the envelope of the fragment and batch frames.
+    const FRAGMENT_DATA: &[u8] = &[0xaa, 0xbb];
+    const BATCH_DATA: &[u8] = &[0x04, 0x78, 0x56, 0x34, 0x12, 0x04, 0x01, 0x00, 0x00, 0x00];
+
+    fn device_id(first: u8) -> [u8; 16] {
+        core::array::from_fn(|i| first + i as u8)
+    }
+
+    /// One frame of each type, with the bytes it must be encoded into with the
+    /// CRC-8. Any change on them breaks the compatibility with the firmware
+    /// already flashed into the other half, so [`LINK_PROTOCOL_VERSION`] must
+    /// be increased along with them.
+    fn golden_frames() -> [(FrameContentEnvelope<u32>, &'static [u8], &'static [u8]); 17] {
+        [
+            (
+                FrameContentEnvelope::new(0, FrameContent::LinkProbe { device_id: device_id(1) }),
+                &[],
+                &[
+                    0x99, 0xb0, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+                    0x0c, 0x0d, 0x0e, 0x0f, 0x10,
+                ],
+            ),
+            (
+                FrameContentEnvelope::new(5, FrameContent::Ack { channel: Channel::Priority }),
+                &[],
+                &[0x99, 0xd2, 0x05, 0x01, 0x01],
+            ),
+            (
+                FrameContentEnvelope::new(
+                    0,
+                    FrameContent::SyncAck { version: 8, integrity: 1, reset_reason: 5, host: 1, device_id: device_id(0xb0) },
+                ),
+                &[],
+                &[
+                    0x99, 0x81, 0x00, 0x02, 0x08, 0x01, 0x05, 0x01, 0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+                    0xb7, 0xb8, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf,
+                ],
+            ),
+            (
+                FrameContentEnvelope::new(
+                    0,
+                    FrameContent::Sync { version: 8, integrity: 0, device_id: device_id(0xa0), reset_reason: 4, host: 2 },
+                ),
+                &[],
+                &[
+                    0x99, 0x1c, 0x00, 0x03, 0x08, 0x00, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8,
+                    0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae, 0xaf, 0x04, 0x02,
+                ],
+            ),
+            (
+                FrameContentEnvelope::new(
+                    7,
+                    FrameContent::TransportMessage { channel: Channel::Normal, msg: 0x12345678 },
+                ),
+                &[],
+                &[0x99, 0xbf, 0x07, 0x04, 0x00, 0x78, 0x56, 0x34, 0x12],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::Bye { reason: ByeReason::Bootloader }),
+                &[],
+                &[0x99, 0x4f, 0x00, 0x05, 0x02],
+            ),
+            (
+                FrameContentEnvelope::new(
+                    3,
+                    FrameContent::TransportFragment { channel: Channel::Priority, index: 1, count: 2 },
+                ),
+                FRAGMENT_DATA,
+                &[0x99, 0x80, 0x03, 0x06, 0x01, 0x01, 0x02, 0xaa, 0xbb],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::Datagram { msg: 0xdeadbeef }),
+                &[],
+                &[0x99, 0xbc, 0x00, 0x07, 0xef, 0xbe, 0xad, 0xde],
+            ),
+            (
+                FrameContentEnvelope::new(9, FrameContent::TransportBatch { channel: Channel::Normal, count: 2 }),
+                BATCH_DATA,
+                &[
+                    0x99, 0x7d, 0x09, 0x08, 0x00, 0x02, 0x04, 0x78, 0x56, 0x34, 0x12, 0x04, 0x01, 0x00, 0x00,
+                    0x00,
+                ],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::TimeRequest { t0: T0 }),
+                &[],
+                &[0x99, 0x08, 0x00, 0x09, 0x40, 0x42, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::TimeResponse { t0: T0, t1: T1 }),
+                &[],
+                &[
+                    0x99, 0x9d, 0x00, 0x0a, 0x40, 0x42, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x25, 0x26,
+                    0x00, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::BootloaderRequest),
+                &[],
+                &[0x99, 0x31, 0x00, 0x0b],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::BootloaderAck),
+                &[],
+                &[0x99, 0x24, 0x00, 0x0c],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::BaudChange { baud: BAUD }),
+                &[],
+                &[0x99, 0xba, 0x00, 0x0d, 0x00, 0xc2, 0x01, 0x00],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::BaudChangeAck { baud: BAUD }),
+                &[],
+                &[0x99, 0x1c, 0x00, 0x0e, 0x00, 0xc2, 0x01, 0x00],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::Ping { id: 5 }),
+                &[],
+                &[0x99, 0xd8, 0x00, 0x0f, 0x05],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::Pong { id: 5 }),
+                &[],
+                &[0x99, 0x4c, 0x00, 0x10, 0x05],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::PowerState { suspended: 1 }),
+                &[],
+                &[0x99, 0x45, 0x00, 0x11, 0x01],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::PowerStateAck { suspended: 1 }),
+                &[],
+                &[0x99, 0x7a, 0x00, 0x12, 0x01],
+            ),
+            (
+                FrameContentEnvelope::new(0, FrameContent::WakeRequest),
+                &[],
+                &[0x99, 0x79, 0x00, 0x13],
+            ),
+        ]
+    }
+
+    fn encode<I: FrameIntegrity>(buf: &mut [u8], envelope: &FrameContentEnvelope<u32>, data: &[u8]) -> usize {
+        let len = match envelope.content {
+            FrameContent::TransportFragment { channel, index, count } => TestBus::<I>::encode_raw_frame(
+                buf,
+                &FrameContentEnvelope::new(envelope.seq, FrameContent::TransportFragment { channel, index, count }),
+                data,
+            ),
+            FrameContent::TransportBatch { channel, count } => TestBus::<I>::encode_raw_frame(
+                buf,
+                &FrameContentEnvelope::new(envelope.seq, FrameContent::TransportBatch { channel, count }),
+                data,
+            ),
+            _ => TestBus::<I>::encode_frame(buf, envelope),
+        };
+        len.unwrap()
+    }
+
+    #[test]
+    fn test_golden_frames_encode() {
+        for (envelope, data, expected) in golden_frames() {
+            let mut buf = [0u8; 64];
+            let len = encode::<Crc8Smbus>(&mut buf, &envelope, data);
+            assert_eq!(&buf[..len], expected, "{:?}", envelope);
+        }
+    }
+
+    #[test]
+    fn test_golden_frames_decode() {
+        for (envelope, data, expected) in golden_frames() {
+            let (frame, leftover) = TestBus::decode_frame(expected).unwrap();
+            assert_eq!(frame.envelope.seq, envelope.seq);
+            assert_eq!(leftover, data);
+
+            // The content has no PartialEq, since the messages may not have
+            // one, so it is compared through its encoding.
+            let mut buf = [0u8; 64];
+            let len = encode::<Crc8Smbus>(&mut buf, &frame.envelope, leftover);
+            assert_eq!(&buf[..len], expected, "{:?}", envelope);
+        }
+    }
+
+    #[test]
+    fn test_golden_frames_crc16() {
+        let mut buf = [0u8; 64];
+        let ack = FrameContentEnvelope::new(5, FrameContent::Ack { channel: Channel::Priority });
+        let len = encode::<Crc16Ccitt>(&mut buf, &ack, &[]);
+        assert_eq!(&buf[..len], &[0x99, 0x04, 0x7c, 0x05, 0x01, 0x01]);
+
+        let msg = FrameContentEnvelope::new(7, FrameContent::TransportMessage { channel: Channel::Normal, msg: 0x12345678 });
+        let len = encode::<Crc16Ccitt>(&mut buf, &msg, &[]);
+        assert_eq!(&buf[..len], &[0x99, 0x3e, 0xd1, 0x07, 0x04, 0x00, 0x78, 0x56, 0x34, 0x12]);
+        assert!(TestBus::<Crc16Ccitt>::decode_frame(&buf[..len]).is_ok());
+
+        // The frames that set up the link keep the CRC-8.
+        let (setup, _, expected) = &golden_frames()[2];
+        let len = encode::<Crc16Ccitt>(&mut buf, setup, &[]);
+        assert_eq!(&buf[..len], *expected);
+        assert!(TestBus::<Crc16Ccitt>::decode_frame(expected).is_ok());
+    }
+
+    #[test]
+    fn test_coalesced_frames_decode() {
+        let mut buf = [0u8; 64];
+        for (first, _, _) in golden_frames().iter().filter(|(envelope, _, _)| !envelope.content.has_raw_data()) {
+            let len = encode::<Crc16Ccitt>(&mut buf, first, &[]);
+            let ack = FrameContentEnvelope::new(5, FrameContent::Ack { channel: Channel::Priority });
+            let next_len = encode::<Crc16Ccitt>(&mut buf[len..], &ack, &[]);
+
+            // The frames that set up the link keep the CRC-8 even when
+            // followed by other frames.
+            let (_, leftover) = TestBus::<Crc16Ccitt>::decode_frame(&buf[..len + next_len]).unwrap();
+            assert_eq!(leftover, &buf[len..len + next_len], "{:?}", first);
+            let (frame, leftover) = TestBus::<Crc16Ccitt>::decode_frame(leftover).unwrap();
+            assert_eq!(frame.envelope.seq, 5);
+            assert!(leftover.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_coalesced_authentic_frames_decode() {
+        type AuthBus = SplitBus<u32, DefaultSplitLinkTimings, NullBus, MockClock, 1, 1, Crc8Smbus, auth::SipHashAuth>;
+        let auth = auth::SipHashAuth::new([7; 16]);
+        let mut chunk = [0u8; 64];
+        let mut chunk_len = 0;
+        for seq in [3, 4] {
+            let msg = FrameContentEnvelope::new(seq, FrameContent::TransportMessage { channel: Channel::Normal, msg: 42 });
+            let len = encode::<Crc8Smbus>(&mut chunk[chunk_len..], &msg, &[]);
+            let (frame, tag) = chunk[chunk_len..].split_at_mut(len);
+            auth.tag(frame, &mut tag[..auth::SipHashAuth::TAG_LEN]);
+            chunk_len += len + auth::SipHashAuth::TAG_LEN;
+        }
+
+        let Ok((frame, raw, len)) = AuthBus::decode_authentic_frame(&auth, &chunk[..chunk_len]) else {
+            panic!("First frame not decoded");
+        };
+        assert_eq!(frame.envelope.seq, 3);
+        assert!(raw.is_empty());
+        assert_eq!(len, chunk_len / 2);
+        let Ok((frame, _, len)) = AuthBus::decode_authentic_frame(&auth, &chunk[chunk_len / 2..chunk_len]) else {
+            panic!("Second frame not decoded");
+        };
+        assert_eq!(frame.envelope.seq, 4);
+        assert_eq!(len, chunk_len / 2);
+    }
+
+    #[test]
+    fn test_malformed_frames_are_rejected() {
+        for (_, _, expected) in golden_frames() {
+            for len in 0..expected.len() {
+                let _ = TestBus::decode_frame(&expected[..len]);
+                let _ = TestBus::<Crc16Ccitt>::decode_frame(&expected[..len]);
+            }
+
+            let mut buf = [0u8; 64];
+            buf[..expected.len()].copy_from_slice(expected);
+            for bit in 0..expected.len() * 8 {
+                buf[bit / 8] ^= 1 << (bit % 8);
+                let res = TestBus::decode_frame(&buf[..expected.len()]);
+                // A flip of the frame type may turn it into a shorter frame,
+                // whose checksum doesn't cover the trailing bytes, so it could
+                // match by chance. Any other flip is always detected.
+                if bit / 8 != 3 {
+                    assert!(res.is_err(), "{:x?}", &buf[..expected.len()]);
+                }
+                let _ = TestBus::<Crc16Ccitt>::decode_frame(&buf[..expected.len()]);
+                buf[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+
+        assert!(matches!(TestBus::decode_frame(&[0x98, 0x31, 0x00, 0x0b]), Err(FrameDecodeError::PreludeError)));
+        assert!(matches!(TestBus::decode_frame(&[0x99, 0x00, 0x00, 0xff]), Err(FrameDecodeError::SerdeError(_))));
+    }
+    #[test]
+    fn test_frames_larger_than_buffer_are_not_encoded() {
+        for (envelope, data, expected) in golden_frames() {
+            for len in 0..expected.len() {
+                let mut buf = [0u8; 64];
+                let res = match envelope.content {
+                    FrameContent::TransportFragment { channel, index, count } => TestBus::encode_raw_frame(
+                        &mut buf[..len],
+                        &FrameContentEnvelope::new(envelope.seq, FrameContent::TransportFragment { channel, index, count }),
+                        data,
+                    ),
+                    FrameContent::TransportBatch { channel, count } => TestBus::encode_raw_frame(
+                        &mut buf[..len],
+                        &FrameContentEnvelope::new(envelope.seq, FrameContent::TransportBatch { channel, count }),
+                        data,
+                    ),
+                    _ => TestBus::encode_frame(&mut buf[..len], &envelope),
+                };
+                assert!(res.is_err(), "{:?} encoded in {} bytes", envelope, len);
+            }
         }
     }
 }
@@ -0,0 +1,19 @@
+//! Ids of the structured log events of the link, logged with
+//! [`dxkb_common::dev_event!`] from the paths that run for every frame.
+
+pub const FRAME_CRC_MISMATCH: u16 = 0x0101;
+/// Args: the number of bytes left.
+pub const FRAME_LEFTOVER_BYTES: u16 = 0x0102;
+/// Args: the seq number of the ACK.
+pub const DUPLICATE_ACK: u16 = 0x0103;
+/// Args: the number of messages ACK'ed beyond the ones in flight.
+pub const UNEXPECTED_ACK: u16 = 0x0104;
+/// Args: the number of unexpected seq numbers.
+pub const TOO_MANY_SEQ_ERRORS: u16 = 0x0105;
+pub const SHORT_TRANSPORT_BATCH: u16 = 0x0106;
+pub const INVALID_BATCHED_MSG: u16 = 0x0107;
+/// Args: the index and count of the fragment.
+pub const UNEXPECTED_FRAGMENT: u16 = 0x0108;
+pub const FRAGMENTED_MSG_TOO_LARGE: u16 = 0x0109;
+pub const INVALID_FRAGMENTED_MSG: u16 = 0x010A;
+pub const FRAME_AUTH_FAILED: u16 = 0x010B;
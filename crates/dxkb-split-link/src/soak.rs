@@ -0,0 +1,232 @@
+//! A self-test of the link, for validating a new cable or baud rate from the
+//! keyboard itself. Only built with the `soak-test` feature.
+//!
+//! While the test runs, both sides stream [`SoakMsg::Counter`]s through the
+//! user messages of the link, with consecutive sequence numbers and a payload
+//! derived from them, and check the ones streamed by the peer:
+//!
+//! - A sequence number skipped by the stream counts as lost, until it arrives.
+//! - A sequence number older than the last one received counts as reordered,
+//!   or as duplicated if it had already been received.
+//! - A payload that doesn't match its sequence number counts as corrupted.
+//!
+//! The link already re-sends the lost frames and keeps the order of the
+//! messages, so anything counted by the test is a message that the link
+//! dropped (e.g the ones queued when it goes down) or a bug. The stats of the
+//! link itself tell how hard it had to work for it.
+//!
+//! Once the test has run for the given duration, each side logs its results
+//! and sends them to the peer with a [`SoakMsg::Report`], so that the side
+//! connected to the host logs the results of both directions.
+//!
+//! Like the [firmware transfers](crate::firmware), the [`SoakTest`] doesn't
+//! own the link: the received [`SoakMsg`]s are handed to it with `on_msg`,
+//! and `poll` sends its own, wrapped in the message type of the link.
+
+use core::time::Duration;
+
+use dxkb_common::time::{Clock, Stopwatch};
+use dxkb_common::{dev_info, dev_warn};
+use serde::{Deserialize, Serialize};
+
+use crate::SplitBusLike;
+
+/// The length of the payload of each counter.
+pub const SOAK_PAYLOAD_LEN: usize = 16;
+
+/// The max number of counters queued on each poll, so that the test doesn't
+/// starve the rest of the messages of the link.
+const SOAK_MSGS_PER_POLL: usize = 2;
+
+/// Number of sequence numbers before the last one received that are told
+/// apart, for telling the reordered counters from the duplicated ones.
+const SOAK_WINDOW: u32 = u32::BITS;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SoakMsg {
+    /// A message of the stream, whose payload is [`soak_payload`] of its
+    /// sequence number.
+    Counter {
+        seq: u32,
+        payload: [u8; SOAK_PAYLOAD_LEN],
+    },
+
+    /// The results of the sender, once its test is over.
+    Report(SoakStats),
+}
+
+/// The results of a soak test, as seen by one of the sides.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SoakStats {
+    /// Counters sent to the peer.
+    pub sent: u32,
+    /// Counters received from the peer, corrupted ones excluded.
+    pub received: u32,
+    /// Counters skipped by the stream of the peer that haven't arrived.
+    pub lost: u32,
+    /// Counters received after a newer one.
+    pub reordered: u32,
+    /// Counters received more than once.
+    pub duplicated: u32,
+    /// Counters whose payload doesn't match their sequence number.
+    pub corrupted: u32,
+}
+
+/// Returns the payload of the counter with the given sequence number, as
+/// pseudo-random bytes, so that most bit errors that get past the checksum of
+/// the frames show up.
+pub fn soak_payload(seq: u32) -> [u8; SOAK_PAYLOAD_LEN] {
+    let mut state = seq ^ 0x9e37_79b9;
+    core::array::from_fn(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    })
+}
+
+pub struct SoakTest<Clk: Clock> {
+    duration: Duration,
+    /// The time since the first counter was queued, which is longer than the
+    /// range of some clocks.
+    since_start: Option<Stopwatch<Clk::TInstant>>,
+    finished: bool,
+    report_pending: bool,
+    next_seq: u32,
+    /// The sequence number expected next from the peer, once its first
+    /// counter has been received.
+    expected_seq: Option<u32>,
+    /// The counters received among the [`SOAK_WINDOW`] before the expected
+    /// one. The bit 0 is the one right before it.
+    recent: u32,
+    stats: SoakStats,
+    peer_stats: Option<SoakStats>,
+}
+
+impl<Clk: Clock> SoakTest<Clk> {
+    /// Creates a test that runs for the given duration, counted from the
+    /// first counter queued.
+    pub const fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            since_start: None,
+            finished: false,
+            report_pending: false,
+            next_seq: 0,
+            expected_seq: None,
+            recent: 0,
+            stats: SoakStats {
+                sent: 0,
+                received: 0,
+                lost: 0,
+                reordered: 0,
+                duplicated: 0,
+                corrupted: 0,
+            },
+            peer_stats: None,
+        }
+    }
+
+    /// Returns the results of this side so far.
+    pub fn stats(&self) -> SoakStats {
+        self.stats
+    }
+
+    /// Returns the results reported by the peer, once its test is over.
+    pub fn peer_stats(&self) -> Option<SoakStats> {
+        self.peer_stats
+    }
+
+    /// Returns whether the test has run for its whole duration.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Handles a message received from the peer.
+    pub fn on_msg(&mut self, msg: &SoakMsg) {
+        match msg {
+            SoakMsg::Counter { seq, payload } => {
+                // Late counters of the peer would only skew the results
+                // already reported.
+                if !self.finished {
+                    self.on_counter(*seq, payload);
+                }
+            }
+            SoakMsg::Report(stats) => {
+                dev_info!("Soak test results of the peer: {:?}", stats);
+                self.peer_stats = Some(*stats);
+            }
+        }
+    }
+
+    fn on_counter(&mut self, seq: u32, payload: &[u8; SOAK_PAYLOAD_LEN]) {
+        if *payload != soak_payload(seq) {
+            // The sequence number itself may be the corrupted part.
+            dev_warn!("Soak test counter {} is corrupted", seq);
+            self.stats.corrupted = self.stats.corrupted.wrapping_add(1);
+            return;
+        }
+
+        self.stats.received = self.stats.received.wrapping_add(1);
+        let expected = *self.expected_seq.get_or_insert(seq);
+        if seq >= expected {
+            let skipped = seq - expected;
+            self.stats.lost = self.stats.lost.wrapping_add(skipped);
+            self.recent = self.recent.checked_shl(skipped.saturating_add(1)).unwrap_or(0) | 1;
+            self.expected_seq = Some(seq.wrapping_add(1));
+            return;
+        }
+
+        // Beyond the window there's no telling whether it was received
+        // already, so it is taken as reordered, which is the most likely.
+        let age = expected - 1 - seq;
+        if age < SOAK_WINDOW {
+            let bit = 1 << age;
+            if self.recent & bit != 0 {
+                self.stats.duplicated = self.stats.duplicated.wrapping_add(1);
+                return;
+            }
+            self.recent |= bit;
+        }
+        self.stats.reordered = self.stats.reordered.wrapping_add(1);
+        self.stats.lost = self.stats.lost.saturating_sub(1);
+    }
+
+    /// Queues the next counters in the link, and ends the test once its
+    /// duration has passed. The messages are wrapped with `wrap` into the
+    /// message type of the link.
+    pub fn poll<M: Clone + core::fmt::Debug, L: SplitBusLike<M>, W: Fn(SoakMsg) -> M>(
+        &mut self,
+        clock: &Clk,
+        link: &mut L,
+        wrap: W,
+    ) {
+        if self.finished {
+            if self.report_pending && link.transfer(wrap(SoakMsg::Report(self.stats))).is_ok() {
+                self.report_pending = false;
+            }
+            return;
+        }
+
+        for _ in 0..SOAK_MSGS_PER_POLL {
+            let counter = SoakMsg::Counter {
+                seq: self.next_seq,
+                payload: soak_payload(self.next_seq),
+            };
+            // The link may be down or congested, so the counter is just
+            // retried on the next poll, keeping the stream contiguous.
+            if link.transfer(wrap(counter)).is_err() {
+                break;
+            }
+            self.since_start.get_or_insert_with(|| Stopwatch::start(clock));
+            self.next_seq = self.next_seq.wrapping_add(1);
+            self.stats.sent = self.stats.sent.wrapping_add(1);
+        }
+
+        if self.since_start.as_mut().is_some_and(|since_start| since_start.elapsed(clock) >= self.duration) {
+            dev_info!("Soak test finished: {:?}", self.stats);
+            self.finished = true;
+            self.report_pending = true;
+        }
+    }
+}
@@ -0,0 +1,1663 @@
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+use core::time::Duration;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::collections::VecDeque;
+use std::{cell::Cell, cell::RefCell, rc::Rc};
+
+use dxkb_common::bus::{AttentionLine, BusBaudRateError, BusPollError, BusRead, BusTransferError, BusWrite};
+use dxkb_common::testing::{Impairments, LoopbackPair, LossyBus, MockClock};
+use dxkb_common::time::{Clock, TimeDiff};
+use dxkb_common::watchdog::Watchdog;
+use dxkb_common::ResetReason;
+use dxkb_split_link::addressing::{AddressedBus, AddressingStats};
+use dxkb_split_link::auth::{NoAuth, SipHashAuth, siphash24};
+use dxkb_split_link::firmware::{
+    FirmwareMsg, FirmwareReceiver, FirmwareRecvState, FirmwareSendState, FirmwareSender, FirmwareStorage,
+};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use dxkb_split_link::{
+    ByeReason, Channel, Crc16Ccitt, Crc8Smbus, DefaultSplitLinkTimings, FrameDropReason, FrameInfo, FrameIntegrity,
+    FrameObserver, HostPresence, LinkDownReason, LinkEvent, LinkStatus, PeerRole, SplitBus, SplitBusLike,
+    SplitLinkTimings, TransferError, TxOverflowPolicy,
+};
+
+type Msg = [u32; 16];
+type LossyLink<I = Crc8Smbus> = SplitBus<Msg, DefaultSplitLinkTimings, LossyBus, MockClock, 8, 4, I>;
+
+/// Time that passes between two polls of both sides.
+const POLL_PERIOD: Duration = Duration::from_millis(1);
+
+fn lossy_pair(clock: &MockClock, seed: u64) -> (LossyLink, LossyLink) {
+    let (a, b) = LossyBus::pair(clock, seed);
+    (SplitBus::new(a, clock.clone(), 1), SplitBus::new(b, clock.clone(), 2))
+}
+
+/// Polls both sides `polls` times, advancing the clock between them, and
+/// returns the first word of every message received by `b`. `tick` is called
+/// before each poll.
+fn run<F: FnMut(&mut LossyLink, &mut LossyLink)>(
+    clock: &MockClock,
+    a: &mut LossyLink,
+    b: &mut LossyLink,
+    polls: usize,
+    mut tick: F,
+) -> Vec<u32> {
+    let mut received = vec![];
+    for _ in 0..polls {
+        clock.advance(POLL_PERIOD);
+        tick(a, b);
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(msg[0]);
+            true
+        });
+    }
+    received
+}
+
+fn sync(clock: &MockClock, a: &mut LossyLink, b: &mut LossyLink) {
+    run(clock, a, b, 1000, |_, _| ());
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+}
+
+fn sync_with<I1: FrameIntegrity, I2: FrameIntegrity>() -> (LinkStatus, LinkStatus) {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1, I1> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1, I2> = SplitBus::new(b, clock.clone(), 2);
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        b.poll(|_| true);
+    }
+    (a.link_status(), b.link_status())
+}
+
+#[test]
+fn test_sync() {
+    assert_eq!(sync_with::<Crc8Smbus, Crc8Smbus>(), (LinkStatus::Up, LinkStatus::Up));
+    assert_eq!(sync_with::<Crc16Ccitt, Crc16Ccitt>(), (LinkStatus::Up, LinkStatus::Up));
+}
+
+#[test]
+fn test_sync_mismatched_integrity() {
+    assert_eq!(
+        sync_with::<Crc8Smbus, Crc16Ccitt>(),
+        (LinkStatus::Incompatible, LinkStatus::Incompatible)
+    );
+}
+
+#[test]
+fn test_sync_mismatched_protocol_version() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    b.set_protocol_version(0xF0);
+    run(&clock, &mut a, &mut b, 1000, |_, _| ());
+
+    assert_eq!(a.link_status(), LinkStatus::Incompatible);
+    assert_eq!(a.peer_protocol_version(), Some(0xF0));
+}
+
+#[test]
+fn test_delivers_in_order() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    let mut sent = 0;
+    let received = run(&clock, &mut a, &mut b, 1000, |a, _| {
+        if sent < 20 && a.transfer([sent; 16]).is_ok() {
+            sent += 1;
+        }
+    });
+    assert_eq!(received, (0..20).collect::<Vec<_>>());
+    assert_eq!(a.stats().retransmissions, 0);
+}
+
+#[test]
+fn test_tx_queue_high_watermark() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+    // The frames that set up the link and the ACKs are never queued.
+    assert_eq!(a.user_tx_queue_high_watermark(), 0);
+    assert_eq!(a.control_tx_queue_high_watermark(), 0);
+
+    for i in 0..8 {
+        assert!(a.transfer([i; 16]).is_ok());
+    }
+    assert!(matches!(a.transfer([8; 16]), Err(TransferError::BufferOverflow)));
+    assert_eq!(a.user_tx_queue_high_watermark(), 8);
+    assert_eq!(a.stats().user_tx_queue_full, 1);
+
+    // Draining the queue doesn't lower the watermark.
+    let received = run(&clock, &mut a, &mut b, 1000, |_, _| ());
+    assert_eq!(received, (0..8).collect::<Vec<_>>());
+    assert_eq!(a.user_tx_queue_high_watermark(), 8);
+    assert_eq!(a.stats().control_tx_queue_full, 0);
+}
+
+#[test]
+fn test_retransmits_lost_and_corrupted_frames() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 7);
+    sync(&clock, &mut a, &mut b);
+
+    let impairments = Impairments::none()
+        .with_drop_rate(0.2)
+        .with_corruption_rate(0.1)
+        .with_delay(Duration::from_millis(2));
+    a.bus_mut().set_impairments(impairments);
+    b.bus_mut().set_impairments(impairments);
+
+    let mut sent = 0;
+    let received = run(&clock, &mut a, &mut b, 20000, |a, _| {
+        if sent < 20 && a.transfer([sent; 16]).is_ok() {
+            sent += 1;
+        }
+    });
+    assert_eq!(received, (0..20).collect::<Vec<_>>());
+    assert!(a.stats().retransmissions > 0);
+    assert!(a.stats().crc_errors + b.stats().crc_errors > 0);
+}
+
+#[test]
+fn test_fragmented_messages() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 3);
+    a.set_max_frame_len(40).unwrap();
+    b.set_max_frame_len(40).unwrap();
+    sync(&clock, &mut a, &mut b);
+    a.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.25));
+
+    let mut sent = 0;
+    let received = run(&clock, &mut a, &mut b, 20000, |a, _| {
+        if sent < 5 && a.transfer([sent; 16]).is_ok() {
+            sent += 1;
+        }
+    });
+    assert_eq!(received, (0..5).collect::<Vec<_>>());
+    // Queued while the previous ones were being sent, so they are serialized
+    // once for checking their length and once more when they reach the head.
+    assert!(a.stats().msg_serializations <= 2 * 5);
+}
+
+#[test]
+fn test_head_message_is_serialized_once() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    // Lose the first transfers, but not for so long that the link goes down.
+    a.bus_mut().set_connected(false);
+    a.transfer_priority([7; 16]).unwrap();
+    let mut received = run(&clock, &mut a, &mut b, 500, |_, _| ());
+    a.bus_mut().set_connected(true);
+    received.extend(run(&clock, &mut a, &mut b, 500, |_, _| ()));
+    assert_eq!(received, vec![7]);
+    assert!(a.stats().retransmissions > 0);
+    assert_eq!(a.stats().msg_serializations, 1);
+}
+
+#[test]
+fn test_coalesced_messages() {
+    let clock = MockClock::new();
+    let (a, b) = LossyBus::pair(&clock, 4);
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, LossyBus, MockClock, 8, 4> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, LossyBus, MockClock, 8, 4> = SplitBus::new(b, clock.clone(), 2);
+    a.set_coalescing(true);
+    let mut received = vec![];
+    let mut poll = |a: &mut SplitBus<_, _, _, _, 8, 4>, b: &mut SplitBus<_, _, _, _, 8, 4>| {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(*msg);
+            true
+        });
+    };
+    for _ in 0..1000 {
+        poll(&mut a, &mut b);
+    }
+    assert_eq!(a.link_status(), LinkStatus::Up);
+
+    a.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.2));
+    b.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.2));
+    for i in 0..8 {
+        a.transfer(i).unwrap();
+    }
+    for _ in 0..5000 {
+        poll(&mut a, &mut b);
+    }
+    assert_eq!(received, (0..8).collect::<Vec<_>>());
+    assert!(a.stats().coalesced_msgs > 0);
+}
+
+#[test]
+fn test_priority_messages_go_first() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    for i in 0..5 {
+        a.transfer([i; 16]).unwrap();
+    }
+    for i in 100..103 {
+        a.transfer_priority([i; 16]).unwrap();
+    }
+
+    let received = run(&clock, &mut a, &mut b, 1000, |_, _| ());
+    assert_eq!(received, vec![100, 101, 102, 0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_datagrams_are_not_retransmitted() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 5);
+    sync(&clock, &mut a, &mut b);
+    a.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.3));
+
+    let mut sent = 0;
+    let received = run(&clock, &mut a, &mut b, 20000, |a, _| {
+        if sent < 30 && a.transfer_unreliable([sent; 16]).is_ok() {
+            sent += 1;
+        }
+    });
+    assert!(received.windows(2).all(|w| w[0] < w[1]));
+    assert!(received.len() < 30);
+
+    a.set_max_frame_len(40).unwrap();
+    assert!(matches!(a.transfer_unreliable([0; 16]), Err(TransferError::DatagramTooLarge)));
+}
+
+/// A message that takes a single byte in memory, but whose serialization takes
+/// as many bytes as its value, plus one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Padded(u8);
+
+impl Serialize for Padded {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.0 as usize + 1)?;
+        tuple.serialize_element(&self.0)?;
+        for _ in 0..self.0 {
+            tuple.serialize_element(&0xaau8)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Padded {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PaddedVisitor;
+
+        impl<'de> Visitor<'de> for PaddedVisitor {
+            type Value = Padded;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("a padded byte")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Padded, A::Error> {
+                let len: u8 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                for i in 0..len {
+                    seq.next_element::<u8>()?
+                        .ok_or_else(|| de::Error::invalid_length(i as usize + 1, &self))?;
+                }
+                Ok(Padded(len))
+            }
+        }
+
+        deserializer.deserialize_tuple(u8::MAX as usize + 1, PaddedVisitor)
+    }
+}
+
+type PaddedLink = SplitBus<Padded, DefaultSplitLinkTimings, LoopbackPair, MockClock, 4>;
+
+fn run_padded(clock: &MockClock, a: &mut PaddedLink, b: &mut PaddedLink, polls: usize) -> Vec<u8> {
+    let mut received = vec![];
+    for _ in 0..polls {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(msg.0);
+            true
+        });
+    }
+    received
+}
+
+#[test]
+fn test_oversized_messages() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: PaddedLink = SplitBus::new(a, clock.clone(), 1);
+    let mut b: PaddedLink = SplitBus::new(b, clock.clone(), 2);
+    run_padded(&clock, &mut a, &mut b, 1000);
+    assert_eq!(a.link_status(), LinkStatus::Up);
+
+    // The messages are refused once they don't fit in the frame buffers, and
+    // the ones that don't fit in a frame along with its header are
+    // fragmented.
+    let mut accepted = 0;
+    for len in 0..=u8::MAX {
+        match a.transfer(Padded(len)) {
+            Ok(()) => {
+                accepted += 1;
+                assert_eq!(run_padded(&clock, &mut a, &mut b, 100), vec![len]);
+            }
+            Err(TransferError::EncodeError(_)) => {}
+            Err(e) => panic!("Unexpected error {:?} for {}", e, len),
+        }
+
+        match a.transfer_unreliable(Padded(len)) {
+            Ok(()) => assert_eq!(run_padded(&clock, &mut a, &mut b, 10), vec![len]),
+            Err(TransferError::DatagramTooLarge | TransferError::EncodeError(_)) => {}
+            Err(e) => panic!("Unexpected error {:?} for {}", e, len),
+        }
+    }
+    assert!(accepted > 1);
+    assert!(matches!(a.transfer(Padded(u8::MAX)), Err(TransferError::EncodeError(_))));
+    assert_eq!(b.link_status(), LinkStatus::Up);
+}
+
+#[test]
+fn test_link_down_on_disconnect() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    a.bus_mut().set_connected(false);
+    b.bus_mut().set_connected(false);
+    run(&clock, &mut a, &mut b, 2000, |_, _| ());
+    assert_eq!(a.link_status(), LinkStatus::Down);
+    assert_eq!(b.link_status(), LinkStatus::Down);
+    assert!(matches!(a.transfer([0; 16]), Err(TransferError::LinkDown)));
+
+    a.bus_mut().set_connected(true);
+    b.bus_mut().set_connected(true);
+    sync(&clock, &mut a, &mut b);
+    assert_eq!(a.stats().link_down_count, 1);
+}
+
+#[test]
+fn test_shutdown_notifies_peer() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    assert!(a.shutdown(ByeReason::Suspend, Duration::from_millis(10)));
+    b.poll(|_| true);
+    assert_ne!(b.link_status(), LinkStatus::Up);
+    assert_eq!(b.peer_bye_reason(), Some(ByeReason::Suspend));
+}
+
+#[test]
+fn test_link_events() {
+    let events = |bus: &mut LossyLink| core::iter::from_fn(|| bus.poll_event()).collect::<Vec<_>>();
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+    assert_eq!(events(&mut a), vec![LinkEvent::Up]);
+    assert_eq!(events(&mut b), vec![LinkEvent::Up]);
+
+    assert!(a.shutdown(ByeReason::Reset, Duration::from_millis(10)));
+    b.poll(|_| true);
+    assert_eq!(events(&mut a), vec![LinkEvent::Down]);
+    assert_eq!(events(&mut b), vec![LinkEvent::Down, LinkEvent::PeerReset]);
+
+    sync(&clock, &mut a, &mut b);
+    a.bus_mut().set_connected(false);
+    b.bus_mut().set_connected(false);
+    run(&clock, &mut a, &mut b, 2000, |_, _| ());
+    assert_eq!(events(&mut a), vec![LinkEvent::Up, LinkEvent::Down]);
+}
+
+/// Polls the bus as any user generic over the bus would.
+fn poll_all<SB: SplitBusLike<u32>>(bus: &mut SB, received: &mut Vec<u32>) {
+    bus.poll(|msg| {
+        received.push(*msg);
+        true
+    });
+}
+
+#[test]
+fn test_dyn_split_bus() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(b, clock.clone(), 2);
+    let mut dyn_a: &mut dyn SplitBusLike<u32> = &mut a;
+    let mut received = vec![];
+    for i in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        if i == 500 {
+            dyn_a.transfer(42).unwrap();
+        }
+        dyn_a.poll_dyn(&mut |_: &u32| true);
+        poll_all(&mut b, &mut received);
+    }
+    assert_eq!(dyn_a.poll_event(), Some(LinkEvent::Up));
+
+    // Back from the peer, through the bus behind the trait object.
+    b.transfer(43).unwrap();
+    for _ in 0..10 {
+        clock.advance(POLL_PERIOD);
+        b.poll(|_| true);
+        poll_all(&mut dyn_a, &mut received);
+    }
+    assert_eq!(received, vec![42, 43]);
+}
+
+/// A node of a multi-drop line, that receives every frame sent by the rest of
+/// the nodes.
+struct WireNode {
+    rx: Rc<RefCell<Vec<VecDeque<Vec<u8>>>>>,
+    index: usize,
+}
+
+fn wire(nodes: usize) -> Vec<WireNode> {
+    let rx = Rc::new(RefCell::new(vec![VecDeque::new(); nodes]));
+    (0..nodes).map(|index| WireNode { rx: rx.clone(), index }).collect()
+}
+
+impl BusWrite for WireNode {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        for (index, queue) in self.rx.borrow_mut().iter_mut().enumerate() {
+            if index != self.index {
+                queue.push_back(buf.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        false
+    }
+}
+
+impl BusRead for WireNode {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        let frame = self.rx.borrow_mut()[self.index].pop_front().ok_or(BusPollError::WouldBlock)?;
+        buf[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len() as u16)
+    }
+}
+
+#[test]
+fn test_addressed_links_share_a_bus() {
+    let clock = MockClock::new();
+    let mut nodes = wire(3).into_iter();
+    let master = AddressedBus::<_, 2, 64, 8>::new(nodes.next().unwrap(), 1, [2, 3]);
+    let numpad = AddressedBus::<_, 1, 64, 8>::new(nodes.next().unwrap(), 2, [1]);
+    let macropad = AddressedBus::<_, 1, 64, 8>::new(nodes.next().unwrap(), 3, [1]);
+    assert!(master.port(4).is_none());
+
+    let mut to_numpad: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> =
+        SplitBus::new(master.port(2).unwrap(), clock.clone(), 1);
+    let mut to_macropad: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> =
+        SplitBus::new(master.port(3).unwrap(), clock.clone(), 2);
+    let mut numpad_link: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> =
+        SplitBus::new(numpad.port(1).unwrap(), clock.clone(), 3);
+    let mut macropad_link: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> =
+        SplitBus::new(macropad.port(1).unwrap(), clock.clone(), 4);
+
+    let (mut from_numpad, mut from_macropad) = (vec![], vec![]);
+    for i in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        if i == 500 {
+            numpad_link.transfer(10).unwrap();
+            macropad_link.transfer(20).unwrap();
+            numpad_link.transfer(11).unwrap();
+        }
+        numpad_link.poll(|_| true);
+        macropad_link.poll(|_| true);
+        poll_all(&mut to_numpad, &mut from_numpad);
+        poll_all(&mut to_macropad, &mut from_macropad);
+    }
+
+    assert_eq!(to_numpad.link_status(), LinkStatus::Up);
+    assert_eq!(to_macropad.link_status(), LinkStatus::Up);
+    assert_eq!(from_numpad, vec![10, 11]);
+    assert_eq!(from_macropad, vec![20]);
+
+    // Each unit hears the frames exchanged between the master and the other
+    // one, and drops them.
+    assert!(numpad.stats().foreign > 0);
+    assert_eq!(master.stats(), AddressingStats::default());
+
+    // Unplugging a unit doesn't take down the link with the other one.
+    drop(macropad_link);
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        numpad_link.poll(|_| true);
+        to_numpad.poll(|_| true);
+        to_macropad.poll(|_| true);
+    }
+    assert_eq!(to_numpad.link_status(), LinkStatus::Up);
+    assert_ne!(to_macropad.link_status(), LinkStatus::Up);
+}
+
+#[test]
+fn test_peer_reset_reason_reported() {
+    let events = |bus: &mut LossyLink| core::iter::from_fn(|| bus.poll_event()).collect::<Vec<_>>();
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    a.set_reset_reason(ResetReason::IndependentWatchdog);
+    sync(&clock, &mut a, &mut b);
+    assert!(events(&mut b).contains(&LinkEvent::PeerRebooted(ResetReason::IndependentWatchdog)));
+    assert_eq!(b.peer_reset_reason(), Some(ResetReason::IndependentWatchdog));
+    assert_eq!(events(&mut a), vec![LinkEvent::Up]);
+    assert_eq!(a.peer_reset_reason(), None);
+
+    // It is only reported once.
+    a.reset();
+    sync(&clock, &mut a, &mut b);
+    assert!(!events(&mut b).iter().any(|event| matches!(event, LinkEvent::PeerRebooted(_))));
+}
+
+static ROLE_CHANGES: AtomicU32 = AtomicU32::new(0);
+
+fn on_role_changed(role: PeerRole) {
+    assert_eq!(role, PeerRole::Slave);
+    ROLE_CHANGES.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn test_role_negotiation() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    a.set_on_role_changed(on_role_changed);
+    assert_eq!(a.negotiated_role(), None);
+
+    a.set_host_presence(HostPresence::Configured);
+    b.set_host_presence(HostPresence::Vbus);
+    sync(&clock, &mut a, &mut b);
+    assert_eq!(a.negotiated_role(), Some(PeerRole::Master));
+    assert_eq!(b.negotiated_role(), Some(PeerRole::Slave));
+    assert_eq!(ROLE_CHANGES.load(Ordering::Relaxed), 0);
+
+    // The USB cable is moved to the other half.
+    a.set_host_presence(HostPresence::None);
+    b.set_host_presence(HostPresence::Configured);
+    run(&clock, &mut a, &mut b, 100, |_, _| ());
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(a.negotiated_role(), Some(PeerRole::Slave));
+    assert_eq!(b.negotiated_role(), Some(PeerRole::Master));
+    assert_eq!(ROLE_CHANGES.load(Ordering::Relaxed), 1);
+
+    // Only one of them is elected when both are as close to the host.
+    a.set_host_presence(HostPresence::Vbus);
+    b.set_host_presence(HostPresence::Vbus);
+    run(&clock, &mut a, &mut b, 100, |_, _| ());
+    assert!(a.negotiated_role().is_some());
+    assert_ne!(a.negotiated_role(), b.negotiated_role());
+}
+
+#[test]
+fn test_measure_rtt() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    assert!(matches!(a.measure_rtt(), Err(TransferError::LinkDown)));
+    sync(&clock, &mut a, &mut b);
+    assert_eq!(a.measured_rtt(), None);
+
+    a.measure_rtt().unwrap();
+    assert!(a.is_rtt_measurement_pending());
+    run(&clock, &mut a, &mut b, 10, |_, _| ());
+    assert!(!a.is_rtt_measurement_pending());
+
+    // Sent before the clock advances for the first poll, answered by the
+    // peer within that poll or the next one, and read on the following one.
+    let rtt = a.measured_rtt().unwrap();
+    assert!(rtt > Duration::ZERO && rtt <= 3 * POLL_PERIOD, "{:?}", rtt);
+}
+
+#[test]
+fn test_loopback_detected() {
+    let clock = MockClock::new();
+    let mut bus: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(LoopbackPair::shorted(), clock.clone(), 1);
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        bus.poll(|_| true);
+    }
+
+    assert_eq!(bus.link_status(), LinkStatus::Down);
+    assert!(bus.is_loopback_detected());
+    // Only reported once.
+    assert_eq!(bus.poll_event(), Some(LinkEvent::Loopback));
+    assert_eq!(bus.poll_event(), None);
+}
+
+#[test]
+fn test_peer_bootloader_request() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 2);
+    sync(&clock, &mut a, &mut b);
+    while b.poll_event().is_some() {}
+
+    // The first request and its ACK are lost.
+    a.bus_mut().set_connected(false);
+    a.request_peer_bootloader().unwrap();
+    run(&clock, &mut a, &mut b, 50, |_, _| ());
+    assert!(a.is_peer_bootloader_pending());
+    a.bus_mut().set_connected(true);
+    b.bus_mut().set_connected(false);
+    run(&clock, &mut a, &mut b, 150, |_, _| ());
+    assert_eq!(b.poll_event(), Some(LinkEvent::BootloaderRequested));
+    assert!(a.is_peer_bootloader_pending());
+    b.bus_mut().set_connected(true);
+    run(&clock, &mut a, &mut b, 150, |_, _| ());
+    assert!(!a.is_peer_bootloader_pending());
+    // Only reported once, however many times it was requested.
+    assert_eq!(b.poll_event(), None);
+
+    assert!(b.shutdown(ByeReason::Bootloader, Duration::from_millis(10)));
+    a.poll(|_| true);
+    assert_eq!(a.peer_bye_reason(), Some(ByeReason::Bootloader));
+}
+
+#[test]
+fn test_power_state_exchange() {
+    let events = |bus: &mut LossyLink| core::iter::from_fn(|| bus.poll_event()).collect::<Vec<_>>();
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 3);
+    // Told as soon as the link is up.
+    a.set_local_suspended(true);
+    sync(&clock, &mut a, &mut b);
+    assert!(b.is_peer_suspended());
+    assert!(events(&mut b).contains(&LinkEvent::PeerSuspended));
+
+    // The link is kept up with the longer probe interval.
+    run(&clock, &mut a, &mut b, 4000, |_, _| ());
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+
+    b.request_peer_wakeup().unwrap();
+    run(&clock, &mut a, &mut b, 50, |_, _| ());
+    assert!(events(&mut a).contains(&LinkEvent::WakeRequested));
+
+    // The first state sent and its ACK are lost.
+    a.bus_mut().set_connected(false);
+    a.set_local_suspended(false);
+    run(&clock, &mut a, &mut b, 50, |_, _| ());
+    assert!(b.is_peer_suspended());
+    a.bus_mut().set_connected(true);
+    run(&clock, &mut a, &mut b, 150, |_, _| ());
+    assert!(!b.is_peer_suspended());
+    assert_eq!(events(&mut b), vec![LinkEvent::PeerResumed]);
+}
+
+#[test]
+fn test_tx_backpressure() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    assert_eq!(a.tx_queue_free(Channel::Normal), 8);
+    for i in 0..5 {
+        a.transfer([i; 16]).unwrap();
+    }
+    assert!(!a.is_tx_congested());
+    a.transfer([5; 16]).unwrap();
+    assert_eq!(a.tx_queue_free(Channel::Normal), 2);
+    assert_eq!(a.tx_queue_free(Channel::Priority), 8);
+    assert!(a.is_tx_congested());
+
+    run(&clock, &mut a, &mut b, 1000, |_, _| ());
+    assert!(!a.is_tx_congested());
+}
+
+/// A bus whose TX can be held busy, like a line stuck in the middle of a long
+/// transfer.
+struct StalledBus {
+    bus: LoopbackPair,
+    busy: Rc<Cell<bool>>,
+}
+
+impl BusWrite for StalledBus {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        if self.busy.get() {
+            return Err(BusTransferError::WouldBlock);
+        }
+        self.bus.transfer(buf)
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.busy.get()
+    }
+}
+
+impl BusRead for StalledBus {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.bus.poll_next(buf)
+    }
+}
+
+#[test]
+fn test_acks_are_coalesced() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let busy = Rc::new(Cell::new(false));
+    let b = StalledBus { bus: b, busy: busy.clone() };
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(b, clock.clone(), 2);
+    let mut received = vec![];
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        poll_all(&mut b, &mut received);
+    }
+    assert_eq!(b.link_status(), LinkStatus::Up);
+
+    // While b can't send anything, a keeps retransmitting the messages in
+    // flight, and each copy received by b has to be ACK'ed again.
+    busy.set(true);
+    for i in 0..8 {
+        a.transfer(i).unwrap();
+        a.transfer_priority(100 + i).unwrap();
+    }
+    for _ in 0..600 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        poll_all(&mut b, &mut received);
+    }
+    assert!(a.stats().retransmissions > 8);
+
+    busy.set(false);
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        poll_all(&mut b, &mut received);
+    }
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(a.user_tx_queue_len(), 0);
+    assert_eq!(received.len(), 16);
+    assert_eq!(b.control_tx_queue_high_watermark(), 0);
+    assert_eq!(b.stats().control_tx_queue_full, 0);
+}
+
+/// A watchdog that counts how many times it has been fed.
+struct CountingWatchdog(AtomicU32);
+
+impl Watchdog for CountingWatchdog {
+    fn feed(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_watchdog_starved_by_stalled_bus() {
+    static WATCHDOG: CountingWatchdog = CountingWatchdog(AtomicU32::new(0));
+    let feeds = || WATCHDOG.0.load(Ordering::Relaxed);
+
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let busy = Rc::new(Cell::new(false));
+    let b = StalledBus { bus: b, busy: busy.clone() };
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(b, clock.clone(), 2);
+    b.set_watchdog(Some(&WATCHDOG));
+    let mut received = vec![];
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        poll_all(&mut b, &mut received);
+    }
+    assert_eq!(b.link_status(), LinkStatus::Up);
+    assert_eq!(feeds(), 1000);
+
+    // The watchdog is still fed while the bus may just be finishing a frame.
+    busy.set(true);
+    let stall_polls = DefaultSplitLinkTimings::TX_STALL_TIME.as_millis() as u32;
+    for _ in 0..stall_polls {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        poll_all(&mut b, &mut received);
+    }
+    let fed = feeds();
+    assert!(fed >= 1000 + stall_polls - 1);
+
+    for _ in 0..100 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        poll_all(&mut b, &mut received);
+    }
+    assert_eq!(feeds(), fed);
+
+    busy.set(false);
+    clock.advance(POLL_PERIOD);
+    poll_all(&mut b, &mut received);
+    assert_eq!(feeds(), fed + 1);
+}
+
+/// Merges a message into the queued one if it only carries the first word,
+/// by appending that word.
+fn merge_first_word(queued: &mut Msg, msg: &Msg) -> bool {
+    match queued.iter().skip(1).position(|word| *word == 0) {
+        Some(free) => {
+            queued[free + 1] = msg[0];
+            true
+        }
+        None => false,
+    }
+}
+
+#[test]
+fn test_tx_overflow_policies() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    sync(&clock, &mut a, &mut b);
+
+    // Nothing is sent until the next poll, so every queued message can be
+    // dropped or merged.
+    a.set_tx_overflow_policy(Channel::Normal, TxOverflowPolicy::DropOldest);
+    for i in 0..9 {
+        a.transfer([i; 16]).unwrap();
+    }
+    assert_eq!(a.stats().user_tx_queue_full, 1);
+    let received = run(&clock, &mut a, &mut b, 1000, |_, _| ());
+    assert_eq!(received, (1..9).collect::<Vec<_>>());
+
+    a.set_tx_overflow_policy(Channel::Priority, TxOverflowPolicy::Coalesce(merge_first_word));
+    for i in 0..8 {
+        let mut msg = [0; 16];
+        msg[0] = 100 + i;
+        a.transfer_priority(msg).unwrap();
+    }
+    let mut last = [0; 16];
+    last[0] = 108;
+    a.transfer_priority(last).unwrap();
+    assert_eq!(a.stats().user_tx_queue_full, 2);
+    let received = run(&clock, &mut a, &mut b, 1000, |_, _| ());
+    assert_eq!(received, (100..108).collect::<Vec<_>>());
+
+    a.set_tx_overflow_policy(Channel::Normal, TxOverflowPolicy::Reject);
+    for i in 0..8 {
+        a.transfer([i; 16]).unwrap();
+    }
+    assert!(matches!(a.transfer([8; 16]), Err(TransferError::BufferOverflow)));
+}
+
+/// A bus that counts the link probes transferred through it.
+struct ProbeCounter<B> {
+    bus: B,
+    probes: Rc<Cell<usize>>,
+}
+
+impl<B: BusWrite> BusWrite for ProbeCounter<B> {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        // Preamble, CRC-8, Seq and Frame Type, which is zero for probes.
+        if buf.get(3) == Some(&0) {
+            self.probes.set(self.probes.get() + 1);
+        }
+        self.bus.transfer(buf)
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.bus.is_tx_busy()
+    }
+}
+
+impl<B: BusRead> BusRead for ProbeCounter<B> {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.bus.poll_next(buf)
+    }
+}
+
+/// A bus that prepends some garbage, with a stray preamble byte, to every
+/// frame it sends, like noise on the line right before the frame would.
+struct NoisyBus {
+    bus: LoopbackPair,
+}
+
+impl BusWrite for NoisyBus {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        let mut frame = vec![0x00, 0x99, 0x42];
+        frame.extend_from_slice(buf);
+        self.bus.transfer(&frame)
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.bus.is_tx_busy()
+    }
+}
+
+impl BusRead for NoisyBus {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.bus.poll_next(buf)
+    }
+}
+
+#[test]
+fn test_resyncs_frames_after_garbage() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(NoisyBus { bus: a }, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(NoisyBus { bus: b }, clock.clone(), 2);
+    let mut received = vec![];
+    for i in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        if i == 500 {
+            a.transfer(42).unwrap();
+        }
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(*msg);
+            true
+        });
+    }
+    assert_eq!(b.link_status(), LinkStatus::Up);
+    assert_eq!(received, vec![42]);
+    assert_eq!(b.stats().resynced_frames, b.stats().frames_received);
+    assert_eq!(b.stats().crc_errors, 0);
+}
+
+/// A bus that holds every other frame it sends, and sends it along with the
+/// next one as a single chunk, as if there was no idle gap between them. Only
+/// the frames that fit together in `max_chunk` bytes are coalesced.
+struct CoalescingBus {
+    bus: LoopbackPair,
+    held: Option<Vec<u8>>,
+    max_chunk: usize,
+    coalesced: Rc<Cell<u32>>,
+}
+
+impl BusWrite for CoalescingBus {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        match self.held.take() {
+            Some(mut chunk) if chunk.len() + buf.len() <= self.max_chunk => {
+                chunk.extend_from_slice(buf);
+                self.coalesced.set(self.coalesced.get() + 1);
+                self.bus.transfer(&chunk)
+            }
+            held => {
+                if let Some(held) = held {
+                    self.bus.transfer(&held)?;
+                }
+                self.held = Some(buf.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.bus.is_tx_busy()
+    }
+}
+
+impl BusRead for CoalescingBus {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.bus.poll_next(buf)
+    }
+}
+
+#[test]
+fn test_coalesced_frames_are_decoded() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let coalesced = Rc::new(Cell::new(0));
+    let coalescing = |bus| CoalescingBus { bus, held: None, max_chunk: 24, coalesced: coalesced.clone() };
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 4, Crc16Ccitt> = SplitBus::new(coalescing(a), clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 4, Crc16Ccitt> = SplitBus::new(coalescing(b), clock.clone(), 2);
+    let mut received = vec![];
+    for i in 0..2000 {
+        clock.advance(POLL_PERIOD);
+        if (500..520).contains(&i) {
+            a.transfer(i).unwrap();
+        }
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(*msg);
+            true
+        });
+    }
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+    assert_eq!(received, (500..520).collect::<Vec<_>>());
+    assert!(coalesced.get() > 0);
+    assert_eq!(a.stats().crc_errors + b.stats().crc_errors, 0);
+}
+
+#[test]
+fn test_probes_suppressed_during_traffic() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let probes = Rc::new(Cell::new(0));
+    let a = ProbeCounter { bus: a, probes: probes.clone() };
+    let mut a: SplitBus<Msg, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<Msg, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(b, clock.clone(), 2);
+    let mut poll = |a: &mut SplitBus<_, _, _, _, 8, 4>, b: &mut SplitBus<_, _, _, _, 8, 4>, polls, bulk| {
+        for _ in 0..polls {
+            clock.advance(POLL_PERIOD);
+            if bulk {
+                let _ = a.transfer([0; 16]);
+            }
+            a.poll(|_| true);
+            b.poll(|_| true);
+        }
+    };
+
+    poll(&mut a, &mut b, 1000, false);
+    assert_eq!(a.link_status(), LinkStatus::Up);
+
+    probes.set(0);
+    poll(&mut a, &mut b, 2000, true);
+    assert_eq!(probes.get(), 0);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+
+    poll(&mut a, &mut b, 1000, false);
+    assert!(probes.get() > 0);
+}
+
+#[test]
+fn test_link_down_after_seq_errors() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    b.set_max_seq_errors(4);
+    sync(&clock, &mut a, &mut b);
+
+    // Every ACK of b is lost, so it keeps receiving the same message again.
+    b.bus_mut().set_connected(false);
+    a.transfer([0; 16]).unwrap();
+    let received = run(&clock, &mut a, &mut b, 600, |_, _| ());
+    assert_eq!(received, vec![0]);
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_ne!(b.link_status(), LinkStatus::Up);
+    assert_eq!(b.stats().link_down_count, 1);
+}
+
+#[test]
+fn test_duplicate_frame_stats() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 1);
+    b.set_seq_jump_log_threshold(1);
+    sync(&clock, &mut a, &mut b);
+
+    // The ACKs of b are lost, so a re-sends a message b already received.
+    b.bus_mut().set_connected(false);
+    a.transfer([0; 16]).unwrap();
+    let received = run(&clock, &mut a, &mut b, 500, |_, _| ());
+    assert_eq!(received, vec![0]);
+    assert!(b.stats().duplicate_frames > 0);
+    assert_eq!(b.stats().out_of_order_frames, 0);
+    assert_eq!(a.stats().unexpected_acks, 0);
+}
+
+#[test]
+fn test_out_of_order_frames_are_reordered() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 3);
+    b.set_rx_reorder_depth(3);
+    sync(&clock, &mut a, &mut b);
+
+    a.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.2));
+    let mut sent = 0;
+    let received = run(&clock, &mut a, &mut b, 20000, |a, _| {
+        if sent < 40 && a.transfer([sent; 16]).is_ok() {
+            sent += 1;
+        }
+    });
+    assert_eq!(received, (0..40).collect::<Vec<_>>());
+    assert!(b.stats().reordered_frames > 0);
+}
+
+#[derive(Default)]
+struct VecStorage {
+    image: Vec<u8>,
+    begin_count: usize,
+}
+
+impl FirmwareStorage for VecStorage {
+    type Error = ();
+
+    fn capacity(&self) -> u32 {
+        4096
+    }
+
+    fn begin(&mut self, image_len: u32) -> Result<(), ()> {
+        self.image = vec![0xFF; image_len as usize];
+        self.begin_count += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+        self.image[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+        buf.copy_from_slice(&self.image[offset as usize..offset as usize + buf.len()]);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_firmware_transfer_resumes() {
+    type FirmwareLink = SplitBus<FirmwareMsg, DefaultSplitLinkTimings, LossyBus, MockClock, 8, 4>;
+
+    let clock = MockClock::new();
+    let (a, b) = LossyBus::pair(&clock, 3);
+    let mut a: FirmwareLink = SplitBus::new(a, clock.clone(), 1);
+    let mut b: FirmwareLink = SplitBus::new(b, clock.clone(), 2);
+
+    let image = (0..3000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+    let mut sender = FirmwareSender::new(&image);
+    let mut receiver = FirmwareReceiver::new(VecStorage::default());
+    let mut disconnected = false;
+    for _ in 0..20000 {
+        clock.advance(POLL_PERIOD);
+
+        // Cut the link for a while once half of the image has been received.
+        if !disconnected && receiver.received_len() >= 1500 {
+            disconnected = true;
+            a.bus_mut().set_connected(false);
+            b.bus_mut().set_connected(false);
+            for _ in 0..2000 {
+                clock.advance(POLL_PERIOD);
+                a.poll(|_| true);
+                b.poll(|_| true);
+            }
+            a.bus_mut().set_connected(true);
+            b.bus_mut().set_connected(true);
+        }
+
+        a.poll(|msg| {
+            sender.on_msg(msg);
+            true
+        });
+        while let Some(event) = a.poll_event() {
+            if event == LinkEvent::Down {
+                sender.on_link_down();
+            }
+        }
+        b.poll(|msg| {
+            receiver.on_msg(msg);
+            true
+        });
+        sender.poll(&mut a, |msg| msg);
+        receiver.poll(&mut b, |msg| msg);
+
+        if sender.is_finished() {
+            break;
+        }
+    }
+
+    assert!(disconnected);
+    assert_eq!(sender.state(), FirmwareSendState::Completed);
+    assert_eq!(receiver.state(), FirmwareRecvState::Completed);
+    assert_eq!(receiver.storage().image, image);
+    assert_eq!(receiver.storage().begin_count, 1);
+}
+
+/// A clock that runs `skew_ppm` faster than another one.
+#[derive(Clone)]
+struct SkewedClock {
+    inner: MockClock,
+    skew_ppm: u64,
+}
+
+impl Clock for SkewedClock {
+    type TInstant = u64;
+
+    fn current_instant(&self) -> u64 {
+        let now = self.inner.current_instant();
+        now + now * self.skew_ppm / 1_000_000
+    }
+
+    fn diff(&self, newer: u64, older: u64) -> TimeDiff {
+        self.inner.diff(newer, older)
+    }
+
+    fn nanos(&self, instant: u64) -> u64 {
+        instant
+    }
+}
+
+#[test]
+fn test_peer_time_offset() {
+    let clock = MockClock::new();
+    let (a, b) = LossyBus::pair(&clock, 1);
+    let mut a: SplitBus<Msg, DefaultSplitLinkTimings, LossyBus, MockClock, 8, 4> = SplitBus::new(a, clock.clone(), 1);
+    // The uptime of b starts 5 ms later.
+    clock.advance(Duration::from_millis(5));
+    let b_clock = SkewedClock { inner: clock.clone(), skew_ppm: 500 };
+    let mut b: SplitBus<Msg, DefaultSplitLinkTimings, LossyBus, SkewedClock, 8, 4> = SplitBus::new(b, b_clock.clone(), 2);
+    a.set_time_sync_interval(Some(Duration::from_millis(100)));
+    assert_eq!(a.peer_time_offset(), None);
+
+    for _ in 0..10000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        b.poll(|_| true);
+    }
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.peer_time_offset(), None);
+
+    let expected = b.uptime_us() as i64 - a.uptime_us() as i64;
+    let offset = a.peer_time_offset().unwrap();
+    // Each answer arrives one poll after the request.
+    assert!((offset - expected).abs() <= POLL_PERIOD.as_micros() as i64, "{} vs {}", offset, expected);
+    let skew = a.peer_clock_skew_ppm().unwrap();
+    assert!((450..=550).contains(&skew), "{}", skew);
+
+    let tolerance = POLL_PERIOD.as_micros() as i64;
+    let local = a.peer_time_to_local(b.uptime_us()).unwrap();
+    assert!((local as i64 - a.uptime_us() as i64).abs() <= tolerance, "{} vs {}", local, a.uptime_us());
+    let peer = a.local_time_to_peer(a.uptime_us()).unwrap();
+    assert!((peer as i64 - b.uptime_us() as i64).abs() <= tolerance, "{} vs {}", peer, b.uptime_us());
+    assert_eq!(b.peer_time_to_local(0), None);
+}
+
+/// A bus whose baud rate can be changed. Frames are lost while both ends run at
+/// different rates, and one of every three gets a wrong CRC when sent faster
+/// than `max_clean_baud`.
+struct BaudBus {
+    bus: LoopbackPair,
+    baud: Rc<Cell<u32>>,
+    peer_baud: Rc<Cell<u32>>,
+    max_clean_baud: Rc<Cell<u32>>,
+    transferred: usize,
+}
+
+impl BusWrite for BaudBus {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        if self.baud.get() != self.peer_baud.get() {
+            return Ok(());
+        }
+
+        self.transferred += 1;
+        let mut frame = buf.to_vec();
+        if self.baud.get() > self.max_clean_baud.get() && self.transferred % 3 == 0 {
+            frame[1] ^= 0xff;
+        }
+        self.bus.transfer(&frame)
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.bus.is_tx_busy()
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        Some(self.baud.get())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), BusBaudRateError> {
+        self.baud.set(baud_rate);
+        Ok(())
+    }
+}
+
+impl BusRead for BaudBus {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.bus.poll_next(buf)
+    }
+}
+
+const BAUD_RATES: &[u32] = &[2_000_000, 1_000_000, 115_200];
+
+#[test]
+fn test_baud_rate_fallback() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let a_baud = Rc::new(Cell::new(2_000_000));
+    let b_baud = Rc::new(Cell::new(2_000_000));
+    let max_clean_baud = Rc::new(Cell::new(1_000_000));
+    let bus = |bus, baud: &Rc<Cell<u32>>, peer_baud: &Rc<Cell<u32>>| BaudBus {
+        bus,
+        baud: baud.clone(),
+        peer_baud: peer_baud.clone(),
+        max_clean_baud: max_clean_baud.clone(),
+        transferred: 0,
+    };
+    let mut a: SplitBus<Msg, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(bus(a, &a_baud, &b_baud), clock.clone(), 1);
+    let mut b: SplitBus<Msg, DefaultSplitLinkTimings, _, _, 8, 4> = SplitBus::new(bus(b, &b_baud, &a_baud), clock.clone(), 2);
+    a.set_baud_fallback(BAUD_RATES).unwrap();
+    b.set_baud_fallback(BAUD_RATES).unwrap();
+
+    let mut received = 0;
+    let mut poll = |a: &mut SplitBus<_, _, _, _, 8, 4>, b: &mut SplitBus<_, _, _, _, 8, 4>, polls| {
+        for _ in 0..polls {
+            clock.advance(POLL_PERIOD);
+            let _ = a.transfer([0; 16]);
+            a.poll(|_| true);
+            b.poll(|_| {
+                received += 1;
+                true
+            });
+        }
+    };
+
+    poll(&mut a, &mut b, 3000);
+    assert_eq!(a.current_baud(), Some(1_000_000));
+    assert_eq!(b.current_baud(), Some(1_000_000));
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+
+    // Once the faster rate works again, the link climbs back to it.
+    max_clean_baud.set(2_000_000);
+    poll(&mut a, &mut b, 61_000);
+    assert_eq!(a.current_baud(), Some(2_000_000));
+    assert_eq!(b.current_baud(), Some(2_000_000));
+    assert_eq!(b.link_status(), LinkStatus::Up);
+    assert!(received > 0);
+}
+
+#[test]
+fn test_siphash24_vectors() {
+    // From the reference implementation of SipHash.
+    let key: [u8; 16] = core::array::from_fn(|i| i as u8);
+    let data: Vec<u8> = (0..15).collect();
+    assert_eq!(siphash24(&key, &[]), 0x726f_db47_dd0e_0e31);
+    assert_eq!(siphash24(&key, &data), 0xa129_ca61_49be_45e5);
+}
+
+type AuthLink = SplitBus<Msg, DefaultSplitLinkTimings, LoopbackPair, MockClock, 8, 4, Crc8Smbus, SipHashAuth>;
+
+#[test]
+fn test_authenticated_link() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: AuthLink = SplitBus::with_auth(a, clock.clone(), 1, SipHashAuth::new([7; 16]));
+    let mut b: AuthLink = SplitBus::with_auth(b, clock.clone(), 2, SipHashAuth::new([7; 16]));
+    let mut received = vec![];
+    for i in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        if i == 500 {
+            a.transfer([42; 16]).unwrap();
+        }
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(msg[0]);
+            true
+        });
+    }
+    assert_eq!(b.link_status(), LinkStatus::Up);
+    assert_eq!(received, vec![42]);
+    assert_eq!(b.stats().auth_failures, 0);
+}
+
+#[test]
+fn test_frames_with_wrong_key_are_dropped() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: AuthLink = SplitBus::with_auth(a, clock.clone(), 1, SipHashAuth::new([7; 16]));
+    let mut b: AuthLink = SplitBus::with_auth(b, clock.clone(), 2, SipHashAuth::new([8; 16]));
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        b.poll(|_| true);
+    }
+    assert_eq!(a.link_status(), LinkStatus::Down);
+    assert_eq!(b.link_status(), LinkStatus::Down);
+    assert!(a.stats().auth_failures > 0);
+    assert!(b.stats().auth_failures > 0);
+}
+
+/// One end of a pair of attention lines wired to each other. Each line latches
+/// its assertions until the peer takes them, like an EXTI pending bit does.
+struct TestAttentionLine {
+    ours: Rc<[Cell<bool>; 2]>,
+    peer: Rc<[Cell<bool>; 2]>,
+    /// Number of polls in which the peer needed attention.
+    attended: Rc<Cell<usize>>,
+}
+
+impl TestAttentionLine {
+    fn pair() -> (Self, Self) {
+        let a: Rc<[Cell<bool>; 2]> = Rc::default();
+        let b: Rc<[Cell<bool>; 2]> = Rc::default();
+        (
+            Self { ours: a.clone(), peer: b.clone(), attended: Rc::default() },
+            Self { ours: b, peer: a, attended: Rc::default() },
+        )
+    }
+}
+
+impl AttentionLine for TestAttentionLine {
+    fn set_pending(&mut self, pending: bool) {
+        self.ours[0].set(pending);
+        if pending {
+            self.ours[1].set(true);
+        }
+    }
+
+    fn take_peer_attention(&mut self) -> bool {
+        let attention = self.peer[0].get() | self.peer[1].replace(false);
+        if attention {
+            self.attended.set(self.attended.get() + 1);
+        }
+        attention
+    }
+}
+
+type AttentionLink = SplitBus<u32, DefaultSplitLinkTimings, LoopbackPair, MockClock, 8, 1, Crc8Smbus, NoAuth, TestAttentionLine>;
+
+#[test]
+fn test_attention_line_only_drains_when_signaled() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let (a_line, b_line) = TestAttentionLine::pair();
+    let b_attended = b_line.attended.clone();
+    let mut a: AttentionLink = SplitBus::with_attention_line(a, clock.clone(), 1, NoAuth, a_line);
+    let mut b: AttentionLink = SplitBus::with_attention_line(b, clock.clone(), 2, NoAuth, b_line);
+    let mut received = vec![];
+    for i in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        if i == 500 {
+            a.transfer(42).unwrap();
+        }
+        a.poll(|_| true);
+        b.poll(|msg| {
+            received.push(*msg);
+            true
+        });
+    }
+    assert_eq!(b.link_status(), LinkStatus::Up);
+    assert_eq!(received, vec![42]);
+    // Once the link is up, the peer only needs attention for the keepalives.
+    assert!(b_attended.get() < 500);
+}
+
+#[test]
+fn test_attention_line_never_asserted_starves_the_peer() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    // Neither side sees the line of the other.
+    let (a_line, _) = TestAttentionLine::pair();
+    let (_, b_line) = TestAttentionLine::pair();
+    let mut a: AttentionLink = SplitBus::with_attention_line(a, clock.clone(), 1, NoAuth, a_line);
+    let mut b: AttentionLink = SplitBus::with_attention_line(b, clock.clone(), 2, NoAuth, b_line);
+    for _ in 0..1000 {
+        clock.advance(POLL_PERIOD);
+        a.poll(|_| true);
+        b.poll(|_| true);
+    }
+    assert_eq!(b.link_status(), LinkStatus::Down);
+}
+
+#[test]
+fn test_link_down_reasons() {
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let mut a: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SplitBus<u32, DefaultSplitLinkTimings, _, _, 8, 1> = SplitBus::new(b, clock.clone(), 2);
+    assert_eq!(a.link_status_detail(), (LinkStatus::Down, Some(LinkDownReason::Initial)));
+
+    let mut poll = |a: &mut SplitBus<_, _, _, _, 8, 1>, b: &mut SplitBus<_, _, _, _, 8, 1>, polls, poll_b| {
+        for _ in 0..polls {
+            clock.advance(POLL_PERIOD);
+            a.poll(|_: &u32| true);
+            if poll_b {
+                b.poll(|_: &u32| true);
+            }
+        }
+    };
+
+    poll(&mut a, &mut b, 1000, true);
+    assert_eq!(a.link_status_detail(), (LinkStatus::Up, None));
+
+    // The peer stops answering.
+    poll(&mut a, &mut b, 1500, false);
+    assert_eq!(a.link_status_detail(), (LinkStatus::Down, Some(LinkDownReason::IdleTimeout)));
+
+    b.reset();
+    poll(&mut a, &mut b, 1000, true);
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    b.shutdown(ByeReason::Suspend, Duration::from_millis(10));
+    poll(&mut a, &mut b, 1, false);
+    assert_eq!(a.link_status_detail(), (LinkStatus::Down, Some(LinkDownReason::PeerClosed(ByeReason::Suspend))));
+    assert_eq!(b.link_status_detail(), (LinkStatus::Down, Some(LinkDownReason::Closed)));
+}
+
+struct LimitedRetransmissionTimings;
+
+impl SplitLinkTimings for LimitedRetransmissionTimings {
+    const MAX_LINK_IDLE_TIME: Duration = DefaultSplitLinkTimings::MAX_LINK_IDLE_TIME;
+    const LINK_IDLE_PROBE_INTERVAL_TIME: Duration = DefaultSplitLinkTimings::LINK_IDLE_PROBE_INTERVAL_TIME;
+    const MAX_SYNC_ACK_WAIT_TIME: Duration = DefaultSplitLinkTimings::MAX_SYNC_ACK_WAIT_TIME;
+    const MSG_REPLAY_DELAY_TIME: Duration = DefaultSplitLinkTimings::MSG_REPLAY_DELAY_TIME;
+    const MAX_RETRANSMISSIONS: u8 = 3;
+}
+
+/// A bus that loses every transport message sent while blocked, but lets the
+/// rest of the frames through, so the link stays up.
+struct TransportBlocker {
+    bus: LoopbackPair,
+    blocked: Rc<Cell<bool>>,
+}
+
+impl BusWrite for TransportBlocker {
+    fn transfer(&mut self, buf: &[u8]) -> Result<(), BusTransferError> {
+        // Preamble, CRC-8, Seq and Frame Type, which is 4 for transport
+        // messages.
+        if self.blocked.get() && buf.get(3) == Some(&4) {
+            return Ok(());
+        }
+        self.bus.transfer(buf)
+    }
+
+    fn is_tx_busy(&self) -> bool {
+        self.bus.is_tx_busy()
+    }
+}
+
+impl BusRead for TransportBlocker {
+    fn poll_next(&self, buf: &mut [u8]) -> Result<u16, BusPollError> {
+        self.bus.poll_next(buf)
+    }
+}
+
+static DROPPED_MSG: AtomicU32 = AtomicU32::new(0);
+
+fn on_message_dropped(channel: Channel, msg: &u32) {
+    assert_eq!(channel, Channel::Normal);
+    DROPPED_MSG.store(*msg, Ordering::Relaxed);
+}
+
+#[test]
+fn test_drops_messages_after_max_retransmissions() {
+    type Link = SplitBus<u32, LimitedRetransmissionTimings, TransportBlocker, MockClock, 8, 1>;
+
+    let clock = MockClock::new();
+    let (a, b) = LoopbackPair::pair();
+    let blocked = Rc::new(Cell::new(false));
+    let mut a: Link = SplitBus::new(TransportBlocker { bus: a, blocked: blocked.clone() }, clock.clone(), 1);
+    let mut b: Link = SplitBus::new(TransportBlocker { bus: b, blocked: Rc::default() }, clock.clone(), 2);
+    a.set_on_message_dropped(on_message_dropped);
+
+    let mut received = vec![];
+    let mut poll = |a: &mut Link, b: &mut Link| {
+        for _ in 0..1000 {
+            clock.advance(POLL_PERIOD);
+            a.poll(|_| true);
+            b.poll(|msg| {
+                received.push(*msg);
+                true
+            });
+        }
+    };
+    poll(&mut a, &mut b);
+    while a.poll_event().is_some() {}
+
+    blocked.set(true);
+    a.transfer(7).unwrap();
+    poll(&mut a, &mut b);
+    assert_eq!(DROPPED_MSG.load(Ordering::Relaxed), 7);
+    assert_eq!(a.stats().dropped_msgs, 1);
+    assert_eq!(a.stats().retransmissions, 3);
+    assert_eq!(a.poll_event(), Some(LinkEvent::Resync));
+
+    // The link was synced again, so the messages that follow the dropped one
+    // are accepted by the peer.
+    blocked.set(false);
+    a.transfer(8).unwrap();
+    poll(&mut a, &mut b);
+    assert_eq!(received, vec![8]);
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+}
+
+/// Counts the frames seen by the observer of a link.
+struct FrameCounter {
+    tx: AtomicU32,
+    rx: AtomicU32,
+    acks: AtomicU32,
+    dropped: AtomicU32,
+}
+
+impl FrameObserver for FrameCounter {
+    fn on_tx(&self, raw: &[u8], _info: &FrameInfo) {
+        assert_eq!(raw[0], 0x99);
+        self.tx.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_rx(&self, raw: &[u8], info: &FrameInfo) {
+        assert_eq!(raw[0], 0x99);
+        self.rx.fetch_add(1, Ordering::Relaxed);
+        // Ack is the second frame type.
+        if info.frame_type == 1 {
+            assert_eq!(info.channel, Some(Channel::Normal));
+            self.acks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_drop(&self, _raw: &[u8], reason: FrameDropReason) {
+        assert_ne!(reason, FrameDropReason::Overflow);
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static FRAMES: FrameCounter = FrameCounter {
+    tx: AtomicU32::new(0),
+    rx: AtomicU32::new(0),
+    acks: AtomicU32::new(0),
+    dropped: AtomicU32::new(0),
+};
+
+#[test]
+fn test_frame_observer() {
+    let clock = MockClock::new();
+    let (mut a, mut b) = lossy_pair(&clock, 5);
+    a.set_frame_observer(Some(&FRAMES));
+    sync(&clock, &mut a, &mut b);
+
+    let mut sent = 0;
+    let received = run(&clock, &mut a, &mut b, 1000, |a, _| {
+        if sent < 5 && a.transfer([sent; 16]).is_ok() {
+            sent += 1;
+        }
+    });
+    assert_eq!(received, (0..5).collect::<Vec<_>>());
+    assert_eq!(FRAMES.tx.load(Ordering::Relaxed), a.stats().frames_sent);
+    assert_eq!(FRAMES.rx.load(Ordering::Relaxed), a.stats().frames_received);
+    assert!(FRAMES.acks.load(Ordering::Relaxed) > 0);
+    assert_eq!(FRAMES.dropped.load(Ordering::Relaxed), 0);
+
+    b.bus_mut().set_impairments(Impairments::none().with_corruption_rate(1.0));
+    run(&clock, &mut a, &mut b, 200, |_, _| ());
+    assert!(FRAMES.dropped.load(Ordering::Relaxed) > 0);
+}
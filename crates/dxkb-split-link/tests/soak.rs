@@ -0,0 +1,89 @@
+#![cfg(feature = "soak-test")]
+
+use core::time::Duration;
+
+use dxkb_common::testing::{Impairments, LossyBus, MockClock};
+use dxkb_split_link::soak::{SoakMsg, SoakStats, SoakTest, soak_payload};
+use dxkb_split_link::{DefaultSplitLinkTimings, LinkStatus, SplitBus, SplitBusLike};
+
+type SoakLink = SplitBus<SoakMsg, DefaultSplitLinkTimings, LossyBus, MockClock, 8, 4>;
+
+fn counter(seq: u32) -> SoakMsg {
+    SoakMsg::Counter {
+        seq,
+        payload: soak_payload(seq),
+    }
+}
+
+#[test]
+fn test_counters_verified() {
+    let mut test = SoakTest::<MockClock>::new(Duration::from_secs(1));
+    for seq in [10, 11, 13, 12, 12, 15, 14] {
+        test.on_msg(&counter(seq));
+    }
+
+    let mut corrupted = counter(16);
+    if let SoakMsg::Counter { payload, .. } = &mut corrupted {
+        payload[3] ^= 0x10;
+    }
+    test.on_msg(&corrupted);
+
+    assert_eq!(
+        test.stats(),
+        SoakStats {
+            sent: 0,
+            received: 7,
+            lost: 0,
+            reordered: 2,
+            duplicated: 1,
+            corrupted: 1,
+        }
+    );
+
+    // The corrupted 16, 17 and 18 never arrive.
+    test.on_msg(&counter(19));
+    assert_eq!(test.stats().lost, 3);
+}
+
+#[test]
+fn test_soak_over_lossy_link() {
+    let clock = MockClock::new();
+    let (a, b) = LossyBus::pair(&clock, 7);
+    let mut a: SoakLink = SplitBus::new(a, clock.clone(), 1);
+    let mut b: SoakLink = SplitBus::new(b, clock.clone(), 2);
+    for _ in 0..1000 {
+        clock.advance(Duration::from_millis(1));
+        a.poll(|_| true);
+        b.poll(|_| true);
+    }
+    assert_eq!(a.link_status(), LinkStatus::Up);
+    assert_eq!(b.link_status(), LinkStatus::Up);
+
+    // The frames lost are re-sent by the link, so the test must not notice.
+    a.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.1));
+    b.bus_mut().set_impairments(Impairments::none().with_drop_rate(0.1));
+
+    let mut test_a = SoakTest::<MockClock>::new(Duration::from_secs(2));
+    let mut test_b = SoakTest::<MockClock>::new(Duration::from_secs(2));
+    for _ in 0..5000 {
+        clock.advance(Duration::from_millis(1));
+        a.poll(|msg| {
+            test_a.on_msg(msg);
+            true
+        });
+        b.poll(|msg| {
+            test_b.on_msg(msg);
+            true
+        });
+        test_a.poll(&clock, &mut a, |msg| msg);
+        test_b.poll(&clock, &mut b, |msg| msg);
+    }
+
+    assert!(test_a.is_finished() && test_b.is_finished());
+    for (test, peer) in [(&test_a, &test_b), (&test_b, &test_a)] {
+        let stats = test.stats();
+        assert!(stats.sent > 0);
+        assert_eq!(stats.lost + stats.reordered + stats.duplicated + stats.corrupted, 0);
+        assert_eq!(test.peer_stats(), Some(peer.stats()));
+    }
+}
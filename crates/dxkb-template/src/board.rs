@@ -0,0 +1,271 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+/// The description of a board, as read from the TOML file provided by the
+/// user. Only the minimal set of things that differ between the existing
+/// targets are described here. Anything else (custom keys, lighting, etc.) is
+/// expected to be added by hand to the generated code.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BoardDescription {
+    /// The name of the generated crate.
+    pub name: String,
+
+    /// The USB product string. The side of the keyboard is appended to it.
+    pub product: String,
+
+    #[serde(default = "default_layers")]
+    pub layers: u8,
+
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u8,
+
+    pub matrix: MatrixDescription,
+    pub usb: UsbDescription,
+    pub split: SplitDescription,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanDirection {
+    Row,
+    Column,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixDescription {
+    #[serde(default = "default_scan")]
+    pub scan: ScanDirection,
+    pub rows: Vec<PinName>,
+    pub cols_left: Vec<PinName>,
+
+    /// The column pins of the right side. If not present, the left side
+    /// columns are used in reverse order, which is what happens when the same
+    /// PCB is used for both sides, flipped.
+    pub cols_right: Option<Vec<PinName>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UsbDescription {
+    pub vid: u16,
+    pub pid: u16,
+    pub master_sense_pin: PinName,
+}
+
+/// The split link configuration. Only half-duplex, single wire links are
+/// supported by the generator for now, since that's what all the existing
+/// targets use.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SplitDescription {
+    pub usart: String,
+    pub dma: String,
+    pub tx_stream: u8,
+    pub rx_stream: u8,
+    pub txrx_pin: PinName,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct PinName {
+    pub port: char,
+    pub pin: u8,
+}
+
+impl TryFrom<String> for PinName {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut chars = value.chars();
+        let port = chars
+            .next()
+            .map(|c| c.to_ascii_uppercase())
+            .filter(|c| ('A'..='H').contains(c))
+            .ok_or_else(|| format!("Invalid pin {:?}: port must be a letter between A and H", value))?;
+        let pin = chars
+            .as_str()
+            .parse::<u8>()
+            .ok()
+            .filter(|pin| *pin < 16)
+            .ok_or_else(|| format!("Invalid pin {:?}: pin number must be between 0 and 15", value))?;
+
+        Ok(PinName { port, pin })
+    }
+}
+
+impl Display for PinName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "P{}{}", self.port, self.pin)
+    }
+}
+
+impl PinName {
+    /// The name of the field of the HAL GPIO parts for this pin (e.g `pa2`).
+    pub fn field(&self) -> String {
+        format!("p{}{}", self.port.to_ascii_lowercase(), self.pin)
+    }
+
+    /// The name of the GPIO parts variable that holds this pin (e.g `gpioa`).
+    pub fn gpio(&self) -> String {
+        format!("gpio{}", self.port.to_ascii_lowercase())
+    }
+
+    /// The EXTI interrupt line that is triggered by this pin on the STM32F4.
+    pub fn exti_interrupt(&self) -> &'static str {
+        match self.pin {
+            0 => "EXTI0",
+            1 => "EXTI1",
+            2 => "EXTI2",
+            3 => "EXTI3",
+            4 => "EXTI4",
+            5..=9 => "EXTI9_5",
+            _ => "EXTI15_10",
+        }
+    }
+}
+
+/// A known valid combination of USART, DMA streams and channels. Only the
+/// ones available on the STM32F411 are listed here.
+pub struct UsartDmaMapping {
+    pub usart: &'static str,
+    pub dma: &'static str,
+    pub tx_stream: u8,
+    pub tx_channel: u8,
+    pub rx_stream: u8,
+    pub rx_channel: u8,
+    pub tx_pins: &'static [(char, u8)],
+}
+
+const USART_DMA_MAPPINGS: &[UsartDmaMapping] = &[
+    UsartDmaMapping { usart: "USART1", dma: "DMA2", tx_stream: 7, tx_channel: 4, rx_stream: 2, rx_channel: 4, tx_pins: &[('A', 9), ('A', 15), ('B', 6)] },
+    UsartDmaMapping { usart: "USART1", dma: "DMA2", tx_stream: 7, tx_channel: 4, rx_stream: 5, rx_channel: 4, tx_pins: &[('A', 9), ('A', 15), ('B', 6)] },
+    UsartDmaMapping { usart: "USART2", dma: "DMA1", tx_stream: 6, tx_channel: 4, rx_stream: 5, rx_channel: 4, tx_pins: &[('A', 2)] },
+    UsartDmaMapping { usart: "USART6", dma: "DMA2", tx_stream: 6, tx_channel: 5, rx_stream: 1, rx_channel: 5, tx_pins: &[('C', 6)] },
+    UsartDmaMapping { usart: "USART6", dma: "DMA2", tx_stream: 6, tx_channel: 5, rx_stream: 2, rx_channel: 5, tx_pins: &[('C', 6)] },
+    UsartDmaMapping { usart: "USART6", dma: "DMA2", tx_stream: 7, tx_channel: 5, rx_stream: 1, rx_channel: 5, tx_pins: &[('C', 6)] },
+    UsartDmaMapping { usart: "USART6", dma: "DMA2", tx_stream: 7, tx_channel: 5, rx_stream: 2, rx_channel: 5, tx_pins: &[('C', 6)] },
+];
+
+fn default_layers() -> u8 {
+    1
+}
+
+fn default_debounce_ms() -> u8 {
+    20
+}
+
+fn default_scan() -> ScanDirection {
+    ScanDirection::Row
+}
+
+impl BoardDescription {
+    pub fn cols_right(&self) -> Vec<PinName> {
+        match &self.matrix.cols_right {
+            Some(cols) => cols.clone(),
+            None => self.matrix.cols_left.iter().rev().copied().collect(),
+        }
+    }
+
+    /// Finds the DMA mapping for the split link USART. It must be called after
+    /// [`BoardDescription::validate`], which ensures this exists.
+    pub fn usart_dma_mapping(&self) -> &'static UsartDmaMapping {
+        USART_DMA_MAPPINGS
+            .iter()
+            .find(|m| {
+                m.usart == self.split.usart
+                    && m.dma == self.split.dma
+                    && m.tx_stream == self.split.tx_stream
+                    && m.rx_stream == self.split.rx_stream
+            })
+            .expect("Board description was not validated")
+    }
+
+    /// Checks that the description can be turned into a target that compiles,
+    /// returning all the errors found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            errors.push(format!("Invalid crate name {:?}", self.name));
+        }
+
+        if self.layers == 0 {
+            errors.push("There must be at least 1 layer".to_string());
+        }
+
+        let rows = self.matrix.rows.len();
+        let cols = self.matrix.cols_left.len();
+        if rows == 0 || cols == 0 {
+            errors.push("The matrix must have at least one row and one column".to_string());
+        }
+
+        // The layout is stored using u8 coordinates, and it contains both sides.
+        if rows > u8::MAX as usize || 2 * cols > u8::MAX as usize {
+            errors.push(format!("Matrix too big: {} rows x {} cols per side", rows, cols));
+        }
+
+        let cols_right = self.cols_right();
+        if cols_right.len() != cols {
+            errors.push(format!(
+                "Both sides must have the same number of columns ({} left, {} right)",
+                cols,
+                cols_right.len()
+            ));
+        }
+
+        for (side, cols) in [("left", &self.matrix.cols_left), ("right", &cols_right)] {
+            let mut used: Vec<(PinName, &str)> = vec![
+                (PinName { port: 'A', pin: 11 }, "USB D-"),
+                (PinName { port: 'A', pin: 12 }, "USB D+"),
+                (self.usb.master_sense_pin, "USB master sense"),
+                (self.split.txrx_pin, "split link TX/RX"),
+            ];
+            used.extend(self.matrix.rows.iter().map(|p| (*p, "matrix row")));
+            used.extend(cols.iter().map(|p| (*p, "matrix column")));
+
+            for (i, (pin, usage)) in used.iter().enumerate() {
+                if let Some((_, prev_usage)) = used[..i].iter().find(|(p, _)| p == pin) {
+                    errors.push(format!(
+                        "Pin {} is used more than once in the {} side: {} and {}",
+                        pin, side, prev_usage, usage
+                    ));
+                }
+            }
+        }
+
+        match USART_DMA_MAPPINGS.iter().find(|m| {
+            m.usart == self.split.usart
+                && m.dma == self.split.dma
+                && m.tx_stream == self.split.tx_stream
+                && m.rx_stream == self.split.rx_stream
+        }) {
+            Some(mapping) => {
+                let pin = self.split.txrx_pin;
+                if !mapping.tx_pins.contains(&(pin.port, pin.pin)) {
+                    errors.push(format!(
+                        "Pin {} cannot be used as TX for {}",
+                        pin, mapping.usart
+                    ));
+                }
+            }
+            None => errors.push(format!(
+                "Unsupported split link USART/DMA combination: {}, {} (TX stream {}, RX stream {})",
+                self.split.usart, self.split.dma, self.split.tx_stream, self.split.rx_stream
+            )),
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Generates the skeleton of a new dxkb board target (Cargo manifest,
+//! config.rs, layout.rs and main.rs) from a short TOML description of the
+//! board pins and matrix size. The generated target follows the same structure
+//! as the existing ones, so that it can be built right away and modified from
+//! there. See `boards/` for examples of board descriptions.
+
+mod board;
+mod render;
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use board::BoardDescription;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Generates a new dxkb board target from a TOML board description")]
+struct Args {
+    /// The TOML file describing the board.
+    board: PathBuf,
+
+    /// The directory where the target crate will be created. A new directory
+    /// named after the board will be created inside it.
+    #[arg(short, long, default_value = "crates")]
+    output_dir: PathBuf,
+
+    /// Overwrite the target files if they already exist.
+    #[arg(short, long)]
+    force: bool,
+
+    /// Print the generated files to stdout instead of writing them.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let source = match fs::read_to_string(&args.board) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", args.board.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let board: BoardDescription = match toml::from_str(&source) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Invalid board description {}: {}", args.board.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Err(errors) = board.validate() {
+        eprintln!("Invalid board description {}:", args.board.display());
+        for e in errors {
+            eprintln!("  - {}", e);
+        }
+        return ExitCode::FAILURE;
+    }
+
+    let source_name = args
+        .board
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let files = render::render(&board, &source_name);
+
+    if args.dry_run {
+        for file in files {
+            println!("==> {} <==\n{}", file.path, file.contents);
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let target_dir = args.output_dir.join(&board.name);
+    if let Some(existing) = files
+        .iter()
+        .map(|f| target_dir.join(f.path))
+        .find(|p| !args.force && p.exists())
+    {
+        eprintln!("{} already exists. Use --force to overwrite it.", existing.display());
+        return ExitCode::FAILURE;
+    }
+
+    for file in files {
+        let path = target_dir.join(file.path);
+        if let Err(e) = path
+            .parent()
+            .map(fs::create_dir_all)
+            .unwrap_or(Ok(()))
+            .and_then(|_| fs::write(&path, file.contents))
+        {
+            eprintln!("Couldn't write {}: {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+        println!("Created {}", path.display());
+    }
+
+    println!(
+        "Done. Remember to add \"{}\" to the workspace members in the root Cargo.toml.",
+        target_dir.display()
+    );
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,198 @@
+use crate::board::{BoardDescription, PinName, ScanDirection};
+
+const CARGO_TOML_TEMPLATE: &str = include_str!("../templates/Cargo.toml.tmpl");
+const CARGO_CONFIG_TEMPLATE: &str = include_str!("../templates/cargo-config.tmpl");
+const CONFIG_RS_TEMPLATE: &str = include_str!("../templates/config.rs.tmpl");
+const LAYOUT_RS_TEMPLATE: &str = include_str!("../templates/layout.rs.tmpl");
+const MAIN_RS_TEMPLATE: &str = include_str!("../templates/main.rs.tmpl");
+
+/// A file of the generated target, relative to the target crate root.
+pub struct GeneratedFile {
+    pub path: &'static str,
+    pub contents: String,
+}
+
+/// Replaces every `{{key}}` placeholder of the template. The values are
+/// copied as they are, so they may contain anything, braces included. Panics
+/// if any placeholder has no value, since that means that the templates and
+/// the generator are out of sync.
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").map(|e| start + e + 2).unwrap_or(rest.len());
+        let placeholder = &rest[start..end];
+        let key = placeholder.trim_start_matches("{{").trim_end_matches("}}");
+        match vars.iter().find(|(k, _)| *k == key) {
+            Some((_, value)) => out.push_str(value),
+            None => panic!("Unreplaced placeholder in template: {}", placeholder),
+        }
+        rest = &rest[end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn pin_types(pins: &[PinName]) -> String {
+    pins.iter()
+        .map(|p| format!("    DynamicPin<'{}', {}>,\n", p.port, p.pin))
+        .collect()
+}
+
+fn pin_exprs(pins: &[PinName]) -> String {
+    pins.iter()
+        .map(|p| format!("            {}.{}.into_dynamic(),\n", p.gpio(), p.field()))
+        .collect()
+}
+
+fn layers(board: &BoardDescription) -> String {
+    let rows = board.matrix.rows.len();
+    let cols = board.matrix.cols_left.len();
+
+    let row = |key: &str| {
+        let side = vec![format!("{:>5}", key); cols].join(",");
+        format!("                    [{},  /* | */{}],\n", side, side)
+    };
+
+    let mut out = String::new();
+    for layer in 0..board.layers {
+        out.push_str(&format!("            {{   // {}\n", layer));
+        if layer == 0 {
+            out.push_str("                name: \"base\",\n");
+        } else {
+            out.push_str(&format!("                name: \"layer{}\",\n", layer));
+            out.push_str("                parent: \"base\",\n");
+        }
+        out.push_str("                rows: [\n");
+        for _ in 0..rows {
+            // The base layer has nothing to fall back to, so it is filled with
+            // no-op keys. The rest just pass through to the base.
+            out.push_str(&row(if layer == 0 { "_" } else { "*" }));
+        }
+        out.push_str("                ]\n");
+        out.push_str("            },\n");
+    }
+
+    out
+}
+
+fn gpio_split(board: &BoardDescription) -> String {
+    let mut ports: Vec<char> = board
+        .matrix
+        .rows
+        .iter()
+        .chain(board.matrix.cols_left.iter())
+        .chain(board.cols_right().iter())
+        .chain([board.usb.master_sense_pin, board.split.txrx_pin].iter())
+        .map(|p| p.port)
+        .collect();
+
+    // USB pins are always on port A.
+    ports.push('A');
+    ports.sort();
+    ports.dedup();
+
+    ports
+        .iter()
+        .map(|port| {
+            let port = port.to_ascii_lowercase();
+            format!("    let gpio{} = dp.GPIO{}.split();\n", port, port.to_ascii_uppercase())
+        })
+        .collect()
+}
+
+/// Renders all the files of the new target. The board must have been
+/// validated before calling this function.
+pub fn render(board: &BoardDescription, source: &str) -> Vec<GeneratedFile> {
+    let dma = board.usart_dma_mapping();
+    let cols_right = board.cols_right();
+
+    let vars: Vec<(&str, String)> = vec![
+        ("source", source.to_string()),
+        ("name", board.name.clone()),
+        ("product", board.product.clone()),
+        ("layers", board.layers.to_string()),
+        ("rows", board.matrix.rows.len().to_string()),
+        ("cols", board.matrix.cols_left.len().to_string()),
+        ("debounce_ms", board.debounce_ms.to_string()),
+        ("vid", format!("0x{:04x}", board.usb.vid)),
+        ("pid", format!("0x{:04x}", board.usb.pid)),
+        (
+            "scan",
+            match board.matrix.scan {
+                ScanDirection::Row => "RowScan",
+                ScanDirection::Column => "ColumnScan",
+            }
+            .to_string(),
+        ),
+        ("row_pin_types", pin_types(&board.matrix.rows)),
+        ("col_left_pin_types", pin_types(&board.matrix.cols_left)),
+        ("col_right_pin_types", pin_types(&cols_right)),
+        ("row_pins", pin_exprs(&board.matrix.rows)),
+        ("col_left_pins", pin_exprs(&board.matrix.cols_left)),
+        ("col_right_pins", pin_exprs(&cols_right)),
+        ("sense_port", board.usb.master_sense_pin.port.to_string()),
+        ("sense_pin", board.usb.master_sense_pin.pin.to_string()),
+        ("sense_gpio", board.usb.master_sense_pin.gpio()),
+        ("sense_field", board.usb.master_sense_pin.field()),
+        ("txrx_port", board.split.txrx_pin.port.to_string()),
+        ("txrx_pin", board.split.txrx_pin.pin.to_string()),
+        ("txrx_gpio", board.split.txrx_pin.gpio()),
+        ("txrx_field", board.split.txrx_pin.field()),
+        ("txrx_exti", board.split.txrx_pin.exti_interrupt().to_string()),
+        ("usart", dma.usart.to_string()),
+        ("dma", dma.dma.to_string()),
+        ("tx_stream", dma.tx_stream.to_string()),
+        ("rx_stream", dma.rx_stream.to_string()),
+        ("tx_channel", dma.tx_channel.to_string()),
+        ("rx_channel", dma.rx_channel.to_string()),
+        ("gpio_split", gpio_split(board)),
+    ];
+
+    let layout_vars: Vec<(&str, String)> = vec![
+        ("source", source.to_string()),
+        ("layers", layers(board)),
+    ];
+
+    vec![
+        GeneratedFile { path: "Cargo.toml", contents: fill(CARGO_TOML_TEMPLATE, &vars) },
+        GeneratedFile { path: ".cargo/config", contents: fill(CARGO_CONFIG_TEMPLATE, &vars) },
+        GeneratedFile { path: "src/config.rs", contents: fill(CONFIG_RS_TEMPLATE, &vars) },
+        GeneratedFile { path: "src/layout.rs", contents: fill(LAYOUT_RS_TEMPLATE, &layout_vars) },
+        GeneratedFile { path: "src/main.rs", contents: fill(MAIN_RS_TEMPLATE, &vars) },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE_BOARD: &str = include_str!("../boards/lily58l-example.toml");
+
+    #[test]
+    fn test_render_example_board() {
+        let board: BoardDescription = toml::from_str(EXAMPLE_BOARD).unwrap();
+        board.validate().unwrap();
+
+        // Panics if any placeholder is left unreplaced.
+        let files = render(&board, "lily58l-example.toml");
+        let paths: Vec<_> = files.iter().map(|f| f.path).collect();
+        assert_eq!(paths, ["Cargo.toml", ".cargo/config", "src/config.rs", "src/layout.rs", "src/main.rs"]);
+        assert!(files[0].contents.contains("name = \"dxkb-lily58l-example\""));
+        assert!(files.iter().all(|f| !f.contents.contains("{{")));
+    }
+
+    #[test]
+    fn test_fill_values_with_braces() {
+        let vars = [("product", "{{name}} }} {{".to_string()), ("name", "dxkb".to_string())];
+        assert_eq!(fill("{{product}} by {{name}}", &vars), "{{name}} }} {{ by dxkb");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unreplaced placeholder in template: {{missing}}")]
+    fn test_fill_unreplaced_placeholder() {
+        fill("{{name}} {{missing}}", &[("name", "dxkb".to_string())]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fs, path::PathBuf};
+
+use dxkb_common::debounce::{
+    Debounce, DebouncerEagerPerKey, KeyTransition, NoDebouncer, replay,
+};
+
+use crate::{XtaskError, XtaskResult};
+
+/// The size of the simulated matrix. Captures of any target fit in it, since
+/// the keys that are never pressed don't produce any event.
+const REPLAY_ROWS: u8 = 16;
+const REPLAY_COLS: u8 = 16;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ReplayDebouncer {
+    /// No debounce at all, every raw transition is an event.
+    None,
+    /// Eager debounce per key, with the time given in --millis.
+    Eager,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DebounceReplayOpts {
+    /// A file with the raw transitions dumped by the `scan-capture` debug
+    /// command, one per line. Lines that don't contain a transition are
+    /// ignored, so the debug log can be used directly.
+    pub capture: PathBuf,
+
+    #[arg(long, value_enum, default_value = "eager")]
+    pub debouncer: ReplayDebouncer,
+
+    /// Debounce time of the debouncer, in milliseconds.
+    #[arg(long, default_value_t = 20)]
+    pub millis: u8,
+
+    /// Time between the simulated matrix scans, in milliseconds.
+    #[arg(long, default_value_t = 1)]
+    pub scan_period: u32,
+
+    /// Time to keep scanning after the last transition, in milliseconds.
+    #[arg(long, default_value_t = 300)]
+    pub tail: u32,
+}
+
+fn load_capture(opts: &DebounceReplayOpts) -> XtaskResult<Vec<KeyTransition>> {
+    let content = fs::read_to_string(&opts.capture).map_err(|e| {
+        XtaskError::new(format!("Couldn't read {}: {}", opts.capture.display(), e))
+    })?;
+
+    let mut capture: Vec<KeyTransition> = content.lines().filter_map(KeyTransition::parse).collect();
+    if let Some(t) = capture.iter().find(|t| t.row >= REPLAY_ROWS || t.col >= REPLAY_COLS) {
+        return Err(XtaskError::new(format!(
+            "Transition out of the supported {}x{} matrix: {}",
+            REPLAY_ROWS, REPLAY_COLS, t
+        )));
+    }
+
+    // The capture is already sorted unless the millis counter wrapped, in
+    // which case the order is kept for the transitions of the same time.
+    capture.sort_by_key(|t| t.millis);
+    Ok(capture)
+}
+
+fn run_replay(
+    debouncer: &mut dyn Debounce<REPLAY_ROWS, REPLAY_COLS>,
+    capture: &[KeyTransition],
+    opts: &DebounceReplayOpts,
+) -> Vec<KeyTransition> {
+    let mut events = Vec::new();
+    replay::<REPLAY_ROWS, REPLAY_COLS, _, _>(
+        debouncer,
+        capture,
+        opts.scan_period,
+        opts.tail,
+        |e| events.push(e),
+    );
+    events
+}
+
+pub fn run(opts: &DebounceReplayOpts) -> XtaskResult<()> {
+    let capture = load_capture(opts)?;
+    if capture.is_empty() {
+        return Err(XtaskError::new("The capture doesn't contain any transition"));
+    }
+
+    let events = match opts.debouncer {
+        ReplayDebouncer::None => run_replay(&mut NoDebouncer {}, &capture, opts),
+        ReplayDebouncer::Eager => {
+            if opts.millis == 255 {
+                return Err(XtaskError::new("Debounce time cannot be greater than 254 ms"));
+            }
+
+            // The debounce time is a const parameter of the debouncer.
+            seq_macro::seq!(N in 0..255 {
+                match opts.millis {
+                    #(N => run_replay(&mut DebouncerEagerPerKey::<REPLAY_ROWS, REPLAY_COLS, N>::new(), &capture, opts),)*
+                    _ => unreachable!(),
+                }
+            })
+        }
+    };
+
+    for e in &events {
+        println!("{}", e);
+    }
+    println!(
+        "{} raw transitions, {} debounced events ({} filtered)",
+        capture.len(),
+        events.len(),
+        capture.len().saturating_sub(events.len())
+    );
+    Ok(())
+}
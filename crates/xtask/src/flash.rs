@@ -0,0 +1,96 @@
+use std::{
+    path::Path,
+    process::Command,
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    XtaskError, XtaskResult,
+    target::{BuildOpts, build},
+};
+
+/// The address where the firmware is flashed on the STM32F4.
+const FLASH_BASE_ADDRESS: u32 = 0x0800_0000;
+
+/// The probe-rs name of the chip used in all the targets.
+const PROBE_RS_CHIP: &str = "STM32F411CEUx";
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum FlashMethod {
+    /// Through the STM32 built-in USB DFU bootloader, using dfu-util. The
+    /// device must be already in bootloader mode (see the debug HID endpoint
+    /// for entering it without pressing BOOT0).
+    Dfu,
+
+    /// Through a debug probe, using probe-rs.
+    ProbeRs,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct FlashOpts {
+    #[command(flatten)]
+    pub build: BuildOpts,
+
+    #[arg(short, long, value_enum, default_value = "dfu")]
+    pub method: FlashMethod,
+}
+
+fn run_tool(cmd: &mut Command, tool: &str, hint: &str) -> XtaskResult<()> {
+    eprintln!("Running {:?}", cmd);
+    let status = cmd.status().map_err(|e| {
+        XtaskError::new(format!("Couldn't run {}: {}. {}", tool, e, hint))
+    })?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(XtaskError::new(format!("{} failed with {}", tool, status)))
+    }
+}
+
+fn flash_dfu(elf: &Path) -> XtaskResult<()> {
+    // dfu-util needs a raw binary, not an ELF.
+    let bin = elf.with_extension("bin");
+    run_tool(
+        Command::new("rust-objcopy").args(["-O", "binary"]).arg(elf).arg(&bin),
+        "rust-objcopy",
+        "Install it with `cargo install cargo-binutils && rustup component add llvm-tools`",
+    )?;
+
+    run_tool(
+        Command::new("dfu-util")
+            .args(["-a", "0", "-s"])
+            .arg(format!("0x{:08x}:leave", FLASH_BASE_ADDRESS))
+            .arg("-D")
+            .arg(&bin),
+        "dfu-util",
+        "Make sure dfu-util is installed",
+    )
+}
+
+fn flash_probe_rs(elf: &Path) -> XtaskResult<()> {
+    run_tool(
+        Command::new("probe-rs")
+            .args(["download", "--chip", PROBE_RS_CHIP])
+            .arg(elf),
+        "probe-rs",
+        "Install it from https://probe.rs",
+    )?;
+
+    run_tool(
+        Command::new("probe-rs").args(["reset", "--chip", PROBE_RS_CHIP]),
+        "probe-rs",
+        "Install it from https://probe.rs",
+    )
+}
+
+pub fn run(opts: &FlashOpts) -> XtaskResult<()> {
+    let (target, elf) = build(&opts.build)?;
+    eprintln!("Flashing {} from {}", target.name, elf.display());
+
+    match opts.method {
+        FlashMethod::Dfu => flash_dfu(&elf),
+        FlashMethod::ProbeRs => flash_probe_rs(&elf),
+    }
+}
@@ -0,0 +1,94 @@
+//! Development tasks for the dxkb workspace. Each firmware target needs to be
+//! built for a specific MCU target triple, MCU feature and keyboard side,
+//! which makes it easy to get the command line wrong. This crate is the single
+//! entry point for building, measuring and flashing them. Run it with `cargo
+//! xtask <command>`.
+
+// Needed for using the debouncers of dxkb-common, which are sized by the
+// dimensions of the matrix.
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+mod debounce_replay;
+mod flash;
+mod size;
+mod target;
+
+use std::{fmt::Display, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+
+#[derive(Debug)]
+pub struct XtaskError {
+    msg: String,
+}
+
+impl XtaskError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        Self { msg: msg.into() }
+    }
+}
+
+impl Display for XtaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+pub type XtaskResult<T> = Result<T, XtaskError>;
+
+#[derive(Parser, Debug)]
+#[command(about = "Development tasks for the dxkb firmware targets")]
+struct Args {
+    #[command(subcommand)]
+    command: XtaskCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum XtaskCommand {
+    /// List the firmware targets available in the workspace.
+    Targets,
+
+    /// Build a firmware target.
+    Build(target::BuildOpts),
+
+    /// Build a firmware target and report its size by crate, optionally
+    /// comparing it against a previous measurement. Requires cargo-bloat.
+    Size(size::SizeOpts),
+
+    /// Build a firmware target and flash it.
+    Flash(flash::FlashOpts),
+
+    /// Replay a capture of raw matrix transitions through a debouncer, and
+    /// print the resulting key events.
+    DebounceReplay(debounce_replay::DebounceReplayOpts),
+}
+
+fn run(args: Args) -> XtaskResult<()> {
+    match args.command {
+        XtaskCommand::Targets => {
+            for t in target::discover_targets() {
+                println!("{}{}", t.name, if t.is_split { " (split)" } else { "" });
+            }
+            Ok(())
+        }
+        XtaskCommand::Build(opts) => {
+            let (_, elf) = target::build(&opts)?;
+            println!("{}", elf.display());
+            Ok(())
+        }
+        XtaskCommand::Size(opts) => size::run(&opts),
+        XtaskCommand::Flash(opts) => flash::run(&opts),
+        XtaskCommand::DebounceReplay(opts) => debounce_replay::run(&opts),
+    }
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    XtaskError, XtaskResult,
+    target::{BuildOpts, cargo, find_target},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct SizeOpts {
+    #[command(flatten)]
+    pub build: BuildOpts,
+
+    /// Store the measured sizes in this file, so that they can be used as a
+    /// baseline for later comparisons.
+    #[arg(long)]
+    pub save: Option<PathBuf>,
+
+    /// Compare the measured sizes against a baseline previously stored with
+    /// --save.
+    #[arg(long)]
+    pub diff: Option<PathBuf>,
+
+    /// Fail if the .text section grew more than this many bytes over the
+    /// baseline given with --diff. Useful for catching size regressions in
+    /// builds for small MCUs.
+    #[arg(long, requires = "diff")]
+    pub max_growth: Option<u64>,
+
+    /// Fail if the .text section is larger than this many bytes, e.g the
+    /// flash available for the firmware in the target MCU.
+    #[arg(long)]
+    pub max_text: Option<u64>,
+}
+
+/// The sizes of a firmware image, broken down by crate. Each dxkb crate is
+/// considered a subsystem (core, split link, peripherals...); everything else
+/// is grouped by its own crate name.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SizeReport {
+    pub file_size: u64,
+    pub text_size: u64,
+    pub crates: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize)]
+struct BloatOutput {
+    #[serde(rename = "file-size")]
+    file_size: u64,
+    #[serde(rename = "text-section-size")]
+    text_size: u64,
+    crates: Vec<BloatCrate>,
+}
+
+#[derive(Deserialize)]
+struct BloatCrate {
+    name: String,
+    size: u64,
+}
+
+fn measure(opts: &BuildOpts) -> XtaskResult<SizeReport> {
+    let target = find_target(&opts.target)?;
+    let output = cargo()
+        .arg("bloat")
+        .args(opts.cargo_args(&target)?)
+        .args(["--crates", "-n", "0", "--message-format", "json"])
+        .current_dir(&target.dir)
+        .output()
+        .map_err(|e| XtaskError::new(format!("Couldn't run cargo bloat: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("no such command") {
+            return Err(XtaskError::new(
+                "cargo-bloat is not installed. Install it with `cargo install cargo-bloat`",
+            ));
+        }
+        return Err(XtaskError::new(format!("cargo bloat failed:\n{}", stderr)));
+    }
+
+    let bloat: BloatOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|e| XtaskError::new(format!("Couldn't parse cargo bloat output: {}", e)))?;
+
+    Ok(SizeReport {
+        file_size: bloat.file_size,
+        text_size: bloat.text_size,
+        crates: bloat.crates.into_iter().map(|c| (c.name, c.size)).collect(),
+    })
+}
+
+fn print_report(report: &SizeReport, baseline: Option<&SizeReport>) {
+    let delta = |new: u64, old: Option<u64>| match old {
+        Some(old) if old != new => format!("{:+}", new as i64 - old as i64),
+        Some(_) => String::new(),
+        None if baseline.is_some() => "(new)".to_string(),
+        None => String::new(),
+    };
+
+    let mut crates: Vec<(&String, &u64)> = report.crates.iter().collect();
+    crates.sort_by(|a, b| b.1.cmp(a.1));
+
+    println!("{:<32} {:>10} {:>10}", "Crate", "Size", "Delta");
+    for (name, size) in crates {
+        let old = baseline.and_then(|b| b.crates.get(name).copied());
+        println!("{:<32} {:>10} {:>10}", name, size, delta(*size, old));
+    }
+
+    if let Some(baseline) = baseline {
+        for (name, size) in baseline.crates.iter().filter(|(n, _)| !report.crates.contains_key(*n)) {
+            println!("{:<32} {:>10} {:>10}", name, 0, format!("-{}", size));
+        }
+    }
+
+    println!();
+    println!(
+        "{:<32} {:>10} {:>10}",
+        ".text",
+        report.text_size,
+        delta(report.text_size, baseline.map(|b| b.text_size))
+    );
+    println!(
+        "{:<32} {:>10} {:>10}",
+        "File",
+        report.file_size,
+        delta(report.file_size, baseline.map(|b| b.file_size))
+    );
+}
+
+pub fn run(opts: &SizeOpts) -> XtaskResult<()> {
+    let baseline = match &opts.diff {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| XtaskError::new(format!("Couldn't read {}: {}", path.display(), e)))?;
+            Some(
+                serde_json::from_str::<SizeReport>(&contents)
+                    .map_err(|e| XtaskError::new(format!("Invalid size baseline {}: {}", path.display(), e)))?,
+            )
+        }
+        None => None,
+    };
+
+    let report = measure(&opts.build)?;
+    print_report(&report, baseline.as_ref());
+
+    if let Some(path) = &opts.save {
+        fs::write(path, serde_json::to_string_pretty(&report).unwrap())
+            .map_err(|e| XtaskError::new(format!("Couldn't write {}: {}", path.display(), e)))?;
+        eprintln!("Size report saved to {}", path.display());
+    }
+
+    check_limits(opts, &report, baseline.as_ref())
+}
+
+fn check_limits(opts: &SizeOpts, report: &SizeReport, baseline: Option<&SizeReport>) -> XtaskResult<()> {
+    if let (Some(max_growth), Some(baseline)) = (opts.max_growth, baseline) {
+        let growth = report.text_size as i64 - baseline.text_size as i64;
+        if growth > max_growth as i64 {
+            return Err(XtaskError::new(format!(
+                ".text grew {} bytes over the baseline, more than the {} bytes allowed",
+                growth, max_growth
+            )));
+        }
+    }
+
+    if let Some(max_text) = opts.max_text {
+        if report.text_size > max_text {
+            return Err(XtaskError::new(format!(
+                ".text is {} bytes, more than the {} bytes allowed",
+                report.text_size, max_text
+            )));
+        }
+    }
+
+    Ok(())
+}
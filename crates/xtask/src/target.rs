@@ -0,0 +1,224 @@
+use std::{
+    fs,
+    io::BufRead,
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{XtaskError, XtaskResult};
+
+/// The triple every firmware target is built for.
+pub const FIRMWARE_TRIPLE: &str = "thumbv7em-none-eabihf";
+
+/// The MCU feature that needs to be enabled on every firmware target. Only the
+/// STM32F411 is supported for now.
+pub const MCU_FEATURE: &str = "stm32f411";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    pub fn feature(self) -> &'static str {
+        match self {
+            Side::Left => "side-left",
+            Side::Right => "side-right",
+        }
+    }
+}
+
+/// A crate of the workspace that builds a firmware image. These are detected
+/// as the binary crates that have their own `.cargo/config` forcing the build
+/// for the MCU target.
+pub struct FirmwareTarget {
+    pub name: String,
+    pub dir: PathBuf,
+    pub is_split: bool,
+}
+
+/// Options shared by all the commands that need to build a firmware image.
+#[derive(clap::Args, Debug, Clone)]
+pub struct BuildOpts {
+    /// The name of the target crate (e.g dxkb-lily58l-stemcell).
+    pub target: String,
+
+    /// The side of the keyboard to build, for split keyboard targets.
+    #[arg(short, long, value_enum)]
+    pub side: Option<Side>,
+
+    /// Build with the release profile.
+    #[arg(short, long)]
+    pub release: bool,
+
+    /// Extra features to enable on the target crate.
+    #[arg(short = 'F', long, value_delimiter = ',')]
+    pub features: Vec<String>,
+}
+
+impl BuildOpts {
+    pub fn profile(&self) -> &'static str {
+        if self.release { "release" } else { "dev" }
+    }
+
+    /// The list of features, MCU and side included, that will be enabled for
+    /// this build.
+    pub fn all_features(&self, target: &FirmwareTarget) -> XtaskResult<Vec<String>> {
+        let mut features = vec![MCU_FEATURE.to_string()];
+        match (target.is_split, self.side) {
+            (true, Some(side)) => features.push(side.feature().to_string()),
+            (true, None) => {
+                return Err(XtaskError::new(format!(
+                    "{} is a split keyboard target. The side (--side) must be specified",
+                    target.name
+                )));
+            }
+            (false, Some(_)) => {
+                return Err(XtaskError::new(format!(
+                    "{} is not a split keyboard target, --side cannot be used",
+                    target.name
+                )));
+            }
+            (false, None) => {}
+        }
+        features.extend(self.features.iter().cloned());
+        Ok(features)
+    }
+
+    /// The common cargo arguments for building this target, after the cargo
+    /// subcommand.
+    pub fn cargo_args(&self, target: &FirmwareTarget) -> XtaskResult<Vec<String>> {
+        Ok(vec![
+            "--package".to_string(),
+            target.name.clone(),
+            "--target".to_string(),
+            FIRMWARE_TRIPLE.to_string(),
+            "--profile".to_string(),
+            self.profile().to_string(),
+            "--features".to_string(),
+            self.all_features(target)?.join(","),
+        ])
+    }
+}
+
+/// A command for running the same cargo that is running this xtask, if
+/// any, so the toolchain selected by rustup is preserved.
+pub fn cargo() -> Command {
+    Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+}
+
+pub fn workspace_root() -> PathBuf {
+    // This crate lives in <root>/crates/xtask.
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .ancestors()
+        .nth(2)
+        .unwrap()
+        .to_path_buf()
+}
+
+fn read_package_info(manifest: &Path) -> Option<(String, bool)> {
+    let contents = fs::read_to_string(manifest).ok()?;
+    let mut name = None;
+    let mut in_package = false;
+    let mut is_split = false;
+
+    // Not worth pulling a TOML parser just for this.
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+        } else if in_package && line.starts_with("name") {
+            name = line
+                .split_once('=')
+                .map(|(_, v)| v.trim().trim_matches('"').to_string());
+        } else if line.starts_with("side-left") {
+            is_split = true;
+        }
+    }
+
+    name.map(|n| (n, is_split))
+}
+
+pub fn discover_targets() -> Vec<FirmwareTarget> {
+    let crates_dir = workspace_root().join("crates");
+    let mut targets: Vec<FirmwareTarget> = fs::read_dir(&crates_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|dir| {
+            let has_mcu_config =
+                dir.join(".cargo").join("config").exists() || dir.join(".cargo").join("config.toml").exists();
+            let has_bin = dir.join("src").join("main.rs").exists()
+                || fs::read_to_string(dir.join("Cargo.toml")).is_ok_and(|m| m.contains("[[bin]]"));
+            has_mcu_config && has_bin
+        })
+        .filter_map(|dir| {
+            read_package_info(&dir.join("Cargo.toml")).map(|(name, is_split)| FirmwareTarget {
+                name,
+                dir,
+                is_split,
+            })
+        })
+        .collect();
+    targets.sort_by(|a, b| a.name.cmp(&b.name));
+    targets
+}
+
+pub fn find_target(name: &str) -> XtaskResult<FirmwareTarget> {
+    let targets = discover_targets();
+    let names: Vec<String> = targets.iter().map(|t| t.name.clone()).collect();
+    targets
+        .into_iter()
+        .find(|t| t.name == name)
+        .ok_or_else(|| XtaskError::new(format!("Unknown target {}. Available targets: {}", name, names.join(", "))))
+}
+
+#[derive(Deserialize)]
+struct CargoArtifactMessage {
+    reason: String,
+    executable: Option<PathBuf>,
+    target: Option<CargoArtifactTarget>,
+}
+
+#[derive(Deserialize)]
+struct CargoArtifactTarget {
+    kind: Vec<String>,
+}
+
+/// Builds the given target and returns the path of the resulting ELF file.
+pub fn build(opts: &BuildOpts) -> XtaskResult<(FirmwareTarget, PathBuf)> {
+    let target = find_target(&opts.target)?;
+    let mut cmd = cargo();
+    cmd.arg("build")
+        .args(opts.cargo_args(&target)?)
+        .arg("--message-format=json-render-diagnostics")
+        // Build from the target crate directory so that its .cargo/config is
+        // honored (linker script arguments, etc.).
+        .current_dir(&target.dir)
+        .stdout(Stdio::piped());
+
+    eprintln!("Building {} ({})", target.name, opts.all_features(&target)?.join(","));
+    let mut child = cmd.spawn().map_err(|e| XtaskError::new(format!("Couldn't run cargo: {}", e)))?;
+    let mut executable = None;
+    for line in BufReader::new(child.stdout.take().unwrap()).lines().map_while(Result::ok) {
+        if let Ok(msg) = serde_json::from_str::<CargoArtifactMessage>(&line)
+            && msg.reason == "compiler-artifact"
+            && msg.target.is_some_and(|t| t.kind.iter().any(|k| k == "bin"))
+        {
+            executable = msg.executable.or(executable);
+        }
+    }
+
+    let status = child.wait().map_err(|e| XtaskError::new(format!("Couldn't run cargo: {}", e)))?;
+    if !status.success() {
+        return Err(XtaskError::new(format!("Build of {} failed", target.name)));
+    }
+
+    let elf = executable.ok_or_else(|| XtaskError::new("Cargo didn't report any binary artifact"))?;
+    Ok((target, elf))
+}
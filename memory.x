@@ -1,8 +1,10 @@
 /* memory.x - Linker script for the STM32F411CEU6 */
 MEMORY
 {
-  /* Flash memory begins at 0x80000000 and has a size of 512kB*/
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* Flash memory begins at 0x80000000 and has a size of 512kB. The
+     last 128kB sector (sector 7) is reserved for the persistent config
+     area (see dxkb-peripheral flash_config) */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384K
   /* RAM begins at 0x20000000 and has a size of 128kB*/
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
@@ -0,0 +1,291 @@
+#!/usr/bin/env python3
+"""Host companion of the dxkb firmware.
+
+Talks to the vendor-defined HID interfaces of a dxkb device:
+
+ - The debug interface (usage page 0xff00), which streams the debug log and
+   accepts the text commands in dxkb-core/src/debug.rs.
+ - The config interface (usage page 0xff01), which exports and imports the
+   config blob and serves the build info, as described in
+   dxkb-core/src/config.rs.
+
+It can be used both as a library (see `Device`) and as a command line tool.
+Requires the `hidapi` package.
+"""
+
+import argparse
+import struct
+import sys
+import time
+
+import hid
+
+DEFAULT_VID = 0x16C0
+DEFAULT_PID = 0x27DB
+
+DEBUG_USAGE_PAGE = 0xFF00
+CONFIG_USAGE_PAGE = 0xFF01
+
+# Must match DEBUG_REPORT_LEN and CONFIG_REPORT_LEN.
+REPORT_LEN = 64
+
+DEBUG_CMD_ENTER_DFU = b"enter-dfu"
+DEBUG_CMD_LINK_STATS = b"link-stats"
+DEBUG_CMD_STATUS = b"status"
+DEBUG_CMD_SCAN_CAPTURE = b"scan-capture"
+DEBUG_CMD_PEER_ENTER_DFU = b"peer-enter-dfu"
+
+CONFIG_CMD_EXPORT = 0x01
+CONFIG_CMD_IMPORT_BEGIN = 0x02
+CONFIG_CMD_IMPORT_DATA = 0x03
+CONFIG_CMD_IMPORT_COMMIT = 0x04
+CONFIG_CMD_BUILD_INFO = 0x05
+CONFIG_RESPONSE_FLAG = 0x80
+
+CONFIG_STATUS_OK = 0
+CONFIG_STATUS_BAD_REQUEST = 0xFF
+CONFIG_RESPONSE_HEADER_LEN = 5
+CONFIG_IMPORT_DATA_HEADER_LEN = 4
+
+# Must match ConfigBlobError::status_code.
+CONFIG_STATUS_NAMES = {
+    1: "buffer too small",
+    2: "invalid magic",
+    3: "invalid checksum",
+    4: "malformed blob",
+    5: "unsupported version",
+    6: "upgrade failed",
+    7: "flash error",
+    CONFIG_STATUS_BAD_REQUEST: "bad request",
+}
+
+CONFIG_BLOB_MAGIC = b"DXKC"
+CONFIG_BLOB_HEADER_LEN = 8
+
+
+class DeviceError(Exception):
+    pass
+
+
+def find_interface(usage_page, vid=DEFAULT_VID, pid=DEFAULT_PID):
+    """Returns the path of the interface of the device with the given usage
+    page."""
+    for info in hid.enumerate(vid, pid):
+        if info["usage_page"] == usage_page:
+            return info["path"]
+    raise DeviceError(
+        "no device %04x:%04x with usage page %04x found" % (vid, pid, usage_page)
+    )
+
+
+def _open(path):
+    dev = hid.device()
+    dev.open_path(path)
+    return dev
+
+
+def _write_report(dev, data):
+    # The first byte is the report id, which the interfaces don't use.
+    dev.write(b"\x00" + data.ljust(REPORT_LEN, b"\x00"))
+
+
+class DebugInterface:
+    def __init__(self, vid=DEFAULT_VID, pid=DEFAULT_PID):
+        self.dev = _open(find_interface(DEBUG_USAGE_PAGE, vid, pid))
+
+    def close(self):
+        self.dev.close()
+
+    def command(self, cmd):
+        _write_report(self.dev, cmd + b"\n")
+
+    def read_log(self, timeout_ms=100):
+        """Returns the log bytes received until no report arrives for
+        `timeout_ms`."""
+        out = bytearray()
+        while True:
+            report = self.dev.read(REPORT_LEN, timeout_ms)
+            if not report:
+                return bytes(out)
+            out += bytes(report).rstrip(b"\x00")
+
+
+class ConfigInterface:
+    def __init__(self, vid=DEFAULT_VID, pid=DEFAULT_PID, timeout_ms=1000):
+        self.dev = _open(find_interface(CONFIG_USAGE_PAGE, vid, pid))
+        self.timeout_ms = timeout_ms
+
+    def close(self):
+        self.dev.close()
+
+    def _response(self, cmd):
+        report = bytes(self.dev.read(REPORT_LEN, self.timeout_ms))
+        if not report:
+            raise TimeoutError("timed out waiting for a response")
+        if report[0] != cmd | CONFIG_RESPONSE_FLAG:
+            raise DeviceError("unexpected response %02x" % report[0])
+        status = report[1]
+        if status != CONFIG_STATUS_OK:
+            name = CONFIG_STATUS_NAMES.get(status, "unknown error")
+            raise DeviceError("device returned status %d (%s)" % (status, name))
+        offset, n = struct.unpack_from("<HB", report, 2)
+        return offset, report[CONFIG_RESPONSE_HEADER_LEN:CONFIG_RESPONSE_HEADER_LEN + n]
+
+    def _read_chunks(self, cmd, done, out):
+        while not done(out):
+            offset, data = self._response(cmd)
+            if offset != len(out):
+                raise DeviceError("out of order chunk at %d" % offset)
+            if not data:
+                break
+            out += data
+
+    def build_info(self):
+        _write_report(self.dev, bytes([CONFIG_CMD_BUILD_INFO]))
+        # The build info has no length header, the last chunk is the first
+        # one that doesn't fill the report, or the last one before the device
+        # goes quiet if its length is a multiple of the chunk length.
+        chunk = REPORT_LEN - CONFIG_RESPONSE_HEADER_LEN
+        out = bytearray()
+        try:
+            self._read_chunks(CONFIG_CMD_BUILD_INFO, lambda out: len(out) % chunk != 0, out)
+        except TimeoutError:
+            if not out:
+                raise
+        return dict(
+            line.split("=", 1) for line in out.decode().splitlines() if "=" in line
+        )
+
+    def export(self):
+        _write_report(self.dev, bytes([CONFIG_CMD_EXPORT]))
+        # The length of the blob is in its header.
+        blob = bytearray()
+        self._read_chunks(
+            CONFIG_CMD_EXPORT,
+            lambda out: len(out) >= CONFIG_BLOB_HEADER_LEN
+            and len(out) >= struct.unpack_from("<H", out, 6)[0],
+            blob,
+        )
+        if blob[:4] != CONFIG_BLOB_MAGIC:
+            raise DeviceError("exported blob has an invalid magic")
+        return bytes(blob)
+
+    def import_(self, blob):
+        if blob[:4] != CONFIG_BLOB_MAGIC:
+            raise DeviceError("not a config blob")
+        _write_report(self.dev, struct.pack("<BH", CONFIG_CMD_IMPORT_BEGIN, len(blob)))
+        self._response(CONFIG_CMD_IMPORT_BEGIN)
+
+        chunk = REPORT_LEN - CONFIG_IMPORT_DATA_HEADER_LEN
+        for offset in range(0, len(blob), chunk):
+            data = blob[offset:offset + chunk]
+            _write_report(
+                self.dev,
+                struct.pack("<BHB", CONFIG_CMD_IMPORT_DATA, offset, len(data)) + data,
+            )
+
+        _write_report(self.dev, bytes([CONFIG_CMD_IMPORT_COMMIT]))
+        self._response(CONFIG_CMD_IMPORT_COMMIT)
+
+
+def cmd_log(args):
+    dbg = DebugInterface(args.vid, args.pid)
+    try:
+        while True:
+            data = dbg.read_log()
+            if data:
+                sys.stdout.write(data.decode(errors="replace"))
+                sys.stdout.flush()
+            elif not args.follow:
+                return
+    except KeyboardInterrupt:
+        pass
+    finally:
+        dbg.close()
+
+
+def _debug_command(cmd):
+    def run(args):
+        dbg = DebugInterface(args.vid, args.pid)
+        try:
+            dbg.command(cmd)
+            # The answer, if any, is written into the debug log.
+            time.sleep(0.05)
+            sys.stdout.write(dbg.read_log().decode(errors="replace"))
+        finally:
+            dbg.close()
+
+    return run
+
+
+def cmd_info(args):
+    cfg = ConfigInterface(args.vid, args.pid)
+    try:
+        for key, value in cfg.build_info().items():
+            print("%s: %s" % (key, value))
+    finally:
+        cfg.close()
+
+
+def cmd_export(args):
+    cfg = ConfigInterface(args.vid, args.pid)
+    try:
+        blob = cfg.export()
+    finally:
+        cfg.close()
+    with open(args.file, "wb") as f:
+        f.write(blob)
+    print("exported %d bytes" % len(blob))
+
+
+def cmd_import(args):
+    with open(args.file, "rb") as f:
+        blob = f.read()
+    cfg = ConfigInterface(args.vid, args.pid)
+    try:
+        cfg.import_(blob)
+    finally:
+        cfg.close()
+    print("imported %d bytes" % len(blob))
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Companion tool for dxkb keyboards")
+    parser.add_argument("--vid", type=lambda v: int(v, 0), default=DEFAULT_VID)
+    parser.add_argument("--pid", type=lambda v: int(v, 0), default=DEFAULT_PID)
+    sub = parser.add_subparsers(dest="command", required=True)
+
+    p = sub.add_parser("log", help="dump the debug log")
+    p.add_argument("-f", "--follow", action="store_true", help="keep reading")
+    p.set_defaults(func=cmd_log)
+
+    for name, cmd, help in [
+        ("status", DEBUG_CMD_STATUS, "print the status of the keyboard"),
+        ("link-stats", DEBUG_CMD_LINK_STATS, "print the split link stats"),
+        ("scan-capture", DEBUG_CMD_SCAN_CAPTURE, "dump the captured matrix transitions"),
+        ("enter-dfu", DEBUG_CMD_ENTER_DFU, "reboot into the bootloader"),
+        ("peer-enter-dfu", DEBUG_CMD_PEER_ENTER_DFU, "reboot the other side into the bootloader"),
+    ]:
+        sub.add_parser(name, help=help).set_defaults(func=_debug_command(cmd))
+
+    sub.add_parser("info", help="print the build info").set_defaults(func=cmd_info)
+
+    p = sub.add_parser("export", help="export the config into a file")
+    p.add_argument("file")
+    p.set_defaults(func=cmd_export)
+
+    p = sub.add_parser("import", help="import the config from a file")
+    p.add_argument("file")
+    p.set_defaults(func=cmd_import)
+
+    args = parser.parse_args()
+    try:
+        args.func(args)
+    except (DeviceError, OSError) as e:
+        print("error: %s" % e, file=sys.stderr)
+        return 1
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())